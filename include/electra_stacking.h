@@ -0,0 +1,121 @@
+#ifndef ELECTRA_STACKING_H
+#define ELECTRA_STACKING_H
+
+/* Generated by cbindgen from src/ffi.rs. Don't edit manually */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+#define ES_MODE_KAPPA_SIGMA 0
+
+#define ES_MODE_MEDIAN 1
+
+#define ES_MODE_MEAN 2
+
+typedef struct EsAlignedFrame EsAlignedFrame;
+
+typedef struct EsCalibrator EsCalibrator;
+
+typedef struct EsFrame EsFrame;
+
+typedef struct EsImage EsImage;
+
+typedef struct EsIntegrator EsIntegrator;
+
+typedef struct EsRegistrar EsRegistrar;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Message of last error of current thread (empty string if there was no error).
+ * Panic of library is error with "Panic:" prefix if library is built with
+ * unwinding (`cargo build --profile release-lib --lib`), otherwise it aborts process
+ */
+const char *es_last_error(void);
+
+/**
+ * Master files can be NULL
+ */
+EsCalibrator *es_calibrator_new(const char *master_flat,
+                                const char *master_dark,
+                                const char *master_bias);
+
+void es_calibrator_free(EsCalibrator *calibrator);
+
+/**
+ * Loads, calibrates and demosaics light file
+ */
+EsFrame *es_frame_load(const EsCalibrator *calibrator, const char *file_name);
+
+void es_frame_free(EsFrame *frame);
+
+/**
+ * Number of stars found on light frame
+ */
+int es_frame_stars_count(const EsFrame *frame);
+
+/**
+ * Reference light file is loaded and calibrated by `calibrator`
+ */
+EsRegistrar *es_registrar_new(const EsCalibrator *calibrator, const char *ref_file_name);
+
+void es_registrar_free(EsRegistrar *registrar);
+
+/**
+ * Aligns frame to reference one. `frame` is released. NULL frame (failed
+ * `es_frame_load`) gives NULL and doesn't change message of last error
+ */
+EsAlignedFrame *es_register(const EsRegistrar *registrar, EsFrame *frame);
+
+void es_aligned_frame_free(EsAlignedFrame *frame);
+
+/**
+ * `mode` is one of ES_MODE_* constants. `kappa` and `repeats`
+ * are used for kappa-sigma mode only
+ */
+EsIntegrator *es_integrator_new(int mode, float kappa, uint32_t repeats);
+
+void es_integrator_free(EsIntegrator *integrator);
+
+/**
+ * Adds aligned frame into integrator. `frame` is released.
+ * NULL frame gives -1 and doesn't change message of last error
+ */
+int es_integrator_add(EsIntegrator *integrator, EsAlignedFrame *frame);
+
+/**
+ * Stacks all added frames. Integrator can be used for next frames after that
+ */
+EsImage *es_integrator_stack(const EsIntegrator *integrator);
+
+void es_image_free(EsImage *image);
+
+int es_image_width(const EsImage *image);
+
+int es_image_height(const EsImage *image);
+
+/**
+ * 1 for mono image, 3 for RGB one
+ */
+int es_image_channels(const EsImage *image);
+
+/**
+ * Row-major values of channel (0 for mono image, 0..2 for R, G and B) in
+ * 0..1 range. Buffer is owned by image and is valid until `es_image_free`
+ */
+const float *es_image_data(const EsImage *image, int channel);
+
+/**
+ * Saves image into FITS, TIFF or XISF file (by extension) as 32 bit float data
+ */
+int es_image_save(const EsImage *image, const char *file_name);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* ELECTRA_STACKING_H */
@@ -0,0 +1,534 @@
+use std::{path::*, io::*, net::*, sync::Arc, collections::{HashMap, HashSet}, time::Duration};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+use crate::{batch::*, config::*, progress::*, project::*, stacking_utils::*, fs_utils::*, image::*, image_io::*, safe_read::*, report};
+
+/* Agent for remote processing: laptop at the telescope sends captured
+   light files over TCP to desktop which registers and stacks them and
+   returns preview of result. To keep Wi-Fi usage low preview is sent
+   as low resolution image first and then only changed tiles of full
+   resolution preview are sent. Agent listens only on localhost by default
+   so SSH tunnel (ssh -L 7878:localhost:7878 desktop) is used for
+   remote access and encryption */
+
+pub const DEFAULT_AGENT_ADDRESS: &str = "127.0.0.1:7878";
+
+const AGENT_MAGIC: &[u8; 4] = b"ESA1";
+const MAX_MESSAGE_SIZE: u64 = 4 << 30;
+const MAX_PREVIEW_WIDTH: Crd = 1600;
+const MAX_LOW_RES_PREVIEW_WIDTH: Crd = 400;
+const PREVIEW_TILE_SIZE: usize = 64;
+const PREVIEW_TILE_MAX_DIFF: f32 = 1.0; // mean difference of bytes to resend tile
+
+#[derive(PartialEq, Clone, Copy)]
+enum PreviewLevel {
+    LowRes, // whole image in one tile
+    Full,   // changed tiles only
+}
+
+struct PreviewTile {
+    x:      usize,
+    y:      usize,
+    width:  usize,
+    height: usize,
+    rgb:    Vec<u8>,
+}
+
+/// 8-bit RGB preview of result image
+struct PreviewImage {
+    width:  usize,
+    height: usize,
+    rgb:    Vec<u8>,
+}
+
+impl PreviewImage {
+    fn new(image: &Image, max_width: Crd, gamma: f32) -> Self {
+        let mut reduced = None;
+        while reduced.as_ref().unwrap_or(image).width() > max_width {
+            reduced = Some(reduced.as_ref().unwrap_or(image).decrease_2x());
+        }
+        let image = reduced.as_ref().unwrap_or(image);
+        let params = image.calc_to_bytes_params(true, true);
+        Self {
+            width: image.width() as usize,
+            height: image.height() as usize,
+            rgb: image.to_rgb_bytes(&params, gamma),
+        }
+    }
+
+    fn tile_rgb(&self, x: usize, y: usize, width: usize, height: usize) -> Vec<u8> {
+        let mut result = Vec::with_capacity(3 * width * height);
+        for row in y..y+height {
+            let start = 3 * (row * self.width + x);
+            result.extend_from_slice(&self.rgb[start..start + 3 * width]);
+        }
+        result
+    }
+
+    fn put_tile(&mut self, tile: &PreviewTile) -> anyhow::Result<()> {
+        if tile.x + tile.width > self.width
+        || tile.y + tile.height > self.height
+        || tile.rgb.len() != 3 * tile.width * tile.height {
+            anyhow::bail!("Wrong preview tile");
+        }
+        for (i, row) in (tile.y..tile.y+tile.height).enumerate() {
+            let start = 3 * (row * self.width + tile.x);
+            self.rgb[start..start + 3 * tile.width]
+                .copy_from_slice(&tile.rgb[3 * i * tile.width..3 * (i + 1) * tile.width]);
+        }
+        Ok(())
+    }
+
+    /// Tiles which are differ from previously sent preview
+    fn changed_tiles(&self, prev: Option<&PreviewImage>) -> Vec<PreviewTile> {
+        let prev = prev.filter(|p| p.width == self.width && p.height == self.height);
+        let mut result = Vec::new();
+        for y in (0..self.height).step_by(PREVIEW_TILE_SIZE) {
+            for x in (0..self.width).step_by(PREVIEW_TILE_SIZE) {
+                let width = PREVIEW_TILE_SIZE.min(self.width - x);
+                let height = PREVIEW_TILE_SIZE.min(self.height - y);
+                let rgb = self.tile_rgb(x, y, width, height);
+                if let Some(prev) = prev {
+                    let prev_rgb = prev.tile_rgb(x, y, width, height);
+                    let diff_sum: u64 = rgb.iter()
+                        .zip(&prev_rgb)
+                        .map(|(a, b)| a.abs_diff(*b) as u64)
+                        .sum();
+                    if (diff_sum as f32 / rgb.len() as f32) < PREVIEW_TILE_MAX_DIFF {
+                        continue;
+                    }
+                }
+                result.push(PreviewTile { x, y, width, height, rgb });
+            }
+        }
+        result
+    }
+
+    fn save_to_file(&self, file_name: &Path) -> anyhow::Result<()> {
+        let mut image = Image::new_color(self.width as Crd, self.height as Crd);
+        let pixels = self.rgb.chunks_exact(3);
+        for (pix, r, g, b) in itertools::izip!(
+            pixels,
+            image.r.iter_mut(),
+            image.g.iter_mut(),
+            image.b.iter_mut()
+        ) {
+            *r = pix[0] as f32 / 255.0;
+            *g = pix[1] as f32 / 255.0;
+            *b = pix[2] as f32 / 255.0;
+        }
+        let info = ImageInfo {
+            file_name: file_name.to_path_buf(),
+            width: self.width,
+            height: self.height,
+            .. Default::default()
+        };
+        write_file_atomically(file_name, |tmp_file_name| {
+            save_image_to_tiff_file(&image, &info, tmp_file_name)
+        })
+    }
+}
+
+enum AgentMessage {
+    File { name: String, data: Vec<u8> }, // client -> agent
+    Stack,                                // client -> agent
+    Status(String),                       // agent -> client
+    Preview {                              // agent -> client
+        name:   String,
+        level:  PreviewLevel,
+        width:  usize,
+        height: usize,
+        tiles:  Vec<PreviewTile>,
+    },
+    Error(String),                        // agent -> client
+}
+
+impl AgentMessage {
+    fn write(&self, stream: &mut impl Write) -> anyhow::Result<()> {
+        let mut payload = Vec::new();
+        let write_name = |payload: &mut Vec<u8>, name: &str| -> anyhow::Result<()> {
+            payload.write_u16::<LittleEndian>(name.len() as u16)?;
+            payload.extend_from_slice(name.as_bytes());
+            Ok(())
+        };
+        let kind = match self {
+            AgentMessage::File { name, data } => {
+                write_name(&mut payload, name)?;
+                payload.extend_from_slice(data);
+                1
+            },
+            AgentMessage::Preview { name, level, width, height, tiles } => {
+                write_name(&mut payload, name)?;
+                payload.write_u8(if *level == PreviewLevel::LowRes { 0 } else { 1 })?;
+                payload.write_u32::<LittleEndian>(*width as u32)?;
+                payload.write_u32::<LittleEndian>(*height as u32)?;
+                payload.write_u32::<LittleEndian>(tiles.len() as u32)?;
+                for tile in tiles {
+                    for v in [tile.x, tile.y, tile.width, tile.height] {
+                        payload.write_u32::<LittleEndian>(v as u32)?;
+                    }
+                    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+                    encoder.write_all(&tile.rgb)?;
+                    let compressed = encoder.finish()?;
+                    payload.write_u32::<LittleEndian>(compressed.len() as u32)?;
+                    payload.extend_from_slice(&compressed);
+                }
+                4
+            },
+            AgentMessage::Stack => 2,
+            AgentMessage::Status(text) => { payload.extend_from_slice(text.as_bytes()); 3 },
+            AgentMessage::Error(text) => { payload.extend_from_slice(text.as_bytes()); 5 },
+        };
+        stream.write_u8(kind)?;
+        stream.write_u64::<LittleEndian>(payload.len() as u64)?;
+        stream.write_all(&payload)?;
+        stream.flush()?;
+        Ok(())
+    }
+
+    /// Returns None if connection is closed
+    fn read(stream: &mut impl Read) -> anyhow::Result<Option<AgentMessage>> {
+        let kind = match stream.read_u8() {
+            Ok(kind) => kind,
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let len = stream.read_u64::<LittleEndian>()?;
+        let payload = read_bytes(
+            stream,
+            len.min(usize::MAX as u64) as usize,
+            MAX_MESSAGE_SIZE as usize,
+            "Agent message"
+        )?;
+
+        fn read_name(slice: &mut &[u8]) -> anyhow::Result<String> {
+            let name_len = slice.read_u16::<LittleEndian>()? as usize;
+            if name_len > slice.len() {
+                anyhow::bail!("Wrong file name length");
+            }
+            let name = String::from_utf8(slice[..name_len].to_vec())?;
+            *slice = &slice[name_len..];
+            Ok(name)
+        }
+
+        let message = match kind {
+            1 => {
+                let mut slice = payload.as_slice();
+                let name = read_name(&mut slice)?;
+                AgentMessage::File { name, data: slice.to_vec() }
+            },
+            2 => AgentMessage::Stack,
+            3 => AgentMessage::Status(String::from_utf8_lossy(&payload).to_string()),
+            4 => {
+                let mut slice = payload.as_slice();
+                let name = read_name(&mut slice)?;
+                let level = if slice.read_u8()? == 0 { PreviewLevel::LowRes } else { PreviewLevel::Full };
+                let width = slice.read_u32::<LittleEndian>()? as usize;
+                let height = slice.read_u32::<LittleEndian>()? as usize;
+                checked_image_size(width, height, 3, 1)?;
+                let tiles_count = slice.read_u32::<LittleEndian>()?;
+                let mut tiles = Vec::new();
+                for _ in 0..tiles_count {
+                    let mut crd = [0_usize; 4];
+                    for v in &mut crd {
+                        *v = slice.read_u32::<LittleEndian>()? as usize;
+                    }
+                    let [x, y, width, height] = crd;
+                    let compressed_len = slice.read_u32::<LittleEndian>()? as usize;
+                    if compressed_len > slice.len() {
+                        anyhow::bail!("Wrong preview tile size");
+                    }
+                    let mut rgb = Vec::new();
+                    ZlibDecoder::new(&slice[..compressed_len])
+                        .take(3 * (width * height) as u64)
+                        .read_to_end(&mut rgb)?;
+                    slice = &slice[compressed_len..];
+                    tiles.push(PreviewTile { x, y, width, height, rgb });
+                }
+                AgentMessage::Preview { name, level, width, height, tiles }
+            },
+            5 => AgentMessage::Error(String::from_utf8_lossy(&payload).to_string()),
+            _ => anyhow::bail!("Wrong agent message type {}", kind),
+        };
+        Ok(Some(message))
+    }
+}
+
+// Only file name is taken from client to not allow writing outside of directory
+fn safe_file_name(name: &str) -> anyhow::Result<String> {
+    let file_name = Path::new(name)
+        .file_name()
+        .and_then(|s| s.to_str())
+        .filter(|s| !s.starts_with('.'))
+        .ok_or_else(|| anyhow::anyhow!("Wrong file name {:?}", name))?;
+    Ok(file_name.to_string())
+}
+
+struct Agent {
+    project:      Project,
+    project_file: PathBuf,
+    files_dir:    PathBuf,
+    config:       Config,
+    progress:     ProgressTs,
+    cancel_flag:  IsCancelledFun,
+    sent_preview: Option<PreviewImage>, // full preview client already has
+}
+
+impl Agent {
+    fn process_file(&mut self, name: &str, data: &[u8]) -> anyhow::Result<String> {
+        let file_name = self.files_dir.join(safe_file_name(name)?);
+        if !is_source_file_name(&file_name) {
+            anyhow::bail!("File {} has unsupported type", name);
+        }
+        write_file_atomically(&file_name, |tmp_file_name| {
+            std::fs::write(tmp_file_name, data)?;
+            Ok(())
+        })?;
+        log::info!("File {:?} received", file_name);
+
+        let mut new_files = vec![file_name];
+        self.project.groups()[0].light_files.retain_files_if_they_are_not_here(&mut new_files);
+        if new_files.is_empty() {
+            return Ok(format!("{}: already in project", name));
+        }
+        let report = add_and_prepare_light_files(
+            &mut self.project,
+            &self.project_file,
+            new_files,
+            &self.config,
+            &self.progress,
+            &self.cancel_flag
+        )?;
+        Ok(report.join("\n"))
+    }
+
+    fn stack_and_send_preview(&mut self, writer: &mut impl Write) -> anyhow::Result<()> {
+        let result = self.project.stack_light_files(
+            &self.progress,
+            &self.cancel_flag,
+            self.config.effective_cpu_load(),
+            ResumeMode::Resume,
+            &StackMapsOpts::default()
+        )?;
+        log::info!("Result file saved to {:?}", result.file_name);
+
+        let ImageData { image: RawOrImage::Image(image), .. } =
+            load_image_from_file(&result.file_name, false)? else {
+            anyhow::bail!("Result file is not image");
+        };
+        let name = format!(
+            "{}_preview.tif",
+            result.file_name.file_stem().and_then(|s| s.to_str()).unwrap_or("result")
+        );
+
+        let low_res = PreviewImage::new(&image, MAX_LOW_RES_PREVIEW_WIDTH, self.config.preview_gamma);
+        AgentMessage::Preview {
+            name: name.clone(),
+            level: PreviewLevel::LowRes,
+            width: low_res.width,
+            height: low_res.height,
+            tiles: low_res.changed_tiles(None),
+        }.write(writer)?;
+
+        let full = PreviewImage::new(&image, MAX_PREVIEW_WIDTH, self.config.preview_gamma);
+        let tiles = full.changed_tiles(self.sent_preview.as_ref());
+        log::info!("{} tiles of preview are sent", tiles.len());
+        AgentMessage::Preview {
+            name,
+            level: PreviewLevel::Full,
+            width: full.width,
+            height: full.height,
+            tiles,
+        }.write(writer)?;
+        self.sent_preview = Some(full);
+        Ok(())
+    }
+
+    fn serve_client(&mut self, stream: TcpStream) -> anyhow::Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream);
+
+        let mut magic = [0_u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != AGENT_MAGIC {
+            anyhow::bail!("Wrong client protocol");
+        }
+        writer.write_all(AGENT_MAGIC)?;
+        writer.flush()?;
+        self.sent_preview = None;
+
+        while let Some(message) = AgentMessage::read(&mut reader)? {
+            let answer = match message {
+                AgentMessage::File { name, data } =>
+                    self.process_file(&name, &data).map(|text| Some(AgentMessage::Status(text))),
+                AgentMessage::Stack =>
+                    self.stack_and_send_preview(&mut writer).map(|_| None),
+                _ =>
+                    Err(anyhow::anyhow!("Unexpected message from client")),
+            };
+            let answer = answer.unwrap_or_else(|err| {
+                log::error!("{}", err.to_string());
+                Some(AgentMessage::Error(err.to_string()))
+            });
+            if let Some(answer) = answer {
+                answer.write(&mut writer)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn run_agent(args: &BatchArgs) -> anyhow::Result<()> {
+    let config = load_config(args)?;
+
+    let mut project = Project::default();
+    if args.file_name.is_file() {
+        project.load(&args.file_name)?;
+    }
+    project.add_default_group_if_empty();
+
+    let files_dir = args.watch_dir.clone().unwrap_or_else(|| {
+        let stem = args.file_name.file_stem().and_then(|s| s.to_str()).unwrap_or("agent");
+        args.file_name.with_file_name(format!("{}_lights", stem))
+    });
+    if !files_dir.is_dir() {
+        std::fs::create_dir_all(&files_dir)?;
+    }
+
+    let mut agent = Agent {
+        project,
+        project_file: args.file_name.clone(),
+        files_dir,
+        config,
+        progress: ProgressConsole::new_ts(),
+        cancel_flag: Arc::new(|| false),
+        sent_preview: None,
+    };
+
+    let listener = TcpListener::bind(&args.listen)?;
+    log::info!("Agent for project {:?} listens on {}", args.file_name, args.listen);
+    report!("Agent listens on {} (press Ctrl+C to stop)...", args.listen);
+
+    // Clients are served one by one because all of them work with the same project
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => { log::error!("{}", err.to_string()); continue; }
+        };
+        let peer = stream.peer_addr().map(|a| a.to_string()).unwrap_or_default();
+        report!("Client {} connected", peer);
+        if let Err(err) = agent.serve_client(stream) {
+            log::error!("{}", err.to_string());
+            report!("Client {}: {}", peer, err.to_string());
+        }
+        report!("Client {} disconnected", peer);
+    }
+    Ok(())
+}
+
+fn read_answer(reader: &mut impl Read) -> anyhow::Result<AgentMessage> {
+    match AgentMessage::read(reader)? {
+        Some(AgentMessage::Error(text)) => anyhow::bail!("Agent: {}", text),
+        Some(answer) => Ok(answer),
+        None => anyhow::bail!("Connection is closed by agent"),
+    }
+}
+
+fn request(
+    reader:  &mut impl Read,
+    writer:  &mut impl Write,
+    message: AgentMessage
+) -> anyhow::Result<AgentMessage> {
+    message.write(writer)?;
+    read_answer(reader)
+}
+
+/// Receives low resolution preview and then changed tiles of full preview
+fn receive_preview(
+    reader:  &mut impl Read,
+    preview: &mut Option<PreviewImage>,
+    out_dir: &Path,
+) -> anyhow::Result<()> {
+    for expected_level in [PreviewLevel::LowRes, PreviewLevel::Full] {
+        let AgentMessage::Preview { name, level, width, height, tiles } = read_answer(reader)? else {
+            anyhow::bail!("Wrong answer from agent");
+        };
+        if level != expected_level {
+            anyhow::bail!("Wrong preview level from agent");
+        }
+        let new_image = || PreviewImage { width, height, rgb: vec![0; 3 * width * height] };
+        let mut image = match level {
+            PreviewLevel::LowRes =>
+                new_image(),
+            PreviewLevel::Full =>
+                preview.take()
+                    .filter(|p| p.width == width && p.height == height)
+                    .unwrap_or_else(new_image),
+        };
+        for tile in &tiles {
+            image.put_tile(tile)?;
+        }
+        let preview_file = out_dir.join(safe_file_name(&name)?);
+        image.save_to_file(&preview_file)?;
+        if level == PreviewLevel::Full {
+            report!(
+                "Preview saved to {} ({} tiles updated)",
+                preview_file.to_str().unwrap_or(""), tiles.len()
+            );
+            *preview = Some(image);
+        }
+    }
+    Ok(())
+}
+
+pub fn run_agent_client(args: &BatchArgs) -> anyhow::Result<()> {
+    let address = args.file_name.to_str().unwrap_or_default();
+    let watch_dir = args.watch_dir.as_ref().unwrap();
+    let out_dir = args.out.clone().unwrap_or_else(|| PathBuf::from("."));
+
+    let stream = TcpStream::connect(address)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    writer.write_all(AGENT_MAGIC)?;
+    writer.flush()?;
+    let mut magic = [0_u8; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != AGENT_MAGIC {
+        anyhow::bail!("{} is not electra stacking agent", address);
+    }
+    log::info!("Connected to agent {}", address);
+
+    let mut prev_sizes = HashMap::new();
+    let mut sent_files = HashSet::new();
+    let mut preview = None;
+
+    report!("Sending files from {} to {} (press Ctrl+C to stop)...", watch_dir.to_str().unwrap_or(""), address);
+    loop {
+        let new_files: Vec<_> = find_completely_written_files(watch_dir, &mut prev_sizes)?
+            .into_iter()
+            .filter(|f| !sent_files.contains(f))
+            .collect();
+
+        if !new_files.is_empty() {
+            for file_name in new_files {
+                let name = extract_file_name(&file_name).to_string();
+                report!("Sending {}...", name);
+                let data = std::fs::read(&file_name)?;
+                match request(&mut reader, &mut writer, AgentMessage::File { name, data }) {
+                    Ok(AgentMessage::Status(text)) => report!("{}", text),
+                    Ok(_) => anyhow::bail!("Wrong answer from agent"),
+                    Err(err) => report!("{}", err.to_string()),
+                }
+                sent_files.insert(file_name);
+            }
+
+            report!("Stacking...");
+            AgentMessage::Stack.write(&mut writer)?;
+            if let Err(err) = receive_preview(&mut reader, &mut preview, &out_dir) {
+                report!("{}", err.to_string());
+            }
+        }
+
+        std::thread::sleep(Duration::from_secs(args.interval.max(1)));
+    }
+}
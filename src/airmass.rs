@@ -0,0 +1,113 @@
+use serde::*;
+use chrono::prelude::*;
+use crate::{image::*, image_io::*, wcs::*};
+
+/* Airmass of light files by coordinates of observation site and target
+   and time of shot. Light files shot at different airmass differ in
+   color because blue light is absorbed by atmosphere more than red.
+   Differential extinction correction scales channels of light files
+   to airmass of reference image before normalization */
+
+/// Geographic coordinates of observation site in degrees (longitude is east positive)
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct SiteCoords {
+    pub lat: f64,
+    pub lon: f64,
+}
+
+impl SiteCoords {
+    /// "<latitude>,<longitude>" in degrees or sexagesimal
+    pub fn from_str(text: &str) -> anyhow::Result<Self> {
+        let Some((lat, lon)) = text.split_once(',') else {
+            anyhow::bail!("Wrong site coordinates {} (<latitude>,<longitude>)", text);
+        };
+        let lat = parse_site_angle(lat)
+            .filter(|v| (-90.0..=90.0).contains(v))
+            .ok_or_else(|| anyhow::anyhow!("Wrong latitude {}", lat))?;
+        let lon = parse_site_angle(lon)
+            .ok_or_else(|| anyhow::anyhow!("Wrong longitude {}", lon))?;
+        Ok(Self { lat, lon })
+    }
+}
+
+/// Equatorial coordinates of target in degrees
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct EqCoords {
+    pub ra:  f64,
+    pub dec: f64,
+}
+
+impl EqCoords {
+    /// "<RA>,<DEC>" as for --ra and --dec of --plate-solve
+    pub fn from_str(text: &str) -> anyhow::Result<Self> {
+        let Some((ra, dec)) = text.split_once(',') else {
+            anyhow::bail!("Wrong target coordinates {} (<RA>,<DEC>)", text);
+        };
+        Ok(Self { ra: parse_ra(ra)?, dec: parse_dec(dec)? })
+    }
+}
+
+/// Degrees or sexagesimal degrees ("+45 30 00") of SITELAT and SITELONG keywords
+pub fn parse_site_angle(text: &str) -> Option<f64> {
+    text.trim().parse::<f64>().ok().or_else(|| parse_sexagesimal(text))
+}
+
+/// Typical extinction coefficients of R, G and B (magnitudes per airmass)
+pub const DEFAULT_EXTINCTION: [f32; 3] = [0.10, 0.15, 0.25];
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct AirmassOpts {
+    pub site:       Option<SiteCoords>, // for light files without SITELAT and SITELONG
+    pub target:     Option<EqCoords>,   // for light files without RA and DEC
+    pub extinction: Option<[f32; 3]>,   // R, G, B coefficients. None - no correction
+}
+
+impl AirmassOpts {
+    /// Airmass of light file by time of shot and coordinates from
+    /// header of file or from options. None if target is below horizon
+    pub fn airmass_for(&self, info: &ImageInfo) -> Option<f64> {
+        let site = info.site.or(self.site)?;
+        let target = info.target.or(self.target)?;
+        let time = info.file_time?;
+        calc_airmass(&site, &target, &time.with_timezone(&Utc))
+    }
+}
+
+pub fn julian_date(time: &DateTime<Utc>) -> f64 {
+    time.timestamp() as f64 / 86400.0
+        + time.timestamp_subsec_nanos() as f64 / 86400e9
+        + 2440587.5
+}
+
+/// Altitude of target above horizon in degrees
+pub fn calc_altitude(site: &SiteCoords, target: &EqCoords, time: &DateTime<Utc>) -> f64 {
+    let gmst = 280.46061837 + 360.98564736629 * (julian_date(time) - 2451545.0);
+    let hour_angle = (gmst + site.lon - target.ra).to_radians();
+    let (lat, dec) = (site.lat.to_radians(), target.dec.to_radians());
+    let sin_alt = lat.sin() * dec.sin() + lat.cos() * dec.cos() * hour_angle.cos();
+    sin_alt.clamp(-1.0, 1.0).asin().to_degrees()
+}
+
+/// Airmass by Pickering (2002) formula which is valid down to horizon
+pub fn calc_airmass(site: &SiteCoords, target: &EqCoords, time: &DateTime<Utc>) -> Option<f64> {
+    let alt = calc_altitude(site, target, time);
+    if alt <= 0.0 { return None; }
+    let arg = alt + 244.0 / (165.0 + 47.0 * alt.powf(1.1));
+    Some(1.0 / arg.to_radians().sin())
+}
+
+/// Scales channels of RGB image shot at `airmass` to brightness
+/// they would have at `ref_airmass`
+pub fn correct_extinction(image: &mut Image, coeffs: [f32; 3], airmass: f64, ref_airmass: f64) {
+    if !image.is_rgb() { return; }
+    let diff = (airmass - ref_airmass) as f32;
+    for (layer, k) in [&mut image.r, &mut image.g, &mut image.b].into_iter().zip(coeffs) {
+        let factor = 10_f32.powf(0.4 * k * diff);
+        for v in layer.iter_mut() {
+            if v.is_finite() && *v != NO_VALUE_F32 {
+                *v *= factor;
+            }
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use std::path::*;
+use rayon::prelude::*;
+use serde::*;
+use crate::{image::*, image_io::*, image_raw::*, fs_utils::*, str_utils::*, stacking_utils::transform_matrix, resample::*};
+
+/* Warping of light files by precomputed transforms. Transforms can be
+   produced by `--register --transforms-only` or by other software */
+
+/// Affine transform from light file to reference image coordinates
+pub struct TransformRecord {
+    pub file:   PathBuf,
+    pub matrix: [[f64; 3]; 2],
+}
+
+#[derive(Deserialize)]
+struct JsonRecord {
+    file:     PathBuf,
+    matrix:   Option<[[f64; 3]; 2]>,
+    offset_x: Option<f64>,
+    offset_y: Option<f64>,
+    angle:    Option<f64>, // radians
+    width:    Option<Crd>,
+    height:   Option<Crd>,
+}
+
+impl JsonRecord {
+    fn into_record(self) -> anyhow::Result<TransformRecord> {
+        let matrix = match (self.matrix, self.offset_x, self.offset_y, self.width, self.height) {
+            (Some(matrix), ..) =>
+                matrix,
+            (None, Some(offset_x), Some(offset_y), Some(width), Some(height)) =>
+                transform_matrix(width, height, offset_x, offset_y, self.angle.unwrap_or(0.0)),
+            _ => anyhow::bail!(
+                "Transform of {} has no matrix or offset with image size",
+                self.file.to_str().unwrap_or("")
+            ),
+        };
+        Ok(TransformRecord { file: self.file, matrix })
+    }
+}
+
+fn load_csv(text: &str) -> anyhow::Result<Vec<JsonRecord>> {
+    let mut lines = text.lines().filter(|l| !l.trim().is_empty());
+    let header_line = lines.next().unwrap_or("");
+    // `;` separates columns if decimal separator is comma
+    let separator = if header_line.contains(';') { ';' } else { ',' };
+    let header: Vec<String> = parse_csv_line(header_line, separator)
+        .into_iter()
+        .map(|s| s.trim().to_lowercase())
+        .collect();
+    let col = |name: &str| header.iter().position(|h| h == name);
+    let file_col = col("file").ok_or_else(|| anyhow::anyhow!("Column 'file' is not found in CSV"))?;
+    let matrix_cols: Option<Vec<usize>> = ["m00", "m01", "m02", "m10", "m11", "m12"]
+        .iter()
+        .map(|name| col(name))
+        .collect();
+
+    let mut result = Vec::new();
+    for line in lines {
+        let values = parse_csv_line(line, separator);
+        let float = |index: Option<usize>| -> anyhow::Result<Option<f64>> {
+            match index.and_then(|i| values.get(i)) {
+                Some(v) => Ok(Some(v.trim().replace(',', ".").parse()?)),
+                None => Ok(None),
+            }
+        };
+        let matrix = match &matrix_cols {
+            Some(cols) => {
+                let m: Vec<f64> = cols.iter()
+                    .map(|&c| float(Some(c)).map(|v| v.unwrap_or(0.0)))
+                    .collect::<anyhow::Result<_>>()?;
+                Some([[m[0], m[1], m[2]], [m[3], m[4], m[5]]])
+            }
+            None => None,
+        };
+        result.push(JsonRecord {
+            file: PathBuf::from(values.get(file_col).map(|s| s.trim()).unwrap_or("")),
+            matrix,
+            offset_x: float(col("offset_x"))?,
+            offset_y: float(col("offset_y"))?,
+            angle: float(col("angle"))?,
+            width: float(col("width"))?.map(|v| v as Crd),
+            height: float(col("height"))?.map(|v| v as Crd),
+        });
+    }
+    Ok(result)
+}
+
+/// Loads transforms from JSON or CSV file. Relative file names are relative to transforms file
+pub fn load_transforms_file(file_name: &Path) -> anyhow::Result<Vec<TransformRecord>> {
+    let text = std::fs::read_to_string(file_name)?;
+    let records = if extract_extension(file_name).eq_ignore_ascii_case("csv") {
+        load_csv(&text)?
+    } else {
+        serde_json::from_str(&text)?
+    };
+    let base_dir = file_name.parent().unwrap_or(Path::new("."));
+    records.into_iter()
+        .map(|r| r.into_record())
+        .map(|r| r.map(|r| TransformRecord { file: base_dir.join(&r.file), ..r }))
+        .collect()
+}
+
+fn warp_layer(layer: &ImageLayerF32, inv: &[[f64; 3]; 2], kernel: ResampleKernel, default_value: f32) -> ImageLayerF32 {
+    if layer.is_empty() { return ImageLayerF32::new_empty(); }
+    let width = layer.width();
+    let mut result = ImageLayerF32::new(width, layer.height());
+    result.as_slice_mut()
+        .par_chunks_mut(width as usize)
+        .enumerate()
+        .for_each(|(y, row)| {
+            let y = y as f64;
+            for (x, v) in row.iter_mut().enumerate() {
+                let x = x as f64;
+                let sx = inv[0][0] * x + inv[0][1] * y + inv[0][2];
+                let sy = inv[1][0] * x + inv[1][1] * y + inv[1][2];
+                *v = interpolate_pixel(layer, sx, sy, kernel).unwrap_or(default_value);
+            }
+        });
+    result
+}
+
+/// Warps image into reference image coordinates. With flux conserving kernel
+/// result pixels are integrated over source pixels instead of interpolation
+pub fn apply_transform(image: &Image, matrix: &[[f64; 3]; 2], kernel: ResampleKernel) -> anyhow::Result<Image> {
+    let [[a, b, c], [d, e, f]] = *matrix;
+    let det = a * e - b * d;
+    if det.abs() < 1e-12 {
+        anyhow::bail!("Transform matrix is degenerate");
+    }
+    // result pixel is taken from inverse transformed point of source image
+    let inv = [
+        [ e / det, -b / det, (b * f - c * e) / det],
+        [-d / det,  a / det, (c * d - a * f) / det],
+    ];
+    let warp = |layer: &ImageLayerF32| if kernel == ResampleKernel::FluxConserving {
+        warp_layer_flux_conserving(layer, &inv, layer.width(), layer.height(), 0.0)
+    } else {
+        warp_layer(layer, &inv, kernel, 0.0)
+    };
+    Ok(Image {
+        l: warp(&image.l),
+        r: warp(&image.r),
+        g: warp(&image.g),
+        b: warp(&image.b),
+    })
+}
+
+/// Loads light file (RAW is demosaiced) and warps it by transform
+pub fn load_and_apply_transform(
+    transform: &TransformRecord,
+    kernel:    ResampleKernel,
+) -> anyhow::Result<(Image, ImageInfo)> {
+    let ImageData { image, info } = load_image_from_file(&transform.file, false)?;
+    let image = match image {
+        RawOrImage::Image(image) => image,
+        RawOrImage::Raw(raw) => raw.demosaic(DemosaicAlgo::Linear, true)?,
+    };
+    Ok((apply_transform(&image, &transform.matrix, kernel)?, info))
+}
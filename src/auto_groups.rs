@@ -0,0 +1,220 @@
+use std::{path::*, collections::BTreeMap};
+use crate::{project::*, image_io::*, progress::*, fs_utils::*};
+
+/* Creation of project from directory with mixed light and calibration
+   files. Type of file is taken from IMAGETYP keyword or from names of
+   subdirectories (lights, darks, flats, bias). Light files are grouped by
+   FILTER, EXPTIME, GAIN and CCD-TEMP keywords and every group gets dark
+   files with same exposure, gain and temperature, flat files with same
+   filter and gain and bias files with same gain */
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum GroupKey {
+    Filter,
+    Exposure,
+    Gain,
+    Temperature,
+}
+
+impl GroupKey {
+    /// "filter,exptime,gain,temp"
+    pub fn list_from_str(text: &str) -> anyhow::Result<Vec<GroupKey>> {
+        text.split(',')
+            .map(|item| match item.trim().to_lowercase().as_str() {
+                "filter"              => Ok(GroupKey::Filter),
+                "exptime"|"exposure"  => Ok(GroupKey::Exposure),
+                "gain"|"iso"          => Ok(GroupKey::Gain),
+                "temp"|"ccd-temp"     => Ok(GroupKey::Temperature),
+                _ => anyhow::bail!("Wrong group key {} (filter, exptime, gain or temp)", item),
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AutoGroupOpts {
+    pub keys:      Vec<GroupKey>,
+    pub temp_step: f32, // °C, temperatures are rounded to this step
+}
+
+impl Default for AutoGroupOpts {
+    fn default() -> Self {
+        Self {
+            keys: vec![GroupKey::Filter, GroupKey::Exposure, GroupKey::Gain, GroupKey::Temperature],
+            temp_step: 2.0,
+        }
+    }
+}
+
+pub struct AutoGroup {
+    pub name:   String,
+    pub lights: usize,
+    pub darks:  usize,
+    pub flats:  usize,
+    pub biases: usize,
+}
+
+pub struct AutoGroupsResult {
+    pub project: Project,
+    pub groups:  Vec<AutoGroup>,
+    pub skipped: Vec<PathBuf>, // files of unsupported type (dark flats)
+}
+
+fn file_type_from_str(text: &str) -> Option<Option<ProjectFileType>> {
+    let text = text.to_lowercase();
+    if text.contains("flat") && text.contains("dark") {
+        Some(None) // dark flats are not supported
+    } else if text.contains("light") || text.contains("object") {
+        Some(Some(ProjectFileType::Light))
+    } else if text.contains("dark") {
+        Some(Some(ProjectFileType::Dark))
+    } else if text.contains("flat") {
+        Some(Some(ProjectFileType::Flat))
+    } else if text.contains("bias") || text.contains("offset") || text.contains("zero") {
+        Some(Some(ProjectFileType::Bias))
+    } else {
+        None
+    }
+}
+
+/// Type by IMAGETYP keyword, then by names of subdirectories.
+/// Files of unknown type are light files
+fn detect_file_type(info: &ImageInfo, dir: &Path) -> Option<ProjectFileType> {
+    if let Some(file_type) = info.frame_type.as_deref().and_then(file_type_from_str) {
+        return file_type;
+    }
+    let rel_path = info.file_name.strip_prefix(dir).unwrap_or(&info.file_name);
+    for dir_name in rel_path.parent().iter().flat_map(|p| p.iter()) {
+        if let Some(file_type) = dir_name.to_str().and_then(file_type_from_str) {
+            return file_type;
+        }
+    }
+    Some(ProjectFileType::Light)
+}
+
+fn round_temp(temp: f32, step: f32) -> f32 {
+    if step <= 0.0 { return temp; }
+    (temp / step).round() * step
+}
+
+fn same_exposure(exp1: Option<f64>, exp2: Option<f64>) -> bool {
+    match (exp1, exp2) {
+        (Some(e1), Some(e2)) => (e1 - e2).abs() <= 0.01 * e1.max(e2).max(1.0),
+        _ => true,
+    }
+}
+
+fn same_opt<T: PartialEq>(v1: &Option<T>, v2: &Option<T>) -> bool {
+    match (v1, v2) {
+        (Some(v1), Some(v2)) => v1 == v2,
+        _ => true,
+    }
+}
+
+// Key of group of light files. Values are in string form to be comparable
+fn group_key(info: &ImageInfo, opts: &AutoGroupOpts) -> Vec<String> {
+    opts.keys.iter().map(|key| match key {
+        GroupKey::Filter      => info.filter.clone().unwrap_or_default(),
+        GroupKey::Exposure    => info.exp.map(|v| format!("{}s", v)).unwrap_or_default(),
+        GroupKey::Gain        => info.iso.map(|v| format!("gain {}", v)).unwrap_or_default(),
+        GroupKey::Temperature => info.temperature
+            .map(|v| format!("{}°C", round_temp(v, opts.temp_step)))
+            .unwrap_or_default(),
+    }).collect()
+}
+
+pub fn create_project_from_dir(
+    dir:          &Path,
+    opts:         &AutoGroupOpts,
+    progress:     &ProgressTs,
+    is_cancelled: &IsCancelledFun,
+) -> anyhow::Result<AutoGroupsResult> {
+    let mut file_names = Vec::new();
+    collect_image_files(dir, &mut file_names)?;
+    file_names.sort();
+    if file_names.is_empty() {
+        anyhow::bail!("No image files in directory {}", path_to_str(dir));
+    }
+    let infos = load_src_file_info_for_files(&file_names, None, is_cancelled, progress)?;
+
+    let mut lights = BTreeMap::<Vec<String>, Vec<ImageInfo>>::new();
+    let mut cal_files = Vec::new();
+    let mut skipped = Vec::new();
+    for info in infos {
+        match detect_file_type(&info, dir) {
+            Some(ProjectFileType::Light) =>
+                lights.entry(group_key(&info, opts)).or_default().push(info),
+            Some(file_type) =>
+                cal_files.push((file_type, info)),
+            None => {
+                log::info!("File {} is skipped: unsupported type", path_to_str(&info.file_name));
+                skipped.push(info.file_name);
+            },
+        }
+    }
+
+    let mut project = Project::default();
+    let mut groups = Vec::new();
+    for (key, light_infos) in lights {
+        let name = key.iter().filter(|v| !v.is_empty()).cloned().collect::<Vec<_>>().join(" ");
+        let first = &light_infos[0];
+        let temp = first.temperature.map(|t| round_temp(t, opts.temp_step));
+        let matches = |file_type: ProjectFileType, info: &ImageInfo| match file_type {
+            ProjectFileType::Dark =>
+                same_exposure(first.exp, info.exp)
+                && same_opt(&first.iso, &info.iso)
+                && same_opt(&temp, &info.temperature.map(|t| round_temp(t, opts.temp_step))),
+            ProjectFileType::Flat =>
+                same_opt(&first.filter, &info.filter)
+                && same_opt(&first.iso, &info.iso),
+            ProjectFileType::Bias =>
+                same_opt(&first.iso, &info.iso),
+            ProjectFileType::Light =>
+                false,
+        };
+        project.add_new_group(GroupOptions {
+            name: if name.is_empty() { None } else { Some(name.clone()) },
+        });
+        let group_index = project.groups().len() - 1;
+        let mut group = AutoGroup { name, lights: light_infos.len(), darks: 0, flats: 0, biases: 0 };
+        for file_type in [ProjectFileType::Dark, ProjectFileType::Flat, ProjectFileType::Bias] {
+            let infos: Vec<ImageInfo> = cal_files.iter()
+                .filter(|(t, info)| *t == file_type && matches(file_type, info))
+                .map(|(_, info)| info.clone())
+                .collect();
+            if infos.is_empty() {
+                log::warn!("No {:?} files for group {}", file_type, group.name);
+                continue;
+            }
+            match file_type {
+                ProjectFileType::Dark => group.darks = infos.len(),
+                ProjectFileType::Flat => group.flats = infos.len(),
+                _                     => group.biases = infos.len(),
+            }
+            project.group_by_index_mut(group_index)
+                .file_list_by_type_mut(file_type)
+                .add_files_from_src_file_info(infos);
+        }
+        project.group_by_index_mut(group_index)
+            .file_list_by_type_mut(ProjectFileType::Light)
+            .add_files_from_src_file_info(light_infos);
+        groups.push(group);
+    }
+    project.add_default_group_if_empty();
+    Ok(AutoGroupsResult { project, groups, skipped })
+}
+
+pub fn collect_image_files(dir: &Path, result: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_image_files(&path, result)?;
+            continue;
+        }
+        let ext = extract_extension(&path);
+        if is_raw_ext(ext) || is_tiff_ext(ext) || is_fits_ext(ext) || is_xisf_ext(ext) {
+            result.push(path);
+        }
+    }
+    Ok(())
+}
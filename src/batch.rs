@@ -0,0 +1,2935 @@
+use std::{path::*, sync::Arc, ops::RangeInclusive, collections::HashMap, time::Duration};
+use gettextrs::*;
+use crate::{config::*, progress::*, project::*, stacking_utils::*, suggest::*, ser::*, fs_utils::*, image_io::*, agent::*, preview::*, image::*, live_sessions::*, image_stat::*, color_calibr::*, perf_report::*, run_report::*, gradient::*, scnr::*, plate_solve::*, wcs::*, apply_transform::*, mosaic::*, duoband::*, siril_script::*, dss_filelist::*, ha_blend::*, resample::*, geometry::*, exposure_map::*, colormap::*, isophotes::*, sky_flat::*, sky_limit::*, power::*, planetary::*, deconv::*, hdr::*, wavelets::*, star_mask::*, bg_mask::*, auto_groups::*, cal_library::*, airmass::*, obs_report::*, plugins::*, presets::*, lrgb::*, timelapse::*, image_diff::*, photometry::*, star_shape::*, config_layers::*, integration_meta::*, dataset_check::*, log_utils::LogOpts, report};
+
+/* Running of whole stacking workflow from project file without GUI */
+
+#[derive(PartialEq)]
+pub enum BatchMode {
+    Run,
+    Suggest,
+    ExtractSer,
+    Watch,
+    Agent,
+    AgentSend,
+    Preview,
+    WatchMulti,
+    Stat,
+    ColorCalibrate,
+    RemoveGradient,
+    Scnr,
+    Register,
+    PlateSolve,
+    ApplyTransform,
+    Mosaic,
+    ExtractDuoBand,
+    SirilScript,
+    ImportDss,
+    BlendHa,
+    Resample,
+    Geometry,
+    ExposureMap,
+    Colormap,
+    Isophotes,
+    MakeSkyFlat,
+    SkyLimit,
+    LiveStack,
+    StackPlanetary,
+    Deconvolve,
+    MergeHdr,
+    StackGroups,
+    Wavelets,
+    AutoStretch,
+    StarMask,
+    GroupDir,
+    CalLibrary,
+    ExportObs,
+    StackLrgb,
+    Check,
+    Timelapse,
+    Diff,
+    Photometry,
+}
+
+impl BatchMode {
+    /// Commands which load image, process it and save result
+    fn is_image_processing(&self) -> bool {
+        matches!(self, BatchMode::ColorCalibrate|BatchMode::RemoveGradient|BatchMode::Scnr|BatchMode::Mosaic|BatchMode::ExtractDuoBand|BatchMode::BlendHa|BatchMode::Resample|BatchMode::Geometry|BatchMode::Deconvolve|BatchMode::MergeHdr|BatchMode::Wavelets|BatchMode::AutoStretch)
+    }
+}
+
+pub struct BatchArgs {
+    pub mode:      BatchMode,
+    pub file_name: PathBuf, // address of agent for --agent-send
+    pub cleanup:   bool,
+    pub force:     bool,
+    pub ignore_incompatible: bool, // errors of compatibility check are warnings
+    pub write:     bool,
+    pub frames:    Option<RangeInclusive<usize>>,
+    pub out:       Option<PathBuf>, // output directory or file
+    pub compress:  Option<FitsCompression>,
+    pub bitpix:    Option<FitsBitPix>,
+    pub compat:    Option<FitsCompat>,
+    pub quantization: Option<Quantization>,
+    pub pedestal:  Option<f32>,
+    pub output_range: Option<OutputRange>,
+    pub bg_mask:   Option<BgMaskPreset>,
+    pub sub_stacks: Option<u32>, // minutes
+    pub flat_drift: bool,
+    pub flat_norm: Option<FlatNorm>,
+    pub watch_dir: Option<PathBuf>,
+    pub interval:  u64,
+    pub listen:    String,
+    pub hdu:       Option<String>,
+    pub biassec:   Option<String>,
+    pub trimsec:   Option<String>,
+    pub preview:   bool,
+    pub stretch:   Option<PreviewStretch>,
+    pub max_width: Option<usize>,
+    pub sessions:  Vec<WatchSession>,
+    pub max_parallel: Option<usize>,
+    pub json:      bool,
+    pub bins:      usize,
+    pub cancel_flag: IsCancelledFun, // set by jobs server
+    pub cc_mode:   ColorCalibrMode,
+    pub bg_region: Option<BgRegion>,
+    pub perf_report: bool,
+    pub run_report: Option<PathBuf>, // JSON report of --run, --register or --stack-groups
+    pub gradient:  GradientOpts,
+    pub model_out: Option<PathBuf>,
+    pub scnr_amount: f32,
+    pub chroma_nr: Option<usize>, // radius
+    pub transforms_only: bool,
+    pub plate_solve: PlateSolveOpts,
+    pub files:     Vec<PathBuf>, // panels for --mosaic
+    pub mosaic:    MosaicOpts,
+    pub green_weight: f32, // part of green in OIII for --extract-duoband
+    pub ha_file:   Option<PathBuf>,
+    pub ha_blend:  HaBlendOpts,
+    pub bin:       usize,
+    pub bin_mode:  BinMode,
+    pub scale:     Option<f64>,
+    pub kernel:    ResampleKernel,
+    pub interpolation: Option<ResampleKernel>, // for --run and --apply-transform
+    pub geometry:  Vec<GeometryOp>, // in order of command line
+    pub contours:  usize,
+    pub colormap:  String, // name or LUT file
+    pub isophotes: IsophoteOpts,
+    pub sky_flat:  SkyFlatOpts,
+    pub reference: Option<PathBuf>, // reference light file
+    pub bias_file: Option<PathBuf>,
+    pub sky_limit: SkyLimitOpts,
+    pub stack_maps: StackMapsOpts,
+    pub power_profile: Option<PowerProfile>,
+    pub planetary: PlanetaryOpts,
+    pub deconv:    DeconvOpts,
+    pub short_file: Option<PathBuf>, // short exposure stack for --merge-hdr
+    pub hdr:       HdrOpts,
+    pub master_group: Option<String>, // name or number of group for --stack-groups
+    pub crop_common: bool,
+    pub wavelets:  WaveletOpts,
+    pub auto_stretch: StretchOpts, // black point and strength for --auto-stretch
+    pub star_mask: StarMaskOpts,
+    pub mask_file: Option<PathBuf>, // star mask for --remove-gradient and --deconvolve
+    pub auto_groups: AutoGroupOpts,
+    pub stack:     bool, // stack groups after creation of project by --group-dir
+    pub cal_library: Option<PathBuf>, // masters for groups without calibration files
+    pub cal_tolerances: CalMatchTolerances,
+    pub cal_lib_add: Option<PathBuf>, // project which masters are added by --cal-library
+    pub site:      Option<SiteCoords>,
+    pub target:    Option<EqCoords>,
+    pub extinction: Option<[f32; 3]>,
+    pub obs_report: ObsReportOpts,
+    pub gpu:       Option<String>, // "auto", index or part of name of adapter
+    pub deterministic: bool, // disables GPU (results of CPU are reproducible)
+    pub plugins:   Vec<PluginOpts>,
+    pub hot_pixels_by_lights: bool,
+    pub streaming: bool, // two-pass integration of light files read one by one
+    pub preset:    Option<Preset>,
+    pub lrgb_files: Vec<(LrgbChannel, PathBuf)>, // directory, image or list file of every channel
+    pub lrgb:      LrgbOpts,
+    pub print_config: bool, // print effective settings and their sources instead of running
+    pub align_mode: Option<AlignMode>,
+    pub timelapse: TimelapseOpts,
+    pub diff: DiffOpts,
+    pub photometry: PhotometryOpts,
+    pub phot_stars: Vec<StarPos>, // target, comparison and check stars
+    pub comp_mag: Option<f64>, // catalog magnitude of comparison star
+    pub fwhm_map: Option<PathBuf>,
+    pub eccentricity_map: Option<PathBuf>,
+}
+
+impl BatchArgs {
+    pub fn from_cmd_line(args: &[String]) -> anyhow::Result<Option<BatchArgs>> {
+        let mode = match args.get(1).map(|s| s.as_str()) {
+            Some("--run") => BatchMode::Run,
+            Some("--analyze-and-suggest") => BatchMode::Suggest,
+            Some("--extract-ser") => BatchMode::ExtractSer,
+            Some("--watch") => BatchMode::Watch,
+            Some("--agent") => BatchMode::Agent,
+            Some("--agent-send") => BatchMode::AgentSend,
+            Some("--preview") => BatchMode::Preview,
+            Some("--watch-multi") => BatchMode::WatchMulti,
+            Some("--stat") => BatchMode::Stat,
+            Some("--color-calibrate") => BatchMode::ColorCalibrate,
+            Some("--remove-gradient") => BatchMode::RemoveGradient,
+            Some("--scnr") => BatchMode::Scnr,
+            Some("--register") => BatchMode::Register,
+            Some("--plate-solve") => BatchMode::PlateSolve,
+            Some("--apply-transform") => BatchMode::ApplyTransform,
+            Some("--mosaic") => BatchMode::Mosaic,
+            Some("--extract-duoband") => BatchMode::ExtractDuoBand,
+            Some("--siril-script") => BatchMode::SirilScript,
+            Some("--import-dss") => BatchMode::ImportDss,
+            Some("--blend-ha") => BatchMode::BlendHa,
+            Some("--resample") => BatchMode::Resample,
+            Some("--geometry") => BatchMode::Geometry,
+            Some("--exposure-map") => BatchMode::ExposureMap,
+            Some("--colormap") => BatchMode::Colormap,
+            Some("--isophotes") => BatchMode::Isophotes,
+            Some("--make-sky-flat") => BatchMode::MakeSkyFlat,
+            Some("--sky-limit") => BatchMode::SkyLimit,
+            Some("--live-stack") => BatchMode::LiveStack,
+            Some("--stack-planetary") => BatchMode::StackPlanetary,
+            Some("--deconvolve") => BatchMode::Deconvolve,
+            Some("--merge-hdr") => BatchMode::MergeHdr,
+            Some("--stack-groups") => BatchMode::StackGroups,
+            Some("--wavelets") => BatchMode::Wavelets,
+            Some("--auto-stretch") => BatchMode::AutoStretch,
+            Some("--star-mask") => BatchMode::StarMask,
+            Some("--group-dir") => BatchMode::GroupDir,
+            Some("--cal-library") => BatchMode::CalLibrary,
+            Some("--export-obs") => BatchMode::ExportObs,
+            Some("--stack-lrgb") => BatchMode::StackLrgb,
+            Some("--check") => BatchMode::Check,
+            Some("--timelapse") => BatchMode::Timelapse,
+            Some("--diff") => BatchMode::Diff,
+            Some("--photometry") => BatchMode::Photometry,
+            _ => return Ok(None),
+        };
+        let mut file_name = None;
+        let mut cleanup = false;
+        let mut force = false;
+        let mut ignore_incompatible = false;
+        let mut write = false;
+        let mut frames = None;
+        let mut out = None;
+        let mut compress = None;
+        let mut bitpix = None;
+        let mut compat = None;
+        let mut quantization = None;
+        let mut pedestal = None;
+        let mut output_range = None;
+        let mut bg_mask = None;
+        let mut sub_stacks = None;
+        let mut flat_drift = false;
+        let mut flat_norm = None;
+        let mut watch_dir = None;
+        let mut interval = 10;
+        let mut listen = DEFAULT_AGENT_ADDRESS.to_string();
+        let mut hdu = None;
+        let mut biassec = None;
+        let mut trimsec = None;
+        let mut preview = false;
+        let mut stretch = None;
+        let mut max_width = None;
+        let mut sessions = Vec::new();
+        let mut max_parallel = None;
+        let mut json = false;
+        let mut bins = 64;
+        let mut cc_mode = ColorCalibrMode::Stars;
+        let mut bg_region = None;
+        let mut perf_report = false;
+        let mut run_report = None;
+        let mut gradient = GradientOpts::default();
+        let mut model_out = None;
+        let mut scnr_amount = 1.0;
+        let mut chroma_nr = None;
+        let mut transforms_only = false;
+        let mut plate_solve = PlateSolveOpts::default();
+        let mut files = Vec::new();
+        let mut mosaic = MosaicOpts::default();
+        let mut green_weight = 0.5;
+        let mut ha_file = None;
+        let mut ha_blend = HaBlendOpts::default();
+        let mut bin = 1;
+        let mut bin_mode = BinMode::Average;
+        let mut scale = None;
+        let mut kernel = ResampleKernel::Lanczos3;
+        let mut interpolation = None;
+        let mut geometry = Vec::new();
+        let mut contours = ExposureMapOpts::default().contours;
+        let mut colormap = "viridis".to_string();
+        let mut isophotes = IsophoteOpts::default();
+        let mut sky_flat = SkyFlatOpts::default();
+        let mut reference = None;
+        let mut bias_file = None;
+        let mut sky_limit = SkyLimitOpts::default();
+        let mut stack_maps = StackMapsOpts::default();
+        let mut power_profile = None;
+        let mut planetary = PlanetaryOpts::default();
+        let mut deconv = DeconvOpts::default();
+        let mut short_file = None;
+        let mut hdr = HdrOpts::default();
+        let mut master_group = None;
+        let mut crop_common = false;
+        let mut wavelets = WaveletOpts::default();
+        let mut auto_stretch = StretchOpts::default();
+        let mut star_mask = StarMaskOpts::default();
+        let mut mask_file = None;
+        let mut auto_groups = AutoGroupOpts::default();
+        let mut stack = false;
+        let mut cal_library = None;
+        let mut cal_tolerances = CalMatchTolerances::default();
+        let mut cal_lib_add = None;
+        let mut site = None;
+        let mut target = None;
+        let mut extinction = None;
+        let mut obs_report = ObsReportOpts::default();
+        let mut gpu = None;
+        let mut deterministic = false;
+        let mut plugins = Vec::new();
+        let mut plugin_io = None;
+        let mut hot_pixels_by_lights = false;
+        let mut streaming = false;
+        let mut lrgb_files = Vec::new();
+        let mut lrgb = LrgbOpts::default();
+        let mut print_config = false;
+        let mut align_mode = None;
+        let mut timelapse = TimelapseOpts::default();
+        let mut diff = DiffOpts::default();
+        let mut photometry = PhotometryOpts::default();
+        let mut phot_target = None;
+        let mut phot_comp = None;
+        let mut phot_check = None;
+        let mut comp_mag = None;
+        let mut fwhm_map = None;
+        let mut eccentricity_map = None;
+
+        // preset defines defaults which are overridden by individual options
+        let preset = args[2..].iter()
+            .position(|arg| arg == "--preset")
+            .map(|idx| args.get(idx + 3)
+                .ok_or_else(|| anyhow::anyhow!("Value for --preset is not defined"))
+                .and_then(|value| Preset::from_str(value))
+            )
+            .transpose()?;
+        if let Some(preset) = preset {
+            let defaults = preset.batch_defaults();
+            interval = defaults.interval.unwrap_or(interval);
+            stretch = defaults.stretch;
+            max_width = defaults.max_width;
+            crop_common = defaults.crop_common;
+            planetary = defaults.planetary.unwrap_or(planetary);
+        }
+
+        let mut args_iter = args[2..].iter();
+        while let Some(arg) = args_iter.next() {
+            let mut get_value = || args_iter.next().ok_or_else(||
+                anyhow::anyhow!("Value for {} is not defined", arg)
+            );
+            match arg.as_str() {
+                "--cleanup" if mode == BatchMode::Run => cleanup = true,
+                "--force" if mode != BatchMode::ExtractSer => force = true,
+                "--ignore-incompatible" if matches!(mode, BatchMode::Run|BatchMode::Register|BatchMode::Suggest|BatchMode::StackGroups|BatchMode::StackLrgb) =>
+                    ignore_incompatible = true,
+                "--write" if mode == BatchMode::Suggest => write = true,
+                "--frames" if mode == BatchMode::ExtractSer =>
+                    frames = Some(parse_frames_range(get_value()?)?),
+                "--out" if matches!(mode, BatchMode::Photometry|BatchMode::Diff|BatchMode::Timelapse|BatchMode::StackLrgb|BatchMode::StarMask|BatchMode::ExtractSer|BatchMode::AgentSend|BatchMode::Preview|BatchMode::Register|BatchMode::ApplyTransform|BatchMode::ImportDss|BatchMode::GroupDir|BatchMode::ExportObs|BatchMode::ExposureMap|BatchMode::Colormap|BatchMode::Isophotes|BatchMode::MakeSkyFlat|BatchMode::LiveStack|BatchMode::StackPlanetary) || mode.is_image_processing() =>
+                    out = Some(PathBuf::from(get_value()?)),
+                "--compress" if matches!(mode, BatchMode::Run|BatchMode::StackLrgb|BatchMode::ExtractSer|BatchMode::ApplyTransform|BatchMode::MakeSkyFlat|BatchMode::StackPlanetary) || mode.is_image_processing() =>
+                    compress = Some(FitsCompression::from_str(get_value()?)?),
+                "--output-bitpix" if matches!(mode, BatchMode::Run|BatchMode::StackLrgb|BatchMode::ExtractSer|BatchMode::ApplyTransform|BatchMode::StackPlanetary) || mode.is_image_processing() =>
+                    bitpix = Some(FitsBitPix::from_str(get_value()?)?),
+                "--compat" if matches!(mode, BatchMode::Run|BatchMode::ExtractSer|BatchMode::ApplyTransform|BatchMode::StackPlanetary) || mode.is_image_processing() =>
+                    compat = Some(FitsCompat::from_str(get_value()?)?),
+                "--quantization" if matches!(mode, BatchMode::Run|BatchMode::ExtractSer|BatchMode::ApplyTransform|BatchMode::StackPlanetary) || mode.is_image_processing() =>
+                    quantization = Some(Quantization::from_str(get_value()?)?),
+                "--pedestal" if matches!(mode, BatchMode::Run|BatchMode::ExtractSer|BatchMode::ApplyTransform|BatchMode::StackPlanetary) || mode.is_image_processing() =>
+                    pedestal = Some(get_value()?.parse()?),
+                "--output-range" if matches!(mode, BatchMode::Run|BatchMode::ExtractSer|BatchMode::ApplyTransform|BatchMode::StackPlanetary) || mode.is_image_processing() =>
+                    output_range = Some(OutputRange::from_str(get_value()?)?),
+                "--dir" if matches!(mode, BatchMode::Watch|BatchMode::Agent|BatchMode::AgentSend|BatchMode::SirilScript|BatchMode::LiveStack) =>
+                    watch_dir = Some(PathBuf::from(get_value()?)),
+                "--interval" if matches!(mode, BatchMode::Watch|BatchMode::AgentSend|BatchMode::WatchMulti|BatchMode::LiveStack) =>
+                    interval = get_value()?.parse()?,
+                "--listen" if mode == BatchMode::Agent =>
+                    listen = get_value()?.to_string(),
+                "--hdu" if matches!(mode, BatchMode::Run|BatchMode::Register|BatchMode::Suggest|BatchMode::StackGroups|BatchMode::Check|BatchMode::CalLibrary|BatchMode::StackLrgb|BatchMode::Watch|BatchMode::LiveStack) =>
+                    hdu = Some(get_value()?.to_string()),
+                "--power-profile" if !matches!(mode, BatchMode::ExtractSer|BatchMode::AgentSend) =>
+                    power_profile = Some(PowerProfile::from_str(get_value()?)?),
+                "--gpu" if !matches!(mode, BatchMode::ExtractSer|BatchMode::AgentSend) =>
+                    gpu = Some(get_value()?.to_string()),
+                "--deterministic" if !matches!(mode, BatchMode::ExtractSer|BatchMode::AgentSend) =>
+                    deterministic = true,
+                "--biassec" if !matches!(mode, BatchMode::ExtractSer|BatchMode::AgentSend) => {
+                    let value = get_value()?;
+                    FitsSection::from_str(value)?;
+                    biassec = Some(value.to_string());
+                },
+                "--trimsec" if !matches!(mode, BatchMode::ExtractSer|BatchMode::AgentSend) => {
+                    let value = get_value()?;
+                    FitsSection::from_str(value)?;
+                    trimsec = Some(value.to_string());
+                },
+                "--preview" if mode == BatchMode::Run =>
+                    preview = true,
+                "--perf-report" if mode == BatchMode::Run =>
+                    perf_report = true,
+                "--report" if matches!(mode, BatchMode::Run|BatchMode::Register|BatchMode::StackGroups) =>
+                    run_report = Some(PathBuf::from(get_value()?)),
+                "--stretch" if matches!(mode, BatchMode::Run|BatchMode::Preview|BatchMode::Colormap|BatchMode::LiveStack|BatchMode::AutoStretch|BatchMode::Timelapse) =>
+                    stretch = Some(PreviewStretch::from_str(get_value()?)?),
+                "--max-width" if matches!(mode, BatchMode::Run|BatchMode::Preview|BatchMode::ExposureMap|BatchMode::Colormap|BatchMode::LiveStack|BatchMode::Timelapse) =>
+                    max_width = Some(get_value()?.parse()?),
+                "--max-parallel" if mode == BatchMode::WatchMulti =>
+                    max_parallel = Some(get_value()?.parse()?),
+                "--json" if matches!(mode, BatchMode::Stat|BatchMode::Check) =>
+                    json = true,
+                "--bins" if mode == BatchMode::Stat =>
+                    bins = get_value()?.parse()?,
+                "--fwhm-map" if mode == BatchMode::Stat =>
+                    fwhm_map = Some(PathBuf::from(get_value()?)),
+                "--eccentricity-map" if mode == BatchMode::Stat =>
+                    eccentricity_map = Some(PathBuf::from(get_value()?)),
+                "--mode" if mode == BatchMode::ColorCalibrate =>
+                    cc_mode = ColorCalibrMode::from_str(get_value()?)?,
+                "--bg-region" if mode == BatchMode::ColorCalibrate =>
+                    bg_region = Some(BgRegion::from_str(get_value()?)?),
+                "--model" if mode == BatchMode::RemoveGradient =>
+                    gradient.model = GradientModel::from_str(get_value()?)?,
+                "--correction" if mode == BatchMode::RemoveGradient =>
+                    gradient.correction = GradientCorrection::from_str(get_value()?)?,
+                "--grid" if mode == BatchMode::RemoveGradient =>
+                    gradient.grid = get_value()?.parse()?,
+                "--tile" if mode == BatchMode::RemoveGradient =>
+                    gradient.tile = Some(get_value()?.parse()?),
+                "--bg-mask" if matches!(mode, BatchMode::Run|BatchMode::RemoveGradient) =>
+                    bg_mask = Some(BgMaskPreset::from_str(get_value()?)?),
+                "--sub-stacks" if matches!(mode, BatchMode::Run|BatchMode::StackGroups) =>
+                    sub_stacks = Some(get_value()?.parse()?),
+                "--flat-drift" if mode == BatchMode::Run =>
+                    flat_drift = true,
+                "--flat-norm" if mode == BatchMode::Run =>
+                    flat_norm = Some(FlatNorm::from_str(get_value()?)?),
+                "--hot-pixels-by-lights" if mode == BatchMode::Run =>
+                    hot_pixels_by_lights = true,
+                "--streaming" if matches!(mode, BatchMode::Run|BatchMode::StackGroups) =>
+                    streaming = true,
+                "--preset" if matches!(mode, BatchMode::Run|BatchMode::Register|BatchMode::StackGroups|BatchMode::StackLrgb|BatchMode::Watch|BatchMode::LiveStack|BatchMode::StackPlanetary) => {
+                    get_value()?; // already parsed
+                },
+                "--fps" if mode == BatchMode::Timelapse =>
+                    timelapse.fps = get_value()?.parse()?,
+                "--threshold" if mode == BatchMode::Diff =>
+                    diff.threshold = get_value()?.parse()?,
+                "--min-pixels" if mode == BatchMode::Diff =>
+                    diff.min_pixels = get_value()?.parse()?,
+                "--target" if mode == BatchMode::Photometry =>
+                    phot_target = Some(StarPos::from_str(get_value()?)?),
+                "--comp" if mode == BatchMode::Photometry =>
+                    phot_comp = Some(StarPos::from_str(get_value()?)?),
+                "--check" if mode == BatchMode::Photometry =>
+                    phot_check = Some(StarPos::from_str(get_value()?)?),
+                "--comp-mag" if mode == BatchMode::Photometry =>
+                    comp_mag = Some(get_value()?.parse()?),
+                "--aperture" if mode == BatchMode::Photometry =>
+                    photometry.aperture = get_value()?.parse()?,
+                "--annulus" if mode == BatchMode::Photometry => {
+                    let value = get_value()?;
+                    let (inner, outer) = value.split_once(',')
+                        .ok_or_else(|| anyhow::anyhow!("Wrong annulus {} (<inner>,<outer>)", value))?;
+                    photometry.annulus = (inner.trim().parse()?, outer.trim().parse()?);
+                },
+                "--no-recenter" if mode == BatchMode::Photometry =>
+                    photometry.recenter = false,
+                "--align-mode" if matches!(mode, BatchMode::Run|BatchMode::Register) =>
+                    align_mode = Some(AlignMode::from_str(get_value()?)?),
+                "--print-config" if matches!(mode, BatchMode::Run|BatchMode::Register|BatchMode::StackGroups|BatchMode::Watch|BatchMode::LiveStack) =>
+                    print_config = true,
+                "--cal-library" if matches!(mode, BatchMode::Run|BatchMode::Register|BatchMode::StackGroups|BatchMode::StackLrgb) =>
+                    cal_library = Some(PathBuf::from(get_value()?)),
+                "--temp-tolerance" if matches!(mode, BatchMode::Run|BatchMode::Register|BatchMode::StackGroups|BatchMode::StackLrgb) =>
+                    cal_tolerances.temperature = get_value()?.parse()?,
+                "--add" if mode == BatchMode::CalLibrary =>
+                    cal_lib_add = Some(PathBuf::from(get_value()?)),
+                "--site" if matches!(mode, BatchMode::Run|BatchMode::Photometry) =>
+                    site = Some(SiteCoords::from_str(get_value()?)?),
+                "--target" if mode == BatchMode::Run =>
+                    target = Some(EqCoords::from_str(get_value()?)?),
+                "--extinction" if mode == BatchMode::Run =>
+                    extinction = Some(parse_extinction(get_value()?)?),
+                "--plugin" if mode == BatchMode::Run =>
+                    plugins.push(PluginOpts::from_str(get_value()?)?),
+                "--plugin-io" if mode == BatchMode::Run =>
+                    plugin_io = Some(PluginIo::from_str(get_value()?)?),
+                "--format" if mode == BatchMode::ExportObs =>
+                    obs_report.format = ObsReportFormat::from_str(get_value()?)?,
+                "--obscode" if mode == BatchMode::ExportObs =>
+                    obs_report.obs_code = Some(get_value()?.to_string()),
+                "--star" if mode == BatchMode::ExportObs =>
+                    obs_report.star = Some(get_value()?.to_string()),
+                "--filter" if mode == BatchMode::ExportObs =>
+                    obs_report.filter = get_value()?.to_string(),
+                "--obstype" if mode == BatchMode::ExportObs =>
+                    obs_report.obs_type = get_value()?.to_string(),
+                "--transformed" if mode == BatchMode::ExportObs =>
+                    obs_report.transformed = true,
+                "--mtype" if mode == BatchMode::ExportObs =>
+                    obs_report.mag_type = get_value()?.to_string(),
+                "--comp" if mode == BatchMode::ExportObs =>
+                    obs_report.comp = Some(get_value()?.to_string()),
+                "--check" if mode == BatchMode::ExportObs =>
+                    obs_report.check = Some(get_value()?.to_string()),
+                "--chart" if mode == BatchMode::ExportObs =>
+                    obs_report.chart = Some(get_value()?.to_string()),
+                "--notes" if mode == BatchMode::ExportObs =>
+                    obs_report.notes = Some(get_value()?.to_string()),
+                "--model-out" if mode == BatchMode::RemoveGradient =>
+                    model_out = Some(PathBuf::from(get_value()?)),
+                "--amount" if mode == BatchMode::Scnr =>
+                    scnr_amount = get_value()?.parse()?,
+                "--chroma-nr" if mode == BatchMode::Scnr =>
+                    chroma_nr = Some(get_value()?.parse()?),
+                "--transforms-only" if mode == BatchMode::Register =>
+                    transforms_only = true,
+                "--ra" if mode == BatchMode::PlateSolve =>
+                    plate_solve.ra = Some(parse_ra(get_value()?)?),
+                "--dec" if mode == BatchMode::PlateSolve =>
+                    plate_solve.dec = Some(parse_dec(get_value()?)?),
+                "--radius" if mode == BatchMode::PlateSolve =>
+                    plate_solve.radius = get_value()?.parse()?,
+                "--scale-low" if mode == BatchMode::PlateSolve =>
+                    plate_solve.scale_low = Some(get_value()?.parse()?),
+                "--scale-high" if mode == BatchMode::PlateSolve =>
+                    plate_solve.scale_high = Some(get_value()?.parse()?),
+                "--solver" if mode == BatchMode::PlateSolve =>
+                    plate_solve.solver = PathBuf::from(get_value()?),
+                "--timeout" if mode == BatchMode::PlateSolve =>
+                    plate_solve.timeout = get_value()?.parse()?,
+                "--feather" if mode == BatchMode::Mosaic =>
+                    mosaic.feather = get_value()?.parse()?,
+                "--green-weight" if mode == BatchMode::ExtractDuoBand =>
+                    green_weight = get_value()?.parse()?,
+                "--ha" if mode == BatchMode::BlendHa =>
+                    ha_file = Some(PathBuf::from(get_value()?)),
+                "--blend" if mode == BatchMode::BlendHa =>
+                    ha_blend.mode = HaBlendMode::from_str(get_value()?)?,
+                "--strength" if mode == BatchMode::BlendHa =>
+                    ha_blend.strength = get_value()?.parse()?,
+                "--lum" if mode == BatchMode::StackLrgb =>
+                    lrgb_files.push((LrgbChannel::L, PathBuf::from(get_value()?))),
+                "--red" if mode == BatchMode::StackLrgb =>
+                    lrgb_files.push((LrgbChannel::R, PathBuf::from(get_value()?))),
+                "--green" if mode == BatchMode::StackLrgb =>
+                    lrgb_files.push((LrgbChannel::G, PathBuf::from(get_value()?))),
+                "--blue" if mode == BatchMode::StackLrgb =>
+                    lrgb_files.push((LrgbChannel::B, PathBuf::from(get_value()?))),
+                "--ha" if mode == BatchMode::StackLrgb =>
+                    lrgb_files.push((LrgbChannel::Ha, PathBuf::from(get_value()?))),
+                "--oiii" if mode == BatchMode::StackLrgb =>
+                    lrgb_files.push((LrgbChannel::Oiii, PathBuf::from(get_value()?))),
+                "--sii" if mode == BatchMode::StackLrgb =>
+                    lrgb_files.push((LrgbChannel::Sii, PathBuf::from(get_value()?))),
+                "--lum-weight" if mode == BatchMode::StackLrgb =>
+                    lrgb.lum_weight = get_value()?.parse()?,
+                "--blend" if mode == BatchMode::StackLrgb =>
+                    lrgb.nb_blend.mode = HaBlendMode::from_str(get_value()?)?,
+                "--strength" if mode == BatchMode::StackLrgb =>
+                    lrgb.nb_blend.strength = get_value()?.parse()?,
+                "--reconstruct-cores" if mode == BatchMode::StackLrgb =>
+                    lrgb.reconstruct_cores = true,
+                "--desaturate-clipped" if mode == BatchMode::StackLrgb =>
+                    lrgb.desaturate_clipped = true,
+                "--bin" if mode == BatchMode::Resample =>
+                    bin = get_value()?.parse()?,
+                "--bin-mode" if mode == BatchMode::Resample =>
+                    bin_mode = BinMode::from_str(get_value()?)?,
+                "--scale" if mode == BatchMode::Resample =>
+                    scale = Some(get_value()?.parse()?),
+                "--kernel" if mode == BatchMode::Resample =>
+                    kernel = ResampleKernel::from_str(get_value()?)?,
+                "--flux-conserving" if mode == BatchMode::ApplyTransform =>
+                    interpolation = Some(ResampleKernel::FluxConserving),
+                "--interpolation" if mode == BatchMode::Mosaic =>
+                    mosaic.kernel = point_interpolation_kernel(get_value()?)?,
+                "--interpolation" if mode == BatchMode::Run =>
+                    interpolation = Some(point_interpolation_kernel(get_value()?)?),
+                "--interpolation" if mode == BatchMode::ApplyTransform =>
+                    interpolation = Some(ResampleKernel::from_str(get_value()?)?),
+                "--crop" if mode == BatchMode::Geometry =>
+                    geometry.push(GeometryOp::crop_from_str(get_value()?)?),
+                "--auto-crop" if mode == BatchMode::Geometry =>
+                    geometry.push(GeometryOp::AutoCrop),
+                "--rotate" if mode == BatchMode::Geometry =>
+                    geometry.push(GeometryOp::Rotate(get_value()?.parse()?)),
+                "--flip" if mode == BatchMode::Geometry =>
+                    geometry.push(GeometryOp::flip_from_str(get_value()?)?),
+                "--contours" if mode == BatchMode::ExposureMap =>
+                    contours = get_value()?.parse()?,
+                "--map" if mode == BatchMode::Colormap =>
+                    colormap = get_value()?.to_string(),
+                "--center" if mode == BatchMode::Isophotes => {
+                    let value = get_value()?;
+                    let (x, y) = value.split_once(',')
+                        .ok_or_else(|| anyhow::anyhow!("Wrong center {} (x,y expected)", value))?;
+                    isophotes.center = Some((x.trim().parse()?, y.trim().parse()?));
+                },
+                "--min-sma" if mode == BatchMode::Isophotes =>
+                    isophotes.min_sma = get_value()?.parse()?,
+                "--max-sma" if mode == BatchMode::Isophotes =>
+                    isophotes.max_sma = Some(get_value()?.parse()?),
+                "--smooth" if mode == BatchMode::MakeSkyFlat =>
+                    sky_flat.smooth = get_value()?.parse()?,
+                "--reference" if matches!(mode, BatchMode::Run|BatchMode::Register) =>
+                    reference = Some(PathBuf::from(get_value()?)),
+                "--rejection-map-low" if mode == BatchMode::Run =>
+                    stack_maps.rejection_low = Some(PathBuf::from(get_value()?)),
+                "--rejection-map-high" if mode == BatchMode::Run =>
+                    stack_maps.rejection_high = Some(PathBuf::from(get_value()?)),
+                "--weight-map" if mode == BatchMode::Run =>
+                    stack_maps.weight = Some(PathBuf::from(get_value()?)),
+                "--variance" if mode == BatchMode::Run =>
+                    stack_maps.variance = Some(VarianceModel::from_str(get_value()?)?),
+                "--best" if mode == BatchMode::StackPlanetary =>
+                    planetary.best_percent = get_value()?.parse()?,
+                "--ap-size" if mode == BatchMode::StackPlanetary =>
+                    planetary.ap_size = get_value()?.parse()?,
+                "--ap-search" if mode == BatchMode::StackPlanetary =>
+                    planetary.ap_search = get_value()?.parse()?,
+                "--iterations" if mode == BatchMode::Deconvolve =>
+                    deconv.iterations = get_value()?.parse()?,
+                "--psf" if mode == BatchMode::Deconvolve =>
+                    deconv.psf = PsfSource::from_str(get_value()?)?,
+                "--psf-fwhm" if mode == BatchMode::Deconvolve =>
+                    deconv.psf_fwhm = Some(get_value()?.parse()?),
+                "--eccentricity" if mode == BatchMode::Deconvolve =>
+                    deconv.eccentricity = Some(get_value()?.parse()?),
+                "--psf-angle" if mode == BatchMode::Deconvolve =>
+                    deconv.psf_angle = Some(get_value()?.parse()?),
+                "--damping" if mode == BatchMode::Deconvolve =>
+                    deconv.damping = get_value()?.parse()?,
+                "--regularization" if mode == BatchMode::Deconvolve =>
+                    deconv.regularization = get_value()?.parse()?,
+                "--protect-stars" if mode == BatchMode::Deconvolve =>
+                    deconv.protect_stars = Some(get_value()?.parse()?),
+                "--snapshot-every" if mode == BatchMode::Deconvolve =>
+                    deconv.snapshot_every = get_value()?.parse()?,
+                "--resume" if mode == BatchMode::Deconvolve =>
+                    deconv.resume = true,
+                "--short" if mode == BatchMode::MergeHdr =>
+                    short_file = Some(PathBuf::from(get_value()?)),
+                "--fit-range" if mode == BatchMode::MergeHdr =>
+                    (hdr.fit_low, hdr.fit_high) = parse_range(get_value()?)?,
+                "--blend-range" if mode == BatchMode::MergeHdr =>
+                    (hdr.blend_low, hdr.blend_high) = parse_range(get_value()?)?,
+                "--master-group" if mode == BatchMode::StackGroups =>
+                    master_group = Some(get_value()?.to_string()),
+                "--crop-common" if matches!(mode, BatchMode::StackGroups|BatchMode::GroupDir) =>
+                    crop_common = true,
+                "--group-by" if mode == BatchMode::GroupDir =>
+                    auto_groups.keys = GroupKey::list_from_str(get_value()?)?,
+                "--temp-step" if mode == BatchMode::GroupDir =>
+                    auto_groups.temp_step = get_value()?.parse()?,
+                "--stack" if mode == BatchMode::GroupDir =>
+                    stack = true,
+                "--layers" if mode == BatchMode::Wavelets =>
+                    wavelets.gains = WaveletOpts::parse_list(get_value()?)?,
+                "--denoise" if mode == BatchMode::Wavelets =>
+                    wavelets.denoise = WaveletOpts::parse_list(get_value()?)?,
+                "--black-point" if mode == BatchMode::AutoStretch =>
+                    auto_stretch.black_clip = get_value()?.parse()?,
+                "--strength" if mode == BatchMode::AutoStretch =>
+                    auto_stretch.target_bg = get_value()?.parse()?,
+                "--grow" if mode == BatchMode::StarMask =>
+                    star_mask.grow = get_value()?.parse()?,
+                "--feather" if mode == BatchMode::StarMask =>
+                    star_mask.feather = get_value()?.parse()?,
+                "--mag-limit" if mode == BatchMode::StarMask =>
+                    star_mask.mag_limit = Some(get_value()?.parse()?),
+                "--mask" if matches!(mode, BatchMode::RemoveGradient|BatchMode::Deconvolve) =>
+                    mask_file = Some(PathBuf::from(get_value()?)),
+                "--bias" if mode == BatchMode::SkyLimit =>
+                    bias_file = Some(PathBuf::from(get_value()?)),
+                "--gain" if mode == BatchMode::SkyLimit =>
+                    sky_limit.gain = Some(get_value()?.parse()?),
+                "--exposure" if mode == BatchMode::SkyLimit =>
+                    sky_limit.exposure = Some(get_value()?.parse()?),
+                "--swamp" if mode == BatchMode::SkyLimit =>
+                    sky_limit.swamp = get_value()?.parse()?,
+                _ if LogOpts::is_option(arg) =>
+                    anyhow::bail!("Logging option {} must be placed before command", arg),
+                _ if arg.starts_with("--") =>
+                    anyhow::bail!("Unknown command line argument {}", arg),
+                _ if mode == BatchMode::WatchMulti => {
+                    let session = WatchSession::from_str(arg)?;
+                    file_name.get_or_insert(session.project_file.clone());
+                    sessions.push(session);
+                },
+                _ if matches!(mode, BatchMode::Mosaic|BatchMode::ExposureMap|BatchMode::MakeSkyFlat|BatchMode::Diff) => {
+                    file_name.get_or_insert(PathBuf::from(arg));
+                    files.push(PathBuf::from(arg));
+                },
+                _ =>
+                    file_name = Some(PathBuf::from(arg)),
+            }
+        }
+        let file_name = file_name.ok_or_else(|| anyhow::anyhow!(
+            "Usage:\n  {0} --run <project file> [--cleanup] [--force] [--ignore-incompatible] [--compress none|rice|gzip] \
+            [--output-bitpix 8|16|64|-32|-64] [--preview [--stretch mtf|asinh] [--max-width <pixels>]] \
+            [--perf-report] [--report <JSON file>] [--reference <light file>] [--rejection-map-low <FITS file>] \
+            [--rejection-map-high <FITS file>] [--weight-map <FITS file>] \
+            [--variance <gain>,<read noise>] [--bg-mask stars|objects|aggressive] [--sub-stacks <minutes>] \
+            [--flat-drift] [--flat-norm percentile|global|channel|center] [--hot-pixels-by-lights] [--streaming] [--cal-library <directory> [--temp-tolerance <°C>]] [--site <latitude>,<longitude>] \
+            [--target <RA>,<DEC>] [--extinction default|<R>,<G>,<B>] \
+            [--plugin calibrated|result:<program> [<args>] ...] [--plugin-io stdio|file] \
+            [--align-mode stars|translation|wcs] [--interpolation <kernel>]\n  \
+            {0} --analyze-and-suggest <project file> [--write] [--force] [--ignore-incompatible]\n  \
+            {0} --check <project file> [--json]\n  \
+            {0} --register <project file> [--force] [--ignore-incompatible] [--reference <light file>] \
+            [--transforms-only [--out <json or csv file>] [--align-mode stars|translation|wcs]] \
+            [--report <JSON file>] [--cal-library <directory> [--temp-tolerance <°C>]]\n  \
+            {0} --apply-transform <json or csv file with transforms> [--out <directory>] [--flux-conserving|--interpolation <kernel>] \
+            [--compress none|rice|gzip] [--output-bitpix 8|16|64|-32|-64]\n  \
+            {0} --extract-ser <SER file> [--frames <first>-<last>] [--out <directory>] \
+            [--compress none|rice|gzip] [--output-bitpix 8|16|64|-32|-64]\n  \
+            {0} --watch <project file> --dir <capture directory> [--interval <seconds>]\n  \
+            {0} --live-stack <project file> --dir <capture directory> [--interval <seconds>] \
+            [--out <png or jpg preview file>] [--stretch mtf|asinh] [--max-width <pixels>]\n  \
+            {0} --agent <project file> [--listen <address:port>] [--dir <directory for received files>]\n  \
+            {0} --agent-send <address:port> --dir <capture directory> [--interval <seconds>] \
+            [--out <directory for previews>]\n  \
+            {0} --preview <image file> [--out <png or jpg file>] [--stretch mtf|asinh] [--max-width <pixels>]\n  \
+            {0} --watch-multi <project file>=<capture directory> [<project file>=<capture directory> ...] \
+            [--interval <seconds>] [--max-parallel <count>]\n  \
+            {0} --stat <image file> [--json] [--bins <count>] [--fwhm-map <file>] [--eccentricity-map <file>]\n  \
+            {0} --color-calibrate <RGB image file> [--mode stars|gray-world|linear-fit] \
+            [--bg-region <x>,<y>,<width>,<height>] [--out <file>] [--compress none|rice|gzip] \
+            [--output-bitpix 8|16|64|-32|-64]\n  \
+            {0} --remove-gradient <image file> [--model poly1|poly2|poly3|poly4|rbf] \
+            [--correction subtract|divide] [--grid <boxes>] [--tile <pixels>] [--bg-mask stars|objects|aggressive] [--model-out <file>] [--mask <star mask file>] [--out <file>] \
+            [--compress none|rice|gzip] [--output-bitpix 8|16|64|-32|-64]\n  \
+            {0} --plate-solve <image file> [--ra <hh:mm:ss or degrees>] [--dec <dd:mm:ss or degrees>] \
+            [--radius <degrees>] [--scale-low <arcsec/pixel>] [--scale-high <arcsec/pixel>] \
+            [--solver <path to solve-field>] [--timeout <seconds>]\n  \
+            {0} --scnr <RGB image file> [--amount <0..1>] [--chroma-nr <radius>] [--out <file>] \
+            [--compress none|rice|gzip] [--output-bitpix 8|16|64|-32|-64]\n  \
+            {0} --mosaic <plate solved panel> <panel> [<panel> ...] [--feather <pixels>] [--interpolation <kernel>] [--out <file>] \
+            [--compress none|rice|gzip] [--output-bitpix 8|16|64|-32|-64]\n  \
+            {0} --extract-duoband <RGB image file> [--green-weight <0..1>] [--out <directory>] \
+            [--compress none|rice|gzip] [--output-bitpix 8|16|64|-32|-64]\n  \
+            {0} --siril-script <script file> [--dir <working directory>]\n  \
+            {0} --import-dss <DSS file list> [--out <project file>]\n  \
+            {0} --blend-ha <RGB image file> --ha <Ha image file> [--blend screen|lighten|linear] \
+            [--strength <0..1>] [--out <file>] [--compress none|rice|gzip] [--output-bitpix 8|16|64|-32|-64]\n  \
+            {0} --resample <image file> [--bin <factor> [--bin-mode average|sum]] [--scale <factor> \
+            [--kernel <kernel>]] [--out <file>] [--compress none|rice|gzip] [--output-bitpix 8|16|64|-32|-64]\n  \
+            {0} --geometry <image file> [--crop <x>,<y>,<width>,<height>] [--auto-crop] [--rotate <degrees>] \
+            [--flip h|v] [--out <file>] [--compress none|rice|gzip] [--output-bitpix 8|16|64|-32|-64]\n  \
+            {0} --exposure-map <plate solved image> [<image> ...] [--out <png or jpg file>] \
+            [--contours <count>] [--max-width <pixels>]\n  \
+            {0} --colormap <mono image file> [--map viridis|inferno|grey|<LUT file>] [--out <png or jpg file>] \
+            [--stretch mtf|asinh] [--max-width <pixels>]\n  \
+            {0} --timelapse <project file, directory or file list> [--out <gif or png file or directory>] \
+            [--fps <frames per second>] [--stretch mtf|asinh] [--max-width <pixels>]\n  \
+            {0} --diff <reference image> <new image> [--threshold <sigma>] [--min-pixels <count>] \
+            [--out <FITS file>]\n  \
+            {0} --photometry <project file, directory or file list> --target <x>,<y>|<RA>,<DEC> \
+            [--comp <x>,<y>|<RA>,<DEC> [--comp-mag <magnitude>]] [--check <x>,<y>|<RA>,<DEC>] \
+            [--aperture <pixels>] [--annulus <inner>,<outer>] [--no-recenter] [--site <latitude>,<longitude>] \
+            [--out <csv file>]\n  \
+            {0} --isophotes <image file> [--center <x>,<y>] [--min-sma <pixels>] [--max-sma <pixels>] \
+            [--out <csv file>]\n  \
+            {0} --make-sky-flat <light file> <light file> <light file> [...] [--smooth <cells>] \
+            [--out <FITS file>] [--compress none|rice|gzip]\n  \
+            {0} --sky-limit <light file> --bias <bias file> --gain <e-/ADU> [--exposure <seconds>] \
+            [--swamp <factor>]\n  \
+            {0} --stack-planetary <SER file> [--best <percent>] [--ap-size <pixels>] [--ap-search <pixels>] \
+            [--out <file>] [--compress none|rice|gzip] [--output-bitpix 8|16|64|-32|-64]\n  \
+            {0} --deconvolve <image file> [--iterations <count>] [--psf gaussian|stars] [--psf-fwhm <pixels>] \
+            [--eccentricity <0..1>] [--psf-angle <degrees>] [--damping <noise units>] [--regularization <weight>] \
+            [--protect-stars <min peak> | --mask <star mask file>] [--snapshot-every <iterations>] [--resume] [--out <file>] [--compress none|rice|gzip] \
+            [--output-bitpix 8|16|64|-32|-64]\n  \
+            {0} --merge-hdr <long exposure stack> --short <short exposure stack> [--fit-range <low>,<high>] \
+            [--blend-range <low>,<high>] [--out <file>] [--compress none|rice|gzip] [--output-bitpix 8|16|64|-32|-64]\n  \
+            {0} --stack-groups <project file> [--master-group <group name or number>] [--crop-common] [--force] [--ignore-incompatible] \
+            [--sub-stacks <minutes>] [--streaming] [--report <JSON file>] [--cal-library <directory> [--temp-tolerance <°C>]]\n  \
+            {0} --cal-library <directory> [--add <project file>]\n  \
+            {0} --export-obs <measurements CSV> [--format aavso|etd] [--obscode <code>] [--star <name>] \
+            [--filter <AAVSO filter>] [--obstype CCD|DSLR] [--transformed] [--mtype STD|DIF] [--comp <name>] \
+            [--check <name>] [--chart <chart id>] [--notes <text>] [--out <report file>]\n  \
+            {0} --group-dir <directory> [--group-by filter,exptime,gain,temp] [--temp-step <°C>] \
+            [--out <project file>] [--stack [--crop-common]]\n  \
+            {0} --stack-lrgb <project file to create> --red <files> --green <files> --blue <files> [--lum <files>] \
+            [--ha <files>] [--oiii <files>] [--sii <files>] [--lum-weight <0..1>] [--blend screen|lighten|linear] \
+            [--reconstruct-cores] [--desaturate-clipped] \
+            [--strength <0..1>] [--out <file>] [--force] [--ignore-incompatible] [--cal-library <directory> [--temp-tolerance <°C>]] \
+            [--compress none|rice|gzip] [--output-bitpix 8|16|64|-32|-64]\n  \
+            {0} --wavelets <image file> [--layers <gain>,<gain>,...] [--denoise <sigmas>,<sigmas>,...] \
+            [--out <file>] [--compress none|rice|gzip] [--output-bitpix 8|16|64|-32|-64]\n  \
+            {0} --auto-stretch <image file> [--stretch mtf|asinh] [--black-point <MAD units>] \
+            [--strength <background level>] [--out <FITS or TIFF file>] [--compress none|rice|gzip] \
+            [--output-bitpix 8|16|64|-32|-64]\n  \
+            {0} --star-mask <image file> [--grow <pixels>] [--feather <pixels>] [--mag-limit <magnitudes>] \
+            [--out <FITS file>]\n\
+            Commands working with project accept --hdu <index|EXTNAME> to select HDU of FITS light and calibration files \
+            and --biassec, --trimsec <[x1:x2,y1:y2]> to define overscan and trim regions of CCD FITS files. \
+            --power-profile performance|battery|auto reduces CPU usage of commands working with project. \
+            --gpu auto|<index>|<name> resamples and stacks light files on GPU (if built with gpu feature). \
+            --deterministic disables GPU (results of CPU are bit-reproducible between runs). \
+            Commands writing FITS files accept --compat pixinsight|siril|aps, \
+            --output-range 0..1|0..65535|input and --pedestal <value in units of output range>. \
+            --run, --register, --stack-groups, --stack-lrgb, --watch and --live-stack accept --preset dslr-osc|mono-lrgb|eaa-live, \
+            --stack-planetary accepts --preset planetary. \
+            --print-config prints effective settings of --run, --register, --stack-groups, --watch \
+            and --live-stack with their sources (default, config file, preset, project or command line). \
+            <kernel> of --interpolation and --kernel is nearest|bilinear|bicubic|lanczos3|lanczos4|bspline \
+            (flux is accepted by --resample and --apply-transform). \
+            Progress of commands shows percent and ETA of current stage (loading, registration, stacking, etc.), \
+            not of whole command. \
+            Logging options -v|-vv (verbose log and console output), -q|--quiet and --log-file <file> \
+            are placed before command",
+            env!("CARGO_PKG_NAME")
+        ))?;
+        if matches!(mode, BatchMode::Watch|BatchMode::AgentSend|BatchMode::LiveStack) && watch_dir.is_none() {
+            anyhow::bail!("Capture directory is not defined (--dir)");
+        }
+        if mode == BatchMode::Resample && bin <= 1 && scale.is_none() {
+            anyhow::bail!("Binning (--bin) or scale (--scale) is not defined");
+        }
+        if mode == BatchMode::Geometry && geometry.is_empty() {
+            anyhow::bail!("No geometry operations (--crop, --auto-crop, --rotate or --flip)");
+        }
+        if mode == BatchMode::BlendHa && ha_file.is_none() {
+            anyhow::bail!("Ha image is not defined (--ha)");
+        }
+        if mode == BatchMode::MergeHdr && short_file.is_none() {
+            anyhow::bail!("Short exposure stack is not defined (--short)");
+        }
+        if mode == BatchMode::AutoStretch && !(auto_stretch.target_bg > 0.0 && auto_stretch.target_bg < 1.0) {
+            anyhow::bail!("Strength (background level after stretch) must be in 0..1 range");
+        }
+        if mode == BatchMode::SkyLimit && bias_file.is_none() {
+            anyhow::bail!("Bias file is not defined (--bias)");
+        }
+        if mode == BatchMode::SkyLimit && sky_limit.gain.is_none() {
+            anyhow::bail!("Gain is not defined (--gain)");
+        }
+        if mode == BatchMode::StackLrgb {
+            for channel in [LrgbChannel::R, LrgbChannel::G, LrgbChannel::B] {
+                if !lrgb_files.iter().any(|(c, _)| *c == channel) {
+                    anyhow::bail!("Files of {} channel are not defined", channel.name());
+                }
+            }
+        }
+        if let Some(preset) = preset {
+            if preset.is_for_projects() == (mode == BatchMode::StackPlanetary) {
+                anyhow::bail!("Preset {} can't be used for this command", preset.name());
+            }
+        }
+        if mode == BatchMode::StackPlanetary && !(planetary.best_percent > 0.0 && planetary.best_percent <= 100.0) {
+            anyhow::bail!("Percent of best frames must be in 0..100 range");
+        }
+        let mut phot_stars = Vec::new();
+        if mode == BatchMode::Photometry {
+            let target = phot_target.ok_or_else(|| anyhow::anyhow!("Target star is not defined (--target)"))?;
+            if phot_check.is_some() && phot_comp.is_none() {
+                anyhow::bail!("Check star can be used only with comparison star (--comp)");
+            }
+            phot_stars.push(target);
+            phot_stars.extend(phot_comp);
+            phot_stars.extend(phot_check);
+        }
+        if let Some(plugin_io) = plugin_io {
+            plugins.iter_mut().for_each(|plugin| plugin.io = plugin_io);
+        }
+        Ok(Some(BatchArgs {
+            mode, file_name, cleanup, force, ignore_incompatible, write, frames,
+            out, compress, bitpix, compat, quantization, pedestal, output_range, bg_mask, sub_stacks, flat_drift, flat_norm, watch_dir, interval, listen, hdu, biassec, trimsec,
+            preview, stretch, max_width, sessions, max_parallel, json, bins,
+            cancel_flag: Arc::new(|| false),
+            cc_mode, bg_region, perf_report, run_report, gradient, model_out,
+            scnr_amount, chroma_nr, transforms_only, plate_solve, files, mosaic,
+            green_weight, ha_file, ha_blend, bin, bin_mode, scale, kernel, interpolation,
+            geometry, contours, colormap, isophotes, sky_flat, reference,
+            bias_file, sky_limit, stack_maps, power_profile, planetary, deconv,
+            short_file, hdr, master_group, crop_common, wavelets, auto_stretch, star_mask, mask_file,
+            auto_groups, stack, cal_library, cal_tolerances, cal_lib_add, site, target, extinction,
+            obs_report, gpu, deterministic, plugins, hot_pixels_by_lights, streaming, preset, lrgb_files, lrgb, print_config,
+            align_mode, timelapse, diff, photometry, phot_stars, comp_mag, fwhm_map, eccentricity_map,
+        }))
+    }
+}
+
+/// "default" or extinction coefficients of R, G and B channels:
+/// "0.25,0.18,0.12" -> [0.25, 0.18, 0.12]
+fn parse_extinction(text: &str) -> anyhow::Result<[f32; 3]> {
+    if text.eq_ignore_ascii_case("default") {
+        return Ok(DEFAULT_EXTINCTION);
+    }
+    let values: Vec<f32> = text.split(',')
+        .map(|v| v.trim().parse::<f32>())
+        .collect::<Result<_, _>>()?;
+    values.try_into().map_err(|_| anyhow::anyhow!("Wrong extinction coefficients {} (<R>,<G>,<B>)", text))
+}
+
+// "0.6,0.9" -> (0.6, 0.9)
+fn parse_range(text: &str) -> anyhow::Result<(f32, f32)> {
+    let err = || anyhow::anyhow!("Wrong range {} (<low>,<high> expected)", text);
+    let (low, high) = text.split_once(',').ok_or_else(err)?;
+    let low: f32 = low.trim().parse().map_err(|_| err())?;
+    let high: f32 = high.trim().parse().map_err(|_| err())?;
+    if low >= high {
+        return Err(err());
+    }
+    Ok((low, high))
+}
+
+// "10-200" -> 9..=199 (frames are numbered from 1 for user)
+fn parse_frames_range(text: &str) -> anyhow::Result<RangeInclusive<usize>> {
+    let err = || anyhow::anyhow!("Wrong frames range {}", text);
+    let (first, last) = text.split_once('-').ok_or_else(err)?;
+    let first: usize = first.trim().parse().map_err(|_| err())?;
+    let last: usize = last.trim().parse().map_err(|_| err())?;
+    if first == 0 || last < first {
+        return Err(err());
+    }
+    Ok(first-1..=last-1)
+}
+
+pub fn run_batch(args: &BatchArgs) -> anyhow::Result<()> {
+    if args.print_config {
+        return print_effective_config(args);
+    }
+    match args.mode {
+        BatchMode::Run => run_project(args),
+        BatchMode::Suggest => suggest_project_params(args),
+        BatchMode::ExtractSer => extract_ser_frames(args),
+        BatchMode::Watch => watch_capture_dir(args),
+        BatchMode::Agent => run_agent(args),
+        BatchMode::AgentSend => run_agent_client(args),
+        BatchMode::Preview => create_preview(args),
+        BatchMode::WatchMulti => watch_multiple_dirs(args, &args.sessions, args.max_parallel),
+        BatchMode::Stat => print_image_stat(args),
+        BatchMode::ColorCalibrate => color_calibrate_image(args),
+        BatchMode::RemoveGradient => remove_image_gradient(args),
+        BatchMode::Scnr => remove_image_green_noise(args),
+        BatchMode::Register => register_project(args),
+        BatchMode::PlateSolve => plate_solve_file(args),
+        BatchMode::ApplyTransform => apply_transforms_from_file(args),
+        BatchMode::Mosaic => create_mosaic_from_panels(args),
+        BatchMode::ExtractDuoBand => extract_duoband_channels(args),
+        BatchMode::SirilScript => run_siril_script(args),
+        BatchMode::ImportDss => import_dss_project(args),
+        BatchMode::BlendHa => blend_ha_into_red(args),
+        BatchMode::Resample => resample_image(args),
+        BatchMode::Geometry => change_image_geometry(args),
+        BatchMode::ExposureMap => create_exposure_map_file(args),
+        BatchMode::Colormap => create_colormapped_image(args),
+        BatchMode::Isophotes => fit_galaxy_isophotes(args),
+        BatchMode::MakeSkyFlat => make_sky_flat(args),
+        BatchMode::SkyLimit => print_sky_limit(args),
+        BatchMode::LiveStack => live_stack_capture_dir(args),
+        BatchMode::StackPlanetary => stack_planetary(args),
+        BatchMode::Deconvolve => deconvolve_image(args),
+        BatchMode::MergeHdr => merge_hdr_stacks(args),
+        BatchMode::StackGroups => stack_project_groups(args),
+        BatchMode::Wavelets => process_image_wavelets(args),
+        BatchMode::AutoStretch => auto_stretch_image(args),
+        BatchMode::StarMask => create_star_mask_file(args),
+        BatchMode::GroupDir => create_project_from_directory(args),
+        BatchMode::CalLibrary => update_cal_library(args),
+        BatchMode::ExportObs => export_observations(args),
+        BatchMode::StackLrgb => stack_lrgb_channels(args),
+        BatchMode::Check => check_dataset(args),
+        BatchMode::Timelapse => create_timelapse_file(args),
+        BatchMode::Diff => create_difference_image(args),
+        BatchMode::Photometry => measure_light_curve(args),
+    }
+}
+
+pub fn load_config(args: &BatchArgs) -> anyhow::Result<Config> {
+    let config: Config = global_config_layers(args)?.get()?;
+    config.apply_global_options();
+    Ok(config)
+}
+
+/// Defaults < config.json < options of command line
+fn global_config_layers(args: &BatchArgs) -> anyhow::Result<ConfigLayers> {
+    let mut layers = ConfigLayers::new(&Config::default())?;
+    let mut file_config = Config::default();
+    file_config.load()?;
+    layers.add_layer(ConfigSource::ConfigFile, &file_config)?;
+    let mut config: Config = layers.get()?;
+    if let Some(biassec) = &args.biassec {
+        config.fits_biassec = biassec.clone();
+    }
+    if let Some(trimsec) = &args.trimsec {
+        config.fits_trimsec = trimsec.clone();
+    }
+    if let Some(power_profile) = args.power_profile {
+        config.power_profile = power_profile;
+    }
+    if let Some(gpu) = &args.gpu {
+        config.gpu_device = gpu.clone();
+    }
+    if args.deterministic {
+        // GPU sums in other order and falls back to CPU on errors
+        config.gpu_device = "none".to_string();
+    }
+    layers.add_changes(ConfigSource::CommandLine, &file_config, &config)?;
+    Ok(layers)
+}
+
+/// Defaults < preset < project < options of command line
+fn run_config_layers(args: &BatchArgs, project_config: &ProjectConfig) -> anyhow::Result<ConfigLayers> {
+    project_config_layers(args.preset, project_config, |config| apply_cli_overrides(args, config))
+}
+
+/// Settings of `--preset` (below settings of project) and options of
+/// command line (over them) are used for this run only. They are not
+/// saved into project file
+fn apply_run_config(args: &BatchArgs, project: &mut Project) -> anyhow::Result<()> {
+    let run_config = run_config_layers(args, project.saved_config())?.get()?;
+    project.set_run_config(run_config);
+    if let Some(preset) = args.preset {
+        report!("Preset {}: {}", preset.name(), preset.description());
+    }
+    Ok(())
+}
+
+/// Options of command line which override settings of project
+fn apply_cli_overrides(args: &BatchArgs, project_config: &mut ProjectConfig) {
+    if let Some(hdu) = &args.hdu {
+        project_config.raw_params.fits_hdu = hdu.clone();
+    }
+    if let Some(compress) = args.compress {
+        project_config.fits_compression = compress;
+    }
+    if let Some(bitpix) = args.bitpix {
+        project_config.fits_bitpix = bitpix;
+    }
+    if let Some(compat) = args.compat {
+        project_config.fits_compat = compat;
+    }
+    if let Some(quantization) = args.quantization {
+        project_config.quantization = quantization;
+    }
+    if let Some(pedestal) = args.pedestal {
+        project_config.pedestal = pedestal;
+    }
+    if let Some(output_range) = args.output_range {
+        project_config.output_range = output_range;
+    }
+    if let Some(bg_mask) = args.bg_mask {
+        project_config.bg_mask = bg_mask;
+    }
+    if let Some(sub_stacks) = args.sub_stacks {
+        project_config.sub_stacks = Some(sub_stacks);
+    }
+    if args.flat_drift {
+        project_config.flat_drift = true;
+    }
+    if let Some(flat_norm) = args.flat_norm {
+        project_config.flat_norm = flat_norm;
+    }
+    if args.hot_pixels_by_lights {
+        project_config.hot_pixels_by_lights = true;
+    }
+    if args.streaming {
+        project_config.streaming_stack = true;
+    }
+    if args.site.is_some() {
+        project_config.airmass.site = args.site;
+    }
+    if args.target.is_some() {
+        project_config.airmass.target = args.target;
+    }
+    if args.extinction.is_some() {
+        project_config.airmass.extinction = args.extinction;
+    }
+    if !args.plugins.is_empty() {
+        project_config.plugins = args.plugins.clone();
+    }
+    if let Some(align_mode) = args.align_mode {
+        project_config.align_mode = align_mode;
+    }
+    if let Some(interpolation) = args.interpolation {
+        project_config.align_interpolation = interpolation;
+    }
+}
+
+/// Kernel for interpolation of rotated or reprojected images (flux
+/// conserving kernel is only for --resample and --apply-transform)
+fn point_interpolation_kernel(text: &str) -> anyhow::Result<ResampleKernel> {
+    let kernel = ResampleKernel::from_str(text)?;
+    if kernel == ResampleKernel::FluxConserving {
+        anyhow::bail!("Kernel flux can be used only for --resample and --apply-transform");
+    }
+    Ok(kernel)
+}
+
+fn print_effective_config(args: &BatchArgs) -> anyhow::Result<()> {
+    let print_entries = |layers: &ConfigLayers| {
+        for entry in layers.entries() {
+            report!("  {} = {} ({})", entry.key, entry.value, entry.source.name());
+        }
+    };
+    report!("Global settings:");
+    print_entries(&global_config_layers(args)?);
+
+    let mut project = Project::default();
+    if args.file_name.is_file() || !matches!(args.mode, BatchMode::Watch|BatchMode::LiveStack) {
+        project.load(&args.file_name)?;
+    }
+    let layers = run_config_layers(args, project.saved_config())?;
+    report!();
+    report!("Project settings:");
+    print_entries(&layers);
+    Ok(())
+}
+
+fn load_and_register_project(
+    args:        &BatchArgs,
+    config:      &Config,
+    progress:    &ProgressTs,
+    cancel_flag: &IsCancelledFun,
+) -> anyhow::Result<Project> {
+    let mut project = Project::default();
+    project.load(&args.file_name)?;
+    apply_run_config(args, &mut project)?;
+
+    if !project.is_any_used_light_file() {
+        anyhow::bail!(gettext("No light files to stack"));
+    }
+
+    if let Some(cal_library) = &args.cal_library {
+        assign_library_masters(args, cal_library, &mut project, progress, cancel_flag)?;
+    }
+
+    check_project_frames(args, &mut project, progress, cancel_flag)?;
+
+    // Registering. Registration info is saved into project file
+    // so next run will not register files again
+
+    if args.force || !project.is_all_light_files_are_registered() {
+        let reg_info = project.register_light_files(progress, cancel_flag, config.effective_cpu_load())?;
+        project.update_light_files_reg_info(reg_info);
+        project.save(&args.file_name)?;
+    }
+
+    Ok(project)
+}
+
+/// Refuses to process project with light and calibration files of different
+/// size, gain, offset or binning. With `--ignore-incompatible` they are only
+/// reported. Passed check is saved into project file and is not repeated
+/// until files are changed
+fn check_project_frames(
+    args:        &BatchArgs,
+    project:     &mut Project,
+    progress:    &ProgressTs,
+    cancel_flag: &IsCancelledFun,
+) -> anyhow::Result<()> {
+    if project.is_frames_check_passed() {
+        log::info!("Light and calibration files are not changed after compatibility check");
+        return Ok(());
+    }
+    let report = check_frames_compatibility(project, progress, cancel_flag)?;
+    report_compatibility(args, &report, "Light and calibration files are incompatible")?;
+    if report.passed() {
+        project.set_frames_check_passed();
+        project.save(&args.file_name)?;
+    }
+    Ok(())
+}
+
+/// Errors of `report` stop command if there is no `--ignore-incompatible`
+fn report_compatibility(args: &BatchArgs, report: &CheckReport, error_text: &str) -> anyhow::Result<()> {
+    for item in &report.items {
+        let level = match item.level {
+            CheckLevel::Error if !args.ignore_incompatible => "ERROR",
+            _ => "WARNING",
+        };
+        match &item.group {
+            Some(group) => report!("{} (group {}): {}", level, group, item.text),
+            None        => report!("{}: {}", level, item.text),
+        }
+    }
+    if !report.passed() && !args.ignore_incompatible {
+        anyhow::bail!("{} (use --ignore-incompatible to process them anyway)", error_text);
+    }
+    Ok(())
+}
+
+/// Assigns masters of calibration library to groups without calibration
+/// files. Poor matches are reported as warnings
+fn assign_library_masters(
+    args:        &BatchArgs,
+    cal_library: &Path,
+    project:     &mut Project,
+    progress:    &ProgressTs,
+    cancel_flag: &IsCancelledFun,
+) -> anyhow::Result<()> {
+    let library = CalLibrary::open(cal_library)?;
+    let assigned = library.assign_masters_to_project(project, &args.cal_tolerances, progress, cancel_flag)?;
+    for master in &assigned {
+        let Some(file) = &master.file else {
+            log::warn!("Group {}: no suitable master {} in library", master.group, master.file_type.name());
+            continue;
+        };
+        report!("Group {}: master {} {}", master.group, master.file_type.name(), path_to_str(file));
+        if !master.warnings.is_empty() {
+            log::warn!(
+                "Group {}: poor match of master {}: {}",
+                master.group, master.file_type.name(), master.warnings.join(", ")
+            );
+        }
+    }
+    project.save(&args.file_name)?;
+    Ok(())
+}
+
+/// Assigns reference image from --reference or selects it automatically
+/// if it is not assigned yet. Choice is printed and saved into project
+fn assign_reference_image(args: &BatchArgs, project: &mut Project) -> anyhow::Result<()> {
+    if let Some(reference) = &args.reference {
+        let file_name = project.groups().iter()
+            .flat_map(|g| g.light_files.list().iter())
+            .map(|f| f.file_name())
+            .find(|f| *f == reference || f.ends_with(reference))
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!(
+                "Light file {} is not found in project",
+                reference.to_str().unwrap_or("")
+            ))?;
+        report!("Reference image: {} (defined by --reference)", file_name.to_str().unwrap_or(""));
+        project.set_ref_image(file_name);
+        project.save(&args.file_name)?;
+    } else if !project.is_ref_image_assigned()
+    && project.is_possible_assign_ref_light_frame_automatically() {
+        if let Some(choice) = project.assign_ref_light_frame_automatically() {
+            report!("Reference image: {}", choice.file_name.to_str().unwrap_or(""));
+            report!("Reason: {}", choice.reason);
+            project.save(&args.file_name)?;
+        }
+    }
+    if let CanExecStackLightsRes::NoRefFile = project.can_exec_stack_light_files() {
+        anyhow::bail!(gettext("Reference image is not defined"));
+    }
+    Ok(())
+}
+
+fn register_project(args: &BatchArgs) -> anyhow::Result<()> {
+    let config = load_config(args)?;
+    let started = chrono::Local::now();
+    let progress = ProgressConsole::new_ts();
+    let cancel_flag = Arc::clone(&args.cancel_flag);
+    let mut project = load_and_register_project(args, &config, &progress, &cancel_flag)?;
+    report!();
+    report!("{} light file(s) registered", project.total_light_files_count());
+    if !args.transforms_only {
+        return save_run_report(args, "register", started, |run_report| {
+            run_report.add_project(&project)
+        });
+    }
+
+    assign_reference_image(args, &mut project)?;
+    let transforms = project.calc_light_files_transforms(&progress, &cancel_flag, config.effective_cpu_load())?;
+
+    let out_file = args.out.clone().unwrap_or_else(||
+        get_processed_file_name(&args.file_name, "transforms").with_extension("json")
+    );
+    let text = if extract_extension(&out_file).eq_ignore_ascii_case("csv") {
+        let fmt = &config.report_format;
+        let sep = fmt.csv_separator().to_string();
+        let header = ["file", "width", "height", "offset_x", "offset_y", "angle", "m00", "m01", "m02", "m10", "m11", "m12"];
+        let mut text = header.join(&sep) + "\n";
+        for t in &transforms {
+            let [[m00, m01, m02], [m10, m11, m12]] = t.matrix;
+            let mut values = vec![
+                format!("\"{}\"", path_to_str(&t.file).replace('"', "\"\"")),
+                t.width.to_string(),
+                t.height.to_string(),
+            ];
+            values.extend(
+                [t.offset_x, t.offset_y, t.angle, m00, m01, m02, m10, m11, m12]
+                    .iter()
+                    .map(|v| fmt.float_full(*v))
+            );
+            text.push_str(&values.join(&sep));
+            text.push('\n');
+        }
+        text
+    } else {
+        serde_json::to_string_pretty(&transforms)?
+    };
+    write_file_atomically(&out_file, |tmp_file_name| {
+        Ok(std::fs::write(tmp_file_name, &text)?)
+    })?;
+    report!();
+    report!("Transforms of {} file(s) saved to {}", transforms.len(), out_file.to_str().unwrap_or(""));
+    save_run_report(args, "register", started, |run_report| {
+        run_report.add_project(&project)?;
+        run_report.add_output(&out_file)?;
+        run_report.transforms = transforms;
+        Ok(())
+    })
+}
+
+fn print_sub_stacks(result: &StackLightsResult) {
+    for sub_stack in &result.sub_stacks {
+        report!(
+            "  {}: {} file(s), noise {:.3e}, saved to {}",
+            sub_stack.start.format("%Y-%m-%d %H:%M"),
+            sub_stack.frames_count,
+            sub_stack.noise.result_noise,
+            sub_stack.file_name.to_str().unwrap_or("")
+        );
+    }
+}
+
+/// Creates and saves JSON report if it is required by --report
+fn save_run_report(
+    args:    &BatchArgs,
+    command: &'static str,
+    started: chrono::DateTime<chrono::Local>,
+    fill:    impl FnOnce(&mut RunReport) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let Some(report_file) = &args.run_report else {
+        return Ok(());
+    };
+    let mut run_report = RunReport::new(command, &args.file_name, started);
+    fill(&mut run_report)?;
+    run_report.save(report_file)?;
+    report!("Run report saved to {}", report_file.to_str().unwrap_or(""));
+    Ok(())
+}
+
+fn run_project(args: &BatchArgs) -> anyhow::Result<()> {
+    log::info!("Batch run for project {:?} started", args.file_name);
+
+    let config = load_config(args)?;
+
+    let started = chrono::Local::now();
+    let perf_progress = ProgressPerf::new_ts(ProgressConsole::new_ts());
+    let progress: ProgressTs = perf_progress.clone();
+    let cancel_flag = Arc::clone(&args.cancel_flag);
+
+    let mut project = load_and_register_project(args, &config, &progress, &cancel_flag)?;
+
+    // Cleanup
+
+    if args.cleanup {
+        let cleaned_up = project.cleanup_light_files()?;
+        report!("{} light file(s) cleaned up", cleaned_up);
+    }
+
+    // Reference image
+
+    assign_reference_image(args, &mut project)?;
+
+    // Stacking
+
+    let resume = if args.force { ResumeMode::Force } else { ResumeMode::Resume };
+    let result = project.stack_light_files(&progress, &cancel_flag, config.effective_cpu_load(), resume, &args.stack_maps)?;
+    report!();
+    report!("Result file saved to {}", result.file_name.to_str().unwrap_or(""));
+    print_sub_stacks(&result);
+    if let Some((snr_improvement, ideal)) = result.noise.snr_improvement() {
+        report!(
+            "Noise of light files {:.3e}, noise of result {:.3e}: SNR improvement {:.2}x (ideal for {} files is {:.2}x)",
+            result.noise.frame_noise, result.noise.result_noise,
+            snr_improvement, result.noise.frames_count, ideal
+        );
+    }
+
+    if args.preview {
+        let preview_file = get_preview_file_name(&result.file_name);
+        save_result_preview(args, &result.file_name, &preview_file)?;
+        report!("Preview saved to {}", preview_file.to_str().unwrap_or(""));
+    }
+
+    if args.perf_report {
+        let report = create_perf_report(&perf_progress, &project, config.effective_cpu_load().to_threads_count(), started);
+        let report_file = get_processed_file_name(&args.file_name, "perf").with_extension("json");
+        report.save(&report_file)?;
+        report!("Performance report saved to {}", report_file.to_str().unwrap_or(""));
+    }
+
+    save_run_report(args, "run", started, |run_report| {
+        run_report.add_project(&project)?;
+        run_report.add_stack(None, &result)?;
+        if args.preview {
+            run_report.add_output(&get_preview_file_name(&result.file_name))?;
+        }
+        for file in args.stack_maps.files() {
+            run_report.add_output(file)?;
+        }
+        Ok(())
+    })
+}
+
+fn check_dataset(args: &BatchArgs) -> anyhow::Result<()> {
+    load_config(args)?;
+    let mut project = Project::default();
+    project.load(&args.file_name)?;
+    apply_run_config(args, &mut project)?;
+    let progress = ProgressConsole::new_ts();
+    let report = check_project(&project, &progress, &args.cancel_flag)?;
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        report!();
+        for item in &report.items {
+            let level = match item.level {
+                CheckLevel::Warning => "WARNING",
+                CheckLevel::Error   => "ERROR",
+            };
+            match &item.group {
+                Some(group) => report!("{} (group {}): {}", level, group, item.text),
+                None        => report!("{}: {}", level, item.text),
+            }
+        }
+        report!(
+            "{} file(s) checked: {} error(s), {} warning(s)",
+            report.files_checked, report.count(CheckLevel::Error), report.count(CheckLevel::Warning)
+        );
+    }
+    if !report.passed() {
+        anyhow::bail!("Check of dataset failed");
+    }
+    if !args.json {
+        report!("Check passed");
+    }
+    Ok(())
+}
+
+fn stack_project_groups(args: &BatchArgs) -> anyhow::Result<()> {
+    log::info!("Separate stacking of groups for project {:?} started", args.file_name);
+
+    let config = load_config(args)?;
+    let started = chrono::Local::now();
+    let progress = ProgressConsole::new_ts();
+    let cancel_flag = Arc::clone(&args.cancel_flag);
+    let mut project = load_and_register_project(args, &config, &progress, &cancel_flag)?;
+
+    let master_group = if let Some(master_group) = &args.master_group {
+        let index = (0..project.groups().len())
+            .find(|&idx| project.groups()[idx].name(idx) == *master_group)
+            .or_else(|| master_group.parse::<usize>().ok()
+                .filter(|&num| num >= 1 && num <= project.groups().len())
+                .map(|num| num - 1)
+            )
+            .ok_or_else(|| anyhow::anyhow!("Group {} is not found in project", master_group))?;
+        Some(index)
+    } else {
+        None
+    };
+
+    let resume = if args.force { ResumeMode::Force } else { ResumeMode::Resume };
+    let results = project.stack_groups_separately(
+        master_group,
+        &progress,
+        &cancel_flag,
+        config.effective_cpu_load(),
+        resume,
+        &args.stack_maps
+    )?;
+    report!();
+    for (group_name, result) in &results {
+        report!("Group {}: result file saved to {}", group_name, result.file_name.to_str().unwrap_or(""));
+        print_sub_stacks(result);
+    }
+    if let Some(master_group) = master_group {
+        report!(
+            "All results are aligned to geometry of group {}",
+            project.groups()[master_group].name(master_group)
+        );
+    }
+    if args.crop_common {
+        let files: Vec<_> = results.iter().map(|(_, result)| result.file_name.clone()).collect();
+        let (x, y, width, height) = crop_to_common_coverage(&files, project.config().fits_save_opts())?;
+        report!("All results cropped to common area {}x{} at ({}, {})", width, height, x, y);
+    }
+    // checksums are taken after cropping
+    save_run_report(args, "stack-groups", started, |run_report| {
+        run_report.add_project(&project)?;
+        for (group_name, result) in &results {
+            run_report.add_stack(Some(group_name.clone()), result)?;
+        }
+        Ok(())
+    })
+}
+
+/// Crops all images identically to intersection of their covered areas
+fn crop_to_common_coverage(
+    files:     &[PathBuf],
+    fits_opts: FitsSaveOpts,
+) -> anyhow::Result<(Crd, Crd, Crd, Crd)> {
+    let mut images = Vec::new();
+    for file_name in files {
+        let ImageData { image: RawOrImage::Image(image), info } =
+            load_image_from_file(file_name, false)? else {
+            anyhow::bail!("{} is RAW image", file_name.to_str().unwrap_or(""));
+        };
+        images.push((image, info));
+    }
+    let image_refs: Vec<_> = images.iter().map(|(image, _)| image).collect();
+    let (x, y, width, height) = find_common_coverage_of_images(&image_refs)?;
+    let crop = [GeometryOp::Crop { x, y, width, height }];
+    for ((image, mut info), file_name) in images.into_iter().zip(files) {
+        let result = apply_geometry(image, &crop)?;
+        info.width = result.image.width() as usize;
+        info.height = result.image.height() as usize;
+        write_file_atomically(file_name, |tmp_file_name| {
+            save_image_to_file(&result.image, &info, tmp_file_name, fits_opts)
+        })?;
+    }
+    Ok((x, y, width, height))
+}
+
+fn save_result_preview(
+    args:         &BatchArgs,
+    file_name:    &Path,
+    preview_file: &Path,
+) -> anyhow::Result<()> {
+    let ImageData { image: RawOrImage::Image(image), .. } =
+        load_image_from_file(file_name, false)? else {
+        anyhow::bail!("{} is not image", file_name.to_str().unwrap_or(""));
+    };
+    let opts = PreviewOpts {
+        stretch: args.stretch.unwrap_or(PreviewStretch::Mtf),
+        max_width: args.max_width,
+        .. PreviewOpts::default()
+    };
+    save_preview_file(&image, preview_file, &opts)
+}
+
+fn create_preview(args: &BatchArgs) -> anyhow::Result<()> {
+    load_config(args)?;
+    let preview_file = args.out.clone()
+        .unwrap_or_else(|| get_preview_file_name(&args.file_name));
+    save_result_preview(args, &args.file_name, &preview_file)?;
+    report!("Preview saved to {}", preview_file.to_str().unwrap_or(""));
+    Ok(())
+}
+
+fn print_image_stat(args: &BatchArgs) -> anyhow::Result<()> {
+    load_config(args)?;
+    let source = ImageSource::open(&args.file_name)?;
+    let stat = ImageStat::new_from_source(&source, args.bins);
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&stat)?);
+    } else {
+        stat.print();
+    }
+    if args.fwhm_map.is_some() || args.eccentricity_map.is_some() {
+        create_star_shape_map_files(args)?;
+    }
+    Ok(())
+}
+
+fn create_star_shape_map_files(args: &BatchArgs) -> anyhow::Result<()> {
+    let (maps, mut info) = create_star_shape_maps_for_file(&args.file_name)?;
+    info.cfa_type = None;
+    let zones_text = |zones: &FieldZones| -> String {
+        let value = |v: Option<f32>| v.map(|v| format!("{:.2}", v)).unwrap_or_else(|| "-".to_string());
+        format!(
+            "center {}, corners {} {} {} {} (top left, top right, bottom left, bottom right)",
+            value(zones.center), value(zones.top_left), value(zones.top_right),
+            value(zones.bottom_left), value(zones.bottom_right)
+        )
+    };
+    // summary is not mixed with statistics in JSON format
+    if !args.json {
+        report!();
+        report!("Stars measured: {} (grid {}x{})", maps.stars, maps.grid.0, maps.grid.1);
+        report!("FWHM: {}", zones_text(&maps.fwhm_zones));
+        report!("Eccentricity: {}", zones_text(&maps.eccentricity_zones));
+        let corners = maps.fwhm_zones.corners();
+        if let (Some(center), true) = (maps.fwhm_zones.center, corners.len() == 4) {
+            let min = corners.iter().copied().fold(f32::MAX, f32::min);
+            let max = corners.iter().copied().fold(f32::MIN, f32::max);
+            let mean = corners.iter().sum::<f32>() / 4.0;
+            report!(
+                "Corners difference (tilt): {:.0}%, corners to center (curvature): {:+.0}%",
+                100.0 * (max - min) / min, 100.0 * (mean - center) / center
+            );
+        }
+    }
+    for (out_file, layer) in [(&args.fwhm_map, maps.fwhm), (&args.eccentricity_map, maps.eccentricity)] {
+        let Some(out_file) = out_file else { continue; };
+        let image = Image { l: layer, ..Image::new() };
+        save_processed_image(args, &image, &mut info, out_file)?;
+        if !args.json {
+            report!("Map saved to {}", path_to_str(out_file));
+        }
+    }
+    Ok(())
+}
+
+/// Default name of file created from source image by processing command
+fn get_processed_file_name(file_name: &Path, suffix: &str) -> PathBuf {
+    let stem = file_name.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+    let ext = extract_extension(file_name);
+    file_name.with_file_name(format!("{}_{}.{}", stem, suffix, ext))
+}
+
+fn color_calibrate_image(args: &BatchArgs) -> anyhow::Result<()> {
+    load_config(args)?;
+    let (mut image, mut info) = load_processed_image(args)?;
+    let result = calibrate_colors(&mut image, args.cc_mode, args.bg_region)?;
+    report!(
+        "Background: R={:.5}, G={:.5}, B={:.5}",
+        result.background[0], result.background[1], result.background[2]
+    );
+    report!(
+        "Factors: R={:.4}, G={:.4}, B={:.4}",
+        result.factors[0], result.factors[1], result.factors[2]
+    );
+    if result.stars_used != 0 {
+        report!("Stars used: {}", result.stars_used);
+    }
+
+    let out_file = args.out.clone()
+        .unwrap_or_else(|| get_processed_file_name(&args.file_name, "cc"));
+    save_processed_image(args, &image, &mut info, &out_file)?;
+    report!("Result saved to {}", out_file.to_str().unwrap_or(""));
+    Ok(())
+}
+
+fn save_processed_image(
+    args:      &BatchArgs,
+    image:     &Image,
+    info:      &mut ImageInfo,
+    file_name: &Path,
+) -> anyhow::Result<()> {
+    info.file_name = file_name.to_path_buf();
+    info.width = image.width() as usize;
+    info.height = image.height() as usize;
+    let fits_opts = FitsSaveOpts {
+        bitpix: args.bitpix.unwrap_or(FitsBitPix::Float32),
+        compression: args.compress.unwrap_or(FitsCompression::None),
+        compat: args.compat.unwrap_or(FitsCompat::Default),
+        quantization: args.quantization.unwrap_or_default(),
+        range: args.output_range.unwrap_or_default(),
+        pedestal: args.pedestal.unwrap_or(0.0),
+    };
+    if is_fits_ext(extract_extension(file_name)) && !args.json {
+        let clip_stats = fits_clip_stats(image, info, &fits_opts);
+        if !clip_stats.is_empty() {
+            report!("Values clipped at saving: {}", clip_stats);
+        }
+    }
+    write_file_atomically(file_name, |tmp_file_name| {
+        save_image_to_file(image, info, tmp_file_name, fits_opts)
+    })
+}
+
+fn load_processed_image(args: &BatchArgs) -> anyhow::Result<(Image, ImageInfo)> {
+    let ImageData { image: RawOrImage::Image(image), info } =
+        load_image_from_file(&args.file_name, false)? else {
+        anyhow::bail!("{} is RAW image", args.file_name.to_str().unwrap_or(""));
+    };
+    Ok((image, info))
+}
+
+fn plate_solve_file(args: &BatchArgs) -> anyhow::Result<()> {
+    let config = load_config(args)?;
+    let mut opts = args.plate_solve.clone();
+    if opts.solver.as_os_str().is_empty() {
+        opts.solver = config.astrometry_solver.clone();
+    }
+    let result = plate_solve_image(&args.file_name, &opts)?;
+    let wcs = &result.wcs;
+    let (ra, dec) = wcs.pixel_to_world(
+        (result.width as f64 - 1.0) / 2.0,
+        (result.height as f64 - 1.0) / 2.0
+    );
+    report!("Solved by {} stars", result.stars_used);
+    report!("Center: RA {:.5}°, DEC {:.5}°", ra, dec);
+    report!("Pixel scale: {:.3}\"", wcs.pixel_scale());
+    report!("Rotation: {:.2}°", wcs.rotation());
+
+    wcs.save_for_image(&args.file_name)?;
+    if is_fits_ext(extract_extension(&args.file_name)) {
+        report!("WCS is written into {}", args.file_name.to_str().unwrap_or(""));
+    } else {
+        // other formats have no standard WCS keywords
+        let wcs_file = wcs_json_file_name(&args.file_name);
+        report!("WCS is saved to {}", wcs_file.to_str().unwrap_or(""));
+    }
+    Ok(())
+}
+
+fn remove_image_green_noise(args: &BatchArgs) -> anyhow::Result<()> {
+    load_config(args)?;
+    let (mut image, mut info) = load_processed_image(args)?;
+    remove_green_noise(&mut image, args.scnr_amount)?;
+    if let Some(radius) = args.chroma_nr {
+        reduce_chroma_noise(&mut image, radius)?;
+    }
+    let out_file = args.out.clone()
+        .unwrap_or_else(|| get_processed_file_name(&args.file_name, "scnr"));
+    save_processed_image(args, &image, &mut info, &out_file)?;
+    report!("Result saved to {}", out_file.to_str().unwrap_or(""));
+    Ok(())
+}
+
+fn remove_image_gradient(args: &BatchArgs) -> anyhow::Result<()> {
+    load_config(args)?;
+    let (mut image, mut info) = load_processed_image(args)?;
+    let star_mask = args.mask_file.as_ref()
+        .map(|file| load_star_mask(file, image.width(), image.height()))
+        .transpose()?;
+    let opts = GradientOpts {
+        keep_model: args.model_out.is_some(),
+        bg_mask: args.bg_mask.unwrap_or_default(),
+        ..args.gradient.clone()
+    };
+    let result = remove_gradient(&mut image, &opts, star_mask.as_ref())?;
+    report!("{} background samples used", result.samples);
+
+    let out_file = args.out.clone()
+        .unwrap_or_else(|| get_processed_file_name(&args.file_name, "nogradient"));
+    save_processed_image(args, &image, &mut info, &out_file)?;
+    report!("Result saved to {}", out_file.to_str().unwrap_or(""));
+
+    if let (Some(model_file), Some(model)) = (&args.model_out, &result.model) {
+        save_processed_image(args, model, &mut info, model_file)?;
+        report!("Background model saved to {}", model_file.to_str().unwrap_or(""));
+    }
+    Ok(())
+}
+
+fn make_sky_flat(args: &BatchArgs) -> anyhow::Result<()> {
+    load_config(args)?;
+    let progress = ProgressConsole::new_ts();
+    let flat = create_sky_flat(&args.files, &args.sky_flat, &progress, &args.cancel_flag)?;
+    let out_file = args.out.clone()
+        .unwrap_or_else(|| args.file_name.with_file_name("sky_flat.fit"));
+    save_cfa_image_to_fits_file(
+        &flat.data, &flat.info, &out_file,
+        args.compress.unwrap_or(FitsCompression::None)
+    )?;
+    update_fits_header(&out_file, &[
+        ("BLKLEVEL", FitsKeyValue::Float(flat.black as f64)),
+        ("IMAGETYP", FitsKeyValue::Str("Flat Field".to_string())),
+    ])?;
+    report!();
+    report!("Synthetic flat from {} files saved to {}", args.files.len(), out_file.to_str().unwrap_or(""));
+    Ok(())
+}
+
+fn print_sky_limit(args: &BatchArgs) -> anyhow::Result<()> {
+    load_config(args)?;
+    let bias_file = args.bias_file.as_ref()
+        .ok_or_else(|| anyhow::anyhow!("Bias file is not defined (--bias)"))?;
+    let result = calc_sky_limit(&args.file_name, bias_file, &args.sky_limit)?;
+    for line in &result.reasoning {
+        report!("{}", line);
+    }
+    Ok(())
+}
+
+fn fit_galaxy_isophotes(args: &BatchArgs) -> anyhow::Result<()> {
+    let config = load_config(args)?;
+    let (image, _) = load_processed_image(args)?;
+    let layer = image.create_greyscale_layer();
+    let result = fit_isophotes(&layer, &args.isophotes)?;
+
+    let fmt = &config.report_format;
+    let sep = fmt.csv_separator().to_string();
+    let header = ["sma", "intensity", "intensity_err", "magnitude", "eps", "pa", "x0", "y0", "iterations"];
+    let mut text = header.join(&sep) + "\n";
+    for iso in &result.isophotes {
+        let values = [
+            fmt.float(iso.sma, 2),
+            fmt.float_full(iso.intensity),
+            fmt.float_full(iso.intensity_err),
+            iso.magnitude().map(|m| fmt.float(m, 3)).unwrap_or_default(),
+            fmt.float(iso.eps, 3),
+            fmt.float(iso.pa.to_degrees(), 1),
+            fmt.float(iso.x0, 2),
+            fmt.float(iso.y0, 2),
+            iso.iterations.to_string(),
+        ];
+        text.push_str(&values.join(&sep));
+        text.push('\n');
+    }
+    let csv_file = args.out.clone()
+        .unwrap_or_else(|| get_processed_file_name(&args.file_name, "isophotes").with_extension("csv"));
+    write_file_atomically(&csv_file, |tmp_file_name| {
+        Ok(std::fs::write(tmp_file_name, &text)?)
+    })?;
+
+    // overlay with ellipses over stretched image
+    let (width, height, bytes) = create_stretched_preview(&image, &PreviewOpts::default());
+    let mut rgb = if image.is_rgb() {
+        bytes
+    } else {
+        bytes.iter().flat_map(|v| [*v, *v, *v]).collect()
+    };
+    draw_isophotes(&mut rgb, width, height, &result.isophotes);
+    let png_file = csv_file.with_extension("png");
+    save_8bit_image(&png_file, width, height, true, &rgb, PreviewOpts::default().quality)?;
+
+    report!(
+        "{} isophote(s) fitted (background = {}, noise = {})",
+        result.isophotes.len(), fmt.float_full(result.background as f64), fmt.float_full(result.noise as f64)
+    );
+    report!("Profile saved to {}", csv_file.to_str().unwrap_or(""));
+    report!("Overlay saved to {}", png_file.to_str().unwrap_or(""));
+    Ok(())
+}
+
+fn create_colormapped_image(args: &BatchArgs) -> anyhow::Result<()> {
+    load_config(args)?;
+    let colormap = Colormap::from_str(&args.colormap)?;
+    let (image, _) = load_processed_image(args)?;
+    let opts = PreviewOpts {
+        stretch: args.stretch.unwrap_or(PreviewStretch::Mtf),
+        max_width: args.max_width,
+        .. PreviewOpts::default()
+    };
+    let (width, height, bytes) = create_colormapped_preview(&image, &opts, &colormap)?;
+    let out_file = args.out.clone()
+        .unwrap_or_else(|| get_processed_file_name(&args.file_name, "colormap").with_extension("png"));
+    save_8bit_image(&out_file, width, height, true, &bytes, opts.quality)?;
+    report!("False color image saved to {}", out_file.to_str().unwrap_or(""));
+    Ok(())
+}
+
+fn create_exposure_map_file(args: &BatchArgs) -> anyhow::Result<()> {
+    load_config(args)?;
+    let progress = ProgressConsole::new_ts();
+    let mut opts = ExposureMapOpts { contours: args.contours, ..ExposureMapOpts::default() };
+    if let Some(max_width) = args.max_width {
+        opts.max_width = max_width;
+    }
+    let map = create_exposure_map(&args.files, &opts, &progress)?;
+    let out_file = args.out.clone()
+        .unwrap_or_else(|| args.file_name.with_file_name("exposure_map.png"));
+    let bytes = map.render(opts.contours);
+    save_8bit_image(
+        &out_file,
+        map.times.width() as usize, map.times.height() as usize,
+        true, &bytes, PreviewOpts::default().quality
+    )?;
+    report!();
+    report!("Max integration time: {:.0} s", map.max_time);
+    for level in map.contour_levels(opts.contours) {
+        report!(">= {:.0} s: {:.1}% of covered area", level, 100.0 * map.covered_part(level));
+    }
+    report!("Exposure map saved to {}", out_file.to_str().unwrap_or(""));
+    Ok(())
+}
+
+fn change_image_geometry(args: &BatchArgs) -> anyhow::Result<()> {
+    load_config(args)?;
+    let (image, mut info) = load_processed_image(args)?;
+    let wcs = Wcs::load_for_image(&args.file_name).ok();
+    let result = apply_geometry(image, &args.geometry)?;
+    let out_file = args.out.clone()
+        .unwrap_or_else(|| get_processed_file_name(&args.file_name, "geometry"));
+    save_processed_image(args, &result.image, &mut info, &out_file)?;
+    if let Some(wcs) = wcs.and_then(|wcs| transform_wcs(&wcs, &result.transform)) {
+        wcs.save_for_image(&out_file)?;
+    }
+    report!(
+        "Result {}x{} saved to {}",
+        result.image.width(), result.image.height(), out_file.to_str().unwrap_or("")
+    );
+    Ok(())
+}
+
+fn resample_image(args: &BatchArgs) -> anyhow::Result<()> {
+    load_config(args)?;
+    let (mut image, mut info) = load_processed_image(args)?;
+    if args.bin > 1 {
+        image = bin_image(&image, args.bin, args.bin_mode);
+    }
+    if let Some(scale) = args.scale {
+        image = rescale_image(&image, scale, args.kernel)?;
+    }
+    let out_file = args.out.clone()
+        .unwrap_or_else(|| get_processed_file_name(&args.file_name, "resampled"));
+    save_processed_image(args, &image, &mut info, &out_file)?;
+    report!(
+        "Result {}x{} saved to {}",
+        image.width(), image.height(), out_file.to_str().unwrap_or("")
+    );
+    Ok(())
+}
+
+fn blend_ha_into_red(args: &BatchArgs) -> anyhow::Result<()> {
+    load_config(args)?;
+    let (mut image, mut info) = load_processed_image(args)?;
+    let ha_file = args.ha_file.as_ref().unwrap();
+    let ImageData { image: RawOrImage::Image(ha), .. } = load_image_from_file(ha_file, false)? else {
+        anyhow::bail!("{} is RAW image", ha_file.to_str().unwrap_or(""));
+    };
+    let ha_layer = if ha.is_rgb() { &ha.r } else { &ha.l };
+    blend_ha(&mut image, ha_layer, &args.ha_blend)?;
+    let out_file = args.out.clone()
+        .unwrap_or_else(|| get_processed_file_name(&args.file_name, "ha"));
+    save_processed_image(args, &image, &mut info, &out_file)?;
+    report!("Result saved to {}", out_file.to_str().unwrap_or(""));
+    Ok(())
+}
+
+fn merge_hdr_stacks(args: &BatchArgs) -> anyhow::Result<()> {
+    load_config(args)?;
+    let (mut image, mut info) = load_processed_image(args)?;
+    let short_file = args.short_file.as_ref().unwrap();
+    let ImageData { image: RawOrImage::Image(short), info: short_info } = load_image_from_file(short_file, false)? else {
+        anyhow::bail!("{} is RAW image", short_file.to_str().unwrap_or(""));
+    };
+    let fits = merge_hdr(&mut image, &short, &args.hdr)?;
+    let mut meta = IntegrationMeta::default();
+    meta.add_stack(&info);
+    meta.add_stack(&short_info);
+    meta.apply_to_info(&mut info);
+    for (fit, channel) in fits.iter().zip(if fits.len() == 3 { ["R", "G", "B"].as_slice() } else { ["L"].as_slice() }) {
+        report!(
+            "{}: long = {:.4} * short + {:.5} ({} pixels)",
+            channel, fit.scale, fit.offset, fit.points
+        );
+    }
+    let out_file = args.out.clone()
+        .unwrap_or_else(|| get_processed_file_name(&args.file_name, "hdr"));
+    save_processed_image(args, &image, &mut info, &out_file)?;
+    report!("Result saved to {}", out_file.to_str().unwrap_or(""));
+    Ok(())
+}
+
+fn process_image_wavelets(args: &BatchArgs) -> anyhow::Result<()> {
+    load_config(args)?;
+    let (mut image, mut info) = load_processed_image(args)?;
+    let result = process_wavelets(&mut image, &args.wavelets)?;
+    let noise: Vec<_> = result.noise.iter().map(|n| format!("{:.3e}", n)).collect();
+    report!("Processed layers: {}, noise: {}", result.layers, noise.join(", "));
+    let out_file = args.out.clone()
+        .unwrap_or_else(|| get_processed_file_name(&args.file_name, "wavelets"));
+    save_processed_image(args, &image, &mut info, &out_file)?;
+    report!("Result saved to {}", out_file.to_str().unwrap_or(""));
+    Ok(())
+}
+
+fn auto_stretch_image(args: &BatchArgs) -> anyhow::Result<()> {
+    load_config(args)?;
+    let (image, mut info) = load_processed_image(args)?;
+    let opts = StretchOpts {
+        stretch: args.stretch.unwrap_or(PreviewStretch::Mtf),
+        .. args.auto_stretch.clone()
+    };
+    let image = stretch_image(&image, &opts);
+    let out_file = args.out.clone()
+        .unwrap_or_else(|| get_processed_file_name(&args.file_name, "stretched"));
+    if is_tiff_ext(extract_extension(&out_file)) {
+        // stretched image is saved as 16 bit TIFF for editing in other programs
+        info.file_name = out_file.clone();
+        write_file_atomically(&out_file, |tmp_file_name| {
+            save_image_to_tiff16_file(&image, &info, tmp_file_name, args.quantization.unwrap_or_default())
+        })?;
+    } else {
+        save_processed_image(args, &image, &mut info, &out_file)?;
+    }
+    report!("Result saved to {}", out_file.to_str().unwrap_or(""));
+    Ok(())
+}
+
+fn create_star_mask_file(args: &BatchArgs) -> anyhow::Result<()> {
+    load_config(args)?;
+    let (image, mut info) = load_processed_image(args)?;
+    let result = create_star_mask(&image, &args.star_mask)?;
+    report!("{} star(s) in mask", result.stars);
+    let out_file = args.out.clone()
+        .unwrap_or_else(|| get_processed_file_name(&args.file_name, "starmask").with_extension("fit"));
+    let mask = Image { l: result.mask, ..Image::new() };
+    save_processed_image(args, &mask, &mut info, &out_file)?;
+    report!("Star mask saved to {}", out_file.to_str().unwrap_or(""));
+    Ok(())
+}
+
+fn import_dss_project(args: &BatchArgs) -> anyhow::Result<()> {
+    load_config(args)?;
+    let progress = ProgressConsole::new_ts();
+    let mut result = import_dss_file_list(&args.file_name, &progress, &args.cancel_flag)?;
+    let project_file = args.out.clone()
+        .unwrap_or_else(|| args.file_name.with_extension("es_proj"));
+    result.project.save(&project_file)?;
+    report!();
+    if !result.missing_files.is_empty() {
+        report!("{} file(s) from list are not found", result.missing_files.len());
+    }
+    report!(
+        "Project with {} light file(s) saved to {}",
+        result.project.total_light_files_count(),
+        project_file.to_str().unwrap_or("")
+    );
+    Ok(())
+}
+
+fn update_cal_library(args: &BatchArgs) -> anyhow::Result<()> {
+    load_config(args)?;
+    let mut library = CalLibrary::open(&args.file_name)?;
+    if let Some(project_file) = &args.cal_lib_add {
+        let mut project = Project::default();
+        project.load(project_file)?;
+        apply_run_config(args, &mut project)?;
+        let progress = ProgressConsole::new_ts();
+        let added = library.add_project_masters(&project, &progress, &args.cancel_flag)?;
+        library.save()?;
+        report!();
+        for file in &added {
+            report!("Master added: {}", path_to_str(file));
+        }
+        if added.is_empty() {
+            report!("No master files in project {} (run it first)", path_to_str(project_file));
+        }
+    }
+    report!("{} master(s) in library:", library.entries.len());
+    for entry in &library.entries {
+        report!(
+            "{:<5} {:>5}x{:<5} gain={:<5} exp={:<7} temp={:<6} filter={:<6} date={} frames={} ({})",
+            entry.file_type.name(),
+            entry.width, entry.height,
+            entry.gain.map(|v| v.to_string()).unwrap_or_else(|| "-".to_string()),
+            entry.exposure.map(|v| format!("{}s", v)).unwrap_or_else(|| "-".to_string()),
+            entry.temperature.map(|v| format!("{:.1}°C", v)).unwrap_or_else(|| "-".to_string()),
+            entry.filter.as_deref().unwrap_or("-"),
+            entry.date.map(|v| v.format("%Y-%m-%d").to_string()).unwrap_or_else(|| "-".to_string()),
+            entry.frames,
+            path_to_str(&entry.file)
+        );
+    }
+    Ok(())
+}
+
+fn export_observations(args: &BatchArgs) -> anyhow::Result<()> {
+    use std::io::Write;
+    load_config(args)?;
+    let observations = load_observations_csv(&args.file_name)?;
+    if observations.is_empty() {
+        anyhow::bail!("No measurements in {}", path_to_str(&args.file_name));
+    }
+    let ext = match args.obs_report.format {
+        ObsReportFormat::AavsoExtended => "txt",
+        ObsReportFormat::Etd => "dat",
+    };
+    let out_file = args.out.clone()
+        .unwrap_or_else(|| get_processed_file_name(&args.file_name, "report").with_extension(ext));
+    write_file_atomically(&out_file, |tmp_file_name| {
+        let mut file = std::io::BufWriter::new(std::fs::File::create(tmp_file_name)?);
+        write_obs_report(&observations, &args.obs_report, &mut file)?;
+        file.flush()?;
+        Ok(())
+    })?;
+    report!("{} measurement(s) saved to {}", observations.len(), path_to_str(&out_file));
+    Ok(())
+}
+
+fn create_project_from_directory(args: &BatchArgs) -> anyhow::Result<()> {
+    let config = load_config(args)?;
+    let progress = ProgressConsole::new_ts();
+    let cancel_flag = Arc::clone(&args.cancel_flag);
+    let mut result = create_project_from_dir(&args.file_name, &args.auto_groups, &progress, &cancel_flag)?;
+    let project_file = args.out.clone().unwrap_or_else(|| {
+        let name = args.file_name.file_name().and_then(|n| n.to_str()).unwrap_or("project");
+        args.file_name.join(name).with_extension("es_proj")
+    });
+    result.project.save(&project_file)?;
+    report!();
+    for group in &result.groups {
+        report!(
+            "Group {}: {} light, {} dark, {} flat, {} bias file(s)",
+            group.name, group.lights, group.darks, group.flats, group.biases
+        );
+    }
+    if !result.skipped.is_empty() {
+        report!("{} file(s) of unsupported type skipped", result.skipped.len());
+    }
+    report!("Project saved to {}", project_file.to_str().unwrap_or(""));
+    if !args.stack {
+        return Ok(());
+    }
+
+    let mut project = result.project;
+    let reg_info = project.register_light_files(&progress, &cancel_flag, config.effective_cpu_load())?;
+    project.update_light_files_reg_info(reg_info);
+    project.save(&project_file)?;
+    let results = project.stack_groups_separately(
+        None,
+        &progress,
+        &cancel_flag,
+        config.effective_cpu_load(),
+        ResumeMode::Resume,
+        &StackMapsOpts::default()
+    )?;
+    project.save(&project_file)?;
+    report!();
+    for (group_name, result) in &results {
+        report!("Group {}: result file saved to {}", group_name, result.file_name.to_str().unwrap_or(""));
+    }
+    if args.crop_common {
+        let files: Vec<_> = results.iter().map(|(_, result)| result.file_name.clone()).collect();
+        let (x, y, width, height) = crop_to_common_coverage(&files, project.config().fits_save_opts())?;
+        report!("All results cropped to common area {}x{} at ({}, {})", width, height, x, y);
+    }
+    Ok(())
+}
+
+/// Directory (with subdirectories), single image file or
+/// text file with list of image files (one per line)
+fn expand_frame_list(path: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut result = Vec::new();
+    if path.is_dir() {
+        collect_image_files(path, &mut result)?;
+        result.sort();
+    } else if is_source_file_name(path) {
+        result.push(path.to_path_buf());
+    } else {
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| anyhow::anyhow!("Can't read file list {}: {}", path_to_str(path), err))?;
+        let list_dir = path.parent().unwrap_or(Path::new(""));
+        for line in text.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+            result.push(list_dir.join(line));
+        }
+    }
+    if result.is_empty() {
+        anyhow::bail!("No image files in {}", path_to_str(path));
+    }
+    Ok(result)
+}
+
+fn create_difference_image(args: &BatchArgs) -> anyhow::Result<()> {
+    let config = load_config(args)?;
+    let [ref_file, new_file] = args.files.as_slice() else {
+        anyhow::bail!("Reference and new image are needed for --diff");
+    };
+    let load = |file_name: &Path| -> anyhow::Result<(Image, ImageInfo)> {
+        let ImageData { image: RawOrImage::Image(image), info } =
+            load_image_from_file(file_name, false)? else {
+            anyhow::bail!("{} is RAW image", path_to_str(file_name));
+        };
+        Ok((image, info))
+    };
+    let (ref_image, mut info) = load(ref_file)?;
+    let (new_image, _) = load(new_file)?;
+    let result = diff_images(&ref_image, &new_image, &args.diff)?;
+    report!(
+        "New image offset = x:{:.2}, y:{:.2}; rotation = {:.3}°; range factor = {:.4}",
+        result.offset.offset_x, result.offset.offset_y, result.offset.angle.to_degrees(), result.scale
+    );
+    report!("Noise of difference: {:.6}", result.noise);
+
+    let out_file = args.out.clone()
+        .unwrap_or_else(|| get_processed_file_name(new_file, "diff").with_extension("fit"));
+    info.cfa_type = None;
+    let diff_image = Image { l: result.image, ..Image::new() };
+    save_processed_image(args, &diff_image, &mut info, &out_file)?;
+    let wcs = Wcs::load_for_image(ref_file).ok();
+    if let Some(wcs) = &wcs {
+        wcs.save_for_image(&out_file)?;
+    }
+    report!("Difference image saved to {}", path_to_str(&out_file));
+
+    // residual sources
+    let fmt = &config.report_format;
+    let sep = fmt.csv_separator().to_string();
+    let header = ["x", "y", "ra", "dec", "flux", "peak", "pixels", "radius", "snr", "on_star"];
+    let mut text = header.join(&sep) + "\n";
+    for source in &result.sources {
+        let (ra, dec) = match &wcs {
+            Some(wcs) => {
+                let (ra, dec) = wcs.pixel_to_world(source.x, source.y);
+                (fmt.float(ra, 6), fmt.float(dec, 6))
+            },
+            None => (String::new(), String::new()),
+        };
+        let values = [
+            fmt.float(source.x, 2), fmt.float(source.y, 2), ra, dec,
+            fmt.float_full(source.flux as f64), fmt.float_full(source.peak as f64),
+            source.pixels.to_string(), fmt.float(source.radius as f64, 2),
+            fmt.float(source.snr as f64, 1), (source.on_star as u8).to_string(),
+        ];
+        text.push_str(&values.join(&sep));
+        text.push('\n');
+    }
+    let csv_file = out_file.with_extension("csv");
+    write_file_atomically(&csv_file, |tmp_file_name| {
+        Ok(std::fs::write(tmp_file_name, &text)?)
+    })?;
+    let on_stars = result.sources.iter().filter(|s| s.on_star).count();
+    report!(
+        "{} residual source(s) ({} on stars of reference image) saved to {}",
+        result.sources.len(), on_stars, path_to_str(&csv_file)
+    );
+    for source in result.sources.iter().filter(|s| !s.on_star).take(10) {
+        report!(
+            "  x={:.1} y={:.1} flux={:.4} snr={:.1} {}",
+            source.x, source.y, source.flux, source.snr,
+            if source.flux > 0.0 { "brighter" } else { "fainter" }
+        );
+    }
+    Ok(())
+}
+
+fn measure_light_curve(args: &BatchArgs) -> anyhow::Result<()> {
+    let config = load_config(args)?;
+    let progress = ProgressConsole::new_ts();
+    let cancel_flag = Arc::clone(&args.cancel_flag);
+    let files = frame_files(&args.file_name)?;
+    let target = match args.phot_stars.first() {
+        Some(StarPos::Sky(coords)) => Some(*coords),
+        _ => None,
+    };
+    let airmass_opts = AirmassOpts { site: args.site, target, extinction: None };
+    let frames = measure_frames(&files, &args.phot_stars, &args.photometry, &airmass_opts, &progress, &cancel_flag)?;
+
+    // mag is differential (or standard with --comp-mag) if comparison star is used
+    let fmt = &config.report_format;
+    let sep = fmt.csv_separator().to_string();
+    let header = ["file", "jd", "mag", "err", "airmass", "comp_mag", "check_mag", "x", "y", "flux"];
+    let mut text = header.join(&sep) + "\n";
+    let mut measured = 0;
+    let opt_float = |value: Option<f64>, precision| value.map(|v| fmt.float(v, precision)).unwrap_or_default();
+    for frame in &frames {
+        let target = frame.stars[0].as_ref();
+        let comp = frame.stars.get(1).map(Option::as_ref);
+        let check = frame.stars.get(2).map(Option::as_ref);
+        let (mag, err) = match (target, comp) {
+            (Some(t), None) => (Some(t.mag), Some(t.err)),
+            (Some(t), Some(Some(c))) => (
+                Some(t.mag - c.mag + args.comp_mag.unwrap_or(0.0)),
+                Some((t.err * t.err + c.err * c.err).sqrt())
+            ),
+            _ => (None, None),
+        };
+        if frame.jd.is_none() && mag.is_some() {
+            log::warn!("Time of frame {} is unknown", path_to_str(&frame.file));
+        }
+        let mag = mag.filter(|_| frame.jd.is_some());
+        if mag.is_some() {
+            measured += 1;
+        }
+        let values = [
+            format!("\"{}\"", path_to_str(&frame.file).replace('"', "\"\"")),
+            opt_float(frame.jd, 6),
+            opt_float(mag, 4),
+            opt_float(err, 4),
+            opt_float(frame.airmass, 4),
+            opt_float(comp.flatten().map(|c| c.mag), 4),
+            opt_float(check.flatten().map(|c| c.mag), 4),
+            opt_float(target.map(|t| t.x), 2),
+            opt_float(target.map(|t| t.y), 2),
+            opt_float(target.map(|t| t.flux), 6),
+        ];
+        text.push_str(&values.join(&sep));
+        text.push('\n');
+    }
+    let out_file = args.out.clone()
+        .unwrap_or_else(|| get_processed_file_name(&args.file_name, "photometry").with_extension("csv"));
+    write_file_atomically(&out_file, |tmp_file_name| {
+        Ok(std::fs::write(tmp_file_name, &text)?)
+    })?;
+    report!();
+    report!(
+        "Target is measured in {} of {} frame(s), light curve saved to {}",
+        measured, frames.len(), path_to_str(&out_file)
+    );
+    Ok(())
+}
+
+/// Used light files of project sorted by time or files of directory or list
+fn frame_files(path: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    if !extract_extension(path).eq_ignore_ascii_case("es_proj") {
+        return expand_frame_list(path);
+    }
+    let mut project = Project::default();
+    project.load(path)?;
+    let mut files: Vec<_> = project.groups().iter()
+        .filter(|group| group.used())
+        .flat_map(|group| group.get_file_list_by_type(ProjectFileType::Light).list().iter())
+        .filter(|file| file.used())
+        .map(|file| (*file.file_time(), file.file_name().clone()))
+        .collect();
+    // files without time are at the end
+    files.sort_by_key(|(time, file_name)| (time.is_none(), *time, file_name.clone()));
+    if files.is_empty() {
+        anyhow::bail!("No used light files in project {}", path_to_str(path));
+    }
+    Ok(files.into_iter().map(|(_, file_name)| file_name).collect())
+}
+
+fn create_timelapse_file(args: &BatchArgs) -> anyhow::Result<()> {
+    load_config(args)?;
+    let progress = ProgressConsole::new_ts();
+    let cancel_flag = Arc::clone(&args.cancel_flag);
+    let files = frame_files(&args.file_name)?;
+    let opts = TimelapseOpts {
+        stretch: args.stretch.unwrap_or(PreviewStretch::Mtf),
+        max_width: args.max_width.or(args.timelapse.max_width),
+        ..args.timelapse.clone()
+    };
+    let out = args.out.clone()
+        .unwrap_or_else(|| get_processed_file_name(&args.file_name, "timelapse").with_extension("gif"));
+    let format = create_timelapse(&files, &out, &opts, &progress, &cancel_flag)?;
+    let what = match format {
+        TimelapseFormat::Gif => "Animated GIF",
+        TimelapseFormat::Apng => "Animated PNG",
+        TimelapseFormat::PngSequence => "PNG files",
+    };
+    report!("{} of {} frame(s) saved to {}", what, files.len(), path_to_str(&out));
+    Ok(())
+}
+
+fn stack_lrgb_channels(args: &BatchArgs) -> anyhow::Result<()> {
+    log::info!("Stacking of LRGB channels into project {:?} started", args.file_name);
+
+    let config = load_config(args)?;
+    let progress = ProgressConsole::new_ts();
+    let cancel_flag = Arc::clone(&args.cancel_flag);
+
+    // every channel is group of new project
+    let mut project = Project::default();
+    let mut channel_infos = Vec::new();
+    for channel in LrgbChannel::ALL {
+        let mut file_names = Vec::new();
+        for (_, path) in args.lrgb_files.iter().filter(|(c, _)| *c == channel) {
+            file_names.extend(expand_frame_list(path)?);
+        }
+        if file_names.is_empty() {
+            continue;
+        }
+        let infos = load_src_file_info_for_files(
+            &file_names,
+            args.hdu.as_deref().and_then(FitsHduSelector::from_str).as_ref(),
+            &cancel_flag,
+            &progress
+        )?;
+        channel_infos.push((channel.name(), infos.clone()));
+        project.add_new_group(GroupOptions { name: Some(channel.name().to_string()) });
+        let group_index = project.groups().len() - 1;
+        project.group_by_index_mut(group_index)
+            .file_list_by_type_mut(ProjectFileType::Light)
+            .add_files_from_src_file_info(infos);
+        report!("{}: {} light file(s)", channel.name(), file_names.len());
+    }
+    let channels: Vec<_> = channel_infos.iter().map(|(name, infos)| (*name, infos.as_slice())).collect();
+    report_compatibility(args, &check_channels_compatibility(&channels), "Light files of channels are incompatible")?;
+    apply_run_config(args, &mut project)?;
+    project.save(&args.file_name)?;
+
+    if let Some(cal_library) = &args.cal_library {
+        assign_library_masters(args, cal_library, &mut project, &progress, &cancel_flag)?;
+    }
+    let reg_info = project.register_light_files(&progress, &cancel_flag, config.effective_cpu_load())?;
+    project.update_light_files_reg_info(reg_info);
+    project.save(&args.file_name)?;
+
+    // reference image of luminance (or red if there is no L) is
+    // used for all channels so all stacks have same geometry
+    let master_group = 0;
+    let resume = if args.force { ResumeMode::Force } else { ResumeMode::Resume };
+    let results = project.stack_groups_separately(
+        Some(master_group),
+        &progress,
+        &cancel_flag,
+        config.effective_cpu_load(),
+        resume,
+        &args.stack_maps
+    )?;
+    project.save(&args.file_name)?;
+    report!();
+    for (group_name, result) in &results {
+        report!("{}: result file saved to {}", group_name, path_to_str(&result.file_name));
+    }
+    let files: Vec<_> = results.iter().map(|(_, result)| result.file_name.clone()).collect();
+    let (x, y, width, height) = crop_to_common_coverage(&files, project.config().fits_save_opts())?;
+    report!("All channels cropped to common area {}x{} at ({}, {})", width, height, x, y);
+
+    let mut layers = Vec::new();
+    let mut info = None;
+    let mut meta = IntegrationMeta::default();
+    for (group_name, result) in &results {
+        let ImageData { image: RawOrImage::Image(image), info: channel_info } =
+            load_image_from_file(&result.file_name, false)? else {
+            anyhow::bail!("{} is RAW image", path_to_str(&result.file_name));
+        };
+        if image.is_rgb() {
+            anyhow::bail!("Stack of channel {} is color image, mono files are needed", group_name);
+        }
+        let channel = LrgbChannel::ALL.into_iter()
+            .find(|c| c.name() == group_name)
+            .ok_or_else(|| anyhow::anyhow!("Unknown channel {}", group_name))?;
+        meta.add_stack(&channel_info);
+        info.get_or_insert(channel_info);
+        layers.push((channel, image.l));
+    }
+    let channels: Vec<_> = layers.iter().map(|(channel, layer)| (*channel, layer)).collect();
+    let merged = merge_lrgb(&channels, &args.lrgb)?;
+    if merged.cores.found != 0 {
+        report!(
+            "Clipped areas: {} found, {} reconstructed, {} desaturated",
+            merged.cores.found, merged.cores.reconstructed, merged.cores.desaturated
+        );
+    }
+    if let Some(lum_scale) = merged.lum_scale {
+        report!("Luminance: L * {:.4} replaces luminance of RGB (weight {:.2})", lum_scale, args.lrgb.lum_weight);
+    }
+    let out_file = args.out.clone()
+        .unwrap_or_else(|| args.file_name.with_file_name(format!(
+            "{}_lrgb.fit",
+            args.file_name.file_stem().and_then(|s| s.to_str()).unwrap_or("result")
+        )));
+    let mut info = info.unwrap_or_default();
+    info.cfa_type = None;
+    meta.apply_to_info(&mut info);
+    save_processed_image(args, &merged.image, &mut info, &out_file)?;
+    report!(
+        "LRGB image {}x{} saved to {}",
+        merged.image.width(), merged.image.height(), path_to_str(&out_file)
+    );
+    Ok(())
+}
+
+fn run_siril_script(args: &BatchArgs) -> anyhow::Result<()> {
+    let config = load_config(args)?;
+    let text = std::fs::read_to_string(&args.file_name)?;
+    let commands = parse_siril_script(&text)?;
+    let work_dir = match &args.watch_dir {
+        Some(dir) => dir.clone(),
+        None => std::env::current_dir()?,
+    };
+    let progress = ProgressConsole::new_ts();
+    let mut runner = SirilScriptRunner::new(&work_dir, &config, &progress, &args.cancel_flag);
+    runner.run(&commands)?;
+    report!();
+    for result in &runner.results {
+        report!("Result file saved to {}", result.to_str().unwrap_or(""));
+    }
+    Ok(())
+}
+
+fn extract_duoband_channels(args: &BatchArgs) -> anyhow::Result<()> {
+    load_config(args)?;
+    let (image, mut info) = load_processed_image(args)?;
+    let channels = extract_duoband(&image, args.green_weight)?;
+    for (channel, suffix) in [(&channels.ha, "ha"), (&channels.oiii, "oiii")] {
+        let mut out_file = get_processed_file_name(&args.file_name, suffix);
+        if let Some(out_dir) = &args.out {
+            std::fs::create_dir_all(out_dir)?;
+            out_file = out_dir.join(out_file.file_name().unwrap_or_default());
+        }
+        info.cfa_type = None;
+        save_processed_image(args, channel, &mut info, &out_file)?;
+        report!("{} saved to {}", suffix.to_uppercase(), out_file.to_str().unwrap_or(""));
+    }
+    Ok(())
+}
+
+fn create_mosaic_from_panels(args: &BatchArgs) -> anyhow::Result<()> {
+    load_config(args)?;
+    let progress = ProgressConsole::new_ts();
+    let result = create_mosaic(&args.files, &args.mosaic, &progress)?;
+    let out_file = args.out.clone()
+        .unwrap_or_else(|| args.file_name.with_file_name("mosaic.fit"));
+    let mut info = ImageInfo::default();
+    result.meta.apply_to_info(&mut info);
+    save_processed_image(args, &result.image, &mut info, &out_file)?;
+    result.wcs.save_for_image(&out_file)?;
+    report!();
+    report!(
+        "Mosaic {}x{} of {} panels saved to {}",
+        result.image.width(), result.image.height(), args.files.len(),
+        out_file.to_str().unwrap_or("")
+    );
+    Ok(())
+}
+
+fn apply_transforms_from_file(args: &BatchArgs) -> anyhow::Result<()> {
+    load_config(args)?;
+    let transforms = load_transforms_file(&args.file_name)?;
+    if let Some(out_dir) = &args.out {
+        std::fs::create_dir_all(out_dir)?;
+    }
+    let progress = ProgressConsole::new_ts();
+    progress.lock().unwrap().stage(&format!("Applying {} transform(s)...", transforms.len()));
+    for (index, transform) in transforms.iter().enumerate() {
+        if (args.cancel_flag)() { anyhow::bail!(gettext("Cancelled")); }
+        let file_name = transform.file.to_str().unwrap_or("");
+        progress.lock().unwrap().percent(index, transforms.len(), file_name);
+        let (image, mut info) = load_and_apply_transform(transform, args.interpolation.unwrap_or_default())
+            .map_err(|err| anyhow::anyhow!("{}: {}", file_name, err))?;
+        let stem = transform.file.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+        let out_dir = args.out.clone()
+            .unwrap_or_else(|| transform.file.parent().map(|p| p.to_path_buf()).unwrap_or_default());
+        let out_file = out_dir.join(format!("{}_aligned.fit", stem));
+        save_processed_image(args, &image, &mut info, &out_file)?;
+    }
+    report!();
+    report!("{} aligned file(s) saved", transforms.len());
+    Ok(())
+}
+
+fn suggest_project_params(args: &BatchArgs) -> anyhow::Result<()> {
+    log::info!("Analyzing of project {:?} started", args.file_name);
+
+    let config = load_config(args)?;
+
+    let progress = ProgressConsole::new_ts();
+    let cancel_flag: IsCancelledFun = Arc::new(|| false);
+
+    let mut project = load_and_register_project(args, &config, &progress, &cancel_flag)?;
+    let (new_config, suggestions) = suggest_project_config(&project)?;
+
+    report!();
+    let mut noise: Vec<f32> = project.groups().iter()
+        .filter(|g| g.used())
+        .flat_map(|g| g.light_files.list().iter())
+        .filter(|f| f.used())
+        .filter_map(|f| f.reg_info().as_ref().map(|info| info.noise_est))
+        .filter(|v| *v > 0.0)
+        .collect();
+    if !noise.is_empty() {
+        noise.sort_by(crate::calc::cmp_f32);
+        report!(
+            "Noise of light files (k-sigma): min {:.3e}, median {:.3e}, max {:.3e}",
+            noise[0], noise[noise.len() / 2], noise[noise.len() - 1]
+        );
+    }
+    if suggestions.is_empty() {
+        report!("Current parameters are fine for this dataset");
+        return Ok(());
+    }
+    for suggestion in &suggestions {
+        report!("{} = {}", suggestion.param, suggestion.value);
+        report!("    {}", suggestion.reason);
+        log::info!("Suggested {}={} ({})", suggestion.param, suggestion.value, suggestion.reason);
+    }
+
+    if args.write {
+        // only suggested changes are saved, not preset or options of command line
+        let mut layers = ConfigLayers::new(project.saved_config())?;
+        layers.add_changes(ConfigSource::Project, project.config(), &new_config)?;
+        project.set_new_config(layers.get()?);
+        project.save(&args.file_name)?;
+        report!("Parameters are written into {}", args.file_name.to_str().unwrap_or(""));
+    }
+
+    Ok(())
+}
+
+fn extract_ser_frames(args: &BatchArgs) -> anyhow::Result<()> {
+    log::info!("Extracting of frames from {:?} started", args.file_name);
+
+    let mut ser_file = SerFile::open(&args.file_name)?;
+    let out_dir = args.out.clone().unwrap_or_else(|| {
+        let mut dir_name = args.file_name.file_stem().unwrap_or_default().to_os_string();
+        dir_name.push("_frames");
+        args.file_name.with_file_name(dir_name)
+    });
+
+    let progress = ProgressConsole::new_ts();
+    progress.lock().unwrap().stage(&format!(
+        "Extracting frames of {}x{} SER video ({} frames)...",
+        ser_file.width, ser_file.height, ser_file.frames
+    ));
+    let files = ser_file.extract_frames(
+        args.frames.clone(),
+        &out_dir,
+        FitsSaveOpts {
+            bitpix: args.bitpix.unwrap_or(FitsBitPix::Int16),
+            compression: args.compress.unwrap_or(FitsCompression::None),
+            compat: args.compat.unwrap_or(FitsCompat::Default),
+            quantization: args.quantization.unwrap_or_default(),
+            range: args.output_range.unwrap_or_default(),
+            pedestal: args.pedestal.unwrap_or(0.0),
+        },
+        &progress
+    )?;
+
+    report!();
+    report!("{} frame(s) saved into {}", files.len(), out_dir.to_str().unwrap_or(""));
+
+    Ok(())
+}
+
+/// Deconvolution can be stopped (Ctrl+C or cancel of job) and continued by `--resume`
+/// from last snapshot. It also can be resumed with bigger `--iterations` after finish
+fn deconvolve_image(args: &BatchArgs) -> anyhow::Result<()> {
+    load_config(args)?;
+    let (image, mut info) = load_processed_image(args)?;
+    let out_file = args.out.clone()
+        .unwrap_or_else(|| get_processed_file_name(&args.file_name, "deconv"));
+    let checkpoint_image_file = get_processed_file_name(&out_file, "checkpoint").with_extension("fit");
+    let checkpoint_file = checkpoint_image_file.with_extension("json");
+    let source_state = get_file_state_str(&args.file_name);
+    let opts = &args.deconv;
+
+    // PSF
+
+    let user_gaussian = opts.psf == PsfSource::Gaussian
+        && opts.psf_fwhm.is_some()
+        && opts.eccentricity.is_some();
+    let measured = if user_gaussian && opts.protect_stars.is_none() {
+        None
+    } else {
+        let measured = measure_psf(&image)?;
+        report!(
+            "Stars: FWHM = {:.2} pixels, eccentricity = {:.2}, angle = {:.1}°",
+            measured.fwhm, measured.eccentricity, measured.angle
+        );
+        Some(measured)
+    };
+    let psf = match (opts.psf, &measured) {
+        (PsfSource::Stars, Some(measured)) =>
+            Psf::from_stars(measured)?,
+        _ => Psf::gaussian(
+            opts.psf_fwhm.or(measured.as_ref().map(|m| m.fwhm)).unwrap_or_default(),
+            opts.eccentricity.or(measured.as_ref().map(|m| m.eccentricity)).unwrap_or_default(),
+            opts.psf_angle.or(measured.as_ref().map(|m| m.angle)).unwrap_or_default(),
+        )?,
+    };
+    report!("PSF: {}", psf.descr());
+
+    let protection_mask = match (&args.mask_file, opts.protect_stars, &measured) {
+        (Some(mask_file), _, _) =>
+            Some(load_star_mask(mask_file, image.width(), image.height())?),
+        (None, Some(min_peak), Some(measured)) =>
+            Some(create_stars_protection_mask(&image, min_peak, measured.fwhm)?),
+        _ => None,
+    };
+
+    // initial estimate
+
+    let checkpoint = if opts.resume && checkpoint_file.is_file() {
+        let checkpoint = DeconvCheckpoint::load(&checkpoint_file)?;
+        if checkpoint.source_state != source_state {
+            anyhow::bail!("Image is changed after checkpoint, run without --resume");
+        }
+        if checkpoint.psf != psf.descr() {
+            anyhow::bail!("PSF of checkpoint is different ({})", checkpoint.psf);
+        }
+        Some(checkpoint)
+    } else {
+        None
+    };
+
+    let (mut estimate, first_iteration) = if let Some(checkpoint) = checkpoint {
+        let ImageData { image: RawOrImage::Image(estimate), .. } =
+            load_image_from_file(&checkpoint_image_file, false)? else {
+            anyhow::bail!("Wrong checkpoint file");
+        };
+        report!("Resuming from iteration {}", checkpoint.iteration);
+        (estimate, checkpoint.iteration)
+    } else {
+        let estimate = Image {
+            r: image.r.clone(),
+            g: image.g.clone(),
+            b: image.b.clone(),
+            l: image.l.clone(),
+        };
+        (estimate, 0)
+    };
+
+    let protected = |estimate: &Image| -> Image {
+        let mut result = Image {
+            r: estimate.r.clone(),
+            g: estimate.g.clone(),
+            b: estimate.b.clone(),
+            l: estimate.l.clone(),
+        };
+        if let Some(mask) = &protection_mask {
+            apply_stars_protection(&image, &mut result, mask);
+        }
+        result
+    };
+
+    let preview_opts = PreviewOpts::default();
+    let mut snapshot = |iteration: usize, estimate: &Image| -> anyhow::Result<()> {
+        let preview_file = get_processed_file_name(&out_file, &format!("iter{:03}", iteration))
+            .with_extension("jpg");
+        save_preview_file(&protected(estimate), &preview_file, &preview_opts)?;
+        let fits_opts = FitsSaveOpts { bitpix: FitsBitPix::Float32, ..FitsSaveOpts::default() };
+        write_file_atomically(&checkpoint_image_file, |tmp_file_name| {
+            save_image_to_fits_file(estimate, &ImageInfo::default(), tmp_file_name, fits_opts)
+        })?;
+        DeconvCheckpoint { source_state: source_state.clone(), psf: psf.descr().to_string(), iteration }
+            .save(&checkpoint_file)?;
+        log::info!("Deconvolution snapshot of iteration {} saved", iteration);
+        Ok(())
+    };
+
+    let progress = ProgressConsole::new_ts();
+    progress.lock().unwrap().stage("Deconvolution...");
+    let done = richardson_lucy(
+        &image,
+        &mut estimate,
+        &psf,
+        first_iteration,
+        opts,
+        &progress,
+        &args.cancel_flag,
+        &mut snapshot
+    )?;
+    report!();
+    if done < opts.iterations {
+        report!("Stopped at iteration {}, continue by --resume", done);
+        return Ok(());
+    }
+    save_processed_image(args, &protected(&estimate), &mut info, &out_file)?;
+    report!("Result of {} iterations saved to {}", done, out_file.to_str().unwrap_or(""));
+    Ok(())
+}
+
+fn stack_planetary(args: &BatchArgs) -> anyhow::Result<()> {
+    log::info!("Planetary stacking of {:?} started", args.file_name);
+    load_config(args)?;
+    let progress = ProgressConsole::new_ts();
+    let result = stack_planetary_video(&args.file_name, &args.planetary, &progress)?;
+    let out_file = args.out.clone()
+        .unwrap_or_else(|| get_processed_file_name(&args.file_name, "stacked").with_extension("fit"));
+    let mut info = ImageInfo::default();
+    result.meta.apply_to_info(&mut info);
+    save_processed_image(args, &result.image, &mut info, &out_file)?;
+    report!();
+    report!(
+        "{} of {} frames stacked with {} alignment points",
+        result.frames_used, result.frames_total, result.ap_count
+    );
+    report!("Result saved to {}", out_file.to_str().unwrap_or(""));
+    Ok(())
+}
+
+/// Watches capture directory all night: adds new light files into first group
+/// of project, registers them and keeps calibrated and aligned temporary files
+/// up to date. So `--run` in the morning starts from ready temporary files
+fn watch_capture_dir(args: &BatchArgs) -> anyhow::Result<()> {
+    let watch_dir = args.watch_dir.as_ref().unwrap();
+    log::info!("Watching of directory {:?} for project {:?} started", watch_dir, args.file_name);
+
+    let config = load_config(args)?;
+
+    let mut project = Project::default();
+    if args.file_name.is_file() {
+        project.load(&args.file_name)?;
+    }
+    project.add_default_group_if_empty();
+    apply_run_config(args, &mut project)?;
+
+    let progress = ProgressConsole::new_ts();
+    let cancel_flag: IsCancelledFun = Arc::new(|| false);
+
+    let mut prev_sizes = HashMap::new();
+
+    report!("Watching {} (press Ctrl+C to stop)...", watch_dir.to_str().unwrap_or(""));
+    loop {
+        let mut new_files = find_completely_written_files(watch_dir, &mut prev_sizes)?;
+        project.groups()[0].light_files.retain_files_if_they_are_not_here(&mut new_files);
+        // rest of files are processed in next iterations
+        if let Some(chunk_size) = config.power_profile.files_chunk_size() {
+            new_files.truncate(chunk_size);
+        }
+
+        if !new_files.is_empty() {
+            let report = add_and_prepare_light_files(
+                &mut project,
+                &args.file_name,
+                new_files,
+                &config,
+                &progress,
+                &cancel_flag
+            )?;
+            report!();
+            for line in report {
+                report!("{}", line);
+            }
+        }
+
+        std::thread::sleep(Duration::from_secs(args.interval.max(1)));
+    }
+}
+
+/// Live stacking for EAA: every new light file of capture directory is
+/// calibrated, registered and added into running stack. Result file
+/// and auto-stretched preview are rewritten after each new portion of files
+fn live_stack_capture_dir(args: &BatchArgs) -> anyhow::Result<()> {
+    let watch_dir = args.watch_dir.as_ref().unwrap();
+    log::info!("Live stacking of directory {:?} for project {:?} started", watch_dir, args.file_name);
+
+    let config = load_config(args)?;
+
+    let mut project = Project::default();
+    if args.file_name.is_file() {
+        project.load(&args.file_name)?;
+    }
+    project.add_default_group_if_empty();
+    apply_run_config(args, &mut project)?;
+
+    let progress = ProgressConsole::new_ts();
+    let cancel_flag = Arc::clone(&args.cancel_flag);
+    let preview_opts = PreviewOpts {
+        stretch: args.stretch.unwrap_or(PreviewStretch::Mtf),
+        max_width: args.max_width,
+        .. PreviewOpts::default()
+    };
+
+    let mut prev_sizes = HashMap::new();
+    let mut live_stack = LiveStack::new();
+
+    report!("Live stacking {} (press Ctrl+C to stop)...", watch_dir.to_str().unwrap_or(""));
+    while !cancel_flag() {
+        let mut new_files = find_completely_written_files(watch_dir, &mut prev_sizes)?;
+        project.groups()[0].light_files.retain_files_if_they_are_not_here(&mut new_files);
+        // rest of files are processed in next iterations
+        if let Some(chunk_size) = config.power_profile.files_chunk_size() {
+            new_files.truncate(chunk_size);
+        }
+
+        if !new_files.is_empty() {
+            let report = add_and_register_light_files(
+                &mut project,
+                &args.file_name,
+                new_files,
+                &config,
+                &progress,
+                &cancel_flag
+            )?;
+            report!();
+            for line in report {
+                report!("{}", line);
+            }
+        }
+
+        if project.is_ref_image_assigned() {
+            let added = project.add_to_live_stack(&mut live_stack, &progress, &cancel_flag, config.effective_cpu_load())?;
+            if added != 0 {
+                let result_file = project.get_result_file_name()?;
+                let result = live_stack.save(&result_file, project.config().fits_save_opts())?;
+                let preview_file = args.out.clone()
+                    .unwrap_or_else(|| get_preview_file_name(&result_file));
+                save_preview_file(&result, &preview_file, &preview_opts)?;
+                report!(
+                    "{} light file(s) added, {} in stack. Preview saved to {}",
+                    added, live_stack.frames_count(), preview_file.to_str().unwrap_or("")
+                );
+            }
+        }
+
+        std::thread::sleep(Duration::from_secs(args.interval.max(1)));
+    }
+    Ok(())
+}
+
+/// Files which are still being written by capture program are
+/// skipped until their size stops changing between calls
+pub fn find_completely_written_files(
+    dir:        &Path,
+    prev_sizes: &mut HashMap<PathBuf, u64>,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let mut result = Vec::new();
+    let mut cur_sizes = HashMap::new();
+    for entry in std::fs::read_dir(dir)?.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        if !path.is_file() || !is_source_file_name(&path) { continue; }
+        if extract_file_name(&path).contains(".partial.") { continue; }
+        let Ok(metadata) = entry.metadata() else { continue; };
+        if prev_sizes.get(&path) == Some(&metadata.len()) {
+            result.push(path.clone());
+        }
+        cur_sizes.insert(path, metadata.len());
+    }
+    *prev_sizes = cur_sizes;
+    result.sort();
+    Ok(result)
+}
+
+/// Adds new light files into first group of project, registers them
+/// and creates temporary files for stacking. Returns text report
+pub fn add_and_prepare_light_files(
+    project:      &mut Project,
+    project_file: &Path,
+    new_files:    Vec<PathBuf>,
+    config:       &Config,
+    progress:     &ProgressTs,
+    cancel_flag:  &IsCancelledFun,
+) -> anyhow::Result<Vec<String>> {
+    let mut report = add_and_register_light_files(
+        project,
+        project_file,
+        new_files,
+        config,
+        progress,
+        cancel_flag
+    )?;
+    if project.is_ref_image_assigned() {
+        let count = project.update_temp_light_files(progress, cancel_flag, config.effective_cpu_load())?;
+        report.push(format!("{} light file(s) are ready for stacking", count));
+    }
+    Ok(report)
+}
+
+/// Adds new light files into first group of project, registers them and
+/// assigns reference image if it is not assigned yet. Returns text report
+fn add_and_register_light_files(
+    project:      &mut Project,
+    project_file: &Path,
+    new_files:    Vec<PathBuf>,
+    config:       &Config,
+    progress:     &ProgressTs,
+    cancel_flag:  &IsCancelledFun,
+) -> anyhow::Result<Vec<String>> {
+    let files_info = load_src_file_info_for_files(
+        &new_files,
+        project.config().raw_params.fits_hdu_selector().as_ref(),
+        cancel_flag,
+        progress
+    )?;
+    let temperatures: HashMap<PathBuf, f32> = files_info.iter()
+        .filter_map(|info| info.temperature.map(|t| (info.file_name.clone(), t)))
+        .collect();
+    project.group_by_index_mut(0).light_files.add_files_from_src_file_info(files_info);
+
+    let reg_info = project.register_new_light_files(progress, cancel_flag, config.effective_cpu_load())?;
+    let fmt = &config.report_format;
+    let mut report = Vec::new();
+    for file_name in &new_files {
+        match reg_info.get(file_name) {
+            Some(Ok(info)) => {
+                let mut line = format!(
+                    "{}: noise={}, bg={}, fwhm={}, stars={}, r.dev={}",
+                    extract_file_name(file_name),
+                    fmt.float(info.noise as f64, 5),
+                    fmt.float(info.background as f64, 4),
+                    fmt.length(info.fwhm as f64, 2),
+                    info.stars,
+                    fmt.float(info.stars_r_dev as f64, 3),
+                );
+                if let Some(temperature) = temperatures.get(file_name) {
+                    line.push_str(&format!(", temp={}", fmt.temperature(*temperature as f64)));
+                }
+                report.push(line);
+            },
+            Some(Err(err)) =>
+                report.push(format!("{}: {}", extract_file_name(file_name), err)),
+            None => {},
+        }
+    }
+    project.update_light_files_reg_info(reg_info);
+
+    // reference image is selected only once because temporary
+    // files have to be processed again if it is changed
+    if !project.is_ref_image_assigned()
+    && project.is_possible_assign_ref_light_frame_automatically() {
+        project.assign_ref_light_frame_automatically();
+    }
+    project.save(project_file)?;
+
+    Ok(report)
+}
@@ -0,0 +1,110 @@
+use rayon::prelude::*;
+use serde::{Serialize, Deserialize};
+use crate::{image::*, calc::*, noise::*};
+
+/* Masks of bright objects (nebulae, galaxies) for background modeling.
+   Image is smoothed by B3 spline up to scale where stars vanish, pixels
+   brighter than background by `threshold` sigmas of smoothed image are
+   masked and mask is dilated to cover faint outskirts of objects */
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+pub enum BgMaskPreset {
+    #[default]
+    Stars,      // only stars are masked
+    Objects,    // stars and bright nebulosity
+    Aggressive, // stars and faint nebulosity with wide border
+}
+
+impl BgMaskPreset {
+    pub fn from_str(text: &str) -> anyhow::Result<Self> {
+        match text.to_lowercase().as_str() {
+            "stars"      => Ok(BgMaskPreset::Stars),
+            "objects"    => Ok(BgMaskPreset::Objects),
+            "aggressive" => Ok(BgMaskPreset::Aggressive),
+            _ => anyhow::bail!("Wrong background mask {} (stars, objects or aggressive)", text),
+        }
+    }
+
+    pub fn objects_opts(self) -> Option<ObjectsMaskOpts> {
+        match self {
+            BgMaskPreset::Stars => None,
+            BgMaskPreset::Objects => Some(ObjectsMaskOpts { threshold: 3.0, dilation: 8 }),
+            BgMaskPreset::Aggressive => Some(ObjectsMaskOpts { threshold: 1.5, dilation: 16 }),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ObjectsMaskOpts {
+    pub threshold: f32, // in sigmas of smoothed image above background
+    pub dilation:  Crd, // pixels
+}
+
+const SMOOTH_STEPS: [usize; 3] = [1, 2, 4];
+const MAD_TO_SIGMA: f32 = 1.4826;
+
+/// Marks bright objects of greyscale image in `mask`
+pub fn mask_bright_objects(grey: &ImageLayerF32, opts: &ObjectsMaskOpts, mask: &mut ImageMask) {
+    let (width, height) = (grey.width() as usize, grey.height() as usize);
+    if width < 16 || height < 16 { return; }
+    let (mut data, valid) = prepare_data(grey);
+    for step in SMOOTH_STEPS {
+        data = b3_smooth(&data, width, height, step);
+    }
+
+    let sample_step = (data.len() / 200_000).max(1);
+    let mut values: Vec<f32> = data.iter().zip(&valid)
+        .step_by(sample_step)
+        .filter(|(_, v)| **v)
+        .map(|(d, _)| *d)
+        .collect();
+    let Some(bg) = median_f32(&mut values) else { return; };
+    for v in &mut values { *v = (*v - bg).abs(); }
+    let sigma = MAD_TO_SIGMA * median_f32(&mut values).unwrap_or(0.0);
+    if sigma <= 0.0 { return; }
+
+    let threshold = bg + opts.threshold * sigma;
+    let bright: Vec<bool> = data.iter().zip(&valid)
+        .map(|(d, v)| *v && *d > threshold)
+        .collect();
+    let bright = dilate(&bright, width, height, opts.dilation.max(0) as usize);
+    for (m, b) in mask.iter_mut().zip(&bright) {
+        if *b { *m = true; }
+    }
+}
+
+// Square dilation by separable running windows
+fn dilate(mask: &[bool], width: usize, height: usize, radius: usize) -> Vec<bool> {
+    if radius == 0 { return mask.to_vec(); }
+    let dilate_line = |src: &mut dyn Iterator<Item = bool>, dst: &mut [bool]| {
+        let line: Vec<bool> = src.collect();
+        let mut last_set: Option<usize> = None;
+        // distance to nearest set value at the left and at the right
+        let mut left = vec![usize::MAX; line.len()];
+        for (i, v) in line.iter().enumerate() {
+            if *v { last_set = Some(i); }
+            if let Some(pos) = last_set { left[i] = i - pos; }
+        }
+        last_set = None;
+        for (i, v) in line.iter().enumerate().rev() {
+            if *v { last_set = Some(i); }
+            let right = last_set.map(|pos| pos - i).unwrap_or(usize::MAX);
+            dst[i] = left[i].min(right) <= radius;
+        }
+    };
+    let mut rows = vec![false; mask.len()];
+    rows.par_chunks_mut(width).enumerate().for_each(|(y, row)| {
+        dilate_line(&mut mask[y * width..(y + 1) * width].iter().copied(), row);
+    });
+    let mut columns = vec![false; mask.len()];
+    columns.par_chunks_mut(height).enumerate().for_each(|(x, col)| {
+        dilate_line(&mut (0..height).map(|y| rows[y * width + x]), col);
+    });
+    let mut result = vec![false; mask.len()];
+    for (x, col) in columns.chunks(height).enumerate() {
+        for (y, v) in col.iter().enumerate() {
+            result[y * width + x] = *v;
+        }
+    }
+    result
+}
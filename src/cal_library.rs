@@ -0,0 +1,325 @@
+use std::path::*;
+use serde::*;
+use chrono::prelude::*;
+use crate::{project::*, image::*, image_io::*, progress::*, fs_utils::*};
+
+/* Library of master darks, flats and biases shared between projects.
+   Masters are copied into directory of library and indexed by camera,
+   size, gain, exposure, sensor temperature, filter and date. Groups of
+   project without own calibration files get best matching masters */
+
+const INDEX_FILE_NAME: &str = "cal-library.json";
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum CalFileType {
+    Dark,
+    Flat,
+    Bias,
+}
+
+impl CalFileType {
+    pub const ALL: [CalFileType; 3] = [CalFileType::Dark, CalFileType::Flat, CalFileType::Bias];
+
+    pub fn project_file_type(self) -> ProjectFileType {
+        match self {
+            CalFileType::Dark => ProjectFileType::Dark,
+            CalFileType::Flat => ProjectFileType::Flat,
+            CalFileType::Bias => ProjectFileType::Bias,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            CalFileType::Dark => "dark",
+            CalFileType::Flat => "flat",
+            CalFileType::Bias => "bias",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CalLibEntry {
+    pub file:        PathBuf, // relative to directory of library
+    pub file_type:   CalFileType,
+    pub camera:      Option<String>,
+    pub width:       Crd,
+    pub height:      Crd,
+    pub gain:        Option<u32>,
+    pub exposure:    Option<f32>,
+    pub temperature: Option<f32>, // mean of source files
+    pub filter:      Option<String>,
+    pub date:        Option<DateTime<Local>>,
+    pub frames:      usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct CalMatchTolerances {
+    pub temperature: f32, // °C
+    pub exposure:    f32, // relative difference of dark exposure
+    pub dark_days:   i64, // max age of dark and bias
+    pub flat_days:   i64, // max age of flat
+}
+
+impl Default for CalMatchTolerances {
+    fn default() -> Self {
+        Self {
+            temperature: 2.0,
+            exposure:    0.1,
+            dark_days:   180,
+            flat_days:   3,
+        }
+    }
+}
+
+pub struct CalLibMatch<'a> {
+    pub entry:    &'a CalLibEntry,
+    pub warnings: Vec<String>, // empty for good match
+}
+
+/// Master of library assigned to group of project
+pub struct AssignedMaster {
+    pub group:     String,
+    pub file_type: CalFileType,
+    pub file:      Option<PathBuf>, // None if library has no suitable master
+    pub warnings:  Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct CalLibrary {
+    #[serde(skip)]
+    dir: PathBuf,
+    pub entries: Vec<CalLibEntry>,
+}
+
+impl CalLibrary {
+    /// Reads index of library. Library without index is empty
+    pub fn open(dir: &Path) -> anyhow::Result<Self> {
+        let index_file = dir.join(INDEX_FILE_NAME);
+        let mut library = if index_file.is_file() {
+            let text = std::fs::read_to_string(&index_file)?;
+            serde_json::from_str::<CalLibrary>(&text).map_err(|err| anyhow::anyhow!(
+                "Wrong index of calibration library {}: {}", path_to_str(&index_file), err
+            ))?
+        } else {
+            CalLibrary::default()
+        };
+        library.dir = dir.to_path_buf();
+        Ok(library)
+    }
+
+    pub fn save(&self) -> anyhow::Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        let text = serde_json::to_string_pretty(self)?;
+        write_file_atomically(&self.dir.join(INDEX_FILE_NAME), |tmp_file_name| {
+            Ok(std::fs::write(tmp_file_name, &text)?)
+        })
+    }
+
+    pub fn full_file_name(&self, entry: &CalLibEntry) -> PathBuf {
+        self.dir.join(&entry.file)
+    }
+
+    /// Copies master file into library. `sources` are infos of files
+    /// master is created from (for temperature, filter and date).
+    /// Master with same parameters is replaced
+    pub fn add_master(
+        &mut self,
+        master_file: &Path,
+        file_type:   CalFileType,
+        sources:     &[ImageInfo],
+    ) -> anyhow::Result<&CalLibEntry> {
+        let raw_info = load_master_format_file(master_file)?.info;
+        let temperatures: Vec<f32> = sources.iter().filter_map(|info| info.temperature).collect();
+        let mut entry = CalLibEntry {
+            file:        PathBuf::new(),
+            file_type,
+            camera:      raw_info.camera.clone().or_else(|| sources.iter().find_map(|info| info.camera.clone())),
+            width:       raw_info.width,
+            height:      raw_info.height,
+            gain:        raw_info.iso.or_else(|| sources.iter().find_map(|info| info.iso)),
+            exposure:    raw_info.exposure.or_else(|| sources.iter().find_map(|info| info.exp.map(|v| v as f32))),
+            temperature: if !temperatures.is_empty() {
+                Some(temperatures.iter().sum::<f32>() / temperatures.len() as f32)
+            } else {
+                None
+            },
+            filter:      sources.iter().find_map(|info| info.filter.clone()),
+            date:        sources.iter().filter_map(|info| info.file_time).min(),
+            frames:      sources.len(),
+        };
+        entry.file = PathBuf::from(Self::entry_file_name(&entry));
+        std::fs::create_dir_all(&self.dir)?;
+        let dst_file = self.dir.join(&entry.file);
+        write_file_atomically(&dst_file, |tmp_file_name| {
+            std::fs::copy(master_file, tmp_file_name)?;
+            Ok(())
+        })?;
+        self.entries.retain(|e| e.file != entry.file);
+        self.entries.push(entry);
+        Ok(self.entries.last().unwrap())
+    }
+
+    // dark-ZWO_ASI294MC-4144x2822-g120-300s-m10C-20240115.es_raw
+    fn entry_file_name(entry: &CalLibEntry) -> String {
+        let mut items = vec![entry.file_type.name().to_string()];
+        let clean = |text: &str| -> String {
+            text.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect()
+        };
+        if let Some(camera) = &entry.camera { items.push(clean(camera)); }
+        items.push(format!("{}x{}", entry.width, entry.height));
+        if let Some(gain) = entry.gain { items.push(format!("g{}", gain)); }
+        if entry.file_type == CalFileType::Dark {
+            if let Some(exposure) = entry.exposure { items.push(format!("{}s", exposure)); }
+        }
+        if let Some(temperature) = entry.temperature {
+            let temperature = temperature.round() as i32;
+            items.push(format!("{}{}C", if temperature < 0 { "m" } else { "" }, temperature.abs()));
+        }
+        if entry.file_type == CalFileType::Flat {
+            if let Some(filter) = &entry.filter { items.push(clean(filter)); }
+        }
+        if let Some(date) = entry.date { items.push(date.format("%Y%m%d").to_string()); }
+        format!("{}.es_raw", items.join("-"))
+    }
+
+    /// Adds master files of all used groups of project
+    /// (masters must be created by `--run` before)
+    pub fn add_project_masters(
+        &mut self,
+        project:      &Project,
+        progress:     &ProgressTs,
+        is_cancelled: &IsCancelledFun,
+    ) -> anyhow::Result<Vec<PathBuf>> {
+        let mut result = Vec::new();
+        let hdu = project.config().raw_params.fits_hdu_selector();
+        for group in project.groups().iter().filter(|g| g.used()) {
+            for file_type in CalFileType::ALL {
+                let files = group.get_file_list_by_type(file_type.project_file_type());
+                let sources: Vec<PathBuf> = files.list().iter()
+                    .filter(|f| f.used())
+                    .map(|f| f.file_name().clone())
+                    .collect();
+                if sources.is_empty() { continue; }
+                let Some(master_file) = group.master_file_name(file_type.project_file_type()) else {
+                    continue;
+                };
+                if !master_file.is_file() {
+                    log::warn!("Master file {} is not created yet", path_to_str(&master_file));
+                    continue;
+                }
+                let infos = load_src_file_info_for_files(&sources, hdu.as_ref(), is_cancelled, progress)?;
+                let entry = self.add_master(&master_file, file_type, &infos)?;
+                result.push(entry.file.clone());
+            }
+        }
+        Ok(result)
+    }
+
+    /// Best master for light file. Master must be of same camera, size
+    /// and gain (and filter for flats). Nearest one by temperature,
+    /// exposure and date is selected
+    pub fn find_best(
+        &self,
+        file_type:  CalFileType,
+        light:      &ImageInfo,
+        tolerances: &CalMatchTolerances,
+    ) -> Option<CalLibMatch<'_>> {
+        let same = |v1: Option<&str>, v2: Option<&str>| match (v1, v2) {
+            (Some(v1), Some(v2)) => v1.eq_ignore_ascii_case(v2),
+            _ => true,
+        };
+        let max_days = if file_type == CalFileType::Flat { tolerances.flat_days } else { tolerances.dark_days };
+        self.entries.iter()
+            .filter(|e| e.file_type == file_type)
+            .filter(|e| e.width == light.width as Crd && e.height == light.height as Crd)
+            .filter(|e| same(e.camera.as_deref(), light.camera.as_deref()))
+            .filter(|e| match (e.gain, light.iso) { (Some(g1), Some(g2)) => g1 == g2, _ => true })
+            .filter(|e| file_type != CalFileType::Flat || same(e.filter.as_deref(), light.filter.as_deref()))
+            .map(|e| {
+                let temp_diff = match (e.temperature, light.temperature) {
+                    (Some(t1), Some(t2)) => (t1 - t2).abs(),
+                    _ => 0.0,
+                };
+                let exp_diff = match (file_type, e.exposure, light.exp) {
+                    (CalFileType::Dark, Some(e1), Some(e2)) if e2 > 0.0 =>
+                        ((e1 as f64 - e2) / e2).abs() as f32,
+                    _ => 0.0,
+                };
+                let days = match (e.date, light.file_time) {
+                    (Some(d1), Some(d2)) => (d1 - d2).num_days().abs(),
+                    _ => 0,
+                };
+                let score =
+                    temp_diff / tolerances.temperature.max(0.1) +
+                    exp_diff / tolerances.exposure.max(0.01) +
+                    days as f32 / max_days.max(1) as f32;
+                (score, e, temp_diff, exp_diff, days)
+            })
+            .min_by(|(s1, ..), (s2, ..)| s1.total_cmp(s2))
+            .map(|(_, entry, temp_diff, exp_diff, days)| {
+                let mut warnings = Vec::new();
+                if temp_diff > tolerances.temperature {
+                    warnings.push(format!("temperature differs by {:.1}°C", temp_diff));
+                }
+                if exp_diff > tolerances.exposure {
+                    warnings.push(format!(
+                        "exposure {}s instead of {}s",
+                        entry.exposure.unwrap_or(0.0), light.exp.unwrap_or(0.0)
+                    ));
+                }
+                if days > max_days {
+                    warnings.push(format!("master is {} days older or newer than light files", days));
+                }
+                CalLibMatch { entry, warnings }
+            })
+    }
+
+    /// Assigns best matching masters to used groups of project which
+    /// have no own calibration files of some type. Parameters of group
+    /// are taken from its first light file
+    pub fn assign_masters_to_project(
+        &self,
+        project:      &mut Project,
+        tolerances:   &CalMatchTolerances,
+        progress:     &ProgressTs,
+        is_cancelled: &IsCancelledFun,
+    ) -> anyhow::Result<Vec<AssignedMaster>> {
+        let mut result = Vec::new();
+        for idx in 0..project.groups().len() {
+            let group = &project.groups()[idx];
+            if !group.used() { continue; }
+            let Some(first_light) = group.light_files.list().iter().find(|f| f.used()) else {
+                continue;
+            };
+            let group_name = group.name(idx);
+            let light_info = load_src_file_info_for_files(
+                &vec![first_light.file_name().clone()],
+                project.config().raw_params.fits_hdu_selector().as_ref(),
+                is_cancelled,
+                progress
+            )?.remove(0);
+            let mut lib_masters = LibMasters::default();
+            for file_type in CalFileType::ALL {
+                let has_own_files = group.get_file_list_by_type(file_type.project_file_type())
+                    .list().iter()
+                    .any(|f| f.used());
+                if has_own_files { continue; }
+                let found = self.find_best(file_type, &light_info, tolerances);
+                let file = found.as_ref().map(|m| self.full_file_name(m.entry));
+                match file_type {
+                    CalFileType::Dark => lib_masters.dark = file.clone(),
+                    CalFileType::Flat => lib_masters.flat = file.clone(),
+                    CalFileType::Bias => lib_masters.bias = file.clone(),
+                }
+                result.push(AssignedMaster {
+                    group:    group_name.clone(),
+                    file_type,
+                    file,
+                    warnings: found.map(|m| m.warnings).unwrap_or_default(),
+                });
+            }
+            project.group_by_index_mut(idx).set_lib_masters(lib_masters);
+        }
+        Ok(result)
+    }
+}
@@ -225,6 +225,54 @@ pub fn calc(values: &mut [CalcValue], opts: &CalcOpts) -> Option<CalcResult> {
     }
 }
 
+/// Running statistics of values which are added one by one (Welford's
+/// algorithm), so values don't have to be kept. Mean and variance are
+/// unweighted (as in kappa-sigma clipping), `weighted_mean` is result.
+/// Accumulators are f64 for thousands of values
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RunningStat {
+    count:  u32,
+    mean:   f64,
+    m2:     f64, // sum of squares of differences from mean
+    weight: f64,
+    sum:    f64, // weighted
+}
+
+impl RunningStat {
+    pub fn add(&mut self, value: f32, weight: f32) {
+        self.count += 1;
+        let value = value as f64;
+        let delta = value - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (value - self.mean);
+        self.weight += weight as f64;
+        self.sum += weight as f64 * value;
+    }
+
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    pub fn mean(&self) -> Option<f32> {
+        if self.count == 0 { return None; }
+        Some(self.mean as f32)
+    }
+
+    pub fn variance(&self) -> Option<f32> {
+        if self.count == 0 { return None; }
+        Some((self.m2 / self.count as f64) as f32)
+    }
+
+    pub fn std_dev(&self) -> Option<f32> {
+        self.variance().map(f32::sqrt)
+    }
+
+    pub fn weighted_mean(&self) -> Option<f32> {
+        if self.weight <= 0.0 { return None; }
+        Some((self.sum / self.weight) as f32)
+    }
+}
+
 pub struct IirFilter {
     a0: f32,
     b0: f32,
@@ -0,0 +1,190 @@
+use crate::{image::*, calc::*, stars::*, light_file::*};
+
+/* Color calibration of RGB images. Backgrounds of channels are neutralized
+   and channels are multiplied by factors calculated by selected mode */
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColorCalibrMode {
+    Stars,     // average color of stars is white
+    GrayWorld, // average color of image is gray
+    LinearFit, // red and blue are fitted to green
+}
+
+impl ColorCalibrMode {
+    pub fn from_str(text: &str) -> anyhow::Result<Self> {
+        match text.to_lowercase().as_str() {
+            "stars"      => Ok(ColorCalibrMode::Stars),
+            "gray-world" => Ok(ColorCalibrMode::GrayWorld),
+            "linear-fit" => Ok(ColorCalibrMode::LinearFit),
+            _ => anyhow::bail!("Wrong color calibration mode {} (stars, gray-world or linear-fit)", text),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct BgRegion {
+    pub x:      Crd,
+    pub y:      Crd,
+    pub width:  Crd,
+    pub height: Crd,
+}
+
+impl BgRegion {
+    /// "x,y,width,height"
+    pub fn from_str(text: &str) -> anyhow::Result<Self> {
+        let values: Vec<Crd> = text.split(',')
+            .map(|v| v.trim().parse())
+            .collect::<Result<_, _>>()
+            .map_err(|_| anyhow::anyhow!("Wrong region {} (x,y,width,height expected)", text))?;
+        let &[x, y, width, height] = values.as_slice() else {
+            anyhow::bail!("Wrong region {} (x,y,width,height expected)", text);
+        };
+        if width <= 0 || height <= 0 {
+            anyhow::bail!("Region {} is empty", text);
+        }
+        Ok(Self { x, y, width, height })
+    }
+}
+
+pub struct ColorCalibrResult {
+    pub background: [f32; 3],
+    pub factors:    [f32; 3],
+    pub stars_used: usize,
+}
+
+fn is_valid(v: f32) -> bool {
+    v.is_finite() && v != NO_VALUE_F32
+}
+
+fn layer_bg(layer: &ImageLayerF32, region: Option<BgRegion>) -> anyhow::Result<f32> {
+    let mut values: Vec<f32> = match region {
+        Some(r) => {
+            if r.x < 0 || r.y < 0 || r.x + r.width > layer.width() || r.y + r.height > layer.height() {
+                anyhow::bail!("Background region is out of image");
+            }
+            layer.iter_rect_crd(r.x, r.y, r.x + r.width - 1, r.y + r.height - 1)
+                .map(|(_, _, v)| v)
+                .filter(|v| is_valid(*v))
+                .collect()
+        }
+        None => {
+            let step = (layer.as_slice().len() / 200_000).max(1);
+            layer.iter().step_by(step).copied().filter(|v| is_valid(*v)).collect()
+        }
+    };
+    median_f32(&mut values).ok_or_else(|| anyhow::anyhow!("Background region has no values"))
+}
+
+fn factors_by_stars(image: &Image, bg: &[f32; 3]) -> anyhow::Result<([f32; 3], usize)> {
+    let grey = image.create_greyscale_layer();
+    let noise = calc_noise(&grey) as f32;
+    let stars = find_stars_on_image(&grey, Some(noise), true, &StarsFindOpts::default())?;
+    let mut ratios_r = Vec::new();
+    let mut ratios_b = Vec::new();
+    for star in stars.iter().filter(|s| !s.overexposured) {
+        let mut sum = [0_f64; 3];
+        for pt in &star.points {
+            for (i, layer) in [&image.r, &image.g, &image.b].into_iter().enumerate() {
+                if let Some(v) = layer.get(pt.x, pt.y).filter(|v| is_valid(*v)) {
+                    sum[i] += (v - bg[i]) as f64;
+                }
+            }
+        }
+        if sum.iter().all(|v| *v > 0.0) {
+            ratios_r.push(sum[1] / sum[0]);
+            ratios_b.push(sum[1] / sum[2]);
+        }
+    }
+    if ratios_r.len() < 5 {
+        anyhow::bail!("Too few stars for color calibration ({}), use gray-world mode", ratios_r.len());
+    }
+    let count = ratios_r.len();
+    let k_r = median_f64(&mut ratios_r).unwrap_or(1.0) as f32;
+    let k_b = median_f64(&mut ratios_b).unwrap_or(1.0) as f32;
+    Ok(([k_r, 1.0, k_b], count))
+}
+
+fn factors_by_gray_world(image: &Image, bg: &[f32; 3]) -> anyhow::Result<[f32; 3]> {
+    // mean of signal above background excluding brightest pixels of stars
+    let signal = |layer: &ImageLayerF32, bg: f32| -> f64 {
+        let mut values: Vec<_> = layer.iter()
+            .copied()
+            .filter(|v| is_valid(*v))
+            .map(|v| v - bg)
+            .collect();
+        let pos = 99 * values.len() / 100;
+        if pos < values.len() {
+            let limit = *values.select_nth_unstable_by(pos, cmp_f32).1;
+            values.retain(|v| *v <= limit);
+        }
+        let sum: f64 = values.iter().filter(|v| **v > 0.0).map(|v| *v as f64).sum();
+        sum / values.len().max(1) as f64
+    };
+    let s = [signal(&image.r, bg[0]), signal(&image.g, bg[1]), signal(&image.b, bg[2])];
+    if s.iter().any(|v| *v <= 0.0) {
+        anyhow::bail!("Image has no signal above background");
+    }
+    Ok([(s[1] / s[0]) as f32, 1.0, (s[1] / s[2]) as f32])
+}
+
+fn factors_by_linear_fit(image: &Image, bg: &[f32; 3]) -> anyhow::Result<[f32; 3]> {
+    // least squares fit of channel to green (line through background point)
+    let fit = |layer: &ImageLayerF32, layer_bg: f32| -> f64 {
+        let mut xy = 0.0;
+        let mut xx = 0.0;
+        for (v, g) in layer.iter().zip(image.g.iter()) {
+            if !is_valid(*v) || !is_valid(*g) || *v >= 1.0 || *g >= 1.0 { continue; }
+            let x = (v - layer_bg) as f64;
+            let y = (g - bg[1]) as f64;
+            xy += x * y;
+            xx += x * x;
+        }
+        if xx > 0.0 { xy / xx } else { 0.0 }
+    };
+    let k_r = fit(&image.r, bg[0]);
+    let k_b = fit(&image.b, bg[2]);
+    if k_r <= 0.0 || k_b <= 0.0 {
+        anyhow::bail!("Linear fit of channels failed");
+    }
+    Ok([k_r as f32, 1.0, k_b as f32])
+}
+
+pub fn calibrate_colors(
+    image:     &mut Image,
+    mode:      ColorCalibrMode,
+    bg_region: Option<BgRegion>,
+) -> anyhow::Result<ColorCalibrResult> {
+    if !image.is_rgb() {
+        anyhow::bail!("Color calibration needs RGB image");
+    }
+    let background = [
+        layer_bg(&image.r, bg_region)?,
+        layer_bg(&image.g, bg_region)?,
+        layer_bg(&image.b, bg_region)?,
+    ];
+    let (factors, stars_used) = match mode {
+        ColorCalibrMode::Stars => factors_by_stars(image, &background)?,
+        ColorCalibrMode::GrayWorld => (factors_by_gray_world(image, &background)?, 0),
+        ColorCalibrMode::LinearFit => (factors_by_linear_fit(image, &background)?, 0),
+    };
+
+    // factors are normalized to keep max. factor 1 so no new clipping occurs
+    let max_factor = factors.iter().copied().fold(0.0_f32, f32::max);
+    let factors = factors.map(|k| k / max_factor);
+
+    // background of all channels is moved to green background
+    let target_bg = background[1] * factors[1];
+    for (layer, bg, k) in itertools::izip!(
+        [&mut image.r, &mut image.g, &mut image.b],
+        background,
+        factors
+    ) {
+        for v in layer.iter_mut() {
+            if is_valid(*v) {
+                *v = (*v - bg) * k + target_bg;
+            }
+        }
+    }
+
+    Ok(ColorCalibrResult { background, factors, stars_used })
+}
@@ -0,0 +1,92 @@
+use std::path::*;
+
+/* Color maps for false-color rendering of scalar data. Besides built-in
+   maps and custom LUT text files can be used: one color per line as
+   three numbers (0..255 or 0..1) separated by spaces or commas */
+
+pub struct Colormap {
+    stops: Vec<[f32; 3]>, // evenly distributed colors from 0 to 1
+}
+
+// viridis of matplotlib sampled in 9 points
+const VIRIDIS: [[f32; 3]; 9] = [
+    [0.267, 0.005, 0.329],
+    [0.278, 0.175, 0.483],
+    [0.231, 0.322, 0.546],
+    [0.173, 0.449, 0.558],
+    [0.128, 0.567, 0.551],
+    [0.155, 0.683, 0.499],
+    [0.360, 0.785, 0.387],
+    [0.668, 0.862, 0.196],
+    [0.993, 0.906, 0.144],
+];
+
+// inferno of matplotlib sampled in 9 points
+const INFERNO: [[f32; 3]; 9] = [
+    [0.001, 0.000, 0.014],
+    [0.087, 0.044, 0.224],
+    [0.258, 0.039, 0.406],
+    [0.416, 0.090, 0.433],
+    [0.578, 0.148, 0.404],
+    [0.736, 0.216, 0.330],
+    [0.868, 0.317, 0.226],
+    [0.960, 0.490, 0.086],
+    [0.988, 0.998, 0.645],
+];
+
+impl Colormap {
+    pub fn viridis() -> Self {
+        Self { stops: VIRIDIS.to_vec() }
+    }
+
+    /// Built-in map by name or LUT file
+    pub fn from_str(text: &str) -> anyhow::Result<Self> {
+        match text.to_lowercase().as_str() {
+            "viridis" => Ok(Self::viridis()),
+            "inferno" => Ok(Self { stops: INFERNO.to_vec() }),
+            "grey"|"gray" => Ok(Self { stops: vec![[0.0; 3], [1.0; 3]] }),
+            _ if Path::new(text).is_file() => Self::load_lut_file(Path::new(text)),
+            _ => anyhow::bail!(
+                "Wrong color map {} (viridis, inferno, grey or LUT file are supported)", text
+            ),
+        }
+    }
+
+    pub fn load_lut_file(file_name: &Path) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(file_name)?;
+        let mut stops = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') { continue; }
+            let values: Vec<f32> = line
+                .split(|c: char| c == ',' || c == ';' || c.is_whitespace())
+                .filter(|v| !v.is_empty())
+                .map(|v| v.parse())
+                .collect::<Result<_, _>>()
+                .map_err(|_| anyhow::anyhow!("Wrong color in line {} of LUT file", index + 1))?;
+            let [r, g, b] = values[..] else {
+                anyhow::bail!("Line {} of LUT file must contain 3 values", index + 1);
+            };
+            stops.push([r, g, b]);
+        }
+        if stops.len() < 2 {
+            anyhow::bail!("LUT file must contain at least 2 colors");
+        }
+        // 0..255 range
+        if stops.iter().flatten().any(|v| *v > 1.0) {
+            stops.iter_mut().flatten().for_each(|v| *v /= 255.0);
+        }
+        Ok(Self { stops })
+    }
+
+    /// Color for value in range 0..1
+    pub fn color(&self, value: f32) -> [u8; 3] {
+        let value = if value.is_finite() { value.clamp(0.0, 1.0) } else { 0.0 };
+        let pos = value * (self.stops.len() - 1) as f32;
+        let index = (pos as usize).min(self.stops.len() - 2);
+        let k = pos - index as f32;
+        let (c1, c2) = (&self.stops[index], &self.stops[index + 1]);
+        let mix = |i: usize| (255.0 * (c1[i] + (c2[i] - c1[i]) * k) + 0.5).clamp(0.0, 255.0) as u8;
+        [mix(0), mix(1), mix(2)]
+    }
+}
@@ -1,5 +1,6 @@
 use std::{path::*, collections::HashMap};
 use serde::*;
+use crate::{fs_utils::*, image_io::*, report_fmt::*, power::*, gpu::*};
 
 #[derive(Serialize, Deserialize)]
 pub enum Theme { Dark, Light, Other(String) }
@@ -59,6 +60,14 @@ pub struct Config {
     pub prj_cols: HashMap<String, PrjTreeCol>,
     pub cpu_load: CpuLoad,
     pub last_path: PathBuf,
+    pub sync_written_files: bool,
+    pub fits_biassec: String, // [x1:x2,y1:y2], empty for BIASSEC from header
+    pub fits_trimsec: String, // [x1:x2,y1:y2], empty for TRIMSEC from header
+    pub fits_parallel_decoding: bool,
+    pub astrometry_solver: PathBuf, // solve-field of astrometry.net
+    pub report_format: ReportFormat,
+    pub power_profile: PowerProfile,
+    pub gpu_device: String, // "auto", index or part of name of adapter, empty for CPU only
 }
 
 impl Default for Config {
@@ -76,6 +85,14 @@ impl Default for Config {
             preview_gamma: 4.0,
             cpu_load: CpuLoad::HalfCPUs,
             last_path: PathBuf::new(),
+            sync_written_files: true,
+            fits_biassec: String::new(),
+            fits_trimsec: String::new(),
+            fits_parallel_decoding: true,
+            astrometry_solver: PathBuf::from("solve-field"),
+            report_format: ReportFormat::default(),
+            power_profile: PowerProfile::Performance,
+            gpu_device: String::new(),
         }
     }
 }
@@ -93,10 +110,36 @@ impl Config {
     pub fn save(&self) -> anyhow::Result<()> {
         let file_name = Self::get_file_name(true)?;
         let json_str = serde_json::to_string_pretty(self)?;
-        std::fs::write(&file_name, &json_str)?;
+        write_file_atomically(&file_name, |file_name| {
+            Ok(std::fs::write(file_name, &json_str)?)
+        })?;
         Ok(())
     }
 
+    /// Applies options which are global for all image loading and writing
+    pub fn apply_global_options(&self) {
+        set_sync_written_files(self.sync_written_files);
+        set_fits_parallel_decoding(self.fits_parallel_decoding);
+        let section = |text: &str| {
+            if text.trim().is_empty() { return None; }
+            FitsSection::from_str(text)
+                .map_err(|err| log::error!("{}", err))
+                .ok()
+        };
+        set_fits_overscan(FitsOverscan {
+            biassec: section(&self.fits_biassec),
+            trimsec: section(&self.fits_trimsec),
+        });
+        if let Err(err) = set_gpu_device(&self.gpu_device) {
+            log::warn!("{}. CPU is used", err);
+        }
+    }
+
+    /// CPU load limited by power profile
+    pub fn effective_cpu_load(&self) -> CpuLoad {
+        self.power_profile.limit_cpu_load(self.cpu_load)
+    }
+
     pub fn get_file_name(create_dir: bool) -> anyhow::Result<PathBuf> {
         let mut conf_dir = get_app_conf_dir(create_dir)?;
         conf_dir.push("config.json");
@@ -0,0 +1,183 @@
+use std::collections::BTreeMap;
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json::Value;
+use crate::{presets::Preset, project::ProjectConfig};
+
+/* Layered settings: defaults < config file < preset < project < command
+   line. Layer overrides only values which differ from values of lower
+   layers, so effective value of every parameter knows where it came from.
+   Values are compared as JSON: structures are compared by fields,
+   enums, lists and maps are compared as whole values */
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ConfigSource {
+    Default,
+    ConfigFile,
+    Preset,
+    Project,
+    CommandLine,
+}
+
+impl ConfigSource {
+    pub fn name(self) -> &'static str {
+        match self {
+            ConfigSource::Default     => "default",
+            ConfigSource::ConfigFile  => "config file",
+            ConfigSource::Preset      => "preset",
+            ConfigSource::Project     => "project",
+            ConfigSource::CommandLine => "command line",
+        }
+    }
+}
+
+pub struct ConfigEntry {
+    pub key:    String, // path of fields separated by dot
+    pub value:  String, // JSON
+    pub source: ConfigSource,
+}
+
+pub struct ConfigLayers {
+    default: Value,
+    merged:  Value,
+    sources: BTreeMap<String, ConfigSource>,
+}
+
+impl ConfigLayers {
+    pub fn new<T: Serialize>(default: &T) -> anyhow::Result<Self> {
+        let default = serde_json::to_value(default)?;
+        Ok(Self {
+            merged: default.clone(),
+            default,
+            sources: BTreeMap::new(),
+        })
+    }
+
+    /// Values of `config` which differ from defaults override lower layers
+    pub fn add_layer<T: Serialize>(&mut self, source: ConfigSource, config: &T) -> anyhow::Result<()> {
+        let layer = serde_json::to_value(config)?;
+        let default = self.default.clone();
+        merge_value("", &default, &mut self.merged, &layer, source, &mut self.sources);
+        Ok(())
+    }
+
+    /// Values which differ in `before` and `after` override lower layers.
+    /// Used for options which change effective settings
+    pub fn add_changes<T: Serialize>(
+        &mut self,
+        source: ConfigSource,
+        before: &T,
+        after:  &T,
+    ) -> anyhow::Result<()> {
+        let before = serde_json::to_value(before)?;
+        let after = serde_json::to_value(after)?;
+        merge_value("", &before, &mut self.merged, &after, source, &mut self.sources);
+        Ok(())
+    }
+
+    /// Effective settings
+    pub fn get<T: DeserializeOwned>(&self) -> anyhow::Result<T> {
+        Ok(serde_json::from_value(self.merged.clone())?)
+    }
+
+    /// Effective value and source of every parameter
+    pub fn entries(&self) -> Vec<ConfigEntry> {
+        let mut result = Vec::new();
+        self.collect_entries("", Some(&self.default), &self.merged, &mut result);
+        result
+    }
+
+    fn collect_entries(
+        &self,
+        path:    &str,
+        default: Option<&Value>,
+        value:   &Value,
+        result:  &mut Vec<ConfigEntry>,
+    ) {
+        if let (Some(Value::Object(default)), Value::Object(fields)) = (default, value) {
+            if same_keys(default, fields) {
+                for (key, field) in fields {
+                    self.collect_entries(&join_path(path, key), default.get(key), field, result);
+                }
+                return;
+            }
+        }
+        result.push(ConfigEntry {
+            key:    path.to_string(),
+            value:  value.to_string(),
+            source: self.source_of(path),
+        });
+    }
+
+    /// Source of value or of its nearest parent
+    pub fn source_of(&self, path: &str) -> ConfigSource {
+        let mut path = path;
+        loop {
+            if let Some(source) = self.sources.get(path) {
+                return *source;
+            }
+            match path.rsplit_once('.') {
+                Some((parent, _)) => path = parent,
+                None => return ConfigSource::Default,
+            }
+        }
+    }
+}
+
+/// Settings of project for current run: defaults < preset < project <
+/// options of command line (`cmd_line` changes effective settings).
+/// Values of project equal to defaults don't override preset
+pub fn project_config_layers(
+    preset:         Option<Preset>,
+    project_config: &ProjectConfig,
+    cmd_line:       impl FnOnce(&mut ProjectConfig),
+) -> anyhow::Result<ConfigLayers> {
+    let default = ProjectConfig::default();
+    let mut layers = ConfigLayers::new(&default)?;
+    if let Some(preset) = preset {
+        let mut preset_config = default.clone();
+        preset.apply_to_project_config(&mut preset_config);
+        layers.add_layer(ConfigSource::Preset, &preset_config)?;
+    }
+    layers.add_layer(ConfigSource::Project, project_config)?;
+    let before: ProjectConfig = layers.get()?;
+    let mut after = before.clone();
+    cmd_line(&mut after);
+    layers.add_changes(ConfigSource::CommandLine, &before, &after)?;
+    Ok(layers)
+}
+
+fn join_path(path: &str, key: &str) -> String {
+    if path.is_empty() { key.to_string() } else { format!("{}.{}", path, key) }
+}
+
+fn same_keys(obj1: &serde_json::Map<String, Value>, obj2: &serde_json::Map<String, Value>) -> bool {
+    obj1.len() == obj2.len() && obj1.keys().all(|k| obj2.contains_key(k))
+}
+
+fn merge_value(
+    path:    &str,
+    base:    &Value,
+    merged:  &mut Value,
+    layer:   &Value,
+    source:  ConfigSource,
+    sources: &mut BTreeMap<String, ConfigSource>,
+) {
+    if let (Value::Object(base), Value::Object(layer), Value::Object(merged)) = (base, layer, &mut *merged) {
+        if same_keys(base, layer) && same_keys(base, merged) {
+            for (key, layer_field) in layer {
+                let (Some(base_field), Some(merged_field)) = (base.get(key), merged.get_mut(key)) else {
+                    continue;
+                };
+                merge_value(&join_path(path, key), base_field, merged_field, layer_field, source, sources);
+            }
+            return;
+        }
+    }
+    if layer == base {
+        return;
+    }
+    *merged = layer.clone();
+    let prefix = format!("{}.", path);
+    sources.retain(|key, _| !key.starts_with(&prefix));
+    sources.insert(path.to_string(), source);
+}
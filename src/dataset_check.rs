@@ -0,0 +1,400 @@
+use std::{path::*, collections::BTreeMap};
+use serde::*;
+use crate::{project::*, image_io::*, image_raw::*, progress::*, fs_utils::*};
+
+/* Check of whole dataset of project before processing: all files are
+   readable, dimensions, CFA patterns and acquisition settings (gain,
+   offset, binning, sensor temperature) of light and calibration files
+   of group are the same, groups have calibration files, time of light
+   files is known and ordered and disks have free space for temporary
+   files. Errors stop processing, warnings are only reported */
+
+/// Samples of list of files in messages
+const MAX_FILES_IN_TEXT: usize = 3;
+
+/// Difference of sensor temperature of dark and light files (°C)
+const MAX_DARK_TEMP_DIFF: f32 = 2.0;
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+pub enum CheckLevel {
+    Warning,
+    Error,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CheckItem {
+    pub level: CheckLevel,
+    pub group: Option<String>,
+    pub text:  String,
+}
+
+#[derive(Serialize, Default, Debug)]
+pub struct CheckReport {
+    pub files_checked: usize,
+    pub items:         Vec<CheckItem>,
+}
+
+impl CheckReport {
+    pub fn passed(&self) -> bool {
+        !self.items.iter().any(|item| item.level == CheckLevel::Error)
+    }
+
+    pub fn count(&self, level: CheckLevel) -> usize {
+        self.items.iter().filter(|item| item.level == level).count()
+    }
+
+    fn add(&mut self, level: CheckLevel, group: Option<&str>, text: String) {
+        self.items.push(CheckItem { level, group: group.map(str::to_string), text });
+    }
+}
+
+fn file_type_name(file_type: ProjectFileType) -> &'static str {
+    match file_type {
+        ProjectFileType::Light => "light",
+        ProjectFileType::Dark  => "dark",
+        ProjectFileType::Flat  => "flat",
+        ProjectFileType::Bias  => "bias",
+    }
+}
+
+fn files_text(files: &[&Path]) -> String {
+    let mut text = files.iter()
+        .take(MAX_FILES_IN_TEXT)
+        .map(|f| extract_file_name(f))
+        .collect::<Vec<_>>()
+        .join(", ");
+    if files.len() > MAX_FILES_IN_TEXT {
+        text.push_str(&format!(" and {} more", files.len() - MAX_FILES_IN_TEXT));
+    }
+    text
+}
+
+type GroupInfos = Vec<(ProjectFileType, Vec<ImageInfo>)>;
+
+fn load_group_infos(
+    group:        &ProjectGroup,
+    hdu:          Option<&FitsHduSelector>,
+    group_name:   Option<&str>,
+    report:       &mut CheckReport,
+    progress:     &ProgressTs,
+    is_cancelled: &IsCancelledFun,
+) -> anyhow::Result<GroupInfos> {
+    let mut infos = Vec::new();
+    for file_type in [ProjectFileType::Light, ProjectFileType::Dark, ProjectFileType::Flat, ProjectFileType::Bias] {
+        let file_names: Vec<_> = group.get_file_list_by_type(file_type).list().iter()
+            .filter(|f| f.used())
+            .map(|f| f.file_name().clone())
+            .collect();
+        let results = try_load_src_file_info_for_files(&file_names, hdu, is_cancelled, progress)?;
+        report.files_checked += file_names.len();
+        let mut type_infos = Vec::new();
+        for (file_name, result) in file_names.iter().zip(results) {
+            match result {
+                Ok(info) => type_infos.push(info),
+                Err(err) => report.add(
+                    CheckLevel::Error, group_name,
+                    format!("Can't read {} file {}: {}", file_type_name(file_type), path_to_str(file_name), err)
+                ),
+            }
+        }
+        infos.push((file_type, type_infos));
+    }
+    Ok(infos)
+}
+
+fn cfa_text(cfa: Option<CfaType>) -> String {
+    cfa.map(|c| format!("{:?}", c)).unwrap_or_else(|| "none".to_string())
+}
+
+/// Upper bound of size of temporary file of calibrated light file
+fn temp_file_size(info: &ImageInfo) -> u64 {
+    let channels = if info.cfa_type.is_some() { 3 } else { 1 };
+    (info.width * info.height * channels * std::mem::size_of::<f32>()) as u64
+}
+
+/// Most common value of light files
+fn most_common<T: PartialEq + Copy>(values: impl Iterator<Item = T>) -> Option<T> {
+    let mut counts = Vec::<(T, usize)>::new();
+    for value in values {
+        match counts.iter_mut().find(|(v, _)| *v == value) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((value, 1)),
+        }
+    }
+    counts.iter().max_by_key(|(_, count)| *count).map(|(v, _)| *v)
+}
+
+/// Dimensions and CFA patterns: most common ones of light files are expected
+fn check_dimensions(infos: &GroupInfos, lights: &[ImageInfo], group_name: Option<&str>, report: &mut CheckReport) {
+    let Some((width, height, cfa)) = most_common(lights.iter().map(|i| (i.width, i.height, i.cfa_type))) else {
+        return;
+    };
+    for (file_type, type_infos) in infos {
+        let wrong_size: Vec<_> = type_infos.iter()
+            .filter(|i| (i.width, i.height) != (width, height))
+            .map(|i| i.file_name.as_path())
+            .collect();
+        if !wrong_size.is_empty() {
+            report.add(CheckLevel::Error, group_name, format!(
+                "{} {} file(s) have size different from {}x{} of light files: {}",
+                wrong_size.len(), file_type_name(*file_type), width, height, files_text(&wrong_size)
+            ));
+        }
+        let wrong_cfa: Vec<_> = type_infos.iter()
+            .filter(|i| (i.width, i.height) == (width, height) && i.cfa_type != cfa)
+            .map(|i| i.file_name.as_path())
+            .collect();
+        if !wrong_cfa.is_empty() {
+            report.add(CheckLevel::Error, group_name, format!(
+                "{} {} file(s) have CFA pattern different from {} of light files: {}",
+                wrong_cfa.len(), file_type_name(*file_type), cfa_text(cfa), files_text(&wrong_cfa)
+            ));
+        }
+    }
+}
+
+fn differs<T: PartialEq>(light: Option<T>, file: Option<T>) -> bool {
+    matches!((light, file), (Some(l), Some(f)) if l != f)
+}
+
+/// Gain, offset, binning and sensor temperature of calibration files
+/// (wrong dark library). Values unknown for some files are not compared
+fn check_acquisition(infos: &GroupInfos, lights: &[ImageInfo], group_name: Option<&str>, report: &mut CheckReport) {
+    let gain = most_common(lights.iter().filter_map(|i| i.iso));
+    let offset = most_common(lights.iter().filter_map(|i| i.offset));
+    let binning = most_common(lights.iter().filter_map(|i| i.binning));
+    let temps: Vec<f32> = lights.iter().filter_map(|i| i.temperature).collect();
+    let temperature = (!temps.is_empty()).then(|| temps.iter().sum::<f32>() / temps.len() as f32);
+    for (file_type, type_infos) in infos {
+        if *file_type == ProjectFileType::Light { continue; }
+        let mut add_check = |level, name: &str, light_value: String, wrong: Vec<&Path>| {
+            if wrong.is_empty() { return; }
+            report.add(level, group_name, format!(
+                "{} {} file(s) have {} different from {} of light files: {}",
+                wrong.len(), file_type_name(*file_type), name, light_value, files_text(&wrong)
+            ));
+        };
+        // flat files are often shot with other gain, it doesn't break calibration
+        let level = if *file_type == ProjectFileType::Flat { CheckLevel::Warning } else { CheckLevel::Error };
+        let wrong: Vec<_> = type_infos.iter()
+            .filter(|i| differs(gain, i.iso))
+            .map(|i| i.file_name.as_path())
+            .collect();
+        add_check(level, "gain", format!("{}", gain.unwrap_or(0)), wrong);
+        let wrong: Vec<_> = type_infos.iter()
+            .filter(|i| differs(offset, i.offset))
+            .map(|i| i.file_name.as_path())
+            .collect();
+        add_check(level, "offset", format!("{}", offset.unwrap_or(0)), wrong);
+        let wrong: Vec<_> = type_infos.iter()
+            .filter(|i| differs(binning, i.binning))
+            .map(|i| i.file_name.as_path())
+            .collect();
+        add_check(CheckLevel::Error, "binning", format!("{0}x{0}", binning.unwrap_or(1)), wrong);
+        if *file_type == ProjectFileType::Dark {
+            let wrong: Vec<_> = type_infos.iter()
+                .filter(|i| matches!(
+                    (temperature, i.temperature),
+                    (Some(t1), Some(t2)) if (t1 - t2).abs() > MAX_DARK_TEMP_DIFF
+                ))
+                .map(|i| i.file_name.as_path())
+                .collect();
+            add_check(CheckLevel::Warning, "sensor temperature", format!("{:.1}°C", temperature.unwrap_or(0.0)), wrong);
+        }
+    }
+}
+
+/// Only dimensions and acquisition settings of light and calibration
+/// files. Used before processing of project
+pub fn check_frames_compatibility(
+    project:      &Project,
+    progress:     &ProgressTs,
+    is_cancelled: &IsCancelledFun,
+) -> anyhow::Result<CheckReport> {
+    let mut report = CheckReport::default();
+    let hdu = project.config().raw_params.fits_hdu_selector();
+    for (group_index, group) in project.groups().iter().enumerate().filter(|(_, g)| g.used()) {
+        let group_name = group.name(group_index);
+        let group_name = Some(group_name.as_str());
+        progress.lock().unwrap().stage(&format!("Checking files of group {}...", group_name.unwrap_or("")));
+        let infos = load_group_infos(group, hdu.as_ref(), group_name, &mut report, progress, is_cancelled)?;
+        let lights = &infos.iter().find(|(t, _)| *t == ProjectFileType::Light).unwrap().1;
+        check_dimensions(&infos, lights, group_name, &mut report);
+        check_acquisition(&infos, lights, group_name, &mut report);
+    }
+    Ok(report)
+}
+
+/// Light files of channels merged into one image (LRGB). Channels are
+/// aligned without scaling so size and binning must be the same,
+/// different gain and offset are usual for filters and only reported
+pub fn check_channels_compatibility(channels: &[(&str, &[ImageInfo])]) -> CheckReport {
+    let mut report = CheckReport::default();
+    let Some((first_name, first_infos)) = channels.first() else { return report; };
+    let size = |infos: &[ImageInfo]| most_common(infos.iter().map(|i| (i.width, i.height)));
+    let binning = |infos: &[ImageInfo]| most_common(infos.iter().filter_map(|i| i.binning));
+    let gain = |infos: &[ImageInfo]| most_common(infos.iter().filter_map(|i| i.iso));
+    let offset = |infos: &[ImageInfo]| most_common(infos.iter().filter_map(|i| i.offset));
+    for (name, infos) in channels {
+        report.files_checked += infos.len();
+        if name == first_name { continue; }
+        let mut add = |level, text: String| report.add(
+            level, Some(*name), format!("{} of channel {}", text, first_name)
+        );
+        if let (Some((w1, h1)), Some((w2, h2))) = (size(first_infos), size(infos)) {
+            if (w1, h1) != (w2, h2) {
+                add(CheckLevel::Error, format!("Size {}x{} differs from {}x{}", w2, h2, w1, h1));
+            }
+        }
+        if let (Some(b1), Some(b2)) = (binning(first_infos), binning(infos)) {
+            if b1 != b2 {
+                add(CheckLevel::Error, format!("Binning {1}x{1} differs from {0}x{0}", b1, b2));
+            }
+        }
+        if let (Some(g1), Some(g2)) = (gain(first_infos), gain(infos)) {
+            if g1 != g2 {
+                add(CheckLevel::Warning, format!("Gain {} differs from {}", g2, g1));
+            }
+        }
+        if let (Some(o1), Some(o2)) = (offset(first_infos), offset(infos)) {
+            if o1 != o2 {
+                add(CheckLevel::Warning, format!("Offset {} differs from {}", o2, o1));
+            }
+        }
+    }
+    report
+}
+
+pub fn check_project(
+    project:      &Project,
+    progress:     &ProgressTs,
+    is_cancelled: &IsCancelledFun,
+) -> anyhow::Result<CheckReport> {
+    let mut report = CheckReport::default();
+    let hdu = project.config().raw_params.fits_hdu_selector();
+    let mut temp_sizes = BTreeMap::<PathBuf, u64>::new();
+    let used_groups: Vec<_> = project.groups().iter()
+        .enumerate()
+        .filter(|(_, group)| group.used())
+        .collect();
+    if used_groups.is_empty() {
+        report.add(CheckLevel::Error, None, "No used groups in project".to_string());
+    }
+
+    for (group_index, group) in used_groups {
+        let group_name = group.name(group_index);
+        let group_name = Some(group_name.as_str());
+        progress.lock().unwrap().stage(&format!("Checking group {}...", group_name.unwrap_or("")));
+        let infos = load_group_infos(group, hdu.as_ref(), group_name, &mut report, progress, is_cancelled)?;
+        let infos_of = |file_type| &infos.iter().find(|(t, _)| *t == file_type).unwrap().1;
+        let lights = infos_of(ProjectFileType::Light);
+        if lights.is_empty() {
+            report.add(CheckLevel::Error, group_name, "No readable light files".to_string());
+            continue;
+        }
+
+        check_dimensions(&infos, lights, group_name, &mut report);
+        check_acquisition(&infos, lights, group_name, &mut report);
+
+        // calibration
+
+        for file_type in [ProjectFileType::Dark, ProjectFileType::Flat, ProjectFileType::Bias] {
+            match group.master_file_name(file_type) {
+                Some(master) if infos_of(file_type).is_empty() && !master.is_file() =>
+                    report.add(CheckLevel::Error, group_name, format!(
+                        "Master {} file {} is not found", file_type_name(file_type), path_to_str(&master)
+                    )),
+                _ => {},
+            }
+        }
+        let has_master = |file_type| group.master_file_name(file_type).is_some();
+        if !has_master(ProjectFileType::Dark) && !has_master(ProjectFileType::Bias) {
+            report.add(CheckLevel::Warning, group_name,
+                "No dark or bias files: hot pixels and offset are not removed".to_string());
+        }
+        if !has_master(ProjectFileType::Flat) {
+            report.add(CheckLevel::Warning, group_name,
+                "No flat files: vignetting and dust are not corrected".to_string());
+        }
+        let light_exp = lights.iter().filter_map(|i| i.exp).next();
+        let wrong_exp: Vec<_> = infos_of(ProjectFileType::Dark).iter()
+            .filter(|i| matches!((i.exp, light_exp), (Some(e1), Some(e2)) if (e1 - e2).abs() > 0.01 * e2))
+            .map(|i| i.file_name.as_path())
+            .collect();
+        if !wrong_exp.is_empty() && !project.config().raw_params.optimize_dark {
+            report.add(CheckLevel::Warning, group_name, format!(
+                "{} dark file(s) have exposure different from {:.1}s of light files: {}",
+                wrong_exp.len(), light_exp.unwrap_or(0.0), files_text(&wrong_exp)
+            ));
+        }
+
+        // time of light files
+
+        let without_time: Vec<_> = lights.iter()
+            .filter(|i| i.file_time.is_none())
+            .map(|i| i.file_name.as_path())
+            .collect();
+        if !without_time.is_empty() {
+            report.add(CheckLevel::Warning, group_name, format!(
+                "Time is unknown for {} light file(s): {}", without_time.len(), files_text(&without_time)
+            ));
+        }
+        let mut by_name: Vec<_> = lights.iter()
+            .filter_map(|i| i.file_time.map(|t| (i.file_name.as_path(), t)))
+            .collect();
+        by_name.sort_by(|(f1, _), (f2, _)| f1.cmp(f2));
+        let same_time: Vec<_> = by_name.windows(2)
+            .filter(|w| w[0].1 == w[1].1)
+            .map(|w| w[1].0)
+            .collect();
+        if !same_time.is_empty() {
+            report.add(CheckLevel::Warning, group_name, format!(
+                "{} light file(s) have the same time as previous file (copies?): {}",
+                same_time.len(), files_text(&same_time)
+            ));
+        }
+        let out_of_order: Vec<_> = by_name.windows(2)
+            .filter(|w| w[1].1 < w[0].1)
+            .map(|w| w[1].0)
+            .collect();
+        if !out_of_order.is_empty() {
+            report.add(CheckLevel::Warning, group_name, format!(
+                "Time of {} light file(s) is earlier than time of previous file by name (wrong clock?): {}",
+                out_of_order.len(), files_text(&out_of_order)
+            ));
+        }
+
+        // temporary files are kept near light files
+
+        for info in lights {
+            let dir = info.file_name.parent().map(Path::to_path_buf).unwrap_or_default();
+            *temp_sizes.entry(dir).or_default() += temp_file_size(info);
+        }
+    }
+
+    for (dir, needed) in temp_sizes {
+        let available = match fs2::available_space(if dir.as_os_str().is_empty() { Path::new(".") } else { &dir }) {
+            Ok(available) => available,
+            Err(err) => {
+                report.add(CheckLevel::Warning, None, format!(
+                    "Can't get free space of {}: {}", path_to_str(&dir), err
+                ));
+                continue;
+            }
+        };
+        const GB: f64 = 1024.0 * 1024.0 * 1024.0;
+        let level = if available < needed {
+            CheckLevel::Error
+        } else if available < 2 * needed {
+            CheckLevel::Warning
+        } else {
+            continue;
+        };
+        report.add(level, None, format!(
+            "{:.1} GB free space in {}, temporary files need up to {:.1} GB",
+            available as f64 / GB, path_to_str(&dir), needed as f64 / GB
+        ));
+    }
+
+    Ok(report)
+}
@@ -0,0 +1,375 @@
+use std::path::*;
+use serde::*;
+use rayon::prelude::*;
+use crate::{image::*, stars::*, light_file::*, progress::*, fs_utils::*, star_mask::*};
+
+/* Regularized Richardson-Lucy deconvolution. PSF is elliptical gaussian
+   (synthesized from FWHM and eccentricity measured by stars or defined by
+   user) or is extracted from stars of image. Long runs are checkpointed:
+   current estimate and number of done iterations are saved together with
+   preview snapshots, so the run can be stopped and resumed and the best
+   iteration count can be picked visually */
+
+const EPSILON: f32 = 1e-7;
+const STAR_IMAGE_MAG: Crd = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PsfSource {
+    Gaussian, // synthesized by FWHM and eccentricity
+    Stars,    // extracted from stars of image
+}
+
+impl PsfSource {
+    pub fn from_str(text: &str) -> anyhow::Result<Self> {
+        match text.to_lowercase().as_str() {
+            "gaussian" => Ok(PsfSource::Gaussian),
+            "stars"    => Ok(PsfSource::Stars),
+            _ => anyhow::bail!("Wrong PSF {} (gaussian or stars)", text),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DeconvOpts {
+    pub iterations:     usize,
+    pub psf:            PsfSource,
+    pub psf_fwhm:       Option<f32>, // pixels, measured by stars if not defined
+    pub eccentricity:   Option<f32>, // measured by stars if not defined
+    pub psf_angle:      Option<f32>, // degrees, direction of major axis
+    pub damping:        f32, // in noise units, differences below it are not deconvolved
+    pub regularization: f32, // weight of total variation regularization
+    pub protect_stars:  Option<f32>, // stars with peak above it are not deconvolved
+    pub snapshot_every: usize, // 0 - no snapshots
+    pub resume:         bool,
+}
+
+impl Default for DeconvOpts {
+    fn default() -> Self {
+        Self {
+            iterations: 30,
+            psf: PsfSource::Gaussian,
+            psf_fwhm: None,
+            eccentricity: None,
+            psf_angle: None,
+            damping: 0.0,
+            regularization: 0.0,
+            protect_stars: None,
+            snapshot_every: 5,
+            resume: false,
+        }
+    }
+}
+
+/// State of interrupted deconvolution. Estimate itself
+/// is stored in FITS file near checkpoint file
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DeconvCheckpoint {
+    pub source_state: String, // size and time of source file
+    pub psf:          String, // description of PSF
+    pub iteration:    usize,
+}
+
+impl DeconvCheckpoint {
+    pub fn load(file_name: &Path) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(file_name)?;
+        Ok(serde_json::from_str(&text)?)
+    }
+
+    pub fn save(&self, file_name: &Path) -> anyhow::Result<()> {
+        let text = serde_json::to_string_pretty(self)?;
+        write_file_atomically(file_name, |tmp_file_name| {
+            Ok(std::fs::write(tmp_file_name, &text)?)
+        })
+    }
+}
+
+/// Shape of stars measured by common star image
+pub struct MeasuredPsf {
+    pub fwhm:         f32, // pixels
+    pub eccentricity: f32,
+    pub angle:        f32, // degrees
+    star_image:       ImageLayerF32,
+}
+
+pub fn measure_psf(image: &Image) -> anyhow::Result<MeasuredPsf> {
+    let layer = image.create_greyscale_layer();
+    let noise = calc_noise(&layer) as f32;
+    let stars = find_stars_on_image(&layer, Some(noise), false, &StarsFindOpts::default())?;
+    let star_image = create_common_star_image(&stars, &layer, STAR_IMAGE_MAG, true)?;
+
+    // area of star above half of maximum -> diameter
+    let area = star_image.iter().filter(|v| **v > 0.5).count() as f32
+        / (STAR_IMAGE_MAG * STAR_IMAGE_MAG) as f32;
+    let fwhm = 2.0 * (area / std::f32::consts::PI).sqrt();
+
+    // second moments for eccentricity and angle
+    let (cx, cy) = ((star_image.width() / 2) as f64, (star_image.height() / 2) as f64);
+    let (mut mxx, mut myy, mut mxy, mut sum) = (0_f64, 0_f64, 0_f64, 0_f64);
+    for (x, y, v) in star_image.iter_crd() {
+        if v < 0.05 { continue; }
+        let (dx, dy) = (x as f64 - cx, y as f64 - cy);
+        mxx += v as f64 * dx * dx;
+        myy += v as f64 * dy * dy;
+        mxy += v as f64 * dx * dy;
+        sum += v as f64;
+    }
+    if sum == 0.0 {
+        anyhow::bail!("Can't measure shape of stars");
+    }
+    let (mxx, myy, mxy) = (mxx / sum, myy / sum, mxy / sum);
+    let d = ((mxx - myy).powi(2) + 4.0 * mxy * mxy).sqrt();
+    let (l1, l2) = (0.5 * (mxx + myy + d), 0.5 * (mxx + myy - d));
+    let eccentricity = if l1 > 0.0 { (1.0 - (l2 / l1).max(0.0)).sqrt() } else { 0.0 };
+    let angle = 0.5 * (2.0 * mxy).atan2(mxx - myy);
+
+    Ok(MeasuredPsf {
+        fwhm,
+        eccentricity: eccentricity as f32,
+        angle: angle.to_degrees() as f32,
+        star_image,
+    })
+}
+
+/// 2d kernel of PSF with odd size, sum of values is 1
+pub struct Psf {
+    radius: isize,
+    data:   Vec<f32>,
+    descr:  String,
+}
+
+impl Psf {
+    pub fn gaussian(fwhm: f32, eccentricity: f32, angle: f32) -> anyhow::Result<Self> {
+        if fwhm <= 0.0 {
+            anyhow::bail!("FWHM of PSF must be positive");
+        }
+        if !(0.0..1.0).contains(&eccentricity) {
+            anyhow::bail!("Eccentricity must be in 0..1 range");
+        }
+        // FWHM is geometric mean of axes
+        let sigma = fwhm / 2.3548;
+        let q = (1.0 - eccentricity * eccentricity).sqrt();
+        let (sigma_major, sigma_minor) = (sigma / q.sqrt(), sigma * q.sqrt());
+        let radius = (3.0 * sigma_major).ceil().max(1.0) as isize;
+        let (sin, cos) = angle.to_radians().sin_cos();
+        let data = Self::grid(radius).map(|(x, y)| {
+            let u = x * cos + y * sin;
+            let v = -x * sin + y * cos;
+            (-0.5 * ((u / sigma_major).powi(2) + (v / sigma_minor).powi(2))).exp()
+        }).collect();
+        let descr = format!("gaussian fwhm={:.3} e={:.3} angle={:.1}", fwhm, eccentricity, angle);
+        Ok(Self::normalized(radius, data, descr))
+    }
+
+    /// PSF from common star image (star image is oversampled by STAR_IMAGE_MAG)
+    pub fn from_stars(measured: &MeasuredPsf) -> anyhow::Result<Self> {
+        let star_image = &measured.star_image;
+        let (cx, cy) = (star_image.width() / 2, star_image.height() / 2);
+        let radius = (cx.min(cy) / STAR_IMAGE_MAG) as isize;
+        if radius < 1 {
+            anyhow::bail!("Stars are too small for PSF extraction");
+        }
+        let data = Self::grid(radius).map(|(x, y)| {
+            star_image
+                .get(cx + x as Crd * STAR_IMAGE_MAG, cy + y as Crd * STAR_IMAGE_MAG)
+                .unwrap_or(0.0)
+                .max(0.0)
+        }).collect();
+        Ok(Self::normalized(radius, data, "stars".to_string()))
+    }
+
+    fn grid(radius: isize) -> impl Iterator<Item = (f32, f32)> {
+        (-radius..=radius).flat_map(move |y| (-radius..=radius).map(move |x| (x as f32, y as f32)))
+    }
+
+    fn normalized(radius: isize, mut data: Vec<f32>, descr: String) -> Self {
+        let sum: f32 = data.iter().sum();
+        if sum > 0.0 {
+            data.iter_mut().for_each(|v| *v /= sum);
+        }
+        Self { radius, data, descr }
+    }
+
+    pub fn descr(&self) -> &str {
+        &self.descr
+    }
+
+    // kernel for correlation (PSF rotated by 180°)
+    fn flipped(&self) -> Self {
+        Self {
+            radius: self.radius,
+            data: self.data.iter().rev().copied().collect(),
+            descr: self.descr.clone(),
+        }
+    }
+}
+
+// Convolution with mirrored borders
+fn convolve(data: &[f32], width: usize, height: usize, psf: &Psf) -> Vec<f32> {
+    let mirror = |pos: isize, size: usize| -> usize {
+        let size = size as isize;
+        let pos = if pos < 0 { -pos } else if pos >= size { 2 * size - 2 - pos } else { pos };
+        pos.clamp(0, size - 1) as usize
+    };
+    let r = psf.radius;
+    let side = (2 * r + 1) as usize;
+    let mut result = vec![0_f32; data.len()];
+    result.par_chunks_mut(width).enumerate().for_each(|(y, row)| {
+        for (x, v) in row.iter_mut().enumerate() {
+            let mut sum = 0_f32;
+            for ky in -r..=r {
+                let sy = mirror(y as isize + ky, height);
+                let src_row = &data[sy * width..(sy + 1) * width];
+                let k_row = &psf.data[(ky + r) as usize * side..];
+                for kx in -r..=r {
+                    let k = k_row[(kx + r) as usize];
+                    if k == 0.0 { continue; }
+                    sum += k * src_row[mirror(x as isize + kx, width)];
+                }
+            }
+            *v = sum;
+        }
+    });
+    result
+}
+
+// Divergence of normalized gradient for total variation regularization
+fn tv_divergence(data: &[f32], width: usize, height: usize) -> Vec<f32> {
+    let grad = |x: usize, y: usize| -> (f32, f32) {
+        let v = data[y * width + x];
+        let gx = if x + 1 < width { data[y * width + x + 1] - v } else { 0.0 };
+        let gy = if y + 1 < height { data[(y + 1) * width + x] - v } else { 0.0 };
+        let len = (gx * gx + gy * gy).sqrt().max(EPSILON);
+        (gx / len, gy / len)
+    };
+    let mut result = vec![0_f32; data.len()];
+    result.par_chunks_mut(width).enumerate().for_each(|(y, row)| {
+        for (x, v) in row.iter_mut().enumerate() {
+            let (gx, gy) = grad(x, y);
+            let gx_left = if x > 0 { grad(x - 1, y).0 } else { 0.0 };
+            let gy_top = if y > 0 { grad(x, y - 1).1 } else { 0.0 };
+            *v = (gx - gx_left) + (gy - gy_top);
+        }
+    });
+    result
+}
+
+struct LayerParams<'a> {
+    psf:            &'a Psf,
+    psf_flipped:    &'a Psf,
+    damping_level:  f32, // absolute value
+    regularization: f32,
+}
+
+fn rl_iteration(observed: &[f32], estimate: &mut [f32], width: usize, height: usize, params: &LayerParams) {
+    let blurred = convolve(estimate, width, height, params.psf);
+    let damping_level = params.damping_level;
+    let ratio: Vec<f32> = observed.par_iter().zip(blurred.par_iter())
+        .map(|(o, b)| {
+            if !o.is_finite() { return 1.0; }
+            // differences comparable with noise are not amplified (damped RL)
+            if damping_level > 0.0 && (o - b).abs() < damping_level { return 1.0; }
+            o / b.max(EPSILON)
+        })
+        .collect();
+    let correction = convolve(&ratio, width, height, params.psf_flipped);
+    let divergence = if params.regularization > 0.0 {
+        Some(tv_divergence(estimate, width, height))
+    } else {
+        None
+    };
+    let regularization = params.regularization;
+    estimate.par_iter_mut().zip(correction.par_iter()).enumerate()
+        .for_each(|(i, (e, c))| {
+            let mut factor = *c;
+            if let Some(divergence) = &divergence {
+                factor /= (1.0 - regularization * divergence[i]).max(0.1);
+            }
+            *e = (*e * factor).max(0.0);
+        });
+}
+
+fn image_layers(image: &Image) -> Vec<&ImageLayerF32> {
+    if image.is_rgb() {
+        vec![&image.r, &image.g, &image.b]
+    } else {
+        vec![&image.l]
+    }
+}
+
+fn image_layers_mut(image: &mut Image) -> Vec<&mut ImageLayerF32> {
+    if image.is_rgb() {
+        vec![&mut image.r, &mut image.g, &mut image.b]
+    } else {
+        vec![&mut image.l]
+    }
+}
+
+/// Mask of bright stars (1 - star, 0 - background) with soft edges
+pub fn create_stars_protection_mask(image: &Image, min_peak: f32, fwhm: f32) -> anyhow::Result<ImageLayerF32> {
+    let layer = image.create_greyscale_layer();
+    let noise = calc_noise(&layer) as f32;
+    let stars = find_stars_on_image(&layer, Some(noise), false, &StarsFindOpts::default())?;
+    Ok(render_soft_stars_mask(
+        layer.width(),
+        layer.height(),
+        stars.iter().filter(|s| s.overexposured || s.max_value >= min_peak),
+        fwhm,
+        2.0 * fwhm
+    ))
+}
+
+/// Restores original values of protected stars
+pub fn apply_stars_protection(observed: &Image, estimate: &mut Image, mask: &ImageLayerF32) {
+    for (obs, est) in image_layers(observed).into_iter().zip(image_layers_mut(estimate)) {
+        est.as_slice_mut().par_iter_mut()
+            .zip(obs.as_slice().par_iter())
+            .zip(mask.as_slice().par_iter())
+            .for_each(|((e, o), m)| if *m > 0.0 && o.is_finite() { *e += m * (o - *e) });
+    }
+}
+
+/// Runs iterations of deconvolution from `first_iteration` to `opts.iterations`.
+/// `estimate` is initial estimate (observed image or estimate of checkpoint).
+/// `snapshot` is called for each `opts.snapshot_every` iteration, for the last
+/// one and when process is cancelled. Returns number of done iterations
+pub fn richardson_lucy(
+    observed:        &Image,
+    estimate:        &mut Image,
+    psf:             &Psf,
+    first_iteration: usize,
+    opts:            &DeconvOpts,
+    progress:        &ProgressTs,
+    cancel_flag:     &IsCancelledFun,
+    snapshot:        &mut dyn FnMut(usize, &Image) -> anyhow::Result<()>,
+) -> anyhow::Result<usize> {
+    let (width, height) = (observed.width() as usize, observed.height() as usize);
+    let psf_flipped = psf.flipped();
+    let layers_params: Vec<_> = image_layers(observed).into_iter()
+        .map(|layer| LayerParams {
+            psf,
+            psf_flipped: &psf_flipped,
+            damping_level: opts.damping * calc_noise(layer) as f32,
+            regularization: opts.regularization,
+        })
+        .collect();
+    progress.lock().unwrap().set_total(opts.iterations.saturating_sub(first_iteration));
+    let mut iteration = first_iteration;
+    while iteration < opts.iterations {
+        if cancel_flag() {
+            snapshot(iteration, estimate)?;
+            return Ok(iteration);
+        }
+        for ((obs, est), params) in image_layers(observed).into_iter()
+            .zip(image_layers_mut(estimate))
+            .zip(&layers_params) {
+            rl_iteration(obs.as_slice(), est.as_slice_mut(), width, height, params);
+        }
+        iteration += 1;
+        progress.lock().unwrap().progress(true, &format!("iteration {}", iteration));
+        let is_last = iteration == opts.iterations;
+        if is_last || (opts.snapshot_every != 0 && iteration % opts.snapshot_every == 0) {
+            snapshot(iteration, estimate)?;
+        }
+    }
+    Ok(iteration)
+}
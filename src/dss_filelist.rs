@@ -0,0 +1,131 @@
+use std::{path::*, collections::BTreeMap};
+use crate::{project::*, image_io::*, progress::*, fs_utils::*};
+
+/* Import of DeepSkyStacker file lists (.dssfilelist). Group 0 of DSS is
+   main group which calibration files are used for all groups. So they are
+   copied into every group which has no own calibration files */
+
+#[derive(Default)]
+struct DssGroup {
+    name:  Option<String>,
+    files: Vec<(ProjectFileType, PathBuf, bool)>, // type, file, checked
+}
+
+pub struct DssImportResult {
+    pub project:       Project,
+    pub missing_files: Vec<PathBuf>,
+}
+
+fn parse_file_type(text: &str) -> Option<ProjectFileType> {
+    match text.trim().to_lowercase().as_str() {
+        "light"|"reflight" => Some(ProjectFileType::Light),
+        "dark"             => Some(ProjectFileType::Dark),
+        "flat"             => Some(ProjectFileType::Flat),
+        "offset"|"bias"    => Some(ProjectFileType::Bias),
+        _                  => None, // dark flats are not supported
+    }
+}
+
+fn parse_file_list(text: &str, base_dir: &Path) -> anyhow::Result<(BTreeMap<u32, DssGroup>, Option<PathBuf>)> {
+    let mut lines = text.lines();
+    if !lines.next().unwrap_or("").trim().eq_ignore_ascii_case("DSS file list") {
+        anyhow::bail!("File is not DeepSkyStacker file list");
+    }
+    let mut groups = BTreeMap::<u32, DssGroup>::new();
+    let mut cur_group = 0;
+    let mut ref_image = None;
+    for line in lines {
+        let line = line.trim_end_matches('\r');
+        let items: Vec<&str> = line.split('\t').collect();
+        if let Some(tag) = items[0].strip_prefix('#') {
+            match tag {
+                "GROUPID" => cur_group = items.get(1).and_then(|v| v.trim().parse().ok()).unwrap_or(0),
+                "GROUPNAME" => groups.entry(cur_group).or_default().name =
+                    items.get(1).map(|v| v.trim().to_string()).filter(|v| !v.is_empty()),
+                _ => {}, // settings of DSS (#WS#...)
+            }
+            continue;
+        }
+        if items.len() < 3 || items[0] == "CHECKED" { continue; }
+        let Some(file_type) = parse_file_type(items[1]) else {
+            log::info!("DSS file of type {} is skipped: {}", items[1], items[2]);
+            continue;
+        };
+        // DSS writes windows paths
+        let file_name = base_dir.join(items[2].trim().replace('\\', "/"));
+        if items[1].trim().eq_ignore_ascii_case("reflight") {
+            ref_image = Some(file_name.clone());
+        }
+        let checked = items[0].trim() != "0";
+        groups.entry(cur_group).or_default().files.push((file_type, file_name, checked));
+    }
+    Ok((groups, ref_image))
+}
+
+pub fn import_dss_file_list(
+    file_name:    &Path,
+    progress:     &ProgressTs,
+    is_cancelled: &IsCancelledFun,
+) -> anyhow::Result<DssImportResult> {
+    let text = std::fs::read_to_string(file_name)?;
+    let base_dir = file_name.parent().unwrap_or(Path::new("."));
+    let (mut groups, ref_image) = parse_file_list(&text, base_dir)?;
+
+    // calibration files of main group are used by all groups
+    if groups.len() > 1 {
+        if let Some(main_group) = groups.remove(&0) {
+            let main_cal_files: Vec<_> = main_group.files.iter()
+                .filter(|(t, _, _)| *t != ProjectFileType::Light)
+                .cloned()
+                .collect();
+            let main_lights: Vec<_> = main_group.files.into_iter()
+                .filter(|(t, _, _)| *t == ProjectFileType::Light)
+                .collect();
+            for group in groups.values_mut() {
+                for (file_type, file, checked) in &main_cal_files {
+                    if !group.files.iter().any(|(t, _, _)| t == file_type) {
+                        group.files.push((*file_type, file.clone(), *checked));
+                    }
+                }
+            }
+            if !main_lights.is_empty() {
+                let mut files = main_lights;
+                files.extend(main_cal_files);
+                groups.insert(0, DssGroup { name: main_group.name, files });
+            }
+        }
+    }
+
+    let mut project = Project::default();
+    let mut missing_files = Vec::new();
+    for group in groups.into_values() {
+        project.add_new_group(GroupOptions { name: group.name.clone() });
+        let group_index = project.groups().len() - 1;
+        for file_type in [ProjectFileType::Light, ProjectFileType::Dark, ProjectFileType::Flat, ProjectFileType::Bias] {
+            let mut files = Vec::new();
+            let mut unchecked = Vec::new();
+            for (t, file, checked) in &group.files {
+                if *t != file_type { continue; }
+                if !file.is_file() {
+                    missing_files.push(file.clone());
+                    continue;
+                }
+                if !checked { unchecked.push(files.len()); }
+                files.push(file.clone());
+            }
+            if files.is_empty() { continue; }
+            let infos = load_src_file_info_for_files(&files, None, is_cancelled, progress)?;
+            let list = project.group_by_index_mut(group_index).file_list_by_type_mut(file_type);
+            list.add_files_from_src_file_info(infos);
+            list.check_by_indices(&unchecked, false);
+        }
+    }
+    project.add_default_group_if_empty();
+    if let Some(ref_image) = ref_image.filter(|f| f.is_file()) {
+        project.set_ref_image(ref_image);
+    }
+    for file in &missing_files {
+        log::error!("File from DSS list is not found: {}", path_to_str(file));
+    }
+    Ok(DssImportResult { project, missing_files })
+}
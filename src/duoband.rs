@@ -0,0 +1,30 @@
+use crate::image::*;
+
+/* Extraction of narrowband channels from OSC image captured
+   with dual-band (Ha + OIII) filter */
+
+pub struct DuoBandChannels {
+    pub ha:   Image,
+    pub oiii: Image,
+}
+
+/// Ha is taken from red channel and OIII is mix of green and blue.
+/// `green_weight` = 1 gives OIII from green only, 0 - from blue only
+pub fn extract_duoband(image: &Image, green_weight: f32) -> anyhow::Result<DuoBandChannels> {
+    if !image.is_rgb() {
+        anyhow::bail!("Dual-band extraction needs RGB image");
+    }
+    if !(0.0..=1.0).contains(&green_weight) {
+        anyhow::bail!("Weight of green must be in 0..1 range");
+    }
+    let mut oiii = Image::new_grey(image.width(), image.height());
+    for (o, g, b) in itertools::izip!(oiii.l.iter_mut(), image.g.iter(), image.b.iter()) {
+        *o = if *g == NO_VALUE_F32 || *b == NO_VALUE_F32 {
+            NO_VALUE_F32
+        } else {
+            green_weight * g + (1.0 - green_weight) * b
+        };
+    }
+    let ha = Image { l: image.r.clone(), ..Image::new() };
+    Ok(DuoBandChannels { ha, oiii })
+}
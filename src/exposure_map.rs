@@ -0,0 +1,108 @@
+use std::path::*;
+use rayon::prelude::*;
+use crate::{image::*, mosaic::*, progress::*, colormap::*};
+
+/* Integration time map of plate solved panels or dithered frames. Map is
+   rendered with color map and contour lines of integration time to see
+   thin areas of mosaic */
+
+#[derive(Clone, Debug)]
+pub struct ExposureMapOpts {
+    pub contours:  usize,
+    pub max_width: usize, // map is calculated with lower resolution for big mosaics
+}
+
+impl Default for ExposureMapOpts {
+    fn default() -> Self {
+        Self {
+            contours: 5,
+            max_width: 2000,
+        }
+    }
+}
+
+pub struct ExposureMap {
+    pub times:    ImageLayerF32, // seconds (frames count if EXPTIME is not defined)
+    pub max_time: f32,
+}
+
+impl ExposureMap {
+    /// Evenly distributed levels between zero and maximum time
+    pub fn contour_levels(&self, count: usize) -> Vec<f32> {
+        (1..=count)
+            .map(|i| self.max_time * i as f32 / (count + 1) as f32)
+            .collect()
+    }
+
+    /// Part of covered area with integration time not less than `time`
+    pub fn covered_part(&self, time: f32) -> f64 {
+        let covered = self.times.iter().filter(|t| **t > 0.0).count();
+        if covered == 0 { return 0.0; }
+        self.times.iter().filter(|t| **t >= time).count() as f64 / covered as f64
+    }
+
+    /// RGB bytes of map. Not covered area is black, contours are white
+    pub fn render(&self, contours: usize) -> Vec<u8> {
+        let colormap = Colormap::viridis();
+        let levels = self.contour_levels(contours);
+        let level_index = |t: f32| levels.iter().filter(|l| t >= **l).count();
+        let mut result = Vec::with_capacity(self.times.as_slice().len() * 3);
+        for (x, y, t) in self.times.iter_crd() {
+            if t <= 0.0 {
+                result.extend([0, 0, 0]);
+                continue;
+            }
+            let index = level_index(t);
+            let is_contour = [(x + 1, y), (x, y + 1)].iter().any(|&(nx, ny)| {
+                matches!(self.times.get(nx, ny), Some(nt) if nt > 0.0 && level_index(nt) != index)
+            });
+            if is_contour {
+                result.extend([255, 255, 255]);
+            } else {
+                let color = colormap.color(if self.max_time > 0.0 { t / self.max_time } else { 0.0 });
+                result.extend(color);
+            }
+        }
+        result
+    }
+}
+
+pub fn create_exposure_map(
+    files:    &[PathBuf],
+    opts:     &ExposureMapOpts,
+    progress: &ProgressTs
+) -> anyhow::Result<ExposureMap> {
+    if files.is_empty() {
+        anyhow::bail!("No files for exposure map");
+    }
+    let panels = load_panels(files, progress)?;
+    for panel in panels.iter().filter(|p| p.exposure.is_none()) {
+        log::warn!(
+            "EXPTIME is not defined for {}. Panel is counted as 1 second",
+            panel.file_name.to_str().unwrap_or("")
+        );
+    }
+    let (wcs, width, height) = calc_common_wcs(&panels)?;
+    let step = (width as usize).div_ceil(opts.max_width.max(1)).max(1);
+    let map_width = (width as usize).div_ceil(step);
+    let map_height = (height as usize).div_ceil(step);
+
+    progress.lock().unwrap().stage("Calculating exposure map...");
+    let mut times = ImageLayerF32::new(map_width as Crd, map_height as Crd);
+    times.as_slice_mut()
+        .par_chunks_mut(map_width)
+        .enumerate()
+        .for_each(|(y, row)| {
+            for (x, time) in row.iter_mut().enumerate() {
+                let (ra, dec) = wcs.pixel_to_world((x * step) as f64, (y * step) as f64);
+                for panel in &panels {
+                    let Some((px, py)) = panel.wcs.world_to_pixel(ra, dec) else { continue; };
+                    if panel.contains(px, py) {
+                        *time += panel.exposure.unwrap_or(1.0) as f32;
+                    }
+                }
+            }
+        });
+    let max_time = times.iter().copied().fold(0.0, f32::max);
+    Ok(ExposureMap { times, max_time })
+}
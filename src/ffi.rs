@@ -0,0 +1,281 @@
+//! C interface of stacking pipeline for capture applications and INDI
+//! drivers. Header is `include/electra_stacking.h`.
+//!
+//! All objects are opaque pointers created by `es_*_new` or returned by
+//! functions and must be released by corresponding `es_*_free`. Functions
+//! taking object by value (`es_register`, `es_integrator_add`) release it
+//! even if error occurs. On error NULL or -1 is returned and message is
+//! available by `es_last_error` until next call in same thread.
+//! Strings are UTF-8 file names terminated by zero.
+//!
+//! Panic inside of function doesn't cross FFI boundary: it is caught and
+//! returned as error. This works only if library is built with unwinding
+//! (`--profile release-lib`), profiles `dev` and `release` of binary abort
+//! process on panic
+
+#![allow(clippy::missing_safety_doc)]
+
+use std::{cell::RefCell, ffi::{CStr, CString, c_char, c_int}, panic::*, path::PathBuf, ptr};
+use crate::{calc::*, image::*, image_io::*, light_file::*, pipeline::*, fs_utils::write_file_atomically};
+
+pub struct EsCalibrator(Calibrator);
+pub struct EsRegistrar(Registrar);
+pub struct EsIntegrator(Integrator);
+pub struct EsFrame(LightFile);
+pub struct EsAlignedFrame(AlignedFrame);
+pub struct EsImage(Image);
+
+pub const ES_MODE_KAPPA_SIGMA: c_int = 0;
+pub const ES_MODE_MEDIAN: c_int = 1;
+pub const ES_MODE_MEAN: c_int = 2;
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+fn set_last_error(err: &anyhow::Error) {
+    log::error!("{}", err);
+    let text = CString::new(err.to_string().replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|e| *e.borrow_mut() = text);
+}
+
+fn catch_panic<T>(fun: impl FnOnce() -> anyhow::Result<T>) -> anyhow::Result<T> {
+    catch_unwind(AssertUnwindSafe(fun)).unwrap_or_else(|payload| {
+        let text = payload.downcast_ref::<&str>().map(|s| s.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        Err(anyhow::anyhow!("Panic: {}", text))
+    })
+}
+
+fn result_to_value<T>(on_error: T, fun: impl FnOnce() -> anyhow::Result<T>) -> T {
+    match catch_panic(fun) {
+        Ok(value) => value,
+        Err(err) => {
+            set_last_error(&err);
+            on_error
+        }
+    }
+}
+
+fn result_to_ptr<T>(fun: impl FnOnce() -> anyhow::Result<T>) -> *mut T {
+    result_to_value(ptr::null_mut(), || Ok(Box::into_raw(Box::new(fun()?))))
+}
+
+fn result_to_int(fun: impl FnOnce() -> anyhow::Result<()>) -> c_int {
+    result_to_value(-1, || { fun()?; Ok(0) })
+}
+
+unsafe fn path_from_c(text: *const c_char) -> anyhow::Result<Option<PathBuf>> {
+    if text.is_null() { return Ok(None); }
+    let text = CStr::from_ptr(text).to_str()?;
+    Ok(Some(PathBuf::from(text)))
+}
+
+unsafe fn required_path_from_c(text: *const c_char) -> anyhow::Result<PathBuf> {
+    path_from_c(text)?.ok_or_else(|| anyhow::anyhow!("File name is not defined"))
+}
+
+unsafe fn obj_ref<'a, T>(obj: *const T) -> anyhow::Result<&'a T> {
+    obj.as_ref().ok_or_else(|| anyhow::anyhow!("NULL object pointer"))
+}
+
+unsafe fn free_obj<T>(obj: *mut T) {
+    if !obj.is_null() {
+        result_to_value((), || { drop(Box::from_raw(obj)); Ok(()) });
+    }
+}
+
+/// Message of last error of current thread (empty string if there was no error).
+/// Panic of library is error with "Panic:" prefix if library is built with
+/// unwinding (`cargo build --profile release-lib --lib`), otherwise it aborts process
+#[no_mangle]
+pub extern "C" fn es_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ptr())
+}
+
+/// Master files can be NULL
+#[no_mangle]
+pub unsafe extern "C" fn es_calibrator_new(
+    master_flat: *const c_char,
+    master_dark: *const c_char,
+    master_bias: *const c_char,
+) -> *mut EsCalibrator {
+    result_to_ptr(|| {
+        let flat = path_from_c(master_flat)?;
+        let dark = path_from_c(master_dark)?;
+        let bias = path_from_c(master_bias)?;
+        Ok(EsCalibrator(Calibrator::new(flat.as_deref(), dark.as_deref(), bias.as_deref())?))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn es_calibrator_free(calibrator: *mut EsCalibrator) {
+    free_obj(calibrator);
+}
+
+/// Loads, calibrates and demosaics light file
+#[no_mangle]
+pub unsafe extern "C" fn es_frame_load(
+    calibrator: *const EsCalibrator,
+    file_name:  *const c_char,
+) -> *mut EsFrame {
+    result_to_ptr(|| {
+        let calibrator = obj_ref(calibrator)?;
+        let file_name = required_path_from_c(file_name)?;
+        Ok(EsFrame(calibrator.0.calibrate(&file_name)?))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn es_frame_free(frame: *mut EsFrame) {
+    free_obj(frame);
+}
+
+/// Number of stars found on light frame
+#[no_mangle]
+pub unsafe extern "C" fn es_frame_stars_count(frame: *const EsFrame) -> c_int {
+    result_to_value(-1, || Ok(obj_ref(frame)?.0.stars.len() as c_int))
+}
+
+/// Reference light file is loaded and calibrated by `calibrator`
+#[no_mangle]
+pub unsafe extern "C" fn es_registrar_new(
+    calibrator:    *const EsCalibrator,
+    ref_file_name: *const c_char,
+) -> *mut EsRegistrar {
+    result_to_ptr(|| {
+        let calibrator = obj_ref(calibrator)?;
+        let ref_file_name = required_path_from_c(ref_file_name)?;
+        Ok(EsRegistrar(Registrar::new(&calibrator.0, &ref_file_name)?))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn es_registrar_free(registrar: *mut EsRegistrar) {
+    free_obj(registrar);
+}
+
+/// Aligns frame to reference one. `frame` is released. NULL frame (failed
+/// `es_frame_load`) gives NULL and doesn't change message of last error
+#[no_mangle]
+pub unsafe extern "C" fn es_register(
+    registrar: *const EsRegistrar,
+    frame:     *mut EsFrame,
+) -> *mut EsAlignedFrame {
+    if frame.is_null() {
+        return ptr::null_mut();
+    }
+    result_to_ptr(|| {
+        let frame = Box::from_raw(frame);
+        let registrar = obj_ref(registrar)?;
+        Ok(EsAlignedFrame(registrar.0.align(frame.0)?))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn es_aligned_frame_free(frame: *mut EsAlignedFrame) {
+    free_obj(frame);
+}
+
+/// `mode` is one of ES_MODE_* constants. `kappa` and `repeats`
+/// are used for kappa-sigma mode only
+#[no_mangle]
+pub extern "C" fn es_integrator_new(mode: c_int, kappa: f32, repeats: u32) -> *mut EsIntegrator {
+    result_to_ptr(|| {
+        let mode = match mode {
+            ES_MODE_KAPPA_SIGMA => CalcMode::CappaSigma,
+            ES_MODE_MEDIAN      => CalcMode::Median,
+            ES_MODE_MEAN        => CalcMode::Mean,
+            _ => anyhow::bail!("Wrong integration mode {}", mode),
+        };
+        Ok(EsIntegrator(Integrator::new(CalcOpts { mode, kappa, repeats })))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn es_integrator_free(integrator: *mut EsIntegrator) {
+    free_obj(integrator);
+}
+
+/// Adds aligned frame into integrator. `frame` is released.
+/// NULL frame gives -1 and doesn't change message of last error
+#[no_mangle]
+pub unsafe extern "C" fn es_integrator_add(
+    integrator: *mut EsIntegrator,
+    frame:      *mut EsAlignedFrame,
+) -> c_int {
+    if frame.is_null() {
+        return -1;
+    }
+    result_to_int(|| {
+        let frame = Box::from_raw(frame);
+        let integrator = integrator.as_mut().ok_or_else(|| anyhow::anyhow!("NULL object pointer"))?;
+        integrator.0.add(frame.0)
+    })
+}
+
+/// Stacks all added frames. Integrator can be used for next frames after that
+#[no_mangle]
+pub unsafe extern "C" fn es_integrator_stack(integrator: *const EsIntegrator) -> *mut EsImage {
+    result_to_ptr(|| {
+        let integrator = obj_ref(integrator)?;
+        Ok(EsImage(integrator.0.integrate()?))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn es_image_free(image: *mut EsImage) {
+    free_obj(image);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn es_image_width(image: *const EsImage) -> c_int {
+    result_to_value(-1, || Ok(obj_ref(image)?.0.width() as c_int))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn es_image_height(image: *const EsImage) -> c_int {
+    result_to_value(-1, || Ok(obj_ref(image)?.0.height() as c_int))
+}
+
+/// 1 for mono image, 3 for RGB one
+#[no_mangle]
+pub unsafe extern "C" fn es_image_channels(image: *const EsImage) -> c_int {
+    result_to_value(-1, || Ok(if obj_ref(image)?.0.is_rgb() { 3 } else { 1 }))
+}
+
+/// Row-major values of channel (0 for mono image, 0..2 for R, G and B) in
+/// 0..1 range. Buffer is owned by image and is valid until `es_image_free`
+#[no_mangle]
+pub unsafe extern "C" fn es_image_data(image: *const EsImage, channel: c_int) -> *const f32 {
+    result_to_value(ptr::null(), || {
+        let image = obj_ref(image)?;
+        let layer = match (image.0.is_rgb(), channel) {
+            (false, 0) => &image.0.l,
+            (true, 0)  => &image.0.r,
+            (true, 1)  => &image.0.g,
+            (true, 2)  => &image.0.b,
+            _ => anyhow::bail!("Wrong channel {}", channel),
+        };
+        Ok(layer.as_slice().as_ptr())
+    })
+}
+
+/// Saves image into FITS, TIFF or XISF file (by extension) as 32 bit float data
+#[no_mangle]
+pub unsafe extern "C" fn es_image_save(image: *const EsImage, file_name: *const c_char) -> c_int {
+    result_to_int(|| {
+        let image = obj_ref(image)?;
+        let file_name = required_path_from_c(file_name)?;
+        let info = ImageInfo {
+            file_name: file_name.clone(),
+            width: image.0.width() as usize,
+            height: image.0.height() as usize,
+            ..ImageInfo::default()
+        };
+        write_file_atomically(&file_name, |tmp_file_name| {
+            save_image_to_file(&image.0, &info, tmp_file_name, FitsSaveOpts::default())
+        })
+    })
+}
@@ -0,0 +1,50 @@
+use serde::*;
+use chrono::prelude::*;
+
+/* Field rotation of untracked or alt-az mounted telescope */
+
+// Earth rotation rate in radians per second (sidereal day)
+const EARTH_ROTATION_RATE: f64 = 2.0 * std::f64::consts::PI / 86164.0905;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct FieldRotationParams {
+    /// Site latitude in degrees (positive to north)
+    pub latitude: f64,
+
+    /// Azimuth of object in degrees (0 - north, 90 - east)
+    pub azimuth: f64,
+
+    /// Altitude of object in degrees
+    pub altitude: f64,
+}
+
+impl Default for FieldRotationParams {
+    fn default() -> Self {
+        Self {
+            latitude: 45.0,
+            azimuth: 180.0,
+            altitude: 45.0,
+        }
+    }
+}
+
+impl FieldRotationParams {
+    /// Field rotation rate in radians per second
+    pub fn calc_rate(&self) -> f64 {
+        let lat = self.latitude.to_radians();
+        let az = self.azimuth.to_radians();
+        let alt = self.altitude.to_radians().min(89_f64.to_radians());
+        EARTH_ROTATION_RATE * lat.cos() * az.cos() / alt.cos()
+    }
+
+    /// Predicted rotation angle (in radians) of image relative to reference one
+    pub fn predict_angle(
+        &self,
+        ref_time: &DateTime<Local>,
+        time:     &DateTime<Local>
+    ) -> f64 {
+        let seconds = (*time - *ref_time).num_milliseconds() as f64 / 1000.0;
+        self.calc_rate() * seconds
+    }
+}
@@ -1,6 +1,7 @@
-use std::path::*;
+use std::{path::*, io::Read, sync::atomic::{AtomicBool, Ordering}};
 use itertools::Itertools;
 use chrono::prelude::*;
+use sha2::{Sha256, Digest};
 
 pub fn file_mask_to_regex_str(text: &str) -> String {
     let mut result = String::new();
@@ -117,3 +118,64 @@ pub fn get_file_time(file_name: &Path) -> anyhow::Result<DateTime<Local>> {
     let metadata = std::fs::metadata(file_name)?;
     Ok(metadata.created()?.into())
 }
+
+/// SHA-256 of content of file as hex string
+pub fn calc_file_sha256(file_name: &Path) -> anyhow::Result<String> {
+    let mut file = std::fs::File::open(file_name)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0_u8; 1024 * 1024];
+    loop {
+        let len = file.read(&mut buffer)?;
+        if len == 0 { break; }
+        hasher.update(&buffer[..len]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/* Atomic writing of output files */
+
+static SYNC_WRITTEN_FILES: AtomicBool = AtomicBool::new(true);
+
+/// Enables or disables flushing of written files to disk (fsync)
+pub fn set_sync_written_files(value: bool) {
+    SYNC_WRITTEN_FILES.store(value, Ordering::Relaxed);
+}
+
+/// Name of temporary file used while writing. Extension is kept
+/// because some writers select file format by extension
+fn get_partial_file_name(file_name: &Path) -> PathBuf {
+    let stem = file_name.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+    let ext = extract_extension(file_name);
+    let name = if ext.is_empty() {
+        format!("{}.partial", stem)
+    } else {
+        format!("{}.partial.{}", stem, ext)
+    };
+    file_name.with_file_name(name)
+}
+
+/// Calls `write_fun` to write into temporary file and renames
+/// temporary file into `file_name` only if writing is successful.
+/// So crash or power loss never leaves truncated `file_name`
+pub fn write_file_atomically<R, F>(file_name: &Path, write_fun: F) -> anyhow::Result<R>
+where F: FnOnce(&Path) -> anyhow::Result<R> {
+    let partial_file_name = get_partial_file_name(file_name);
+    let result = write_fun(&partial_file_name).and_then(|result| {
+        if SYNC_WRITTEN_FILES.load(Ordering::Relaxed) {
+            // Windows doesn't flush file opened only for reading
+            std::fs::OpenOptions::new().write(true).open(&partial_file_name)?.sync_all()?;
+        }
+        std::fs::rename(&partial_file_name, file_name)?;
+        #[cfg(unix)]
+        if SYNC_WRITTEN_FILES.load(Ordering::Relaxed) {
+            if let Some(dir) = file_name.parent().filter(|d| !d.as_os_str().is_empty()) {
+                std::fs::File::open(dir)?.sync_all()?;
+            }
+        }
+        Ok(result)
+    });
+    if result.is_err() {
+        _ = std::fs::remove_file(&partial_file_name);
+    }
+    result
+}
@@ -0,0 +1,228 @@
+use rayon::prelude::*;
+use crate::{image::*, wcs::*};
+
+/* Crop, rotation and flip of images. Every operation is affine transform
+   of pixel coordinates of result into coordinates of source image, so WCS
+   of source image can be transformed too */
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum GeometryOp {
+    Crop { x: Crd, y: Crd, width: Crd, height: Crd },
+    AutoCrop, // area covered by all frames
+    Rotate(f64), // degrees counterclockwise
+    FlipH,
+    FlipV,
+}
+
+impl GeometryOp {
+    /// "x,y,width,height"
+    pub fn crop_from_str(text: &str) -> anyhow::Result<Self> {
+        let values: Vec<Crd> = text.split(',')
+            .map(|v| v.trim().parse())
+            .collect::<Result<_, _>>()
+            .map_err(|_| anyhow::anyhow!("Wrong crop rectangle {}", text))?;
+        let [x, y, width, height] = values[..] else {
+            anyhow::bail!("Wrong crop rectangle {} (x,y,width,height expected)", text);
+        };
+        Ok(GeometryOp::Crop { x, y, width, height })
+    }
+
+    pub fn flip_from_str(text: &str) -> anyhow::Result<Self> {
+        match text.to_lowercase().as_str() {
+            "h"|"horizontal" => Ok(GeometryOp::FlipH),
+            "v"|"vertical"   => Ok(GeometryOp::FlipV),
+            _ => anyhow::bail!("Wrong flip {} (h or v)", text),
+        }
+    }
+}
+
+/// Coordinates of result pixel in source image: `src = a * dst + t`
+#[derive(Clone, Copy, Debug)]
+pub struct PixelTransform {
+    pub a: [[f64; 2]; 2],
+    pub t: [f64; 2],
+}
+
+impl PixelTransform {
+    const IDENTITY: PixelTransform = PixelTransform { a: [[1.0, 0.0], [0.0, 1.0]], t: [0.0, 0.0] };
+
+    fn apply(&self, x: f64, y: f64) -> (f64, f64) {
+        (
+            self.a[0][0] * x + self.a[0][1] * y + self.t[0],
+            self.a[1][0] * x + self.a[1][1] * y + self.t[1],
+        )
+    }
+
+    // self is applied after `next`
+    fn then(&self, next: &PixelTransform) -> PixelTransform {
+        let (a, b) = (&self.a, &next.a);
+        let (tx, ty) = self.apply(next.t[0], next.t[1]);
+        PixelTransform {
+            a: [
+                [a[0][0] * b[0][0] + a[0][1] * b[1][0], a[0][0] * b[0][1] + a[0][1] * b[1][1]],
+                [a[1][0] * b[0][0] + a[1][1] * b[1][0], a[1][0] * b[0][1] + a[1][1] * b[1][1]],
+            ],
+            t: [tx, ty],
+        }
+    }
+}
+
+fn is_covered(image: &Image, x: Crd, y: Crd) -> bool {
+    [&image.l, &image.r, &image.g, &image.b]
+        .iter()
+        .filter(|l| !l.is_empty())
+        .all(|l| {
+            // overexposed pixels of stacked image are marked by infinity
+            let v = l.get(x, y).unwrap_or(NO_VALUE_F32);
+            !v.is_nan() && v != NO_VALUE_F32 && v != 0.0
+        })
+}
+
+/// Biggest rectangle without not covered pixels (zeros or NO_VALUE) near borders.
+/// Border with biggest part of not covered pixels is moved inside first
+pub fn find_common_coverage(image: &Image) -> anyhow::Result<(Crd, Crd, Crd, Crd)> {
+    find_common_coverage_of_images(&[image])
+}
+
+/// The same as `find_common_coverage` for intersection of covered areas
+/// of several images of the same size (for example stacks of all filters)
+pub fn find_common_coverage_of_images(images: &[&Image]) -> anyhow::Result<(Crd, Crd, Crd, Crd)> {
+    let Some(first) = images.first() else {
+        anyhow::bail!("No images to find common coverage");
+    };
+    if images.iter().any(|img| img.width() != first.width() || img.height() != first.height()) {
+        anyhow::bail!("Images have different sizes");
+    }
+    let covered = |x, y| images.iter().all(|img| is_covered(img, x, y));
+    let (mut x1, mut y1) = (0, 0);
+    let (mut x2, mut y2) = (first.width() - 1, first.height() - 1);
+    loop {
+        if x1 >= x2 || y1 >= y2 {
+            anyhow::bail!("Image has no covered area");
+        }
+        let row_part = |y| (x1..=x2).filter(|&x| !covered(x, y)).count() as f64 / (x2 - x1 + 1) as f64;
+        let col_part = |x| (y1..=y2).filter(|&y| !covered(x, y)).count() as f64 / (y2 - y1 + 1) as f64;
+        let parts = [row_part(y1), row_part(y2), col_part(x1), col_part(x2)];
+        let (max_index, max_part) = parts.iter()
+            .copied()
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .unwrap();
+        if max_part == 0.0 { break; }
+        match max_index {
+            0 => y1 += 1,
+            1 => y2 -= 1,
+            2 => x1 += 1,
+            _ => x2 -= 1,
+        }
+    }
+    Ok((x1, y1, x2 - x1 + 1, y2 - y1 + 1))
+}
+
+fn warp_layer(
+    layer:       &ImageLayerF32,
+    width:       Crd,
+    height:      Crd,
+    transform:   &PixelTransform,
+    interpolate: bool
+) -> ImageLayerF32 {
+    if layer.is_empty() { return ImageLayerF32::new_empty(); }
+    let mut result = ImageLayerF32::new(width, height);
+    result.as_slice_mut()
+        .par_chunks_mut(width as usize)
+        .enumerate()
+        .for_each(|(y, row)| {
+            for (x, v) in row.iter_mut().enumerate() {
+                let (sx, sy) = transform.apply(x as f64, y as f64);
+                *v = if interpolate {
+                    layer.get_f64_crd(sx, sy)
+                } else {
+                    layer.get(sx.round() as Crd, sy.round() as Crd)
+                }.unwrap_or(0.0);
+            }
+        });
+    result
+}
+
+fn op_transform(op: &GeometryOp, image: &Image) -> anyhow::Result<(Crd, Crd, PixelTransform, bool)> {
+    let (w, h) = (image.width(), image.height());
+    let (wf, hf) = (w as f64 - 1.0, h as f64 - 1.0);
+    let transform = |a, t| PixelTransform { a, t };
+    let result = match op {
+        GeometryOp::Crop { x, y, width, height } => {
+            if *x < 0 || *y < 0 || *width < 1 || *height < 1 || x + width > w || y + height > h {
+                anyhow::bail!("Crop rectangle is out of image {}x{}", w, h);
+            }
+            (*width, *height, transform([[1.0, 0.0], [0.0, 1.0]], [*x as f64, *y as f64]), false)
+        }
+        GeometryOp::AutoCrop => {
+            let (x, y, width, height) = find_common_coverage(image)?;
+            return op_transform(&GeometryOp::Crop { x, y, width, height }, image);
+        }
+        GeometryOp::Rotate(angle) => {
+            let angle = angle.rem_euclid(360.0);
+            if angle == 0.0 {
+                (w, h, PixelTransform::IDENTITY, false)
+            } else if angle == 90.0 {
+                (h, w, transform([[0.0, -1.0], [1.0, 0.0]], [wf, 0.0]), false)
+            } else if angle == 180.0 {
+                (w, h, transform([[-1.0, 0.0], [0.0, -1.0]], [wf, hf]), false)
+            } else if angle == 270.0 {
+                (h, w, transform([[0.0, 1.0], [-1.0, 0.0]], [0.0, hf]), false)
+            } else {
+                // rotation around center, size is not changed
+                let (sin_a, cos_a) = angle.to_radians().sin_cos();
+                let (cx, cy) = (wf / 2.0, hf / 2.0);
+                let a = [[cos_a, -sin_a], [sin_a, cos_a]];
+                let t = [cx - a[0][0] * cx - a[0][1] * cy, cy - a[1][0] * cx - a[1][1] * cy];
+                (w, h, transform(a, t), true)
+            }
+        }
+        GeometryOp::FlipH =>
+            (w, h, transform([[-1.0, 0.0], [0.0, 1.0]], [wf, 0.0]), false),
+        GeometryOp::FlipV =>
+            (w, h, transform([[1.0, 0.0], [0.0, -1.0]], [0.0, hf]), false),
+    };
+    Ok(result)
+}
+
+pub struct GeometryResult {
+    pub image:     Image,
+    pub transform: PixelTransform, // from result to source image
+}
+
+pub fn apply_geometry(image: Image, ops: &[GeometryOp]) -> anyhow::Result<GeometryResult> {
+    let mut image = image;
+    let mut total = PixelTransform::IDENTITY;
+    for op in ops {
+        let (width, height, transform, interpolate) = op_transform(op, &image)?;
+        image = Image {
+            l: warp_layer(&image.l, width, height, &transform, interpolate),
+            r: warp_layer(&image.r, width, height, &transform, interpolate),
+            g: warp_layer(&image.g, width, height, &transform, interpolate),
+            b: warp_layer(&image.b, width, height, &transform, interpolate),
+        };
+        total = total.then(&transform);
+    }
+    Ok(GeometryResult { image, transform: total })
+}
+
+/// WCS of result image from WCS of source image
+pub fn transform_wcs(wcs: &Wcs, transform: &PixelTransform) -> Option<Wcs> {
+    let a = &transform.a;
+    let det = a[0][0] * a[1][1] - a[0][1] * a[1][0];
+    if det.abs() < 1e-12 { return None; }
+    // reference pixel (FITS convention) in result coordinates
+    let (px, py) = (wcs.crpix[0] - 1.0 - transform.t[0], wcs.crpix[1] - 1.0 - transform.t[1]);
+    let rx = ( a[1][1] * px - a[0][1] * py) / det;
+    let ry = (-a[1][0] * px + a[0][0] * py) / det;
+    let cd = &wcs.cd;
+    Some(Wcs {
+        crval: wcs.crval,
+        crpix: [rx + 1.0, ry + 1.0],
+        cd: [
+            [cd[0][0] * a[0][0] + cd[0][1] * a[1][0], cd[0][0] * a[0][1] + cd[0][1] * a[1][1]],
+            [cd[1][0] * a[0][0] + cd[1][1] * a[1][0], cd[1][0] * a[0][1] + cd[1][1] * a[1][1]],
+        ],
+    })
+}
@@ -0,0 +1,191 @@
+#![cfg(test)]
+
+use std::path::*;
+use crate::{image::*, image_io::*};
+
+/* Golden images for tests of algorithms. Result of algorithm is compared
+   with FITS file from test_data/golden directory. Check is skipped with
+   warning if golden file is missing. Set ELECTRA_UPDATE_GOLDEN=1 to create
+   missing or recreate all golden files after intended change of algorithm. Values of image must be
+   in 0..1 range (float FITS data with greater values is normalized) */
+
+#[derive(Clone, Copy)]
+pub struct Tolerance {
+    pub abs: f32,
+    pub rel: f32,
+}
+
+impl Tolerance {
+    pub const DEFAULT: Tolerance = Tolerance { abs: 1e-5, rel: 1e-4 };
+
+    fn is_equal(&self, value: f32, expected: f32) -> bool {
+        if value.is_nan() || expected.is_nan() {
+            return value.is_nan() && expected.is_nan();
+        }
+        if value.is_infinite() || expected.is_infinite() {
+            return value == expected;
+        }
+        (value - expected).abs() <= self.abs + self.rel * expected.abs()
+    }
+}
+
+fn golden_file_name(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("test_data")
+        .join("golden")
+        .join(format!("{}.fit", name))
+}
+
+fn compare_layers(
+    name:      &str,
+    layer:     &ImageLayerF32,
+    expected:  &ImageLayerF32,
+    tolerance: Tolerance
+) -> Result<(), String> {
+    let mut wrong_cnt = 0;
+    let mut max_diff = 0_f32;
+    let mut first_wrong = None;
+    for (x, y, v) in layer.iter_crd() {
+        let e = expected.get(x, y).unwrap_or(f32::NAN);
+        if tolerance.is_equal(v, e) { continue; }
+        wrong_cnt += 1;
+        max_diff = max_diff.max((v - e).abs());
+        first_wrong.get_or_insert((x, y, v, e));
+    }
+    if let Some((x, y, v, e)) = first_wrong {
+        return Err(format!(
+            "{} values of {} layer differ (max. diff {}), first at ({}, {}): {} != {}",
+            wrong_cnt, name, max_diff, x, y, v, e
+        ));
+    }
+    Ok(())
+}
+
+/// Panics if image differs from golden image
+pub fn check_golden_image(name: &str, image: &Image, tolerance: Tolerance) {
+    let file_name = golden_file_name(name);
+    let update = std::env::var("ELECTRA_UPDATE_GOLDEN").map(|v| v == "1").unwrap_or(false);
+    if !update && !file_name.is_file() {
+        eprintln!(
+            "WARNING: golden image {:?} is not found, check is skipped. \
+            Run tests with ELECTRA_UPDATE_GOLDEN=1 to create it",
+            file_name
+        );
+        return;
+    }
+    if update {
+        std::fs::create_dir_all(file_name.parent().unwrap()).unwrap();
+        save_image_to_fits_file(image, &ImageInfo::default(), &file_name, FitsSaveOpts::default())
+            .unwrap();
+        eprintln!("Golden image {:?} is written", file_name);
+        return;
+    }
+    let ImageData { image: RawOrImage::Image(expected), .. } =
+        load_image_from_file(&file_name, false).unwrap() else {
+        panic!("Golden image {:?} is not image", file_name);
+    };
+    assert_eq!(
+        (image.width(), image.height(), image.is_rgb()),
+        (expected.width(), expected.height(), expected.is_rgb()),
+        "Size or color of image differs from golden image {}", name
+    );
+    let layers = [
+        ("L", &image.l, &expected.l),
+        ("R", &image.r, &expected.r),
+        ("G", &image.g, &expected.g),
+        ("B", &image.b, &expected.b),
+    ];
+    for (layer_name, layer, expected_layer) in layers {
+        if layer.is_empty() { continue; }
+        if let Err(text) = compare_layers(layer_name, layer, expected_layer, tolerance) {
+            panic!("Image differs from golden image {}: {}", name, text);
+        }
+    }
+}
+
+/* Synthetic star field frames for end-to-end tests. Light files are
+   shifted by few pixels and have gaussian stars, vignetting, dark
+   current with hot pixels and noise. Noise is generated by seeded RNG
+   so files are the same for every run */
+
+const FIELD_WIDTH: Crd = 192;
+const FIELD_HEIGHT: Crd = 128;
+const FIELD_STARS: usize = 40;
+
+pub struct StarFieldFixtures {
+    pub lights: Vec<PathBuf>,
+    pub darks:  Vec<PathBuf>,
+    pub flats:  Vec<PathBuf>,
+}
+
+fn vignetting(x: Crd, y: Crd) -> f32 {
+    let dx = (x - FIELD_WIDTH / 2) as f32 / FIELD_WIDTH as f32;
+    let dy = (y - FIELD_HEIGHT / 2) as f32 / FIELD_WIDTH as f32;
+    1.0 - 0.8 * (dx * dx + dy * dy)
+}
+
+fn dark_current(x: Crd, y: Crd) -> f32 {
+    if (x * 7 + y * 13) % 997 == 0 { 0.4 } else { 0.01 }
+}
+
+fn save_fixture(layer: ImageLayerF32, exp: f64, file_name: &Path) {
+    let mut image = Image::new_grey(layer.width(), layer.height());
+    image.l = layer;
+    let info = ImageInfo { exp: Some(exp), ..ImageInfo::default() };
+    let opts = FitsSaveOpts { bitpix: FitsBitPix::Int16, ..FitsSaveOpts::default() };
+    save_image_to_fits_file(&image, &info, file_name, opts).unwrap();
+}
+
+pub fn create_star_field_fixtures(dir: &Path, lights: usize) -> StarFieldFixtures {
+    use rand::prelude::*;
+    let mut rng = StdRng::seed_from_u64(42);
+    std::fs::create_dir_all(dir).unwrap();
+    let mut noise = |sigma: f32| -> f32 {
+        // sum of uniform values is close to gaussian
+        sigma * (0..12).map(|_| rng.gen_range(-0.5..0.5)).sum::<f32>()
+    };
+    let stars: Vec<(f64, f64, f32)> = {
+        let mut rng = StdRng::seed_from_u64(7);
+        (0..FIELD_STARS).map(|_| (
+            rng.gen_range(10.0..FIELD_WIDTH as f64 - 10.0),
+            rng.gen_range(10.0..FIELD_HEIGHT as f64 - 10.0),
+            rng.gen_range(0.05..0.5),
+        )).collect()
+    };
+    let mut result = StarFieldFixtures { lights: Vec::new(), darks: Vec::new(), flats: Vec::new() };
+    for i in 0..lights {
+        let (dx, dy) = (1.3 * i as f64, -0.7 * i as f64);
+        let mut layer = ImageLayerF32::new(FIELD_WIDTH, FIELD_HEIGHT);
+        for (x, y, v) in layer.iter_crd_mut() {
+            let sky: f32 = 0.1 + stars.iter()
+                .map(|(sx, sy, ampl)| {
+                    let r2 = (x as f64 - sx - dx).powi(2) + (y as f64 - sy - dy).powi(2);
+                    ampl * (-r2 / (2.0 * 1.5 * 1.5)).exp() as f32
+                })
+                .sum::<f32>();
+            *v = sky * vignetting(x, y) + dark_current(x, y) + noise(0.005);
+        }
+        let file_name = dir.join(format!("light{:02}.fit", i));
+        save_fixture(layer, 60.0, &file_name);
+        result.lights.push(file_name);
+    }
+    for i in 0..3 {
+        let mut layer = ImageLayerF32::new(FIELD_WIDTH, FIELD_HEIGHT);
+        for (x, y, v) in layer.iter_crd_mut() {
+            *v = dark_current(x, y) + noise(0.005);
+        }
+        let file_name = dir.join(format!("dark{:02}.fit", i));
+        save_fixture(layer, 60.0, &file_name);
+        result.darks.push(file_name);
+    }
+    for i in 0..3 {
+        let mut layer = ImageLayerF32::new(FIELD_WIDTH, FIELD_HEIGHT);
+        for (x, y, v) in layer.iter_crd_mut() {
+            *v = 0.5 * vignetting(x, y) + noise(0.005);
+        }
+        let file_name = dir.join(format!("flat{:02}.fit", i));
+        save_fixture(layer, 1.0, &file_name);
+        result.flats.push(file_name);
+    }
+    result
+}
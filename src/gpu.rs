@@ -0,0 +1,629 @@
+use crate::{image::*, calc::*};
+
+/* Offloading of per-pixel work to GPU by compute shaders of wgpu:
+   bilinear resampling of light files at registration and integration
+   of stack by weighted mean or kappa-sigma clipping. Coordinates and
+   sums are calculated in f32 on GPU so results differ from CPU ones
+   within float precision. Any error of GPU disables it and the work
+   continues on CPU. Without `gpu` feature GPU can't be selected and
+   CPU is used always */
+
+/// Result of integration of one pixel by GPU
+#[derive(Clone, Copy, Default, Debug)]
+pub struct GpuStackPixel {
+    pub result:        f32,
+    pub count:         usize, // values without NO_VALUE_F32
+    pub weight:        f32, // sum of weights of used values
+    pub rejected_low:  usize,
+    pub rejected_high: usize,
+}
+
+// empty string or "none" - CPU only
+fn requested_device(device: &str) -> Option<&str> {
+    let device = device.trim();
+    if device.is_empty() || device.eq_ignore_ascii_case("none") {
+        None
+    } else {
+        Some(device)
+    }
+}
+
+/// Selects GPU: "auto" (high performance adapter), index of adapter or
+/// part of its name. Empty string or "none" disables GPU
+#[cfg(feature = "gpu")]
+pub fn set_gpu_device(device: &str) -> anyhow::Result<()> {
+    device::select(requested_device(device))
+}
+
+#[cfg(not(feature = "gpu"))]
+pub fn set_gpu_device(device: &str) -> anyhow::Result<()> {
+    if requested_device(device).is_some() {
+        anyhow::bail!("Program is built without GPU support (gpu feature)");
+    }
+    Ok(())
+}
+
+#[cfg(feature = "gpu")]
+pub fn is_gpu_enabled() -> bool {
+    device::current().is_some()
+}
+
+#[cfg(not(feature = "gpu"))]
+pub fn is_gpu_enabled() -> bool {
+    false
+}
+
+/// Same as `rotated_and_translated` of image module. None if GPU is
+/// not selected or failed
+#[cfg(feature = "gpu")]
+pub fn gpu_rotated_and_translated(
+    source:        &ImageLayerF32,
+    angle:         f64,
+    transl_x:      f64,
+    transl_y:      f64,
+    default_value: f32,
+    result_width:  Crd,
+    result_height: Crd
+) -> Option<ImageLayerF32> {
+    let gpu = device::current()?;
+    let pixels = (result_width * result_height) as usize;
+    let max_values = gpu.max_buffer_values();
+    if pixels == 0 || pixels > max_values || source.as_slice().len() > max_values {
+        return None;
+    }
+    let result = gpu.rotated_and_translated(
+        source, angle, transl_x, transl_y,
+        default_value, result_width, result_height
+    );
+    match result {
+        Ok(data) => Some(ImageLayerF32::new_from_vec(result_width, result_height, data)),
+        Err(err) => { device::disable(&err); None },
+    }
+}
+
+#[cfg(not(feature = "gpu"))]
+pub fn gpu_rotated_and_translated(
+    _source:        &ImageLayerF32,
+    _angle:         f64,
+    _transl_x:      f64,
+    _transl_y:      f64,
+    _default_value: f32,
+    _result_width:  Crd,
+    _result_height: Crd
+) -> Option<ImageLayerF32> {
+    None
+}
+
+/// Max number of pixels integrated by one call of `gpu_stack_values`
+/// for `frames` frames. None if GPU is not selected
+#[cfg(feature = "gpu")]
+pub fn gpu_stack_band_pixels(frames: usize) -> Option<usize> {
+    let gpu = device::current()?;
+    Some(gpu.max_buffer_values() / frames.max(device::STACK_RESULT_VALUES))
+}
+
+#[cfg(not(feature = "gpu"))]
+pub fn gpu_stack_band_pixels(_frames: usize) -> Option<usize> {
+    None
+}
+
+/// Integrates `pixels` pixels of frames. `values` are in
+/// `[frame * pixels + pixel]` order, `weights` are weights of frames.
+/// None for median (it is calculated on CPU) or if GPU failed
+#[cfg(feature = "gpu")]
+pub fn gpu_stack_values(
+    values:  &[f32],
+    weights: &[f32],
+    pixels:  usize,
+    opts:    &CalcOpts
+) -> Option<Vec<GpuStackPixel>> {
+    if opts.mode == CalcMode::Median || pixels == 0 {
+        return None;
+    }
+    assert!(values.len() == weights.len() * pixels);
+    let gpu = device::current()?;
+    match gpu.stack_values(values, weights, pixels, opts) {
+        Ok(result) => Some(result),
+        Err(err) => { device::disable(&err); None },
+    }
+}
+
+#[cfg(not(feature = "gpu"))]
+pub fn gpu_stack_values(
+    _values:  &[f32],
+    _weights: &[f32],
+    _pixels:  usize,
+    _opts:    &CalcOpts
+) -> Option<Vec<GpuStackPixel>> {
+    None
+}
+
+#[cfg(feature = "gpu")]
+mod device {
+    use std::sync::*;
+    use wgpu::util::DeviceExt;
+    use crate::{image::*, calc::*};
+    use super::GpuStackPixel;
+
+    const WORKGROUP_SIZE: u32 = 256;
+    const MAX_WORKGROUPS_X: u32 = 65535;
+    pub const STACK_RESULT_VALUES: usize = 5; // result, count, weight, rejected low, rejected high
+
+    const RESAMPLE_SHADER: &str = r#"
+struct Params {
+    src_width:     u32,
+    src_height:    u32,
+    dst_width:     u32,
+    dst_height:    u32,
+    cos_a:         f32,
+    sin_a:         f32,
+    transl_x:      f32,
+    transl_y:      f32,
+    center_x:      f32,
+    center_y:      f32,
+    default_value: f32,
+    padding:       f32,
+}
+
+@group(0) @binding(0) var<uniform> params: Params;
+@group(0) @binding(1) var<storage, read> src: array<f32>;
+@group(0) @binding(2) var<storage, read_write> dst: array<f32>;
+
+@compute @workgroup_size(256)
+fn main(@builtin(global_invocation_id) gid: vec3<u32>, @builtin(num_workgroups) groups: vec3<u32>) {
+    let index = gid.x + gid.y * groups.x * 256u;
+    if index >= params.dst_width * params.dst_height { return; }
+    let x = f32(index % params.dst_width) - params.transl_x;
+    let y = f32(index / params.dst_width) - params.transl_y;
+    let dx = x - params.center_x;
+    let dy = y - params.center_y;
+    let rot_x = params.center_x + dx * params.cos_a - dy * params.sin_a;
+    let rot_y = params.center_y + dy * params.cos_a + dx * params.sin_a;
+    let fx = floor(rot_x);
+    let fy = floor(rot_y);
+    let kx = rot_x - fx;
+    let ky = rot_y - fy;
+    var sum = 0.0;
+    var s_sum = 0.0;
+    var found = false;
+    for (var i = 0; i < 4; i++) {
+        let px = i32(fx) + (i & 1);
+        let py = i32(fy) + (i >> 1u);
+        if px < 0 || py < 0 || px >= i32(params.src_width) || py >= i32(params.src_height) { continue; }
+        found = true;
+        let s = select(1.0 - kx, kx, (i & 1) == 1) * select(1.0 - ky, ky, (i >> 1u) == 1);
+        s_sum += s;
+        if s > 1e-10 { sum += s * src[u32(py) * params.src_width + u32(px)]; }
+    }
+    var result = params.default_value;
+    if s_sum >= 0.9999 {
+        result = sum;
+    } else if found && s_sum > 0.0 {
+        result = sum / s_sum;
+    }
+    dst[index] = result;
+}
+"#;
+
+    const STACK_SHADER: &str = r#"
+struct Params {
+    frames:   u32,
+    pixels:   u32,
+    mode:     u32, // 0 - weighted mean, 1 - kappa-sigma
+    repeats:  u32,
+    kappa:    f32,
+    padding1: f32,
+    padding2: f32,
+    padding3: f32,
+}
+
+@group(0) @binding(0) var<uniform> params: Params;
+@group(0) @binding(1) var<storage, read> values: array<f32>; // [frame * pixels + pixel]
+@group(0) @binding(2) var<storage, read> weights: array<f32>;
+@group(0) @binding(3) var<storage, read_write> result: array<u32>; // bits of 5 f32 per pixel
+
+const NO_VALUE: f32 = -999.0;
+const BIG: f32 = 3.0e38;
+const INF_BITS: u32 = 0x7f800000u;
+
+fn is_inf(v: f32) -> bool {
+    return (bitcast<u32>(v) & INF_BITS) == INF_BITS;
+}
+
+fn is_used(v: f32, low: f32, high: f32) -> bool {
+    return v != NO_VALUE && !is_inf(v) && v >= low && v <= high;
+}
+
+@compute @workgroup_size(256)
+fn main(@builtin(global_invocation_id) gid: vec3<u32>, @builtin(num_workgroups) groups: vec3<u32>) {
+    let pixel = gid.x + gid.y * groups.x * 256u;
+    if pixel >= params.pixels { return; }
+    let out = pixel * 5u;
+
+    var finite_cnt = 0u;
+    var inf_cnt = 0u;
+    for (var f = 0u; f < params.frames; f++) {
+        let v = values[f * params.pixels + pixel];
+        if v == NO_VALUE { continue; }
+        if is_inf(v) { inf_cnt++; } else { finite_cnt++; }
+    }
+    if finite_cnt == 0u {
+        result[out] = select(0u, INF_BITS, inf_cnt != 0u);
+        result[out + 1u] = bitcast<u32>(f32(inf_cnt));
+        result[out + 2u] = 0u;
+        result[out + 3u] = 0u;
+        result[out + 4u] = 0u;
+        return;
+    }
+
+    // values which were rejected once are not used anymore
+    // so allowed range is intersection of ranges of all passes
+    var low = -BIG;
+    var high = BIG;
+    if params.mode == 1u && finite_cnt > 1u {
+        for (var r = 0u; r < params.repeats; r++) {
+            var sum = 0.0;
+            var cnt = 0.0;
+            for (var f = 0u; f < params.frames; f++) {
+                let v = values[f * params.pixels + pixel];
+                if !is_used(v, low, high) { continue; }
+                sum += v;
+                cnt += 1.0;
+            }
+            if cnt == 0.0 { break; }
+            let mean = sum / cnt;
+            var sum2 = 0.0;
+            for (var f = 0u; f < params.frames; f++) {
+                let v = values[f * params.pixels + pixel];
+                if !is_used(v, low, high) { continue; }
+                sum2 += (v - mean) * (v - mean);
+            }
+            let std_dev = sqrt(sum2 / cnt);
+            let new_low = mean - params.kappa * std_dev;
+            let new_high = mean + params.kappa * std_dev;
+            var changed = false;
+            for (var f = 0u; f < params.frames; f++) {
+                let v = values[f * params.pixels + pixel];
+                if is_used(v, low, high) && (v < new_low || v > new_high) { changed = true; }
+            }
+            low = max(low, new_low);
+            high = min(high, new_high);
+            if !changed { break; }
+        }
+    }
+
+    var sum = 0.0;
+    var sum_w = 0.0;
+    for (var f = 0u; f < params.frames; f++) {
+        let v = values[f * params.pixels + pixel];
+        if !is_used(v, low, high) { continue; }
+        sum += v * weights[f];
+        sum_w += weights[f];
+    }
+    result[out + 1u] = bitcast<u32>(f32(finite_cnt));
+    if sum_w == 0.0 {
+        result[out] = bitcast<u32>(NO_VALUE);
+        result[out + 2u] = 0u;
+        result[out + 3u] = 0u;
+        result[out + 4u] = 0u;
+        return;
+    }
+    let res = sum / sum_w;
+    var rejected_low = 0u;
+    var rejected_high = 0u;
+    for (var f = 0u; f < params.frames; f++) {
+        let v = values[f * params.pixels + pixel];
+        if v == NO_VALUE || is_inf(v) || is_used(v, low, high) { continue; }
+        if v < res { rejected_low++; } else { rejected_high++; }
+    }
+    result[out] = bitcast<u32>(res);
+    result[out + 2u] = bitcast<u32>(sum_w);
+    result[out + 3u] = bitcast<u32>(f32(rejected_low));
+    result[out + 4u] = bitcast<u32>(f32(rejected_high));
+}
+"#;
+
+    #[repr(C)]
+    #[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+    struct ResampleParams {
+        src_width:     u32,
+        src_height:    u32,
+        dst_width:     u32,
+        dst_height:    u32,
+        cos_a:         f32,
+        sin_a:         f32,
+        transl_x:      f32,
+        transl_y:      f32,
+        center_x:      f32,
+        center_y:      f32,
+        default_value: f32,
+        padding:       f32,
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+    struct StackParams {
+        frames:  u32,
+        pixels:  u32,
+        mode:    u32,
+        repeats: u32,
+        kappa:   f32,
+        padding: [f32; 3],
+    }
+
+    pub struct GpuContext {
+        requested: String,
+        name:      String,
+        device:    wgpu::Device,
+        queue:     wgpu::Queue,
+        resample:  wgpu::ComputePipeline,
+        stack:     wgpu::ComputePipeline,
+        lock:      Mutex<()>, // error scopes of device are not per thread
+    }
+
+    static GPU: RwLock<Option<Arc<GpuContext>>> = RwLock::new(None);
+
+    pub fn current() -> Option<Arc<GpuContext>> {
+        GPU.read().unwrap().clone()
+    }
+
+    pub fn select(device: Option<&str>) -> anyhow::Result<()> {
+        let mut gpu = GPU.write().unwrap();
+        if gpu.as_ref().map(|ctx| ctx.requested.as_str()) == device {
+            return Ok(());
+        }
+        *gpu = None;
+        let Some(device) = device else { return Ok(()); };
+        let context = GpuContext::new(device)?;
+        log::info!("GPU {} is used for resampling and stacking", context.name);
+        *gpu = Some(Arc::new(context));
+        Ok(())
+    }
+
+    pub fn disable(err: &anyhow::Error) {
+        log::warn!("GPU error: {}. CPU is used for the rest of processing", err);
+        *GPU.write().unwrap() = None;
+    }
+
+    fn select_adapter(instance: &wgpu::Instance, device: &str) -> anyhow::Result<wgpu::Adapter> {
+        if device.eq_ignore_ascii_case("auto") {
+            let adapter = pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference:       wgpu::PowerPreference::HighPerformance,
+                force_fallback_adapter: false,
+                compatible_surface:     None,
+            }));
+            return adapter.ok_or_else(|| anyhow::anyhow!("No GPU adapter found"));
+        }
+        let mut adapters: Vec<wgpu::Adapter> = instance
+            .enumerate_adapters(wgpu::Backends::all())
+            .into_iter()
+            .collect();
+        let names: Vec<String> = adapters.iter()
+            .map(|adapter| adapter.get_info().name)
+            .collect();
+        let lower = device.to_lowercase();
+        let index = device.parse::<usize>().ok()
+            .filter(|index| *index < adapters.len())
+            .or_else(|| names.iter().position(|name| name.to_lowercase().contains(&lower)));
+        let Some(index) = index else {
+            let list: Vec<String> = names.iter()
+                .enumerate()
+                .map(|(i, name)| format!("{}: {}", i, name))
+                .collect();
+            anyhow::bail!("GPU {} is not found. Available adapters: {}", device, list.join(", "));
+        };
+        Ok(adapters.swap_remove(index))
+    }
+
+    impl GpuContext {
+        fn new(requested: &str) -> anyhow::Result<Self> {
+            let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::default());
+            let adapter = select_adapter(&instance, requested)?;
+            let info = adapter.get_info();
+            let (device, queue) = pollster::block_on(adapter.request_device(
+                &wgpu::DeviceDescriptor {
+                    label:             Some("electra_stacking"),
+                    required_features: wgpu::Features::empty(),
+                    required_limits:   adapter.limits(),
+                },
+                None
+            ))?;
+            // default handler panics
+            device.on_uncaptured_error(Box::new(|err| log::error!("GPU: {}", err)));
+            let create_pipeline = |shader: &str, label: &str| {
+                let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                    label:  Some(label),
+                    source: wgpu::ShaderSource::Wgsl(shader.into()),
+                });
+                device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                    label:       Some(label),
+                    layout:      None,
+                    module:      &module,
+                    entry_point: "main",
+                })
+            };
+            device.push_error_scope(wgpu::ErrorFilter::Validation);
+            let resample = create_pipeline(RESAMPLE_SHADER, "resample");
+            let stack = create_pipeline(STACK_SHADER, "stack");
+            if let Some(err) = pollster::block_on(device.pop_error_scope()) {
+                anyhow::bail!("Shaders are not compiled for GPU {}: {}", info.name, err);
+            }
+            Ok(Self {
+                requested: requested.to_string(),
+                name: format!("{} ({:?})", info.name, info.backend),
+                device, queue, resample, stack,
+                lock: Mutex::new(()),
+            })
+        }
+
+        pub fn max_buffer_values(&self) -> usize {
+            let limits = self.device.limits();
+            let max_bytes = u64::min(limits.max_storage_buffer_binding_size as u64, limits.max_buffer_size);
+            (max_bytes / 4) as usize
+        }
+
+        pub fn rotated_and_translated(
+            &self,
+            source:        &ImageLayerF32,
+            angle:         f64,
+            transl_x:      f64,
+            transl_y:      f64,
+            default_value: f32,
+            result_width:  Crd,
+            result_height: Crd
+        ) -> anyhow::Result<Vec<f32>> {
+            let params = ResampleParams {
+                src_width:  source.width() as u32,
+                src_height: source.height() as u32,
+                dst_width:  result_width as u32,
+                dst_height: result_height as u32,
+                cos_a:      f64::cos(-angle) as f32,
+                sin_a:      f64::sin(-angle) as f32,
+                transl_x:   transl_x as f32,
+                transl_y:   transl_y as f32,
+                center_x:   ((result_width as f64 - 1.0) / 2.0) as f32,
+                center_y:   ((result_height as f64 - 1.0) / 2.0) as f32,
+                default_value,
+                padding:    0.0,
+            };
+            let pixels = (result_width * result_height) as usize;
+            self.run(&self.resample, bytemuck::bytes_of(&params), &[source.as_slice()], pixels, pixels)
+        }
+
+        pub fn stack_values(
+            &self,
+            values:  &[f32],
+            weights: &[f32],
+            pixels:  usize,
+            opts:    &CalcOpts
+        ) -> anyhow::Result<Vec<GpuStackPixel>> {
+            let params = StackParams {
+                frames:  weights.len() as u32,
+                pixels:  pixels as u32,
+                mode:    if opts.mode == CalcMode::CappaSigma { 1 } else { 0 },
+                repeats: opts.repeats,
+                kappa:   opts.kappa,
+                padding: [0.0; 3],
+            };
+            let result = self.run(
+                &self.stack,
+                bytemuck::bytes_of(&params),
+                &[values, weights],
+                pixels * STACK_RESULT_VALUES,
+                pixels
+            )?;
+            Ok(result
+                .chunks_exact(STACK_RESULT_VALUES)
+                .map(|v| GpuStackPixel {
+                    result:        v[0],
+                    count:         v[1] as usize,
+                    weight:        v[2],
+                    rejected_low:  v[3] as usize,
+                    rejected_high: v[4] as usize,
+                })
+                .collect())
+        }
+
+        // Binding 0 is params, next ones are inputs, last one is output
+        fn run(
+            &self,
+            pipeline:    &wgpu::ComputePipeline,
+            params:      &[u8],
+            inputs:      &[&[f32]],
+            out_values:  usize,
+            invocations: usize,
+        ) -> anyhow::Result<Vec<f32>> {
+            let _lock = self.lock.lock().unwrap();
+            self.device.push_error_scope(wgpu::ErrorFilter::OutOfMemory);
+            self.device.push_error_scope(wgpu::ErrorFilter::Validation);
+            let result = self.run_impl(pipeline, params, inputs, out_values, invocations);
+            let validation_err = pollster::block_on(self.device.pop_error_scope());
+            let memory_err = pollster::block_on(self.device.pop_error_scope());
+            if let Some(err) = validation_err.or(memory_err) {
+                anyhow::bail!("{}", err);
+            }
+            result
+        }
+
+        fn run_impl(
+            &self,
+            pipeline:    &wgpu::ComputePipeline,
+            params:      &[u8],
+            inputs:      &[&[f32]],
+            out_values:  usize,
+            invocations: usize,
+        ) -> anyhow::Result<Vec<f32>> {
+            let params_buffer = self.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label:    None,
+                contents: params,
+                usage:    wgpu::BufferUsages::UNIFORM,
+            });
+            let input_buffers: Vec<wgpu::Buffer> = inputs.iter()
+                .map(|data| self.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label:    None,
+                    contents: bytemuck::cast_slice(data),
+                    usage:    wgpu::BufferUsages::STORAGE,
+                }))
+                .collect();
+            let out_size = (out_values * std::mem::size_of::<f32>()) as u64;
+            let out_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+                label:              None,
+                size:               out_size,
+                usage:              wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+                mapped_at_creation: false,
+            });
+            let read_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+                label:              None,
+                size:               out_size,
+                usage:              wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            });
+
+            let mut entries = vec![wgpu::BindGroupEntry {
+                binding:  0,
+                resource: params_buffer.as_entire_binding(),
+            }];
+            for buffer in input_buffers.iter().chain([&out_buffer]) {
+                entries.push(wgpu::BindGroupEntry {
+                    binding:  entries.len() as u32,
+                    resource: buffer.as_entire_binding(),
+                });
+            }
+            let bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label:   None,
+                layout:  &pipeline.get_bind_group_layout(0),
+                entries: &entries,
+            });
+
+            // 2D dispatch because count of workgroups is limited by 65535 in one dimension
+            let groups = (invocations as u32).div_ceil(WORKGROUP_SIZE);
+            let groups_x = groups.min(MAX_WORKGROUPS_X);
+            let groups_y = groups.div_ceil(groups_x);
+            let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: None
+            });
+            {
+                let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                    label:            None,
+                    timestamp_writes: None,
+                });
+                pass.set_pipeline(pipeline);
+                pass.set_bind_group(0, &bind_group, &[]);
+                pass.dispatch_workgroups(groups_x, groups_y, 1);
+            }
+            encoder.copy_buffer_to_buffer(&out_buffer, 0, &read_buffer, 0, out_size);
+            self.queue.submit(Some(encoder.finish()));
+
+            let slice = read_buffer.slice(..);
+            let (sender, receiver) = std::sync::mpsc::channel();
+            slice.map_async(wgpu::MapMode::Read, move |result| { let _ = sender.send(result); });
+            self.device.poll(wgpu::Maintain::Wait);
+            receiver.recv()??;
+            let view = slice.get_mapped_range();
+            let result = bytemuck::cast_slice::<u8, f32>(&view).to_vec();
+            drop(view);
+            read_buffer.unmap();
+            Ok(result)
+        }
+    }
+}
@@ -0,0 +1,569 @@
+use rayon::prelude::*;
+use crate::{image::*, calc::*, stars::*, light_file::*, bg_mask::*};
+
+/* Background gradient extraction. Background is sampled in boxes free
+   of stars, smooth surface is fitted through samples and is subtracted
+   from image or image is divided by it.
+   Huge images (mosaics) are processed by tiles: stars and samples are
+   found for each tile in parallel, surface is fitted for each tile by
+   samples of tile and its neighbours and surfaces of nearest tiles are
+   blended so model is continuous. Only tiles and grid of model nodes
+   are kept in memory in addition to image */
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GradientModel {
+    Polynomial(usize), // degree
+    Rbf,               // thin plate spline with smoothing
+}
+
+impl GradientModel {
+    /// "poly1".."poly4" or "rbf"
+    pub fn from_str(text: &str) -> anyhow::Result<Self> {
+        let text = text.to_lowercase();
+        if text == "rbf" {
+            return Ok(GradientModel::Rbf);
+        }
+        match text.strip_prefix("poly").and_then(|d| d.parse().ok()) {
+            Some(degree @ 1..=4) => Ok(GradientModel::Polynomial(degree)),
+            _ => anyhow::bail!("Wrong gradient model {} (poly1..poly4 or rbf)", text),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GradientCorrection {
+    Subtract, // for light pollution
+    Divide,   // for vignetting
+}
+
+impl GradientCorrection {
+    pub fn from_str(text: &str) -> anyhow::Result<Self> {
+        match text.to_lowercase().as_str() {
+            "subtract" => Ok(GradientCorrection::Subtract),
+            "divide"   => Ok(GradientCorrection::Divide),
+            _ => anyhow::bail!("Wrong gradient correction {} (subtract or divide)", text),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct GradientOpts {
+    pub model:      GradientModel,
+    pub correction: GradientCorrection,
+    pub grid:       usize, // number of sample boxes along bigger side (of tile for tiled mode)
+    pub tile:       Option<usize>, // size of tile in pixels for tiled mode
+    pub keep_model: bool, // background model is returned in result
+    pub bg_mask:    BgMaskPreset, // objects excluded from samples
+}
+
+impl Default for GradientOpts {
+    fn default() -> Self {
+        Self {
+            model: GradientModel::Polynomial(2),
+            correction: GradientCorrection::Subtract,
+            grid: 16,
+            tile: None,
+            keep_model: true,
+            bg_mask: BgMaskPreset::Stars,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Sample {
+    x:     f64, // normalized to -1..1 (in pixels for samples of tiles)
+    y:     f64,
+    value: f64,
+}
+
+const MODEL_STEP: Crd = 16; // model is calculated in nodes and interpolated between them
+const TILE_MARGIN: Crd = 32; // stars on border of tile are found in margin
+const MAX_MASKED_PART: f32 = 0.5;
+const SAMPLES_REJECT_KAPPA: f64 = 2.5;
+const RBF_SMOOTHING: f64 = 0.01;
+
+fn norm_crd(v: f64, size: Crd) -> f64 {
+    2.0 * v / (size - 1).max(1) as f64 - 1.0
+}
+
+fn collect_samples(
+    layer: &ImageLayerF32,
+    mask:  &ImageLayer<bool>,
+    grid:  usize
+) -> Vec<Sample> {
+    let (width, height) = (layer.width(), layer.height());
+    let box_size = (width.max(height) / grid.max(2) as Crd).max(4);
+    let mut result = collect_box_samples(layer, mask, box_size, (0, 0), (0, 0, width, height));
+    for sample in &mut result {
+        sample.x = norm_crd(sample.x, width);
+        sample.y = norm_crd(sample.y, height);
+    }
+    result
+}
+
+/// Samples (in pixels) of boxes with centers inside of `area` (x1, y1, x2, y2 excluding).
+/// Boxes are placed on grid common for whole image. `origin` is
+/// coordinates of (0, 0) of `layer` and `mask` in whole image
+fn collect_box_samples(
+    layer:    &ImageLayerF32,
+    mask:     &ImageLayer<bool>,
+    box_size: Crd,
+    origin:   (Crd, Crd),
+    area:     (Crd, Crd, Crd, Crd),
+) -> Vec<Sample> {
+    let (area_x1, area_y1, area_x2, area_y2) = area;
+    let first = |from: Crd| box_size / 2 + (from - box_size / 2 + box_size - 1).max(0) / box_size * box_size;
+    let half = box_size / 4; // only central part of box is used
+    let mut result = Vec::new();
+    let mut values = Vec::new();
+    let mut y = first(area_y1);
+    while y < area_y2 {
+        let mut x = first(area_x1);
+        while x < area_x2 {
+            values.clear();
+            let mut total = 0;
+            let (lx, ly) = (x - origin.0, y - origin.1);
+            for (px, py, v) in layer.iter_rect_crd(lx - half, ly - half, lx + half, ly + half) {
+                total += 1;
+                if mask.get(px, py).unwrap_or(true) { continue; }
+                if !v.is_finite() || v == NO_VALUE_F32 { continue; }
+                values.push(v);
+            }
+            if total != 0 && values.len() as f32 >= (1.0 - MAX_MASKED_PART) * total as f32 {
+                if let Some(median) = median_f32(&mut values) {
+                    result.push(Sample { x: x as f64, y: y as f64, value: median as f64 });
+                }
+            }
+            x += box_size;
+        }
+        y += box_size;
+    }
+    result
+}
+
+trait Surface: Sync + Send {
+    fn value(&self, x: f64, y: f64) -> f64;
+}
+
+struct Polynomial {
+    degree: usize,
+    coeffs: Vec<f64>,
+}
+
+fn poly_terms(degree: usize, x: f64, y: f64) -> Vec<f64> {
+    let mut result = Vec::new();
+    for i in 0..=degree {
+        for j in 0..=degree-i {
+            result.push(x.powi(i as i32) * y.powi(j as i32));
+        }
+    }
+    result
+}
+
+impl Polynomial {
+    fn fit(samples: &[Sample], degree: usize) -> anyhow::Result<Self> {
+        use nalgebra::{DMatrix, DVector};
+        let terms_cnt = poly_terms(degree, 0.0, 0.0).len();
+        if samples.len() < terms_cnt {
+            anyhow::bail!("Too few background samples ({}) for polynomial of degree {}", samples.len(), degree);
+        }
+        let a = DMatrix::from_fn(samples.len(), terms_cnt, |r, c| {
+            poly_terms(degree, samples[r].x, samples[r].y)[c]
+        });
+        let b = DVector::from_iterator(samples.len(), samples.iter().map(|s| s.value));
+        let coeffs = a.svd(true, true)
+            .solve(&b, 1e-12)
+            .map_err(|e| anyhow::anyhow!("Can't fit polynomial: {}", e))?;
+        Ok(Self { degree, coeffs: coeffs.iter().copied().collect() })
+    }
+}
+
+impl Surface for Polynomial {
+    fn value(&self, x: f64, y: f64) -> f64 {
+        poly_terms(self.degree, x, y).iter()
+            .zip(&self.coeffs)
+            .map(|(t, c)| t * c)
+            .sum()
+    }
+}
+
+struct ThinPlateSpline {
+    points:  Vec<(f64, f64)>,
+    weights: Vec<f64>,
+    affine:  [f64; 3],
+}
+
+fn tps_kernel(r2: f64) -> f64 {
+    if r2 <= 0.0 { 0.0 } else { 0.5 * r2 * r2.ln() }
+}
+
+impl ThinPlateSpline {
+    fn fit(samples: &[Sample]) -> anyhow::Result<Self> {
+        use nalgebra::{DMatrix, DVector};
+        let n = samples.len();
+        if n < 4 {
+            anyhow::bail!("Too few background samples ({}) for RBF", n);
+        }
+        let mut a = DMatrix::<f64>::zeros(n + 3, n + 3);
+        let mut b = DVector::<f64>::zeros(n + 3);
+        for (i, si) in samples.iter().enumerate() {
+            for (j, sj) in samples.iter().enumerate() {
+                let r2 = (si.x - sj.x).powi(2) + (si.y - sj.y).powi(2);
+                a[(i, j)] = tps_kernel(r2);
+            }
+            a[(i, i)] += RBF_SMOOTHING * n as f64;
+            for (k, v) in [1.0, si.x, si.y].into_iter().enumerate() {
+                a[(i, n + k)] = v;
+                a[(n + k, i)] = v;
+            }
+            b[i] = si.value;
+        }
+        let solution = a.lu().solve(&b)
+            .ok_or_else(|| anyhow::anyhow!("Can't fit RBF surface"))?;
+        Ok(Self {
+            points: samples.iter().map(|s| (s.x, s.y)).collect(),
+            weights: solution.iter().take(n).copied().collect(),
+            affine: [solution[n], solution[n + 1], solution[n + 2]],
+        })
+    }
+}
+
+impl Surface for ThinPlateSpline {
+    fn value(&self, x: f64, y: f64) -> f64 {
+        let mut result = self.affine[0] + self.affine[1] * x + self.affine[2] * y;
+        for ((px, py), w) in self.points.iter().zip(&self.weights) {
+            result += w * tps_kernel((x - px).powi(2) + (y - py).powi(2));
+        }
+        result
+    }
+}
+
+fn fit_surface(samples: &[Sample], model: GradientModel) -> anyhow::Result<Box<dyn Surface>> {
+    Ok(match model {
+        GradientModel::Polynomial(degree) => Box::new(Polynomial::fit(samples, degree)?),
+        GradientModel::Rbf => Box::new(ThinPlateSpline::fit(samples)?),
+    })
+}
+
+/// Samples on nebulae or galaxies are rejected by residuals of fitted surface
+fn fit_surface_with_rejection(
+    samples: &mut Vec<Sample>,
+    model:   GradientModel
+) -> anyhow::Result<Box<dyn Surface>> {
+    let mut surface = fit_surface(samples, model)?;
+    for _ in 0..3 {
+        let residuals: Vec<_> = samples.iter()
+            .map(|s| CalcValue::new(s.value - surface.value(s.x, s.y)))
+            .collect();
+        let Some((_, dev)) = mean_and_std_dev(&residuals) else { break; };
+        let len_before = samples.len();
+        let kept: Vec<_> = samples.iter()
+            .zip(&residuals)
+            .filter(|(_, r)| r.value.abs() <= SAMPLES_REJECT_KAPPA * dev)
+            .map(|(s, _)| *s)
+            .collect();
+        if kept.len() == len_before || kept.len() < samples.len() / 2 { break; }
+        *samples = kept;
+        surface = fit_surface(samples, model)?;
+    }
+    Ok(surface)
+}
+
+/// Values of model in nodes of grid with `MODEL_STEP`
+struct ModelNodes {
+    nodes:   Vec<f32>,
+    nodes_x: Crd,
+    nodes_y: Crd,
+}
+
+impl ModelNodes {
+    /// `value` is value of model for coordinates in pixels
+    fn new(width: Crd, height: Crd, value: impl Fn(f64, f64) -> f64 + Sync) -> Self {
+        let nodes_x = (width + MODEL_STEP - 1) / MODEL_STEP + 1;
+        let nodes_y = (height + MODEL_STEP - 1) / MODEL_STEP + 1;
+        let nodes = (0..nodes_x * nodes_y)
+            .into_par_iter()
+            .map(|i| {
+                let x = ((i % nodes_x) * MODEL_STEP) as f64;
+                let y = ((i / nodes_x) * MODEL_STEP) as f64;
+                value(x, y) as f32
+            })
+            .collect();
+        Self { nodes, nodes_x, nodes_y }
+    }
+
+    fn node(&self, nx: Crd, ny: Crd) -> f32 {
+        self.nodes[(ny.min(self.nodes_y - 1) * self.nodes_x + nx.min(self.nodes_x - 1)) as usize]
+    }
+
+    /// Model values of row `y` interpolated between nodes
+    fn fill_row(&self, y: Crd, row: &mut [f32]) {
+        let (ny, fy) = (y / MODEL_STEP, (y % MODEL_STEP) as f32 / MODEL_STEP as f32);
+        for (x, v) in row.iter_mut().enumerate() {
+            let x = x as Crd;
+            let (nx, fx) = (x / MODEL_STEP, (x % MODEL_STEP) as f32 / MODEL_STEP as f32);
+            let top = self.node(nx, ny) * (1.0 - fx) + self.node(nx + 1, ny) * fx;
+            let bottom = self.node(nx, ny + 1) * (1.0 - fx) + self.node(nx + 1, ny + 1) * fx;
+            *v = top * (1.0 - fy) + bottom * fy;
+        }
+    }
+
+    fn create_layer(&self, width: Crd, height: Crd) -> ImageLayerF32 {
+        let mut result = ImageLayerF32::new(width, height);
+        result.as_slice_mut()
+            .par_chunks_mut(width as usize)
+            .enumerate()
+            .for_each(|(y, row)| self.fill_row(y as Crd, row));
+        result
+    }
+}
+
+fn create_model_layer(surface: &dyn Surface, width: Crd, height: Crd) -> ImageLayerF32 {
+    ModelNodes::new(width, height, |x, y| surface.value(norm_crd(x, width), norm_crd(y, height)))
+        .create_layer(width, height)
+}
+
+fn correct_value(v: &mut f32, model: f32, model_median: f32, correction: GradientCorrection) {
+    if !v.is_finite() || *v == NO_VALUE_F32 { return; }
+    *v = match correction {
+        GradientCorrection::Subtract =>
+            *v - model + model_median,
+        GradientCorrection::Divide =>
+            if model > 0.0 { *v * model_median / model } else { *v },
+    };
+}
+
+pub struct GradientResult {
+    pub model:   Option<Image>, // background model (if `keep_model` is set)
+    pub samples: usize, // min. number of used samples for channels
+}
+
+/// Pixels of `star_mask` (made by `--star-mask` command for example) greater
+/// than 0.5 are excluded from background samples together with found stars
+pub fn remove_gradient(
+    image:     &mut Image,
+    opts:      &GradientOpts,
+    star_mask: Option<&ImageLayerF32>,
+) -> anyhow::Result<GradientResult> {
+    if let Some(tile) = opts.tile {
+        return remove_gradient_tiled(image, opts, star_mask, tile as Crd);
+    }
+    let (width, height) = (image.width(), image.height());
+    let grey = image.create_greyscale_layer();
+    let noise = calc_noise(&grey) as f32;
+    let stars = find_stars_on_image(&grey, Some(noise), true, &StarsFindOpts::default())?;
+    let mut mask = create_stars_mask(width, height, &stars, 3);
+    if let Some(objects_opts) = opts.bg_mask.objects_opts() {
+        mask_bright_objects(&grey, &objects_opts, &mut mask);
+    }
+    drop(grey);
+    if let Some(star_mask) = star_mask {
+        for (m, s) in mask.iter_mut().zip(star_mask.iter()) {
+            if *s > 0.5 { *m = true; }
+        }
+    }
+
+    let mut model = if image.is_rgb() {
+        Image::new_color(width, height)
+    } else {
+        Image::new_grey(width, height)
+    };
+    let mut min_samples = usize::MAX;
+    let layers = if image.is_rgb() {
+        vec![(&mut image.r, &mut model.r), (&mut image.g, &mut model.g), (&mut image.b, &mut model.b)]
+    } else {
+        vec![(&mut image.l, &mut model.l)]
+    };
+    for (layer, model_layer) in layers {
+        let mut samples = collect_samples(layer, &mask, opts.grid);
+        let surface = fit_surface_with_rejection(&mut samples, opts.model)?;
+        min_samples = min_samples.min(samples.len());
+        *model_layer = create_model_layer(surface.as_ref(), width, height);
+
+        // background level is kept
+        let mut model_values: Vec<_> = model_layer.iter().step_by(97).copied().collect();
+        let model_median = median_f32(&mut model_values).unwrap_or(0.0);
+        for (v, m) in layer.iter_mut().zip(model_layer.iter()) {
+            correct_value(v, *m, model_median, opts.correction);
+        }
+    }
+    Ok(GradientResult {
+        model: opts.keep_model.then_some(model),
+        samples: min_samples,
+    })
+}
+
+// Surface fitted by samples of tile and its neighbours. Coordinates
+// of surface are relative to center of tile in tile sizes
+struct TileSurface {
+    surface: Box<dyn Surface>,
+    cx:      f64,
+    cy:      f64,
+    size:    f64,
+}
+
+impl TileSurface {
+    fn value(&self, x: f64, y: f64) -> f64 {
+        self.surface.value((x - self.cx) / self.size, (y - self.cy) / self.size)
+    }
+}
+
+fn copy_area(layer: &ImageLayerF32, x1: Crd, y1: Crd, x2: Crd, y2: Crd) -> ImageLayerF32 {
+    let data = (y1..y2)
+        .flat_map(|y| layer.row(y)[x1 as usize..x2 as usize].iter().copied())
+        .collect();
+    ImageLayerF32::new_from_vec(x2 - x1, y2 - y1, data)
+}
+
+fn remove_gradient_tiled(
+    image:     &mut Image,
+    opts:      &GradientOpts,
+    star_mask: Option<&ImageLayerF32>,
+    tile:      Crd,
+) -> anyhow::Result<GradientResult> {
+    let (width, height) = (image.width(), image.height());
+    let grid = opts.grid.max(2) as Crd;
+    let box_size = (tile / grid).max(4);
+    let tile = box_size * grid; // tile contains whole boxes
+    let tiles_x = (width + tile - 1) / tile;
+    let tiles_y = (height + tile - 1) / tile;
+    let tiles_cnt = (tiles_x * tiles_y) as usize;
+    let is_rgb = image.is_rgb();
+
+    // samples of every tile for every channel
+    let tile_samples = (0..tiles_cnt)
+        .into_par_iter()
+        .map(|i| -> anyhow::Result<Vec<Vec<Sample>>> {
+            let (tx, ty) = (i as Crd % tiles_x, i as Crd / tiles_x);
+            let area = (tx * tile, ty * tile, ((tx + 1) * tile).min(width), ((ty + 1) * tile).min(height));
+            let x1 = (area.0 - TILE_MARGIN).max(0);
+            let y1 = (area.1 - TILE_MARGIN).max(0);
+            let x2 = (area.2 + TILE_MARGIN).min(width);
+            let y2 = (area.3 + TILE_MARGIN).min(height);
+            let mut tile_image = Image::new();
+            if is_rgb {
+                tile_image.r = copy_area(&image.r, x1, y1, x2, y2);
+                tile_image.g = copy_area(&image.g, x1, y1, x2, y2);
+                tile_image.b = copy_area(&image.b, x1, y1, x2, y2);
+            } else {
+                tile_image.l = copy_area(&image.l, x1, y1, x2, y2);
+            }
+            let grey = tile_image.create_greyscale_layer();
+            let noise = calc_noise(&grey) as f32;
+            let stars = find_stars_on_image(&grey, Some(noise), true, &StarsFindOpts::default())?;
+            let mut mask = create_stars_mask(x2 - x1, y2 - y1, &stars, 3);
+            if let Some(objects_opts) = opts.bg_mask.objects_opts() {
+                mask_bright_objects(&grey, &objects_opts, &mut mask);
+            }
+            if let Some(star_mask) = star_mask {
+                for (x, y, m) in mask.iter_crd_mut() {
+                    if star_mask.get(x + x1, y + y1).unwrap_or(0.0) > 0.5 { *m = true; }
+                }
+            }
+            let layers = if is_rgb {
+                vec![&tile_image.r, &tile_image.g, &tile_image.b]
+            } else {
+                vec![&tile_image.l]
+            };
+            Ok(layers.into_iter()
+                .map(|layer| collect_box_samples(layer, &mask, box_size, (x1, y1), area))
+                .collect())
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let mut model = if !opts.keep_model {
+        Image::new()
+    } else if is_rgb {
+        Image::new_color(width, height)
+    } else {
+        Image::new_grey(width, height)
+    };
+    let mut min_samples = usize::MAX;
+    let layers = if is_rgb {
+        vec![(&mut image.r, &mut model.r), (&mut image.g, &mut model.g), (&mut image.b, &mut model.b)]
+    } else {
+        vec![(&mut image.l, &mut model.l)]
+    };
+    for (channel, (layer, model_layer)) in layers.into_iter().enumerate() {
+        min_samples = min_samples.min(tile_samples.iter().map(|s| s[channel].len()).sum());
+
+        // surface of every tile by samples of tile and its neighbours
+        let surfaces: Vec<Option<TileSurface>> = (0..tiles_cnt)
+            .into_par_iter()
+            .map(|i| {
+                let (tx, ty) = (i as Crd % tiles_x, i as Crd / tiles_x);
+                let cx = ((tx * tile + tile / 2) as f64).min(width as f64);
+                let cy = ((ty * tile + tile / 2) as f64).min(height as f64);
+                let size = tile as f64;
+                let mut samples: Vec<Sample> = Vec::new();
+                for ny in (ty - 1).max(0)..=(ty + 1).min(tiles_y - 1) {
+                    for nx in (tx - 1).max(0)..=(tx + 1).min(tiles_x - 1) {
+                        samples.extend(tile_samples[(ny * tiles_x + nx) as usize][channel].iter().map(|s| Sample {
+                            x: (s.x - cx) / size,
+                            y: (s.y - cy) / size,
+                            value: s.value,
+                        }));
+                    }
+                }
+                let surface = fit_surface_with_rejection(&mut samples, opts.model).ok()?;
+                Some(TileSurface { surface, cx, cy, size })
+            })
+            .collect();
+
+        // tiles without surface (empty areas of mosaic or nebula
+        // over whole neighbourhood) use nearest fitted surface
+        let fitted: Vec<usize> = (0..tiles_cnt).filter(|i| surfaces[*i].is_some()).collect();
+        if fitted.is_empty() {
+            anyhow::bail!("Too few background samples for tiles {}x{}", tile, tile);
+        }
+        let surface_of_tile: Vec<&TileSurface> = (0..tiles_cnt)
+            .map(|i| {
+                let (tx, ty) = (i as Crd % tiles_x, i as Crd / tiles_x);
+                let nearest = fitted.iter()
+                    .min_by_key(|j| {
+                        let (jx, jy) = (**j as Crd % tiles_x, **j as Crd / tiles_x);
+                        (jx - tx).pow(2) + (jy - ty).pow(2)
+                    })
+                    .copied()
+                    .unwrap_or(i);
+                surfaces[nearest].as_ref().unwrap()
+            })
+            .collect();
+
+        // surfaces of nearest tiles are blended by bilinear weights
+        let blend_pos = |v: f64, count: Crd| {
+            let pos = ((v - (tile / 2) as f64) / tile as f64).clamp(0.0, (count - 1) as f64);
+            let i0 = pos.floor() as Crd;
+            (i0, (i0 + 1).min(count - 1), pos - i0 as f64)
+        };
+        let nodes = ModelNodes::new(width, height, |x, y| {
+            let (x0, x1, fx) = blend_pos(x, tiles_x);
+            let (y0, y1, fy) = blend_pos(y, tiles_y);
+            let value = |tx: Crd, ty: Crd| surface_of_tile[(ty * tiles_x + tx) as usize].value(x, y);
+            let top = value(x0, y0) * (1.0 - fx) + value(x1, y0) * fx;
+            let bottom = value(x0, y1) * (1.0 - fx) + value(x1, y1) * fx;
+            top * (1.0 - fy) + bottom * fy
+        });
+
+        // background level is kept
+        let mut node_values = nodes.nodes.clone();
+        let model_median = median_f32(&mut node_values).unwrap_or(0.0);
+        layer.as_slice_mut()
+            .par_chunks_mut(width as usize)
+            .enumerate()
+            .for_each(|(y, row)| {
+                let mut model_row = vec![0_f32; row.len()];
+                nodes.fill_row(y as Crd, &mut model_row);
+                for (v, m) in row.iter_mut().zip(&model_row) {
+                    correct_value(v, *m, model_median, opts.correction);
+                }
+            });
+        if opts.keep_model {
+            *model_layer = nodes.create_layer(width, height);
+        }
+    }
+    Ok(GradientResult {
+        model: opts.keep_model.then_some(model),
+        samples: min_samples,
+    })
+}
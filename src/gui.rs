@@ -22,6 +22,7 @@ use crate::{
     config::*,
     project::*,
     str_utils::*,
+    fs_utils::*,
 };
 
 pub fn build_ui(application: &gtk::Application) {
@@ -150,6 +151,7 @@ pub fn build_ui(application: &gtk::Application) {
     if let Err(error) = res {
         show_error_message(&objects.window, &gettext("Error"), &error.to_string());
     }
+    objects.config.borrow().apply_global_options();
     apply_config(&objects);
 
     // Font (only for MS Windows)
@@ -963,8 +965,10 @@ fn get_project_title(project: &Project, markup: bool) -> String {
         result = format!("<b>{}</b>", result);
     }
 
-    if project.config().image_size == ImageSize::Bin2x2 {
-        result.push_str(" - bin 2x2");
+    match project.config().image_size {
+        ImageSize::Bin2x2 => result.push_str(" - bin 2x2"),
+        ImageSize::Bin3x3 => result.push_str(" - bin 3x3"),
+        ImageSize::Original => {},
     }
 
     let time = project.calc_time();
@@ -989,7 +993,7 @@ fn exec_and_show_progress<R, ExecFun, OkFun> (
 {
     enum UiMessage<R: Sized> {
         ProgressStage{ text: String },
-        ProgressPercent{ text: String, percent: usize },
+        ProgressPercent{ text: String, percent: usize, eta: Option<std::time::Duration> },
         Finished(R),
         Error(String)
     }
@@ -1028,10 +1032,11 @@ fn exec_and_show_progress<R, ExecFun, OkFun> (
                     text: text.to_string(),
                 }).unwrap();
             },
-            move |percent, text: &str| {
+            move |percent, eta, text: &str| {
                 sndr2.send_blocking(UiMessage::ProgressPercent {
                     text: text.to_string(),
-                    percent
+                    percent,
+                    eta,
                 }).unwrap();
             }
         );
@@ -1053,8 +1058,19 @@ fn exec_and_show_progress<R, ExecFun, OkFun> (
                     progress_bar.set_fraction(0.0);
                     progress_bar.set_text(None);
                 },
-                UiMessage::ProgressPercent { text, percent } => {
-                    let text = format!("{} {}%", text, percent);
+                UiMessage::ProgressPercent { text, percent, eta } => {
+                    let text = if let Some(eta) = eta {
+                        transl_and_replace(
+                            "{text} {percent}% (remaining {eta})",
+                            &[
+                                ("{text}", text),
+                                ("{percent}", percent.to_string()),
+                                ("{eta}", duration_to_str(eta)),
+                            ]
+                        )
+                    } else {
+                        format!("{} {}%", text, percent)
+                    };
                     progress_bar.set_fraction(percent as f64 / 100.0);
                     progress_bar.set_text(Some(&text));
                 },
@@ -1593,6 +1609,7 @@ fn select_and_add_files_into_project(
         };
         add_exts(RAW_EXTS);
         add_exts(FIT_EXTS);
+        add_exts(XISF_EXTS);
         if light_files {
             add_exts(TIF_EXTS);
         }
@@ -1720,12 +1737,13 @@ fn add_files_into_project(
         let files = &group.get_file_list_by_type(file_type);
         files.retain_files_if_they_are_not_here(&mut file_names);
     }
+    let hdu = project.config().raw_params.fits_hdu_selector();
     drop(project);
 
     exec_and_show_progress(
         objects,
         move |progress, cancel_flag| {
-            load_src_file_info_for_files(&file_names, cancel_flag, progress)
+            load_src_file_info_for_files(&file_names, hdu.as_ref(), cancel_flag, progress)
         },
         move |objects, result| {
             let mut project = objects.project.borrow_mut();
@@ -1957,11 +1975,14 @@ fn preview_image_file(
     let bin = match project.config().image_size {
         ImageSize::Bin2x2 =>
             if mode == PreviewFileMode::ResultFile {1} else {2},
+        ImageSize::Bin3x3 =>
+            if mode == PreviewFileMode::ResultFile {1} else {3},
         ImageSize::Original =>
             1,
     };
 
     let mut raw_params = project.config().raw_params.clone();
+    let stars_opts = project.config().stars_opts.clone();
 
     objects.preview_tp.spawn(clone!(@strong cancel_flag => move || {
         if cancel_flag.load(Ordering::Relaxed) { return; }
@@ -1989,7 +2010,8 @@ fn preview_image_file(
             flags,
             OpenMode::Preview,
             bin,
-            &raw_params
+            &raw_params,
+            &stars_opts
         );
 
         match light_file {
@@ -2309,20 +2331,41 @@ fn configure_project_options<F: Fn(ProjectConfig) + 'static>(
     let cb_cfa_array = builder.object::<gtk::ComboBoxText>("cb_cfa_array").unwrap();
     let chb_apply_wb = builder.object::<gtk::CheckButton>("chb_apply_wb").unwrap();
     let chb_apply_color = builder.object::<gtk::CheckButton>("chb_apply_color").unwrap();
+    let chb_optimize_dark = builder.object::<gtk::CheckButton>("chb_optimize_dark").unwrap();
 
     let chb_save_calibrated_img = builder.object::<gtk::CheckButton>("chb_save_calibrated_img").unwrap();
     let chb_save_common_star_img = builder.object::<gtk::CheckButton>("chb_save_common_star_img").unwrap();
 
+    let cb_fits_compression = builder.object::<gtk::ComboBoxText>("cb_fits_compression").unwrap();
+    let cb_fits_bitpix = builder.object::<gtk::ComboBoxText>("cb_fits_bitpix").unwrap();
+    let cb_fits_compat = builder.object::<gtk::ComboBoxText>("cb_fits_compat").unwrap();
+
+    let cb_align_mode = builder.object::<gtk::ComboBoxText>("cb_align_mode").unwrap();
+    let e_min_stars_in_light = builder.object::<gtk::Entry>("e_min_stars_in_light").unwrap();
+    let chb_skip_bad_lights = builder.object::<gtk::CheckButton>("chb_skip_bad_lights").unwrap();
+    let chb_field_rotation = builder.object::<gtk::CheckButton>("chb_field_rotation").unwrap();
+    let e_fr_latitude = builder.object::<gtk::Entry>("e_fr_latitude").unwrap();
+    let e_fr_azimuth = builder.object::<gtk::Entry>("e_fr_azimuth").unwrap();
+    let e_fr_altitude = builder.object::<gtk::Entry>("e_fr_altitude").unwrap();
+
+    let e_stars_threshold = builder.object::<gtk::Entry>("e_stars_threshold").unwrap();
+    let e_stars_bg_filter = builder.object::<gtk::Entry>("e_stars_bg_filter").unwrap();
+    let e_stars_deblend = builder.object::<gtk::Entry>("e_stars_deblend").unwrap();
+    let e_stars_max = builder.object::<gtk::Entry>("e_stars_max").unwrap();
+
     project_name.set_text(project_config.name.as_ref().unwrap_or(&String::new()).as_str());
 
     img_size.set_active(Some(match project_config.image_size {
         ImageSize::Original => 0,
         ImageSize::Bin2x2 => 1,
+        ImageSize::Bin3x3 => 2,
     }));
 
     res_img_type.set_active(Some(match project_config.res_img_type {
         ResFileType::Fit => 0,
         ResFileType::Tif => 1,
+        ResFileType::Tif16 => 2,
+        ResFileType::Xisf => 3,
     }));
 
     align_rgb.set_active(project_config.align_rgb);
@@ -2368,11 +2411,62 @@ fn configure_project_options<F: Fn(ProjectConfig) + 'static>(
     chb_apply_wb.set_active(project_config.raw_params.apply_wb);
     chb_apply_color.set_active(project_config.raw_params.apply_color);
     chb_apply_color.set_sensitive(chb_apply_wb.is_active());
+    chb_optimize_dark.set_active(project_config.raw_params.optimize_dark);
 
     chb_apply_wb.connect_active_notify(clone!(@strong chb_apply_color => move |v| {
         chb_apply_color.set_sensitive(v.is_active());
     }));
 
+    cb_fits_compression.set_active(Some(match project_config.fits_compression {
+        FitsCompression::None => 0,
+        FitsCompression::Rice => 1,
+        FitsCompression::Gzip => 2,
+    }));
+
+    cb_fits_bitpix.set_active(Some(match project_config.fits_bitpix {
+        FitsBitPix::Int16   => 0,
+        FitsBitPix::Float32 => 1,
+        FitsBitPix::Float64 => 2,
+        FitsBitPix::UInt8   => 3,
+        FitsBitPix::Int64   => 4,
+    }));
+
+    cb_fits_compat.set_active(Some(match project_config.fits_compat {
+        FitsCompat::Default    => 0,
+        FitsCompat::PixInsight => 1,
+        FitsCompat::Siril      => 2,
+        FitsCompat::Aps        => 3,
+    }));
+
+    cb_align_mode.set_active(Some(match project_config.align_mode {
+        AlignMode::Triangles   => 0,
+        AlignMode::Translation => 1,
+        AlignMode::Wcs         => 2,
+    }));
+    e_min_stars_in_light.set_text(&format!("{}", project_config.min_stars_in_light));
+    chb_skip_bad_lights.set_active(project_config.skip_bad_lights);
+
+    let field_rotation = project_config.field_rotation.clone().unwrap_or_default();
+    chb_field_rotation.set_active(project_config.field_rotation.is_some());
+    e_fr_latitude.set_text(&format!("{:.1}", field_rotation.latitude));
+    e_fr_azimuth.set_text(&format!("{:.1}", field_rotation.azimuth));
+    e_fr_altitude.set_text(&format!("{:.1}", field_rotation.altitude));
+    let fr_entries = [e_fr_latitude.clone(), e_fr_azimuth.clone(), e_fr_altitude.clone()];
+    for entry in &fr_entries {
+        entry.set_sensitive(chb_field_rotation.is_active());
+    }
+    chb_field_rotation.connect_active_notify(move |v| {
+        for entry in &fr_entries {
+            entry.set_sensitive(v.is_active());
+        }
+    });
+
+    let stars_opts = &project_config.stars_opts;
+    e_stars_threshold.set_text(&format!("{:.1}", stars_opts.threshold));
+    e_stars_bg_filter.set_text(&format!("{:.1}", stars_opts.bg_filter_size));
+    e_stars_deblend.set_text(&format!("{:.2}", stars_opts.deblend_level));
+    e_stars_max.set_text(&format!("{}", stars_opts.max_stars));
+
     dialog.set_transient_for(Some(&objects.window));
 
     if cfg!(target_os = "windows") {
@@ -2413,12 +2507,15 @@ fn configure_project_options<F: Fn(ProjectConfig) + 'static>(
             project_config.image_size = match img_size.active() {
                 Some(0) => ImageSize::Original,
                 Some(1) => ImageSize::Bin2x2,
+                Some(2) => ImageSize::Bin3x3,
                 _ => panic!("Wrong img_size.active(): {:?}", img_size.active()),
             };
 
             project_config.res_img_type = match res_img_type.active() {
                 Some(0) => ResFileType::Fit,
                 Some(1) => ResFileType::Tif,
+                Some(2) => ResFileType::Tif16,
+                Some(3) => ResFileType::Xisf,
                 _ => panic!("Wrong res_img_type.active(): {:?}", res_img_type.active()),
             };
 
@@ -2439,6 +2536,58 @@ fn configure_project_options<F: Fn(ProjectConfig) + 'static>(
 
             project_config.raw_params.apply_wb = chb_apply_wb.is_active();
             project_config.raw_params.apply_color = chb_apply_color.is_active();
+            project_config.raw_params.optimize_dark = chb_optimize_dark.is_active();
+
+            project_config.fits_compression = match cb_fits_compression.active() {
+                Some(0) => FitsCompression::None,
+                Some(1) => FitsCompression::Rice,
+                Some(2) => FitsCompression::Gzip,
+                _ => panic!("Wrong cb_fits_compression.active(): {:?}", cb_fits_compression.active()),
+            };
+
+            project_config.fits_bitpix = match cb_fits_bitpix.active() {
+                Some(0) => FitsBitPix::Int16,
+                Some(1) => FitsBitPix::Float32,
+                Some(2) => FitsBitPix::Float64,
+                Some(3) => FitsBitPix::UInt8,
+                Some(4) => FitsBitPix::Int64,
+                _ => panic!("Wrong cb_fits_bitpix.active(): {:?}", cb_fits_bitpix.active()),
+            };
+
+            project_config.fits_compat = match cb_fits_compat.active() {
+                Some(0) => FitsCompat::Default,
+                Some(1) => FitsCompat::PixInsight,
+                Some(2) => FitsCompat::Siril,
+                Some(3) => FitsCompat::Aps,
+                _ => panic!("Wrong cb_fits_compat.active(): {:?}", cb_fits_compat.active()),
+            };
+
+            project_config.align_mode = match cb_align_mode.active() {
+                Some(0) => AlignMode::Triangles,
+                Some(1) => AlignMode::Translation,
+                Some(2) => AlignMode::Wcs,
+                _ => panic!("Wrong cb_align_mode.active(): {:?}", cb_align_mode.active()),
+            };
+            project_config.min_stars_in_light = e_min_stars_in_light
+                .text().as_str().parse()
+                .unwrap_or(project_config.min_stars_in_light);
+            project_config.skip_bad_lights = chb_skip_bad_lights.is_active();
+
+            project_config.field_rotation = if chb_field_rotation.is_active() {
+                let mut params = project_config.field_rotation.clone().unwrap_or_default();
+                params.latitude = e_fr_latitude.text().as_str().parse().unwrap_or(params.latitude);
+                params.azimuth = e_fr_azimuth.text().as_str().parse().unwrap_or(params.azimuth);
+                params.altitude = e_fr_altitude.text().as_str().parse().unwrap_or(params.altitude);
+                Some(params)
+            } else {
+                None
+            };
+
+            let stars_opts = &mut project_config.stars_opts;
+            stars_opts.threshold = e_stars_threshold.text().as_str().parse().unwrap_or(stars_opts.threshold);
+            stars_opts.bg_filter_size = e_stars_bg_filter.text().as_str().parse().unwrap_or(stars_opts.bg_filter_size);
+            stars_opts.deblend_level = e_stars_deblend.text().as_str().parse().unwrap_or(stars_opts.deblend_level);
+            stars_opts.max_stars = e_stars_max.text().as_str().parse().unwrap_or(stars_opts.max_stars);
 
             set_fun(project_config);
         }
@@ -2657,6 +2806,7 @@ fn ask_user_to_assign_ref_light_image_auto(
     let rb_roundest_stars = builder.object::<gtk::RadioButton>("rb_roundest_stars").unwrap();
     let rb_min_bg = builder.object::<gtk::RadioButton>("rb_min_bg").unwrap();
     let rb_min_noise = builder.object::<gtk::RadioButton>("rb_min_noise").unwrap();
+    let rb_best = builder.object::<gtk::RadioButton>("rb_best").unwrap();
 
     dialog.set_transient_for(Some(&objects.window));
     if cfg!(target_os = "windows") {
@@ -2676,6 +2826,7 @@ fn ask_user_to_assign_ref_light_image_auto(
         RefImageAutoMode::RoundestStars => rb_roundest_stars.set_active(true),
         RefImageAutoMode::MinBg         => rb_min_bg.set_active(true),
         RefImageAutoMode::NinNoise      => rb_min_noise.set_active(true),
+        RefImageAutoMode::Best          => rb_best.set_active(true),
     }
 
     dialog.connect_response(clone!(@strong objects => move |dialog, response| {
@@ -2686,7 +2837,8 @@ fn ask_user_to_assign_ref_light_image_auto(
                 if rb_smallest_stars.is_active()      { RefImageAutoMode::SmallestStars }
                 else if rb_roundest_stars.is_active() { RefImageAutoMode::RoundestStars }
                 else if rb_min_bg.is_active()         { RefImageAutoMode::MinBg }
-                else                                  { RefImageAutoMode::NinNoise };
+                else if rb_min_noise.is_active()      { RefImageAutoMode::NinNoise }
+                else                                  { RefImageAutoMode::Best };
 
             project.set_config(config);
             project.assign_ref_light_frame_automatically();
@@ -2751,7 +2903,7 @@ fn action_stack(objects: &Rc<MainWindow>) {
         objects,
         move|progress, is_canceled| {
             let project = Project::from_json_string(&project_json);
-            project.stack_light_files(progress, is_canceled, cpu_load)
+            project.stack_light_files(progress, is_canceled, cpu_load, ResumeMode::Off, &StackMapsOpts::default())
         },
         move |objects, result| {
             preview_image_file(objects, &result.file_name, PreviewFileMode::ResultFile);
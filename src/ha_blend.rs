@@ -0,0 +1,86 @@
+use rayon::prelude::*;
+use crate::{image::*, calc::*};
+
+/* Blending of Ha master into red channel of RGB image (HaRGB) */
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HaBlendMode {
+    Screen,
+    Lighten,
+    Linear, // weighted mix of red and Ha
+}
+
+impl HaBlendMode {
+    pub fn from_str(text: &str) -> anyhow::Result<Self> {
+        match text.to_lowercase().as_str() {
+            "screen"  => Ok(HaBlendMode::Screen),
+            "lighten" => Ok(HaBlendMode::Lighten),
+            "linear"  => Ok(HaBlendMode::Linear),
+            _ => anyhow::bail!("Wrong Ha blend mode {} (screen, lighten or linear)", text),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct HaBlendOpts {
+    pub mode:     HaBlendMode,
+    pub strength: f32, // 0..1
+}
+
+impl Default for HaBlendOpts {
+    fn default() -> Self {
+        Self {
+            mode: HaBlendMode::Screen,
+            strength: 0.5,
+        }
+    }
+}
+
+fn is_valid(v: f32) -> bool {
+    v.is_finite() && v != NO_VALUE_F32
+}
+
+fn background(layer: &ImageLayerF32) -> f32 {
+    let mut values: Vec<_> = layer.iter().copied().filter(|v| is_valid(*v)).collect();
+    median_f32(&mut values).unwrap_or(0.0)
+}
+
+/// Ha must be aligned with RGB image. Background of Ha is
+/// moved to background of red channel before blending
+pub fn blend_ha(image: &mut Image, ha: &ImageLayerF32, opts: &HaBlendOpts) -> anyhow::Result<()> {
+    if !image.is_rgb() {
+        anyhow::bail!("Ha blending needs RGB image");
+    }
+    if ha.width() != image.width() || ha.height() != image.height() {
+        anyhow::bail!(
+            "Size of Ha image {}x{} differs from RGB image {}x{}",
+            ha.width(), ha.height(), image.width(), image.height()
+        );
+    }
+    if !(0.0..=1.0).contains(&opts.strength) {
+        anyhow::bail!("Strength of Ha blending must be in 0..1 range");
+    }
+    blend_into_layer(&mut image.r, ha, opts);
+    Ok(())
+}
+
+/// Blends aligned narrowband layer of same size into `layer`
+/// (Ha or SII into red, OIII into green and blue)
+pub fn blend_into_layer(layer: &mut ImageLayerF32, nb: &ImageLayerF32, opts: &HaBlendOpts) {
+    let bg_shift = background(layer) - background(nb);
+    let mode = opts.mode;
+    let strength = opts.strength;
+    layer.as_slice_mut()
+        .par_iter_mut()
+        .zip(nb.as_slice().par_iter())
+        .for_each(|(v, nb)| {
+            if !is_valid(*v) || !is_valid(*nb) { return; }
+            let nb = nb + bg_shift;
+            let blended = match mode {
+                HaBlendMode::Screen  => 1.0 - (1.0 - *v) * (1.0 - nb),
+                HaBlendMode::Lighten => v.max(nb),
+                HaBlendMode::Linear  => nb,
+            };
+            *v += strength * (blended - *v);
+        });
+}
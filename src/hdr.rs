@@ -0,0 +1,132 @@
+use rayon::prelude::*;
+use crate::image::*;
+
+/* HDR combination of long and short exposure stacks of the same target.
+   Short stack is scaled to long one by linear fit of pixels where both
+   are well exposed. Bright areas of long stack are replaced by scaled
+   short stack with smooth transition by luminance of long stack */
+
+#[derive(Clone, Debug)]
+pub struct HdrOpts {
+    pub fit_low:     f32, // range of long stack values for linear fit
+    pub fit_high:    f32,
+    pub blend_low:   f32, // luminance of long stack where transition starts
+    pub blend_high:  f32, // and where only short stack is used
+    pub mask_smooth: usize, // radius of mask smoothing
+}
+
+impl Default for HdrOpts {
+    fn default() -> Self {
+        Self {
+            fit_low: 0.05,
+            fit_high: 0.7,
+            blend_low: 0.6,
+            blend_high: 0.9,
+            mask_smooth: 3,
+        }
+    }
+}
+
+pub struct HdrChannelFit {
+    pub scale:  f64,
+    pub offset: f64,
+    pub points: usize,
+}
+
+fn is_valid(v: f32) -> bool {
+    v.is_finite() && v != NO_VALUE_F32
+}
+
+// long = scale * short + offset by least squares
+fn linear_fit(long: &ImageLayerF32, short: &ImageLayerF32, opts: &HdrOpts) -> anyhow::Result<HdrChannelFit> {
+    let (mut sx, mut sy, mut sxx, mut sxy, mut n) = (0_f64, 0_f64, 0_f64, 0_f64, 0_usize);
+    for (l, s) in long.iter().zip(short.iter()) {
+        if !is_valid(*l) || !is_valid(*s) { continue; }
+        if *l < opts.fit_low || *l > opts.fit_high { continue; }
+        let (x, y) = (*s as f64, *l as f64);
+        sx += x;
+        sy += y;
+        sxx += x * x;
+        sxy += x * y;
+        n += 1;
+    }
+    let denom = n as f64 * sxx - sx * sx;
+    if n < 100 || denom <= 0.0 {
+        anyhow::bail!("Not enough pixels in overlap range of exposures ({})", n);
+    }
+    let scale = (n as f64 * sxy - sx * sy) / denom;
+    if scale <= 0.0 {
+        anyhow::bail!("Wrong linear fit of exposures (scale = {})", scale);
+    }
+    let offset = (sy - scale * sx) / n as f64;
+    Ok(HdrChannelFit { scale, offset, points: n })
+}
+
+fn box_blur(data: &[f32], width: usize, height: usize, radius: usize) -> Vec<f32> {
+    if radius == 0 { return data.to_vec(); }
+    let r = radius as isize;
+    let mut rows = vec![0_f32; data.len()];
+    rows.par_chunks_mut(width).enumerate().for_each(|(y, row)| {
+        let src = &data[y * width..(y + 1) * width];
+        for (x, v) in row.iter_mut().enumerate() {
+            let from = (x as isize - r).max(0) as usize;
+            let to = (x as isize + r).min(width as isize - 1) as usize;
+            *v = src[from..=to].iter().sum::<f32>() / (to - from + 1) as f32;
+        }
+    });
+    let mut result = vec![0_f32; data.len()];
+    result.par_chunks_mut(width).enumerate().for_each(|(y, row)| {
+        let from = (y as isize - r).max(0) as usize;
+        let to = (y as isize + r).min(height as isize - 1) as usize;
+        for (x, v) in row.iter_mut().enumerate() {
+            *v = (from..=to).map(|yy| rows[yy * width + x]).sum::<f32>() / (to - from + 1) as f32;
+        }
+    });
+    result
+}
+
+/// Both stacks must be aligned. Result is linear and normalized to 0..1 range.
+/// Returns fit of each channel
+pub fn merge_hdr(long: &mut Image, short: &Image, opts: &HdrOpts) -> anyhow::Result<Vec<HdrChannelFit>> {
+    if long.width() != short.width() || long.height() != short.height() || long.is_rgb() != short.is_rgb() {
+        anyhow::bail!("Sizes or color types of stacks are different");
+    }
+    if opts.blend_low >= opts.blend_high || opts.fit_low >= opts.fit_high {
+        anyhow::bail!("Wrong ranges of HDR fit or blending");
+    }
+    let (width, height) = (long.width() as usize, long.height() as usize);
+
+    // mask by luminance of long stack
+    let lum = long.create_greyscale_layer();
+    let mask: Vec<f32> = lum.as_slice().iter()
+        .map(|v| {
+            // overexposed areas are marked by infinity
+            let v = if v.is_infinite() { 1.0 } else if is_valid(*v) { *v } else { 0.0 };
+            let t = ((v - opts.blend_low) / (opts.blend_high - opts.blend_low)).clamp(0.0, 1.0);
+            t * t * (3.0 - 2.0 * t)
+        })
+        .collect();
+    let mask = box_blur(&mask, width, height, opts.mask_smooth);
+
+    let layers = if long.is_rgb() {
+        vec![(&mut long.r, &short.r), (&mut long.g, &short.g), (&mut long.b, &short.b)]
+    } else {
+        vec![(&mut long.l, &short.l)]
+    };
+    let mut fits = Vec::new();
+    for (long, short) in layers {
+        let fit = linear_fit(long, short, opts)?;
+        let (scale, offset) = (fit.scale as f32, fit.offset as f32);
+        long.as_slice_mut().par_iter_mut()
+            .zip(short.as_slice().par_iter())
+            .zip(mask.par_iter())
+            .for_each(|((l, s), m)| {
+                if !is_valid(*s) { return; }
+                let scaled = scale * s + offset;
+                *l = if is_valid(*l) { *l + m * (scaled - *l) } else { scaled };
+            });
+        fits.push(fit);
+    }
+    long.normalize_to_1(true);
+    Ok(fits)
+}
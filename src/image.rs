@@ -1,7 +1,7 @@
 use std::collections::{VecDeque,HashSet};
 use itertools::izip;
 use rayon::prelude::*;
-use crate::calc::*;
+use crate::{calc::*, gpu::*, resample::{ResampleKernel, interpolate_pixel}};
 
 pub const NO_VALUE_F32: f32 = -999.0;
 
@@ -429,6 +429,22 @@ pub fn rotated_and_translated(
     default_value: f32,
     result_width:  Crd,
     result_height: Crd
+) -> ImageLayerF32 {
+    rotated_and_translated_impl(
+        |x, y| source.get_f64_crd(x, y),
+        angle, transl_x, transl_y,
+        default_value, result_width, result_height
+    )
+}
+
+fn rotated_and_translated_impl(
+    get_value:     impl Fn(f64, f64) -> Option<f32>,
+    angle:         f64,
+    transl_x:      f64,
+    transl_y:      f64,
+    default_value: f32,
+    result_width:  Crd,
+    result_height: Crd
 ) -> ImageLayerF32 {
     let mut result = ImageLayerF32::new(result_width, result_height);
     let center_x = (result_width as f64 - 1.0) / 2.0;
@@ -442,7 +458,7 @@ pub fn rotated_and_translated(
         let dy = y - center_y;
         let rot_x = center_x + dx * cos_a - dy * sin_a;
         let rot_y = center_y + dy * cos_a + dx * sin_a;
-        *v = source.get_f64_crd(rot_x, rot_y).unwrap_or(default_value);
+        *v = get_value(rot_x, rot_y).unwrap_or(default_value);
     }
     result
 }
@@ -464,9 +480,36 @@ impl ImageLayer<f32> {
         result_height: Crd
     ) -> ImageLayerF32 {
         if self.is_empty() { return ImageLayerF32::new_empty(); }
+        let gpu_result = gpu_rotated_and_translated(
+            self, angle, transl_x, transl_y,
+            default_value, result_width, result_height
+        );
+        if let Some(result) = gpu_result { return result; }
         rotated_and_translated(self, angle, transl_x, transl_y, default_value, result_width, result_height)
     }
 
+    /// Same as `rotated_and_translated` with interpolation by `kernel`
+    pub fn rotated_and_translated_by(
+        &self,
+        kernel:        ResampleKernel,
+        angle:         f64,
+        transl_x:      f64,
+        transl_y:      f64,
+        default_value: f32,
+        result_width:  Crd,
+        result_height: Crd
+    ) -> ImageLayerF32 {
+        if kernel == ResampleKernel::Bilinear {
+            return self.rotated_and_translated(angle, transl_x, transl_y, default_value, result_width, result_height);
+        }
+        if self.is_empty() { return ImageLayerF32::new_empty(); }
+        rotated_and_translated_impl(
+            |x, y| interpolate_pixel(self, x, y, kernel),
+            angle, transl_x, transl_y,
+            default_value, result_width, result_height
+        )
+    }
+
     pub fn substract(&mut self, other: &ImageLayerF32) {
         assert!(self.width == other.width);
         assert!(self.height == other.height);
@@ -993,7 +1036,30 @@ impl Image {
         }
     }
 
-    pub fn normalize_to_1(&mut self, if_greater_1: bool) {
+    pub fn rotated_and_translated_by(
+        &self,
+        kernel:        ResampleKernel,
+        angle:         f64,
+        transl_x:      f64,
+        transl_y:      f64,
+        default_value: f32,
+        result_width:  Crd,
+        result_height: Crd
+    ) -> Image {
+        let rotate = |layer: &ImageLayerF32| layer.rotated_and_translated_by(
+            kernel, angle, transl_x, transl_y,
+            default_value, result_width, result_height
+        );
+        Image {
+            l: rotate(&self.l),
+            r: rotate(&self.r),
+            g: rotate(&self.g),
+            b: rotate(&self.b),
+        }
+    }
+
+    /// Returns factor values were multiplied by
+    pub fn normalize_to_1(&mut self, if_greater_1: bool) -> f32 {
         let max = self.l
             .iter()
             .chain(self.r.iter())
@@ -1006,7 +1072,7 @@ impl Image {
 
         const MAX: f32 = 0.999;
 
-        if max < MAX && if_greater_1 { return; }
+        if max < MAX && if_greater_1 { return 1.0; }
 
         let do_norm = |k, img: &mut ImageLayerF32| {
             for v in img.iter_mut() {
@@ -1019,6 +1085,7 @@ impl Image {
         do_norm(k, &mut self.r);
         do_norm(k, &mut self.g);
         do_norm(k, &mut self.b);
+        k
     }
 
     pub fn check_contains_inf_or_nan(&self,
@@ -0,0 +1,155 @@
+use crate::{image::*, calc::*, stars::*};
+
+/* Difference of two stacks or frames of the same field for transients
+   hunting (supernovae, asteroids, variable stars). New image is aligned
+   to reference one by stars, its background is subtracted and range is
+   scaled to reference by bright pixels. Connected areas of difference
+   above threshold are reported as residual sources */
+
+#[derive(Clone, Debug)]
+pub struct DiffOpts {
+    pub threshold:  f32,   // detection threshold in noise units of difference
+    pub min_pixels: usize, // smaller areas are noise or hot pixels
+}
+
+impl Default for DiffOpts {
+    fn default() -> Self {
+        Self {
+            threshold:  5.0,
+            min_pixels: 4,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DiffSource {
+    pub x:       f64,
+    pub y:       f64,
+    pub flux:    f32, // positive - brighter on new image, negative - fainter
+    pub peak:    f32,
+    pub pixels:  usize,
+    pub radius:  f32, // radius of circle of same area
+    pub snr:     f32,
+    pub on_star: bool, // residual of star of reference image (variable star or bad subtraction)
+}
+
+pub struct DiffResult {
+    pub image:   ImageLayerF32, // new minus reference in units of reference image
+    pub offset:  ImageOffset,   // of new image relative to reference
+    pub scale:   f32,           // range factor of new image
+    pub noise:   f32,           // of difference
+    pub sources: Vec<DiffSource>,
+}
+
+fn is_valid(v: f32) -> bool {
+    v.is_finite() && v != NO_VALUE_F32
+}
+
+fn background_and_noise(layer: &ImageLayerF32) -> (f32, f32) {
+    let step = (layer.as_slice().len() / 200_000).max(1);
+    let mut values: Vec<f32> = layer.as_slice()
+        .iter()
+        .step_by(step)
+        .copied()
+        .filter(|v| is_valid(*v))
+        .collect();
+    let background = median_f32(&mut values).unwrap_or(0.0);
+    values.iter_mut().for_each(|v| *v = (*v - background).abs());
+    let noise = median_f32(&mut values).unwrap_or(0.0) * 1.4826;
+    (background, noise)
+}
+
+/// Aligns `new` image to `reference` and finds residual sources of difference
+pub fn diff_images(reference: &Image, new: &Image, opts: &DiffOpts) -> anyhow::Result<DiffResult> {
+    if reference.width() != new.width() || reference.height() != new.height() {
+        anyhow::bail!(
+            "Size of images {}x{} and {}x{} differs. Resample or crop them first",
+            reference.width(), reference.height(), new.width(), new.height()
+        );
+    }
+    let (width, height) = (reference.width(), reference.height());
+    let ref_grey = reference.create_greyscale_layer();
+    let new_grey = new.create_greyscale_layer();
+    let stars_opts = StarsFindOpts::default();
+    let ref_stars = find_stars_on_image(&ref_grey, None, false, &stars_opts)?;
+    let new_stars = find_stars_on_image(&new_grey, None, false, &stars_opts)?;
+    let offset = find_image_offset_by_stars(&ref_stars, &new_stars, width as f64, height as f64)
+        .ok_or_else(|| anyhow::anyhow!("Can't align images by stars"))?;
+    let aligned = new_grey.rotated_and_translated(
+        -offset.angle, -offset.offset_x, -offset.offset_y,
+        NO_VALUE_F32, width, height
+    );
+    drop(new_grey);
+
+    // range factor by bright pixels of reference image (median of ratios
+    // is not affected by transients which are small part of bright pixels)
+    let (ref_bg, ref_noise) = background_and_noise(&ref_grey);
+    let (new_bg, _) = background_and_noise(&aligned);
+    let bright = ref_bg + 10.0 * ref_noise;
+    let mut ratios: Vec<f32> = ref_grey.as_slice().iter()
+        .zip(aligned.as_slice())
+        .filter(|(r, n)| is_valid(**r) && is_valid(**n) && **r > bright && **n > new_bg)
+        .map(|(r, n)| (r - ref_bg) / (n - new_bg))
+        .collect();
+    let scale = median_f32(&mut ratios).unwrap_or(1.0);
+
+    let mut image = ImageLayerF32::new(width, height);
+    for ((d, r), n) in image.as_slice_mut().iter_mut().zip(ref_grey.as_slice()).zip(aligned.as_slice()) {
+        *d = if is_valid(*r) && is_valid(*n) {
+            (n - new_bg) * scale - (r - ref_bg)
+        } else {
+            NO_VALUE_F32
+        };
+    }
+    let (_, noise) = background_and_noise(&image);
+    let sources = find_sources(&image, noise, &ref_stars, opts);
+    Ok(DiffResult { image, offset, scale, noise, sources })
+}
+
+fn find_sources(diff: &ImageLayerF32, noise: f32, ref_stars: &Stars, opts: &DiffOpts) -> Vec<DiffSource> {
+    let (width, height) = (diff.width(), diff.height());
+    let border = opts.threshold * noise;
+    let mut taken = vec![false; diff.as_slice().len()];
+    let mut flood_filler = FloodFiller::new();
+    let mut sources = Vec::new();
+    let mut points = Vec::new();
+    for start_y in 0..height {
+        for start_x in 0..width {
+            let start = diff.get(start_x, start_y).unwrap_or(NO_VALUE_F32);
+            if !is_valid(start) || start.abs() <= border { continue; }
+            let sign = start.signum();
+            points.clear();
+            flood_filler.fill(start_x, start_y, |x, y| {
+                let Some(v) = diff.get(x, y) else { return false; };
+                let index = (x + y * width) as usize;
+                if taken[index] || !is_valid(v) || v * sign <= border { return false; }
+                taken[index] = true;
+                points.push((x, y, v));
+                true
+            });
+            if points.len() < opts.min_pixels.max(1) { continue; }
+            let flux: f64 = points.iter().map(|(_, _, v)| *v as f64).sum();
+            let weight: f64 = points.iter().map(|(_, _, v)| v.abs() as f64).sum();
+            let x = points.iter().map(|(x, _, v)| *x as f64 * v.abs() as f64).sum::<f64>() / weight;
+            let y = points.iter().map(|(_, y, v)| *y as f64 * v.abs() as f64).sum::<f64>() / weight;
+            let peak = points.iter().map(|(_, _, v)| *v).fold(0.0, |a: f32, v| if v.abs() > a.abs() { v } else { a });
+            let radius = (points.len() as f32 / std::f32::consts::PI).sqrt();
+            let snr = (flux.abs() / (noise as f64 * (points.len() as f64).sqrt())) as f32;
+            let on_star = ref_stars.iter().any(|star| {
+                let dist = ((star.x - x).powi(2) + (star.y - y).powi(2)).sqrt();
+                dist < (star.radius + radius) as f64 + 1.0
+            });
+            sources.push(DiffSource {
+                x, y,
+                flux: flux as f32,
+                peak,
+                pixels: points.len(),
+                radius,
+                snr,
+                on_star,
+            });
+        }
+    }
+    sources.sort_by(|s1, s2| s2.snr.total_cmp(&s1.snr));
+    sources
+}
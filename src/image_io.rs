@@ -7,7 +7,6 @@ use bitstream_io::{BitWriter, BitWrite, BitReader};
 use chrono::prelude::*;
 use fitsio::{*, images::*, hdu::*};
 use regex::Regex;
-use gettextrs::*;
 use crate::{
     image::*,
     image_raw::*,
@@ -15,20 +14,34 @@ use crate::{
     compression::*,
     progress::*,
     calc::*,
-    cameras_database::*
+    cameras_database::*,
+    xisf::*,
+    safe_read::*,
+    airmass::*,
+    integration_meta::*,
+    wcs::{parse_ra, parse_dec},
+    str_utils::tr,
 };
 
 pub const FIT_EXTS: &[&str] = &["fit", "fits", "fts"];
 pub const TIF_EXTS: &[&str] = &["tif", "tiff"];
+pub const XISF_EXTS: &[&str] = &["xisf"];
 pub const RAW_EXTS: &[&str] = &[
     "dng",
-    "cr2", // Canon
+    "cr2", // Canon (CR3 is not supported by rawloader yet)
+    "crw", // Canon (old models)
     "nef", // Nikon
+    "nrw", // Nikon
     "arw", // Sony
+    "sr2", // Sony
+    "srw", // Samsung
     "pef", // Pentax
+    "orf", // Olympus
+    "rw2", // Panasonic
+    "mrw", // Minolta
 ];
 
-fn try_to_decode_date_time_str(dt_str: &str) -> Option<DateTime<Local>> {
+pub fn try_to_decode_date_time_str(dt_str: &str) -> Option<DateTime<Local>> {
     if dt_str.is_empty() {
         return None;
     }
@@ -57,6 +70,15 @@ fn try_to_decode_date_time_str(dt_str: &str) -> Option<DateTime<Local>> {
 pub fn load_image_from_file(
     file_name:    &Path,
     force_as_raw: bool
+) -> anyhow::Result<ImageData> {
+    load_src_image_from_file(file_name, force_as_raw, None)
+}
+
+/// Loads light or calibration file. `hdu` selects image HDU of FITS file
+pub fn load_src_image_from_file(
+    file_name:    &Path,
+    force_as_raw: bool,
+    hdu:          Option<&FitsHduSelector>,
 ) -> anyhow::Result<ImageData> {
     let ext = extract_extension(file_name);
     if is_raw_ext(ext) {
@@ -68,7 +90,9 @@ pub fn load_image_from_file(
         }
         load_image_from_tiff_file(file_name)
     } else if is_fits_ext(ext) {
-        load_image_from_fits_file(file_name, force_as_raw)
+        load_image_from_fits_file(file_name, force_as_raw, hdu)
+    } else if is_xisf_ext(ext) {
+        load_image_from_xisf_file(file_name, force_as_raw)
     } else {
         err_format_not_supported(ext)
     }
@@ -77,7 +101,8 @@ pub fn load_image_from_file(
 pub fn save_image_to_file(
     image:     &Image,
     info:      &ImageInfo,
-    file_name: &Path
+    file_name: &Path,
+    fits_opts: FitsSaveOpts,
 ) -> anyhow::Result<()> {
     assert!(!image.is_empty());
 
@@ -85,7 +110,9 @@ pub fn save_image_to_file(
     if is_tiff_ext(ext) {
         save_image_to_tiff_file(image, info, file_name)
     } else if is_fits_ext(ext) {
-        save_image_to_fits_file(image, info, file_name)
+        save_image_to_fits_file(image, info, file_name, fits_opts)
+    } else if is_xisf_ext(ext) {
+        save_image_to_xisf_file(image, info, file_name)
     } else {
         err_format_not_supported(ext)
     }
@@ -103,11 +130,16 @@ pub fn is_fits_ext(ext: &str) -> bool {
     FIT_EXTS.iter().any(|e| ext.eq_ignore_ascii_case(e))
 }
 
+pub fn is_xisf_ext(ext: &str) -> bool {
+    XISF_EXTS.iter().any(|e| ext.eq_ignore_ascii_case(e))
+}
+
 pub fn is_source_file_name(file_name: &Path) -> bool {
     let ext = extract_extension(file_name);
     is_raw_ext(ext) |
     is_tiff_ext(ext) |
-    is_fits_ext(ext)
+    is_fits_ext(ext) |
+    is_xisf_ext(ext)
 }
 
 pub enum RawOrImage {
@@ -158,11 +190,48 @@ pub struct ImageInfo {
 
     /// Lens or telescope
     pub lens: Option<String>,
+
+    /// Sensor temperature in celsius
+    pub temperature: Option<f32>,
+
+    /// Filter name (FILTER keyword)
+    #[serde(default)]
+    pub filter: Option<String>,
+
+    /// Type of frame (IMAGETYP keyword: Light Frame, Dark Frame...)
+    #[serde(default)]
+    pub frame_type: Option<String>,
+
+    /// Coordinates of target (RA/DEC or OBJCTRA/OBJCTDEC keywords)
+    #[serde(default)]
+    pub target: Option<EqCoords>,
+
+    /// Observation site (SITELAT/SITELONG keywords)
+    #[serde(default)]
+    pub site: Option<SiteCoords>,
+
+    /// Exposure, frames count and time range of stacked image
+    /// (NCOMBINE, DATE-OBS and DATE-END keywords)
+    #[serde(default)]
+    pub integration: Option<IntegrationMeta>,
+
+    /// Maximum value of integer data of FITS file (65535 for 16-bit data)
+    #[serde(default)]
+    pub data_max: Option<f64>,
+
+    /// Offset of sensor (OFFSET keyword)
+    #[serde(default)]
+    pub offset: Option<i32>,
+
+    /// Binning (XBINNING keyword)
+    #[serde(default)]
+    pub binning: Option<u32>,
 }
 
 
 fn load_src_file_info(
     file_name:    &Path,
+    hdu:          Option<&FitsHduSelector>,
     fn_extractor: &FromFileNameInfoExtractor
 ) -> anyhow::Result<ImageInfo> {
     let ext = extract_extension(file_name);
@@ -171,7 +240,9 @@ fn load_src_file_info(
     } else if is_tiff_ext(ext) {
         load_src_file_info_tiff(file_name)
     } else if is_fits_ext(ext) {
-        load_src_file_info_fits(file_name)
+        load_src_file_info_fits(file_name, hdu)
+    } else if is_xisf_ext(ext) {
+        load_src_file_info_xisf(file_name)
     } else {
         err_format_not_supported(ext)
     }?;
@@ -214,8 +285,10 @@ impl FromFileNameInfoExtractor {
     }
 }
 
+/// `hdu` selects image HDU of FITS files
 pub fn load_src_file_info_for_files(
     file_names:   &Vec<PathBuf>,
+    hdu:          Option<&FitsHduSelector>,
     is_cancelled: &IsCancelledFun,
     progress:     &ProgressTs,
 ) -> anyhow::Result<Vec<ImageInfo>> {
@@ -225,9 +298,31 @@ pub fn load_src_file_info_for_files(
     let fn_extractor = FromFileNameInfoExtractor::new();
     for file_name in file_names {
         if is_cancelled() {
-            anyhow::bail!(gettext("Cancelled"));
+            anyhow::bail!(tr("Cancelled"));
         }
-        let item = load_src_file_info(file_name, &fn_extractor)?;
+        let item = load_src_file_info(file_name, hdu, &fn_extractor)?;
+        progress.lock().unwrap().progress(true, file_name.to_str().unwrap_or(""));
+        result.push(item);
+    }
+    Ok(result)
+}
+
+/// As `load_src_file_info_for_files` but error of one
+/// file doesn't stop reading of other ones
+pub fn try_load_src_file_info_for_files(
+    file_names:   &[PathBuf],
+    hdu:          Option<&FitsHduSelector>,
+    is_cancelled: &IsCancelledFun,
+    progress:     &ProgressTs,
+) -> anyhow::Result<Vec<anyhow::Result<ImageInfo>>> {
+    let mut result = Vec::new();
+    progress.lock().unwrap().set_total(file_names.len());
+    let fn_extractor = FromFileNameInfoExtractor::new();
+    for file_name in file_names {
+        if is_cancelled() {
+            anyhow::bail!(tr("Cancelled"));
+        }
+        let item = load_src_file_info(file_name, hdu, &fn_extractor);
         progress.lock().unwrap().progress(true, file_name.to_str().unwrap_or(""));
         result.push(item);
     }
@@ -286,15 +381,27 @@ pub fn load_src_file_info_raw(file_name: &Path) -> anyhow::Result<ImageInfo> {
         focal_len,
         camera,
         lens,
+        temperature: None,
+        filter: None,
+        frame_type: None,
+        target: None,
+        site: None,
+        integration: None,
+        data_max: None,
+        offset: None,
+        binning: None,
     })
 }
 
-pub fn load_raw_file(file_name: &Path) -> anyhow::Result<(RawImage, ImageInfo)> {
+pub fn load_raw_file(
+    file_name: &Path,
+    hdu:       Option<&FitsHduSelector>,
+) -> anyhow::Result<(RawImage, ImageInfo)> {
     let ext = extract_extension(file_name);
     if is_raw_ext(ext) {
         return RawImage::load(file_name);
-    } else if is_fits_ext(ext) {
-        let result = load_image_from_fits_file(file_name, true)?;
+    } else if is_fits_ext(ext) || is_xisf_ext(ext) {
+        let result = load_src_image_from_file(file_name, true, hdu)?;
         if let ImageData { image: RawOrImage::Raw(raw), info } = result {
             return Ok((raw, info));
         }
@@ -356,6 +463,10 @@ pub fn load_image_from_tiff_file(file_name: &Path) -> anyhow::Result<ImageData>
         is_rgb: bool,
         cvt:    fn (from: S) -> f32
     ) -> anyhow::Result<()> {
+        let values_cnt = (img.width() * img.height()) as usize * if is_rgb { 3 } else { 1 };
+        if src.len() < values_cnt {
+            anyhow::bail!("TIFF image data is too small");
+        }
         if is_rgb {
             for (dr, dg, db, (sr, sg, sb))
             in izip!(img.r.iter_mut(), img.g.iter_mut(), img.b.iter_mut(), src.iter().tuples()) {
@@ -375,6 +486,7 @@ pub fn load_image_from_tiff_file(file_name: &Path) -> anyhow::Result<ImageData>
     let mut decoder = Decoder::new(file)?;
 
     let (width, height) = decoder.dimensions()?;
+    checked_image_size(width as usize, height as usize, 3, 4)?;
 
     let (mut image, is_rgb) = match decoder.colortype()? {
         ColorType::Gray(_) => {
@@ -414,6 +526,14 @@ pub fn load_image_from_tiff_file(file_name: &Path) -> anyhow::Result<ImageData>
                 |v| (v as f64 / u32::MAX as f64) as f32
             ),
 
+        DecodingResult::U64(data) =>
+            assign_img_data(
+                &data,
+                &mut image,
+                is_rgb,
+                |v| (v as f64 / u64::MAX as f64) as f32
+            ),
+
         DecodingResult::F32(data) =>
             assign_img_data(
                 &data,
@@ -492,26 +612,32 @@ pub fn save_image_to_tiff_file(
 }
 
 pub fn save_image_to_tiff16_file(
-    image:     &Image,
-    info:      &ImageInfo,
-    file_name: &Path
+    image:        &Image,
+    info:         &ImageInfo,
+    file_name:    &Path,
+    quantization: Quantization,
 ) -> anyhow::Result<()> {
     assert!(!image.is_empty());
 
+    let to_u16 = |i: usize, v: f32| -> u16 {
+        quantization.quantize(v, u16::MAX as f64, i as u64) as u16
+    };
+
     let mut file = BufWriter::new(File::create(file_name)?);
     let mut decoder = TiffEncoder::new(&mut file)?;
     if image.is_greyscale() {
-        let mut tiff = decoder.new_image::<colortype::Gray32Float>(
+        let data: Vec<_> = image.l.iter().enumerate().map(|(i, v)| to_u16(i, *v)).collect();
+        let mut tiff = decoder.new_image::<colortype::Gray16>(
             image.width() as u32,
             image.height() as u32
         )?;
         write_info_into_tiff(tiff.encoder(), info)?;
-        tiff.write_data(image.l.iter().as_slice())?;
+        tiff.write_data(&data)?;
     }
     else if image.is_rgb() {
         let data: Vec<_> = izip!(image.r.iter(), image.g.iter(), image.b.iter())
-            .map(|(r, g, b)| [(*r * 65535.0) as u16, (*g * 65535.0) as u16, (*b * 65535.0) as u16])
-            .flatten()
+            .enumerate()
+            .flat_map(|(i, (r, g, b))| [to_u16(3*i, *r), to_u16(3*i+1, *g), to_u16(3*i+2, *b)])
             .collect();
         let mut tiff = decoder.new_image::<colortype::RGB16>(
             image.width() as u32,
@@ -551,22 +677,376 @@ fn write_info_into_tiff<W: Write + Seek, K: TiffKind>(
 
 // FITS format
 
-fn find_image_hdu(
-    file: &mut FitsFile
-) -> anyhow::Result<(FitsHdu, usize, usize, bool, ImageType)> {
-    for hdu in file.iter() {
-        if let HduInfo::ImageInfo { shape, image_type } = &hdu.info {
-            let image_type = *image_type;
-            match shape.as_slice() {
-                &[height, width] =>
-                    return Ok((hdu, width, height, false, image_type)),
-                &[3, height, width] =>
-                    return Ok((hdu, width, height, true, image_type)),
-                _ => {},
+/// HDU of multi-extension FITS files
+#[derive(Clone, Debug, PartialEq)]
+pub enum FitsHduSelector {
+    Index(usize), // 0 is primary HDU
+    Name(String), // EXTNAME
+}
+
+impl FitsHduSelector {
+    /// Empty text means that first image HDU is used
+    pub fn from_str(text: &str) -> Option<Self> {
+        let text = text.trim();
+        if text.is_empty() {
+            None
+        } else if let Ok(index) = text.parse() {
+            Some(FitsHduSelector::Index(index))
+        } else {
+            Some(FitsHduSelector::Name(text.to_string()))
+        }
+    }
+}
+
+/// Region of FITS image. IRAF notation `[x1:x2,y1:y2]` is 1-based
+/// and inclusive, here coordinates are 0-based and inclusive
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FitsSection {
+    pub x1: usize,
+    pub x2: usize,
+    pub y1: usize,
+    pub y2: usize,
+}
+
+impl FitsSection {
+    pub fn from_str(text: &str) -> anyhow::Result<Self> {
+        let err = || anyhow::anyhow!("Wrong FITS section {} ([x1:x2,y1:y2] expected)", text);
+        let text = text.trim().trim_start_matches('[').trim_end_matches(']');
+        let (x_range, y_range) = text.split_once(',').ok_or_else(err)?;
+        let parse_range = |range: &str| -> anyhow::Result<(usize, usize)> {
+            let (v1, v2) = range.split_once(':').ok_or_else(err)?;
+            let v1: usize = v1.trim().parse().map_err(|_| err())?;
+            let v2: usize = v2.trim().parse().map_err(|_| err())?;
+            if v1 == 0 || v2 == 0 { return Err(err()); }
+            Ok((v1.min(v2) - 1, v1.max(v2) - 1))
+        };
+        let (x1, x2) = parse_range(x_range)?;
+        let (y1, y2) = parse_range(y_range)?;
+        Ok(Self { x1, x2, y1, y2 })
+    }
+
+    pub fn width(&self) -> usize { self.x2 - self.x1 + 1 }
+    pub fn height(&self) -> usize { self.y2 - self.y1 + 1 }
+
+    fn check_inside(&self, width: usize, height: usize) -> anyhow::Result<()> {
+        if self.x2 >= width || self.y2 >= height {
+            anyhow::bail!("FITS section {:?} is out of image {}x{}", self, width, height);
+        }
+        Ok(())
+    }
+}
+
+/// Overscan (BIASSEC) and useful area (TRIMSEC) of CCD image. Values
+/// are used instead of keywords of FITS header if defined
+#[derive(Clone, Debug, Default)]
+pub struct FitsOverscan {
+    pub biassec: Option<FitsSection>,
+    pub trimsec: Option<FitsSection>,
+}
+
+static FITS_OVERSCAN: std::sync::Mutex<Option<FitsOverscan>> = std::sync::Mutex::new(None);
+
+pub fn set_fits_overscan(overscan: FitsOverscan) {
+    *FITS_OVERSCAN.lock().unwrap() = Some(overscan);
+}
+
+fn fits_overscan_regions(
+    fptr:   &mut FitsFile,
+    hdu:    &FitsHdu,
+    width:  usize,
+    height: usize
+) -> anyhow::Result<FitsOverscan> {
+    let overrides = FITS_OVERSCAN.lock().unwrap().clone().unwrap_or_default();
+    let mut read_section = |key: &str| -> anyhow::Result<Option<FitsSection>> {
+        match hdu.read_key::<String>(fptr, key) {
+            Ok(text) => Ok(Some(FitsSection::from_str(&text)?)),
+            Err(_) => Ok(None),
+        }
+    };
+    let biassec = match overrides.biassec {
+        Some(section) => Some(section),
+        None => read_section("BIASSEC")?,
+    };
+    let trimsec = match overrides.trimsec {
+        Some(section) => Some(section),
+        None => read_section("TRIMSEC")?,
+    };
+    for section in [&biassec, &trimsec].into_iter().flatten() {
+        section.check_inside(width, height)?;
+    }
+    Ok(FitsOverscan { biassec, trimsec })
+}
+
+/// Subtracts bias level measured in overscan region (median of each row of
+/// region for vertical strip or of each column for horizontal one, smoothed
+/// along the strip) and crops image to TRIMSEC. Returns new size
+pub fn correct_overscan(
+    data:     &mut Vec<f32>,
+    width:    usize,
+    height:   usize,
+    overscan: &FitsOverscan
+) -> (usize, usize) {
+    const SMOOTH_RADIUS: usize = 8;
+    if let Some(bias) = &overscan.biassec {
+        let by_rows = bias.height() >= bias.width();
+        let medians: Vec<f32> = if by_rows {
+            (bias.y1..=bias.y2).map(|y| {
+                let mut values = data[y * width + bias.x1..=y * width + bias.x2].to_vec();
+                median_f32(&mut values).unwrap_or(0.0)
+            }).collect()
+        } else {
+            (bias.x1..=bias.x2).map(|x| {
+                let mut values: Vec<f32> = (bias.y1..=bias.y2).map(|y| data[y * width + x]).collect();
+                median_f32(&mut values).unwrap_or(0.0)
+            }).collect()
+        };
+        let levels: Vec<f32> = (0..medians.len()).map(|i| {
+            let window = &medians[i.saturating_sub(SMOOTH_RADIUS)..(i + SMOOTH_RADIUS + 1).min(medians.len())];
+            window.iter().sum::<f32>() / window.len() as f32
+        }).collect();
+        let first = if by_rows { bias.y1 } else { bias.x1 };
+        let level_at = |pos: usize| levels[pos.clamp(first, first + levels.len() - 1) - first];
+        for (y, row) in data.chunks_exact_mut(width).enumerate() {
+            for (x, v) in row.iter_mut().enumerate() {
+                *v -= level_at(if by_rows { y } else { x });
             }
         }
     }
-    anyhow::bail!("Supported image HDU not found in FITS file")
+    if let Some(trim) = &overscan.trimsec {
+        let mut result = Vec::with_capacity(trim.width() * trim.height());
+        for y in trim.y1..=trim.y2 {
+            result.extend_from_slice(&data[y * width + trim.x1..=y * width + trim.x2]);
+        }
+        *data = result;
+        return (trim.width(), trim.height());
+    }
+    (width, height)
+}
+
+/// Order of axes of FITS image
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum FitsLayout {
+    Mono,
+    ChannelFirst, // NAXIS3 = 3, RGB planes one after another
+    ChannelLast,  // NAXIS1 = 3, interleaved RGB values
+}
+
+fn image_hdu_params(hdu: &FitsHdu) -> Option<(usize, usize, FitsLayout, ImageType)> {
+    if let HduInfo::ImageInfo { shape, image_type } = &hdu.info {
+        // shape is in reverse order of NAXISn
+        let (width, height, layout) = match shape.as_slice() {
+            &[height, width] | &[1, height, width] => (width, height, FitsLayout::Mono),
+            &[3, height, width] => (width, height, FitsLayout::ChannelFirst),
+            &[height, width, 3] => (width, height, FitsLayout::ChannelLast),
+            _ => return None,
+        };
+        let channels = if layout == FitsLayout::Mono { 1 } else { 3 };
+        if checked_image_size(width, height, channels, 8).is_err() {
+            return None;
+        }
+        return Some((width, height, layout, *image_type));
+    }
+    None
+}
+
+/// Rows are stored from bottom to top (ROWORDER = 'BOTTOM-UP')
+fn fits_is_bottom_up(fptr: &mut FitsFile, hdu: &FitsHdu) -> bool {
+    hdu.read_key::<String>(fptr, "ROWORDER")
+        .map(|v| v.trim().eq_ignore_ascii_case("BOTTOM-UP"))
+        .unwrap_or(false)
+}
+
+fn flip_rows(data: &mut [f32], width: usize) {
+    let height = data.len() / width;
+    for y in 0..height / 2 {
+        let (top, bottom) = data.split_at_mut((height - 1 - y) * width);
+        top[y * width..(y + 1) * width].swap_with_slice(&mut bottom[..width]);
+    }
+}
+
+static FITS_PARALLEL_DECODING: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(true);
+
+/// Enables or disables decoding of uncompressed FITS data in several threads
+pub fn set_fits_parallel_decoding(value: bool) {
+    FITS_PARALLEL_DECODING.store(value, std::sync::atomic::Ordering::Relaxed);
+}
+
+/// Reads `count` values of image starting from `first` value. Data of
+/// uncompressed HDU is read directly from file and converted (byte order,
+/// BSCALE and BZERO) in parallel chunks. CFITSIO does this conversion
+/// in one thread and it is bottleneck for big frames
+fn read_fits_image_values(
+    fptr:      &mut FitsFile,
+    hdu:       &FitsHdu,
+    file_name: &Path,
+    first:     usize,
+    count:     usize,
+) -> anyhow::Result<Vec<f32>> {
+    if FITS_PARALLEL_DECODING.load(std::sync::atomic::Ordering::Relaxed) {
+        if let Some(result) = read_fits_values_parallel(fptr, hdu, file_name, first, count)? {
+            return Ok(result);
+        }
+    }
+    Ok(hdu.read_section(fptr, first, first + count)?)
+}
+
+/// Position and encoding of data of uncompressed image HDU in file
+#[derive(Clone, Copy)]
+struct FitsDataLocation {
+    bitpix:     i64,
+    bzero:      f64,
+    bscale:     f64,
+    data_start: u64,
+    data_end:   u64,
+}
+
+impl FitsDataLocation {
+    /// None for tile-compressed images
+    fn new(fptr: &mut FitsFile, hdu: &FitsHdu) -> Option<Self> {
+        // tile-compressed images are stored in binary tables
+        if hdu.read_key::<String>(fptr, "ZIMAGE").is_ok() {
+            return None;
+        }
+        let bitpix = hdu.read_key::<i64>(fptr, "BITPIX").ok()?;
+        if !matches!(bitpix, 8|16|32|64|-32|-64) {
+            return None;
+        }
+        let bzero = hdu.read_key::<f64>(fptr, "BZERO").unwrap_or(0.0);
+        let bscale = hdu.read_key::<f64>(fptr, "BSCALE").unwrap_or(1.0);
+
+        // reading of key above makes HDU current
+        let (mut head_start, mut data_start, mut data_end, mut status) = (0_i64, 0_i64, 0_i64, 0);
+        unsafe {
+            fitsio::sys::ffghadll(fptr.as_raw(), &mut head_start, &mut data_start, &mut data_end, &mut status);
+        }
+        if status != 0 {
+            return None;
+        }
+        Some(Self { bitpix, bzero, bscale, data_start: data_start as u64, data_end: data_end as u64 })
+    }
+
+    fn item_size(&self) -> usize {
+        (self.bitpix.unsigned_abs() / 8) as usize
+    }
+
+    /// Converts big endian values of `src` with BSCALE and BZERO
+    fn decode(&self, src: &[u8], dst: &mut [f32]) {
+        use byteorder::{BigEndian, ByteOrder};
+        let scale = |v: f64| (self.bscale * v + self.bzero) as f32;
+        let src_iter = src.chunks_exact(self.item_size());
+        match self.bitpix {
+            8   => dst.iter_mut().zip(src_iter).for_each(|(d, s)| *d = scale(s[0] as f64)),
+            16  => dst.iter_mut().zip(src_iter).for_each(|(d, s)| *d = scale(BigEndian::read_i16(s) as f64)),
+            32  => dst.iter_mut().zip(src_iter).for_each(|(d, s)| *d = scale(BigEndian::read_i32(s) as f64)),
+            64  => dst.iter_mut().zip(src_iter).for_each(|(d, s)| *d = scale(BigEndian::read_i64(s) as f64)),
+            -32 => dst.iter_mut().zip(src_iter).for_each(|(d, s)| *d = scale(BigEndian::read_f32(s) as f64)),
+            _   => dst.iter_mut().zip(src_iter).for_each(|(d, s)| *d = scale(BigEndian::read_f64(s))),
+        }
+    }
+}
+
+fn read_fits_values_parallel(
+    fptr:      &mut FitsFile,
+    hdu:       &FitsHdu,
+    file_name: &Path,
+    first:     usize,
+    count:     usize,
+) -> anyhow::Result<Option<Vec<f32>>> {
+    use rayon::prelude::*;
+
+    let Some(location) = FitsDataLocation::new(fptr, hdu) else {
+        return Ok(None);
+    };
+    let item_size = location.item_size();
+    let offset = location.data_start + (first * item_size) as u64;
+    if offset + (count * item_size) as u64 > location.data_end {
+        anyhow::bail!("FITS data is shorter than image size");
+    }
+    let mut file = File::open(file_name)?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut bytes = vec![0_u8; count * item_size];
+    file.read_exact(&mut bytes)?;
+
+    let mut result = vec![0_f32; count];
+    const CHUNK: usize = 64 * 1024;
+    result.par_chunks_mut(CHUNK)
+        .zip(bytes.par_chunks(CHUNK * item_size))
+        .for_each(|(dst, src)| location.decode(src, dst));
+    Ok(Some(result))
+}
+
+/// Description of HDU for error messages (`#1 "SCI" image 4096x4096`)
+fn describe_fits_hdu(file: &mut FitsFile, index: usize, hdu: &FitsHdu) -> String {
+    let mut result = format!("#{}", index);
+    if let Ok(name) = hdu.read_key::<String>(file, "EXTNAME") {
+        result.push_str(&format!(" {:?}", name.trim()));
+    }
+    match &hdu.info {
+        HduInfo::ImageInfo { shape, .. } if shape.is_empty() =>
+            result.push_str(" without data"),
+        HduInfo::ImageInfo { shape, .. } => {
+            let size: Vec<_> = shape.iter().rev().map(|v| v.to_string()).collect();
+            result.push_str(&format!(" image {}", size.join("x")));
+        }
+        _ =>
+            result.push_str(" table"),
+    }
+    result
+}
+
+fn describe_fits_hdus(file: &mut FitsFile, hdus: &[FitsHdu]) -> String {
+    hdus.iter()
+        .enumerate()
+        .map(|(index, hdu)| describe_fits_hdu(file, index, hdu))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// HDU selected by `selector` (`--hdu` for light and calibration files)
+/// or primary HDU if it has supported image or first supported image extension
+fn find_image_hdu(
+    file:     &mut FitsFile,
+    selector: Option<&FitsHduSelector>,
+) -> anyhow::Result<(FitsHdu, usize, usize, FitsLayout, ImageType)> {
+    let hdus: Vec<FitsHdu> = file.iter().collect();
+    if let Some(selector) = selector {
+        let (hdu, descr) = match selector {
+            FitsHduSelector::Index(index) => (file.hdu(*index), format!("#{}", index)),
+            FitsHduSelector::Name(name) => (file.hdu(name.as_str()), format!("{:?}", name)),
+        };
+        let Ok(hdu) = hdu else {
+            anyhow::bail!(
+                "HDU {} not found in FITS file. HDUs of file: {}",
+                descr, describe_fits_hdus(file, &hdus)
+            );
+        };
+        let Some((width, height, layout, image_type)) = image_hdu_params(&hdu) else {
+            anyhow::bail!(
+                "HDU {} of FITS file is not supported image. HDUs of file: {}",
+                descr, describe_fits_hdus(file, &hdus)
+            );
+        };
+        return Ok((hdu, width, height, layout, image_type));
+    }
+
+    let candidates: Vec<_> = hdus.iter()
+        .enumerate()
+        .filter_map(|(index, hdu)| Some((index, image_hdu_params(hdu)?)))
+        .collect();
+    let Some(&(index, (width, height, layout, image_type))) = candidates.first() else {
+        anyhow::bail!(
+            "Supported image HDU not found in FITS file. HDUs of file: {}",
+            describe_fits_hdus(file, &hdus)
+        );
+    };
+    if candidates.len() > 1 {
+        log::debug!(
+            "FITS file has {} image HDUs, HDU #{} is used (select other with --hdu)",
+            candidates.len(), index
+        );
+    }
+    let hdu = hdus.into_iter().nth(index).unwrap();
+    Ok((hdu, width, height, layout, image_type))
 }
 
 fn load_src_file_info_from_fits_hdu(
@@ -584,11 +1064,39 @@ fn load_src_file_info_from_fits_hdu(
     let focal_len = hdu.read_key(fptr, "FOCALLEN").ok();
     let focal_ratio = hdu.read_key(fptr, "FOCRATIO").ok();
     let lens = hdu.read_key(fptr, "TELESCOP").ok();
+    let temperature = hdu.read_key(fptr, "CCD-TEMP").ok();
+    let offset = hdu.read_key::<i64>(fptr, "OFFSET").ok().map(|v| v as i32);
+    let binning = hdu.read_key::<i64>(fptr, "XBINNING").ok().filter(|v| *v > 0).map(|v| v as u32);
+    let filter = hdu.read_key::<String>(fptr, "FILTER").ok()
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty());
+    let frame_type = hdu.read_key::<String>(fptr, "IMAGETYP")
+        .or_else(|_| hdu.read_key::<String>(fptr, "FRAME")).ok();
+    let ra = hdu.read_key::<f64>(fptr, "RA").ok()
+        .or_else(|| hdu.read_key::<String>(fptr, "OBJCTRA").ok().and_then(|v| parse_ra(&v).ok()));
+    let dec = hdu.read_key::<f64>(fptr, "DEC").ok()
+        .or_else(|| hdu.read_key::<String>(fptr, "OBJCTDEC").ok().and_then(|v| parse_dec(&v).ok()));
+    let site_lat = hdu.read_key::<f64>(fptr, "SITELAT").ok()
+        .or_else(|| hdu.read_key::<String>(fptr, "SITELAT").ok().and_then(|v| parse_site_angle(&v)));
+    let site_lon = hdu.read_key::<f64>(fptr, "SITELONG").ok()
+        .or_else(|| hdu.read_key::<String>(fptr, "SITELONG").ok().and_then(|v| parse_site_angle(&v)));
 
     let file_time = hdu.read_key::<String>(fptr, "DATE-LOC")
         .or_else(|_| hdu.read_key::<String>(fptr, "DATE-OBS")).ok()
         .and_then(|v| try_to_decode_date_time_str(&v))
         .or_else(|| get_file_time(file_name).ok() );
+    let combined = hdu.read_key::<i64>(fptr, "NCOMBINE").ok().filter(|v| *v > 0);
+    let date_obs = hdu.read_key::<String>(fptr, "DATE-OBS").ok()
+        .and_then(|v| try_to_decode_date_time_str(&v));
+    let date_end = hdu.read_key::<String>(fptr, "DATE-END").ok()
+        .and_then(|v| try_to_decode_date_time_str(&v));
+    let integration = combined.map(|count| IntegrationMeta {
+        exp_time: exp_time.unwrap_or(0.0),
+        count: count as usize,
+        date_obs,
+        date_end,
+        files: Vec::new(),
+    });
 
     ImageInfo {
         file_name: file_name.to_path_buf(),
@@ -602,6 +1110,14 @@ fn load_src_file_info_from_fits_hdu(
         focal_len,
         camera,
         lens,
+        temperature,
+        filter,
+        frame_type,
+        target: ra.zip(dec).map(|(ra, dec)| EqCoords { ra, dec }),
+        site: site_lat.zip(site_lon).map(|(lat, lon)| SiteCoords { lat, lon }),
+        integration,
+        offset,
+        binning,
         .. Default::default()
     }
 }
@@ -660,12 +1176,20 @@ where F: Fn(&Path) -> anyhow::Result<FitsFile> {
     fun(file_name)
 }
 
-pub fn load_src_file_info_fits(file_name: &Path) -> anyhow::Result<ImageInfo> {
+pub fn load_src_file_info_fits(
+    file_name: &Path,
+    hdu:       Option<&FitsHduSelector>,
+) -> anyhow::Result<ImageInfo> {
     let mut fptr = fits_file_open_helper(
         file_name,
         |file_name| Ok(FitsFile::open(file_name)?)
     )?;
-    let (image_hdu, width, height, ..) = find_image_hdu(&mut fptr)?;
+    let (image_hdu, mut width, mut height, layout, _) = find_image_hdu(&mut fptr, hdu)?;
+    if layout == FitsLayout::Mono {
+        if let Some(trim) = fits_overscan_regions(&mut fptr, &image_hdu, width, height)?.trimsec {
+            (width, height) = (trim.width(), trim.height());
+        }
+    }
     Ok(load_src_file_info_from_fits_hdu(
         &mut fptr,
         &image_hdu,
@@ -675,31 +1199,55 @@ pub fn load_src_file_info_fits(file_name: &Path) -> anyhow::Result<ImageInfo> {
     ))
 }
 
+/// `hdu` selects image HDU (see `find_image_hdu`)
 pub fn load_image_from_fits_file(
     file_name:    &Path,
-    force_as_raw: bool
+    force_as_raw: bool,
+    hdu:          Option<&FitsHduSelector>,
 ) -> anyhow::Result<ImageData> {
     let mut fptr = fits_file_open_helper(
         file_name,
         |file_name| Ok(FitsFile::open(file_name)?)
     )?;
 
-    let (image_hdu, width, height, is_color_image, data_type) = find_image_hdu(&mut fptr)?;
-    let info = load_src_file_info_from_fits_hdu(&mut fptr, &image_hdu, file_name, width, height);
+    let (image_hdu, width, height, layout, data_type) = find_image_hdu(&mut fptr, hdu)?;
+    let is_color_image = layout != FitsLayout::Mono;
+    let bottom_up = fits_is_bottom_up(&mut fptr, &image_hdu);
+
+    // overscan correction and trimming of CCD image
+    let mut mono_data = None;
+    let (width, height) = if !is_color_image {
+        let overscan = fits_overscan_regions(&mut fptr, &image_hdu, width, height)?;
+        let mut data = read_fits_image_values(&mut fptr, &image_hdu, file_name, 0, width * height)?;
+        let (width, height) = correct_overscan(&mut data, width, height, &overscan);
+        if bottom_up {
+            flip_rows(&mut data, width);
+        }
+        mono_data = Some(data);
+        (width, height)
+    } else {
+        (width, height)
+    };
+
+    let mut info = load_src_file_info_from_fits_hdu(&mut fptr, &image_hdu, file_name, width, height);
+    if bottom_up && height % 2 == 0 {
+        // BAYERPAT describes rows in order of file
+        info.cfa_type = info.cfa_type.map(|ct| ct.flipped_vertically());
+    }
     let camera_params = find_camera_params(info.camera.as_deref());
 
+    let max = fits_data_max_value(&mut fptr, &image_hdu, data_type);
+    info.data_max = max;
+
     if !is_color_image && (info.cfa_type.is_some() || camera_params.is_some() || force_as_raw) {
-        let max = match data_type {
-            ImageType::UnsignedByte => 127.0,
-            ImageType::Byte  =>  255.0,
-            ImageType::Short  => 32767.0,
-            ImageType::UnsignedShort  => 65535.0,
-            ImageType::Long  => ((1u64 << 31) - 1) as f64,
-            ImageType::UnsignedLong  => ((1u64 << 32) - 1) as f64,
-            ImageType::LongLong  => ((1u64 << 63) - 1) as f64,
-            ImageType::Float  => 1.0,
-            ImageType::Double  => 1.0,
-        };
+        let max = max.unwrap_or_else(|| {
+            if matches!(data_type, ImageType::LongLong) {
+                // 64-bit integer data without DATAMAX
+                mono_data.iter().flatten().copied().max_by(cmp_f32).unwrap_or(1.0).max(1.0) as f64
+            } else {
+                1.0
+            }
+        });
 
         let ct = info.cfa_type.or_else(|| camera_params.map(|(_, ct, _)| ct).flatten());
         let black = image_hdu.read_key(&mut fptr, "BLKLEVEL").unwrap_or(0.0);
@@ -717,7 +1265,7 @@ pub fn load_image_from_fits_file(
             iso: info.iso,
         };
 
-        let data: Vec<f32> = image_hdu.read_image(&mut fptr)?;
+        let data = mono_data.take().unwrap_or_default();
         let image = ImageLayerF32::new_from_vec(width as Crd, height as Crd, data);
 
         let raw = RawImage {
@@ -737,28 +1285,45 @@ pub fn load_image_from_fits_file(
     let mut image = Image::new();
 
     if is_color_image {
-        let r_data = image_hdu.read_section(&mut fptr, 0, width * height)?;
-        let g_data = image_hdu.read_section(&mut fptr, width * height, 2 * width * height)?;
-        let b_data = image_hdu.read_section(&mut fptr, 2 * width * height, 3 * width * height)?;
+        let (mut r_data, mut g_data, mut b_data) = if layout == FitsLayout::ChannelFirst {
+            (
+                read_fits_image_values(&mut fptr, &image_hdu, file_name, 0, width * height)?,
+                read_fits_image_values(&mut fptr, &image_hdu, file_name, width * height, width * height)?,
+                read_fits_image_values(&mut fptr, &image_hdu, file_name, 2 * width * height, width * height)?,
+            )
+        } else {
+            let data = read_fits_image_values(&mut fptr, &image_hdu, file_name, 0, 3 * width * height)?;
+            let channel = |i: usize| data.iter().skip(i).step_by(3).copied().collect::<Vec<_>>();
+            (channel(0), channel(1), channel(2))
+        };
+        if bottom_up {
+            for data in [&mut r_data, &mut g_data, &mut b_data] {
+                flip_rows(data, width);
+            }
+        }
 
         image.r = ImageLayerF32::new_from_vec(width as Crd, height as Crd, r_data);
         image.g = ImageLayerF32::new_from_vec(width as Crd, height as Crd, g_data);
         image.b = ImageLayerF32::new_from_vec(width as Crd, height as Crd, b_data);
     } else {
-        let data: Vec<f32> = image_hdu.read_image(&mut fptr)?;
+        let data = mono_data.take().unwrap_or_default();
         image.l = ImageLayerF32::new_from_vec(width as Crd, height as Crd, data);
     }
 
-    let max = image.l.iter()
-        .chain(image.r.iter())
-        .chain(image.g.iter())
-        .chain(image.b.iter())
-        .copied()
-        .max_by(cmp_f32)
-        .unwrap_or(0.0);
-
-    if max > 1.0 {
-        image.mult_f32(1.0 / max);
+    if let Some(max) = max {
+        image.mult_f32(1.0 / max as f32);
+    } else {
+        let max = image.l.iter()
+            .chain(image.r.iter())
+            .chain(image.g.iter())
+            .chain(image.b.iter())
+            .copied()
+            .max_by(cmp_f32)
+            .unwrap_or(0.0);
+
+        if max > 1.0 {
+            image.mult_f32(1.0 / max);
+        }
     }
 
     Ok(ImageData{
@@ -767,10 +1332,610 @@ pub fn load_image_from_fits_file(
     })
 }
 
+/// Memory-mapped uncompressed FITS image. Channels are converted
+/// into float values one by one on demand so large images are not
+/// kept in memory as whole
+pub struct MappedFitsImage {
+    mmap:      memmap2::Mmap,
+    location:  FitsDataLocation,
+    layout:    FitsLayout,
+    bottom_up: bool,
+    norm_k:    f32, // multiplier to 0..1 range as for load_image_from_fits_file
+    pub info:  ImageInfo,
+}
+
+impl MappedFitsImage {
+    /// None if image can't be mapped (compressed data, CFA image, overscan
+    /// regions). Such images must be loaded by `load_image_from_fits_file`
+    pub fn open(file_name: &Path) -> anyhow::Result<Option<Self>> {
+        let mut fptr = fits_file_open_helper(
+            file_name,
+            |file_name| Ok(FitsFile::open(file_name)?)
+        )?;
+        let (image_hdu, width, height, layout, data_type) = find_image_hdu(&mut fptr, None)?;
+        let Some(location) = FitsDataLocation::new(&mut fptr, &image_hdu) else {
+            return Ok(None);
+        };
+        let channels = if layout == FitsLayout::Mono { 1 } else { 3 };
+        if location.data_start + (width * height * channels * location.item_size()) as u64 > location.data_end {
+            anyhow::bail!("FITS data is shorter than image size");
+        }
+        if layout == FitsLayout::Mono {
+            let overscan = fits_overscan_regions(&mut fptr, &image_hdu, width, height)?;
+            if overscan.biassec.is_some() || overscan.trimsec.is_some() {
+                return Ok(None);
+            }
+        }
+        let mut info = load_src_file_info_from_fits_hdu(&mut fptr, &image_hdu, file_name, width, height);
+        if layout == FitsLayout::Mono
+        && (info.cfa_type.is_some() || find_camera_params(info.camera.as_deref()).is_some()) {
+            return Ok(None);
+        }
+        let bottom_up = fits_is_bottom_up(&mut fptr, &image_hdu);
+        let max = fits_data_max_value(&mut fptr, &image_hdu, data_type);
+        info.data_max = max;
+        drop(fptr);
+
+        let file = File::open(file_name)?;
+        // Safety: file must not be changed by other processes while it is mapped
+        let mmap = unsafe { memmap2::Mmap::map(&file)? };
+        let mut result = Self { mmap, location, layout, bottom_up, norm_k: 1.0, info };
+        result.norm_k = match max {
+            Some(max) => 1.0 / max as f32,
+            None => {
+                let max = (0..channels)
+                    .map(|channel| result.channel_max(channel))
+                    .max_by(cmp_f32)
+                    .unwrap_or(0.0);
+                if max > 1.0 { 1.0 / max } else { 1.0 }
+            }
+        };
+        Ok(Some(result))
+    }
+
+    pub fn channels_count(&self) -> usize {
+        if self.layout == FitsLayout::Mono { 1 } else { 3 }
+    }
+
+    // Values of row as they are in file (without normalization)
+    fn read_row(&self, channel: usize, y: usize, buffer: &mut Vec<u8>, dst: &mut [f32]) {
+        let width = self.info.width;
+        let item_size = self.location.item_size();
+        let file_y = if self.bottom_up { self.info.height - 1 - y } else { y };
+        let data = &self.mmap[self.location.data_start as usize..];
+        match self.layout {
+            FitsLayout::Mono | FitsLayout::ChannelFirst => {
+                let start = ((channel * self.info.height + file_y) * width) * item_size;
+                self.location.decode(&data[start..start + width * item_size], dst);
+            }
+            FitsLayout::ChannelLast => {
+                let start = file_y * width * 3 * item_size;
+                let row = &data[start..start + 3 * width * item_size];
+                buffer.clear();
+                for item in row.chunks_exact(item_size).skip(channel).step_by(3) {
+                    buffer.extend_from_slice(item);
+                }
+                self.location.decode(buffer, dst);
+            }
+        }
+    }
+
+    fn channel_max(&self, channel: usize) -> f32 {
+        use rayon::prelude::*;
+        (0..self.info.height).into_par_iter()
+            .map_init(
+                || (Vec::new(), vec![0_f32; self.info.width]),
+                |(buffer, row), y| {
+                    self.read_row(channel, y, buffer, row);
+                    row.iter().copied().max_by(cmp_f32).unwrap_or(0.0)
+                }
+            )
+            .max_by(cmp_f32)
+            .unwrap_or(0.0)
+    }
+
+    /// Normalized values of channel (0 - L or R, 1 - G, 2 - B)
+    pub fn load_channel(&self, channel: usize) -> ImageLayerF32 {
+        use rayon::prelude::*;
+        let (width, height) = (self.info.width, self.info.height);
+        let mut layer = ImageLayerF32::new(width as Crd, height as Crd);
+        layer.as_slice_mut()
+            .par_chunks_mut(width)
+            .enumerate()
+            .for_each_init(Vec::new, |buffer, (y, row)| {
+                self.read_row(channel, y, buffer, row);
+                for v in row { *v *= self.norm_k; }
+            });
+        layer
+    }
+}
+
+/// Image for read-only processing of large files. Uncompressed FITS
+/// files are memory-mapped and channels are loaded one by one, other
+/// files (including compressed FITS) are loaded completely
+pub enum ImageSource {
+    Mapped(MappedFitsImage),
+    Loaded(ImageData),
+}
+
+impl ImageSource {
+    pub fn open(file_name: &Path) -> anyhow::Result<Self> {
+        if is_fits_ext(extract_extension(file_name)) {
+            if let Some(mapped) = MappedFitsImage::open(file_name)? {
+                log::debug!("File {} is memory-mapped", path_to_str(file_name));
+                return Ok(Self::Mapped(mapped));
+            }
+        }
+        Ok(Self::Loaded(load_image_from_file(file_name, false)?))
+    }
+
+    pub fn info(&self) -> &ImageInfo {
+        match self {
+            Self::Mapped(mapped) => &mapped.info,
+            Self::Loaded(data) => &data.info,
+        }
+    }
+
+    /// CFA image without debayering
+    pub fn is_raw(&self) -> bool {
+        matches!(self, Self::Loaded(ImageData { image: RawOrImage::Raw(_), .. }))
+    }
+
+    pub fn channels_count(&self) -> usize {
+        match self {
+            Self::Mapped(mapped) => mapped.channels_count(),
+            Self::Loaded(ImageData { image: RawOrImage::Image(image), .. }) if image.is_rgb() => 3,
+            Self::Loaded(_) => 1,
+        }
+    }
+
+    /// Channel of image (0 - L, raw or R, 1 - G, 2 - B). Channel of
+    /// mapped image is loaded, channel of loaded image is borrowed
+    pub fn channel(&self, index: usize) -> std::borrow::Cow<'_, ImageLayerF32> {
+        use std::borrow::Cow;
+        match self {
+            Self::Mapped(mapped) => Cow::Owned(mapped.load_channel(index)),
+            Self::Loaded(ImageData { image: RawOrImage::Raw(raw), .. }) => Cow::Borrowed(&raw.data),
+            Self::Loaded(ImageData { image: RawOrImage::Image(image), .. }) => Cow::Borrowed(match index {
+                _ if !image.is_rgb() => &image.l,
+                0 => &image.r,
+                1 => &image.g,
+                _ => &image.b,
+            }),
+        }
+    }
+}
+
+pub enum FitsKeyValue {
+    Float(f64),
+    Str(String),
+}
+
+/// Replaces or adds keys in header of image HDU of existing FITS file
+pub fn update_fits_header(file_name: &Path, keys: &[(&str, FitsKeyValue)]) -> anyhow::Result<()> {
+    let mut fptr = fits_file_open_helper(
+        file_name,
+        |file_name| Ok(FitsFile::edit(file_name)?)
+    )?;
+    let (hdu, ..) = find_image_hdu(&mut fptr, None)?;
+    for (name, value) in keys {
+        // writing of key appends new card so old ones are deleted.
+        // Reading of key makes HDU current
+        _ = hdu.read_key::<String>(&mut fptr, name);
+        let c_name = std::ffi::CString::new(*name)?;
+        loop {
+            let mut status = 0;
+            unsafe { fitsio::sys::ffdkey(fptr.as_raw(), c_name.as_ptr(), &mut status); }
+            if status != 0 { break; }
+        }
+        match value {
+            FitsKeyValue::Float(v) => hdu.write_key(&mut fptr, name, *v)?,
+            FitsKeyValue::Str(v)   => hdu.write_key(&mut fptr, name, v.as_str())?,
+        }
+    }
+    Ok(())
+}
+
+/// Reads numeric keys from image HDU or from primary HDU if file has no image
+pub fn read_fits_float_keys(
+    file_name: &Path,
+    names:     &[&str]
+) -> anyhow::Result<std::collections::HashMap<String, f64>> {
+    let mut fptr = fits_file_open_helper(
+        file_name,
+        |file_name| Ok(FitsFile::open(file_name)?)
+    )?;
+    let hdu = match find_image_hdu(&mut fptr, None) {
+        Ok((hdu, ..)) => hdu,
+        Err(_) => fptr.primary_hdu()?,
+    };
+    Ok(names.iter()
+        .filter_map(|name| hdu.read_key::<f64>(&mut fptr, name).ok().map(|v| (name.to_string(), v)))
+        .collect())
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum FitsCompression {
+    None,
+    Rice, // quantizes float values
+    Gzip, // lossless
+}
+
+impl FitsCompression {
+    pub fn from_str(text: &str) -> anyhow::Result<Self> {
+        match text.to_ascii_lowercase().as_str() {
+            "none" => Ok(FitsCompression::None),
+            "rice" => Ok(FitsCompression::Rice),
+            "gzip" => Ok(FitsCompression::Gzip),
+            _ => anyhow::bail!("Wrong FITS compression {}", text),
+        }
+    }
+
+    // Extended file name syntax of cfitsio
+    fn file_name_suffix(self) -> &'static str {
+        match self {
+            FitsCompression::None => "",
+            FitsCompression::Rice => "[compress R]",
+            FitsCompression::Gzip => "[compress G; q 0]",
+        }
+    }
+}
+
+/// Creates FITS file and returns HDU to write image into.
+/// Compressed image can't be primary so cfitsio writes it into
+/// first extension after empty primary HDU
+fn create_fits_file(
+    file_name:         &Path,
+    image_description: &ImageDescription,
+    compression:       FitsCompression
+) -> anyhow::Result<(FitsFile, FitsHdu)> {
+    let mut fptr = fits_file_open_helper(
+        file_name,
+        |file_name| {
+            let file_name = format!(
+                "{}{}",
+                file_name.to_str().unwrap_or(""),
+                compression.file_name_suffix()
+            );
+            Ok(FitsFile::create(file_name)
+                .with_custom_primary(image_description)
+                .open()?)
+        }
+    )?;
+    let hdu = if compression == FitsCompression::None {
+        fptr.primary_hdu()?
+    } else {
+        fptr.hdu(1)?
+    };
+    Ok((fptr, hdu))
+}
+
+/// Data type of saved FITS file (BITPIX)
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum FitsBitPix {
+    Int16,   // 16, unsigned with BZERO=32768
+    Float32, // -32
+    Float64, // -64
+    UInt8,   // 8 (masks, previews of guide cameras)
+    Int64,   // 64, 0..1 is scaled to 0..u32::MAX (DATAMAX)
+}
+
+impl FitsBitPix {
+    pub fn from_str(text: &str) -> anyhow::Result<Self> {
+        match text {
+            "8"   => Ok(FitsBitPix::UInt8),
+            "16"  => Ok(FitsBitPix::Int16),
+            "64"  => Ok(FitsBitPix::Int64),
+            "-32" => Ok(FitsBitPix::Float32),
+            "-64" => Ok(FitsBitPix::Float64),
+            _ => anyhow::bail!("Wrong FITS BITPIX {} (8, 16, 64, -32 or -64 are supported)", text),
+        }
+    }
+
+    pub fn is_integer(self) -> bool {
+        matches!(self, FitsBitPix::UInt8|FitsBitPix::Int16|FitsBitPix::Int64)
+    }
+
+    /// Value of integer data for 1.0
+    fn integer_max(self) -> f64 {
+        match self {
+            FitsBitPix::UInt8 => u8::MAX as f64,
+            FitsBitPix::Int16 => u16::MAX as f64,
+            FitsBitPix::Int64 => u32::MAX as f64,
+            FitsBitPix::Float32|FitsBitPix::Float64 => 1.0,
+        }
+    }
+}
+
+/// Conventions of saved FITS expected by other software
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum FitsCompat {
+    Default,
+    PixInsight, // float values out of 0..1 range are clipped (else PixInsight rescales image)
+    Siril,      // same as PixInsight
+    Aps,        // Astro Pixel Processor: float values in 0..65535 range, bottom-up rows
+}
+
+impl FitsCompat {
+    pub fn from_str(text: &str) -> anyhow::Result<Self> {
+        match text.to_lowercase().as_str() {
+            "default"    => Ok(FitsCompat::Default),
+            "pixinsight" => Ok(FitsCompat::PixInsight),
+            "siril"      => Ok(FitsCompat::Siril),
+            "aps"        => Ok(FitsCompat::Aps),
+            _ => anyhow::bail!("Wrong FITS compatibility profile {} (pixinsight, siril or aps)", text),
+        }
+    }
+
+    fn float_range(self) -> f32 {
+        match self {
+            FitsCompat::Aps => u16::MAX as f32,
+            _ => 1.0,
+        }
+    }
+
+    fn is_bottom_up(self) -> bool {
+        self == FitsCompat::Aps
+    }
+}
+
+/// Range of values in saved FITS files
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+pub enum OutputRange {
+    #[default]
+    Normalized, // 0..1 for float data, full range of type for integer data
+    Adu16,      // 0..65535
+    Input,      // range of integer data of source file (0..4095 for 12-bit camera for example)
+}
+
+impl OutputRange {
+    pub fn from_str(text: &str) -> anyhow::Result<Self> {
+        match text.to_lowercase().as_str() {
+            "normalized"|"0..1" => Ok(OutputRange::Normalized),
+            "65535"|"0..65535"  => Ok(OutputRange::Adu16),
+            "input"             => Ok(OutputRange::Input),
+            _ => anyhow::bail!("Wrong output range {} (0..1, 0..65535 or input)", text),
+        }
+    }
+
+    /// Value of saved data for 1.0 or None for default range of data type.
+    /// Range of source file is unknown for RAW files and results of stacking
+    fn max_value(self, info: &ImageInfo) -> Option<f64> {
+        match self {
+            OutputRange::Normalized => None,
+            OutputRange::Adu16      => Some(u16::MAX as f64),
+            OutputRange::Input      => info.data_max,
+        }
+    }
+}
+
+/// Count of values clipped at saving of FITS file
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ClipStats {
+    pub low:   usize, // below zero
+    pub high:  usize, // above maximum of integer type or 1.0 for clipping compatibility profiles
+    pub total: usize,
+}
+
+impl ClipStats {
+    pub fn is_empty(&self) -> bool {
+        self.low == 0 && self.high == 0
+    }
+
+    fn add(&mut self, other: &ClipStats) {
+        self.low += other.low;
+        self.high += other.high;
+        self.total += other.total;
+    }
+}
+
+impl std::fmt::Display for ClipStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let percent = |count: usize| 100.0 * count as f64 / self.total.max(1) as f64;
+        write!(
+            f, "{} ({:.3}%) to black, {} ({:.3}%) to white",
+            self.low, percent(self.low), self.high, percent(self.high)
+        )
+    }
+}
+
+/// Conversion of float values into integer ones for 8 and 16 bit output
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+pub enum Quantization {
+    #[default]
+    Nearest, // rounding to nearest integer
+    Floor,   // truncation
+    Dither,  // triangular (TPDF) noise of ±1 LSB before rounding. Prevents
+             // posterization of smooth gradients of stretched background
+}
+
+impl Quantization {
+    pub fn from_str(text: &str) -> anyhow::Result<Self> {
+        match text.to_lowercase().as_str() {
+            "nearest" => Ok(Quantization::Nearest),
+            "floor"   => Ok(Quantization::Floor),
+            "dither"  => Ok(Quantization::Dither),
+            _ => anyhow::bail!("Wrong quantization {} (nearest, floor or dither)", text),
+        }
+    }
+
+    /// Integer value for `value` in 0..1 range where `max` is value for 1.0.
+    /// `index` is position of value used as seed of dithering noise
+    /// so result doesn't depend on order of conversion. Result is always
+    /// in 0..=max range: overexposure (+inf) is `max`, NaN and values
+    /// without data are 0, so it can be safely casted to output type
+    pub fn quantize(self, value: f32, max: f64, index: u64) -> f64 {
+        if value.is_nan() { return 0.0; }
+        let v = value.clamp(0.0, 1.0) as f64 * max;
+        let result = match self {
+            Quantization::Nearest => v.round(),
+            Quantization::Floor => v.floor(),
+            Quantization::Dither => {
+                let noise = uniform_noise(2 * index) - uniform_noise(2 * index + 1);
+                (v + noise).round()
+            }
+        };
+        result.clamp(0.0, max)
+    }
+}
+
+// Uniform value in 0..1 range by hash of `index` (splitmix64)
+fn uniform_noise(index: u64) -> f64 {
+    let mut z = index.wrapping_add(0x9E3779B97F4A7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^= z >> 31;
+    (z >> 11) as f64 / (1_u64 << 53) as f64
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct FitsSaveOpts {
+    pub bitpix:       FitsBitPix,
+    pub compression:  FitsCompression,
+    #[serde(default = "default_fits_compat")]
+    pub compat:       FitsCompat,
+    #[serde(default)]
+    pub quantization: Quantization,
+    #[serde(default)]
+    pub range:        OutputRange,
+    #[serde(default)]
+    pub pedestal:     f32, // added to values in units of saved data (ADU for 0..65535 range)
+}
+
+fn default_fits_compat() -> FitsCompat {
+    FitsCompat::Default
+}
+
+impl Default for FitsSaveOpts {
+    fn default() -> Self {
+        Self {
+            bitpix: FitsBitPix::Float32,
+            compression: FitsCompression::None,
+            compat: FitsCompat::Default,
+            quantization: Quantization::Nearest,
+            range: OutputRange::Normalized,
+            pedestal: 0.0,
+        }
+    }
+}
+
+impl FitsSaveOpts {
+    /// Data of layer in order and range of saved file. For integer types
+    /// result is part of maximum of type (it is clipped by quantization)
+    fn convert_layer(&self, layer: &ImageLayerF32, info: &ImageInfo) -> (Vec<f32>, ClipStats) {
+        let mut result: Vec<f32> = if self.compat.is_bottom_up() {
+            (0..layer.height()).rev().flat_map(|y| layer.row(y).iter().copied()).collect()
+        } else {
+            layer.as_slice().to_vec()
+        };
+        let mut stats = ClipStats { total: result.len(), ..ClipStats::default() };
+        let range_max = self.range.max_value(info);
+        if self.bitpix.is_integer() {
+            let type_max = self.bitpix.integer_max();
+            let scale = (range_max.unwrap_or(type_max) / type_max) as f32;
+            let pedestal = (self.pedestal as f64 / type_max) as f32;
+            for v in &mut result {
+                if v.is_nan() || *v == NO_VALUE_F32 { continue; }
+                *v = pedestal + *v * scale;
+                if *v < 0.0 { stats.low += 1; }
+                if *v > 1.0 { stats.high += 1; }
+            }
+            return (result, stats);
+        }
+        let clip = self.compat != FitsCompat::Default;
+        let scale = range_max.map(|v| v as f32).unwrap_or(self.compat.float_range());
+        for v in &mut result {
+            if !v.is_finite() { continue; }
+            let no_value = *v == NO_VALUE_F32;
+            if clip {
+                if *v < 0.0 && !no_value { stats.low += 1; }
+                if *v > 1.0 { stats.high += 1; }
+                *v = v.clamp(0.0, 1.0);
+            } else if no_value {
+                continue;
+            }
+            *v = self.pedestal + *v * scale;
+        }
+        (result, stats)
+    }
+}
+
+/// Values of `image` which will be clipped at saving into FITS file with `opts`
+pub fn fits_clip_stats(image: &Image, info: &ImageInfo, opts: &FitsSaveOpts) -> ClipStats {
+    let mut result = ClipStats::default();
+    for layer in [&image.l, &image.r, &image.g, &image.b] {
+        if layer.is_empty() { continue; }
+        result.add(&opts.convert_layer(layer, info).1);
+    }
+    result
+}
+
+/// Max. value of integer data considering BZERO and BSCALE.
+/// Returns None for floating point data
+fn fits_data_max_value(
+    fptr:      &mut FitsFile,
+    hdu:       &FitsHdu,
+    data_type: ImageType
+) -> Option<f64> {
+    let bzero = hdu.read_key::<f64>(fptr, "BZERO").unwrap_or(0.0);
+    let bscale = hdu.read_key::<f64>(fptr, "BSCALE").unwrap_or(1.0);
+    let raw_max = match data_type {
+        ImageType::UnsignedByte  => u8::MAX as f64,
+        ImageType::Byte          => i8::MAX as f64,
+        ImageType::Short         => i16::MAX as f64,
+        ImageType::UnsignedShort => u16::MAX as f64,
+        ImageType::Long          => i32::MAX as f64,
+        ImageType::UnsignedLong  => u32::MAX as f64,
+        // 64-bit data has no natural range (sums of frames for example)
+        ImageType::LongLong      => return hdu.read_key::<f64>(fptr, "DATAMAX").ok(),
+        ImageType::Float|ImageType::Double => return None,
+    };
+    // Data type is taken from BITPIX so unsigned 16 and 32 bit data
+    // is reported as signed with BZERO=32768 or BZERO=2147483648
+    let max = bscale * raw_max + bzero;
+    Some(if max > 0.0 { max } else { raw_max })
+}
+
+/// `seed` is added to index of dithering noise (different for every channel)
+fn write_fits_region(
+    fptr:         &mut FitsFile,
+    hdu:          &FitsHdu,
+    ranges:       &[&std::ops::Range<usize>],
+    data:         &[f32],
+    bitpix:       FitsBitPix,
+    quantization: Quantization,
+    seed:         u64,
+) -> anyhow::Result<()> {
+    let to_int = |(i, v): (usize, &f32)| quantization.quantize(*v, bitpix.integer_max(), seed + i as u64);
+    match bitpix {
+        FitsBitPix::UInt8 => {
+            let data: Vec<u8> = data.iter().enumerate().map(|v| to_int(v) as u8).collect();
+            hdu.write_region(fptr, ranges, &data)?;
+        }
+        FitsBitPix::Int16 => {
+            let data: Vec<u16> = data.iter().enumerate().map(|v| to_int(v) as u16).collect();
+            hdu.write_region(fptr, ranges, &data)?;
+        }
+        FitsBitPix::Int64 => {
+            let data: Vec<i64> = data.iter().enumerate().map(|v| to_int(v) as i64).collect();
+            hdu.write_region(fptr, ranges, &data)?;
+        }
+        FitsBitPix::Float32 => {
+            hdu.write_region(fptr, ranges, data)?;
+        }
+        FitsBitPix::Float64 => {
+            let data: Vec<f64> = data.iter().map(|v| *v as f64).collect();
+            hdu.write_region(fptr, ranges, &data)?;
+        }
+    }
+    Ok(())
+}
+
 pub fn save_image_to_fits_file(
     image:     &Image,
     info:      &ImageInfo,
-    file_name: &Path
+    file_name: &Path,
+    opts:      FitsSaveOpts,
 ) -> anyhow::Result<()> {
     let width = image.width() as usize;
     let height = image.height() as usize;
@@ -784,45 +1949,179 @@ pub fn save_image_to_fits_file(
     };
 
     let image_description = ImageDescription {
-        data_type: ImageType::Float,
+        data_type: match opts.bitpix {
+            FitsBitPix::UInt8   => ImageType::UnsignedByte,
+            FitsBitPix::Int16   => ImageType::UnsignedShort,
+            FitsBitPix::Int64   => ImageType::LongLong,
+            FitsBitPix::Float32 => ImageType::Float,
+            FitsBitPix::Float64 => ImageType::Double,
+        },
         dimensions: &dimensions,
     };
 
-    let mut fptr = fits_file_open_helper(
-        file_name,
-        |file_name| {
-            Ok(FitsFile::create(file_name)
-                .with_custom_primary(&image_description)
-                .open()?)
+    let (mut fptr, hdu) = create_fits_file(file_name, &image_description, opts.compression)?;
+
+    if image.is_rgb() {
+        for (i, layer) in [&image.r, &image.g, &image.b].into_iter().enumerate() {
+            write_fits_region(
+                &mut fptr, &hdu,
+                &[&(0..width), &(0..height), &(i..i+1)],
+                &opts.convert_layer(layer, info).0,
+                opts.bitpix,
+                opts.quantization,
+                (i * width * height) as u64
+            )?;
         }
-    )?;
+    } else {
+        write_fits_region(
+            &mut fptr, &hdu,
+            &[&(0..width), &(0..height)],
+            &opts.convert_layer(&image.l, info).0,
+            opts.bitpix,
+            opts.quantization,
+            0
+        )?;
+    };
+
+    if opts.bitpix == FitsBitPix::Int64 {
+        hdu.write_key(&mut fptr, "DATAMAX", opts.bitpix.integer_max())?;
+    }
 
-    let hdu = fptr.primary_hdu().unwrap();
+    if let Some(exp) = info.exp {
+        hdu.write_key(&mut fptr, "EXPTIME", exp)?;
+    }
 
+    if let Some(integration) = &info.integration {
+        write_fits_integration_keys(&mut fptr, &hdu, integration)?;
+    }
+
+    if let Some(camera) = &info.camera {
+        hdu.write_key(&mut fptr, "INSTRUME", camera.as_str())?;
+    }
+
+    if let Some(lens) = &info.lens {
+        hdu.write_key(&mut fptr, "TELESCOP", lens.as_str())?;
+    }
+
+    let row_order = if opts.compat.is_bottom_up() { "BOTTOM-UP" } else { "TOP-DOWN" };
+    hdu.write_key(&mut fptr, "ROWORDER", row_order)?;
+
+    Ok(())
+}
+
+fn write_fits_integration_keys(
+    fptr: &mut FitsFile,
+    hdu:  &FitsHdu,
+    meta: &IntegrationMeta,
+) -> anyhow::Result<()> {
+    hdu.write_key(fptr, "NCOMBINE", meta.count as i64)?;
+    if let Some(date_obs) = meta.date_obs {
+        hdu.write_key(fptr, "DATE-OBS", format_fits_time(date_obs).as_str())?;
+    }
+    if let Some(date_end) = meta.date_end {
+        hdu.write_key(fptr, "DATE-END", format_fits_time(date_end).as_str())?;
+    }
+    // fitsio has no functions for commentary cards
+    let (history, comments) = meta.history();
+    let mut status = 0;
+    let text = std::ffi::CString::new(history)?;
+    unsafe { fitsio::sys::ffphis(fptr.as_raw(), text.as_ptr(), &mut status); }
+    for comment in comments {
+        let text = std::ffi::CString::new(comment)?;
+        unsafe { fitsio::sys::ffpcom(fptr.as_raw(), text.as_ptr(), &mut status); }
+    }
+    if status != 0 {
+        anyhow::bail!("Can't write HISTORY of FITS file (error {})", status);
+    }
+    Ok(())
+}
+
+/// Appends 32-bit float image HDU named `ext_name` to existing FITS
+/// file (variance plane of stacked image for example)
+pub fn append_image_to_fits_file(
+    image:     &Image,
+    ext_name:  &str,
+    file_name: &Path,
+) -> anyhow::Result<()> {
+    let width = image.width() as usize;
+    let height = image.height() as usize;
+    let dimensions = if image.is_rgb() {
+        vec![3_usize, height, width]
+    } else {
+        vec![height, width]
+    };
+    let image_description = ImageDescription {
+        data_type: ImageType::Float,
+        dimensions: &dimensions,
+    };
+    let mut fptr = fits_file_open_helper(
+        file_name,
+        |file_name| Ok(FitsFile::edit(file_name)?)
+    )?;
+    let hdu = fptr.create_image(ext_name, &image_description)?;
     if image.is_rgb() {
-        hdu.write_region(
-            &mut fptr,
-            &[&(0..width), &(0..height), &(0..1)],
-            image.r.as_slice()
-        )?;
-        hdu.write_region(
-            &mut fptr,
-            &[&(0..width), &(0..height), &(1..2)],
-            image.g.as_slice()
-        )?;
-        hdu.write_region(
-            &mut fptr,
-            &[&(0..width), &(0..height), &(2..3)],
-            image.b.as_slice()
-        )?;
+        for (i, layer) in [&image.r, &image.g, &image.b].into_iter().enumerate() {
+            write_fits_region(
+                &mut fptr, &hdu,
+                &[&(0..width), &(0..height), &(i..i+1)],
+                layer.as_slice(),
+                FitsBitPix::Float32,
+                Quantization::Nearest,
+                0
+            )?;
+        }
     } else {
-        hdu.write_image(&mut fptr, image.l.as_slice())?;
+        write_fits_region(
+            &mut fptr, &hdu,
+            &[&(0..width), &(0..height)],
+            image.l.as_slice(),
+            FitsBitPix::Float32,
+            Quantization::Nearest,
+            0
+        )?;
+    }
+    Ok(())
+}
+
+/// Saves 16-bit mono or CFA image (video frames for example)
+pub fn save_cfa_image_to_fits_file(
+    data:        &[u16],
+    info:        &ImageInfo,
+    file_name:   &Path,
+    compression: FitsCompression,
+) -> anyhow::Result<()> {
+    assert!(data.len() == info.width * info.height);
+
+    _ = std::fs::remove_file(file_name);
+
+    let dimensions = [info.height, info.width];
+    let image_description = ImageDescription {
+        data_type: ImageType::UnsignedShort,
+        dimensions: &dimensions,
     };
 
+    let (mut fptr, hdu) = create_fits_file(file_name, &image_description, compression)?;
+    hdu.write_image(&mut fptr, data)?;
+
+    if let Some(cfa_type) = info.cfa_type {
+        let bayer = match cfa_type {
+            CfaType::GBRG => "GBRG",
+            CfaType::RGGB => "RGGB",
+            CfaType::BGGR => "BGGR",
+            CfaType::GRBG => "GRBG",
+        };
+        hdu.write_key(&mut fptr, "BAYERPAT", bayer)?;
+    }
+
     if let Some(exp) = info.exp {
         hdu.write_key(&mut fptr, "EXPTIME", exp)?;
     }
 
+    if let Some(time) = &info.file_time {
+        let time_str = time.format("%Y-%m-%dT%H:%M:%S%.3f").to_string();
+        hdu.write_key(&mut fptr, "DATE-LOC", time_str.as_str())?;
+    }
+
     if let Some(camera) = &info.camera {
         hdu.write_key(&mut fptr, "INSTRUME", camera.as_str())?;
     }
@@ -908,6 +2207,7 @@ pub fn save_master_format_file(
     master_info.write_to(&mut file)?;
     raw.info.write_to(&mut file)?;
     for v in raw.data.iter() { file.write_f32::<byteorder::BigEndian>(*v)?; }
+    file.flush()?;
     Ok(())
 }
 
@@ -921,6 +2221,10 @@ pub fn load_master_format_file(file_name: &Path) -> anyhow::Result<RawImage> {
     file.seek_relative(header_len as i64)?;
 
     let info = RawImageInfo::read_from(&mut file)?;
+    let data_size = checked_image_size(info.width.max(0) as usize, info.height.max(0) as usize, 1, 4)?;
+    if file.get_ref().metadata()?.len() < data_size as u64 {
+        anyhow::bail!("Master file {} is truncated", file_name.to_str().unwrap_or(""));
+    }
     let mut image = ImageLayerF32::new(info.width, info.height);
     for v in image.iter_mut() { *v = file.read_f32::<byteorder::BigEndian>()?; }
     Ok(RawImage{
@@ -967,6 +2271,8 @@ impl InternalFormatReader {
 pub struct MasterFileInfo {
     pub files:     Vec<PathBuf>,
     pub calc_opts: CalcOpts,
+    #[serde(default)]
+    pub flat_norm: FlatNorm, // default for master dark and bias
 }
 
 impl MasterFileInfo {
@@ -981,9 +2287,7 @@ impl MasterFileInfo {
         file.read_exact(&mut sig)?;
         if sig != Self::MASTER_FILE_SIG { anyhow::bail!("Wrong file format"); }
         let header_len = leb128::read::unsigned(&mut file)? as usize;
-        let mut buf = Vec::<u8>::new();
-        buf.resize(header_len, 0);
-        file.read_exact(&mut buf)?;
+        let buf = read_bytes(&mut file, header_len, MAX_HEADER_SIZE, "Master file header")?;
         let header_str = std::str::from_utf8(buf.as_slice())?;
         Ok(serde_json::from_str(header_str)?)
     }
@@ -1023,9 +2327,7 @@ impl RawImageInfo {
         src.read_exact(&mut sig)?;
         if sig != Self::CALIBR_FILE_SIG { anyhow::bail!("Wrong file format"); }
         let header_len = leb128::read::unsigned(src)? as usize;
-        let mut buf = Vec::<u8>::new();
-        buf.resize(header_len, 0);
-        src.read_exact(&mut buf)?;
+        let buf = read_bytes(src, header_len, MAX_HEADER_SIZE, "Calibration file header")?;
         let header_str = std::str::from_utf8(buf.as_slice())?;
         Ok(serde_json::from_str(header_str)?)
     }
@@ -1,7 +1,7 @@
 use std::{collections::HashMap, path::*};
 use itertools::*;
 
-use crate::{image::*, image_raw::*, calc::*, light_file::*, stars::*, log_utils::*};
+use crate::{image::*, image_raw::*, calc::*, light_file::*, stars::*, log_utils::*, bg_mask::*};
 
 pub struct NormResult {
     pub range_factor: f32,
@@ -41,11 +41,23 @@ pub fn normalize_range_and_bg(
         if v.is_infinite() || *v == NO_VALUE_F32 { *m = true; }
     }
 
+    // bright objects are excluded from background but are used for range
+    let bg_mask = match &ref_data.objects {
+        Some(objects) => {
+            let mut bg_mask = mask.clone();
+            for (m, o) in izip!(bg_mask.iter_mut(), objects.iter()) {
+                if *o { *m = true; }
+            }
+            bg_mask
+        }
+        None => mask.clone(),
+    };
+
     let calc_log = TimeLogger::start();
-    let image_bg = calc_image_bg(&light_file.image, &mask)?;
+    let image_bg = calc_image_bg(&light_file.image, &bg_mask)?;
     calc_log.log("calc bg");
 
-    let gs_bg = calc_image_layer_bg(&grey_image, &mask)?;
+    let gs_bg = calc_image_layer_bg(&grey_image, &bg_mask)?;
     gs_bg.apply_to_image(&mut grey_image, true)?;
 
     let range_log = TimeLogger::start();
@@ -290,9 +302,10 @@ impl ImageBg {
 }
 
 pub struct RefBgData {
-    pub image: LightFile,
-    pub grey:  ImageLayerF32, // greyscale image minus background
-    pub bg:    ImageBg,
+    pub image:   LightFile,
+    pub grey:    ImageLayerF32, // greyscale image minus background
+    pub bg:      ImageBg,
+    pub objects: Option<ImageMask>, // bright objects of reference image
 }
 
 impl RefBgData {
@@ -301,6 +314,8 @@ impl RefBgData {
         cal_data:      &CalibrationData,
         bin:           usize,
         raw_params:    &RawOpenParams,
+        stars_opts:    &StarsFindOpts,
+        bg_mask:       BgMaskPreset,
     ) -> anyhow::Result<RefBgData> {
         let image = LightFile::load_and_calc_params(
             ref_file_name,
@@ -309,17 +324,33 @@ impl RefBgData {
             OpenMode::Processing,
             bin,
             raw_params,
+            stars_opts,
         )?;
 
         let mut mask = ImageMask::new_empty();
         mask_stars(&mut mask, image.image.width(), image.image.height(), &image.stars);
 
         let mut grey = image.image.create_greyscale_layer();
+        let objects = bg_mask.objects_opts().map(|opts| {
+            let mut objects = ImageMask::new(grey.width(), grey.height());
+            mask_bright_objects(&grey, &opts, &mut objects);
+            log::info!(
+                "{} pixels of bright objects are excluded from background",
+                objects.iter().filter(|v| **v).count()
+            );
+            objects
+        });
+        if let Some(objects) = &objects {
+            for (m, o) in izip!(mask.iter_mut(), objects.iter()) {
+                if *o { *m = true; }
+            }
+        }
+
         let grey_bg = calc_image_layer_bg(&grey, &mask)?;
         grey_bg.apply_to_image(&mut grey, true)?;
 
         let bg = calc_image_bg(&image.image, &mask)?;
 
-        Ok(RefBgData { image, grey, bg })
+        Ok(RefBgData { image, grey, bg, objects })
     }
 }
@@ -1,7 +1,9 @@
 use std::{path::*, collections::{HashSet, HashMap}, hash::Hash};
 use itertools::{izip, Itertools};
+use rayon::prelude::*;
 use serde::{Serialize, Deserialize};
-use crate::{image::*, fs_utils, log_utils::*, calc::*, image_io::*};
+use chrono::prelude::*;
+use crate::{image::*, fs_utils, log_utils::*, calc::*, image_io::*, safe_read::*};
 
 #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
 pub enum CfaColor {
@@ -30,6 +32,16 @@ impl CfaType {
         }
     }
 
+    /// Pattern of image with reversed order of rows (with even height)
+    pub fn flipped_vertically(self) -> CfaType {
+        match self {
+            CfaType::GBRG => CfaType::RGGB,
+            CfaType::RGGB => CfaType::GBRG,
+            CfaType::BGGR => CfaType::GRBG,
+            CfaType::GRBG => CfaType::BGGR,
+        }
+    }
+
     pub fn get_arr(self) -> CfaArr {
         use CfaColor::*;
         match self {
@@ -137,10 +149,27 @@ impl RawImage {
     pub fn load(file_name: &Path) -> anyhow::Result<(RawImage, ImageInfo)> {
         let raw = rawloader::decode_file(file_name)?;
 
+        // Only 2x2 bayer matrix is supported (no X-Trans)
+        if raw.cfa.width > 2 || raw.cfa.height > 2 {
+            anyhow::bail!(
+                "CFA pattern {} of file {} is not supported",
+                raw.cfa.name,
+                file_name.to_str().unwrap_or("")
+            );
+        }
+
         let crop_left = raw.crops[3] as Crd;
         let crop_top = raw.crops[0] as Crd;
         let width = raw.width as Crd - raw.crops[1] as Crd - crop_left;
         let height = raw.height as Crd - raw.crops[2] as Crd - crop_top;
+        let raw_data_len = match &raw.data {
+            rawloader::RawImageData::Integer(data) => data.len(),
+            rawloader::RawImageData::Float(data) => data.len(),
+        };
+        if width <= 0 || height <= 0 || raw.width.checked_mul(raw.height).map(|s| s > raw_data_len).unwrap_or(true) {
+            anyhow::bail!("Wrong size of RAW image in file {}", file_name.to_str().unwrap_or(""));
+        }
+        checked_image_size(width as usize, height as usize, 1, 4)?;
 
         let mut info = ImageInfo::default();
         if let Some(raw_exif) = &raw.exif {
@@ -176,9 +205,7 @@ impl RawImage {
             }
 
             let srgb_to_cam = srgb_to_cam.normalize();
-            let cam_to_rgb = srgb_to_cam.try_inverse().unwrap();
-
-            Some([
+            srgb_to_cam.try_inverse().map(|cam_to_rgb| [
                 cam_to_rgb[0], cam_to_rgb[1], cam_to_rgb[2],
                 cam_to_rgb[3], cam_to_rgb[4], cam_to_rgb[5],
                 cam_to_rgb[6], cam_to_rgb[7], cam_to_rgb[8],
@@ -329,7 +356,18 @@ impl RawImage {
         }
     }
 
-    pub fn calibrate(&mut self, cal_data: &CalibrationData) -> anyhow::Result<()> {
+    pub fn calibrate(&mut self, cal_data: &CalibrationData, optimize_dark: bool) -> anyhow::Result<()> {
+        self.calibrate_at(cal_data, optimize_dark, None)
+    }
+
+    /// Calibration of light file shot at `time`. Time is used for
+    /// interpolation of master flats of start and end of session
+    pub fn calibrate_at(
+        &mut self,
+        cal_data:      &CalibrationData,
+        optimize_dark: bool,
+        time:          Option<DateTime<Local>>,
+    ) -> anyhow::Result<()> {
         // extract master-bias image
         if let Some(bias) = &cal_data.bias_image {
             CalibrationData::is_usable_for_raw(&self.info, &bias.info, "master bias", false)?;
@@ -344,7 +382,13 @@ impl RawImage {
             let cal_exp = dark.info.exposure.unwrap_or(0.0);
             let exp = self.info.exposure.unwrap_or(0.0);
             let exp_diff = (cal_exp - exp).abs();
-            if exp_diff == 0.0 || exp_diff < exp * 0.2 {
+            if optimize_dark {
+                let scale = self.find_dark_scale(dark, &cal_data.hot_pixels);
+                log::info!("Master dark is scaled by {:.3}", scale);
+                for (v, d) in self.data.iter_mut().zip(dark.data.iter()) {
+                    *v -= scale * *d;
+                }
+            } else if exp_diff == 0.0 || exp_diff < exp * 0.2 {
                 self.data -= &dark.data;
             } else {
                 log::info!("Master dark is used only for hot bixels because exposures differ")
@@ -354,7 +398,17 @@ impl RawImage {
         // flatten by master-flat
         if let Some(flat) = &cal_data.flat_image {
             CalibrationData::is_usable_for_raw(&self.info, &flat.info, "master flat", false)?;
-            self.data *= &flat.data;
+            match (&cal_data.flat_drift, time) {
+                (Some(drift), Some(time)) => {
+                    let k = drift.end_part(time);
+                    log::info!("Master flats of start and end are mixed with {:.3} part of end one", k);
+                    // flats are kept inverted
+                    for (v, s, e) in izip!(self.data.iter_mut(), flat.data.iter(), drift.end_flat.data.iter()) {
+                        *v /= (1.0 - k) / *s + k / *e;
+                    }
+                },
+                _ => self.data *= &flat.data,
+            }
         }
 
         // remove hot pixels from RAW image
@@ -363,6 +417,44 @@ impl RawImage {
         Ok(())
     }
 
+    /// Scale of master dark which gives minimal noise of calibrated image.
+    /// Noise is sum of absolute differences between neighbour pixels of same
+    /// CFA color so minimum is weighted median of ratios of these differences
+    fn find_dark_scale(&self, dark: &RawImage, hot_pixels: &HashSet<BadPixel>) -> f32 {
+        const ROWS_STEP: usize = 4;
+        const MAX_SCALE: f32 = 2.0;
+        let max_value = self.info.max_values.iter().copied().fold(0.0, f32::max);
+        let overexposed = |v: f32| max_value > 0.0 && v >= 0.95 * max_value;
+        let mut ratios = Vec::new(); // (ratio, weight)
+        for y in (0..self.info.height).step_by(ROWS_STEP) {
+            let light_row = self.data.row(y);
+            let dark_row = dark.data.row(y);
+            for x in 0..(self.info.width as usize).saturating_sub(2) {
+                let (l1, l2) = (light_row[x], light_row[x + 2]);
+                let (d1, d2) = (dark_row[x], dark_row[x + 2]);
+                if !l1.is_finite() || !l2.is_finite() || overexposed(l1) || overexposed(l2) {
+                    continue;
+                }
+                if hot_pixels.contains(&BadPixel { x: x as Crd, y })
+                || hot_pixels.contains(&BadPixel { x: x as Crd + 2, y }) {
+                    continue;
+                }
+                let dark_diff = d1 - d2;
+                if dark_diff == 0.0 || !dark_diff.is_finite() { continue; }
+                ratios.push(((l1 - l2) / dark_diff, dark_diff.abs()));
+            }
+        }
+        if ratios.is_empty() { return 1.0; }
+        ratios.sort_unstable_by(|a, b| cmp_f32(&a.0, &b.0));
+        let half_weight = ratios.iter().map(|(_, w)| w).sum::<f32>() / 2.0;
+        let mut weight = 0.0;
+        let median = ratios.iter()
+            .find(|(_, w)| { weight += w; weight >= half_weight })
+            .map(|(r, _)| *r)
+            .unwrap_or(1.0);
+        median.clamp(0.0, MAX_SCALE)
+    }
+
     fn find_clip_value(data: &[f32]) -> f32 {
         let mut max_value = 1e10;
         loop {
@@ -1012,6 +1104,56 @@ pub struct CalibrationData {
     pub flat_image: Option<RawImage>,
     pub bias_image: Option<RawImage>,
     pub hot_pixels: HashSet<BadPixel>,
+    pub flat_drift: Option<FlatDrift>,
+}
+
+/// Normalization of flat files before stacking into master flat.
+/// Flats of OSC cameras normalized by one value for all CFA channels
+/// change color balance of calibrated lights
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+pub enum FlatNorm {
+    #[default]
+    Percentile,  // 99th percentile of every CFA channel
+    GlobalMean,  // mean of all pixels (keeps color of flat panel)
+    ChannelMean, // mean of every CFA channel
+    CenterMean,  // mean of every CFA channel in center of frame
+}
+
+impl FlatNorm {
+    pub fn from_str(text: &str) -> anyhow::Result<Self> {
+        match text.to_lowercase().as_str() {
+            "percentile" => Ok(FlatNorm::Percentile),
+            "global"     => Ok(FlatNorm::GlobalMean),
+            "channel"    => Ok(FlatNorm::ChannelMean),
+            "center"     => Ok(FlatNorm::CenterMean),
+            _ => anyhow::bail!("Wrong flat normalization {} (percentile, global, channel or center)", text),
+        }
+    }
+}
+
+/// Master flats of start and end of session. Flat of light file
+/// is interpolated by its time to compensate dew or dust changes
+#[derive(Clone, Debug)]
+pub struct FlatDriftParams {
+    pub end_flat:   PathBuf,
+    pub start_time: DateTime<Local>, // mean time of flat files of start
+    pub end_time:   DateTime<Local>, // mean time of flat files of end
+}
+
+pub struct FlatDrift {
+    pub end_flat:   RawImage, // inverted as `flat_image`
+    pub start_time: DateTime<Local>,
+    pub end_time:   DateTime<Local>,
+}
+
+impl FlatDrift {
+    /// Part of end flat for light file shot at `time` (0..1)
+    pub fn end_part(&self, time: DateTime<Local>) -> f32 {
+        let total = (self.end_time - self.start_time).num_milliseconds();
+        if total <= 0 { return 0.0; }
+        let pos = (time - self.start_time).num_milliseconds();
+        (pos as f64 / total as f64).clamp(0.0, 1.0) as f32
+    }
 }
 
 impl CalibrationData {
@@ -1021,6 +1163,7 @@ impl CalibrationData {
             flat_image: None,
             bias_image: None,
             hot_pixels: HashSet::new(),
+            flat_drift: None,
         }
     }
 
@@ -1058,19 +1201,7 @@ impl CalibrationData {
         };
 
         let flat_image = match master_flat {
-            Some(file_name) => {
-                log::info!(
-                    "loading master flat '{}'...",
-                    fs_utils::path_to_str(file_name)
-                );
-                let mut image = load_master_format_file(file_name)?;
-                image.remove_bad_pixels(&hot_pixels);
-                let filter_log = TimeLogger::start();
-                let mut image = image.filter_flat_image();
-                filter_log.log("filtering flat image");
-                for v in image.data.iter_mut() { *v = 1.0 / *v; }
-                Some(image)
-            }
+            Some(file_name) => Some(Self::load_master_flat(file_name, &hot_pixels)?),
             None => None,
         };
 
@@ -1078,10 +1209,44 @@ impl CalibrationData {
             dark_image,
             flat_image,
             bias_image,
-            hot_pixels
+            hot_pixels,
+            flat_drift: None,
         })
     }
 
+    /// Loads master flat of end of session. Master flat
+    /// of start must be loaded already
+    pub fn load_flat_drift(&mut self, params: &FlatDriftParams) -> anyhow::Result<()> {
+        let Some(flat_image) = &self.flat_image else {
+            anyhow::bail!("Master flat of start of session is not loaded");
+        };
+        let end_flat = Self::load_master_flat(&params.end_flat, &self.hot_pixels)?;
+        if end_flat.info.width != flat_image.info.width
+        || end_flat.info.height != flat_image.info.height {
+            anyhow::bail!("Master flats of start and end of session have different sizes");
+        }
+        self.flat_drift = Some(FlatDrift {
+            end_flat,
+            start_time: params.start_time,
+            end_time:   params.end_time,
+        });
+        Ok(())
+    }
+
+    fn load_master_flat(file_name: &Path, hot_pixels: &HashSet<BadPixel>) -> anyhow::Result<RawImage> {
+        log::info!(
+            "loading master flat '{}'...",
+            fs_utils::path_to_str(file_name)
+        );
+        let mut image = load_master_format_file(file_name)?;
+        image.remove_bad_pixels(hot_pixels);
+        let filter_log = TimeLogger::start();
+        let mut image = image.filter_flat_image();
+        filter_log.log("filtering flat image");
+        for v in image.data.iter_mut() { *v = 1.0 / *v; }
+        Ok(image)
+    }
+
     fn is_usable_for_raw(
         info:        &RawImageInfo,
         cal_info:    &RawImageInfo,
@@ -1122,8 +1287,66 @@ impl CalibrationData {
     pub fn is_empty(&self) -> bool {
         self.dark_image.is_none() &&
         self.flat_image.is_none() &&
-        self.bias_image.is_none()
+        self.bias_image.is_none() &&
+        self.hot_pixels.is_empty()
+    }
+
+    /// Finds hot pixels by light files if there is no master dark. Thanks
+    /// to dithering stars are in different pixels of detector in different
+    /// light files while hot pixels stay in the same ones (as in DSS)
+    pub fn find_hot_pixels_in_light_files(
+        &mut self,
+        files:       &[PathBuf],
+        hdu:         Option<&FitsHduSelector>,
+        thread_pool: &rayon::ThreadPool
+    ) {
+        const MIN_FILES: usize = 3;
+        const MAX_FILES: usize = 16;
+        const PERCENTILE: usize = 99;
+        const K: f32 = 3.0;
+        const MIN_PART: f32 = 0.75; // of files where pixel is outlier
+
+        if files.len() < MIN_FILES {
+            log::warn!("Hot pixels are not detected: at least {} light files are required", MIN_FILES);
+            return;
+        }
+        let count = files.len().min(MAX_FILES);
+        let samples: Vec<&PathBuf> = (0..count)
+            .map(|i| &files[i * files.len() / count])
+            .collect();
+        let find_in_file = |file: &&PathBuf| -> anyhow::Result<HashSet<BadPixel>> {
+            let RawOrImage::Raw(mut raw) = load_src_image_from_file(file, true, hdu)?.image else {
+                anyhow::bail!("{} is not RAW image", fs_utils::path_to_str(file));
+            };
+            raw.extract_black();
+            raw.calibrate(self, false)?;
+            Ok(raw.find_hot_pixels_in_light_file(PERCENTILE, K))
+        };
+        let found = thread_pool.install(|| {
+            samples.par_iter().map(find_in_file).collect::<anyhow::Result<Vec<_>>>()
+        });
+        match found {
+            Ok(found) => {
+                self.hot_pixels = select_persistent_pixels(&found, MIN_PART);
+                log::info!("hot pixels found in {} light files = {}", found.len(), self.hot_pixels.len());
+            },
+            Err(err) =>
+                log::warn!("Hot pixels are not detected: {}", err),
+        }
+    }
+}
+
+/// Pixels which are in `min_part` of sets (and at least in two ones)
+pub fn select_persistent_pixels(sets: &[HashSet<BadPixel>], min_part: f32) -> HashSet<BadPixel> {
+    let mut counts = HashMap::<BadPixel, usize>::new();
+    for pixel in sets.iter().flatten() {
+        *counts.entry(*pixel).or_insert(0) += 1;
     }
+    let min_count = ((min_part * sets.len() as f32).ceil() as usize).max(2);
+    counts.into_iter()
+        .filter(|(_, count)| *count >= min_count)
+        .map(|(pixel, _)| pixel)
+        .collect()
 }
 
 pub struct RowColorIterator<'a> {
@@ -0,0 +1,162 @@
+use serde::*;
+use crate::{image::*, image_io::*, calc::*, noise::*};
+
+/* Statistics and histogram of image channels */
+
+const PERCENTILES: &[f32] = &[1.0, 5.0, 25.0, 75.0, 95.0, 99.0];
+
+#[derive(Serialize)]
+pub struct Percentile {
+    pub percent: f32,
+    pub value:   f32,
+}
+
+#[derive(Serialize)]
+pub struct ChannelStat {
+    pub name:        &'static str,
+    pub count:       usize, // number of valid values
+    pub min:         f32,
+    pub max:         f32,
+    pub mean:        f64,
+    pub median:      f32,
+    pub mad:         f32, // median absolute deviation
+    pub noise_k_sigma: f32, // sigma of gaussian noise
+    pub noise_mrs:   f32,
+    pub percentiles: Vec<Percentile>,
+    pub histogram:   Vec<usize>, // equal bins in 0..1 range
+}
+
+#[derive(Serialize)]
+pub struct ImageStat {
+    pub width:    usize,
+    pub height:   usize,
+    pub channels: Vec<ChannelStat>,
+}
+
+fn channel_stat(name: &'static str, layer: &ImageLayerF32, bins: usize) -> ChannelStat {
+    let mut values: Vec<_> = layer.as_slice()
+        .iter()
+        .filter(|v| v.is_finite() && **v != NO_VALUE_F32)
+        .copied()
+        .collect();
+    values.sort_unstable_by(cmp_f32);
+
+    let count = values.len();
+    let by_pos = |pos: f32| -> f32 {
+        if values.is_empty() { return 0.0; }
+        let index = ((pos * (count - 1) as f32).round() as usize).min(count - 1);
+        values[index]
+    };
+    let mean = if count != 0 {
+        values.iter().map(|v| *v as f64).sum::<f64>() / count as f64
+    } else {
+        0.0
+    };
+    let median = by_pos(0.5);
+    let mut deviations: Vec<_> = values.iter().map(|v| (v - median).abs()).collect();
+    let mad = median_f32(&mut deviations).unwrap_or(0.0);
+
+    let mut histogram = vec![0_usize; bins.max(1)];
+    let last = histogram.len() - 1;
+    for v in &values {
+        let index = ((v.clamp(0.0, 1.0) * histogram.len() as f32) as usize).min(last);
+        histogram[index] += 1;
+    }
+
+    ChannelStat {
+        name,
+        count,
+        min: values.first().copied().unwrap_or(0.0),
+        max: values.last().copied().unwrap_or(0.0),
+        mean,
+        median,
+        mad,
+        noise_k_sigma: estimate_noise(layer, NoiseMethod::KSigma),
+        noise_mrs: estimate_noise(layer, NoiseMethod::Mrs),
+        percentiles: PERCENTILES.iter()
+            .map(|&percent| Percentile { percent, value: by_pos(percent / 100.0) })
+            .collect(),
+        histogram,
+    }
+}
+
+impl ImageStat {
+    pub fn new_from_image(image: &Image, bins: usize) -> Self {
+        let channels = if image.is_rgb() {
+            vec![
+                channel_stat("red", &image.r, bins),
+                channel_stat("green", &image.g, bins),
+                channel_stat("blue", &image.b, bins),
+            ]
+        } else {
+            vec![channel_stat("luminance", &image.l, bins)]
+        };
+        Self {
+            width: image.width() as usize,
+            height: image.height() as usize,
+            channels,
+        }
+    }
+
+    /// For RAW and CFA images without debayering
+    pub fn new_from_layer(layer: &ImageLayerF32, bins: usize) -> Self {
+        Self {
+            width: layer.width() as usize,
+            height: layer.height() as usize,
+            channels: vec![channel_stat("raw", layer, bins)],
+        }
+    }
+
+    /// Channels of memory-mapped image are loaded one
+    /// by one so only one channel is kept in memory
+    pub fn new_from_source(source: &ImageSource, bins: usize) -> Self {
+        let names: &[&'static str] = match source.channels_count() {
+            3 => &["red", "green", "blue"],
+            _ if source.is_raw() => &["raw"],
+            _ => &["luminance"],
+        };
+        let channels = names.iter()
+            .enumerate()
+            .map(|(i, name)| channel_stat(*name, &source.channel(i), bins))
+            .collect();
+        Self {
+            width: source.info().width,
+            height: source.info().height,
+            channels,
+        }
+    }
+
+    pub fn print(&self) {
+        println!("Size: {}x{}", self.width, self.height);
+        for ch in &self.channels {
+            println!();
+            println!("{}:", ch.name);
+            println!("  count:  {}", ch.count);
+            println!("  min:    {:.6}", ch.min);
+            println!("  max:    {:.6}", ch.max);
+            println!("  mean:   {:.6}", ch.mean);
+            println!("  median: {:.6}", ch.median);
+            println!("  MAD:    {:.6}", ch.mad);
+            println!("  noise (k-sigma): {:.6}", ch.noise_k_sigma);
+            println!("  noise (MRS):     {:.6}", ch.noise_mrs);
+            for p in &ch.percentiles {
+                println!("  {:>4}%:  {:.6}", p.percent, p.value);
+            }
+            let max_count = ch.histogram.iter().copied().max().unwrap_or(0).max(1);
+            println!("  histogram:");
+            const MAX_BAR: usize = 50;
+            let bins = ch.histogram.len();
+            for (i, cnt) in ch.histogram.iter().enumerate() {
+                if *cnt == 0 { continue; }
+                let bar = (MAX_BAR * cnt + max_count - 1) / max_count;
+                println!(
+                    "  {:.4}-{:.4} {:>10} {}",
+                    i as f32 / bins as f32,
+                    (i + 1) as f32 / bins as f32,
+                    cnt,
+                    "#".repeat(bar)
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use std::path::*;
+use chrono::prelude::*;
+use serde::*;
+use crate::{image_io::*, fs_utils::*};
+
+/* Metadata of stacked image: total exposure (EXPTIME), number of combined
+   frames (NCOMBINE), start of first frame (DATE-OBS), end of last frame
+   (DATE-END) and list of contributing files (HISTORY and COMMENT cards).
+   Collected from light files by stacking and merged from stacks by
+   commands which combine several stacks (mosaic, LRGB, HDR) */
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct IntegrationMeta {
+    pub exp_time: f64,   // seconds, sum of exposures of all frames
+    pub count:    usize, // number of frames
+    pub date_obs: Option<DateTime<Local>>, // start of first frame
+    pub date_end: Option<DateTime<Local>>, // end of last frame
+    #[serde(default)]
+    pub files:    Vec<String>, // names of contributing files
+}
+
+impl IntegrationMeta {
+    /// Adds light file with `info`
+    pub fn add_frame(&mut self, info: &ImageInfo, file_name: &Path) {
+        let exp = info.exp.unwrap_or(0.0);
+        self.exp_time += exp;
+        self.count += 1;
+        if let Some(start) = info.file_time {
+            let end = start + chrono::Duration::milliseconds((exp * 1000.0) as i64);
+            self.add_time_range(start, end);
+        }
+        self.files.push(extract_file_name(file_name).to_string());
+    }
+
+    /// Adds stack (or any image) with `info`. Stacks without
+    /// integration metadata are counted as single frame
+    pub fn add_stack(&mut self, info: &ImageInfo) {
+        match &info.integration {
+            Some(meta) => self.merge(meta),
+            None => self.add_frame(info, &info.file_name),
+        }
+    }
+
+    pub fn merge(&mut self, other: &IntegrationMeta) {
+        self.exp_time += other.exp_time;
+        self.count += other.count;
+        if let (Some(start), Some(end)) = (other.date_obs, other.date_end) {
+            self.add_time_range(start, end);
+        }
+        self.files.extend(other.files.iter().cloned());
+    }
+
+    fn add_time_range(&mut self, start: DateTime<Local>, end: DateTime<Local>) {
+        self.date_obs = Some(self.date_obs.map_or(start, |v| v.min(start)));
+        self.date_end = Some(self.date_end.map_or(end, |v| v.max(end)));
+    }
+
+    /// Sets EXPTIME, time of observation and metadata of result file
+    pub fn apply_to_info(&self, info: &mut ImageInfo) {
+        info.exp = Some(self.exp_time);
+        if self.date_obs.is_some() {
+            info.file_time = self.date_obs;
+        }
+        info.integration = Some(self.clone());
+    }
+
+    /// Text of HISTORY and COMMENT cards
+    pub fn history(&self) -> (String, Vec<String>) {
+        let history = format!(
+            "Integration of {} frame(s), total exposure {:.1} s",
+            self.count, self.exp_time
+        );
+        let comments = self.files.iter().map(|f| format!("Frame: {}", f)).collect();
+        (history, comments)
+    }
+}
+
+/// UTC time in format of FITS standard
+pub fn format_fits_time(time: DateTime<Local>) -> String {
+    time.with_timezone(&Utc).format("%Y-%m-%dT%H:%M:%S%.3f").to_string()
+}
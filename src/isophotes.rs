@@ -0,0 +1,263 @@
+use std::f64::consts::PI;
+use crate::{image::*, calc::*};
+
+/* Fitting of elliptical isophotes of galaxy (Jedrzejewski, 1987). Intensity
+   along ellipse is expanded into harmonics and biggest of first and second
+   harmonics is used to correct center, ellipticity or position angle until
+   harmonics are small compared to noise along the ellipse */
+
+const MAX_ITERATIONS: usize = 50;
+const CONVERGENCE: f64 = 0.05;
+const MAX_EPS: f64 = 0.95;
+
+#[derive(Clone, Debug)]
+pub struct IsophoteOpts {
+    pub center:  Option<(f64, f64)>, // brightest area near center of image if not defined
+    pub min_sma: f64, // first semi-major axis in pixels
+    pub max_sma: Option<f64>,
+    pub step:    f64, // relative increase of semi-major axis
+}
+
+impl Default for IsophoteOpts {
+    fn default() -> Self {
+        Self {
+            center: None,
+            min_sma: 5.0,
+            max_sma: None,
+            step: 0.1,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Isophote {
+    pub sma:           f64,
+    pub intensity:     f64, // mean intensity along ellipse minus background
+    pub intensity_err: f64,
+    pub eps:           f64, // ellipticity 1 - b/a
+    pub pa:            f64, // position angle of major axis relative to X axis (radians)
+    pub x0:            f64,
+    pub y0:            f64,
+    pub iterations:    usize,
+}
+
+impl Isophote {
+    /// Instrumental surface brightness in magnitudes per pixel
+    pub fn magnitude(&self) -> Option<f64> {
+        if self.intensity > 0.0 { Some(-2.5 * self.intensity.log10()) } else { None }
+    }
+
+    /// Point of ellipse for polar angle relative to major axis
+    pub fn point(&self, angle: f64) -> (f64, f64) {
+        ellipse_point(self.x0, self.y0, self.sma, self.eps, self.pa, angle)
+    }
+}
+
+pub struct IsophotesResult {
+    pub isophotes:  Vec<Isophote>,
+    pub background: f32,
+    pub noise:      f32,
+}
+
+fn ellipse_point(x0: f64, y0: f64, sma: f64, eps: f64, pa: f64, angle: f64) -> (f64, f64) {
+    let (sin_a, cos_a) = angle.sin_cos();
+    let q = 1.0 - eps;
+    let r = sma * q / ((q * cos_a).powi(2) + sin_a.powi(2)).sqrt();
+    let (sin_p, cos_p) = (angle + pa).sin_cos();
+    (x0 + r * cos_p, y0 + r * sin_p)
+}
+
+#[derive(Clone, Copy)]
+struct Geometry {
+    x0:  f64,
+    y0:  f64,
+    eps: f64,
+    pa:  f64,
+}
+
+// Values and angles along ellipse. None if ellipse leaves image
+fn sample_ellipse(layer: &ImageLayerF32, g: &Geometry, sma: f64) -> Option<(Vec<f64>, Vec<f64>)> {
+    let count = ((2.0 * PI * sma) as usize).clamp(16, 256);
+    let mut angles = Vec::with_capacity(count);
+    let mut values = Vec::with_capacity(count);
+    for i in 0..count {
+        let angle = 2.0 * PI * i as f64 / count as f64;
+        let (x, y) = ellipse_point(g.x0, g.y0, sma, g.eps, g.pa, angle);
+        let v = layer.get_f64_crd(x, y)?;
+        if !v.is_finite() || v == NO_VALUE_F32 { continue; }
+        angles.push(angle);
+        values.push(v as f64);
+    }
+    if values.len() < count * 3 / 4 { return None; }
+    Some((angles, values))
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+// Coefficients of sin(a), cos(a), sin(2a) and cos(2a) and RMS of residuals
+fn harmonics(angles: &[f64], values: &[f64]) -> ([f64; 4], f64) {
+    let m = mean(values);
+    let n = values.len() as f64;
+    let mut coeffs = [0.0; 4];
+    for (a, v) in angles.iter().zip(values) {
+        let d = v - m;
+        coeffs[0] += d * a.sin();
+        coeffs[1] += d * a.cos();
+        coeffs[2] += d * (2.0 * a).sin();
+        coeffs[3] += d * (2.0 * a).cos();
+    }
+    coeffs.iter_mut().for_each(|c| *c *= 2.0 / n);
+    let rms = (angles.iter().zip(values).map(|(a, v)| {
+        let model = m
+            + coeffs[0] * a.sin() + coeffs[1] * a.cos()
+            + coeffs[2] * (2.0 * a).sin() + coeffs[3] * (2.0 * a).cos();
+        (v - model).powi(2)
+    }).sum::<f64>() / n).sqrt();
+    (coeffs, rms)
+}
+
+fn fit_isophote(layer: &ImageLayerF32, g: &mut Geometry, sma: f64) -> Option<(f64, f64, usize)> {
+    let mut iterations = 0;
+    loop {
+        let (angles, values) = sample_ellipse(layer, g, sma)?;
+        let (_, outer_values) = sample_ellipse(layer, g, sma * 1.1)?;
+        let intensity = mean(&values);
+        let gradient = (mean(&outer_values) - intensity) / (0.1 * sma);
+        let (coeffs, rms) = harmonics(&angles, &values);
+        let intensity_err = rms / (values.len() as f64).sqrt();
+        let (index, max_coeff) = coeffs.iter()
+            .copied()
+            .enumerate()
+            .max_by(|a, b| a.1.abs().total_cmp(&b.1.abs()))
+            .unwrap();
+        iterations += 1;
+        if max_coeff.abs() < CONVERGENCE * rms || iterations >= MAX_ITERATIONS || gradient >= 0.0 {
+            return Some((intensity, intensity_err, iterations));
+        }
+        let q = 1.0 - g.eps;
+        match index {
+            0 => { // shift along minor axis
+                let aux = -max_coeff * q / gradient;
+                g.x0 -= aux * g.pa.sin();
+                g.y0 += aux * g.pa.cos();
+            }
+            1 => { // shift along major axis
+                let aux = -max_coeff / gradient;
+                g.x0 += aux * g.pa.cos();
+                g.y0 += aux * g.pa.sin();
+            }
+            2 => {
+                let denom = q * q - 1.0;
+                if denom.abs() > 1e-6 {
+                    g.pa = (g.pa + max_coeff * 2.0 * q / sma / gradient / denom).rem_euclid(PI);
+                } else {
+                    g.eps = 0.05; // circle has no position angle
+                }
+            }
+            _ => {
+                g.eps -= max_coeff * 2.0 * q / sma / gradient;
+                if g.eps < 0.0 {
+                    g.eps = (-g.eps).min(MAX_EPS);
+                    g.pa = (g.pa + PI / 2.0).rem_euclid(PI);
+                }
+                g.eps = g.eps.min(MAX_EPS);
+            }
+        }
+    }
+}
+
+// Brightest area (mean in 5x5 box) in central part of image refined by centroid
+fn find_galaxy_center(layer: &ImageLayerF32) -> (f64, f64) {
+    const R: Crd = 2;
+    let (w, h) = (layer.width(), layer.height());
+    let mut best = (f32::MIN, w / 2, h / 2);
+    for y in (h / 4).max(R)..(3 * h / 4).min(h - R) {
+        for x in (w / 4).max(R)..(3 * w / 4).min(w - R) {
+            let sum: f32 = layer.iter_rect_crd(x - R, y - R, x + R, y + R)
+                .map(|(_, _, v)| v)
+                .filter(|v| v.is_finite() && *v != NO_VALUE_F32)
+                .sum();
+            if sum > best.0 { best = (sum, x, y); }
+        }
+    }
+    let (_, cx, cy) = best;
+    let (mut sx, mut sy, mut sw) = (0.0, 0.0, 0.0);
+    for (x, y, v) in layer.iter_rect_crd((cx - 2 * R).max(0), (cy - 2 * R).max(0), (cx + 2 * R).min(w - 1), (cy + 2 * R).min(h - 1)) {
+        if !v.is_finite() || v == NO_VALUE_F32 || v <= 0.0 { continue; }
+        sx += x as f64 * v as f64;
+        sy += y as f64 * v as f64;
+        sw += v as f64;
+    }
+    if sw > 0.0 { (sx / sw, sy / sw) } else { (cx as f64, cy as f64) }
+}
+
+fn background_and_noise(layer: &ImageLayerF32) -> (f32, f32) {
+    let step = (layer.as_slice().len() / 200_000).max(1);
+    let mut values: Vec<f32> = layer.as_slice()
+        .iter()
+        .step_by(step)
+        .copied()
+        .filter(|v| v.is_finite() && *v != NO_VALUE_F32)
+        .collect();
+    let background = median_f32(&mut values).unwrap_or(0.0);
+    values.iter_mut().for_each(|v| *v = (*v - background).abs());
+    let noise = median_f32(&mut values).unwrap_or(0.0) * 1.4826;
+    (background, noise)
+}
+
+/// Fits isophotes from center to outer parts until intensity falls
+/// to noise level of background or ellipse leaves image
+pub fn fit_isophotes(layer: &ImageLayerF32, opts: &IsophoteOpts) -> anyhow::Result<IsophotesResult> {
+    if layer.is_empty() {
+        anyhow::bail!("Image is empty");
+    }
+    let (background, noise) = background_and_noise(layer);
+    let (x0, y0) = opts.center.unwrap_or_else(|| find_galaxy_center(layer));
+    log::info!("Isophotes center = ({:.1}, {:.1}), background = {}, noise = {}", x0, y0, background, noise);
+    let max_sma = opts.max_sma
+        .unwrap_or(f64::MAX)
+        .min(layer.width().max(layer.height()) as f64);
+    let mut geometry = Geometry { x0, y0, eps: 0.2, pa: 0.0 };
+    let mut isophotes = Vec::new();
+    let mut sma = opts.min_sma.max(1.0);
+    while sma <= max_sma {
+        let start = geometry;
+        let Some((intensity, intensity_err, iterations)) = fit_isophote(layer, &mut geometry, sma) else {
+            break;
+        };
+        let intensity = intensity - background as f64;
+        if intensity < noise as f64 { break; }
+        // geometry of diverged fit is not used for next isophote
+        if (geometry.x0 - start.x0).hypot(geometry.y0 - start.y0) > sma {
+            geometry = start;
+        }
+        isophotes.push(Isophote {
+            sma, intensity, intensity_err, iterations,
+            eps: geometry.eps,
+            pa:  geometry.pa,
+            x0:  geometry.x0,
+            y0:  geometry.y0,
+        });
+        sma *= 1.0 + opts.step.max(0.01);
+    }
+    if isophotes.is_empty() {
+        anyhow::bail!("No isophotes found (center is too dim or out of image)");
+    }
+    Ok(IsophotesResult { isophotes, background, noise })
+}
+
+/// Draws ellipses into RGB bytes of image
+pub fn draw_isophotes(rgb: &mut [u8], width: usize, height: usize, isophotes: &[Isophote]) {
+    for isophote in isophotes {
+        let count = ((4.0 * PI * isophote.sma) as usize).max(32);
+        for i in 0..count {
+            let (x, y) = isophote.point(2.0 * PI * i as f64 / count as f64);
+            let (x, y) = (x.round(), y.round());
+            if x < 0.0 || y < 0.0 || x >= width as f64 || y >= height as f64 { continue; }
+            let pos = 3 * (y as usize * width + x as usize);
+            rgb[pos..pos + 3].copy_from_slice(&[255, 64, 64]);
+        }
+    }
+}
@@ -0,0 +1,322 @@
+use std::{path::*, sync::{Arc, atomic::{AtomicBool, Ordering}}, time::Duration};
+use serde::*;
+use chrono::prelude::*;
+use crate::{batch::*, config::*, fs_utils::*, report};
+
+/* Queue of batch jobs. Jobs are kept as files in application directory
+   so they can be added, listed and cancelled while jobs server is running.
+   Every read-modify-write of job files is done under lock file of jobs
+   directory so commands and server don't overwrite changes of each other */
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum JobState {
+    Queued,
+    Running,
+    Done,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Job {
+    pub id:       u64,
+    pub priority: i32, // greater value is processed first
+    pub args:     Vec<String>, // command line of --run
+    pub work_dir: PathBuf,
+    pub state:    JobState,
+    pub added:    DateTime<Local>,
+    pub started:  Option<DateTime<Local>>,
+    pub finished: Option<DateTime<Local>>,
+    pub message:  String,
+}
+
+impl Job {
+    fn file_name(id: u64) -> anyhow::Result<PathBuf> {
+        Ok(get_jobs_dir()?.join(format!("{:06}.json", id)))
+    }
+
+    fn load(id: u64) -> anyhow::Result<Job> {
+        let text = std::fs::read_to_string(Self::file_name(id)?)?;
+        Ok(serde_json::from_str(&text)?)
+    }
+
+    fn save(&self) -> anyhow::Result<()> {
+        let text = serde_json::to_string_pretty(self)?;
+        write_file_atomically(&Self::file_name(self.id)?, |file_name| {
+            Ok(std::fs::write(file_name, &text)?)
+        })
+    }
+}
+
+fn get_jobs_dir() -> anyhow::Result<PathBuf> {
+    let dir = get_app_conf_dir(true)?.join("jobs");
+    if !dir.is_dir() {
+        std::fs::create_dir(&dir)?;
+    }
+    Ok(dir)
+}
+
+/// Lock of jobs directory. Lock file is removed on drop
+struct JobsLock(PathBuf);
+
+impl JobsLock {
+    fn acquire() -> anyhow::Result<JobsLock> {
+        // lock of crashed process is stale after this time
+        const STALE_TIMEOUT: Duration = Duration::from_secs(60);
+        const WAIT_TIMEOUT: Duration = Duration::from_secs(30);
+        let file_name = get_jobs_dir()?.join("jobs.lock");
+        let start = std::time::Instant::now();
+        loop {
+            match std::fs::OpenOptions::new().write(true).create_new(true).open(&file_name) {
+                Ok(_) => return Ok(JobsLock(file_name)),
+                Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+                    let is_stale = std::fs::metadata(&file_name)
+                        .and_then(|m| m.modified())
+                        .ok()
+                        .and_then(|t| t.elapsed().ok())
+                        .map(|age| age > STALE_TIMEOUT)
+                        .unwrap_or(false);
+                    if is_stale {
+                        log::warn!("Stale lock file {:?} is removed", file_name);
+                        _ = std::fs::remove_file(&file_name);
+                        continue;
+                    }
+                    if start.elapsed() > WAIT_TIMEOUT {
+                        anyhow::bail!("Can't lock jobs directory (remove {:?} if no jobs command is running)", file_name);
+                    }
+                    std::thread::sleep(Duration::from_millis(50));
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+}
+
+impl Drop for JobsLock {
+    fn drop(&mut self) {
+        _ = std::fs::remove_file(&self.0);
+    }
+}
+
+fn load_all_jobs() -> anyhow::Result<Vec<Job>> {
+    let mut result = Vec::new();
+    for entry in std::fs::read_dir(get_jobs_dir()?)?.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        if extract_extension(&path) != "json" { continue; }
+        let text = std::fs::read_to_string(&path)?;
+        match serde_json::from_str::<Job>(&text) {
+            Ok(job) => result.push(job),
+            Err(err) => log::error!("Can't read job {:?}: {}", path, err),
+        }
+    }
+    result.sort_by_key(|job| job.id);
+    Ok(result)
+}
+
+pub enum JobsCommand {
+    Add { priority: i32, args: Vec<String> },
+    List,
+    Cancel(u64),
+    Server { interval: u64 },
+}
+
+impl JobsCommand {
+    pub fn from_cmd_line(args: &[String]) -> anyhow::Result<Option<JobsCommand>> {
+        let usage = || anyhow::anyhow!(
+            "Usage:\n  {0} --jobs-add [--priority <value>] <project file> [options of --run]\n  \
+            {0} --jobs-list\n  \
+            {0} --jobs-cancel <job id>\n  \
+            {0} --jobs-server [--interval <seconds>]",
+            env!("CARGO_PKG_NAME")
+        );
+        let rest = args.get(2..).unwrap_or_default();
+        let command = match args.get(1).map(|s| s.as_str()) {
+            Some("--jobs-add") => {
+                let mut priority = 0;
+                let mut run_args = vec!["--run".to_string()];
+                let mut iter = rest.iter();
+                while let Some(arg) = iter.next() {
+                    if arg == "--priority" {
+                        priority = iter.next().ok_or_else(usage)?.parse()?;
+                    } else {
+                        run_args.push(arg.clone());
+                    }
+                }
+                // checks arguments before adding into queue
+                let mut check_args = vec![String::new()];
+                check_args.extend(run_args.iter().cloned());
+                BatchArgs::from_cmd_line(&check_args)?;
+                JobsCommand::Add { priority, args: run_args }
+            }
+            Some("--jobs-list") => JobsCommand::List,
+            Some("--jobs-cancel") => {
+                let id = rest.first().ok_or_else(usage)?.parse()?;
+                JobsCommand::Cancel(id)
+            }
+            Some("--jobs-server") => {
+                let interval = match rest {
+                    [] => 5,
+                    [opt, value] if opt == "--interval" => value.parse()?,
+                    _ => return Err(usage()),
+                };
+                JobsCommand::Server { interval }
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(command))
+    }
+}
+
+pub fn run_jobs_command(command: &JobsCommand) -> anyhow::Result<()> {
+    match command {
+        JobsCommand::Add { priority, args } => add_job(*priority, args),
+        JobsCommand::List => list_jobs(),
+        JobsCommand::Cancel(id) => cancel_job(*id),
+        JobsCommand::Server { interval } => run_jobs_server(*interval),
+    }
+}
+
+fn add_job(priority: i32, args: &[String]) -> anyhow::Result<()> {
+    let lock = JobsLock::acquire()?;
+    let id = load_all_jobs()?.iter().map(|job| job.id).max().unwrap_or(0) + 1;
+    let job = Job {
+        id,
+        priority,
+        args: args.to_vec(),
+        work_dir: std::env::current_dir()?,
+        state: JobState::Queued,
+        added: Local::now(),
+        started: None,
+        finished: None,
+        message: String::new(),
+    };
+    job.save()?;
+    drop(lock);
+    report!("Job {} is added", id);
+    Ok(())
+}
+
+fn list_jobs() -> anyhow::Result<()> {
+    let time_str = |time: Option<DateTime<Local>>| {
+        time.map(|t| t.format("%Y-%m-%d %H:%M").to_string()).unwrap_or_default()
+    };
+    println!(
+        "{:>6} {:>8} {:<10} {:<16} {:<16} {:<16} command",
+        "id", "priority", "state", "added", "started", "finished"
+    );
+    for job in load_all_jobs()? {
+        println!(
+            "{:>6} {:>8} {:<10} {:<16} {:<16} {:<16} {}",
+            job.id, job.priority, format!("{:?}", job.state),
+            time_str(Some(job.added)), time_str(job.started), time_str(job.finished),
+            job.args.join(" ")
+        );
+        if !job.message.is_empty() {
+            println!("{:>6} {}", "", job.message);
+        }
+    }
+    Ok(())
+}
+
+fn cancel_job(id: u64) -> anyhow::Result<()> {
+    let _lock = JobsLock::acquire()?;
+    let mut job = Job::load(id)?;
+    match job.state {
+        JobState::Queued|JobState::Running => {
+            job.state = JobState::Cancelled;
+            job.save()?;
+            report!("Job {} is cancelled", id);
+        }
+        _ => anyhow::bail!("Job {} is already finished", id),
+    }
+    Ok(())
+}
+
+fn run_job(job: &Job) -> anyhow::Result<()> {
+    let mut cmd_line = vec![String::new()];
+    cmd_line.extend(job.args.iter().cloned());
+    let mut args = BatchArgs::from_cmd_line(&cmd_line)?
+        .ok_or_else(|| anyhow::anyhow!("Wrong job command line"))?;
+
+    // job is cancelled by changing state in its file
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let finished = Arc::new(AtomicBool::new(false));
+    let checker = {
+        let (cancelled, finished, id) = (Arc::clone(&cancelled), Arc::clone(&finished), job.id);
+        std::thread::spawn(move || {
+            while !finished.load(Ordering::Relaxed) {
+                if matches!(Job::load(id), Ok(job) if job.state == JobState::Cancelled) {
+                    cancelled.store(true, Ordering::Relaxed);
+                    break;
+                }
+                std::thread::sleep(Duration::from_secs(1));
+            }
+        })
+    };
+    let cancelled_fun = Arc::clone(&cancelled);
+    args.cancel_flag = Arc::new(move || cancelled_fun.load(Ordering::Relaxed));
+
+    std::env::set_current_dir(&job.work_dir)?;
+    let result = run_batch(&args);
+
+    finished.store(true, Ordering::Relaxed);
+    _ = checker.join();
+    result
+}
+
+fn run_jobs_server(interval: u64) -> anyhow::Result<()> {
+    report!("Jobs server is started (press Ctrl+C to stop)...");
+
+    // Jobs interrupted by previous server are started again
+    let lock = JobsLock::acquire()?;
+    for mut job in load_all_jobs()?.into_iter().filter(|j| j.state == JobState::Running) {
+        job.state = JobState::Queued;
+        job.save()?;
+    }
+    drop(lock);
+
+    loop {
+        let lock = JobsLock::acquire()?;
+        let next_job = load_all_jobs()?
+            .into_iter()
+            .filter(|job| job.state == JobState::Queued)
+            .min_by_key(|job| (-job.priority, job.id));
+
+        let Some(mut job) = next_job else {
+            drop(lock);
+            std::thread::sleep(Duration::from_secs(interval.max(1)));
+            continue;
+        };
+
+        job.state = JobState::Running;
+        job.started = Some(Local::now());
+        job.save()?;
+        drop(lock);
+
+        report!();
+        report!("Job {}: {}", job.id, job.args.join(" "));
+        log::info!("Job {} started", job.id);
+
+        let result = run_job(&job);
+
+        let _lock = JobsLock::acquire()?;
+        let mut job = Job::load(job.id).unwrap_or(job);
+        job.finished = Some(Local::now());
+        match result {
+            _ if job.state == JobState::Cancelled => {
+                job.message = "Cancelled by user".to_string();
+            }
+            Ok(_) => {
+                job.state = JobState::Done;
+                job.message.clear();
+            }
+            Err(err) => {
+                log::error!("Job {}: {}", job.id, err.to_string());
+                report!("Job {} failed: {}", job.id, err.to_string());
+                job.state = JobState::Failed;
+                job.message = err.to_string();
+            }
+        }
+        job.save()?;
+    }
+}
@@ -0,0 +1,89 @@
+//! Stacking engine of Electra Stacking: reading of RAW, FITS, TIFF and
+//! XISF files, calibration, registration by stars and integration of
+//! light frames, post processing of stacked images.
+//!
+//! [`Calibrator`], [`Registrar`] and [`Integrator`] of [`pipeline`] are
+//! entry points for embedding of stacking into other programs (capture
+//! software or GUIs) without running of command line tool. Projects with
+//! groups of files and master calibration files are in [`project`].
+
+#![allow(clippy::too_many_arguments)]
+#![allow(clippy::needless_range_loop)]
+#![allow(clippy::new_without_default)]
+
+pub mod image;
+pub mod image_norm;
+pub mod image_raw;
+pub mod cameras_database;
+pub mod image_io;
+pub mod ser;
+pub mod planetary;
+pub mod deconv;
+pub mod hdr;
+pub mod wavelets;
+pub mod star_mask;
+pub mod bg_mask;
+pub mod xisf;
+pub mod safe_read;
+pub mod preview;
+pub mod timelapse;
+pub mod image_diff;
+pub mod photometry;
+pub mod star_shape;
+pub mod image_stat;
+pub mod noise;
+pub mod color_calibr;
+pub mod gradient;
+pub mod scnr;
+pub mod wcs;
+pub mod airmass;
+pub mod obs_report;
+pub mod integration_meta;
+pub mod plate_solve;
+pub mod apply_transform;
+pub mod mosaic;
+pub mod duoband;
+pub mod siril_script;
+pub mod dss_filelist;
+pub mod auto_groups;
+pub mod cal_library;
+pub mod ha_blend;
+pub mod lrgb;
+pub mod resample;
+pub mod geometry;
+pub mod colormap;
+pub mod exposure_map;
+pub mod isophotes;
+pub mod sky_flat;
+pub mod sky_limit;
+pub mod perf_report;
+pub mod run_report;
+pub mod report_fmt;
+pub mod light_file;
+pub mod fs_utils;
+pub mod log_utils;
+pub mod calc;
+pub mod gpu;
+pub mod stars;
+pub mod field_rotation;
+pub mod suggest;
+pub mod dataset_check;
+mod tests;
+mod golden;
+pub mod progress;
+pub mod compression;
+pub mod stacking_utils;
+pub mod pipeline;
+pub mod ffi;
+pub mod config;
+pub mod config_layers;
+pub mod power;
+pub mod plugins;
+pub mod presets;
+pub mod project;
+pub mod str_utils;
+
+pub use image::Image;
+pub use calc::{CalcOpts, CalcMode};
+pub use light_file::LightFile;
+pub use pipeline::{Calibrator, Registrar, Integrator, AlignedFrame};
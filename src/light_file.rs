@@ -2,7 +2,7 @@ use std::path::*;
 use serde::*;
 use bitflags::bitflags;
 use itertools::Itertools;
-use crate::{image::*, image_io::*, image_raw::*, stars::*, log_utils::*, calc::*};
+use crate::{image::*, image_io::*, image_raw::*, stars::*, log_utils::*, calc::*, resample::*, noise::*};
 
 
 bitflags! { pub struct LoadLightFlags: u32 {
@@ -26,7 +26,8 @@ pub struct LightFile {
     pub info:       ImageInfo,
     pub stars:      Stars,
     pub stars_stat: anyhow::Result<StarsStat>,
-    pub noise:      f32,
+    pub noise:      f32, // for stars detection
+    pub noise_est:  f32, // sigma of gaussian noise by k-sigma method
     pub background: f32,
 }
 
@@ -36,6 +37,8 @@ pub struct RawOpenParams {
     pub apply_wb: bool,
     pub apply_color: bool,
     pub force_cfa: Option<CfaType>,
+    pub optimize_dark: bool, // scale master dark for each light file
+    pub fits_hdu: String, // index or EXTNAME of image HDU of FITS files, empty for first image HDU
 }
 
 impl Default for RawOpenParams {
@@ -44,10 +47,18 @@ impl Default for RawOpenParams {
             apply_wb: true,
             apply_color: false,
             force_cfa: None,
+            optimize_dark: false,
+            fits_hdu: String::new(),
         }
     }
 }
 
+impl RawOpenParams {
+    pub fn fits_hdu_selector(&self) -> Option<FitsHduSelector> {
+        FitsHduSelector::from_str(&self.fits_hdu)
+    }
+}
+
 impl LightFile {
     pub fn load_and_calc_params(
         file_name:   &Path,
@@ -56,6 +67,7 @@ impl LightFile {
         open_mode:   OpenMode,
         bin:         usize,
         raw_params:  &RawOpenParams,
+        stars_opts:  &StarsFindOpts,
     ) -> anyhow::Result<LightFile> {
         log::info!(
             "LightFile::load_and_calc_params: file_name={}, flags={:?}, open_mode={:?}, bin={}",
@@ -72,14 +84,18 @@ impl LightFile {
         let force_load_as_raw = !cal_data.is_empty() || raw_params.force_cfa.is_some();
 
         let tmr = TimeLogger::start();
-        let image_data = load_image_from_file(file_name, force_load_as_raw)?;
+        let image_data = load_src_image_from_file(
+            file_name,
+            force_load_as_raw,
+            raw_params.fits_hdu_selector().as_ref()
+        )?;
         tmr.log("loading image from file");
 
         let (mut image, mut overexposures) = match image_data.image {
             RawOrImage::Image(image) =>
                 (image, Vec::new()),
             RawOrImage::Raw(mut raw) => {
-                let demosaic = if bin == 2 {
+                let demosaic = if bin >= 2 {
                     DemosaicAlgo::Linear
                 } else { match open_mode {
                     OpenMode::Preview =>
@@ -98,7 +114,7 @@ impl LightFile {
 
                 raw.extract_black();
 
-                raw.calibrate(cal_data)?;
+                raw.calibrate_at(cal_data, raw_params.optimize_dark, image_data.info.file_time)?;
 
 
                 let mut result = if !do_not_demosaic_flag {
@@ -156,6 +172,15 @@ impl LightFile {
             0.0
         };
 
+        let noise_est = if flags.contains(LoadLightFlags::NOISE) {
+            let noise_log = TimeLogger::start();
+            let result = estimate_noise_k_sigma(img_layer_to_calc);
+            noise_log.log("k-sigma noise estimation");
+            result
+        } else {
+            0.0
+        };
+
         let background = if flags.contains(LoadLightFlags::BACKGROUND) {
             let bg_log = TimeLogger::start();
             let result = calc_background(img_layer_to_calc);
@@ -165,10 +190,10 @@ impl LightFile {
             0.0
         };
 
-        if bin == 2 {
+        if bin >= 2 {
             let bin_log = TimeLogger::start();
-            image = image.decrease_2x();
-            bin_log.log("decreasing image 2x");
+            image = bin_image(&image, bin, BinMode::Average);
+            bin_log.log(&format!("binning {}x{}", bin, bin));
         }
 
         let img_layer_to_calc = if image.is_greyscale() {
@@ -182,7 +207,8 @@ impl LightFile {
             let result = find_stars_on_image(
                 &img_layer_to_calc,
                 Some(noise),
-                flags.contains(LoadLightFlags::NO_ERR_IF_NO_STARS)
+                flags.contains(LoadLightFlags::NO_ERR_IF_NO_STARS),
+                stars_opts
             )?;
             stars_log.log("looking for stars on image");
             log::info!("stars count = {}", result.len());
@@ -193,10 +219,10 @@ impl LightFile {
 
         let stars_stat = calc_stars_stat(&stars, &img_layer_to_calc, open_mode == OpenMode::Preview);
 
-        if bin == 2 {
+        if bin >= 2 {
             for (x, y) in &mut overexposures {
-                *x /= 2;
-                *y /= 2;
+                *x /= bin as Crd;
+                *y /= bin as Crd;
             }
         }
         image.mark_overexposures(&overexposures);
@@ -210,6 +236,7 @@ impl LightFile {
             stars_stat,
             background,
             noise,
+            noise_est,
         })
     }
 }
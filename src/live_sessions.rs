@@ -0,0 +1,207 @@
+use std::{path::*, sync::*, collections::HashMap, time::Duration, io::{stdout, Write, IsTerminal}};
+use crate::{batch::*, config::*, progress::*, project::*, log_utils::*, report};
+
+/* Several watching sessions at the same time (dual-rig setups). Sessions
+   share CPU threads and only limited number of them can process files
+   simultaneously so they don't starve each other and don't eat all memory */
+
+pub struct WatchSession {
+    pub project_file: PathBuf,
+    pub watch_dir:    PathBuf,
+}
+
+impl WatchSession {
+    /// "path/to/project.es_proj=path/to/captured/lights"
+    pub fn from_str(text: &str) -> anyhow::Result<Self> {
+        let (project_file, watch_dir) = text.split_once('=').ok_or_else(||
+            anyhow::anyhow!("Wrong session {} (<project file>=<capture directory> expected)", text)
+        )?;
+        Ok(Self {
+            project_file: PathBuf::from(project_file),
+            watch_dir: PathBuf::from(watch_dir),
+        })
+    }
+
+    fn name(&self) -> String {
+        self.project_file
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or("")
+            .to_string()
+    }
+}
+
+struct ProcessingSlots {
+    free: Mutex<usize>,
+    cond: Condvar,
+}
+
+struct ProcessingSlotGuard<'a>(&'a ProcessingSlots);
+
+impl ProcessingSlots {
+    fn new(count: usize) -> Self {
+        Self { free: Mutex::new(count.max(1)), cond: Condvar::new() }
+    }
+
+    fn acquire(&self) -> ProcessingSlotGuard {
+        let mut free = self.free.lock().unwrap();
+        while *free == 0 {
+            free = self.cond.wait(free).unwrap();
+        }
+        *free -= 1;
+        ProcessingSlotGuard(self)
+    }
+}
+
+impl Drop for ProcessingSlotGuard<'_> {
+    fn drop(&mut self) {
+        *self.0.free.lock().unwrap() += 1;
+        self.0.cond.notify_one();
+    }
+}
+
+#[derive(Default, Clone, PartialEq)]
+struct SessionStatus {
+    stage:   String,
+    percent: Option<usize>,
+    ready:   usize,
+}
+
+type SessionsStatus = Arc<Mutex<Vec<SessionStatus>>>;
+
+fn set_status(status: &SessionsStatus, index: usize, fun: impl FnOnce(&mut SessionStatus)) {
+    fun(&mut status.lock().unwrap()[index]);
+}
+
+fn process_session(
+    session:  &WatchSession,
+    index:    usize,
+    config:   &Config,
+    interval: u64,
+    slots:    &ProcessingSlots,
+    status:   &SessionsStatus,
+) -> anyhow::Result<()> {
+    let mut project = Project::default();
+    if session.project_file.is_file() {
+        project.load(&session.project_file)?;
+    }
+    project.add_default_group_if_empty();
+
+    let progress = {
+        let status1 = Arc::clone(status);
+        let status2 = Arc::clone(status);
+        ProgressCallBack::new_ts(
+            move |text| set_status(&status1, index, |s| {
+                s.stage = text.to_string();
+                s.percent = None;
+            }),
+            move |percent, _, _| set_status(&status2, index, |s| s.percent = Some(percent)),
+        )
+    };
+    let cancel_flag: IsCancelledFun = Arc::new(|| false);
+    let name = session.name();
+
+    let mut prev_sizes = HashMap::new();
+    loop {
+        let mut new_files = find_completely_written_files(&session.watch_dir, &mut prev_sizes)?;
+        project.groups()[0].light_files.retain_files_if_they_are_not_here(&mut new_files);
+
+        if !new_files.is_empty() {
+            set_status(status, index, |s| {
+                s.stage = format!("{} new file(s) are waiting", new_files.len());
+                s.percent = None;
+            });
+            let report = {
+                let _slot = slots.acquire();
+                add_and_prepare_light_files(
+                    &mut project,
+                    &session.project_file,
+                    new_files,
+                    config,
+                    &progress,
+                    &cancel_flag
+                )?
+            };
+            let mut out = stdout().lock();
+            writeln!(out)?;
+            for line in report {
+                writeln!(out, "[{}] {}", name, line)?;
+            }
+            let ready = project.groups()[0].light_files.get_checked_count();
+            set_status(status, index, |s| {
+                s.stage = "watching".to_string();
+                s.percent = None;
+                s.ready = ready;
+            });
+        }
+
+        std::thread::sleep(Duration::from_secs(interval.max(1)));
+    }
+}
+
+pub fn watch_multiple_dirs(
+    args:         &BatchArgs,
+    sessions:     &[WatchSession],
+    max_parallel: Option<usize>,
+) -> anyhow::Result<()> {
+    let mut config = load_config(args)?;
+    let max_parallel = max_parallel.unwrap_or(sessions.len()).clamp(1, sessions.len());
+
+    // CPU threads are divided between sessions processing files simultaneously
+    let threads = (config.effective_cpu_load().to_threads_count() / max_parallel).max(1);
+    config.cpu_load = CpuLoad::CustomCPUs(threads);
+    log::info!(
+        "Watching of {} sessions started ({} in parallel, {} threads each)",
+        sessions.len(), max_parallel, threads
+    );
+
+    let slots = ProcessingSlots::new(max_parallel);
+    let status: SessionsStatus = Arc::new(Mutex::new(vec![
+        SessionStatus { stage: "watching".to_string(), ..Default::default() };
+        sessions.len()
+    ]));
+
+    report!("Watching {} sessions (press Ctrl+C to stop)...", sessions.len());
+    std::thread::scope(|scope| {
+        for (index, session) in sessions.iter().enumerate() {
+            let (config, slots, status) = (&config, &slots, &status);
+            scope.spawn(move || {
+                // Error in one session doesn't stop others
+                loop {
+                    let res = process_session(session, index, config, args.interval, slots, status);
+                    if let Err(err) = res {
+                        log::error!("{}: {}", session.name(), err.to_string());
+                        report!("[{}] {}", session.name(), err.to_string());
+                        set_status(status, index, |s| s.stage = format!("error: {}", err));
+                    }
+                    std::thread::sleep(Duration::from_secs(args.interval.max(1)));
+                }
+            });
+        }
+
+        // Combined status of all sessions
+        let is_terminal = stdout().is_terminal();
+        let mut prev_status = Vec::new();
+        loop {
+            let cur_status = status.lock().unwrap().clone();
+            if cur_status != prev_status {
+                let line = sessions.iter()
+                    .zip(&cur_status)
+                    .map(|(session, s)| {
+                        let percent = s.percent.map(|p| format!(" {}%", p)).unwrap_or_default();
+                        format!("[{}] {}{} ({} files)", session.name(), s.stage, percent, s.ready)
+                    })
+                    .collect::<Vec<_>>()
+                    .join(" | ");
+                if is_terminal && !is_quiet() {
+                    print!("{}          \r", line);
+                    _ = stdout().flush();
+                } else {
+                    report!("{}", line);
+                }
+                prev_status = cur_status;
+            }
+            std::thread::sleep(Duration::from_millis(500));
+        }
+    })
+}
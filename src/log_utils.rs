@@ -1,6 +1,98 @@
-use std::path::*;
+use std::{path::*, sync::atomic::{AtomicBool, Ordering}};
 use flexi_logger::*;
 
+/* Logging options of command line. They are global options placed before
+   command of batch mode or jobs (or without command for GUI), so values
+   of other options are never taken as them:
+
+   -v, -vv            debug and trace messages (per frame decisions,
+                      timings) in log and in console
+   -q, --quiet        no messages in console except errors
+   --log-file <file>  log file instead of default one in config directory */
+
+#[derive(Default, Debug, Clone)]
+pub struct LogOpts {
+    pub verbosity: u8, // 0 - info, 1 - debug, 2 - trace
+    pub quiet:     bool,
+    pub log_file:  Option<PathBuf>,
+}
+
+impl LogOpts {
+    pub fn is_option(arg: &str) -> bool {
+        matches!(arg, "-v" | "-vv" | "-q" | "--quiet" | "--log-file")
+    }
+
+    /// Takes logging options from beginning of command line
+    /// (after program name). They are removed from `args`
+    pub fn from_cmd_line(args: &mut Vec<String>) -> anyhow::Result<Self> {
+        let mut result = Self::default();
+        while let Some(arg) = args.get(1) {
+            match arg.as_str() {
+                "-v" => result.verbosity = result.verbosity.max(1),
+                "-vv" => result.verbosity = 2,
+                "-q" | "--quiet" => result.quiet = true,
+                "--log-file" => {
+                    let file = args.get(2)
+                        .ok_or_else(|| anyhow::anyhow!("Log file name is not defined"))?;
+                    result.log_file = Some(PathBuf::from(file));
+                    args.remove(2);
+                },
+                _ => break,
+            }
+            args.remove(1);
+        }
+        if result.quiet && result.verbosity != 0 {
+            anyhow::bail!("--quiet can't be used together with -v or -vv");
+        }
+        Ok(result)
+    }
+
+    fn level_str(&self) -> &'static str {
+        match self.verbosity {
+            0 => "info",
+            1 => "debug",
+            _ => "trace",
+        }
+    }
+
+    fn console_level(&self) -> Duplicate {
+        match (self.quiet, self.verbosity) {
+            (true, _) => Duplicate::Error,
+            (_, 0)    => Duplicate::Warn,
+            (_, 1)    => Duplicate::Info,
+            _         => Duplicate::All,
+        }
+    }
+}
+
+static QUIET: AtomicBool = AtomicBool::new(false);
+static INFO_IN_CONSOLE: AtomicBool = AtomicBool::new(false);
+
+/// Console output is disabled by `--quiet`
+pub fn is_quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+/// Info messages of log are duplicated into console by `-v` or `-vv`
+pub fn is_info_in_console() -> bool {
+    INFO_IN_CONSOLE.load(Ordering::Relaxed)
+}
+
+/// Message for user of command line. It is written into log and is
+/// printed into stdout if `--quiet` is not used and log doesn't show
+/// it in console already (`-v`), so message is printed once
+#[macro_export]
+macro_rules! report {
+    () => {
+        if !$crate::log_utils::is_quiet() && !$crate::log_utils::is_info_in_console() { println!(); }
+    };
+    ($($arg:tt)*) => {{
+        let text = format!($($arg)*);
+        log::info!("{}", text);
+        if !$crate::log_utils::is_quiet() && !$crate::log_utils::is_info_in_console() { println!("{}", text); }
+    }};
+}
+
 pub struct TimeLogger {
     start_time: std::time::Instant,
 }
@@ -12,11 +104,16 @@ impl TimeLogger {
 
     pub fn log(self, text: &str) {
         let time = self.start_time.elapsed().as_secs_f64();
-        log::info!("BENCH {} time = {:.6} s", text, time);
+        log::debug!("BENCH {} time = {:.6} s", text, time);
     }
 }
 
-pub fn start_logger(log_path: &Path) -> anyhow::Result<()> {
+/// Starts logging into file of `log_path` directory (or into file
+/// from `opts`). Warnings and errors are duplicated into stderr
+pub fn start_logger(log_path: &Path, opts: &LogOpts) -> anyhow::Result<()> {
+    QUIET.store(opts.quiet, Ordering::Relaxed);
+    INFO_IN_CONSOLE.store(!opts.quiet && opts.verbosity != 0, Ordering::Relaxed);
+
     let custom_format_fun = |
         w:      &mut dyn std::io::Write,
         now:    &mut DeferredNow,
@@ -30,15 +127,28 @@ pub fn start_logger(log_path: &Path) -> anyhow::Result<()> {
         )
     };
 
-    Logger::try_with_str("info")?
-        .log_to_file(
-            FileSpec::default()
-                .directory(log_path)
-                .basename(env!("CARGO_PKG_NAME"))
-        )
+    let console_format_fun = |
+        w:      &mut dyn std::io::Write,
+        _now:   &mut DeferredNow,
+        record: &Record
+    | -> Result<(), std::io::Error> {
+        write!(w, "{}: {}", record.level(), record.args())
+    };
+
+    let file_spec = match &opts.log_file {
+        Some(log_file) => FileSpec::try_from(log_file)?.suppress_timestamp(),
+        None => FileSpec::default()
+            .directory(log_path)
+            .basename(env!("CARGO_PKG_NAME")),
+    };
+
+    let logger = Logger::try_with_str(opts.level_str())?
+        .log_to_file(file_spec)
         .format(custom_format_fun)
-        .print_message()
-        .start()?;
+        .duplicate_to_stderr(opts.console_level())
+        .format_for_stderr(console_format_fun);
+    let logger = if opts.log_file.is_none() { logger.print_message() } else { logger };
+    logger.start()?;
 
     Ok(())
 }
@@ -0,0 +1,382 @@
+use rayon::prelude::*;
+use crate::{image::*, calc::*, ha_blend::*};
+
+/* Merging of aligned mono stacks of filters into color image. Luminance
+   of RGB is replaced by L: L is scaled to luminance of RGB and every
+   pixel of RGB is multiplied by ratio of L to RGB luminance (softened
+   near background by noise level so noise of background is not boosted).
+   Narrowband stacks are blended into channels as in HaRGB. Cores of bright
+   stars clipped in some of channels are white or miscolored, so their color
+   can be reconstructed before merging: fractions of channels in unclipped
+   periphery of star are fitted by distance from center of star and
+   extrapolated into core */
+
+/// Part of maximum of channel above which values can be clipped
+const SATURATION_LEVEL: f32 = 0.98;
+
+/// Overexposed values of stacking result (white point)
+const WHITE_POINT: f32 = 1.0;
+
+/// Area near maximum of channel is clipped only if it has flat top:
+/// at least `MIN_PLATEAU_PIXELS` values within `PLATEAU_TOLERANCE` (part
+/// of top value) of top value, or it contains overexposed values. Peak
+/// of unsaturated star has one or two pixels so close to its top
+const MIN_PLATEAU_PIXELS: usize = 4;
+const PLATEAU_TOLERANCE: f32 = 0.002;
+
+/// Larger clipped areas are not cores of stars
+const MAX_CORE_RADIUS: f32 = 64.0;
+
+/// Minimum of periphery pixels for fit of color
+const MIN_PERIPHERY_PIXELS: usize = 6;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LrgbChannel {
+    L,
+    R,
+    G,
+    B,
+    Ha,
+    Oiii,
+    Sii,
+}
+
+impl LrgbChannel {
+    pub const ALL: [LrgbChannel; 7] = [
+        LrgbChannel::L, LrgbChannel::R, LrgbChannel::G, LrgbChannel::B,
+        LrgbChannel::Ha, LrgbChannel::Oiii, LrgbChannel::Sii,
+    ];
+
+    /// Name is used as name of group in project
+    pub fn name(self) -> &'static str {
+        match self {
+            LrgbChannel::L    => "L",
+            LrgbChannel::R    => "R",
+            LrgbChannel::G    => "G",
+            LrgbChannel::B    => "B",
+            LrgbChannel::Ha   => "Ha",
+            LrgbChannel::Oiii => "OIII",
+            LrgbChannel::Sii  => "SII",
+        }
+    }
+
+    pub fn is_narrowband(self) -> bool {
+        matches!(self, LrgbChannel::Ha|LrgbChannel::Oiii|LrgbChannel::Sii)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct LrgbOpts {
+    pub lum_weight: f32, // 0 - luminance of RGB, 1 - luminance of L
+    pub nb_blend:   HaBlendOpts,
+    pub reconstruct_cores:  bool, // color of clipped star cores by their periphery
+    pub desaturate_clipped: bool, // clipped areas which are not reconstructed become neutral
+}
+
+impl Default for LrgbOpts {
+    fn default() -> Self {
+        Self {
+            lum_weight: 1.0,
+            nb_blend:   HaBlendOpts::default(),
+            reconstruct_cores:  false,
+            desaturate_clipped: false,
+        }
+    }
+}
+
+pub struct LrgbResult {
+    pub image:     Image,
+    pub lum_scale: Option<f32>, // factor of L to luminance of RGB
+    pub cores:     ClippedCores,
+}
+
+/// Counts of clipped areas of RGB
+#[derive(Clone, Debug, Default)]
+pub struct ClippedCores {
+    pub found:         usize,
+    pub reconstructed: usize,
+    pub desaturated:   usize,
+}
+
+fn is_valid(v: f32) -> bool {
+    v.is_finite() && v != NO_VALUE_F32
+}
+
+fn background_and_noise(layer: &ImageLayerF32) -> (f32, f32) {
+    let step = (layer.as_slice().len() / 200_000).max(1);
+    let mut values: Vec<f32> = layer.as_slice()
+        .iter()
+        .step_by(step)
+        .copied()
+        .filter(|v| is_valid(*v))
+        .collect();
+    let background = median_f32(&mut values).unwrap_or(0.0);
+    values.iter_mut().for_each(|v| *v = (*v - background).abs());
+    let noise = median_f32(&mut values).unwrap_or(0.0) * 1.4826;
+    (background, noise)
+}
+
+/// Merges aligned channels of same size. `channels` must contain R, G
+/// and B, L and narrowband channels are optional
+pub fn merge_lrgb(
+    channels: &[(LrgbChannel, &ImageLayerF32)],
+    opts:     &LrgbOpts,
+) -> anyhow::Result<LrgbResult> {
+    if !(0.0..=1.0).contains(&opts.lum_weight) {
+        anyhow::bail!("Weight of luminance must be in 0..1 range");
+    }
+    if !(0.0..=1.0).contains(&opts.nb_blend.strength) {
+        anyhow::bail!("Strength of narrowband blending must be in 0..1 range");
+    }
+    let find = |channel: LrgbChannel| channels.iter()
+        .find(|(c, _)| *c == channel)
+        .map(|(_, layer)| *layer);
+    let (Some(r), Some(g), Some(b)) = (find(LrgbChannel::R), find(LrgbChannel::G), find(LrgbChannel::B)) else {
+        anyhow::bail!("R, G and B channels are needed for LRGB image");
+    };
+    for (channel, layer) in channels {
+        if layer.width() != r.width() || layer.height() != r.height() {
+            anyhow::bail!(
+                "Size of {} channel {}x{} differs from R channel {}x{}",
+                channel.name(), layer.width(), layer.height(), r.width(), r.height()
+            );
+        }
+    }
+
+    let mut image = Image::new_color(r.width(), r.height());
+    image.r.as_slice_mut().copy_from_slice(r.as_slice());
+    image.g.as_slice_mut().copy_from_slice(g.as_slice());
+    image.b.as_slice_mut().copy_from_slice(b.as_slice());
+
+    // clipped cores are detected by levels of source RGB
+    let cores = if opts.reconstruct_cores || opts.desaturate_clipped {
+        fix_clipped_cores(&mut image, opts.reconstruct_cores, opts.desaturate_clipped)
+    } else {
+        ClippedCores::default()
+    };
+
+    // narrowband is blended before luminance so L defines brightness of result
+    for (channel, layer) in channels.iter().filter(|(c, _)| c.is_narrowband()) {
+        match channel {
+            LrgbChannel::Ha|LrgbChannel::Sii =>
+                blend_into_layer(&mut image.r, layer, &opts.nb_blend),
+            _ => {
+                blend_into_layer(&mut image.g, layer, &opts.nb_blend);
+                blend_into_layer(&mut image.b, layer, &opts.nb_blend);
+            },
+        }
+    }
+
+    let lum_scale = match find(LrgbChannel::L) {
+        Some(l) if opts.lum_weight > 0.0 => Some(replace_luminance(&mut image, l, opts.lum_weight)),
+        _ => None,
+    };
+    Ok(LrgbResult { image, lum_scale, cores })
+}
+
+fn replace_luminance(image: &mut Image, l: &ImageLayerF32, weight: f32) -> f32 {
+    let (bg_r, _) = background_and_noise(&image.r);
+    let (bg_g, _) = background_and_noise(&image.g);
+    let (bg_b, _) = background_and_noise(&image.b);
+    let (bg_l, _) = background_and_noise(l);
+
+    let mut lum = ImageLayerF32::new(image.width(), image.height());
+    lum.as_slice_mut()
+        .par_iter_mut()
+        .enumerate()
+        .for_each(|(i, y)| {
+            let (r, g, b) = (image.r.as_slice()[i], image.g.as_slice()[i], image.b.as_slice()[i]);
+            *y = if is_valid(r) && is_valid(g) && is_valid(b) {
+                ((r - bg_r) + (g - bg_g) + (b - bg_b)) / 3.0
+            } else {
+                NO_VALUE_F32
+            };
+        });
+    let (_, noise) = background_and_noise(&lum);
+    let soft = (3.0 * noise).max(1e-6);
+
+    // least squares fit of L to luminance of RGB (both without background).
+    // Sums of fixed portions don't depend on order of work of threads
+    const PORTION: usize = 64 * 1024;
+    let portions: Vec<(f64, f64)> = l.as_slice()
+        .par_chunks(PORTION)
+        .zip(lum.as_slice().par_chunks(PORTION))
+        .map(|(l, y)| {
+            l.iter().zip(y)
+                .filter(|(l, y)| is_valid(**l) && is_valid(**y))
+                .fold((0.0, 0.0), |(sum_ly, sum_ll), (l, y)| {
+                    let l = (*l - bg_l) as f64;
+                    (sum_ly + l * *y as f64, sum_ll + l * l)
+                })
+        })
+        .collect();
+    let (sum_ly, sum_ll) = portions.iter()
+        .fold((0.0, 0.0), |a, b| (a.0 + b.0, a.1 + b.1));
+    let scale = if sum_ll > 0.0 { (sum_ly / sum_ll) as f32 } else { 1.0 };
+
+    let backgrounds = [bg_r, bg_g, bg_b];
+    for (layer, bg) in [&mut image.r, &mut image.g, &mut image.b].into_iter().zip(backgrounds) {
+        layer.as_slice_mut()
+            .par_iter_mut()
+            .zip(l.as_slice().par_iter())
+            .zip(lum.as_slice().par_iter())
+            .for_each(|((v, l), y)| {
+                if !is_valid(*v) || !is_valid(*l) || !is_valid(*y) { return; }
+                let ratio = (scale * (*l - bg_l) + soft) / (*y + soft);
+                if !ratio.is_finite() || ratio <= 0.0 { return; }
+                *v = bg + (*v - bg) * (1.0 + weight * (ratio - 1.0));
+            });
+    }
+    scale
+}
+
+/// Reconstructs color of clipped areas (if `reconstruct` is set) and makes
+/// neutral ones which can't be reconstructed (if `desaturate` is set).
+/// Brightest channel of area keeps its value, others are set by fraction
+fn fix_clipped_cores(image: &mut Image, reconstruct: bool, desaturate: bool) -> ClippedCores {
+    let (width, height) = (image.width(), image.height());
+    let layers = [&image.r, &image.g, &image.b];
+    let bg_and_noise = layers.map(background_and_noise);
+    let levels = layers.map(|layer| {
+        let max = layer.as_slice().iter()
+            .copied()
+            .filter(|v| is_valid(*v))
+            .fold(0.0, f32::max);
+        SATURATION_LEVEL * max
+    });
+    let rgb = |x: Crd, y: Crd| -> Option<[f32; 3]> {
+        let values = [image.r.get(x, y)?, image.g.get(x, y)?, image.b.get(x, y)?];
+        if values.iter().any(|v| *v == NO_VALUE_F32 || v.is_nan()) { return None; }
+        Some(values)
+    };
+    let is_clipped = |values: &[f32; 3]| {
+        values.iter().zip(&levels).any(|(v, level)| v.is_infinite() || (*level > 0.0 && *v >= *level))
+    };
+
+    // every area is changed after all areas are found
+    // so periphery of neighbour star is not affected
+    let mut taken = vec![false; width as usize * height as usize];
+    let mut flood_filler = FloodFiller::new();
+    let mut areas = Vec::new();
+    for start_y in 0..height {
+        for start_x in 0..width {
+            let mut points = Vec::new();
+            flood_filler.fill(start_x, start_y, |x, y| {
+                let Some(values) = rgb(x, y) else { return false; };
+                let index = (x + y * width) as usize;
+                if taken[index] || !is_clipped(&values) { return false; }
+                taken[index] = true;
+                points.push((x, y));
+                true
+            });
+            if !points.is_empty() {
+                areas.push(points);
+            }
+        }
+    }
+    let is_plateau = |points: &Vec<(Crd, Crd)>| (0..3).any(|i| {
+        let values: Vec<f32> = points.iter()
+            .filter_map(|(x, y)| rgb(*x, *y))
+            .map(|values| values[i])
+            .collect();
+        if values.iter().any(|v| v.is_infinite() || *v >= WHITE_POINT) {
+            return true;
+        }
+        let top = values.iter().copied().fold(0.0, f32::max);
+        top > 0.0 && top >= levels[i] && values.iter()
+            .filter(|v| **v >= (1.0 - PLATEAU_TOLERANCE) * top)
+            .count() >= MIN_PLATEAU_PIXELS
+    });
+    areas.retain(is_plateau);
+
+    let mut result = ClippedCores { found: areas.len(), ..ClippedCores::default() };
+    let mut changes = Vec::new();
+    for points in &areas {
+        let cx = points.iter().map(|(x, _)| *x as f32).sum::<f32>() / points.len() as f32;
+        let cy = points.iter().map(|(_, y)| *y as f32).sum::<f32>() / points.len() as f32;
+        let dist = |x: Crd, y: Crd| ((x as f32 - cx).powi(2) + (y as f32 - cy).powi(2)).sqrt();
+        let radius = points.iter().map(|(x, y)| dist(*x, *y)).fold(0.0, f32::max) + 0.5;
+        let fractions = if reconstruct && radius <= MAX_CORE_RADIUS {
+            periphery_color_fit(radius, cx, cy, &rgb, &is_clipped, &bg_and_noise)
+        } else {
+            None
+        };
+        if fractions.is_some() {
+            result.reconstructed += 1;
+        } else if desaturate {
+            result.desaturated += 1;
+        } else {
+            continue;
+        }
+        for (x, y) in points {
+            let Some(values) = rgb(*x, *y) else { continue; };
+            // clipped values are infinite in results of stacking before normalization
+            let signals: [f32; 3] = std::array::from_fn(|i| {
+                values[i].min(levels[i] / SATURATION_LEVEL) - bg_and_noise[i].0
+            });
+            let peak = signals.iter().copied().fold(0.0, f32::max);
+            let fractions = match &fractions {
+                Some(fit) => fit(dist(*x, *y)),
+                None => [1.0; 3],
+            };
+            let max_fraction = fractions.iter().copied().fold(0.0, f32::max);
+            if max_fraction <= 0.0 { continue; }
+            let new_values: [f32; 3] = std::array::from_fn(|i| {
+                bg_and_noise[i].0 + peak * fractions[i] / max_fraction
+            });
+            changes.push((*x, *y, new_values));
+        }
+    }
+    for (x, y, [r, g, b]) in changes {
+        image.r.set(x, y, r);
+        image.g.set(x, y, g);
+        image.b.set(x, y, b);
+    }
+    result
+}
+
+/// Linear fit of fractions of channels in unclipped periphery of star
+/// by distance from its center. Result is function of distance
+fn periphery_color_fit(
+    radius:       f32,
+    cx:           f32,
+    cy:           f32,
+    rgb:          &impl Fn(Crd, Crd) -> Option<[f32; 3]>,
+    is_clipped:   &impl Fn(&[f32; 3]) -> bool,
+    bg_and_noise: &[(f32, f32); 3],
+) -> Option<impl Fn(f32) -> [f32; 3]> {
+    let outer = 1.5 * radius + 3.0;
+    let noise_sum: f32 = bg_and_noise.iter().map(|(_, noise)| noise).sum();
+    let mut samples = Vec::new(); // distance and fractions
+    let r = outer.ceil() as Crd;
+    let (icx, icy) = (cx.round() as Crd, cy.round() as Crd);
+    for y in icy - r..=icy + r {
+        for x in icx - r..=icx + r {
+            let dist = ((x as f32 - cx).powi(2) + (y as f32 - cy).powi(2)).sqrt();
+            if dist < radius || dist > outer { continue; }
+            let Some(values) = rgb(x, y) else { continue; };
+            if is_clipped(&values) || values.iter().any(|v| !v.is_finite()) { continue; }
+            let signals: [f32; 3] = std::array::from_fn(|i| (values[i] - bg_and_noise[i].0).max(0.0));
+            let sum: f32 = signals.iter().sum();
+            if sum <= 3.0 * noise_sum || sum <= 0.0 { continue; }
+            samples.push((dist, signals.map(|v| v / sum)));
+        }
+    }
+    if samples.len() < MIN_PERIPHERY_PIXELS { return None; }
+
+    let n = samples.len() as f32;
+    let mean_d = samples.iter().map(|(d, _)| d).sum::<f32>() / n;
+    let var_d = samples.iter().map(|(d, _)| (d - mean_d).powi(2)).sum::<f32>();
+    let mut coeffs = [(0.0_f32, 0.0_f32); 3]; // fraction at mean distance and slope
+    for (i, (mean_f, slope)) in coeffs.iter_mut().enumerate() {
+        *mean_f = samples.iter().map(|(_, f)| f[i]).sum::<f32>() / n;
+        if var_d > 0.0 {
+            *slope = samples.iter().map(|(d, f)| (d - mean_d) * (f[i] - *mean_f)).sum::<f32>() / var_d;
+        }
+    }
+    Some(move |dist: f32| {
+        let fractions = coeffs.map(|(mean_f, slope)| (mean_f + slope * (dist - mean_d)).clamp(0.0, 1.0));
+        let sum: f32 = fractions.iter().sum();
+        if sum > 0.0 { fractions.map(|f| f / sum) } else { coeffs.map(|(mean_f, _)| mean_f) }
+    })
+}
@@ -6,29 +6,20 @@
 #![allow(dead_code)]
 
 
-mod image;
-mod image_norm;
-mod image_raw;
-mod cameras_database;
-mod image_io;
-mod light_file;
-mod fs_utils;
-mod log_utils;
-mod calc;
-mod stars;
-mod tests;
-mod progress;
-mod compression;
-mod stacking_utils;
+// Stacking engine is in library crate (src/lib.rs). Its modules are
+// imported into root so GUI and batch modules use them as `crate::...`
+use electra_stacking::*;
+
 mod gtk_utils;
-mod config;
-mod project;
-mod str_utils;
+mod batch;
+mod agent;
+mod live_sessions;
+mod jobs;
 mod gui;
 
 use gtk::prelude::*;
 use gettextrs::*;
-use crate::{config::*, log_utils::*};
+use crate::{config::*, log_utils::*, batch::*, jobs::*, str_utils::*};
 
 fn main() -> anyhow::Result<()> {
     // localization
@@ -40,14 +31,17 @@ fn main() -> anyhow::Result<()> {
     setlocale(LocaleCategory::LcAll, "");
     bindtextdomain("electra_stacking_gui", locale_path.to_str().unwrap_or(""))?;
     textdomain("electra_stacking_gui")?;
+    set_translator(|text| gettext(text));
 
     // logger
+    let mut args: Vec<String> = std::env::args().collect();
+    let log_opts = LogOpts::from_cmd_line(&mut args)?;
     let mut log_dir = get_app_conf_dir(true)?;
     log_dir.push("logs");
     if !log_dir.exists() {
         std::fs::create_dir(&log_dir)?;
     }
-    start_logger(&log_dir)?;
+    start_logger(&log_dir, &log_opts)?;
     log::info!(
         "Application {} {} started",
         env!("CARGO_PKG_NAME"),
@@ -57,6 +51,22 @@ fn main() -> anyhow::Result<()> {
     // Panic handler
     std::panic::set_hook(Box::new(panic_handler));
 
+    // batch mode
+    if let Some(batch_args) = BatchArgs::from_cmd_line(&args)? {
+        let res = run_batch(&batch_args);
+        if let Err(err) = &res {
+            log::error!("{}", err.to_string());
+        }
+        return res;
+    }
+    if let Some(jobs_command) = JobsCommand::from_cmd_line(&args)? {
+        let res = run_jobs_command(&jobs_command);
+        if let Err(err) = &res {
+            log::error!("{}", err.to_string());
+        }
+        return res;
+    }
+
     // build gui
     let application = gtk::Application::new(
         Some("com.github.art-den.electra-stacking"),
@@ -65,7 +75,7 @@ fn main() -> anyhow::Result<()> {
     application.connect_activate(crate::gui::build_ui);
 
     // run
-    application.run();
+    application.run_with_args(&args);
 
     Ok(())
 }
@@ -0,0 +1,233 @@
+use std::{path::*, sync::Arc};
+use rayon::prelude::*;
+use crate::{image::*, image_io::*, wcs::*, safe_read::*, progress::*, integration_meta::*, resample::*};
+
+/* Mosaic of plate solved panels. Panels are reprojected onto common
+   tangent plane and overlapped areas are blended with weights which
+   fall to zero near panel edges (feathering) */
+
+const EDGE_POINTS: usize = 16;
+
+#[derive(Clone, Debug)]
+pub struct MosaicOpts {
+    pub feather: f64, // width of blended area near edges of panel in pixels
+    pub kernel:  ResampleKernel, // for reprojection of panels
+}
+
+impl Default for MosaicOpts {
+    fn default() -> Self {
+        Self {
+            feather: 100.0,
+            kernel:  ResampleKernel::Bilinear,
+        }
+    }
+}
+
+pub struct MosaicResult {
+    pub image: Image,
+    pub wcs:   Wcs,
+    pub meta:  IntegrationMeta, // of all panels
+}
+
+pub struct Panel {
+    pub file_name: PathBuf,
+    pub wcs:       Wcs,
+    pub width:     Crd,
+    pub height:    Crd,
+    pub exposure:  Option<f64>, // EXPTIME
+}
+
+impl Panel {
+    pub fn contains(&self, x: f64, y: f64) -> bool {
+        x >= 0.0 && y >= 0.0 && x <= self.width as f64 - 1.0 && y <= self.height as f64 - 1.0
+    }
+}
+
+/// Loads size and WCS of plate solved panels
+pub fn load_panels(files: &[PathBuf], progress: &ProgressTs) -> anyhow::Result<Vec<Panel>> {
+    let is_cancelled: IsCancelledFun = Arc::new(|| false);
+    let infos = load_src_file_info_for_files(&files.to_vec(), None, &is_cancelled, progress)?;
+    let mut panels = Vec::new();
+    for (file_name, info) in files.iter().zip(infos) {
+        panels.push(Panel {
+            file_name: file_name.clone(),
+            wcs: Wcs::load_for_image(file_name)?,
+            width: info.width as Crd,
+            height: info.height as Crd,
+            exposure: info.exp,
+        });
+    }
+    Ok(panels)
+}
+
+// Points of panel border to find bounds of mosaic
+fn border_points(width: Crd, height: Crd) -> Vec<(f64, f64)> {
+    let (w, h) = (width as f64 - 1.0, height as f64 - 1.0);
+    (0..=EDGE_POINTS)
+        .map(|i| i as f64 / EDGE_POINTS as f64)
+        .flat_map(|t| [(t * w, 0.0), (t * w, h), (0.0, t * h), (w, t * h)])
+        .collect()
+}
+
+/// Tangent point in center of panels and orientation of first panel
+/// with finest pixel scale of all panels. Returns WCS and size of mosaic
+pub fn calc_common_wcs(panels: &[Panel]) -> anyhow::Result<(Wcs, Crd, Crd)> {
+    let (mut sx, mut sy, mut sz) = (0.0, 0.0, 0.0);
+    for panel in panels {
+        let (ra, dec) = panel.wcs.pixel_to_world(
+            (panel.width as f64 - 1.0) / 2.0,
+            (panel.height as f64 - 1.0) / 2.0
+        );
+        let (ra, dec) = (ra.to_radians(), dec.to_radians());
+        sx += dec.cos() * ra.cos();
+        sy += dec.cos() * ra.sin();
+        sz += dec.sin();
+    }
+    let ra = sy.atan2(sx).to_degrees().rem_euclid(360.0);
+    let dec = sz.atan2((sx * sx + sy * sy).sqrt()).to_degrees();
+
+    let scale = panels.iter()
+        .map(|p| p.wcs.pixel_scale())
+        .fold(f64::MAX, f64::min);
+    let first = &panels[0].wcs;
+    let k = scale / first.pixel_scale();
+    let mut wcs = Wcs {
+        crval: [ra, dec],
+        crpix: [1.0, 1.0],
+        cd: [
+            [first.cd[0][0] * k, first.cd[0][1] * k],
+            [first.cd[1][0] * k, first.cd[1][1] * k],
+        ],
+    };
+
+    let (mut min_x, mut min_y, mut max_x, mut max_y) = (f64::MAX, f64::MAX, f64::MIN, f64::MIN);
+    for panel in panels {
+        for (x, y) in border_points(panel.width, panel.height) {
+            let (ra, dec) = panel.wcs.pixel_to_world(x, y);
+            let (mx, my) = wcs.world_to_pixel(ra, dec).ok_or_else(|| anyhow::anyhow!(
+                "Panel {} is too far from center of mosaic",
+                panel.file_name.to_str().unwrap_or("")
+            ))?;
+            min_x = min_x.min(mx);
+            min_y = min_y.min(my);
+            max_x = max_x.max(mx);
+            max_y = max_y.max(my);
+        }
+    }
+    wcs.crpix = [1.0 - min_x.floor(), 1.0 - min_y.floor()];
+    let width = (max_x.ceil() - min_x.floor()) as usize + 1;
+    let height = (max_y.ceil() - min_y.floor()) as usize + 1;
+    checked_image_size(width, height, 1, 1)?;
+    Ok((wcs, width as Crd, height as Crd))
+}
+
+// Weight falls linearly to zero at borders of panel
+fn feather_weight(x: f64, y: f64, width: Crd, height: Crd, feather: f64) -> f64 {
+    let dist = x.min(y)
+        .min(width as f64 - 1.0 - x)
+        .min(height as f64 - 1.0 - y);
+    if dist < 0.0 { return 0.0; }
+    if feather <= 0.0 { return 1.0; }
+    ((dist + 1.0) / feather).min(1.0)
+}
+
+struct Accumulator {
+    sums:    ImageLayerF32,
+    weights: ImageLayerF32,
+}
+
+impl Accumulator {
+    fn new(width: Crd, height: Crd) -> Self {
+        Self {
+            sums: ImageLayerF32::new(width, height),
+            weights: ImageLayerF32::new(width, height),
+        }
+    }
+
+    fn add(&mut self, layer: &ImageLayerF32, panel: &Panel, wcs: &Wcs, opts: &MosaicOpts) {
+        let width = self.sums.width() as usize;
+        self.sums.as_slice_mut()
+            .par_chunks_mut(width)
+            .zip(self.weights.as_slice_mut().par_chunks_mut(width))
+            .enumerate()
+            .for_each(|(y, (sums_row, weights_row))| {
+                for (x, (sum, weight)) in sums_row.iter_mut().zip(weights_row).enumerate() {
+                    let (ra, dec) = wcs.pixel_to_world(x as f64, y as f64);
+                    let Some((px, py)) = panel.wcs.world_to_pixel(ra, dec) else { continue; };
+                    let w = feather_weight(px, py, panel.width, panel.height, opts.feather);
+                    if w <= 0.0 { continue; }
+                    let Some(v) = interpolate_pixel(layer, px, py, opts.kernel) else { continue; };
+                    if v == NO_VALUE_F32 || !v.is_finite() { continue; }
+                    *sum += v * w as f32;
+                    *weight += w as f32;
+                }
+            });
+    }
+
+    fn result(self) -> ImageLayerF32 {
+        let mut result = self.sums;
+        for (v, w) in result.iter_mut().zip(self.weights.iter()) {
+            *v = if *w > 0.0 { *v / *w } else { 0.0 };
+        }
+        result
+    }
+}
+
+pub fn create_mosaic(
+    files:    &[PathBuf],
+    opts:     &MosaicOpts,
+    progress: &ProgressTs
+) -> anyhow::Result<MosaicResult> {
+    if files.len() < 2 {
+        anyhow::bail!("Mosaic needs at least 2 panels");
+    }
+    let panels = load_panels(files, progress)?;
+    let (wcs, width, height) = calc_common_wcs(&panels)?;
+    log::info!("Mosaic size is {}x{}, pixel scale {:.3}\"", width, height, wcs.pixel_scale());
+
+    progress.lock().unwrap().stage("Reprojecting panels...");
+    progress.lock().unwrap().set_total(panels.len());
+    let mut is_rgb = None;
+    let mut layers: Vec<Accumulator> = Vec::new();
+    let mut meta = IntegrationMeta::default();
+    for panel in &panels {
+        let ImageData { image: RawOrImage::Image(image), info } =
+            load_image_from_file(&panel.file_name, false)? else {
+            anyhow::bail!("{} is RAW image", panel.file_name.to_str().unwrap_or(""));
+        };
+        meta.add_stack(&info);
+        if *is_rgb.get_or_insert(image.is_rgb()) != image.is_rgb() {
+            anyhow::bail!("All panels of mosaic must be of same color type (mono or RGB)");
+        }
+        let src_layers = if image.is_rgb() {
+            vec![&image.r, &image.g, &image.b]
+        } else {
+            vec![&image.l]
+        };
+        if layers.is_empty() {
+            layers = src_layers.iter().map(|_| Accumulator::new(width, height)).collect();
+        }
+        for (acc, layer) in layers.iter_mut().zip(src_layers) {
+            acc.add(layer, panel, &wcs, opts);
+        }
+        progress.lock().unwrap().progress(true, panel.file_name.to_str().unwrap_or(""));
+    }
+
+    let mut layers = layers.into_iter().map(|acc| acc.result());
+    let image = if is_rgb == Some(true) {
+        Image {
+            l: ImageLayerF32::new_empty(),
+            r: layers.next().unwrap(),
+            g: layers.next().unwrap(),
+            b: layers.next().unwrap(),
+        }
+    } else {
+        Image {
+            l: layers.next().unwrap(),
+            r: ImageLayerF32::new_empty(),
+            g: ImageLayerF32::new_empty(),
+            b: ImageLayerF32::new_empty(),
+        }
+    };
+    Ok(MosaicResult { image, wcs, meta })
+}
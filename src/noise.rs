@@ -0,0 +1,158 @@
+use rayon::prelude::*;
+use crate::{image::*, calc::*};
+
+/* Estimation of gaussian noise of image. Both methods use wavelet scales
+   of B3 spline "a trous" transform (Starck & Murtagh):
+   k-sigma: iterative k-sigma clipping of first scale (fast, for every frame)
+   MRS: standard deviation of pixels outside of multiresolution support
+   (pixels significant at any of first scales) */
+
+const B3: [f32; 5] = [1.0 / 16.0, 4.0 / 16.0, 6.0 / 16.0, 4.0 / 16.0, 1.0 / 16.0];
+
+// Standard deviation of wavelet scales for gaussian noise with sigma = 1
+pub const SCALE_SIGMA: [f32; 4] = [0.889, 0.200, 0.086, 0.041];
+
+const KAPPA: f32 = 3.0;
+const MAX_ITERATIONS: usize = 10;
+const EPSILON: f32 = 1e-4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NoiseMethod {
+    KSigma,
+    Mrs,
+}
+
+impl NoiseMethod {
+    pub fn from_str(text: &str) -> anyhow::Result<Self> {
+        match text.to_lowercase().as_str() {
+            "k-sigma" | "ksigma" => Ok(NoiseMethod::KSigma),
+            "mrs"                => Ok(NoiseMethod::Mrs),
+            _ => anyhow::bail!("Wrong noise estimation method {} (k-sigma or mrs)", text),
+        }
+    }
+}
+
+pub fn estimate_noise(layer: &ImageLayerF32, method: NoiseMethod) -> f32 {
+    match method {
+        NoiseMethod::KSigma => estimate_noise_k_sigma(layer),
+        NoiseMethod::Mrs => estimate_noise_mrs(layer),
+    }
+}
+
+// Values of layer where undefined pixels are replaced by median
+// so they don't produce false structures in wavelet scales
+pub fn prepare_data(layer: &ImageLayerF32) -> (Vec<f32>, Vec<bool>) {
+    let is_valid = |v: f32| v.is_finite() && v != NO_VALUE_F32;
+    let mut values: Vec<f32> = layer.as_slice().iter()
+        .step_by((layer.as_slice().len() / 200_000).max(1))
+        .copied()
+        .filter(|v| is_valid(*v))
+        .collect();
+    let median = median_f32(&mut values).unwrap_or(0.0);
+    let mask: Vec<bool> = layer.as_slice().iter().map(|v| is_valid(*v)).collect();
+    let data = layer.as_slice().iter().map(|v| if is_valid(*v) { *v } else { median }).collect();
+    (data, mask)
+}
+
+#[inline]
+fn mirror(pos: isize, size: usize) -> usize {
+    let size = size as isize;
+    let mut pos = pos;
+    if pos < 0 { pos = -pos; }
+    if pos >= size { pos = 2 * size - 2 - pos; }
+    pos.clamp(0, size - 1) as usize
+}
+
+// Smoothing by B3 spline with holes of `step` pixels
+pub fn b3_smooth(data: &[f32], width: usize, height: usize, step: usize) -> Vec<f32> {
+    let mut rows = vec![0_f32; data.len()];
+    rows.par_chunks_mut(width).enumerate().for_each(|(y, row)| {
+        let src = &data[y * width..(y + 1) * width];
+        for (x, v) in row.iter_mut().enumerate() {
+            *v = B3.iter().enumerate()
+                .map(|(i, k)| k * src[mirror(x as isize + (i as isize - 2) * step as isize, width)])
+                .sum();
+        }
+    });
+    let mut result = vec![0_f32; data.len()];
+    result.par_chunks_mut(width).enumerate().for_each(|(y, row)| {
+        for (x, v) in row.iter_mut().enumerate() {
+            *v = B3.iter().enumerate()
+                .map(|(i, k)| k * rows[mirror(y as isize + (i as isize - 2) * step as isize, height) * width + x])
+                .sum();
+        }
+    });
+    result
+}
+
+fn std_dev(values: impl Iterator<Item = f32>) -> f32 {
+    let (mut sum, mut sum2, mut cnt) = (0_f64, 0_f64, 0_usize);
+    for v in values {
+        sum += v as f64;
+        sum2 += (v as f64) * (v as f64);
+        cnt += 1;
+    }
+    if cnt < 2 { return 0.0; }
+    let mean = sum / cnt as f64;
+    ((sum2 / cnt as f64 - mean * mean).max(0.0)).sqrt() as f32
+}
+
+fn k_sigma_std_dev(values: &[f32], mask: &[bool]) -> f32 {
+    let mut sigma = std_dev(values.iter().zip(mask).filter(|(_, m)| **m).map(|(v, _)| *v));
+    for _ in 0..MAX_ITERATIONS {
+        if sigma == 0.0 { break; }
+        let new_sigma = std_dev(
+            values.iter().zip(mask)
+                .filter(|(v, m)| **m && v.abs() < KAPPA * sigma)
+                .map(|(v, _)| *v)
+        );
+        let converged = (new_sigma - sigma).abs() <= EPSILON * sigma;
+        sigma = new_sigma;
+        if converged { break; }
+    }
+    sigma
+}
+
+pub fn estimate_noise_k_sigma(layer: &ImageLayerF32) -> f32 {
+    let (width, height) = (layer.width() as usize, layer.height() as usize);
+    if width < 3 || height < 3 { return 0.0; }
+    let (data, mask) = prepare_data(layer);
+    let smoothed = b3_smooth(&data, width, height, 1);
+    let scale: Vec<f32> = data.iter().zip(&smoothed).map(|(v, s)| v - s).collect();
+    k_sigma_std_dev(&scale, &mask) / SCALE_SIGMA[0]
+}
+
+pub fn estimate_noise_mrs(layer: &ImageLayerF32) -> f32 {
+    let (width, height) = (layer.width() as usize, layer.height() as usize);
+    if width < 3 || height < 3 { return 0.0; }
+    let (data, mask) = prepare_data(layer);
+
+    let mut scales = Vec::new();
+    let mut current = data.clone();
+    for j in 0..SCALE_SIGMA.len() {
+        let step = 1 << j;
+        if 4 * step >= width.min(height) { break; }
+        let smoothed = b3_smooth(&current, width, height, step);
+        scales.push(current.iter().zip(&smoothed).map(|(c, s)| c - s).collect::<Vec<_>>());
+        current = smoothed;
+    }
+    let residual: Vec<f32> = data.iter().zip(&current).map(|(v, c)| v - c).collect();
+
+    let mut sigma = k_sigma_std_dev(&scales[0], &mask) / SCALE_SIGMA[0];
+    for _ in 0..MAX_ITERATIONS {
+        if sigma == 0.0 { break; }
+        // pixels outside of multiresolution support
+        let new_sigma = std_dev(
+            residual.iter().enumerate()
+                .filter(|(i, _)| mask[*i])
+                .filter(|(i, _)| scales.iter().zip(SCALE_SIGMA)
+                    .all(|(scale, k)| scale[*i].abs() < KAPPA * sigma * k)
+                )
+                .map(|(_, v)| *v)
+        );
+        let converged = (new_sigma - sigma).abs() <= EPSILON * sigma;
+        sigma = new_sigma;
+        if converged { break; }
+    }
+    sigma
+}
@@ -0,0 +1,204 @@
+use std::{path::*, io::Write};
+use crate::str_utils::*;
+
+/* Export of photometric measurements of variable stars and exoplanet
+   transits into report formats of observer organizations. AAVSO Extended
+   File Format is accepted by AAVSO WebObs and by BAA VSS database, plain
+   table of JD, magnitude and error is accepted by Exoplanet Transit
+   Database (ETD) and most light curve tools */
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ObsReportFormat {
+    AavsoExtended,
+    Etd,
+}
+
+impl ObsReportFormat {
+    pub fn from_str(text: &str) -> anyhow::Result<Self> {
+        match text.to_lowercase().as_str() {
+            "aavso" => Ok(ObsReportFormat::AavsoExtended),
+            "etd"   => Ok(ObsReportFormat::Etd),
+            _ => anyhow::bail!("Wrong report format {} (aavso or etd)", text),
+        }
+    }
+}
+
+/// Filters of AAVSO Extended File Format
+const AAVSO_FILTERS: &[&str] = &[
+    "U", "B", "V", "R", "I", "J", "H", "K", "TG", "TB", "TR", "CV", "CR",
+    "SZ", "SU", "SG", "SR", "SI", "STU", "STV", "STB", "STY", "STHBW", "STHBN",
+    "MA", "MB", "MI", "ZS", "Y", "HA", "HAC", "O",
+];
+
+/// Measurement of variable star in one light file (or in stack of several ones)
+#[derive(Clone, Debug, Default)]
+pub struct Observation {
+    pub jd:        f64,
+    pub mag:       f64,
+    pub err:       Option<f64>,
+    pub airmass:   Option<f64>,
+    pub comp_mag:  Option<f64>, // instrumental magnitude of comparison star
+    pub check_mag: Option<f64>, // instrumental magnitude of check star
+}
+
+#[derive(Clone, Debug)]
+pub struct ObsReportOpts {
+    pub format:      ObsReportFormat,
+    pub obs_code:    Option<String>,
+    pub star:        Option<String>,
+    pub filter:      String,
+    pub obs_type:    String, // CCD or DSLR
+    pub transformed: bool,
+    pub mag_type:    String, // STD (standardized) or DIF (differential)
+    pub comp:        Option<String>, // label or AUID of comparison star or ENSEMBLE
+    pub check:       Option<String>,
+    pub chart:       Option<String>,
+    pub notes:       Option<String>,
+}
+
+impl Default for ObsReportOpts {
+    fn default() -> Self {
+        Self {
+            format:      ObsReportFormat::AavsoExtended,
+            obs_code:    None,
+            star:        None,
+            filter:      "V".to_string(),
+            obs_type:    "CCD".to_string(),
+            transformed: false,
+            mag_type:    "STD".to_string(),
+            comp:        None,
+            check:       None,
+            chart:       None,
+            notes:       None,
+        }
+    }
+}
+
+/// Loads measurements from CSV file with `jd` and `mag` columns. Optional
+/// columns are `err`, `airmass`, `comp_mag` and `check_mag`, other ones
+/// are ignored. Rows with empty magnitude are skipped
+pub fn load_observations_csv(file_name: &Path) -> anyhow::Result<Vec<Observation>> {
+    let text = std::fs::read_to_string(file_name)?;
+    let mut lines = text.lines().filter(|l| !l.trim().is_empty() && !l.starts_with('#'));
+    let header_line = lines.next().unwrap_or("");
+    let separator = if header_line.contains(';') { ';' } else { ',' };
+    let header: Vec<String> = parse_csv_line(header_line, separator)
+        .into_iter()
+        .map(|s| s.trim().to_lowercase())
+        .collect();
+    let col = |name: &str| header.iter().position(|h| h == name);
+    let jd_col = col("jd").ok_or_else(|| anyhow::anyhow!("Column 'jd' is not found in CSV"))?;
+    let mag_col = col("mag").ok_or_else(|| anyhow::anyhow!("Column 'mag' is not found in CSV"))?;
+
+    let mut result = Vec::new();
+    for line in lines {
+        let values = parse_csv_line(line, separator);
+        let float = |index: Option<usize>| -> anyhow::Result<Option<f64>> {
+            match index.and_then(|i| values.get(i)).map(|v| v.trim()) {
+                Some("") | None => Ok(None),
+                Some(v) => Ok(Some(v.replace(',', ".").parse().map_err(|_| anyhow::anyhow!(
+                    "Wrong number {} in line '{}'", v, line
+                ))?)),
+            }
+        };
+        let Some(mag) = float(Some(mag_col))? else { continue; };
+        let jd = float(Some(jd_col))?
+            .ok_or_else(|| anyhow::anyhow!("JD is not defined in line '{}'", line))?;
+        result.push(Observation {
+            jd,
+            mag,
+            err:       float(col("err"))?,
+            airmass:   float(col("airmass"))?,
+            comp_mag:  float(col("comp_mag"))?,
+            check_mag: float(col("check_mag"))?,
+        });
+    }
+    Ok(result)
+}
+
+pub fn write_obs_report<W: Write>(
+    observations: &[Observation],
+    opts:         &ObsReportOpts,
+    dst:          &mut W,
+) -> anyhow::Result<()> {
+    match opts.format {
+        ObsReportFormat::AavsoExtended => write_aavso_extended(observations, opts, dst),
+        ObsReportFormat::Etd => write_etd(observations, dst),
+    }
+}
+
+fn write_aavso_extended<W: Write>(
+    observations: &[Observation],
+    opts:         &ObsReportOpts,
+    dst:          &mut W,
+) -> anyhow::Result<()> {
+    let obs_code = opts.obs_code.as_deref()
+        .ok_or_else(|| anyhow::anyhow!("Observer code is not defined (--obscode)"))?;
+    let star = opts.star.as_deref()
+        .ok_or_else(|| anyhow::anyhow!("Name of star is not defined (--star)"))?;
+    let filter = opts.filter.to_uppercase();
+    if !AAVSO_FILTERS.contains(&filter.as_str()) {
+        anyhow::bail!("Filter {} is not supported by AAVSO Extended File Format", opts.filter);
+    }
+    let obs_type = opts.obs_type.to_uppercase();
+    if !matches!(obs_type.as_str(), "CCD"|"DSLR") {
+        anyhow::bail!("Wrong observation type {} (CCD or DSLR)", opts.obs_type);
+    }
+    let mag_type = opts.mag_type.to_uppercase();
+    if !matches!(mag_type.as_str(), "STD"|"DIF") {
+        anyhow::bail!("Wrong magnitude type {} (STD or DIF)", opts.mag_type);
+    }
+    // values are separated by comma so they can't contain it
+    let text = |value: Option<&str>| -> anyhow::Result<String> {
+        match value.map(str::trim).filter(|v| !v.is_empty()) {
+            Some(v) if v.contains(',') => anyhow::bail!("Value '{}' contains comma", v),
+            Some(v) => Ok(v.to_string()),
+            None => Ok("na".to_string()),
+        }
+    };
+    let num = |value: Option<f64>, digits: usize| match value {
+        Some(v) => format!("{:.*}", digits, v),
+        None => "na".to_string(),
+    };
+
+    writeln!(dst, "#TYPE=EXTENDED")?;
+    writeln!(dst, "#OBSCODE={}", obs_code)?;
+    writeln!(dst, "#SOFTWARE=Electra Stacking {}", env!("CARGO_PKG_VERSION"))?;
+    writeln!(dst, "#DELIM=,")?;
+    writeln!(dst, "#DATE=JD")?;
+    writeln!(dst, "#OBSTYPE={}", obs_type)?;
+    writeln!(dst, "#NAME,DATE,MAG,MERR,FILT,TRANS,MTYPE,CNAME,CMAG,KNAME,KMAG,AMASS,GROUP,CHART,NOTES")?;
+    let star = text(Some(star))?;
+    let comp = text(opts.comp.as_deref())?;
+    let check = text(opts.check.as_deref())?;
+    let chart = text(opts.chart.as_deref())?;
+    let notes = text(opts.notes.as_deref())?;
+    for obs in observations {
+        writeln!(
+            dst,
+            "{},{:.5},{:.3},{},{},{},{},{},{},{},{},{},na,{},{}",
+            star,
+            obs.jd,
+            obs.mag,
+            num(obs.err, 3),
+            filter,
+            if opts.transformed { "YES" } else { "NO" },
+            mag_type,
+            comp,
+            num(obs.comp_mag, 3),
+            check,
+            num(obs.check_mag, 3),
+            num(obs.airmass, 3),
+            chart,
+            notes,
+        )?;
+    }
+    Ok(())
+}
+
+fn write_etd<W: Write>(observations: &[Observation], dst: &mut W) -> anyhow::Result<()> {
+    for obs in observations {
+        writeln!(dst, "{:.5} {:.4} {:.4}", obs.jd, obs.mag, obs.err.unwrap_or(0.0))?;
+    }
+    Ok(())
+}
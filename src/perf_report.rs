@@ -0,0 +1,159 @@
+use std::{path::*, sync::*, time::*};
+use serde::*;
+use chrono::prelude::*;
+use crate::{progress::*, project::*, fs_utils::*};
+
+/* Local performance report of batch run. Nothing is sent anywhere:
+   report is written into file which user can attach to issue */
+
+#[derive(Serialize)]
+pub struct StageTime {
+    pub name: String,
+    pub secs: f64,
+}
+
+#[derive(Serialize)]
+pub struct PerfReport {
+    pub app_version:      &'static str,
+    pub os:               &'static str,
+    pub arch:             &'static str,
+    pub cpu_count:        usize,
+    pub threads:          usize,
+    pub started:          DateTime<Local>,
+    pub total_secs:       f64,
+    pub stages:           Vec<StageTime>,
+    pub input_files:      usize,
+    pub input_mb:         f64,
+    pub throughput_mb_s:  f64,
+    pub cpu_time_secs:    Option<f64>,
+    pub core_utilization: Option<f64>, // 1.0 if all cores are busy whole time
+    pub memory_peak_mb:   Option<f64>,
+}
+
+/// Progress which remembers start time of every stage
+pub struct ProgressPerf {
+    inner:   ProgressTs,
+    started: Instant,
+    stages:  Vec<(String, Instant)>,
+}
+
+impl ProgressPerf {
+    pub fn new_ts(inner: ProgressTs) -> Arc<Mutex<ProgressPerf>> {
+        Arc::new(Mutex::new(ProgressPerf {
+            inner,
+            started: Instant::now(),
+            stages: Vec::new(),
+        }))
+    }
+
+    fn stage_times(&self, finished: Instant) -> Vec<StageTime> {
+        self.stages.iter()
+            .enumerate()
+            .map(|(i, (name, start))| {
+                let end = self.stages.get(i+1).map(|(_, t)| *t).unwrap_or(finished);
+                StageTime { name: name.clone(), secs: (end - *start).as_secs_f64() }
+            })
+            .collect()
+    }
+}
+
+impl Progress for ProgressPerf {
+    fn stage(&mut self, text: &str) {
+        self.stages.push((text.to_string(), Instant::now()));
+        self.inner.lock().unwrap().stage(text);
+    }
+
+    fn set_total(&mut self, total: usize) {
+        self.inner.lock().unwrap().set_total(total);
+    }
+
+    fn progress(&mut self, step: bool, text: &str) {
+        self.inner.lock().unwrap().progress(step, text);
+    }
+
+    fn percent(&mut self, value: usize, total: usize, text: &str) {
+        self.inner.lock().unwrap().percent(value, total, text);
+    }
+}
+
+// User + system time of process
+#[cfg(target_os = "linux")]
+fn process_cpu_time() -> Option<f64> {
+    let stat = std::fs::read_to_string("/proc/self/stat").ok()?;
+    // fields after process name which can contain spaces
+    let fields: Vec<&str> = stat.rsplit_once(')')?.1.split_whitespace().collect();
+    let utime: f64 = fields.get(11)?.parse().ok()?;
+    let stime: f64 = fields.get(12)?.parse().ok()?;
+    const TICKS_PER_SEC: f64 = 100.0;
+    Some((utime + stime) / TICKS_PER_SEC)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn process_cpu_time() -> Option<f64> {
+    None
+}
+
+#[cfg(target_os = "linux")]
+fn memory_peak_mb() -> Option<f64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmHWM:"))?;
+    let kb: f64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb / 1024.0)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn memory_peak_mb() -> Option<f64> {
+    None
+}
+
+pub fn create_perf_report(
+    progress: &Mutex<ProgressPerf>,
+    project:  &Project,
+    threads:  usize,
+    started:  DateTime<Local>,
+) -> PerfReport {
+    let progress = progress.lock().unwrap();
+    let finished = Instant::now();
+    let total_secs = (finished - progress.started).as_secs_f64();
+
+    let input_sizes: Vec<u64> = project.groups().iter()
+        .filter(|g| g.used())
+        .flat_map(|g| g.light_files.list().iter())
+        .filter(|f| f.used())
+        .filter_map(|f| std::fs::metadata(f.file_name()).ok())
+        .map(|m| m.len())
+        .collect();
+    let input_mb = input_sizes.iter().sum::<u64>() as f64 / (1024.0 * 1024.0);
+
+    let cpu_count = num_cpus::get();
+    let cpu_time_secs = process_cpu_time();
+    let core_utilization = cpu_time_secs
+        .filter(|_| total_secs > 0.0)
+        .map(|t| t / (total_secs * cpu_count as f64));
+
+    PerfReport {
+        app_version: env!("CARGO_PKG_VERSION"),
+        os: std::env::consts::OS,
+        arch: std::env::consts::ARCH,
+        cpu_count,
+        threads,
+        started,
+        total_secs,
+        stages: progress.stage_times(finished),
+        input_files: input_sizes.len(),
+        input_mb,
+        throughput_mb_s: if total_secs > 0.0 { input_mb / total_secs } else { 0.0 },
+        cpu_time_secs,
+        core_utilization,
+        memory_peak_mb: memory_peak_mb(),
+    }
+}
+
+impl PerfReport {
+    pub fn save(&self, file_name: &Path) -> anyhow::Result<()> {
+        let text = serde_json::to_string_pretty(self)?;
+        write_file_atomically(file_name, |tmp_file_name| {
+            Ok(std::fs::write(tmp_file_name, &text)?)
+        })
+    }
+}
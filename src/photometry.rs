@@ -0,0 +1,290 @@
+use std::path::*;
+use chrono::prelude::*;
+use rayon::prelude::*;
+use crate::{image::*, image_io::*, calc::*, stars::*, wcs::*, airmass::*, progress::*, fs_utils::*, stacking_utils::transform_matrix};
+
+/* Aperture photometry of target, comparison and check stars over series
+   of frames. Frames are aligned to first frame by stars, so positions of
+   stars are defined once (in pixels of first frame or as RA/DEC if first
+   frame has WCS) and every star is re-centered in every frame. Background
+   is median of annulus around aperture */
+
+/// Zero point of instrumental magnitudes
+pub const INSTR_ZERO_POINT: f64 = 25.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StarPos {
+    Pixel(f64, f64),  // in pixels of first frame
+    Sky(EqCoords),
+}
+
+impl StarPos {
+    /// "<x>,<y>" in pixels or "<RA>,<DEC>" in sexagesimal form
+    /// ("05:35:17.3,-05:23:28" or "5h35m17.3s,-5d23m28s")
+    pub fn from_str(text: &str) -> anyhow::Result<Self> {
+        let Some((v1, v2)) = text.split_once(',') else {
+            anyhow::bail!("Wrong position of star {} (<x>,<y> or <RA>,<DEC>)", text);
+        };
+        if let (Ok(x), Ok(y)) = (v1.trim().parse(), v2.trim().parse()) {
+            return Ok(StarPos::Pixel(x, y));
+        }
+        Ok(StarPos::Sky(EqCoords::from_str(text)?))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PhotometryOpts {
+    pub aperture: f64,        // radius in pixels
+    pub annulus:  (f64, f64), // inner and outer radius of background annulus
+    pub recenter: bool,       // move aperture to centroid of star
+}
+
+impl Default for PhotometryOpts {
+    fn default() -> Self {
+        Self {
+            aperture: 5.0,
+            annulus:  (8.0, 12.0),
+            recenter: true,
+        }
+    }
+}
+
+impl PhotometryOpts {
+    pub fn check(&self) -> anyhow::Result<()> {
+        let (inner, outer) = self.annulus;
+        if self.aperture <= 0.0 || inner < self.aperture || outer <= inner {
+            anyhow::bail!(
+                "Aperture {} and annulus {}-{} must be 0 < aperture <= inner < outer",
+                self.aperture, inner, outer
+            );
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct StarMeasurement {
+    pub x:    f64,
+    pub y:    f64,
+    pub flux: f64, // minus background
+    pub bg:   f64, // per pixel
+    pub mag:  f64, // instrumental magnitude
+    pub err:  f64,
+}
+
+/// Measurements of target, comparison and check stars in one frame
+#[derive(Clone, Debug)]
+pub struct FrameMeasurement {
+    pub file:    PathBuf,
+    pub jd:      Option<f64>, // of middle of exposure
+    pub airmass: Option<f64>,
+    pub stars:   Vec<Option<StarMeasurement>>, // in order of positions
+}
+
+const SUBPIXELS: usize = 5;
+
+/// Part of pixel with center (`px`, `py`) inside circle
+fn pixel_coverage(px: f64, py: f64, cx: f64, cy: f64, radius: f64) -> f64 {
+    let dist = ((px - cx).powi(2) + (py - cy).powi(2)).sqrt();
+    if dist <= radius - 0.75 { return 1.0; }
+    if dist >= radius + 0.75 { return 0.0; }
+    let mut inside = 0;
+    for sy in 0..SUBPIXELS {
+        for sx in 0..SUBPIXELS {
+            let x = px - 0.5 + (sx as f64 + 0.5) / SUBPIXELS as f64;
+            let y = py - 0.5 + (sy as f64 + 0.5) / SUBPIXELS as f64;
+            if (x - cx).powi(2) + (y - cy).powi(2) <= radius * radius {
+                inside += 1;
+            }
+        }
+    }
+    inside as f64 / (SUBPIXELS * SUBPIXELS) as f64
+}
+
+fn annulus_background(layer: &ImageLayerF32, x: f64, y: f64, opts: &PhotometryOpts) -> Option<(f64, f64, usize)> {
+    let (inner, outer) = opts.annulus;
+    let r = outer.ceil() as Crd;
+    let (cx, cy) = (x.round() as Crd, y.round() as Crd);
+    let mut values = Vec::new();
+    for py in cy - r..=cy + r {
+        for px in cx - r..=cx + r {
+            let dist2 = (px as f64 - x).powi(2) + (py as f64 - y).powi(2);
+            if dist2 < inner * inner || dist2 > outer * outer { continue; }
+            let Some(v) = layer.get(px, py) else { continue; };
+            if !v.is_finite() || v == NO_VALUE_F32 { continue; }
+            values.push(v);
+        }
+    }
+    let count = values.len();
+    if count < 8 { return None; }
+    let bg = median_f32(&mut values)?;
+    values.iter_mut().for_each(|v| *v = (*v - bg).abs());
+    let noise = median_f32(&mut values)? * 1.4826;
+    Some((bg as f64, noise as f64, count))
+}
+
+/// Centroid of background subtracted pixels inside aperture
+fn centroid(layer: &ImageLayerF32, x: f64, y: f64, radius: f64, bg: f64) -> Option<(f64, f64)> {
+    let r = radius.ceil() as Crd;
+    let (cx, cy) = (x.round() as Crd, y.round() as Crd);
+    let (mut sum, mut sum_x, mut sum_y) = (0.0, 0.0, 0.0);
+    for py in cy - r..=cy + r {
+        for px in cx - r..=cx + r {
+            if (px as f64 - x).powi(2) + (py as f64 - y).powi(2) > radius * radius { continue; }
+            let Some(v) = layer.get(px, py) else { continue; };
+            if !v.is_finite() || v == NO_VALUE_F32 { continue; }
+            let v = v as f64 - bg;
+            if v <= 0.0 { continue; }
+            sum += v;
+            sum_x += v * px as f64;
+            sum_y += v * py as f64;
+        }
+    }
+    if sum <= 0.0 { return None; }
+    Some((sum_x / sum, sum_y / sum))
+}
+
+/// Measures star near (`x`, `y`). None if star is out of image, is
+/// overexposed or its flux is not positive. `exp` normalizes flux to 1 s
+pub fn measure_star(
+    layer: &ImageLayerF32,
+    x:     f64,
+    y:     f64,
+    exp:   Option<f32>,
+    opts:  &PhotometryOpts,
+) -> Option<StarMeasurement> {
+    let (mut x, mut y) = (x, y);
+    let (mut bg, mut noise, mut bg_count) = annulus_background(layer, x, y, opts)?;
+    if opts.recenter {
+        for _ in 0..3 {
+            let (new_x, new_y) = centroid(layer, x, y, opts.aperture, bg + 3.0 * noise)?;
+            let shift = ((new_x - x).powi(2) + (new_y - y).powi(2)).sqrt();
+            (x, y) = (new_x, new_y);
+            (bg, noise, bg_count) = annulus_background(layer, x, y, opts)?;
+            if shift < 0.05 { break; }
+        }
+    }
+    let r = (opts.aperture + 1.0).ceil() as Crd;
+    let (cx, cy) = (x.round() as Crd, y.round() as Crd);
+    let (mut sum, mut area) = (0.0, 0.0);
+    for py in cy - r..=cy + r {
+        for px in cx - r..=cx + r {
+            let part = pixel_coverage(px as f64, py as f64, x, y, opts.aperture);
+            if part == 0.0 { continue; }
+            let v = layer.get(px, py)?; // aperture is out of image
+            if v.is_infinite() || v == NO_VALUE_F32 || v.is_nan() {
+                return None; // overexposed or not covered
+            }
+            sum += part * v as f64;
+            area += part;
+        }
+    }
+    let flux = sum - bg * area;
+    if flux <= 0.0 { return None; }
+    let flux_noise = noise * (area + area * area / bg_count as f64).sqrt();
+    let exp = exp.filter(|e| *e > 0.0).unwrap_or(1.0) as f64;
+    Some(StarMeasurement {
+        x, y, flux, bg,
+        mag: INSTR_ZERO_POINT - 2.5 * (flux / exp).log10(),
+        err: 1.0857 * flux_noise / flux,
+    })
+}
+
+fn julian_date_of_middle(info: &ImageInfo) -> Option<f64> {
+    let start = info.file_time?;
+    let half_exp = chrono::Duration::milliseconds((info.exp.unwrap_or(0.0) * 500.0) as i64);
+    Some(julian_date(&(start + half_exp).with_timezone(&Utc)))
+}
+
+fn load_grey(file_name: &Path) -> anyhow::Result<(ImageLayerF32, ImageInfo)> {
+    let ImageData { image, info } = load_image_from_file(file_name, false)?;
+    let layer = match image {
+        RawOrImage::Image(image) => image.create_greyscale_layer(),
+        RawOrImage::Raw(raw) => raw.demosaic(crate::image_raw::DemosaicAlgo::Linear, false)?.create_greyscale_layer(),
+    };
+    Ok((layer, info))
+}
+
+/// Pixel coordinates of star in first (reference) frame
+fn ref_pixel_pos(pos: &StarPos, ref_wcs: Option<&Wcs>, ref_file: &Path) -> anyhow::Result<(f64, f64)> {
+    match pos {
+        StarPos::Pixel(x, y) => Ok((*x, *y)),
+        StarPos::Sky(coords) => {
+            let wcs = ref_wcs.ok_or_else(|| anyhow::anyhow!(
+                "WCS of {} is needed for RA/DEC of stars. Plate solve it first", path_to_str(ref_file)
+            ))?;
+            wcs.world_to_pixel(coords.ra, coords.dec).ok_or_else(|| anyhow::anyhow!(
+                "Star {:.5},{:.5} is not on {}", coords.ra, coords.dec, path_to_str(ref_file)
+            ))
+        }
+    }
+}
+
+/// Measures stars at `positions` in every frame of `files`.
+/// First file is reference for alignment and pixel positions
+pub fn measure_frames(
+    files:        &[PathBuf],
+    positions:    &[StarPos],
+    opts:         &PhotometryOpts,
+    airmass_opts: &AirmassOpts,
+    progress:     &ProgressTs,
+    is_cancelled: &IsCancelledFun,
+) -> anyhow::Result<Vec<FrameMeasurement>> {
+    opts.check()?;
+    let Some(ref_file) = files.first() else {
+        anyhow::bail!("No frames for photometry");
+    };
+    let stars_opts = StarsFindOpts::default();
+    let (ref_layer, _) = load_grey(ref_file)?;
+    let ref_stars = find_stars_on_image(&ref_layer, None, false, &stars_opts)?;
+    let ref_wcs = Wcs::load_for_image(ref_file).ok();
+    let ref_positions = positions.iter()
+        .map(|pos| ref_pixel_pos(pos, ref_wcs.as_ref(), ref_file))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    drop(ref_layer);
+
+    progress.lock().unwrap().set_total(files.len());
+    files.par_iter()
+        .map(|file| {
+            if is_cancelled() { anyhow::bail!("Termimated"); }
+            let (layer, info) = load_grey(file)?;
+
+            // positions of stars in pixels of frame
+            let frame_positions: Vec<Option<(f64, f64)>> = if file == ref_file {
+                ref_positions.iter().copied().map(Some).collect()
+            } else {
+                let stars = find_stars_on_image(&layer, None, true, &stars_opts)?;
+                let offset = find_image_offset_by_stars(
+                    &ref_stars, &stars, layer.width() as f64, layer.height() as f64
+                );
+                match offset {
+                    Some(offset) => {
+                        // inverse of transform from frame to reference frame
+                        let [[a, b, c], [d, e, f]] = transform_matrix(
+                            layer.width(), layer.height(), offset.offset_x, offset.offset_y, offset.angle
+                        );
+                        let det = a * e - b * d;
+                        ref_positions.iter().map(|(x, y)| Some((
+                            ( e * (x - c) - b * (y - f)) / det,
+                            (-d * (x - c) + a * (y - f)) / det,
+                        ))).collect()
+                    }
+                    None => {
+                        log::warn!("Frame {} can't be aligned by stars", path_to_str(file));
+                        vec![None; ref_positions.len()]
+                    }
+                }
+            };
+            let stars = frame_positions.iter()
+                .map(|pos| pos.and_then(|(x, y)| measure_star(&layer, x, y, info.exp, opts)))
+                .collect();
+            progress.lock().unwrap().progress(true, extract_file_name(file));
+            Ok(FrameMeasurement {
+                file:    file.clone(),
+                jd:      julian_date_of_middle(&info),
+                airmass: airmass_opts.airmass_for(&info),
+                stars,
+            })
+        })
+        .collect()
+}
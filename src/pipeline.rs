@@ -0,0 +1,254 @@
+//! Stacking pipeline for embedding into other programs (capture software,
+//! GUIs). Frames are calibrated by [`Calibrator`], aligned to reference
+//! frame by [`Registrar`] and combined by [`Integrator`]:
+//!
+//! ```no_run
+//! use electra_stacking::*;
+//! # fn stack(lights: &[std::path::PathBuf], dark: &std::path::Path) -> anyhow::Result<()> {
+//! let calibrator = Calibrator::new(None, Some(dark), None)?;
+//! let registrar = Registrar::new(&calibrator, &lights[0])?;
+//! let mut integrator = Integrator::new(CalcOpts::default());
+//! for file in lights {
+//!     let light = calibrator.calibrate(file)?;
+//!     integrator.add(registrar.align(light)?)?;
+//! }
+//! let result = integrator.integrate()?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! All aligned frames are kept in memory by [`Integrator`]. Projects
+//! ([`project::Project`](crate::project::Project)) use temporary files
+//! instead and are suitable for sessions with hundreds of frames
+
+use std::path::Path;
+use rayon::prelude::*;
+use crate::{
+    image::*,
+    image_io::*,
+    image_raw::*,
+    image_norm::*,
+    light_file::*,
+    stars::*,
+    calc::*,
+    stacking_utils::*,
+    bg_mask::*,
+    resample::ResampleKernel,
+};
+
+/// Calibration of light files by master bias, dark and flat files
+/// (made by `--run` of project or by other software)
+pub struct Calibrator {
+    pub cal_data:   CalibrationData,
+    pub raw_params: RawOpenParams,
+    pub stars_opts: StarsFindOpts,
+    pub bin:        usize, // 1 (or 0) for no binning
+}
+
+impl Calibrator {
+    /// Loads master files. Hot pixels are found in master dark
+    pub fn new(
+        master_flat: Option<&Path>,
+        master_dark: Option<&Path>,
+        master_bias: Option<&Path>,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            cal_data:   CalibrationData::load(master_flat, master_dark, master_bias)?,
+            raw_params: RawOpenParams::default(),
+            stars_opts: StarsFindOpts::default(),
+            bin:        1,
+        })
+    }
+
+    /// Loads, calibrates and demosaics light file. Stars and noise
+    /// of result are found for registration and integration
+    pub fn calibrate(&self, file_name: &Path) -> anyhow::Result<LightFile> {
+        LightFile::load_and_calc_params(
+            file_name,
+            &self.cal_data,
+            LoadLightFlags::STARS | LoadLightFlags::NOISE,
+            OpenMode::Processing,
+            self.bin,
+            &self.raw_params,
+            &self.stars_opts,
+        )
+    }
+}
+
+/// Light frame aligned to reference one with range
+/// and background normalized to reference
+pub struct AlignedFrame {
+    pub image:  Image,
+    pub info:   ImageInfo,
+    pub offset: ImageOffset,
+    pub noise:  f32, // noise after normalization (defines weight of frame)
+}
+
+/// Alignment of light frames to reference frame by stars
+pub struct Registrar {
+    pub align_opts: LightsAlignOpts,
+    ref_data:       RefBgData,
+}
+
+impl Registrar {
+    pub fn new(calibrator: &Calibrator, ref_file_name: &Path) -> anyhow::Result<Self> {
+        let ref_data = RefBgData::new(
+            ref_file_name,
+            &calibrator.cal_data,
+            calibrator.bin,
+            &calibrator.raw_params,
+            &calibrator.stars_opts,
+            BgMaskPreset::Stars,
+        )?;
+        let align_opts = LightsAlignOpts {
+            translation_only: false,
+            by_wcs:           false,
+            skip_bad_lights:  false,
+            min_stars:        0,
+            field_rotation:   None,
+            interpolation:    ResampleKernel::Bilinear,
+        };
+        Ok(Self { align_opts, ref_data })
+    }
+
+    /// Offset and rotation of light frame relative to reference one.
+    /// None if they can't be found by stars
+    pub fn find_offset(&self, light: &LightFile) -> Option<ImageOffset> {
+        calc_light_file_offset(light, &self.ref_data, &self.align_opts)
+    }
+
+    /// Rotates and translates light frame to reference
+    /// one and normalizes its range and background
+    pub fn align(&self, mut light: LightFile) -> anyhow::Result<AlignedFrame> {
+        let ref_image = &self.ref_data.image.image;
+        if light.image.width() != ref_image.width() || light.image.height() != ref_image.height() {
+            anyhow::bail!(
+                "Size of light frame {}x{} differs from reference one {}x{}",
+                light.image.width(), light.image.height(),
+                ref_image.width(), ref_image.height()
+            );
+        }
+        let offset = self.find_offset(&light).ok_or_else(|| anyhow::anyhow!(
+            "Can't calculate offset and angle between reference image and light file"
+        ))?;
+        light.image = light.image.rotated_and_translated_by(
+            self.align_opts.interpolation,
+            -offset.angle,
+            -offset.offset_x,
+            -offset.offset_y,
+            NO_VALUE_F32,
+            light.image.width(),
+            light.image.height()
+        );
+        let norm_res = normalize_range_and_bg(&self.ref_data, &mut light)?;
+        let noise = if light.noise_est > 0.0 { light.noise_est } else { light.noise };
+        Ok(AlignedFrame {
+            image: light.image,
+            info:  light.info,
+            offset,
+            noise: noise * norm_res.range_factor,
+        })
+    }
+}
+
+/// Combination of aligned frames. Every frame is weighted by
+/// inverse square of its noise as in stacking of project
+pub struct Integrator {
+    pub calc_opts: CalcOpts,
+    frames:        Vec<AlignedFrame>,
+}
+
+impl Integrator {
+    pub fn new(calc_opts: CalcOpts) -> Self {
+        Self { calc_opts, frames: Vec::new() }
+    }
+
+    pub fn add(&mut self, frame: AlignedFrame) -> anyhow::Result<()> {
+        if let Some(first) = self.frames.first() {
+            if first.image.width() != frame.image.width()
+            || first.image.height() != frame.image.height()
+            || first.image.is_rgb() != frame.image.is_rgb() {
+                anyhow::bail!("Size or color type of frame differs from previous ones");
+            }
+        }
+        self.frames.push(frame);
+        Ok(())
+    }
+
+    pub fn frames_count(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Result is normalized to 0..1 range. Overexposed areas are filled by 1.0
+    pub fn integrate(&self) -> anyhow::Result<Image> {
+        let Some(first) = self.frames.first() else {
+            anyhow::bail!("No frames to integrate");
+        };
+        let (width, height) = (first.image.width(), first.image.height());
+        let min_noise = self.frames.iter().map(|f| f.noise).min_by(cmp_f32).unwrap_or(0.0);
+        let weights: Vec<f64> = self.frames.iter()
+            .map(|f| if f.noise > 0.0 { (min_noise as f64 / f.noise as f64).powi(2) } else { 1.0 })
+            .collect();
+
+        let is_rgb = first.image.is_rgb();
+        let mut result = Image::new();
+        if is_rgb {
+            result.make_color(width, height);
+        } else {
+            result.make_grey(width, height);
+        }
+        for channel in 0..if is_rgb { 3 } else { 1 } {
+            let sources: Vec<&[f32]> = self.frames.iter()
+                .map(|f| get_channel(&f.image, is_rgb, channel).as_slice())
+                .collect();
+            let layer = match (is_rgb, channel) {
+                (false, _) => &mut result.l,
+                (true, 0)  => &mut result.r,
+                (true, 1)  => &mut result.g,
+                _          => &mut result.b,
+            };
+            layer.as_slice_mut()
+                .par_chunks_mut(width as usize)
+                .enumerate()
+                .for_each(|(y, row)| {
+                    let mut values = Vec::with_capacity(sources.len());
+                    for (x, v) in row.iter_mut().enumerate() {
+                        let pos = y * width as usize + x;
+                        values.clear();
+                        values.extend(sources.iter().zip(&weights)
+                            .map(|(src, w)| (src[pos], *w))
+                            .filter(|(v, _)| *v != NO_VALUE_F32)
+                            .map(|(v, w)| CalcValue::new_weighted(v as f64, w))
+                        );
+                        *v = self.integrate_values(&mut values);
+                    }
+                });
+        }
+        result.check_contains_inf_or_nan(false, true)?;
+        result.normalize_to_1(false);
+        result.fill_inf_areas_with_one();
+        Ok(result)
+    }
+
+    // overexposed values are used only if all values are overexposed.
+    // Pixels out of all aligned frames are black
+    fn integrate_values(&self, values: &mut Vec<CalcValue>) -> f32 {
+        if values.is_empty() { return 0.0; }
+        if values.iter().all(|v| v.value.is_infinite()) {
+            return f32::INFINITY;
+        }
+        values.retain(|v| !v.value.is_infinite());
+        calc(values, &self.calc_opts)
+            .map(|r| r.result as f32)
+            .unwrap_or(NO_VALUE_F32)
+    }
+}
+
+fn get_channel(image: &Image, is_rgb: bool, channel: usize) -> &ImageLayerF32 {
+    match (is_rgb, channel) {
+        (false, _) => &image.l,
+        (true, 0)  => &image.r,
+        (true, 1)  => &image.g,
+        _          => &image.b,
+    }
+}
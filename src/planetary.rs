@@ -0,0 +1,392 @@
+use std::path::*;
+use rayon::prelude::*;
+use crate::{image::*, image_io::*, ser::*, progress::*, calc::*, integration_meta::*};
+
+/* Planetary (lucky imaging) stacking of SER videos. Frames are ranked by
+   sharpness (energy of laplacian), best of them are aligned globally by
+   centroid of planet disk and locally by alignment points (patches of
+   reference image). Shifts of alignment points warp every frame before
+   stacking so seeing distortions of different parts of disk are corrected */
+
+const REF_FRAMES: usize = 10; // count of best frames for reference image
+
+#[derive(Clone, Debug)]
+pub struct PlanetaryOpts {
+    pub best_percent: f32,   // percent of best frames to stack
+    pub ap_size:      usize, // size of alignment point patch
+    pub ap_search:    usize, // maximum local shift in pixels
+}
+
+impl Default for PlanetaryOpts {
+    fn default() -> Self {
+        Self {
+            best_percent: 25.0,
+            ap_size: 48,
+            ap_search: 8,
+        }
+    }
+}
+
+pub struct PlanetaryResult {
+    pub image:        Image,
+    pub frames_total: usize,
+    pub frames_used:  usize,
+    pub ap_count:     usize,
+    pub meta:         IntegrationMeta, // time range of used frames
+}
+
+// Simple 2d array of luminance for quality and alignment calculations
+struct Lum {
+    width:  usize,
+    height: usize,
+    data:   Vec<f32>,
+}
+
+impl Lum {
+    fn from_image(image: &Image) -> Self {
+        let layer = image.create_greyscale_layer();
+        Self {
+            width: layer.width() as usize,
+            height: layer.height() as usize,
+            data: layer.as_slice().to_vec(),
+        }
+    }
+
+    #[inline(always)]
+    fn get(&self, x: usize, y: usize) -> f32 {
+        self.data[y * self.width + x]
+    }
+
+    fn centroid(&self) -> (f64, f64) {
+        // pixels brighter than mean belong to planet disk
+        let mean = self.data.iter().map(|v| *v as f64).sum::<f64>() / self.data.len() as f64;
+        let (mut sx, mut sy, mut sw) = (0_f64, 0_f64, 0_f64);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let w = self.get(x, y) as f64 - mean;
+                if w <= 0.0 { continue; }
+                sx += w * x as f64;
+                sy += w * y as f64;
+                sw += w;
+            }
+        }
+        if sw == 0.0 {
+            return (self.width as f64 / 2.0, self.height as f64 / 2.0);
+        }
+        (sx / sw, sy / sw)
+    }
+}
+
+#[inline(always)]
+fn is_inside(width: usize, height: usize, x: f64, y: f64) -> bool {
+    x >= 0.0 && y >= 0.0 && (x as usize) + 1 < width && (y as usize) + 1 < height
+}
+
+// Coordinates must be checked by `is_inside`
+#[inline(always)]
+fn bilinear(data: &[f32], width: usize, x: f64, y: f64) -> f32 {
+    let (x0, y0) = (x as usize, y as usize);
+    let (fx, fy) = ((x - x0 as f64) as f32, (y - y0 as f64) as f32);
+    let i = y0 * width + x0;
+    let top = data[i] * (1.0 - fx) + data[i + 1] * fx;
+    let bottom = data[i + width] * (1.0 - fx) + data[i + width + 1] * fx;
+    top * (1.0 - fy) + bottom * fy
+}
+
+/// Sharpness of frame: mean squared laplacian of 2x2 binned luminance
+/// divided by squared mean brightness (so transparency changes don't matter)
+fn frame_quality(lum: &Lum) -> f64 {
+    let (width, height) = (lum.width / 2, lum.height / 2);
+    if width < 3 || height < 3 { return 0.0; }
+    let binned: Vec<f32> = (0..width * height).map(|i| {
+        let (x, y) = (2 * (i % width), 2 * (i / width));
+        lum.get(x, y) + lum.get(x + 1, y) + lum.get(x, y + 1) + lum.get(x + 1, y + 1)
+    }).collect();
+    let mean = binned.iter().map(|v| *v as f64).sum::<f64>() / binned.len() as f64;
+    if mean <= 0.0 { return 0.0; }
+    let mut sum = 0_f64;
+    for y in 1..height-1 {
+        for x in 1..width-1 {
+            let i = y * width + x;
+            let lap = 4.0 * binned[i] - binned[i - 1] - binned[i + 1] - binned[i - width] - binned[i + width];
+            sum += (lap as f64) * (lap as f64);
+        }
+    }
+    sum / ((width - 2) * (height - 2)) as f64 / (mean * mean)
+}
+
+struct AlignPoint {
+    x:     usize, // center of patch in reference image
+    y:     usize,
+    patch: Vec<f32>,
+}
+
+// Alignment points are placed by grid with half of patch step
+// on detailed parts of planet disk
+fn create_align_points(reference: &Lum, opts: &PlanetaryOpts) -> Vec<AlignPoint> {
+    let size = opts.ap_size.max(8);
+    let half = size / 2;
+    let margin = half + opts.ap_search + 1;
+    if reference.width <= 2 * margin || reference.height <= 2 * margin {
+        return Vec::new();
+    }
+    let mut values = reference.data.clone();
+    let median = median_f32(&mut values).unwrap_or(0.0);
+    let max = reference.data.iter().copied().fold(0.0_f32, f32::max);
+    let disk_level = median + 0.2 * (max - median);
+
+    let mut candidates = Vec::new();
+    for y in (margin..reference.height - margin).step_by(half) {
+        for x in (margin..reference.width - margin).step_by(half) {
+            let mut patch = Vec::with_capacity(size * size);
+            for py in 0..size {
+                for px in 0..size {
+                    patch.push(reference.get(x + px - half, y + py - half));
+                }
+            }
+            let mean = patch.iter().sum::<f32>() / patch.len() as f32;
+            if mean < disk_level { continue; }
+            let contrast = (patch.iter().map(|v| (v - mean) * (v - mean)).sum::<f32>() / patch.len() as f32).sqrt();
+            candidates.push((contrast, AlignPoint { x, y, patch }));
+        }
+    }
+    // patches without details can't be aligned reliably
+    let mut contrasts: Vec<f32> = candidates.iter().map(|(c, _)| *c).collect();
+    let min_contrast = 0.5 * median_f32(&mut contrasts).unwrap_or(0.0);
+    candidates.into_iter()
+        .filter(|(c, _)| *c >= min_contrast && *c > 0.0)
+        .map(|(_, ap)| ap)
+        .collect()
+}
+
+// Local shift of alignment point: integer search by sum of absolute
+// differences and subpixel refinement by parabola
+fn find_ap_shift(ap: &AlignPoint, frame: &Lum, global: (f64, f64), opts: &PlanetaryOpts) -> Option<(f64, f64)> {
+    let size = opts.ap_size.max(8);
+    let half = size as isize / 2;
+    let search = opts.ap_search as isize;
+    let cx = (ap.x as f64 + global.0).round() as isize;
+    let cy = (ap.y as f64 + global.1).round() as isize;
+    let (rx, ry) = (ap.x as f64 + global.0 - cx as f64, ap.y as f64 + global.1 - cy as f64);
+    if cx - half - search < 0 || cy - half - search < 0
+    || cx + half + search >= frame.width as isize || cy + half + search >= frame.height as isize {
+        return None;
+    }
+    let diff_at = |dx: isize, dy: isize| -> f32 {
+        let mut sum = 0_f32;
+        for py in 0..size as isize {
+            let fy = (cy + dy + py - half) as usize;
+            let row = &frame.data[fy * frame.width..];
+            let patch_row = &ap.patch[py as usize * size..(py as usize + 1) * size];
+            let fx0 = (cx + dx - half) as usize;
+            for (p, f) in patch_row.iter().zip(&row[fx0..fx0 + size]) {
+                sum += (p - f).abs();
+            }
+        }
+        sum
+    };
+    let mut best = (0, 0, f32::MAX);
+    for dy in -search..=search {
+        for dx in -search..=search {
+            let diff = diff_at(dx, dy);
+            if diff < best.2 { best = (dx, dy, diff); }
+        }
+    }
+    let (bx, by, bd) = best;
+    let parabola = |v1: f32, v2: f32, v3: f32| -> f64 {
+        let denom = v1 - 2.0 * v2 + v3;
+        if denom <= 0.0 { 0.0 } else { (0.5 * (v1 - v3) / denom).clamp(-0.5, 0.5) as f64 }
+    };
+    let sub_x = if bx.abs() < search { parabola(diff_at(bx - 1, by), bd, diff_at(bx + 1, by)) } else { 0.0 };
+    let sub_y = if by.abs() < search { parabola(diff_at(bx, by - 1), bd, diff_at(bx, by + 1)) } else { 0.0 };
+    Some((bx as f64 + sub_x - rx, by as f64 + sub_y - ry))
+}
+
+// Local shifts on coarse grid (gaussian weighted shifts of nearby
+// alignment points). Shifts between nodes are interpolated bilinearly
+struct ShiftField {
+    step:   usize,
+    cols:   usize,
+    rows:   usize,
+    shifts: Vec<(f64, f64)>,
+}
+
+impl ShiftField {
+    fn new(width: usize, height: usize, step: usize, points: &[(usize, usize, f64, f64)]) -> Self {
+        let cols = width / step + 2;
+        let rows = height / step + 2;
+        let sigma = step as f64;
+        let shifts = (0..cols * rows).map(|i| {
+            let (nx, ny) = (((i % cols) * step) as f64, ((i / cols) * step) as f64);
+            let (mut sx, mut sy, mut sw) = (0_f64, 0_f64, 0_f64);
+            for (x, y, dx, dy) in points {
+                let d2 = (*x as f64 - nx).powi(2) + (*y as f64 - ny).powi(2);
+                if d2 > 9.0 * sigma * sigma { continue; }
+                let w = (-0.5 * d2 / (sigma * sigma)).exp();
+                sx += w * dx;
+                sy += w * dy;
+                sw += w;
+            }
+            if sw > 0.0 { (sx / sw, sy / sw) } else { (0.0, 0.0) }
+        }).collect();
+        Self { step, cols, rows, shifts }
+    }
+
+    fn get(&self, x: usize, y: usize) -> (f64, f64) {
+        let (gx, gy) = (x as f64 / self.step as f64, y as f64 / self.step as f64);
+        let (x0, y0) = ((gx as usize).min(self.cols - 2), (gy as usize).min(self.rows - 2));
+        let (fx, fy) = (gx - x0 as f64, gy - y0 as f64);
+        let node = |cx: usize, cy: usize| self.shifts[cy * self.cols + cx];
+        let lerp = |a: (f64, f64), b: (f64, f64), k: f64| (a.0 + (b.0 - a.0) * k, a.1 + (b.1 - a.1) * k);
+        let top = lerp(node(x0, y0), node(x0 + 1, y0), fx);
+        let bottom = lerp(node(x0, y0 + 1), node(x0 + 1, y0 + 1), fx);
+        lerp(top, bottom, fy)
+    }
+}
+
+struct Accumulator {
+    sums:   Vec<Vec<f32>>, // for each channel
+    counts: Vec<f32>,
+}
+
+impl Accumulator {
+    fn new(channels: usize, size: usize) -> Self {
+        Self {
+            sums: vec![vec![0.0; size]; channels],
+            counts: vec![0.0; size],
+        }
+    }
+
+    fn add_frame(&mut self, frame: &Image, global: (f64, f64), field: Option<&ShiftField>) {
+        let (width, height) = (frame.width() as usize, frame.height() as usize);
+        let layers: Vec<&[f32]> = if frame.is_rgb() {
+            vec![frame.r.as_slice(), frame.g.as_slice(), frame.b.as_slice()]
+        } else {
+            vec![frame.l.as_slice()]
+        };
+        let mut sums: Vec<&mut [f32]> = self.sums.iter_mut().map(|s| s.as_mut_slice()).collect();
+        for y in 0..height {
+            for x in 0..width {
+                let (lx, ly) = field.map(|f| f.get(x, y)).unwrap_or((0.0, 0.0));
+                let (sx, sy) = (x as f64 + global.0 + lx, y as f64 + global.1 + ly);
+                if !is_inside(width, height, sx, sy) { continue; }
+                let i = y * width + x;
+                for (layer, sum) in layers.iter().zip(sums.iter_mut()) {
+                    sum[i] += bilinear(layer, width, sx, sy);
+                }
+                self.counts[i] += 1.0;
+            }
+        }
+    }
+
+    fn result(self, width: Crd, height: Crd) -> Image {
+        let mut image = if self.sums.len() == 3 {
+            Image::new_color(width, height)
+        } else {
+            Image::new_grey(width, height)
+        };
+        let layers = if image.is_rgb() {
+            vec![&mut image.r, &mut image.g, &mut image.b]
+        } else {
+            vec![&mut image.l]
+        };
+        for (layer, sum) in layers.into_iter().zip(&self.sums) {
+            for ((d, s), c) in layer.iter_mut().zip(sum).zip(&self.counts) {
+                *d = if *c > 0.0 { s / c } else { 0.0 };
+            }
+        }
+        image
+    }
+}
+
+pub fn stack_planetary_video(
+    ser_file: &Path,
+    opts:     &PlanetaryOpts,
+    progress: &ProgressTs,
+) -> anyhow::Result<PlanetaryResult> {
+    let mut ser = SerFile::open(ser_file)?;
+    if ser.frames == 0 {
+        anyhow::bail!("SER file has no frames");
+    }
+    let (width, height) = (ser.width, ser.height);
+
+    // ranking of frames by sharpness
+
+    progress.lock().unwrap().stage("Ranking frames by quality...");
+    progress.lock().unwrap().set_total(ser.frames);
+    let mut frames = Vec::with_capacity(ser.frames);
+    for index in 0..ser.frames {
+        let lum = Lum::from_image(&ser.read_frame_image(index)?);
+        frames.push((index, frame_quality(&lum), lum.centroid()));
+        progress.lock().unwrap().progress(true, &format!("frame {}", index + 1));
+    }
+    frames.sort_by(|(_, q1, _), (_, q2, _)| q2.total_cmp(q1));
+    let used_count = ((frames.len() as f32 * opts.best_percent / 100.0).round() as usize)
+        .clamp(1, frames.len());
+    frames.truncate(used_count);
+    log::info!(
+        "Best {} of {} frames are used (quality {:.3e}..{:.3e})",
+        used_count, ser.frames, frames.last().unwrap().1, frames[0].1
+    );
+
+    // reference image: best frames aligned by centroid
+
+    progress.lock().unwrap().stage("Creating reference image...");
+    let ref_centroid = frames[0].2;
+    let global_shift = |centroid: (f64, f64)| (centroid.0 - ref_centroid.0, centroid.1 - ref_centroid.1);
+    let mut ref_acc = Accumulator::new(1, width * height);
+    for (index, _, centroid) in frames.iter().take(REF_FRAMES) {
+        let lum = Lum::from_image(&ser.read_frame_image(*index)?);
+        let layer = ImageLayerF32::new_from_vec(width as Crd, height as Crd, lum.data);
+        let mut grey = Image::new_grey(width as Crd, height as Crd);
+        grey.l = layer;
+        ref_acc.add_frame(&grey, global_shift(*centroid), None);
+    }
+    let reference = Lum::from_image(&ref_acc.result(width as Crd, height as Crd));
+    let align_points = create_align_points(&reference, opts);
+    log::info!("{} alignment points are created", align_points.len());
+
+    // stacking with local warping
+
+    progress.lock().unwrap().stage("Stacking best frames...");
+    progress.lock().unwrap().set_total(frames.len());
+    let mut acc: Option<Accumulator> = None;
+    let mut meta = IntegrationMeta::default();
+    for (index, _, centroid) in &frames {
+        let frame = ser.read_frame_image(*index)?;
+        let frame_info = ImageInfo { file_time: ser.frame_time(*index), ..ImageInfo::default() };
+        meta.add_frame(&frame_info, ser_file);
+        let lum = Lum::from_image(&frame);
+        let global = global_shift(*centroid);
+        let points: Vec<_> = align_points.par_iter()
+            .filter_map(|ap| {
+                let (dx, dy) = find_ap_shift(ap, &lum, global, opts)?;
+                // alignment point with the biggest possible shift is not reliable
+                let limit = opts.ap_search as f64 - 0.5;
+                if dx.abs() >= limit || dy.abs() >= limit { return None; }
+                Some((ap.x, ap.y, dx, dy))
+            })
+            .collect();
+        let field = if points.is_empty() {
+            None
+        } else {
+            Some(ShiftField::new(width, height, opts.ap_size.max(8) / 2, &points))
+        };
+        let acc = acc.get_or_insert_with(|| {
+            Accumulator::new(if frame.is_rgb() { 3 } else { 1 }, width * height)
+        });
+        acc.add_frame(&frame, global, field.as_ref());
+        progress.lock().unwrap().progress(true, &format!("frame {}", index + 1));
+    }
+    let mut image = acc.unwrap().result(width as Crd, height as Crd);
+    image.normalize_to_1(true);
+    meta.files.dedup(); // all frames are from one video
+
+    Ok(PlanetaryResult {
+        image,
+        frames_total: ser.frames,
+        frames_used: used_count,
+        ap_count: align_points.len(),
+        meta,
+    })
+}
@@ -0,0 +1,155 @@
+use std::{path::*, process::Command};
+use fitsio::{FitsFile, tables::*};
+use crate::{image::*, image_io::*, image_raw::*, stars::*, light_file::*, wcs::*, fs_utils::*};
+
+/* Plate solving by local astrometry.net solver (solve-field with installed
+   index files). Stars are found by own detector and passed to solver as
+   list of coordinates so solver doesn't need to read image */
+
+const MAX_STARS: usize = 300;
+const MIN_STARS: usize = 10;
+const HINT_SCALE_TOLERANCE: f64 = 0.1;
+
+#[derive(Clone, Debug)]
+pub struct PlateSolveOpts {
+    pub ra:         Option<f64>, // degrees
+    pub dec:        Option<f64>, // degrees
+    pub radius:     f64, // search radius around ra and dec in degrees
+    pub scale_low:  Option<f64>, // arcseconds per pixel
+    pub scale_high: Option<f64>,
+    pub solver:     PathBuf, // path to solve-field
+    pub timeout:    u64, // seconds
+}
+
+impl Default for PlateSolveOpts {
+    fn default() -> Self {
+        Self {
+            ra: None,
+            dec: None,
+            radius: 5.0,
+            scale_low: None,
+            scale_high: None,
+            solver: PathBuf::new(),
+            timeout: 300,
+        }
+    }
+}
+
+pub struct PlateSolveResult {
+    pub wcs:        Wcs,
+    pub stars_used: usize,
+    pub width:      Crd,
+    pub height:     Crd,
+}
+
+fn write_xylist(file_name: &Path, stars: &[Star]) -> anyhow::Result<()> {
+    let mut fptr = FitsFile::create(file_name).overwrite().open()?;
+    let columns = [
+        ColumnDescription::new("X").with_type(ColumnDataType::Double).create()?,
+        ColumnDescription::new("Y").with_type(ColumnDataType::Double).create()?,
+        ColumnDescription::new("FLUX").with_type(ColumnDataType::Double).create()?,
+    ];
+    let hdu = fptr.create_table("SOURCES".to_string(), &columns)?;
+
+    // FITS pixel coordinates start from 1
+    let x: Vec<f64> = stars.iter().map(|s| s.x + 1.0).collect();
+    let y: Vec<f64> = stars.iter().map(|s| s.y + 1.0).collect();
+    let flux: Vec<f64> = stars.iter().map(|s| s.brightness).collect();
+    hdu.write_col(&mut fptr, "X", &x)?;
+    hdu.write_col(&mut fptr, "Y", &y)?;
+    hdu.write_col(&mut fptr, "FLUX", &flux)?;
+    Ok(())
+}
+
+// Position and pixel scale hints from FITS header (written by capture programs)
+fn hints_from_fits_header(file_name: &Path, opts: &mut PlateSolveOpts) {
+    if !is_fits_ext(extract_extension(file_name)) { return; }
+    let Ok(keys) = read_fits_float_keys(file_name, &["RA", "DEC", "FOCALLEN", "XPIXSZ", "XBINNING"]) else {
+        return;
+    };
+    if let (None, None, Some(ra), Some(dec)) = (opts.ra, opts.dec, keys.get("RA"), keys.get("DEC")) {
+        opts.ra = Some(*ra);
+        opts.dec = Some(*dec);
+    }
+    if let (None, None, Some(focal_len), Some(pix_size)) =
+    (opts.scale_low, opts.scale_high, keys.get("FOCALLEN"), keys.get("XPIXSZ")) {
+        if *focal_len > 0.0 {
+            let bin = keys.get("XBINNING").copied().unwrap_or(1.0).max(1.0);
+            let scale = 206.265 * pix_size * bin / focal_len;
+            opts.scale_low = Some(scale * (1.0 - HINT_SCALE_TOLERANCE));
+            opts.scale_high = Some(scale * (1.0 + HINT_SCALE_TOLERANCE));
+        }
+    }
+}
+
+fn run_solver(
+    work_dir: &Path,
+    stars:    &[Star],
+    width:    Crd,
+    height:   Crd,
+    opts:     &PlateSolveOpts
+) -> anyhow::Result<Wcs> {
+    let xy_file = work_dir.join("stars.xyls");
+    write_xylist(&xy_file, stars)?;
+
+    let mut cmd = Command::new(&opts.solver);
+    cmd.args(["--no-plots", "--overwrite", "--no-tweak"])
+        .args(["--x-column", "X", "--y-column", "Y"])
+        .args(["--width", &width.to_string(), "--height", &height.to_string()])
+        .args(["--cpulimit", &opts.timeout.to_string()])
+        .arg("--dir").arg(work_dir);
+    if let (Some(ra), Some(dec)) = (opts.ra, opts.dec) {
+        cmd.args(["--ra", &ra.to_string(), "--dec", &dec.to_string(), "--radius", &opts.radius.to_string()]);
+    }
+    if opts.scale_low.is_some() || opts.scale_high.is_some() {
+        cmd.args(["--scale-units", "arcsecperpix"]);
+        if let Some(low) = opts.scale_low { cmd.args(["--scale-low", &low.to_string()]); }
+        if let Some(high) = opts.scale_high { cmd.args(["--scale-high", &high.to_string()]); }
+    }
+    cmd.arg(&xy_file);
+    log::info!("Running plate solver {:?}", cmd);
+
+    let output = cmd.output().map_err(|err| anyhow::anyhow!(
+        "Can't run plate solver {} ({}). Install astrometry.net with index files \
+        or define path to solve-field",
+        opts.solver.to_str().unwrap_or(""), err
+    ))?;
+    log::info!("Plate solver output: {}", String::from_utf8_lossy(&output.stdout));
+
+    let wcs_file = xy_file.with_extension("wcs");
+    if !wcs_file.is_file() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let last_line = stderr.lines().rev().find(|l| !l.trim().is_empty()).unwrap_or("");
+        anyhow::bail!("Image is not solved {}", last_line);
+    }
+    Wcs::load_from_fits_file(&wcs_file)
+}
+
+pub fn plate_solve_image(file_name: &Path, opts: &PlateSolveOpts) -> anyhow::Result<PlateSolveResult> {
+    let mut opts = opts.clone();
+    hints_from_fits_header(file_name, &mut opts);
+
+    let grey = match load_image_from_file(file_name, false)?.image {
+        RawOrImage::Image(image) => image.create_greyscale_layer(),
+        RawOrImage::Raw(raw) => raw.demosaic(DemosaicAlgo::Linear, true)?.create_greyscale_layer(),
+    };
+    let noise = calc_noise(&grey) as f32;
+    let mut stars = find_stars_on_image(&grey, Some(noise), true, &StarsFindOpts::default())?;
+    stars.sort_by(|s1, s2| s2.brightness.total_cmp(&s1.brightness));
+    stars.truncate(MAX_STARS);
+    if stars.len() < MIN_STARS {
+        anyhow::bail!("Too few stars for plate solving ({})", stars.len());
+    }
+
+    let work_dir = std::env::temp_dir().join(format!("electra_plate_solve_{}", std::process::id()));
+    std::fs::create_dir_all(&work_dir)?;
+    let result = run_solver(&work_dir, &stars, grey.width(), grey.height(), &opts);
+    _ = std::fs::remove_dir_all(&work_dir);
+
+    Ok(PlateSolveResult {
+        wcs: result?,
+        stars_used: stars.len(),
+        width: grey.width(),
+        height: grey.height(),
+    })
+}
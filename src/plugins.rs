@@ -0,0 +1,218 @@
+use std::{path::*, io::{Read, Write}, process::*, sync::atomic::*};
+use serde::*;
+use crate::{image::*, image_io::*, fs_utils::*, log_utils::TimeLogger};
+
+/* Plugins are external programs which process image at some stage of
+   pipeline (custom denoisers, deconvolution, etc.). Image is passed as
+   32-bit float FITS file into stdin of program and processed image is
+   read from its stdout. In file mode paths of input and output FITS files
+   are passed in ELECTRA_PLUGIN_INPUT and ELECTRA_PLUGIN_OUTPUT variables
+   (files are in shared memory /dev/shm on Linux). Stage and source file
+   are passed in ELECTRA_PLUGIN_STAGE and ELECTRA_PLUGIN_SOURCE. Plugin
+   must return image of same size and number of channels and exit with
+   zero code. Text of stderr is logged */
+
+const PLUGIN_API_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum PluginStage {
+    Calibrated, // every light file after calibration and before registration
+    Result,     // stacked result (and sub-stacks) before saving
+}
+
+impl PluginStage {
+    pub fn from_str(text: &str) -> anyhow::Result<Self> {
+        match text.trim().to_lowercase().as_str() {
+            "calibrated" => Ok(PluginStage::Calibrated),
+            "result"     => Ok(PluginStage::Result),
+            _ => anyhow::bail!("Wrong plugin stage {} (calibrated or result)", text),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            PluginStage::Calibrated => "calibrated",
+            PluginStage::Result     => "result",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+pub enum PluginIo {
+    #[default]
+    Stdio, // FITS via stdin and stdout
+    File,  // FITS files in shared memory
+}
+
+impl PluginIo {
+    pub fn from_str(text: &str) -> anyhow::Result<Self> {
+        match text.to_lowercase().as_str() {
+            "stdio" => Ok(PluginIo::Stdio),
+            "file"  => Ok(PluginIo::File),
+            _ => anyhow::bail!("Wrong plugin I/O {} (stdio or file)", text),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PluginOpts {
+    pub stage:   PluginStage,
+    pub program: PathBuf,
+    #[serde(default)]
+    pub args:    Vec<String>,
+    #[serde(default)]
+    pub io:      PluginIo,
+}
+
+impl PluginOpts {
+    /// "<stage>:<program> [<arg> ...]"
+    pub fn from_str(text: &str) -> anyhow::Result<Self> {
+        let Some((stage, command)) = text.split_once(':') else {
+            anyhow::bail!("Wrong plugin {} (<stage>:<program> [<arg> ...])", text);
+        };
+        let mut items = command.split_whitespace();
+        let Some(program) = items.next() else {
+            anyhow::bail!("Program of plugin is not defined in {}", text);
+        };
+        Ok(Self {
+            stage:   PluginStage::from_str(stage)?,
+            program: PathBuf::from(program),
+            args:    items.map(str::to_string).collect(),
+            io:      PluginIo::default(),
+        })
+    }
+}
+
+/// Runs plugins of `stage` one by one over `image`
+pub fn run_plugins(
+    plugins:  &[PluginOpts],
+    stage:    PluginStage,
+    image:    &mut Image,
+    info:     &ImageInfo,
+    src_file: &Path,
+) -> anyhow::Result<()> {
+    for plugin in plugins.iter().filter(|p| p.stage == stage) {
+        let time_log = TimeLogger::start();
+        let result = run_plugin(plugin, image, info, src_file).map_err(|err| anyhow::anyhow!(
+            "Plugin {} failed for {}: {}",
+            path_to_str(&plugin.program), path_to_str(src_file), err
+        ))?;
+        *image = result;
+        time_log.log("running plugin");
+    }
+    Ok(())
+}
+
+fn plugin_work_dir() -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let shm_dir = Path::new("/dev/shm");
+    let base_dir = if shm_dir.is_dir() { shm_dir.to_path_buf() } else { std::env::temp_dir() };
+    base_dir.join(format!(
+        "electra_plugin_{}_{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+fn run_plugin(
+    plugin:   &PluginOpts,
+    image:    &Image,
+    info:     &ImageInfo,
+    src_file: &Path,
+) -> anyhow::Result<Image> {
+    let work_dir = plugin_work_dir();
+    std::fs::create_dir_all(&work_dir)?;
+    let result = run_plugin_in_dir(plugin, image, info, src_file, &work_dir);
+    _ = std::fs::remove_dir_all(&work_dir);
+    result
+}
+
+fn run_plugin_in_dir(
+    plugin:   &PluginOpts,
+    image:    &Image,
+    info:     &ImageInfo,
+    src_file: &Path,
+    work_dir: &Path,
+) -> anyhow::Result<Image> {
+    let input_file = work_dir.join("input.fits");
+    let output_file = work_dir.join("output.fits");
+    write_file_atomically(&input_file, |tmp_file_name| {
+        save_image_to_fits_file(image, info, tmp_file_name, FitsSaveOpts::default())
+    })?;
+
+    let mut cmd = Command::new(&plugin.program);
+    cmd.args(&plugin.args)
+        .env("ELECTRA_PLUGIN_API", PLUGIN_API_VERSION.to_string())
+        .env("ELECTRA_PLUGIN_STAGE", plugin.stage.name())
+        .env("ELECTRA_PLUGIN_SOURCE", src_file)
+        .stderr(Stdio::piped());
+    if plugin.io == PluginIo::File {
+        cmd.env("ELECTRA_PLUGIN_INPUT", &input_file)
+            .env("ELECTRA_PLUGIN_OUTPUT", &output_file)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped());
+    } else {
+        cmd.stdin(Stdio::piped()).stdout(Stdio::piped());
+    }
+    log::info!("Running plugin {:?}", cmd);
+    let mut child = cmd.spawn().map_err(|err| anyhow::anyhow!("Can't run program ({})", err))?;
+
+    // stdin is written in separate thread because plugin
+    // can start writing of result before reading of all input
+    let writer = child.stdin.take().map(|mut stdin| {
+        let data = std::fs::read(&input_file);
+        std::thread::spawn(move || -> anyhow::Result<()> {
+            stdin.write_all(&data?)?;
+            Ok(())
+        })
+    });
+    let mut stderr = child.stderr.take();
+    let stderr_reader = std::thread::spawn(move || {
+        let mut text = String::new();
+        if let Some(stderr) = &mut stderr {
+            _ = stderr.read_to_string(&mut text);
+        }
+        text
+    });
+    let mut stdout_data = Vec::new();
+    if let Some(stdout) = &mut child.stdout {
+        stdout.read_to_end(&mut stdout_data)?;
+    }
+    let status = child.wait()?;
+    let stderr_text = stderr_reader.join().unwrap_or_default();
+    if !stderr_text.trim().is_empty() {
+        log::info!("Plugin stderr: {}", stderr_text);
+    }
+    if !status.success() {
+        let last_line = stderr_text.lines().rev().find(|l| !l.trim().is_empty()).unwrap_or("");
+        anyhow::bail!("program exited with {} {}", status, last_line);
+    }
+    if let Some(writer) = writer {
+        writer.join().map_err(|_| anyhow::anyhow!("Writing into stdin of plugin panicked"))??;
+    }
+
+    if plugin.io == PluginIo::Stdio {
+        if stdout_data.is_empty() {
+            anyhow::bail!("program returned no image");
+        }
+        write_file_atomically(&output_file, |tmp_file_name| {
+            Ok(std::fs::write(tmp_file_name, &stdout_data)?)
+        })?;
+    } else if !stdout_data.is_empty() {
+        log::info!("Plugin output: {}", String::from_utf8_lossy(&stdout_data));
+    }
+
+    let RawOrImage::Image(result) = load_image_from_fits_file(&output_file, false, None)?.image else {
+        anyhow::bail!("program returned raw image");
+    };
+    if result.width() != image.width()
+    || result.height() != image.height()
+    || result.is_rgb() != image.is_rgb() {
+        anyhow::bail!(
+            "program returned image {}x{} ({} channel(s)) for image {}x{} ({} channel(s))",
+            result.width(), result.height(), if result.is_rgb() { 3 } else { 1 },
+            image.width(), image.height(), if image.is_rgb() { 3 } else { 1 },
+        );
+    }
+    Ok(result)
+}
@@ -0,0 +1,102 @@
+use serde::*;
+use crate::config::*;
+
+/* Power profile for processing on field laptop. On battery less threads are
+   used, new files of capture directory are processed by small portions and
+   number of threads is halved while CPU is hot to avoid thermal throttling */
+
+const BATTERY_CPU_PART: usize = 4; // quarter of CPUs on battery
+const BATTERY_FILES_CHUNK: usize = 2;
+const HOT_CPU_TEMPERATURE: f32 = 80.0; // °C
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum PowerProfile {
+    Performance,
+    Battery,
+    Auto, // battery profile if computer is not connected to mains
+}
+
+impl PowerProfile {
+    pub fn from_str(text: &str) -> anyhow::Result<Self> {
+        match text.to_lowercase().as_str() {
+            "performance" => Ok(PowerProfile::Performance),
+            "battery"     => Ok(PowerProfile::Battery),
+            "auto"        => Ok(PowerProfile::Auto),
+            _ => anyhow::bail!("Wrong power profile {} (performance, battery or auto)", text),
+        }
+    }
+
+    pub fn is_battery_mode(self) -> bool {
+        match self {
+            PowerProfile::Performance => false,
+            PowerProfile::Battery     => true,
+            PowerProfile::Auto        => is_on_battery_power(),
+        }
+    }
+
+    /// Limits CPU load by power profile and current CPU temperature
+    pub fn limit_cpu_load(self, cpu_load: CpuLoad) -> CpuLoad {
+        if self == PowerProfile::Performance {
+            return cpu_load;
+        }
+        let threads = cpu_load.to_threads_count();
+        let mut max_threads = if self.is_battery_mode() {
+            (num_cpus::get() / BATTERY_CPU_PART).max(1)
+        } else {
+            threads
+        };
+        if let Some(temperature) = cpu_temperature() {
+            if temperature >= HOT_CPU_TEMPERATURE {
+                log::info!("CPU temperature is {:.0}°C, count of threads is halved", temperature);
+                max_threads = (max_threads / 2).max(1);
+            }
+        }
+        if max_threads < threads {
+            CpuLoad::CustomCPUs(max_threads)
+        } else {
+            cpu_load
+        }
+    }
+
+    /// Maximum count of new files processed at once by watching commands
+    pub fn files_chunk_size(self) -> Option<usize> {
+        if self.is_battery_mode() {
+            Some(BATTERY_FILES_CHUNK)
+        } else {
+            None
+        }
+    }
+}
+
+// Power supplies and thermal zones are known only for linux (sysfs)
+
+fn read_sys_file(path: &std::path::Path) -> Option<String> {
+    std::fs::read_to_string(path).ok().map(|s| s.trim().to_string())
+}
+
+fn is_on_battery_power() -> bool {
+    let Ok(dir) = std::fs::read_dir("/sys/class/power_supply") else {
+        return false;
+    };
+    let mut has_battery = false;
+    let mut mains_online = false;
+    for entry in dir.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        match read_sys_file(&path.join("type")).as_deref() {
+            Some("Battery") => has_battery = true,
+            Some("Mains") =>
+                mains_online |= read_sys_file(&path.join("online")).as_deref() == Some("1"),
+            _ => {},
+        }
+    }
+    has_battery && !mains_online
+}
+
+fn cpu_temperature() -> Option<f32> {
+    let dir = std::fs::read_dir("/sys/class/thermal").ok()?;
+    dir.filter_map(|e| e.ok())
+        .filter(|e| e.file_name().to_str().map(|n| n.starts_with("thermal_zone")).unwrap_or(false))
+        .filter_map(|e| read_sys_file(&e.path().join("temp"))?.parse::<f32>().ok())
+        .map(|milli_degrees| milli_degrees / 1000.0)
+        .max_by(|a, b| a.total_cmp(b))
+}
@@ -0,0 +1,118 @@
+use crate::{project::*, calc::*, planetary::*, preview::*};
+
+/* Built-in presets of pipeline for most common rigs. Preset changes
+   settings of project and defaults of command line options, individual
+   options of command line override them */
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Preset {
+    DslrOsc,  // DSLR or one shot color camera, often without darks
+    MonoLrgb, // mono camera with filter wheel, groups of filters
+    Planetary, // SER videos of planets, Moon and Sun
+    EaaLive,  // electronically assisted astronomy: fast live stacking
+}
+
+/// Defaults of command line options defined by preset
+#[derive(Default)]
+pub struct PresetDefaults {
+    pub interval:    Option<u64>,
+    pub stretch:     Option<PreviewStretch>,
+    pub max_width:   Option<usize>,
+    pub crop_common: bool,
+    pub planetary:   Option<PlanetaryOpts>,
+}
+
+impl Preset {
+    pub const ALL: [Preset; 4] = [Preset::DslrOsc, Preset::MonoLrgb, Preset::Planetary, Preset::EaaLive];
+
+    pub fn from_str(text: &str) -> anyhow::Result<Self> {
+        Self::ALL.into_iter()
+            .find(|preset| preset.name().eq_ignore_ascii_case(text.trim()))
+            .ok_or_else(|| {
+                let names: Vec<_> = Self::ALL.iter().map(|p| p.name()).collect();
+                anyhow::anyhow!("Wrong preset {} ({})", text, names.join(", "))
+            })
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Preset::DslrOsc   => "dslr-osc",
+            Preset::MonoLrgb  => "mono-lrgb",
+            Preset::Planetary => "planetary",
+            Preset::EaaLive   => "eaa-live",
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            Preset::DslrOsc =>
+                "color RAW files, white balance and color matrix of camera, scaled darks \
+                or hot pixels by dithered light files",
+            Preset::MonoLrgb =>
+                "mono files without color processing, strict star requirements, \
+                results of groups cropped to common area",
+            Preset::Planetary =>
+                "lucky imaging of 20% best frames with big alignment points",
+            Preset::EaaLive =>
+                "binning 2x2, mean of frames, limited stars count and asinh preview for fast live view",
+        }
+    }
+
+    /// Planetary preset is only for SER videos, others are for projects
+    pub fn is_for_projects(self) -> bool {
+        self != Preset::Planetary
+    }
+
+    pub fn apply_to_project_config(self, config: &mut ProjectConfig) {
+        match self {
+            Preset::DslrOsc => {
+                config.raw_params.apply_wb = true;
+                config.raw_params.apply_color = true;
+                config.raw_params.optimize_dark = true; // temperature of DSLR is not regulated
+                config.light_calc_opts = CalcOpts::default();
+                config.align_rgb = true;
+                config.skip_bad_lights = true;
+                config.hot_pixels_by_lights = true;
+            },
+            Preset::MonoLrgb => {
+                config.raw_params.apply_wb = false;
+                config.raw_params.apply_color = false;
+                config.raw_params.optimize_dark = false;
+                config.light_calc_opts = CalcOpts { kappa: 3.0, ..CalcOpts::default() };
+                config.align_rgb = false;
+                config.skip_bad_lights = true;
+                config.min_stars_in_light = 20;
+            },
+            Preset::Planetary => {},
+            Preset::EaaLive => {
+                config.image_size = ImageSize::Bin2x2;
+                config.raw_params.apply_wb = true;
+                config.raw_params.apply_color = true;
+                config.light_calc_opts = CalcOpts { mode: CalcMode::Mean, ..CalcOpts::default() };
+                config.skip_bad_lights = true;
+                config.min_stars_in_light = 10;
+                config.stars_opts.max_stars = 200;
+            },
+        }
+    }
+
+    pub fn batch_defaults(self) -> PresetDefaults {
+        match self {
+            Preset::DslrOsc => PresetDefaults::default(),
+            Preset::MonoLrgb => PresetDefaults {
+                crop_common: true,
+                ..PresetDefaults::default()
+            },
+            Preset::Planetary => PresetDefaults {
+                planetary: Some(PlanetaryOpts { best_percent: 20.0, ap_size: 64, ap_search: 12 }),
+                ..PresetDefaults::default()
+            },
+            Preset::EaaLive => PresetDefaults {
+                interval:  Some(3),
+                stretch:   Some(PreviewStretch::Asinh),
+                max_width: Some(1920),
+                ..PresetDefaults::default()
+            },
+        }
+    }
+}
@@ -0,0 +1,317 @@
+use std::{path::*, fs::File, io::BufWriter};
+use serde::*;
+use rayon::prelude::*;
+use crate::{image::*, calc::*, fs_utils::*, colormap::*};
+
+/* Export of auto-stretched 8-bit previews (PNG or JPEG) */
+
+pub const PREVIEW_EXTS: &[&str] = &["png", "jpg", "jpeg"];
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum PreviewStretch {
+    Mtf,   // midtones transfer function
+    Asinh,
+}
+
+impl PreviewStretch {
+    pub fn from_str(text: &str) -> anyhow::Result<Self> {
+        match text.to_lowercase().as_str() {
+            "mtf"   => Ok(PreviewStretch::Mtf),
+            "asinh" => Ok(PreviewStretch::Asinh),
+            _ => anyhow::bail!("Wrong stretch {} (mtf or asinh are supported)", text),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PreviewOpts {
+    pub stretch:   PreviewStretch,
+    pub max_width: Option<usize>, // image is halved until it fits
+    pub quality:   u8, // for JPEG
+}
+
+impl Default for PreviewOpts {
+    fn default() -> Self {
+        Self {
+            stretch: PreviewStretch::Mtf,
+            max_width: None,
+            quality: 90,
+        }
+    }
+}
+
+const SHADOWS_CLIP: f32 = -2.8; // in normalized MAD units
+const TARGET_BG: f32 = 0.25;
+
+/// Parameters of auto-stretch. Previews use default ones
+#[derive(Clone, Debug)]
+pub struct StretchOpts {
+    pub stretch:    PreviewStretch,
+    pub black_clip: f32, // black point relative to background in normalized MAD units
+    pub target_bg:  f32, // background level after stretch (strength)
+}
+
+impl Default for StretchOpts {
+    fn default() -> Self {
+        Self {
+            stretch: PreviewStretch::Mtf,
+            black_clip: SHADOWS_CLIP,
+            target_bg: TARGET_BG,
+        }
+    }
+}
+
+// Background level (median) and shadows clipping point of layer
+fn calc_bg_and_black(layer: &ImageLayerF32, black_clip: f32) -> (f32, f32) {
+    let step = (layer.as_slice().len() / 200_000).max(1);
+    let mut values: Vec<_> = layer.as_slice()
+        .iter()
+        .step_by(step)
+        .filter(|v| v.is_finite() && **v != NO_VALUE_F32)
+        .copied()
+        .collect();
+    let Some(median) = median_f32(&mut values) else {
+        return (0.0, 0.0);
+    };
+    for v in &mut values {
+        *v = (*v - median).abs();
+    }
+    let mad = median_f32(&mut values).unwrap_or(0.0) * 1.4826;
+    let black = (median + black_clip * mad).clamp(0.0, median);
+    (median, black)
+}
+
+fn mtf(m: f32, x: f32) -> f32 {
+    if x <= 0.0 { return 0.0; }
+    if x >= 1.0 { return 1.0; }
+    ((m - 1.0) * x) / ((2.0 * m - 1.0) * x - m)
+}
+
+// Finds beta for asinh(beta*x)/asinh(beta) = y
+fn find_asinh_beta(x: f32, y: f32) -> f32 {
+    let (mut low, mut high) = (1e-3_f32, 1e6_f32);
+    for _ in 0..60 {
+        let beta = (low * high).sqrt();
+        if (beta * x).asinh() / beta.asinh() < y {
+            low = beta;
+        } else {
+            high = beta;
+        }
+    }
+    (low * high).sqrt()
+}
+
+// Function of stretching of layer values into 0..1 range
+fn create_stretch_fun(layer: &ImageLayerF32, opts: &StretchOpts) -> impl Fn(f32) -> f32 + Send + Sync {
+    let (bg, black) = calc_bg_and_black(layer, opts.black_clip);
+    let range = if black < 1.0 { 1.0 / (1.0 - black) } else { 1.0 };
+    let bg = ((bg - black) * range).clamp(0.0, 1.0);
+    let target_bg = opts.target_bg;
+
+    let fun: Box<dyn Fn(f32) -> f32 + Send + Sync> = match opts.stretch {
+        PreviewStretch::Mtf => {
+            // midtones balance which maps background into target level
+            let m = if bg > 0.0 { mtf(target_bg, bg) } else { 0.5 };
+            Box::new(move |x| mtf(m, x))
+        }
+        PreviewStretch::Asinh => {
+            if bg > 0.0 && bg < target_bg {
+                let beta = find_asinh_beta(bg, target_bg);
+                let div = beta.asinh();
+                Box::new(move |x| (beta * x).asinh() / div)
+            } else {
+                Box::new(|x| x)
+            }
+        }
+    };
+
+    move |v| {
+        if v.is_nan() || v == NO_VALUE_F32 { return 0.0; }
+        fun(((v - black) * range).clamp(0.0, 1.0))
+    }
+}
+
+fn stretch_layer(layer: &ImageLayerF32, stretch: PreviewStretch) -> Vec<u8> {
+    let opts = StretchOpts { stretch, ..StretchOpts::default() };
+    let fun = create_stretch_fun(layer, &opts);
+    layer_to_bytes(layer, &fun)
+}
+
+fn layer_to_bytes(layer: &ImageLayerF32, fun: &(dyn Fn(f32) -> f32 + Sync)) -> Vec<u8> {
+    layer.as_slice()
+        .iter()
+        .map(|v| {
+            if !v.is_finite() { return 0; }
+            (255.0 * fun(*v) + 0.5).clamp(0.0, 255.0) as u8
+        })
+        .collect()
+}
+
+/// Non-linear copy of image in 0..1 range. Channels of RGB image
+/// are stretched independently to neutralize background
+pub fn stretch_image(image: &Image, opts: &StretchOpts) -> Image {
+    let stretch = |layer: &ImageLayerF32| {
+        if layer.is_empty() { return ImageLayerF32::new_empty(); }
+        let fun = create_stretch_fun(layer, opts);
+        let mut result = layer.clone();
+        result.as_slice_mut().par_iter_mut().for_each(|v| *v = fun(*v));
+        result
+    };
+    Image {
+        l: stretch(&image.l),
+        r: stretch(&image.r),
+        g: stretch(&image.g),
+        b: stretch(&image.b),
+    }
+}
+
+// Image is halved until it fits into max_width
+fn reduce_for_preview(image: &Image, opts: &PreviewOpts) -> Option<Image> {
+    let mut reduced = None;
+    if let Some(max_width) = opts.max_width {
+        while reduced.as_ref().unwrap_or(image).width() as usize > max_width.max(1) {
+            reduced = Some(reduced.as_ref().unwrap_or(image).decrease_2x());
+        }
+    }
+    reduced
+}
+
+/// Returns width, height and RGB or grey bytes of stretched image
+pub fn create_stretched_preview(
+    image: &Image,
+    opts:  &PreviewOpts,
+) -> (usize, usize, Vec<u8>) {
+    let reduced = reduce_for_preview(image, opts);
+    let image = reduced.as_ref().unwrap_or(image);
+    let width = image.width() as usize;
+    let height = image.height() as usize;
+
+    // Channels are stretched independently to neutralize background
+    let bytes = if image.is_rgb() {
+        let r = stretch_layer(&image.r, opts.stretch);
+        let g = stretch_layer(&image.g, opts.stretch);
+        let b = stretch_layer(&image.b, opts.stretch);
+        itertools::izip!(r, g, b)
+            .flat_map(|(r, g, b)| [r, g, b])
+            .collect()
+    } else {
+        stretch_layer(&image.l, opts.stretch)
+    };
+    (width, height, bytes)
+}
+
+type StretchFun = Box<dyn Fn(f32) -> f32 + Send + Sync>;
+
+/// Auto-stretch calculated for one image and applied unchanged to
+/// other images (frames of timelapse) so changes of brightness are kept
+pub struct FixedStretch {
+    funs:   Vec<StretchFun>, // L or R, G, B
+    is_rgb: bool,
+}
+
+impl FixedStretch {
+    pub fn new(image: &Image, stretch: PreviewStretch) -> Self {
+        let opts = StretchOpts { stretch, ..StretchOpts::default() };
+        let fun = |layer: &ImageLayerF32| -> StretchFun {
+            Box::new(create_stretch_fun(layer, &opts))
+        };
+        let funs = if image.is_rgb() {
+            vec![fun(&image.r), fun(&image.g), fun(&image.b)]
+        } else {
+            vec![fun(&image.l)]
+        };
+        Self { funs, is_rgb: image.is_rgb() }
+    }
+
+    pub fn is_rgb(&self) -> bool {
+        self.is_rgb
+    }
+
+    /// Returns width, height and RGB or grey bytes of stretched image
+    pub fn apply(&self, image: &Image, max_width: Option<usize>) -> anyhow::Result<(usize, usize, Vec<u8>)> {
+        if image.is_rgb() != self.is_rgb {
+            anyhow::bail!("Mono and color images can't be mixed");
+        }
+        let opts = PreviewOpts { max_width, ..PreviewOpts::default() };
+        let reduced = reduce_for_preview(image, &opts);
+        let image = reduced.as_ref().unwrap_or(image);
+        let bytes = if self.is_rgb {
+            let r = layer_to_bytes(&image.r, &self.funs[0]);
+            let g = layer_to_bytes(&image.g, &self.funs[1]);
+            let b = layer_to_bytes(&image.b, &self.funs[2]);
+            itertools::izip!(r, g, b)
+                .flat_map(|(r, g, b)| [r, g, b])
+                .collect()
+        } else {
+            layer_to_bytes(&image.l, &self.funs[0])
+        };
+        Ok((image.width() as usize, image.height() as usize, bytes))
+    }
+}
+
+/// Returns width, height and RGB bytes of stretched mono image in false colors
+pub fn create_colormapped_preview(
+    image:    &Image,
+    opts:     &PreviewOpts,
+    colormap: &Colormap,
+) -> anyhow::Result<(usize, usize, Vec<u8>)> {
+    if image.l.is_empty() {
+        anyhow::bail!("Color map can be applied only to mono image");
+    }
+    let reduced = reduce_for_preview(image, opts);
+    let image = reduced.as_ref().unwrap_or(image);
+    let bytes = stretch_layer(&image.l, opts.stretch)
+        .into_iter()
+        .flat_map(|v| colormap.color(v as f32 / 255.0))
+        .collect();
+    Ok((image.width() as usize, image.height() as usize, bytes))
+}
+
+pub fn save_preview_file(
+    image:     &Image,
+    file_name: &Path,
+    opts:      &PreviewOpts,
+) -> anyhow::Result<()> {
+    let (width, height, bytes) = create_stretched_preview(image, opts);
+    save_8bit_image(file_name, width, height, image.is_rgb(), &bytes, opts.quality)
+}
+
+/// Saves RGB or grey bytes into PNG or JPEG file (by extension)
+pub fn save_8bit_image(
+    file_name: &Path,
+    width:     usize,
+    height:    usize,
+    is_rgb:    bool,
+    bytes:     &[u8],
+    quality:   u8,
+) -> anyhow::Result<()> {
+    let ext = extract_extension(file_name).to_lowercase();
+    if !PREVIEW_EXTS.contains(&ext.as_str()) {
+        anyhow::bail!("Preview file must have extension png or jpg");
+    }
+    write_file_atomically(file_name, |tmp_file_name| {
+        if ext == "png" {
+            let writer = BufWriter::new(File::create(tmp_file_name)?);
+            let mut encoder = png::Encoder::new(writer, width as u32, height as u32);
+            encoder.set_color(if is_rgb { png::ColorType::Rgb } else { png::ColorType::Grayscale });
+            encoder.set_depth(png::BitDepth::Eight);
+            let mut writer = encoder.write_header()?;
+            writer.write_image_data(bytes)?;
+            writer.finish()?;
+        } else {
+            if width > u16::MAX as usize || height > u16::MAX as usize {
+                anyhow::bail!("Image is too big for JPEG");
+            }
+            let encoder = jpeg_encoder::Encoder::new_file(tmp_file_name, quality)?;
+            let color = if is_rgb { jpeg_encoder::ColorType::Rgb } else { jpeg_encoder::ColorType::Luma };
+            encoder.encode(bytes, width as u16, height as u16, color)?;
+        }
+        Ok(())
+    })
+}
+
+/// File name of preview saved near result file
+pub fn get_preview_file_name(file_name: &Path) -> PathBuf {
+    let stem = file_name.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+    file_name.with_file_name(format!("{}.preview.jpg", stem))
+}
@@ -1,4 +1,5 @@
-use std::{io::stdout, io::Write, sync::*};
+use std::{io::stdout, io::Write, io::IsTerminal, sync::*, time::*};
+use crate::{log_utils::*, report};
 
 pub trait Progress {
     fn stage(&mut self, text: &str);
@@ -11,11 +12,48 @@ pub type IsCancelledFun = Arc::<dyn Fn() -> bool + Send + Sync + 'static>;
 
 pub type ProgressTs = Arc<Mutex<dyn Progress + Send>>;
 
+/* Estimated time of finishing */
+
+struct Eta {
+    start: Instant,
+}
+
+impl Eta {
+    fn new() -> Self {
+        Self { start: Instant::now() }
+    }
+
+    fn restart(&mut self) {
+        self.start = Instant::now();
+    }
+
+    fn remaining(&self, pos: usize, total: usize) -> Option<Duration> {
+        if pos == 0 || pos >= total { return None; }
+        let elapsed = self.start.elapsed().as_secs_f64();
+        if elapsed < 1.0 { return None; }
+        let remaining = elapsed * (total - pos) as f64 / pos as f64;
+        Some(Duration::from_secs_f64(remaining))
+    }
+}
+
+pub fn duration_to_str(duration: Duration) -> String {
+    let secs_total = duration.as_secs();
+    let minutes_total = secs_total / 60;
+    let hours = minutes_total / 60;
+    if hours != 0 {
+        format!("{}:{:02}:{:02}", hours, minutes_total % 60, secs_total % 60)
+    } else {
+        format!("{:02}:{:02}", minutes_total % 60, secs_total % 60)
+    }
+}
+
 pub struct ProgressConsole {
     pos: usize,
     total: usize,
     prev_percent: usize,
     prev_text: String,
+    eta: Eta,
+    is_terminal: bool,
 }
 
 impl ProgressConsole {
@@ -24,7 +62,9 @@ impl ProgressConsole {
             pos: 0,
             total: 1,
             prev_percent: 101,
-            prev_text: String::new()
+            prev_text: String::new(),
+            eta: Eta::new(),
+            is_terminal: stdout().is_terminal(),
         }
     }
 
@@ -41,12 +81,21 @@ impl ProgressConsole {
         if percent == self.prev_percent && text == self.prev_text {
             return;
         }
-        if self.prev_percent > percent { println!();}
-        print!("{:3}% [", percent);
-        for _ in 0..width { print!("#"); }
-        for _ in width..MAX_WIDTH { print!("-"); }
-        print!("] {}                   \r", text);
-        stdout().flush().unwrap();
+        let eta_str = self.eta
+            .remaining(self.pos, self.total)
+            .map(|eta| format!(" ETA {}", duration_to_str(eta)))
+            .unwrap_or_default();
+        if self.is_terminal && !is_quiet() {
+            if self.prev_percent > percent { println!();}
+            print!("{:3}% [", percent);
+            for _ in 0..width { print!("#"); }
+            for _ in width..MAX_WIDTH { print!("-"); }
+            print!("]{} {}                   \r", eta_str, text);
+            stdout().flush().unwrap();
+        } else if !is_quiet() {
+            // plain lines for log files and pipes
+            println!("{:3}%{} {}", percent, eta_str, text);
+        }
         if text != self.prev_text { log::info!("{}", text); }
         self.prev_text = text.to_string();
         self.prev_percent = percent;
@@ -56,15 +105,17 @@ impl ProgressConsole {
 impl Progress for ProgressConsole {
     fn stage(&mut self, text: &str) {
         if self.pos != 0 {
-            println!();
+            if !is_quiet() { println!(); }
             self.pos = 0;
         }
-        println!("{}", text);
+        report!("{}", text);
+        self.eta.restart();
     }
 
     fn set_total(&mut self, total: usize) {
         self.total = total;
         self.pos = 0;
+        self.eta.restart();
     }
 
     fn progress(&mut self, step: bool, text: &str) {
@@ -84,21 +135,23 @@ pub struct ProgressCallBack {
     total: usize,
     prev_percent: usize,
     prev_text: String,
+    eta: Eta,
     stage_cb: Box<dyn Fn(&str) + Send + 'static>,
-    progress_cb: Box<dyn Fn(usize, &str) + Send + 'static>,
+    progress_cb: Box<dyn Fn(usize, Option<Duration>, &str) + Send + 'static>,
 }
 
 impl ProgressCallBack {
     pub fn new_ts<SF, PF>(stage_fun: SF, progress_fun: PF) -> ProgressTs
     where
         SF: Fn(&str) + Send + 'static,
-        PF: Fn(usize, &str) + Send + 'static,
+        PF: Fn(usize, Option<Duration>, &str) + Send + 'static,
     {
         Arc::new(Mutex::new(ProgressCallBack {
             pos: 0,
             total: 1,
             prev_percent: 101,
             prev_text: String::new(),
+            eta: Eta::new(),
             stage_cb: Box::new(stage_fun),
             progress_cb: Box::new(progress_fun),
         }))
@@ -111,18 +164,21 @@ impl ProgressCallBack {
         }
         self.prev_percent = percent;
         self.prev_text = text.to_string();
-        (*self.progress_cb)(percent, text);
+        let eta = self.eta.remaining(self.pos, self.total);
+        (*self.progress_cb)(percent, eta, text);
     }
 }
 
 impl Progress for ProgressCallBack {
     fn stage(&mut self, text: &str) {
+        self.eta.restart();
         (*self.stage_cb)(text);
     }
 
     fn set_total(&mut self, total: usize) {
         self.total = total;
         self.pos = 0;
+        self.eta.restart();
     }
 
     fn progress(&mut self, step: bool, text: &str) {
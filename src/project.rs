@@ -1,10 +1,10 @@
 use std::{path::*, io::*, fs::*, collections::*, rc::*, cell::*};
 use std::sync::Mutex;
 use serde::*;
-use gettextrs::*;
 use chrono::prelude::*;
 use crate::{
     log_utils::TimeLogger,
+    str_utils::tr,
     calc::*,
     progress::*,
     stacking_utils::*,
@@ -13,12 +13,22 @@ use crate::{
     image_norm::*,
     image_io::*,
     fs_utils::*,
-    config::*
+    config::*,
+    field_rotation::*,
+    stars::*,
+    bg_mask::*,
+    airmass::*,
+    plugins::*,
+    resample::ResampleKernel,
 };
 
 const MASTER_DARK_FN: &str = "master-dark.es_raw";
 const MASTER_FLAT_FN: &str = "master-flat.es_raw";
 const MASTER_BIAS_FN: &str = "master-bias.es_raw";
+const MASTER_FLAT_END_FN: &str = "master-flat-end.es_raw";
+
+// minimal pause between flat files of start and end of session
+const FLAT_DRIFT_MIN_GAP_MINUTES: i64 = 30;
 
 #[derive(Serialize, Deserialize, Clone, Default)]
 #[serde(default)]
@@ -28,9 +38,14 @@ pub struct Project {
     groups: Vec<ProjectGroup>,
     ref_image: Option<PathBuf>,
     file_name: Option<PathBuf>,
+    frames_check_key: Option<String>, // of files which passed compatibility check
 
     #[serde(skip)]
     changed: Rc<Cell<bool>>,
+
+    // settings of project if `config` is changed for current run only
+    #[serde(skip)]
+    saved_config: Option<ProjectConfig>,
 }
 
 pub enum CanExecStackLightsRes {
@@ -76,11 +91,22 @@ impl Project {
     }
 
     pub fn save(&mut self, file_name: &Path) -> anyhow::Result<()> {
-        let writer = BufWriter::new(File::create(file_name)?);
         self.file_name = Some(file_name.to_path_buf());
         self.make_file_names_relative();
-        serde_json::to_writer_pretty(writer, self)?;
+        // settings of current run are not saved
+        let run_config = self.saved_config.take()
+            .map(|saved| std::mem::replace(&mut self.config, saved));
+        let save_res = write_file_atomically(file_name, |file_name| {
+            let mut writer = BufWriter::new(File::create(file_name)?);
+            serde_json::to_writer_pretty(&mut writer, self)?;
+            writer.flush()?;
+            Ok(())
+        });
+        if let Some(run_config) = run_config {
+            self.saved_config = Some(std::mem::replace(&mut self.config, run_config));
+        }
         self.make_file_names_absolute();
+        save_res?;
         self.reset_changed_flag();
         Ok(())
     }
@@ -113,9 +139,24 @@ impl Project {
 
     pub fn set_config(&mut self, new_config: ProjectConfig) {
         self.config = new_config;
+        self.saved_config = None;
         self.changed.set(true);
     }
 
+    /// Settings of project without changes of current run
+    pub fn saved_config(&self) -> &ProjectConfig {
+        self.saved_config.as_ref().unwrap_or(&self.config)
+    }
+
+    /// Settings for current run only (preset and options of command line).
+    /// They are used for processing but are not saved into project file
+    pub fn set_run_config(&mut self, run_config: ProjectConfig) {
+        if self.saved_config.is_none() {
+            self.saved_config = Some(self.config.clone());
+        }
+        self.config = run_config;
+    }
+
     pub fn cleanup_conf(&self) -> &CleanupConf {
         &self.cleanup_conf
     }
@@ -195,6 +236,7 @@ impl Project {
 
     pub fn set_new_config(&mut self, config: ProjectConfig) {
         self.config = config;
+        self.saved_config = None;
         self.changed.set(true);
     }
 
@@ -210,9 +252,29 @@ impl Project {
         progress:    &ProgressTs,
         cancel_flag: &IsCancelledFun,
         cpu_load:    CpuLoad,
+    ) -> anyhow::Result<HashMap<PathBuf, anyhow::Result<RegInfo>>> {
+        self.register_light_files_impl(progress, cancel_flag, cpu_load, false)
+    }
+
+    /// Registers only light files which were not registered before
+    pub fn register_new_light_files(
+        &self,
+        progress:    &ProgressTs,
+        cancel_flag: &IsCancelledFun,
+        cpu_load:    CpuLoad,
+    ) -> anyhow::Result<HashMap<PathBuf, anyhow::Result<RegInfo>>> {
+        self.register_light_files_impl(progress, cancel_flag, cpu_load, true)
+    }
+
+    fn register_light_files_impl(
+        &self,
+        progress:    &ProgressTs,
+        cancel_flag: &IsCancelledFun,
+        cpu_load:    CpuLoad,
+        only_new:    bool,
     ) -> anyhow::Result<HashMap<PathBuf, anyhow::Result<RegInfo>>> {
         if self.get_total_light_files() == 0 {
-            anyhow::bail!(gettext("No files to register"));
+            anyhow::bail!(tr("No files to register"));
         }
 
         let thread_pool = rayon::ThreadPoolBuilder::new()
@@ -241,7 +303,9 @@ impl Project {
                 &thread_pool,
                 &result,
                 self.config.save_common_star_img,
-                &self.config.raw_params
+                &self.config.raw_params,
+                &self.config.stars_opts,
+                only_new
             )?;
         }
 
@@ -261,6 +325,32 @@ impl Project {
         true
     }
 
+    /// Key of used light and calibration files (names, sizes and
+    /// modification times) for caching of compatibility check
+    fn calc_frames_check_key(&self) -> String {
+        let mut key_src = self.config.raw_params.fits_hdu.clone();
+        for group in self.groups.iter().filter(|g| g.used) {
+            for file_type in [ProjectFileType::Light, ProjectFileType::Dark, ProjectFileType::Flat, ProjectFileType::Bias] {
+                for file in group.get_file_list_by_type(file_type).list().iter().filter(|f| f.used()) {
+                    key_src.push('|');
+                    key_src.push_str(&get_file_state_str(file.file_name()));
+                }
+            }
+        }
+        format!("{:016x}", fnv1a_hash(key_src.as_bytes()))
+    }
+
+    /// Compatibility check of files is passed and
+    /// files are not changed after that
+    pub fn is_frames_check_passed(&self) -> bool {
+        self.frames_check_key.as_deref() == Some(self.calc_frames_check_key().as_str())
+    }
+
+    pub fn set_frames_check_passed(&mut self) {
+        self.frames_check_key = Some(self.calc_frames_check_key());
+        self.changed.set(true);
+    }
+
     pub fn is_any_used_light_file(&self) -> bool {
         self.groups.iter().any(|g|
             g.used && g.light_files.list.iter().any(|f|
@@ -301,29 +391,68 @@ impl Project {
         CanExecStackLightsRes::Ok
     }
 
-    pub fn assign_ref_light_frame_automatically(&mut self) {
+    pub fn assign_ref_light_frame_automatically(&mut self) -> Option<RefImageChoice> {
         assert!(self.is_possible_assign_ref_light_frame_automatically());
-        let group = &mut self.groups[0];
-        let key_fun = match self.config.ref_image_auto_mode {
-            RefImageAutoMode::SmallestStars => |info: &RegInfo| info.fwhm,
-            RefImageAutoMode::RoundestStars => |info: &RegInfo| info.stars_r_dev,
-            RefImageAutoMode::MinBg         => |info: &RegInfo| info.background,
-            RefImageAutoMode::NinNoise      => |info: &RegInfo| info.noise,
-        };
-        let min_file = group.light_files.list
-            .iter_mut()
+        let choice = self.choose_ref_light_frame(0)?;
+        self.set_ref_image(choice.file_name.clone());
+        Some(choice)
+    }
+
+    fn choose_ref_light_frame(&self, group_index: usize) -> Option<RefImageChoice> {
+        let group = &self.groups[group_index];
+        let used_files: Vec<_> = group.light_files.list
+            .iter()
             .filter(|f| f.used)
-            .min_by(|f1, f2| {
-                if let (Some(info1), Some(info2)) = (&f1.reg_info, &f2.reg_info) {
-                    cmp_f32(&key_fun(info1), &key_fun(info2))
-                } else {
-                    core::cmp::Ordering::Equal
-                }
-            });
-        if let Some(file) = min_file {
-            let file_name = file.file_name.clone();
-            self.set_ref_image(file_name);
+            .filter_map(|f| f.reg_info.as_ref().map(|info| (f, info)))
+            .collect();
+        if used_files.is_empty() {
+            return None;
         }
+
+        let choice = if let RefImageAutoMode::Best = self.config.ref_image_auto_mode {
+            // Score is number of stars divided by FWHM. Files with
+            // background far from median level (twilight, clouds,
+            // light of moon) are penalized
+            let mut bgs: Vec<f32> = used_files.iter().map(|(_, info)| info.background).collect();
+            let bg_median = median_f32(&mut bgs).unwrap_or(0.0);
+            let mut bg_devs: Vec<f32> = bgs.iter().map(|bg| (bg - bg_median).abs()).collect();
+            let bg_mad = median_f32(&mut bg_devs).unwrap_or(0.0).max(f32::EPSILON);
+            let score = |info: &RegInfo| {
+                if info.fwhm <= 0.0 { return 0.0; }
+                let bg_k = (info.background - bg_median).abs() / (3.0 * bg_mad);
+                info.stars as f32 / info.fwhm / (1.0 + bg_k * bg_k)
+            };
+            let (file, info) = used_files.iter()
+                .max_by(|(_, info1), (_, info2)| cmp_f32(&score(info1), &score(info2)))?;
+            let mut fwhms: Vec<f32> = used_files.iter().map(|(_, info)| info.fwhm).collect();
+            let mut stars: Vec<f32> = used_files.iter().map(|(_, info)| info.stars as f32).collect();
+            RefImageChoice {
+                file_name: file.file_name.clone(),
+                reason: format!(
+                    "best of {} files by stars count and FWHM: {} stars (median {:.0}), \
+                    FWHM {:.2} (median {:.2}), background {:.5} (median {:.5})",
+                    used_files.len(),
+                    info.stars, median_f32(&mut stars).unwrap_or(0.0),
+                    info.fwhm, median_f32(&mut fwhms).unwrap_or(0.0),
+                    info.background, bg_median,
+                ),
+            }
+        } else {
+            let (key_fun, descr): (fn(&RegInfo) -> f32, _) = match self.config.ref_image_auto_mode {
+                RefImageAutoMode::SmallestStars => (|info| info.fwhm, "smallest FWHM"),
+                RefImageAutoMode::RoundestStars => (|info| info.stars_r_dev, "roundest stars"),
+                RefImageAutoMode::MinBg         => (|info| info.background, "minimum background"),
+                RefImageAutoMode::NinNoise      => (|info| info.noise, "minimum noise"),
+                RefImageAutoMode::Best          => unreachable!(),
+            };
+            let (file, info) = used_files.iter()
+                .min_by(|(_, info1), (_, info2)| cmp_f32(&key_fun(info1), &key_fun(info2)))?;
+            RefImageChoice {
+                file_name: file.file_name.clone(),
+                reason: format!("{} of {} files ({:.5})", descr, used_files.len(), key_fun(info)),
+            }
+        };
+        Some(choice)
     }
 
     pub fn stack_light_files(
@@ -331,6 +460,8 @@ impl Project {
         progress:    &ProgressTs,
         cancel_flag: &IsCancelledFun,
         cpu_load:    CpuLoad,
+        resume:      ResumeMode,
+        maps_opts:   &StackMapsOpts,
     ) -> anyhow::Result<StackLightsResult> {
         let result_file_name = self.get_result_file_name()?;
 
@@ -338,55 +469,245 @@ impl Project {
             .num_threads(cpu_load.to_threads_count())
             .build()?;
 
-        // master-files
+        let files_to_del_later = Mutex::new(FilesToDeleteLater::new());
+        let (temp_file_names, ref_data) = self.prepare_temp_light_files(
+            progress,
+            cancel_flag,
+            &thread_pool,
+            resume,
+            &files_to_del_later
+        )?;
 
-        for (idx, group) in self.groups.iter().filter(|g| g.used).enumerate() {
-            if cancel_flag() { anyhow::bail!("Termimated") }
-            group.create_master_files(
-                idx,
-                progress,
-                cancel_flag,
-                &self.config,
-                &thread_pool
-            )?;
+        if temp_file_names.is_empty() {
+            anyhow::bail!(tr("No light files to stack"));
         }
 
-        let bin = match self.config.image_size {
-            ImageSize::Original => 1,
-            ImageSize::Bin2x2 => 2,
-        };
-
-        // Find and load reference files
+        // stacking all temporary light files into result image
 
-        progress.lock().unwrap().stage(&gettext(
-            "Loading reference image..."
+        progress.lock().unwrap().stage(&tr(
+            "Stacking all images into result image file..."
         ));
 
-        let group_with_ref_file = self
-            .find_group_with_light_file(self.ref_image.as_ref().unwrap())
-            .ok_or_else(|| anyhow::anyhow!(gettext("Can't find group with reference image")))?;
+        let stat = merge_temp_light_files(
+            progress,
+            &temp_file_names,
+            &self.config.light_calc_opts,
+            self.config.streaming_stack,
+            ref_data.image.image.is_rgb(),
+            ref_data.image.image.width(),
+            ref_data.image.image.height(),
+            self.config.align_rgb,
+            &result_file_name,
+            matches!(self.config.res_img_type, ResFileType::Tif16),
+            self.config.fits_save_opts(),
+            maps_opts,
+            &self.config.plugins,
+            cancel_flag
+        )?;
+
+        if cancel_flag() {
+            anyhow::bail!(tr("Termimated"))
+        }
+
+        // sub-stacks of time windows
+
+        let mut sub_stacks = Vec::new();
+        if let Some(minutes) = self.config.sub_stacks {
+            let windows = split_temp_files_by_time(&temp_file_names, minutes);
+            for (idx, (start, files)) in windows.iter().enumerate() {
+                progress.lock().unwrap().stage(&format!(
+                    "Sub-stack {} of {} ({} files from {})",
+                    idx + 1, windows.len(), files.len(), start.format("%H:%M")
+                ));
+                let file_name = get_sub_stack_file_name(&result_file_name, start);
+                let sub_stat = merge_temp_light_files(
+                    progress,
+                    files,
+                    &self.config.light_calc_opts,
+                    self.config.streaming_stack,
+                    ref_data.image.image.is_rgb(),
+                    ref_data.image.image.width(),
+                    ref_data.image.image.height(),
+                    self.config.align_rgb,
+                    &file_name,
+                    matches!(self.config.res_img_type, ResFileType::Tif16),
+                    self.config.fits_save_opts(),
+                    &StackMapsOpts::default(),
+                    &self.config.plugins,
+                    cancel_flag
+                )?;
+                if cancel_flag() {
+                    anyhow::bail!(tr("Termimated"))
+                }
+                sub_stacks.push(SubStack {
+                    file_name,
+                    start: *start,
+                    frames_count: files.len(),
+                    noise: sub_stat.noise,
+                });
+            }
+        }
+
+        if resume != ResumeMode::Off {
+            delete_temp_light_files(&temp_file_names);
+            // states of skipped light files
+            for group in self.groups.iter().filter(|g| g.used) {
+                for file in group.light_files.get_selected_file_names() {
+                    _ = std::fs::remove_file(file.with_extension("temp_light_state"));
+                }
+            }
+        }
+
+        Ok(StackLightsResult {
+            file_name: result_file_name,
+            noise:     stat.noise,
+            frames:    stat.frames,
+            rejection: stat.rejection,
+            sub_stacks,
+        })
+    }
+
+    /// Stacks every used group into its own result file (for example
+    /// when each group contains light files of one filter). If master
+    /// group is defined, it is stacked first and its reference image is
+    /// used for all other groups so all results have the same geometry
+    /// and can be merged without extra alignment. Otherwise reference
+    /// image is chosen inside each group
+    pub fn stack_groups_separately(
+        &mut self,
+        master_group: Option<usize>,
+        progress:     &ProgressTs,
+        cancel_flag:  &IsCancelledFun,
+        cpu_load:     CpuLoad,
+        resume:       ResumeMode,
+        maps_opts:    &StackMapsOpts,
+    ) -> anyhow::Result<Vec<(String, StackLightsResult)>> {
+        let used_flags: Vec<bool> = self.groups.iter().map(|g| g.used).collect();
+        let mut order: Vec<usize> = (0..self.groups.len()).filter(|&idx| used_flags[idx]).collect();
+        if let Some(master_group) = master_group {
+            if !order.contains(&master_group) {
+                anyhow::bail!("Master group {} is not used", self.groups[master_group].name(master_group));
+            }
+            order.retain(|&idx| idx != master_group);
+            order.insert(0, master_group);
+        }
+        if order.is_empty() {
+            anyhow::bail!(tr("No light files to stack"));
+        }
+        let prev_ref_image = self.ref_image.clone();
+        let mut stack_groups = || -> anyhow::Result<Vec<(String, StackLightsResult)>> {
+            let mut results = Vec::new();
+            for &group_idx in &order {
+                for (idx, group) in self.groups.iter_mut().enumerate() {
+                    group.used = idx == group_idx;
+                }
+                let ref_in_group = self.ref_image.as_ref().map(|ref_image|
+                    self.groups[group_idx].light_files.find_file_by_name(ref_image).is_some()
+                ).unwrap_or(false);
+                let keep_master_ref = master_group.is_some() && !results.is_empty();
+                if !ref_in_group && !keep_master_ref {
+                    let choice = self.choose_ref_light_frame(group_idx)
+                        .ok_or_else(|| anyhow::anyhow!(
+                            "No registered light files in group {}",
+                            self.groups[group_idx].name(group_idx)
+                        ))?;
+                    self.set_ref_image(choice.file_name);
+                }
+                let group_name = self.groups[group_idx].name(group_idx);
+                progress.lock().unwrap().stage(&format!("Stacking group {}", group_name));
+                let mut result = self.stack_light_files(progress, cancel_flag, cpu_load, resume, maps_opts)?;
+                let group_file_name = get_group_result_file_name(&result.file_name, &group_name);
+                std::fs::rename(&result.file_name, &group_file_name)?;
+                result.file_name = group_file_name;
+                for sub_stack in &mut result.sub_stacks {
+                    let group_file_name = get_group_result_file_name(&sub_stack.file_name, &group_name);
+                    std::fs::rename(&sub_stack.file_name, &group_file_name)?;
+                    sub_stack.file_name = group_file_name;
+                }
+                results.push((group_name, result));
+            }
+            Ok(results)
+        };
+        let results = stack_groups();
+        for (group, used) in self.groups.iter_mut().zip(used_flags) {
+            group.used = used;
+        }
+        if let Some(prev_ref_image) = prev_ref_image {
+            self.set_ref_image(prev_ref_image);
+        }
+        results
+    }
 
-        let ref_cal = CalibrationData::load(
-            group_with_ref_file.flat_files.get_master_full_file_name(MASTER_FLAT_FN).as_deref(),
-            group_with_ref_file.dark_files.get_master_full_file_name(MASTER_DARK_FN).as_deref(),
-            group_with_ref_file.bias_files.get_master_full_file_name(MASTER_BIAS_FN).as_deref(),
+    /// Creates or updates calibrated and aligned temporary light files
+    /// for all selected light files without stacking. Next stacking in
+    /// resume mode starts from these files
+    pub fn update_temp_light_files(
+        &self,
+        progress:    &ProgressTs,
+        cancel_flag: &IsCancelledFun,
+        cpu_load:    CpuLoad,
+    ) -> anyhow::Result<usize> {
+        let thread_pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(cpu_load.to_threads_count())
+            .build()?;
+        let files_to_del_later = Mutex::new(FilesToDeleteLater::new());
+        let (temp_file_names, _) = self.prepare_temp_light_files(
+            progress,
+            cancel_flag,
+            &thread_pool,
+            ResumeMode::Resume,
+            &files_to_del_later
         )?;
+        Ok(temp_file_names.len())
+    }
 
-        let ref_data = RefBgData::new(
-            self.ref_image.as_ref().unwrap(),
-            &ref_cal,
-            bin,
-            &self.config.raw_params
+    /// Creates temporary files for new light files and adds
+    /// them into live stack. Returns count of added files
+    pub fn add_to_live_stack(
+        &self,
+        live_stack:  &mut LiveStack,
+        progress:    &ProgressTs,
+        cancel_flag: &IsCancelledFun,
+        cpu_load:    CpuLoad,
+    ) -> anyhow::Result<usize> {
+        let thread_pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(cpu_load.to_threads_count())
+            .build()?;
+        let files_to_del_later = Mutex::new(FilesToDeleteLater::new());
+        let (temp_file_names, ref_data) = self.prepare_temp_light_files(
+            progress,
+            cancel_flag,
+            &thread_pool,
+            ResumeMode::Resume,
+            &files_to_del_later
         )?;
+        live_stack.add_temp_files(
+            &temp_file_names,
+            ref_data.image.image.is_rgb(),
+            ref_data.image.image.width(),
+            ref_data.image.image.height(),
+            cancel_flag
+        )
+    }
+
+    fn prepare_temp_light_files(
+        &self,
+        progress:           &ProgressTs,
+        cancel_flag:        &IsCancelledFun,
+        thread_pool:        &rayon::ThreadPool,
+        resume:             ResumeMode,
+        files_to_del_later: &Mutex<FilesToDeleteLater>,
+    ) -> anyhow::Result<(Vec<TempFileData>, RefBgData)> {
+        let (ref_data, bin) = self.create_masters_and_load_ref_data(progress, cancel_flag, thread_pool)?;
 
         // temporary light files
 
         let temp_file_names = Mutex::new(Vec::<TempFileData>::new());
-        let files_to_del_later = Mutex::new(FilesToDeleteLater::new());
+        let align_opts = self.lights_align_opts();
 
         for (idx, group) in self.groups.iter().enumerate() {
             if cancel_flag() {
-                anyhow::bail!(gettext("Termimated"))
+                anyhow::bail!(tr("Termimated"))
             }
             if !group.used {
                 continue;
@@ -397,64 +718,161 @@ impl Project {
                 group.name(idx)
             ));
 
+            // everything except light file itself what affects temporary file
+            let options_key = format!(
+                "{}|{}|{}|{}|{}|{}",
+                serde_json::to_string(&self.config)?,
+                get_file_content_state_str(self.ref_image.as_ref().unwrap()),
+                Self::master_file_state_str(group.master_file_name(ProjectFileType::Flat)),
+                Self::master_file_state_str(group.flat_files.get_master_full_file_name(MASTER_FLAT_END_FN)),
+                Self::master_file_state_str(group.master_file_name(ProjectFileType::Dark)),
+                Self::master_file_state_str(group.master_file_name(ProjectFileType::Bias)),
+            );
+
             let save_aligned_mode =
                 match (self.config.save_aligned_img, self.config.res_img_type) {
-                    (true, ResFileType::Fit) => SaveAlignedImageMode::Fits,
-                    (true, ResFileType::Tif) => SaveAlignedImageMode::Tif,
+                    (true, ResFileType::Fit|ResFileType::Xisf) =>
+                        SaveAlignedImageMode::Fits(self.config.fits_save_opts()),
+                    (true, ResFileType::Tif|ResFileType::Tif16) => SaveAlignedImageMode::Tif,
                     _                        => SaveAlignedImageMode::No,
                 };
 
             create_temp_light_files(
                 progress,
                 group.light_files.get_selected_file_names(),
-                group.flat_files.get_master_full_file_name(MASTER_FLAT_FN).as_deref(),
-                group.dark_files.get_master_full_file_name(MASTER_DARK_FN).as_deref(),
-                group.bias_files.get_master_full_file_name(MASTER_BIAS_FN).as_deref(),
+                group.master_file_name(ProjectFileType::Flat).as_deref(),
+                group.master_file_name(ProjectFileType::Dark).as_deref(),
+                group.master_file_name(ProjectFileType::Bias).as_deref(),
+                group.flat_drift_params().as_ref(),
+                self.config.hot_pixels_by_lights,
                 &ref_data,
                 bin,
                 &self.config.raw_params,
+                &self.config.stars_opts,
                 &temp_file_names,
-                &files_to_del_later,
-                &thread_pool,
+                files_to_del_later,
+                thread_pool,
                 cancel_flag,
                 idx,
                 save_aligned_mode,
-                self.config.align_rgb_each
+                self.config.align_rgb_each,
+                &align_opts,
+                &self.config.airmass,
+                &self.config.plugins,
+                resume,
+                &options_key,
             )?;
         }
 
         if cancel_flag() {
-            anyhow::bail!(gettext("Termimated"))
+            anyhow::bail!(tr("Termimated"))
         }
-        if temp_file_names.lock().unwrap().is_empty() {
-            anyhow::bail!(gettext("No light files to stack"));
+
+        Ok((temp_file_names.into_inner().unwrap(), ref_data))
+    }
+
+    fn create_masters_and_load_ref_data(
+        &self,
+        progress:    &ProgressTs,
+        cancel_flag: &IsCancelledFun,
+        thread_pool: &rayon::ThreadPool,
+    ) -> anyhow::Result<(RefBgData, usize)> {
+        // master-files
+
+        for (idx, group) in self.groups.iter().filter(|g| g.used).enumerate() {
+            if cancel_flag() { anyhow::bail!("Termimated") }
+            group.create_master_files(
+                idx,
+                progress,
+                cancel_flag,
+                &self.config,
+                thread_pool
+            )?;
         }
 
-        // stacking all temporary light files into result image
+        let bin = self.bin();
 
-        progress.lock().unwrap().stage(&gettext(
-            "Stacking all images into result image file..."
+        // Find and load reference files
+
+        progress.lock().unwrap().stage(&tr(
+            "Loading reference image..."
         ));
 
-        merge_temp_light_files(
-            progress,
-            &temp_file_names.lock().unwrap(),
-            &self.config.light_calc_opts,
-            ref_data.image.image.is_rgb(),
-            ref_data.image.image.width(),
-            ref_data.image.image.height(),
-            self.config.align_rgb,
-            &result_file_name,
-            cancel_flag
+        let group_with_ref_file = self
+            .find_group_with_light_file(self.ref_image.as_ref().unwrap())
+            .ok_or_else(|| anyhow::anyhow!(tr("Can't find group with reference image")))?;
+
+        let ref_cal = group_with_ref_file.load_cal_data()?;
+
+        let ref_data = RefBgData::new(
+            self.ref_image.as_ref().unwrap(),
+            &ref_cal,
+            bin,
+            &self.config.raw_params,
+            &self.config.stars_opts,
+            self.config.bg_mask,
         )?;
 
-        if cancel_flag() {
-            anyhow::bail!(gettext("Termimated"))
+        Ok((ref_data, bin))
+    }
+
+    fn bin(&self) -> usize {
+        match self.config.image_size {
+            ImageSize::Original => 1,
+            ImageSize::Bin2x2 => 2,
+            ImageSize::Bin3x3 => 3,
         }
+    }
 
-        Ok(StackLightsResult {
-            file_name: result_file_name,
-        })
+    fn lights_align_opts(&self) -> LightsAlignOpts {
+        LightsAlignOpts {
+            translation_only: self.config.align_mode == AlignMode::Translation,
+            by_wcs:           self.config.align_mode == AlignMode::Wcs,
+            skip_bad_lights:  self.config.skip_bad_lights,
+            min_stars:        self.config.min_stars_in_light,
+            field_rotation:   self.config.field_rotation.clone(),
+            interpolation:    self.config.align_interpolation,
+        }
+    }
+
+    /// Transforms of selected light files relative to reference image.
+    /// Light files are not resampled and nothing is written on disk
+    pub fn calc_light_files_transforms(
+        &self,
+        progress:    &ProgressTs,
+        cancel_flag: &IsCancelledFun,
+        cpu_load:    CpuLoad,
+    ) -> anyhow::Result<Vec<FrameTransform>> {
+        let thread_pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(cpu_load.to_threads_count())
+            .build()?;
+        let (ref_data, bin) = self.create_masters_and_load_ref_data(progress, cancel_flag, &thread_pool)?;
+        let align_opts = self.lights_align_opts();
+        let mut result = Vec::new();
+        for (idx, group) in self.groups.iter().enumerate() {
+            if !group.used { continue; }
+            progress.lock().unwrap().stage(&format!("Aligning files of group {}", group.name(idx)));
+            let cal_data = group.load_cal_data()?;
+            result.extend(calc_light_files_transforms(
+                progress,
+                &group.light_files.get_selected_file_names(),
+                &cal_data,
+                &ref_data,
+                bin,
+                &self.config.raw_params,
+                &self.config.stars_opts,
+                &thread_pool,
+                cancel_flag,
+                &align_opts,
+            )?);
+        }
+        Ok(result)
+    }
+
+    fn master_file_state_str(master_file_name: Option<PathBuf>) -> String {
+        master_file_name
+            .map(|file_name| get_file_content_state_str(&file_name))
+            .unwrap_or_default()
     }
 
     fn find_group_with_light_file(&self, file_name: &Path) -> Option<&ProjectGroup> {
@@ -489,7 +907,7 @@ impl Project {
             let result = [parent, &Path::new(&file_name)].iter().collect();
             Ok(result)
         } else {
-            anyhow::bail!(gettext("You have to save project before"));
+            anyhow::bail!(tr("You have to save project before"));
         }
     }
 
@@ -513,19 +931,41 @@ impl Project {
 pub enum ImageSize {
     Original,
     Bin2x2,
+    Bin3x3,
 }
 
 #[derive(Serialize, Deserialize, Clone, Copy, Debug)]
 pub enum ResFileType {
     Fit,
-    Tif,
+    Tif,   // 32-bit float
+    Tif16, // 16-bit integer
+    Xisf,
 }
 
 impl ResFileType {
     pub fn get_file_ext(self) -> &'static str {
         match self {
             ResFileType::Fit => FIT_EXTS[0],
-            ResFileType::Tif => TIF_EXTS[0],
+            ResFileType::Tif|ResFileType::Tif16 => TIF_EXTS[0],
+            ResFileType::Xisf => XISF_EXTS[0],
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum AlignMode {
+    Triangles,
+    Translation, // for sub-second untracked exposures
+    Wcs,         // by WCS of plate solved or archival files
+}
+
+impl AlignMode {
+    pub fn from_str(text: &str) -> anyhow::Result<Self> {
+        match text.to_lowercase().as_str() {
+            "stars"       => Ok(AlignMode::Triangles),
+            "translation" => Ok(AlignMode::Translation),
+            "wcs"         => Ok(AlignMode::Wcs),
+            _ => anyhow::bail!("Wrong align mode {} (stars, translation or wcs)", text),
         }
     }
 }
@@ -536,6 +976,13 @@ pub enum RefImageAutoMode {
     RoundestStars,
     MinBg,
     NinNoise,
+    Best, // many small stars and usual background
+}
+
+/// Reference image selected automatically and description why
+pub struct RefImageChoice {
+    pub file_name: PathBuf,
+    pub reason:    String,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -554,6 +1001,26 @@ pub struct ProjectConfig {
     pub align_rgb: bool,
     pub align_rgb_each: bool,
     pub ref_image_auto_mode: RefImageAutoMode,
+    pub align_mode: AlignMode,
+    pub skip_bad_lights: bool,
+    pub min_stars_in_light: usize,
+    pub field_rotation: Option<FieldRotationParams>,
+    pub align_interpolation: ResampleKernel, // for rotation and translation of light files
+    pub stars_opts: StarsFindOpts,
+    pub fits_compression: FitsCompression,
+    pub fits_bitpix: FitsBitPix,
+    pub fits_compat: FitsCompat,
+    pub quantization: Quantization,
+    pub output_range: OutputRange,
+    pub pedestal: f32, // in units of saved data
+    pub bg_mask: BgMaskPreset, // mask for background normalization of light files
+    pub sub_stacks: Option<u32>, // minutes of time window for additional sub-stacks
+    pub flat_drift: bool, // separate master flats of start and end of session
+    pub flat_norm: FlatNorm, // normalization of flat files
+    pub airmass: AirmassOpts, // site and target for light files without coordinates, extinction correction
+    pub plugins: Vec<PluginOpts>, // external programs for calibrated light files and result
+    pub hot_pixels_by_lights: bool, // detect hot pixels by dithered light files if there is no master dark
+    pub streaming_stack: bool, // two-pass integration of light files read one by one
 }
 
 impl Default for ProjectConfig {
@@ -571,7 +1038,40 @@ impl Default for ProjectConfig {
             raw_params: RawOpenParams::default(),
             align_rgb: false,
             align_rgb_each: false,
-            ref_image_auto_mode: RefImageAutoMode::SmallestStars,
+            ref_image_auto_mode: RefImageAutoMode::Best,
+            align_mode: AlignMode::Triangles,
+            skip_bad_lights: false,
+            min_stars_in_light: 0,
+            field_rotation: None,
+            align_interpolation: ResampleKernel::Bilinear,
+            stars_opts: StarsFindOpts::default(),
+            fits_compression: FitsCompression::None,
+            fits_bitpix: FitsBitPix::Float32,
+            fits_compat: FitsCompat::Default,
+            quantization: Quantization::Nearest,
+            output_range: OutputRange::Normalized,
+            pedestal: 0.0,
+            bg_mask: BgMaskPreset::Stars,
+            sub_stacks: None,
+            flat_drift: false,
+            flat_norm: FlatNorm::Percentile,
+            airmass: AirmassOpts::default(),
+            plugins: Vec::new(),
+            hot_pixels_by_lights: false,
+            streaming_stack: false,
+        }
+    }
+}
+
+impl ProjectConfig {
+    pub fn fits_save_opts(&self) -> FitsSaveOpts {
+        FitsSaveOpts {
+            bitpix: self.fits_bitpix,
+            compression: self.fits_compression,
+            compat: self.fits_compat,
+            quantization: self.quantization,
+            range: self.output_range,
+            pedestal: self.pedestal,
         }
     }
 }
@@ -584,6 +1084,15 @@ pub enum ProjectFileType {
     Bias,
 }
 
+/// Masters of calibration library for group without
+/// own calibration files (assigned by `--cal-library`)
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Debug)]
+pub struct LibMasters {
+    pub dark: Option<PathBuf>,
+    pub flat: Option<PathBuf>,
+    pub bias: Option<PathBuf>,
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 #[serde(default)]
 pub struct ProjectGroup {
@@ -594,6 +1103,7 @@ pub struct ProjectGroup {
     pub dark_files: ProjectFiles,
     pub bias_files: ProjectFiles,
     pub flat_files: ProjectFiles,
+    lib_masters: LibMasters,
 
     #[serde(skip)]
     project_changed: Weak<Cell<bool>>,
@@ -609,6 +1119,7 @@ impl Default for ProjectGroup {
             bias_files: ProjectFiles::default(),
             flat_files: ProjectFiles::default(),
             light_files: ProjectFiles::default(),
+            lib_masters: LibMasters::default(),
             project_changed: Default::default(),
         }
     }
@@ -679,12 +1190,36 @@ impl ProjectGroup {
             }
         }
         if group_index == 0 {
-            gettext("Main group")
+            tr("Main group")
         } else {
-            format!("{} #{}", gettext("Group"), group_index+1)
+            format!("{} #{}", tr("Group"), group_index+1)
         }
     }
 
+    pub fn lib_masters(&self) -> &LibMasters {
+        &self.lib_masters
+    }
+
+    pub fn set_lib_masters(&mut self, lib_masters: LibMasters) {
+        if self.lib_masters == lib_masters {
+            return;
+        }
+        self.lib_masters = lib_masters;
+        self.project_changed.upgrade().unwrap().set(true);
+    }
+
+    /// Master file created from calibration files of group or master
+    /// of calibration library if group has no files of this type
+    pub fn master_file_name(&self, file_type: ProjectFileType) -> Option<PathBuf> {
+        let (files, master_file_name, lib_master) = match file_type {
+            ProjectFileType::Dark  => (&self.dark_files, MASTER_DARK_FN, &self.lib_masters.dark),
+            ProjectFileType::Flat  => (&self.flat_files, MASTER_FLAT_FN, &self.lib_masters.flat),
+            ProjectFileType::Bias  => (&self.bias_files, MASTER_BIAS_FN, &self.lib_masters.bias),
+            ProjectFileType::Light => return None,
+        };
+        files.get_master_full_file_name(master_file_name).or_else(|| lib_master.clone())
+    }
+
     pub fn get_file_list_by_type(&self, file_type: ProjectFileType) -> &ProjectFiles {
         match file_type {
             ProjectFileType::Light => &self.light_files,
@@ -715,6 +1250,19 @@ impl ProjectGroup {
         to_files.add_files(files_to_move);
     }
 
+    /// Existing master flat, dark and bias files of group
+    pub fn master_files(&self) -> Vec<PathBuf> {
+        [
+            self.master_file_name(ProjectFileType::Flat),
+            self.flat_files.get_master_full_file_name(MASTER_FLAT_END_FN),
+            self.master_file_name(ProjectFileType::Dark),
+            self.master_file_name(ProjectFileType::Bias),
+        ].into_iter()
+            .flatten()
+            .filter(|f| f.is_file())
+            .collect()
+    }
+
     fn create_master_files(
         &self,
         group_index: usize,
@@ -723,11 +1271,13 @@ impl ProjectGroup {
         config:      &ProjectConfig,
         thread_pool: &rayon::ThreadPool,
     ) -> anyhow::Result<()> {
+        let hdu = config.raw_params.fits_hdu_selector();
         let bias_recreated = self.create_master_bias(
             group_index,
             progress,
             cancel_flag,
             &config.bias_calc_opts,
+            hdu.as_ref(),
             thread_pool
         )?;
 
@@ -736,6 +1286,7 @@ impl ProjectGroup {
             progress,
             cancel_flag,
             &config.dark_calc_opts,
+            hdu.as_ref(),
             thread_pool
         )?;
 
@@ -744,14 +1295,72 @@ impl ProjectGroup {
             progress,
             cancel_flag,
             &config.flat_calc_opts,
-            &self.bias_files.get_master_full_file_name(MASTER_BIAS_FN),
+            hdu.as_ref(),
+            &self.master_file_name(ProjectFileType::Bias),
             thread_pool,
-            bias_recreated
+            bias_recreated,
+            config.flat_drift,
+            config.flat_norm
         )?;
 
         Ok(())
     }
 
+    /// Master files of group with master flats of start and end of session
+    fn load_cal_data(&self) -> anyhow::Result<CalibrationData> {
+        let mut cal_data = CalibrationData::load(
+            self.master_file_name(ProjectFileType::Flat).as_deref(),
+            self.master_file_name(ProjectFileType::Dark).as_deref(),
+            self.master_file_name(ProjectFileType::Bias).as_deref(),
+        )?;
+        if let Some(params) = self.flat_drift_params() {
+            cal_data.load_flat_drift(&params)?;
+        }
+        Ok(cal_data)
+    }
+
+    /// Defined if master flat of end of session was created
+    pub fn flat_drift_params(&self) -> Option<FlatDriftParams> {
+        let end_flat = self.flat_files.get_master_full_file_name(MASTER_FLAT_END_FN)?;
+        if !end_flat.is_file() { return None; }
+        let split = self.split_flats_by_time()?;
+        Some(FlatDriftParams {
+            end_flat,
+            start_time: split.start_time,
+            end_time:   split.end_time,
+        })
+    }
+
+    /// Splits selected flat files by longest pause between them
+    /// into files of start and files of end of session
+    fn split_flats_by_time(&self) -> Option<FlatsSplit> {
+        let mut files: Vec<_> = self.flat_files.list.iter()
+            .filter(|f| f.used)
+            .map(|f| f.file_time.map(|t| (t, f.file_name.clone())))
+            .collect::<Option<_>>()?;
+        if files.len() < 2 { return None; }
+        files.sort_by_key(|(time, _)| *time);
+        let (split_pos, gap) = files.windows(2)
+            .enumerate()
+            .map(|(i, w)| (i + 1, w[1].0 - w[0].0))
+            .max_by_key(|(_, gap)| *gap)?;
+        if gap.num_minutes() < FLAT_DRIFT_MIN_GAP_MINUTES { return None; }
+        let mean_time = |files: &[(DateTime<Local>, PathBuf)]| {
+            let first = files[0].0;
+            let offset_ms = files.iter()
+                .map(|(t, _)| (*t - first).num_milliseconds())
+                .sum::<i64>() / files.len() as i64;
+            first + chrono::Duration::milliseconds(offset_ms)
+        };
+        let (start, end) = files.split_at(split_pos);
+        Some(FlatsSplit {
+            start_time:  mean_time(start),
+            end_time:    mean_time(end),
+            start_files: start.iter().map(|(_, f)| f.clone()).collect(),
+            end_files:   end.iter().map(|(_, f)| f.clone()).collect(),
+        })
+    }
+
 
     fn create_master_dark(
         &self,
@@ -759,6 +1368,7 @@ impl ProjectGroup {
         progress:    &ProgressTs,
         cancel_flag: &IsCancelledFun,
         calc_opts:   &CalcOpts,
+        hdu:         Option<&FitsHduSelector>,
         thread_pool: &rayon::ThreadPool,
     ) -> anyhow::Result<()> {
         progress.lock().unwrap().stage(&format!(
@@ -774,6 +1384,7 @@ impl ProjectGroup {
                 create_master_dark_or_bias_file(
                     file_names,
                     calc_opts,
+                    hdu,
                     file_name,
                     progress,
                     thread_pool,
@@ -790,15 +1401,55 @@ impl ProjectGroup {
         progress:            &ProgressTs,
         cancel_flag:         &IsCancelledFun,
         calc_opts:           &CalcOpts,
+        hdu:                 Option<&FitsHduSelector>,
         master_bias_file:    &Option<PathBuf>,
         thread_pool:         &rayon::ThreadPool,
         force_even_if_exist: bool,
+        flat_drift:          bool,
+        flat_norm:           FlatNorm,
     ) -> anyhow::Result<()> {
         progress.lock().unwrap().stage(&format!(
             "Creating master-flat for group {}",
             self.name(group_index)
         ));
 
+        let end_file_name = self.flat_files.get_master_full_file_name(MASTER_FLAT_END_FN);
+        let split = if flat_drift { self.split_flats_by_time() } else { None };
+        if let (Some(split), Some(end_file_name)) = (split, &end_file_name) {
+            log::info!(
+                "Flat files are split into {} files of start and {} files of end of session",
+                split.start_files.len(), split.end_files.len()
+            );
+            for (files, file_name) in [
+                (&split.start_files, &self.flat_files.get_path().join(MASTER_FLAT_FN)),
+                (&split.end_files, end_file_name),
+            ] {
+                if cancel_flag() { return Ok(()); }
+                create_master_flat_file(
+                    files,
+                    calc_opts,
+                    hdu,
+                    master_bias_file,
+                    file_name,
+                    progress,
+                    thread_pool,
+                    cancel_flag,
+                    force_even_if_exist,
+                    flat_norm
+                )?;
+            }
+            return Ok(());
+        }
+        if flat_drift {
+            log::warn!(
+                "Flat files of group {} can't be split into start and end of session",
+                self.name(group_index)
+            );
+        }
+        if let Some(end_file_name) = end_file_name.filter(|f| f.exists()) {
+            std::fs::remove_file(end_file_name)?;
+        }
+
         Self::create_master_file(
             &self.flat_files,
             cancel_flag,
@@ -807,12 +1458,14 @@ impl ProjectGroup {
                 create_master_flat_file(
                     file_names,
                     calc_opts,
+                    hdu,
                     master_bias_file,
                     file_name,
                     progress,
                     thread_pool,
                     cancel_flag,
-                    force_even_if_exist
+                    force_even_if_exist,
+                    flat_norm
                 )
             }
         )?;
@@ -825,6 +1478,7 @@ impl ProjectGroup {
         progress:    &ProgressTs,
         cancel_flag: &IsCancelledFun,
         calc_opts:   &CalcOpts,
+        hdu:         Option<&FitsHduSelector>,
         thread_pool: &rayon::ThreadPool,
     ) -> anyhow::Result<bool> {
         progress.lock().unwrap().stage(&format!(
@@ -840,6 +1494,7 @@ impl ProjectGroup {
                 create_master_dark_or_bias_file(
                     file_names,
                     calc_opts,
+                    hdu,
                     file_name,
                     progress,
                     thread_pool,
@@ -873,31 +1528,34 @@ impl ProjectGroup {
         result:        &Mutex<HashMap<PathBuf, anyhow::Result<RegInfo>>>,
         save_star_img: bool,
         raw_params:    &RawOpenParams,
+        stars_opts:    &StarsFindOpts,
+        only_new:      bool,
     ) -> anyhow::Result<()> {
+        let file_names: Vec<_> = self.light_files.list
+            .iter()
+            .filter(|f| !only_new || (f.reg_info.is_none() && f.error_text.is_none()))
+            .map(|f| f.file_name.clone())
+            .collect();
+
+        if file_names.is_empty() {
+            return Ok(());
+        }
+
         progress.lock().unwrap().stage(&format!(
             "Registering files for group {}...",
             self.name(group_idx)
         ));
         progress.lock().unwrap()
-            .set_total(self.light_files.list.len());
+            .set_total(file_names.len());
         progress.lock().unwrap()
-            .progress(false, &gettext(
+            .progress(false, &tr(
                 "Loading calibration master files..."
             ));
 
-        let cal_data = CalibrationData::load(
-            self.flat_files.get_master_full_file_name(MASTER_FLAT_FN).as_deref(),
-            self.dark_files.get_master_full_file_name(MASTER_DARK_FN).as_deref(),
-            self.bias_files.get_master_full_file_name(MASTER_BIAS_FN).as_deref(),
-        )?;
+        let cal_data = self.load_cal_data()?;
 
         let cur_result = Mutex::new(anyhow::Result::<()>::Ok(()));
 
-        let file_names: Vec<_> = self.light_files.list
-            .iter()
-            .map(|f| f.file_name.clone())
-            .collect();
-
         thread_pool.scope(|s| {
             for file_name in file_names {
                 s.spawn(|_| {
@@ -918,7 +1576,8 @@ impl ProjectGroup {
                         | LoadLightFlags::BACKGROUND,
                         OpenMode::Processing,
                         1,
-                        raw_params
+                        raw_params,
+                        stars_opts
                     );
 
                     let file_result = match load_light_file_res {
@@ -943,6 +1602,7 @@ impl ProjectGroup {
                                     }
                                     Ok(RegInfo {
                                         noise:       light_file.noise,
+                                        noise_est:   light_file.noise_est,
                                         background:  light_file.background,
                                         fwhm:        stars_stat.fwhm,
                                         stars:       light_file.stars.len(),
@@ -972,7 +1632,7 @@ impl ProjectGroup {
         });
 
         if cancel_flag() {
-            anyhow::bail!(gettext("Cancelled"));
+            anyhow::bail!(tr("Cancelled"));
         }
 
         cur_result.into_inner()?
@@ -1102,7 +1762,7 @@ impl ProjectGroup {
             }
 
             if values.is_empty() {
-                anyhow::bail!(gettext("Conditions are too strict"));
+                anyhow::bail!(tr("Conditions are too strict"));
             }
 
             let (mean, dev) =
@@ -1402,6 +2062,7 @@ impl ProjectFiles {
 #[serde(default)]
 pub struct RegInfo {
     pub noise: f32,
+    pub noise_est: f32, // sigma of noise by k-sigma method
     pub background: f32,
     pub fwhm: f32,
     pub stars: usize,
@@ -1412,6 +2073,7 @@ impl Default for RegInfo {
     fn default() -> Self {
         Self {
             noise: 0.0,
+            noise_est: 0.0,
             background: 0.0,
             fwhm: 0.0,
             stars: 0,
@@ -1562,6 +2224,10 @@ impl ProjectFile {
         &self.focal_len
     }
 
+    pub fn cfa_type(&self) -> &Option<CfaType> {
+        &self.cfa_type
+    }
+
     pub fn camera(&self) -> &Option<String> {
         &self.camera
     }
@@ -1662,6 +2328,42 @@ impl Default for CleanupConf {
     }
 }
 
+// "result-....fit" + 22:30 -> "result-...-sub-20240115-2230.fit"
+fn get_sub_stack_file_name(result_file_name: &Path, start: &DateTime<Local>) -> PathBuf {
+    let stem = result_file_name.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+    let ext = result_file_name.extension().and_then(|s| s.to_str()).unwrap_or("");
+    result_file_name.with_file_name(format!("{}-sub-{}.{}", stem, start.format("%Y%m%d-%H%M"), ext))
+}
+
+// "result-....fit" + "Ha" -> "result-...-Ha.fit"
+fn get_group_result_file_name(result_file_name: &Path, group_name: &str) -> PathBuf {
+    let group_name: String = group_name.trim().chars()
+        .map(|c| if c.is_alphanumeric() || c == '-' || c == '.' { c } else { '_' })
+        .collect();
+    let stem = result_file_name.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+    let ext = result_file_name.extension().and_then(|s| s.to_str()).unwrap_or("");
+    result_file_name.with_file_name(format!("{}-{}.{}", stem, group_name, ext))
+}
+
+struct FlatsSplit {
+    start_files: Vec<PathBuf>,
+    start_time:  DateTime<Local>,
+    end_files:   Vec<PathBuf>,
+    end_time:    DateTime<Local>,
+}
+
 pub struct StackLightsResult {
-    pub file_name: PathBuf,
+    pub file_name:  PathBuf,
+    pub noise:      StackNoise,
+    pub frames:     Vec<StackedFrame>,
+    pub rejection:  RejectionStat,
+    pub sub_stacks: Vec<SubStack>,
+}
+
+/// Stack of light files shot during one time window
+pub struct SubStack {
+    pub file_name:    PathBuf,
+    pub start:        DateTime<Local>,
+    pub frames_count: usize,
+    pub noise:        StackNoise,
 }
\ No newline at end of file
@@ -0,0 +1,106 @@
+use serde::*;
+
+/* Formatting of numbers and units in text reports and CSV files. Decimal
+   separator can be taken from locale so spreadsheets import values as
+   numbers. CSV column separator becomes `;` for comma decimal separator */
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum DecimalSeparator {
+    Auto, // from LC_ALL, LC_NUMERIC or LANG
+    Point,
+    Comma,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum LengthUnits {
+    Pixels,
+    Arcsec,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum TemperatureUnits {
+    Celsius,
+    Fahrenheit,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ReportFormat {
+    pub decimal_separator: DecimalSeparator,
+    pub length_units: LengthUnits,
+    pub temperature_units: TemperatureUnits,
+    pub pixel_scale: f64, // arcsec per pixel, 0 if unknown
+}
+
+impl Default for ReportFormat {
+    fn default() -> Self {
+        Self {
+            decimal_separator: DecimalSeparator::Auto,
+            length_units: LengthUnits::Pixels,
+            temperature_units: TemperatureUnits::Celsius,
+            pixel_scale: 0.0,
+        }
+    }
+}
+
+const COMMA_LANGUAGES: &[&str] = &[
+    "de", "fr", "ru", "es", "it", "pt", "nl", "pl", "cs",
+    "sv", "fi", "da", "nb", "tr", "uk",
+];
+
+fn locale_uses_comma() -> bool {
+    let locale = ["LC_ALL", "LC_NUMERIC", "LANG"]
+        .iter()
+        .filter_map(|name| std::env::var(name).ok())
+        .find(|v| !v.is_empty());
+    let Some(locale) = locale else { return false; };
+    let language = locale
+        .split(|c| c == '_' || c == '.' || c == '-')
+        .next()
+        .unwrap_or("")
+        .to_lowercase();
+    COMMA_LANGUAGES.contains(&language.as_str())
+}
+
+impl ReportFormat {
+    fn use_comma(&self) -> bool {
+        match self.decimal_separator {
+            DecimalSeparator::Auto  => locale_uses_comma(),
+            DecimalSeparator::Point => false,
+            DecimalSeparator::Comma => true,
+        }
+    }
+
+    pub fn csv_separator(&self) -> char {
+        if self.use_comma() { ';' } else { ',' }
+    }
+
+    pub fn float(&self, value: f64, precision: usize) -> String {
+        let text = format!("{:.*}", precision, value);
+        if self.use_comma() { text.replace('.', ",") } else { text }
+    }
+
+    /// Value without rounding (for CSV files)
+    pub fn float_full(&self, value: f64) -> String {
+        let text = value.to_string();
+        if self.use_comma() { text.replace('.', ",") } else { text }
+    }
+
+    /// Length in pixels is converted to arcseconds if pixel scale is known
+    pub fn length(&self, pixels: f64, precision: usize) -> String {
+        if self.length_units == LengthUnits::Arcsec && self.pixel_scale > 0.0 {
+            format!("{}\"", self.float(pixels * self.pixel_scale, precision))
+        } else {
+            format!("{}px", self.float(pixels, precision))
+        }
+    }
+
+    pub fn temperature(&self, celsius: f64) -> String {
+        match self.temperature_units {
+            TemperatureUnits::Celsius =>
+                format!("{}°C", self.float(celsius, 1)),
+            TemperatureUnits::Fahrenheit =>
+                format!("{}°F", self.float(celsius * 9.0 / 5.0 + 32.0, 1)),
+        }
+    }
+}
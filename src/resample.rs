@@ -0,0 +1,464 @@
+use std::f64::consts::PI;
+use rayon::prelude::*;
+use serde::*;
+use crate::image::*;
+
+/* Software binning, rescaling and interpolation of images. Flux conserving
+   mode integrates source image over exact area of result pixel instead of
+   interpolation, so photometry of result is the same as of source. Results
+   of Lanczos kernels are clamped to range of nearest source pixels to
+   prevent dark rings around stars (anti-ringing) */
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BinMode {
+    Average,
+    Sum,
+}
+
+impl BinMode {
+    pub fn from_str(text: &str) -> anyhow::Result<Self> {
+        match text.to_lowercase().as_str() {
+            "average" => Ok(BinMode::Average),
+            "sum"     => Ok(BinMode::Sum),
+            _ => anyhow::bail!("Wrong binning mode {} (average or sum)", text),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+pub enum ResampleKernel {
+    Nearest,
+    #[default]
+    Bilinear,
+    Bicubic,
+    Lanczos3,
+    Lanczos4,
+    BSpline,        // cubic B-spline, smooths but never rings
+    FluxConserving, // exact integration of pixels overlap
+}
+
+impl ResampleKernel {
+    pub fn from_str(text: &str) -> anyhow::Result<Self> {
+        match text.to_lowercase().as_str() {
+            "nearest"            => Ok(ResampleKernel::Nearest),
+            "bilinear"           => Ok(ResampleKernel::Bilinear),
+            "bicubic"            => Ok(ResampleKernel::Bicubic),
+            "lanczos"|"lanczos3" => Ok(ResampleKernel::Lanczos3),
+            "lanczos4"           => Ok(ResampleKernel::Lanczos4),
+            "bspline"            => Ok(ResampleKernel::BSpline),
+            "flux"               => Ok(ResampleKernel::FluxConserving),
+            _ => anyhow::bail!(
+                "Wrong resampling kernel {} (nearest, bilinear, bicubic, lanczos3, lanczos4, bspline or flux)",
+                text
+            ),
+        }
+    }
+
+    fn radius(self) -> f64 {
+        match self {
+            ResampleKernel::Nearest => 0.5,
+            ResampleKernel::Bilinear => 1.0,
+            ResampleKernel::Bicubic => 2.0,
+            ResampleKernel::Lanczos3 => 3.0,
+            ResampleKernel::Lanczos4 => 4.0,
+            ResampleKernel::BSpline => 2.0,
+            ResampleKernel::FluxConserving => 1.0,
+        }
+    }
+
+    /// Result is clamped to range of nearest source pixels because
+    /// negative lobes of kernel create dark rings around stars
+    fn anti_ringing(self) -> bool {
+        matches!(self, ResampleKernel::Lanczos3|ResampleKernel::Lanczos4)
+    }
+
+    fn weight(self, x: f64) -> f64 {
+        let x = x.abs();
+        let lanczos = |a: f64| {
+            if x < 1e-8 {
+                1.0
+            } else if x < a {
+                let px = PI * x;
+                a * px.sin() * (px / a).sin() / (px * px)
+            } else {
+                0.0
+            }
+        };
+        match self {
+            ResampleKernel::Nearest =>
+                if x < 0.5 { 1.0 } else { 0.0 },
+            ResampleKernel::Bilinear =>
+                (1.0 - x).max(0.0),
+            // Catmull-Rom (a = -0.5)
+            ResampleKernel::Bicubic =>
+                if x < 1.0 {
+                    1.5 * x * x * x - 2.5 * x * x + 1.0
+                } else if x < 2.0 {
+                    -0.5 * x * x * x + 2.5 * x * x - 4.0 * x + 2.0
+                } else {
+                    0.0
+                },
+            ResampleKernel::Lanczos3 => lanczos(3.0),
+            ResampleKernel::Lanczos4 => lanczos(4.0),
+            ResampleKernel::BSpline =>
+                if x < 1.0 {
+                    (4.0 - 6.0 * x * x + 3.0 * x * x * x) / 6.0
+                } else if x < 2.0 {
+                    (2.0 - x).powi(3) / 6.0
+                } else {
+                    0.0
+                },
+            // box
+            ResampleKernel::FluxConserving =>
+                if x < 0.5 { 1.0 } else { 0.0 },
+        }
+    }
+
+    /// Normalized weights of source pixels `first..` for point `pos`
+    fn point_weights(self, pos: f64) -> (Crd, Vec<f64>) {
+        let radius = self.radius();
+        let first = (pos - radius).ceil() as Crd;
+        let last = (pos + radius).floor() as Crd;
+        let mut weights: Vec<f64> = (first..=last)
+            .map(|s| self.weight(s as f64 - pos))
+            .collect();
+        let sum: f64 = weights.iter().sum();
+        if sum != 0.0 {
+            weights.iter_mut().for_each(|w| *w /= sum);
+        }
+        (first, weights)
+    }
+}
+
+/// Value of `layer` at point (`x`, `y`) interpolated by `kernel`. Pixels
+/// without value are excluded, overexposure is kept. None if point is out
+/// of image. Flux conserving kernel is not defined for single point so
+/// bilinear interpolation is used for it
+pub fn interpolate_pixel(layer: &ImageLayerF32, x: f64, y: f64, kernel: ResampleKernel) -> Option<f32> {
+    match kernel {
+        ResampleKernel::Bilinear|ResampleKernel::FluxConserving =>
+            return layer.get_f64_crd(x, y),
+        ResampleKernel::Nearest =>
+            return layer.get(x.round() as Crd, y.round() as Crd),
+        _ => {},
+    }
+    let (ix, iy) = (x.floor() as Crd, y.floor() as Crd);
+    let nearest = [(ix, iy), (ix + 1, iy), (ix, iy + 1), (ix + 1, iy + 1)];
+    if nearest.iter().all(|(x, y)| layer.get(*x, *y).is_none()) {
+        return None;
+    }
+    let (first_x, weights_x) = kernel.point_weights(x);
+    let (first_y, weights_y) = kernel.point_weights(y);
+    let (mut sum, mut w_sum) = (0_f64, 0_f64);
+    for (sy, wy) in (first_y..).zip(&weights_y) {
+        for (sx, wx) in (first_x..).zip(&weights_x) {
+            let Some(v) = layer.get(sx, sy) else { continue; };
+            let w = wx * wy;
+            if v.is_infinite() && w.abs() > 1e-3 {
+                return Some(f32::INFINITY); // overexposure
+            }
+            if !is_valid(v) { continue; }
+            sum += v as f64 * w;
+            w_sum += w;
+        }
+    }
+    if w_sum.abs() < 1e-6 {
+        return layer.get_f64_crd(x, y);
+    }
+    let mut result = (sum / w_sum) as f32;
+    if kernel.anti_ringing() {
+        let (min, max) = nearest.iter()
+            .filter_map(|(x, y)| layer.get(*x, *y))
+            .filter(|v| is_valid(*v))
+            .fold((f32::MAX, f32::MIN), |(min, max), v| (min.min(v), max.max(v)));
+        if min <= max {
+            result = result.clamp(min, max);
+        }
+    }
+    Some(result)
+}
+
+/// Mean reduction of variance of gaussian noise by interpolation at
+/// fractional offsets `fx` and `fy`. None offsets mean rotated image:
+/// reduction is averaged for all fractional parts
+pub fn interpolation_variance_k(kernel: ResampleKernel, fx: Option<f64>, fy: Option<f64>) -> f64 {
+    let kernel = if kernel == ResampleKernel::FluxConserving { ResampleKernel::Bilinear } else { kernel };
+    let k = |f: f64| -> f64 {
+        let (_, weights) = kernel.point_weights(f.rem_euclid(1.0));
+        weights.iter().map(|w| w * w).sum()
+    };
+    const STEPS: usize = 32;
+    let mean_k = || (0..STEPS).map(|i| k((i as f64 + 0.5) / STEPS as f64)).sum::<f64>() / STEPS as f64;
+    match (fx, fy) {
+        (Some(fx), Some(fy)) => k(fx) * k(fy),
+        _ => mean_k() * mean_k(),
+    }
+}
+
+fn is_valid(v: f32) -> bool {
+    v.is_finite() && v != NO_VALUE_F32
+}
+
+/// Binning of `factor` x `factor` pixels into one. Incomplete
+/// blocks at right and bottom edges are dropped
+pub fn bin_layer(layer: &ImageLayerF32, factor: usize, mode: BinMode) -> ImageLayerF32 {
+    if layer.is_empty() || factor <= 1 {
+        return layer.clone();
+    }
+    let res_width = layer.width() as usize / factor;
+    let res_height = layer.height() as usize / factor;
+    let mut result = ImageLayerF32::new(res_width as Crd, res_height as Crd);
+    if res_width == 0 { return result; }
+    result.as_slice_mut()
+        .par_chunks_mut(res_width)
+        .enumerate()
+        .for_each(|(y, dst_row)| {
+            for (x, dst) in dst_row.iter_mut().enumerate() {
+                let (mut sum, mut cnt, mut inf) = (0_f32, 0, false);
+                for sy in y * factor..(y + 1) * factor {
+                    let row = layer.row(sy as Crd);
+                    for v in &row[x * factor..(x + 1) * factor] {
+                        if v.is_infinite() { inf = true; }
+                        if !is_valid(*v) { continue; }
+                        sum += v;
+                        cnt += 1;
+                    }
+                }
+                *dst = if inf {
+                    f32::INFINITY // overexposure
+                } else if cnt == 0 {
+                    NO_VALUE_F32
+                } else {
+                    match mode {
+                        BinMode::Average => sum / cnt as f32,
+                        BinMode::Sum => sum * (factor * factor) as f32 / cnt as f32,
+                    }
+                };
+            }
+        });
+    result
+}
+
+pub fn bin_image(image: &Image, factor: usize, mode: BinMode) -> Image {
+    Image {
+        l: bin_layer(&image.l, factor, mode),
+        r: bin_layer(&image.r, factor, mode),
+        g: bin_layer(&image.g, factor, mode),
+        b: bin_layer(&image.b, factor, mode),
+    }
+}
+
+struct Contribution {
+    first:   usize,
+    weights: Vec<f32>,
+    total:   f32, // sum of weights
+    clamp:   Option<(usize, usize)>, // anti-ringing: indices of weights of nearest pixels
+}
+
+// Source pixel `s` covers [s, s+1) and destination pixel `d`
+// covers [d/scale, (d+1)/scale). Weights are lengths of overlaps
+fn calc_flux_contributions(src_size: usize, dst_size: usize) -> Vec<Contribution> {
+    let scale = dst_size as f64 / src_size as f64;
+    (0..dst_size).map(|d| {
+        let (from, to) = (d as f64 / scale, (d + 1) as f64 / scale);
+        let first = from.floor() as usize;
+        let last = ((to.ceil() as usize).max(first + 1) - 1).min(src_size - 1);
+        let weights: Vec<f32> = (first..=last)
+            .map(|s| (to.min(s as f64 + 1.0) - from.max(s as f64)).max(0.0) as f32)
+            .collect();
+        let total = weights.iter().sum();
+        Contribution { first, weights, total, clamp: None }
+    }).collect()
+}
+
+// Weights of source pixels for every destination pixel along one axis.
+// Kernel is widened when image is decreased to avoid aliasing
+fn calc_contributions(src_size: usize, dst_size: usize, kernel: ResampleKernel) -> Vec<Contribution> {
+    if kernel == ResampleKernel::FluxConserving {
+        return calc_flux_contributions(src_size, dst_size);
+    }
+    let scale = dst_size as f64 / src_size as f64;
+    let support = if scale < 1.0 { 1.0 / scale } else { 1.0 };
+    let radius = kernel.radius() * support;
+    (0..dst_size).map(|d| {
+        let center = (d as f64 + 0.5) / scale - 0.5;
+        let first = (center - radius).ceil().max(0.0) as usize;
+        let last = ((center + radius).floor() as usize).min(src_size - 1);
+        let mut weights: Vec<f32> = (first..=last)
+            .map(|s| kernel.weight((s as f64 - center) / support) as f32)
+            .collect();
+        let sum: f32 = weights.iter().sum();
+        if sum != 0.0 {
+            weights.iter_mut().for_each(|w| *w /= sum);
+        }
+        let clamp = kernel.anti_ringing().then(|| {
+            let from = ((center - support).ceil().max(0.0) as usize).max(first);
+            let to = ((center + support).floor().max(0.0) as usize).min(last);
+            (from - first, to.max(from) - first)
+        });
+        Contribution { first, weights, total: 1.0, clamp }
+    }).collect()
+}
+
+// Not defined pixels are excluded, weights of others are increased
+fn resample_value(values: impl Iterator<Item = f32>, c: &Contribution) -> f32 {
+    let (mut sum, mut w_sum) = (0_f32, 0_f32);
+    let (mut min, mut max) = (f32::MAX, f32::MIN);
+    for (i, (v, w)) in values.zip(&c.weights).enumerate() {
+        if !is_valid(v) { continue; }
+        sum += v * w;
+        w_sum += w;
+        if matches!(c.clamp, Some((from, to)) if (from..=to).contains(&i)) {
+            min = min.min(v);
+            max = max.max(v);
+        }
+    }
+    if w_sum.abs() < 1e-6 { return NO_VALUE_F32; }
+    let result = c.total * sum / w_sum;
+    if min <= max { result.clamp(min, max) } else { result }
+}
+
+pub fn resize_layer(layer: &ImageLayerF32, width: Crd, height: Crd, kernel: ResampleKernel) -> ImageLayerF32 {
+    if layer.is_empty() {
+        return ImageLayerF32::new_empty();
+    }
+    let src_width = layer.width() as usize;
+    let src_height = layer.height() as usize;
+    let (dst_width, dst_height) = (width as usize, height as usize);
+
+    // horizontal pass
+    let h_contr = calc_contributions(src_width, dst_width, kernel);
+    let mut tmp = ImageLayerF32::new(width, layer.height());
+    tmp.as_slice_mut()
+        .par_chunks_mut(dst_width)
+        .enumerate()
+        .for_each(|(y, dst_row)| {
+            let src_row = layer.row(y as Crd);
+            for (dst, c) in dst_row.iter_mut().zip(&h_contr) {
+                *dst = resample_value(src_row[c.first..].iter().copied(), c);
+            }
+        });
+
+    // vertical pass
+    let v_contr = calc_contributions(src_height, dst_height, kernel);
+    let mut result = ImageLayerF32::new(width, height);
+    result.as_slice_mut()
+        .par_chunks_mut(dst_width)
+        .zip(v_contr.par_iter())
+        .for_each(|(dst_row, c)| {
+            for (x, dst) in dst_row.iter_mut().enumerate() {
+                let column = (c.first..src_height).map(|y| tmp.get(x as Crd, y as Crd).unwrap_or(NO_VALUE_F32));
+                *dst = resample_value(column, c);
+            }
+        });
+    result
+}
+
+/// Rescales image by arbitrary factor
+pub fn rescale_image(image: &Image, scale: f64, kernel: ResampleKernel) -> anyhow::Result<Image> {
+    let width = (image.width() as f64 * scale).round() as Crd;
+    let height = (image.height() as f64 * scale).round() as Crd;
+    if scale <= 0.0 || width < 1 || height < 1 {
+        anyhow::bail!("Wrong scale {}", scale);
+    }
+    crate::safe_read::checked_image_size(width as usize, height as usize, 1, 1)?;
+    Ok(Image {
+        l: resize_layer(&image.l, width, height, kernel),
+        r: resize_layer(&image.r, width, height, kernel),
+        g: resize_layer(&image.g, width, height, kernel),
+        b: resize_layer(&image.b, width, height, kernel),
+    })
+}
+
+// Area of intersection of convex polygon with unit square at (x1, y1)
+// (Sutherland-Hodgman clipping and shoelace formula)
+fn clipped_area(poly: &[(f64, f64)], x1: f64, y1: f64) -> f64 {
+    let (x2, y2) = (x1 + 1.0, y1 + 1.0);
+    let mut points = poly.to_vec();
+    // each edge is defined by signed distance of point from it (inside >= 0)
+    let edges: [&dyn Fn(f64, f64) -> f64; 4] = [
+        &|x, _| x - x1, &|x, _| x2 - x, &|_, y| y - y1, &|_, y| y2 - y,
+    ];
+    for dist in edges {
+        if points.is_empty() { return 0.0; }
+        let mut clipped = Vec::with_capacity(points.len() + 2);
+        for (i, &(ax, ay)) in points.iter().enumerate() {
+            let (bx, by) = points[(i + 1) % points.len()];
+            let (da, db) = (dist(ax, ay), dist(bx, by));
+            if da >= 0.0 { clipped.push((ax, ay)); }
+            if (da >= 0.0) != (db >= 0.0) {
+                let t = da / (da - db);
+                clipped.push((ax + t * (bx - ax), ay + t * (by - ay)));
+            }
+        }
+        points = clipped;
+    }
+    polygon_area(&points)
+}
+
+fn polygon_area(points: &[(f64, f64)]) -> f64 {
+    let mut area = 0.0;
+    for (i, &(ax, ay)) in points.iter().enumerate() {
+        let (bx, by) = points[(i + 1) % points.len()];
+        area += ax * by - bx * ay;
+    }
+    0.5 * area.abs()
+}
+
+/// Flux conserving affine warp. Value of result pixel is integral of source
+/// image over area of result pixel transformed by `inv` into source image
+/// (pixel centers are at integer coordinates)
+pub fn warp_layer_flux_conserving(
+    layer:         &ImageLayerF32,
+    inv:           &[[f64; 3]; 2],
+    width:         Crd,
+    height:        Crd,
+    default_value: f32,
+) -> ImageLayerF32 {
+    if layer.is_empty() { return ImageLayerF32::new_empty(); }
+    let transform = |x: f64, y: f64| (
+        inv[0][0] * x + inv[0][1] * y + inv[0][2],
+        inv[1][0] * x + inv[1][1] * y + inv[1][2],
+    );
+    let mut result = ImageLayerF32::new(width, height);
+    result.as_slice_mut()
+        .par_chunks_mut(width as usize)
+        .enumerate()
+        .for_each(|(y, row)| {
+            let y = y as f64;
+            for (x, dst) in row.iter_mut().enumerate() {
+                let x = x as f64;
+                let poly = [
+                    transform(x - 0.5, y - 0.5),
+                    transform(x + 0.5, y - 0.5),
+                    transform(x + 0.5, y + 0.5),
+                    transform(x - 0.5, y + 0.5),
+                ];
+                let pixel_area = polygon_area(&poly);
+                let min_x = poly.iter().map(|p| p.0).fold(f64::MAX, f64::min);
+                let max_x = poly.iter().map(|p| p.0).fold(f64::MIN, f64::max);
+                let min_y = poly.iter().map(|p| p.1).fold(f64::MAX, f64::min);
+                let max_y = poly.iter().map(|p| p.1).fold(f64::MIN, f64::max);
+                let (mut sum, mut valid_area, mut inf) = (0_f64, 0_f64, false);
+                for sy in (min_y + 0.5).floor() as Crd ..= (max_y + 0.5).floor() as Crd {
+                    for sx in (min_x + 0.5).floor() as Crd ..= (max_x + 0.5).floor() as Crd {
+                        let Some(v) = layer.get(sx, sy) else { continue; };
+                        let area = clipped_area(&poly, sx as f64 - 0.5, sy as f64 - 0.5);
+                        if area <= 0.0 { continue; }
+                        if v.is_infinite() { inf = true; }
+                        if !is_valid(v) { continue; }
+                        sum += v as f64 * area;
+                        valid_area += area;
+                    }
+                }
+                *dst = if inf {
+                    f32::INFINITY // overexposure
+                } else if valid_area < 1e-6 {
+                    default_value
+                } else {
+                    (sum * pixel_area / valid_area) as f32
+                };
+            }
+        });
+    result
+}
@@ -0,0 +1,159 @@
+use std::path::*;
+use serde::*;
+use chrono::prelude::*;
+use crate::{project::*, stacking_utils::*, fs_utils::*};
+
+/* Machine-readable report of batch run (`--report <file>`) for automation
+   of observatories: statistics of every light file, weights, transforms
+   and rejection of stacks and checksums of created files */
+
+#[derive(Serialize)]
+pub struct OutputFile {
+    pub file:   PathBuf,
+    pub size:   u64,
+    pub sha256: String,
+}
+
+impl OutputFile {
+    pub fn new(file_name: &Path) -> anyhow::Result<Self> {
+        Ok(Self {
+            file:   file_name.to_path_buf(),
+            size:   std::fs::metadata(file_name)?.len(),
+            sha256: calc_file_sha256(file_name)?,
+        })
+    }
+}
+
+/// Light file of project with its registration info
+#[derive(Serialize)]
+pub struct FrameReport {
+    pub group:    String,
+    pub file:     PathBuf,
+    pub used:     bool,
+    pub exposure: Option<f32>,
+    pub time:     Option<DateTime<Local>>,
+    pub reg_info: Option<RegInfo>,
+    pub cleanup:  Vec<&'static str>, // reasons of excluding by cleanup
+    pub error:    Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct StackReport {
+    pub group:           Option<String>,
+    pub result_file:     OutputFile,
+    pub frames_count:    usize,
+    pub frame_noise:     f32,
+    pub result_noise:    f32,
+    pub snr_improvement: Option<f32>,
+    pub rejection:       RejectionStat,
+    pub frames:          Vec<StackedFrame>,
+    pub sub_stacks:      Vec<SubStackReport>,
+}
+
+#[derive(Serialize)]
+pub struct SubStackReport {
+    pub start:        DateTime<Local>,
+    pub frames_count: usize,
+    pub result_noise: f32,
+    pub result_file:  OutputFile,
+}
+
+#[derive(Serialize)]
+pub struct RunReport {
+    pub app_version: &'static str,
+    pub command:     &'static str,
+    pub project:     PathBuf,
+    pub started:     DateTime<Local>,
+    pub finished:    Option<DateTime<Local>>,
+    pub masters:     Vec<OutputFile>,
+    pub frames:      Vec<FrameReport>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub transforms:  Vec<FrameTransform>,
+    pub stacks:      Vec<StackReport>,
+    pub outputs:     Vec<OutputFile>, // previews, maps and other files
+}
+
+impl RunReport {
+    pub fn new(command: &'static str, project_file: &Path, started: DateTime<Local>) -> Self {
+        Self {
+            app_version: env!("CARGO_PKG_VERSION"),
+            command,
+            project:     project_file.to_path_buf(),
+            started,
+            finished:    None,
+            masters:     Vec::new(),
+            frames:      Vec::new(),
+            transforms:  Vec::new(),
+            stacks:      Vec::new(),
+            outputs:     Vec::new(),
+        }
+    }
+
+    /// Adds master files and light files of used groups of project
+    pub fn add_project(&mut self, project: &Project) -> anyhow::Result<()> {
+        const CLEANUP_FLAGS: [(FileFlags, &str); 5] = [
+            (FILE_FLAG_CLEANUP_R_DEV, "stars_r_dev"),
+            (FILE_FLAG_CLEANUP_FWHM,  "fwhm"),
+            (FILE_FLAG_CLEANUP_STARS, "stars"),
+            (FILE_FLAG_CLEANUP_NOISE, "noise"),
+            (FILE_FLAG_CLEANUP_BG,    "background"),
+        ];
+        for (idx, group) in project.groups().iter().enumerate() {
+            if !group.used() { continue; }
+            for file in group.master_files() {
+                self.masters.push(OutputFile::new(&file)?);
+            }
+            for file in group.light_files.list() {
+                self.frames.push(FrameReport {
+                    group:    group.name(idx),
+                    file:     file.file_name().clone(),
+                    used:     file.used(),
+                    exposure: *file.exp(),
+                    time:     *file.file_time(),
+                    reg_info: file.reg_info().clone(),
+                    cleanup:  CLEANUP_FLAGS.iter()
+                        .filter(|(flag, _)| file.flags() & flag != 0)
+                        .map(|(_, name)| *name)
+                        .collect(),
+                    error:    file.get_error_test().map(str::to_string),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    pub fn add_stack(&mut self, group: Option<String>, result: &StackLightsResult) -> anyhow::Result<()> {
+        self.stacks.push(StackReport {
+            group,
+            result_file:     OutputFile::new(&result.file_name)?,
+            frames_count:    result.noise.frames_count,
+            frame_noise:     result.noise.frame_noise,
+            result_noise:    result.noise.result_noise,
+            snr_improvement: result.noise.snr_improvement().map(|(snr, _)| snr),
+            rejection:       result.rejection.clone(),
+            frames:          result.frames.clone(),
+            sub_stacks:      result.sub_stacks.iter()
+                .map(|sub_stack| Ok(SubStackReport {
+                    start:        sub_stack.start,
+                    frames_count: sub_stack.frames_count,
+                    result_noise: sub_stack.noise.result_noise,
+                    result_file:  OutputFile::new(&sub_stack.file_name)?,
+                }))
+                .collect::<anyhow::Result<_>>()?,
+        });
+        Ok(())
+    }
+
+    pub fn add_output(&mut self, file_name: &Path) -> anyhow::Result<()> {
+        self.outputs.push(OutputFile::new(file_name)?);
+        Ok(())
+    }
+
+    pub fn save(&mut self, file_name: &Path) -> anyhow::Result<()> {
+        self.finished = Some(Local::now());
+        let text = serde_json::to_string_pretty(self)?;
+        write_file_atomically(file_name, |tmp_file_name| {
+            Ok(std::fs::write(tmp_file_name, &text)?)
+        })
+    }
+}
@@ -0,0 +1,67 @@
+use std::io::*;
+
+/* Validation of sizes read from headers of binary files. Sizes are checked
+   before allocation and reading so malformed or truncated files give error
+   instead of panic or huge memory allocation */
+
+pub const MAX_IMAGE_PIXELS: usize = 1_000_000_000;
+pub const MAX_HEADER_SIZE: usize = 64 * 1024 * 1024;
+
+/// Checks image dimensions and returns size of image data in bytes
+pub fn checked_image_size(
+    width:     usize,
+    height:    usize,
+    planes:    usize,
+    item_size: usize
+) -> anyhow::Result<usize> {
+    if width == 0 || height == 0 || planes == 0 {
+        anyhow::bail!("Wrong image size {}x{}x{}", width, height, planes);
+    }
+    let pixels = width.checked_mul(height)
+        .filter(|pixels| *pixels <= MAX_IMAGE_PIXELS)
+        .ok_or_else(|| anyhow::anyhow!("Image size {}x{} is too big", width, height))?;
+    pixels.checked_mul(planes)
+        .and_then(|v| v.checked_mul(item_size))
+        .ok_or_else(|| anyhow::anyhow!("Image size {}x{}x{} is too big", width, height, planes))
+}
+
+/// Checks data block of `size` bytes at `pos` is inside of file
+pub fn check_block_in_file(pos: u64, size: u64, file_len: u64, what: &str) -> anyhow::Result<()> {
+    match pos.checked_add(size) {
+        Some(end) if end <= file_len => Ok(()),
+        _ => anyhow::bail!("{} is out of file (file is truncated or corrupted)", what),
+    }
+}
+
+/// Reads exactly `len` bytes. Buffer grows while data is read
+/// so wrong length in header doesn't cause huge allocation
+pub fn read_bytes<R: Read>(
+    src:     &mut R,
+    len:     usize,
+    max_len: usize,
+    what:    &str
+) -> anyhow::Result<Vec<u8>> {
+    if len > max_len {
+        anyhow::bail!("{} is too big ({} bytes)", what, len);
+    }
+    let mut result = Vec::new();
+    src.take(len as u64).read_to_end(&mut result)?;
+    if result.len() != len {
+        anyhow::bail!("{} is truncated", what);
+    }
+    Ok(result)
+}
+
+/// Decompresses data but no more than `max_len` bytes (to protect from zip bombs)
+pub fn read_to_end_limited<R: Read>(
+    src:     R,
+    max_len: usize,
+    what:    &str
+) -> anyhow::Result<Vec<u8>> {
+    let mut result = Vec::new();
+    src.take(max_len as u64 + 1).read_to_end(&mut result)?;
+    if result.len() > max_len {
+        anyhow::bail!("{} is bigger than expected", what);
+    }
+    Ok(result)
+}
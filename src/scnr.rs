@@ -0,0 +1,124 @@
+use rayon::prelude::*;
+use crate::image::*;
+
+/* SCNR (subtractive chromatic noise reduction) of green and
+   chroma noise reduction of RGB images */
+
+fn is_valid(v: f32) -> bool {
+    v.is_finite() && v != NO_VALUE_F32
+}
+
+/// Average neutral SCNR. `amount` = 1 gives full removal of green excess
+pub fn remove_green_noise(image: &mut Image, amount: f32) -> anyhow::Result<()> {
+    if !image.is_rgb() {
+        anyhow::bail!("SCNR needs RGB image");
+    }
+    if !(0.0..=1.0).contains(&amount) {
+        anyhow::bail!("SCNR amount must be in 0..1 range");
+    }
+    image.g.as_slice_mut()
+        .par_iter_mut()
+        .zip(image.r.as_slice().par_iter())
+        .zip(image.b.as_slice().par_iter())
+        .for_each(|((g, r), b)| {
+            if !is_valid(*g) || !is_valid(*r) || !is_valid(*b) { return; }
+            let neutral = 0.5 * (r + b);
+            if *g > neutral {
+                *g = *g * (1.0 - amount) + neutral * amount;
+            }
+        });
+    Ok(())
+}
+
+// Mean in (2*radius+1)x(2*radius+1) box. NAN values are skipped
+fn box_blur(layer: &ImageLayerF32, radius: usize) -> ImageLayerF32 {
+    let width = layer.width() as usize;
+    let height = layer.height() as usize;
+    let r = radius as isize;
+
+    // horizontal pass: sums and counts of valid values
+    let mut sums = vec![0_f32; width * height];
+    let mut counts = vec![0_u32; width * height];
+    sums.par_chunks_mut(width)
+        .zip(counts.par_chunks_mut(width))
+        .enumerate()
+        .for_each(|(y, (sums_row, counts_row))| {
+            let row = layer.row(y as Crd);
+            for x in 0..width as isize {
+                let (mut sum, mut cnt) = (0_f32, 0_u32);
+                for v in &row[(x - r).max(0) as usize..=(x + r).min(width as isize - 1) as usize] {
+                    if v.is_nan() { continue; }
+                    sum += v;
+                    cnt += 1;
+                }
+                sums_row[x as usize] = sum;
+                counts_row[x as usize] = cnt;
+            }
+        });
+
+    // vertical pass
+    let mut result = ImageLayerF32::new(width as Crd, height as Crd);
+    result.as_slice_mut()
+        .par_chunks_mut(width)
+        .enumerate()
+        .for_each(|(y, dst_row)| {
+            let y1 = y.saturating_sub(radius);
+            let y2 = (y + radius).min(height - 1);
+            for (x, dst) in dst_row.iter_mut().enumerate() {
+                let (mut sum, mut cnt) = (0_f32, 0_u32);
+                for sy in y1..=y2 {
+                    sum += sums[sy * width + x];
+                    cnt += counts[sy * width + x];
+                }
+                *dst = if cnt != 0 { sum / cnt as f32 } else { f32::NAN };
+            }
+        });
+    result
+}
+
+/// Chroma is blurred but luminance is kept
+pub fn reduce_chroma_noise(image: &mut Image, radius: usize) -> anyhow::Result<()> {
+    if !image.is_rgb() {
+        anyhow::bail!("Chroma noise reduction needs RGB image");
+    }
+    if radius == 0 {
+        return Ok(());
+    }
+    let width = image.width();
+    let height = image.height();
+    let mut cr = ImageLayerF32::new(width, height);
+    let mut cb = ImageLayerF32::new(width, height);
+    for (r, g, b, cr, cb) in itertools::izip!(
+        image.r.iter(), image.g.iter(), image.b.iter(), cr.iter_mut(), cb.iter_mut()
+    ) {
+        if is_valid(*r) && is_valid(*g) && is_valid(*b) {
+            let l = (r + g + b) / 3.0;
+            *cr = r - l;
+            *cb = b - l;
+        } else {
+            *cr = f32::NAN;
+            *cb = f32::NAN;
+        }
+    }
+    let mut cr = box_blur(&cr, radius);
+    let mut cb = box_blur(&cb, radius);
+
+    // 3 box blurs are close to gaussian blur
+    for _ in 0..2 {
+        cr = box_blur(&cr, radius);
+        cb = box_blur(&cb, radius);
+    }
+
+    for (r, g, b, cr, cb) in itertools::izip!(
+        image.r.iter_mut(), image.g.iter_mut(), image.b.iter_mut(), cr.iter(), cb.iter()
+    ) {
+        if !is_valid(*r) || !is_valid(*g) || !is_valid(*b) || cr.is_nan() || cb.is_nan() {
+            continue;
+        }
+        let l = (*r + *g + *b) / 3.0;
+        *r = l + cr;
+        *b = l + cb;
+        *g = l - cr - cb;
+    }
+    Ok(())
+}
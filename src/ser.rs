@@ -0,0 +1,328 @@
+use std::{path::*, io::*, fs::File, ops::RangeInclusive};
+use byteorder::{LittleEndian, BigEndian, ReadBytesExt};
+use chrono::prelude::*;
+use crate::{image::*, image_io::*, image_raw::*, progress::*, fs_utils::*, safe_read::*};
+
+/* SER video files (planetary and lucky imaging captures) */
+
+const SER_HEADER_SIZE: u64 = 178;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SerColor {
+    Mono,
+    Bayer(CfaType),
+    Rgb,
+    Bgr,
+}
+
+impl SerColor {
+    fn from_id(id: i32) -> anyhow::Result<Self> {
+        match id {
+            0   => Ok(SerColor::Mono),
+            8   => Ok(SerColor::Bayer(CfaType::RGGB)),
+            9   => Ok(SerColor::Bayer(CfaType::GRBG)),
+            10  => Ok(SerColor::Bayer(CfaType::GBRG)),
+            11  => Ok(SerColor::Bayer(CfaType::BGGR)),
+            100 => Ok(SerColor::Rgb),
+            101 => Ok(SerColor::Bgr),
+            _   => anyhow::bail!("SER color format {} is not supported", id),
+        }
+    }
+
+    fn planes(self) -> usize {
+        match self {
+            SerColor::Rgb|SerColor::Bgr => 3,
+            _ => 1,
+        }
+    }
+}
+
+pub struct SerFile {
+    file:          File,
+    file_name:     PathBuf,
+    pub color:     SerColor,
+    pub width:     usize,
+    pub height:    usize,
+    pub bit_depth: u32,
+    pub frames:    usize,
+    little_endian: bool,
+    pub camera:    Option<String>,
+    pub telescope: Option<String>,
+    start_time:    Option<DateTime<Local>>,
+    frame_times:   Vec<DateTime<Local>>,
+}
+
+// Time in SER file is number of 100ns intervals since 01.01.0001
+fn ser_time_to_date_time(value: i64) -> Option<DateTime<Local>> {
+    const TICKS_TO_UNIX_EPOCH: i64 = 621_355_968_000_000_000;
+    if value <= 0 { return None; }
+    let ticks = value - TICKS_TO_UNIX_EPOCH;
+    let secs = ticks.div_euclid(10_000_000);
+    let nanos = (ticks.rem_euclid(10_000_000) * 100) as u32;
+    Utc.timestamp_opt(secs, nanos).single().map(|t| t.with_timezone(&Local))
+}
+
+fn read_str<R: Read>(src: &mut R, len: usize) -> anyhow::Result<Option<String>> {
+    let mut buf = vec![0_u8; len];
+    src.read_exact(&mut buf)?;
+    let text = String::from_utf8_lossy(&buf)
+        .trim_end_matches('\0')
+        .trim()
+        .to_string();
+    Ok(if text.is_empty() { None } else { Some(text) })
+}
+
+impl SerFile {
+    pub fn open(file_name: &Path) -> anyhow::Result<SerFile> {
+        let mut file = File::open(file_name)?;
+        let mut file_id = [0_u8; 14];
+        file.read_exact(&mut file_id)?;
+        if &file_id != b"LUCAM-RECORDER" {
+            anyhow::bail!("{} is not SER file", file_name.to_str().unwrap_or(""));
+        }
+        let _lu_id = file.read_i32::<LittleEndian>()?;
+        let color = SerColor::from_id(file.read_i32::<LittleEndian>()?)?;
+
+        // Most of capture programs write 0 for little endian data
+        // despite of specification
+        let little_endian = file.read_i32::<LittleEndian>()? == 0;
+
+        let width = file.read_i32::<LittleEndian>()?;
+        let height = file.read_i32::<LittleEndian>()?;
+        let bit_depth = file.read_i32::<LittleEndian>()?;
+        let frames = file.read_i32::<LittleEndian>()?;
+        if width <= 0 || height <= 0 || frames < 0 || !(1..=16).contains(&bit_depth) {
+            anyhow::bail!("Wrong SER file header");
+        }
+        let frame_size = checked_image_size(
+            width as usize,
+            height as usize,
+            color.planes(),
+            if bit_depth <= 8 { 1 } else { 2 }
+        )?;
+        let _observer = read_str(&mut file, 40)?;
+        let camera = read_str(&mut file, 40)?;
+        let telescope = read_str(&mut file, 40)?;
+        let _start_time_local = file.read_i64::<LittleEndian>()?;
+        let start_time = ser_time_to_date_time(file.read_i64::<LittleEndian>()?);
+
+        let mut result = SerFile {
+            file,
+            file_name: file_name.to_path_buf(),
+            color,
+            width: width as usize,
+            height: height as usize,
+            bit_depth: bit_depth as u32,
+            frames: frames as usize,
+            little_endian,
+            camera,
+            telescope,
+            start_time,
+            frame_times: Vec::new(),
+        };
+
+        // Optional trailer with UTC time of each frame
+        let file_len = result.file.metadata()?.len();
+        let frames_size = (frame_size as u64).checked_mul(result.frames as u64)
+            .ok_or_else(|| anyhow::anyhow!("Wrong SER file header"))?;
+        check_block_in_file(SER_HEADER_SIZE, frames_size, file_len, "SER frames data")?;
+        let trailer_pos = SER_HEADER_SIZE + frames_size;
+        if file_len - trailer_pos >= 8 * result.frames as u64 {
+            result.file.seek(SeekFrom::Start(trailer_pos))?;
+            let mut reader = BufReader::new(&result.file);
+            for _ in 0..result.frames {
+                let time = reader.read_i64::<LittleEndian>()?;
+                match ser_time_to_date_time(time) {
+                    Some(time) => result.frame_times.push(time),
+                    None => { result.frame_times.clear(); break; }
+                }
+            }
+        }
+
+        Ok(result)
+    }
+
+    fn bytes_per_value(&self) -> usize {
+        if self.bit_depth <= 8 { 1 } else { 2 }
+    }
+
+    fn frame_size(&self) -> usize {
+        self.width * self.height * self.color.planes() * self.bytes_per_value()
+    }
+
+    pub fn frame_time(&self, index: usize) -> Option<DateTime<Local>> {
+        self.frame_times.get(index).copied().or(self.start_time)
+    }
+
+    /// Exposure is not stored in SER file so it is estimated
+    /// by time between frames
+    pub fn estimated_exposure(&self) -> Option<f64> {
+        if self.frame_times.len() < 2 { return None; }
+        let first = self.frame_times.first()?;
+        let last = self.frame_times.last()?;
+        let secs = (*last - *first).num_microseconds()? as f64 / 1_000_000.0;
+        if secs <= 0.0 { return None; }
+        Some(secs / (self.frame_times.len() - 1) as f64)
+    }
+
+    /// Returns values of frame normalized to 0..65535 range
+    pub fn read_frame_u16(&mut self, index: usize) -> anyhow::Result<Vec<u16>> {
+        if index >= self.frames {
+            anyhow::bail!("Frame {} is out of range (total {} frames)", index, self.frames);
+        }
+        let pos = SER_HEADER_SIZE + index as u64 * self.frame_size() as u64;
+        self.file.seek(SeekFrom::Start(pos))?;
+        let values_cnt = self.width * self.height * self.color.planes();
+        let mut data = vec![0_u16; values_cnt];
+        let mut reader = BufReader::new(&self.file);
+        if self.bytes_per_value() == 1 {
+            let mut buf = vec![0_u8; values_cnt];
+            reader.read_exact(&mut buf)?;
+            for (d, s) in data.iter_mut().zip(buf) {
+                *d = s as u16;
+            }
+        } else if self.little_endian {
+            reader.read_u16_into::<LittleEndian>(&mut data)?;
+        } else {
+            reader.read_u16_into::<BigEndian>(&mut data)?;
+        }
+        let max = ((1_u32 << self.bit_depth) - 1) as f32;
+        if max != u16::MAX as f32 {
+            let k = u16::MAX as f32 / max;
+            for v in &mut data {
+                *v = (*v as f32 * k).round().min(u16::MAX as f32) as u16;
+            }
+        }
+        Ok(data)
+    }
+
+    /// Returns frame as image in 0..1 range. Bayer frames are debayered
+    pub fn read_frame_image(&mut self, index: usize) -> anyhow::Result<Image> {
+        let data = self.read_frame_u16(index)?;
+        let (width, height) = (self.width as Crd, self.height as Crd);
+        let to_f32 = |v: u16| v as f32 / u16::MAX as f32;
+        match self.color {
+            SerColor::Mono => {
+                let mut image = Image::new_grey(width, height);
+                for (d, s) in image.l.iter_mut().zip(data) {
+                    *d = to_f32(s);
+                }
+                Ok(image)
+            }
+            SerColor::Bayer(cfa) => {
+                let raw_info = RawImageInfo {
+                    width, height,
+                    max_values: [1.0; 4],
+                    black_values: [0.0; 4],
+                    wb: [1.0; 4],
+                    cam_to_rgb: None,
+                    cfa: Cfa::from_cfa_type(Some(cfa)),
+                    camera: self.camera.clone(),
+                    exposure: None,
+                    iso: None,
+                };
+                let mut raw = RawImage::new_from_info(raw_info);
+                for (d, s) in raw.data.iter_mut().zip(data) {
+                    *d = to_f32(s);
+                }
+                raw.demosaic(DemosaicAlgo::Linear, false)
+            }
+            SerColor::Rgb|SerColor::Bgr => {
+                let mut image = Image::new_color(width, height);
+                let (r_idx, b_idx) = if self.color == SerColor::Rgb { (0, 2) } else { (2, 0) };
+                for (pix, r, g, b) in itertools::izip!(
+                    data.chunks_exact(3),
+                    image.r.iter_mut(),
+                    image.g.iter_mut(),
+                    image.b.iter_mut()
+                ) {
+                    *r = to_f32(pix[r_idx]);
+                    *g = to_f32(pix[1]);
+                    *b = to_f32(pix[b_idx]);
+                }
+                Ok(image)
+            }
+        }
+    }
+
+    fn frame_info(&self, index: usize, file_name: &Path) -> ImageInfo {
+        ImageInfo {
+            file_name: file_name.to_path_buf(),
+            width: self.width,
+            height: self.height,
+            file_time: self.frame_time(index),
+            exp: self.estimated_exposure(),
+            cfa_type: match self.color {
+                SerColor::Bayer(cfa) => Some(cfa),
+                _ => None,
+            },
+            camera: self.camera.clone(),
+            lens: self.telescope.clone(),
+            .. Default::default()
+        }
+    }
+
+    /// Saves frames of SER file as separate FITS files so they can be used as
+    /// light files. Bayer frames are saved as is and debayered during stacking
+    pub fn extract_frames(
+        &mut self,
+        frames:      Option<RangeInclusive<usize>>,
+        dest_dir:    &Path,
+        fits_opts:   FitsSaveOpts,
+        progress:    &ProgressTs,
+    ) -> anyhow::Result<Vec<PathBuf>> {
+        if self.frames == 0 {
+            anyhow::bail!("SER file has no frames");
+        }
+        let frames = frames.unwrap_or(0..=self.frames-1);
+        if frames.is_empty() || *frames.end() >= self.frames {
+            anyhow::bail!(
+                "Wrong frames range {}-{} (total {} frames)",
+                frames.start()+1, frames.end()+1, self.frames
+            );
+        }
+        if !dest_dir.is_dir() {
+            std::fs::create_dir_all(dest_dir)?;
+        }
+        let base_name = self.file_name
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or("frame")
+            .to_string();
+
+        progress.lock().unwrap().set_total(frames.clone().count());
+        let mut result = Vec::new();
+        for index in frames {
+            let file_name = dest_dir.join(format!("{}_{:06}.fit", base_name, index+1));
+            let data = self.read_frame_u16(index)?;
+            let info = self.frame_info(index, &file_name);
+            write_file_atomically(&file_name, |tmp_file_name| {
+                match self.color {
+                    SerColor::Mono|SerColor::Bayer(_) => {
+                        save_cfa_image_to_fits_file(&data, &info, tmp_file_name, fits_opts.compression)?;
+                    }
+                    SerColor::Rgb|SerColor::Bgr => {
+                        let mut image = Image::new_color(self.width as Crd, self.height as Crd);
+                        let (r_idx, b_idx) = if self.color == SerColor::Rgb { (0, 2) } else { (2, 0) };
+                        let pixels = data.chunks_exact(3);
+                        for (pix, r, g, b) in itertools::izip!(
+                            pixels,
+                            image.r.iter_mut(),
+                            image.g.iter_mut(),
+                            image.b.iter_mut()
+                        ) {
+                            *r = pix[r_idx] as f32 / u16::MAX as f32;
+                            *g = pix[1] as f32 / u16::MAX as f32;
+                            *b = pix[b_idx] as f32 / u16::MAX as f32;
+                        }
+                        save_image_to_fits_file(&image, &info, tmp_file_name, fits_opts)?;
+                    }
+                }
+                Ok(())
+            })?;
+            progress.lock().unwrap().progress(true, extract_file_name(&file_name));
+            result.push(file_name);
+        }
+        Ok(result)
+    }
+}
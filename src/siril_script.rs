@@ -0,0 +1,308 @@
+use std::{path::*, collections::HashMap};
+use crate::{
+    calc::*, config::*, image_io::*, fs_utils::*, progress::*, project::*,
+    stacking_utils::{ResumeMode, StackMapsOpts},
+};
+
+/* Interpreter of subset of Siril script commands (convert, cd, calibrate,
+   register, stack). Sequences of Siril are only names for lists of source
+   files here. Stacking of not registered sequence creates master file
+   (it's stacked later by project) and stacking of registered sequence
+   creates project with all these files and stacks it */
+
+#[derive(Debug, PartialEq)]
+pub enum SirilCommand {
+    Cd(PathBuf),
+    Convert { name: String },
+    Calibrate {
+        seq:    String,
+        bias:   Option<String>,
+        dark:   Option<String>,
+        flat:   Option<String>,
+        prefix: String,
+    },
+    Register { seq: String, prefix: String },
+    Stack { seq: String, calc_opts: CalcOpts, out: Option<String> },
+    Ignored(String),
+}
+
+fn split_args(line: &str) -> Vec<String> {
+    let mut result = Vec::new();
+    let mut value = String::new();
+    let mut in_quotes = false;
+    for c in line.chars() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            c if c.is_whitespace() && !in_quotes => {
+                if !value.is_empty() { result.push(std::mem::take(&mut value)); }
+            },
+            _ => value.push(c),
+        }
+    }
+    if !value.is_empty() { result.push(value); }
+    result
+}
+
+fn find_option(args: &[String], name: &str) -> Option<String> {
+    let prefix = format!("-{}=", name);
+    args.iter()
+        .find_map(|a| a.strip_prefix(&prefix))
+        .map(|s| s.to_string())
+}
+
+// stack <seq> [sum|min|max|mean|median|rej [type] [sig_low sig_high]] [-out=name] ...
+fn parse_stack_calc_opts(args: &[String]) -> CalcOpts {
+    let params: Vec<&str> = args.iter()
+        .map(|s| s.as_str())
+        .take_while(|s| !s.starts_with('-'))
+        .collect();
+    match params.first().copied() {
+        Some("median") =>
+            CalcOpts { mode: CalcMode::Median, ..CalcOpts::default() },
+        Some("sum"|"mean"|"min"|"max") =>
+            CalcOpts { mode: CalcMode::Mean, ..CalcOpts::default() },
+        _ => {
+            let sigmas: Vec<f32> = params.iter()
+                .skip(1)
+                .filter_map(|s| s.parse().ok())
+                .collect();
+            let mut result = CalcOpts::default();
+            if let Some(kappa) = sigmas.iter().copied().reduce(f32::max) {
+                result.kappa = kappa;
+            }
+            result
+        },
+    }
+}
+
+fn parse_command(line: &str) -> anyhow::Result<Option<SirilCommand>> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+    let args = split_args(line);
+    let command = args[0].to_lowercase();
+    let seq_arg = || args.get(1).cloned().ok_or_else(||
+        anyhow::anyhow!("Sequence is not defined for {}", command)
+    );
+    let result = match command.as_str() {
+        "cd" =>
+            SirilCommand::Cd(PathBuf::from(seq_arg()?)),
+        "convert"|"convertraw"|"link" =>
+            SirilCommand::Convert { name: seq_arg()? },
+        "calibrate"|"preprocess" => SirilCommand::Calibrate {
+            seq:    seq_arg()?,
+            bias:   find_option(&args, "bias"),
+            dark:   find_option(&args, "dark"),
+            flat:   find_option(&args, "flat"),
+            prefix: find_option(&args, "prefix").unwrap_or("pp_".to_string()),
+        },
+        "register" => SirilCommand::Register {
+            seq:    seq_arg()?,
+            prefix: find_option(&args, "prefix").unwrap_or("r_".to_string()),
+        },
+        "stack" => SirilCommand::Stack {
+            seq:       seq_arg()?,
+            calc_opts: parse_stack_calc_opts(&args[2..]),
+            out:       find_option(&args, "out"),
+        },
+        "requires"|"setext"|"set16bits"|"set32bits"|"setcompress"|"setmem"|
+        "setcpu"|"close"|"load"|"save"|"seqsubsky"|"subsky" =>
+            SirilCommand::Ignored(command),
+        _ =>
+            anyhow::bail!("Siril command {} is not supported", command),
+    };
+    Ok(Some(result))
+}
+
+/// Parses script. Result contains line numbers (starting from 1) and commands
+pub fn parse_siril_script(text: &str) -> anyhow::Result<Vec<(usize, SirilCommand)>> {
+    let mut result = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let command = parse_command(line)
+            .map_err(|err| anyhow::anyhow!("Line {}: {}", index + 1, err))?;
+        if let Some(command) = command {
+            result.push((index + 1, command));
+        }
+    }
+    Ok(result)
+}
+
+#[derive(Clone, Default)]
+struct Sequence {
+    files:      Vec<PathBuf>,
+    bias:       Option<String>,
+    dark:       Option<String>,
+    flat:       Option<String>,
+    registered: bool,
+    calc_opts:  CalcOpts,
+}
+
+pub struct SirilScriptRunner<'a> {
+    work_dir:    PathBuf,
+    sequences:   HashMap<String, Sequence>,
+    masters:     HashMap<String, Sequence>, // by name of stacked file
+    config:      &'a Config,
+    progress:    ProgressTs,
+    cancel_flag: IsCancelledFun,
+    pub results: Vec<PathBuf>,
+}
+
+// "../masters/bias_stacked.fit" -> "bias_stacked"
+fn master_key(name: &str) -> String {
+    Path::new(name)
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or(name)
+        .to_lowercase()
+}
+
+impl<'a> SirilScriptRunner<'a> {
+    pub fn new(
+        work_dir:    &Path,
+        config:      &'a Config,
+        progress:    &ProgressTs,
+        cancel_flag: &IsCancelledFun
+    ) -> Self {
+        Self {
+            work_dir: work_dir.to_path_buf(),
+            sequences: HashMap::new(),
+            masters: HashMap::new(),
+            config,
+            progress: progress.clone(),
+            cancel_flag: cancel_flag.clone(),
+            results: Vec::new(),
+        }
+    }
+
+    pub fn run(&mut self, commands: &[(usize, SirilCommand)]) -> anyhow::Result<()> {
+        for (line, command) in commands {
+            log::info!("Siril script line {}: {:?}", line, command);
+            self.exec(command)
+                .map_err(|err| anyhow::anyhow!("Line {}: {}", line, err))?;
+        }
+        Ok(())
+    }
+
+    fn sequence(&self, name: &str) -> anyhow::Result<Sequence> {
+        self.sequences.get(name)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("Sequence {} is not defined", name))
+    }
+
+    fn master(&self, name: &str) -> anyhow::Result<&Sequence> {
+        self.masters.get(&master_key(name)).ok_or_else(|| anyhow::anyhow!(
+            "Master {} is not stacked by this script", name
+        ))
+    }
+
+    fn exec(&mut self, command: &SirilCommand) -> anyhow::Result<()> {
+        match command {
+            SirilCommand::Cd(dir) => {
+                let new_dir = self.work_dir.join(dir);
+                std::fs::create_dir_all(&new_dir)?;
+                self.work_dir = new_dir;
+            }
+            SirilCommand::Convert { name } => {
+                let mut files: Vec<_> = std::fs::read_dir(&self.work_dir)?
+                    .filter_map(|e| e.ok())
+                    .map(|e| e.path())
+                    .filter(|p| p.is_file() && is_source_file_name(p))
+                    .collect();
+                if files.is_empty() {
+                    anyhow::bail!("No image files in {}", path_to_str(&self.work_dir));
+                }
+                files.sort();
+                self.sequences.insert(name.clone(), Sequence { files, ..Sequence::default() });
+            }
+            SirilCommand::Calibrate { seq, bias, dark, flat, prefix } => {
+                let mut sequence = self.sequence(seq)?;
+                for master in [bias, dark, flat].into_iter().flatten() {
+                    self.master(master)?;
+                }
+                sequence.bias = bias.clone();
+                sequence.dark = dark.clone();
+                sequence.flat = flat.clone();
+                self.sequences.insert(format!("{}{}", prefix, seq), sequence);
+            }
+            SirilCommand::Register { seq, prefix } => {
+                let mut sequence = self.sequence(seq)?;
+                sequence.registered = true;
+                self.sequences.insert(format!("{}{}", prefix, seq), sequence);
+            }
+            SirilCommand::Stack { seq, calc_opts, out } => {
+                let mut sequence = self.sequence(seq)?;
+                sequence.calc_opts = calc_opts.clone();
+                let out_name = out.clone().unwrap_or_else(|| format!("{}_stacked", seq));
+                if sequence.registered {
+                    let result = self.stack_lights(&sequence, &out_name)?;
+                    self.results.push(result);
+                } else {
+                    self.masters.insert(master_key(&out_name), sequence);
+                }
+            }
+            SirilCommand::Ignored(_) => {}
+        }
+        Ok(())
+    }
+
+    fn add_files(&self, project: &mut Project, file_type: ProjectFileType, files: &[PathBuf]) -> anyhow::Result<()> {
+        let infos = load_src_file_info_for_files(&files.to_vec(), None, &self.cancel_flag, &self.progress)?;
+        project.group_by_index_mut(0)
+            .file_list_by_type_mut(file_type)
+            .add_files_from_src_file_info(infos);
+        Ok(())
+    }
+
+    fn stack_lights(&self, lights: &Sequence, out_name: &str) -> anyhow::Result<PathBuf> {
+        let mut project = Project::default();
+        project.make_default();
+        let mut project_config = ProjectConfig {
+            name: Some(master_key(out_name)),
+            light_calc_opts: lights.calc_opts.clone(),
+            ..ProjectConfig::default()
+        };
+        self.add_files(&mut project, ProjectFileType::Light, &lights.files)?;
+        if let Some(dark) = &lights.dark {
+            let darks = self.master(dark)?;
+            project_config.dark_calc_opts = darks.calc_opts.clone();
+            self.add_files(&mut project, ProjectFileType::Dark, &darks.files)?;
+        }
+        let mut bias = lights.bias.as_ref();
+        if let Some(flat) = &lights.flat {
+            let flats = self.master(flat)?;
+            project_config.flat_calc_opts = flats.calc_opts.clone();
+            self.add_files(&mut project, ProjectFileType::Flat, &flats.files)?;
+            bias = bias.or(flats.bias.as_ref());
+        }
+        if let Some(bias) = bias {
+            let biases = self.master(bias)?;
+            project_config.bias_calc_opts = biases.calc_opts.clone();
+            self.add_files(&mut project, ProjectFileType::Bias, &biases.files)?;
+        }
+        project.set_config(project_config);
+
+        let project_file = self.work_dir.join(format!("{}.es_proj", master_key(out_name)));
+        project.save(&project_file)?;
+
+        let reg_info = project.register_light_files(&self.progress, &self.cancel_flag, self.config.effective_cpu_load())?;
+        project.update_light_files_reg_info(reg_info);
+        if !project.is_ref_image_assigned()
+        && project.is_possible_assign_ref_light_frame_automatically() {
+            project.assign_ref_light_frame_automatically();
+        }
+        project.save(&project_file)?;
+
+        let result = project.stack_light_files(
+            &self.progress, &self.cancel_flag, self.config.effective_cpu_load(), ResumeMode::Force,
+            &StackMapsOpts::default()
+        )?;
+
+        // result is named as in script
+        let out_file = self.work_dir
+            .join(out_name)
+            .with_extension(extract_extension(&result.file_name));
+        std::fs::rename(&result.file_name, &out_file)?;
+        Ok(out_file)
+    }
+}
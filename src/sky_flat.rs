@@ -0,0 +1,202 @@
+use std::path::*;
+use rayon::prelude::*;
+use crate::{image_raw::*, image_io::*, calc::*, progress::*};
+
+/* Synthetic flat from dithered light frames. Every frame is divided into
+   cells and stars are rejected inside cells (separately for each pixel
+   position of 2x2 CFA block). Then cells are combined between frames with
+   strong clipping and smoothed. Result is 16-bit CFA FITS file with the
+   same black level as light frames so it can be used as flat of project */
+
+const CELL: usize = 16; // even to contain whole CFA pattern
+const TARGET_LEVEL: f32 = 30000.0;
+
+#[derive(Clone, Debug)]
+pub struct SkyFlatOpts {
+    pub star_kappa: f32, // rejection of stars inside cells
+    pub clip_kappa: f32, // rejection between frames
+    pub smooth:     usize, // radius of smoothing in cells
+}
+
+impl Default for SkyFlatOpts {
+    fn default() -> Self {
+        Self {
+            star_kappa: 3.0,
+            clip_kappa: 2.0,
+            smooth: 2,
+        }
+    }
+}
+
+pub struct SkyFlat {
+    pub data:  Vec<u16>,
+    pub info:  ImageInfo,
+    pub black: f32,
+}
+
+// Values of cells for each of 4 positions of pixel in 2x2 CFA block.
+// NaN if value is not defined
+type CellValues = Vec<[f32; 4]>;
+
+fn parity(x: usize, y: usize) -> usize {
+    (y & 1) * 2 + (x & 1)
+}
+
+// Mean of values without outliers. NaN for empty values
+fn clipped_mean(values: &mut [f32], kappa: f32) -> f32 {
+    let Some(median) = median_f32(values) else { return f32::NAN; };
+    let mut deviations: Vec<f32> = values.iter().map(|v| (v - median).abs()).collect();
+    let sigma = median_f32(&mut deviations).unwrap_or(0.0) * 1.4826;
+    let (mut sum, mut cnt) = (0_f32, 0);
+    for v in values.iter() {
+        if sigma > 0.0 && (v - median).abs() > kappa * sigma { continue; }
+        sum += v;
+        cnt += 1;
+    }
+    if cnt == 0 { median } else { sum / cnt as f32 }
+}
+
+fn frame_cells(raw: &RawImage, opts: &SkyFlatOpts) -> anyhow::Result<CellValues> {
+    let (width, height) = (raw.info.width as usize, raw.info.height as usize);
+    let data = raw.data.as_slice();
+
+    // level of each pixel position
+    let step = ((width * height / 400_000) as f64).sqrt().max(1.0) as usize;
+    let mut levels = [0_f32; 4];
+    for (p, level) in levels.iter_mut().enumerate() {
+        let mut values: Vec<f32> = (p / 2..height).step_by(2 * step)
+            .flat_map(|y| (p % 2..width).step_by(2 * step).map(move |x| data[y * width + x]))
+            .filter(|v| v.is_finite())
+            .collect();
+        *level = median_f32(&mut values).unwrap_or(0.0);
+        if *level <= 0.0 {
+            anyhow::bail!("Light frame has no background signal");
+        }
+    }
+
+    let cells_width = width.div_ceil(CELL);
+    let cells_height = height.div_ceil(CELL);
+    let rows: Vec<CellValues> = (0..cells_height).into_par_iter().map(|cy| {
+        let mut values: [Vec<f32>; 4] = Default::default();
+        (0..cells_width).map(|cx| {
+            values.iter_mut().for_each(|v| v.clear());
+            for y in cy * CELL..((cy + 1) * CELL).min(height) {
+                for x in cx * CELL..((cx + 1) * CELL).min(width) {
+                    let v = data[y * width + x];
+                    if !v.is_finite() { continue; }
+                    let p = parity(x, y);
+                    values[p].push(v / levels[p]);
+                }
+            }
+            let mut result = [f32::NAN; 4];
+            for (r, v) in result.iter_mut().zip(values.iter_mut()) {
+                *r = clipped_mean(v, opts.star_kappa);
+            }
+            result
+        }).collect()
+    }).collect();
+    Ok(rows.concat())
+}
+
+fn combine_frames(frames: &[CellValues], kappa: f32) -> CellValues {
+    let count = frames[0].len();
+    (0..count).into_par_iter().map(|i| {
+        let mut result = [f32::NAN; 4];
+        for (p, r) in result.iter_mut().enumerate() {
+            let mut values: Vec<f32> = frames.iter()
+                .map(|f| f[i][p])
+                .filter(|v| v.is_finite())
+                .collect();
+            *r = clipped_mean(&mut values, kappa);
+        }
+        result
+    }).collect()
+}
+
+// Box filter over cells. Not defined cells are filled by neighbours
+fn smooth_cells(cells: &CellValues, width: usize, height: usize, radius: usize) -> CellValues {
+    let mut result = vec![[f32::NAN; 4]; cells.len()];
+    for y in 0..height {
+        for x in 0..width {
+            for p in 0..4 {
+                let (mut sum, mut cnt) = (0_f32, 0);
+                for sy in y.saturating_sub(radius)..(y + radius + 1).min(height) {
+                    for sx in x.saturating_sub(radius)..(x + radius + 1).min(width) {
+                        let v = cells[sy * width + sx][p];
+                        if !v.is_finite() { continue; }
+                        sum += v;
+                        cnt += 1;
+                    }
+                }
+                if cnt != 0 { result[y * width + x][p] = sum / cnt as f32; }
+            }
+        }
+    }
+    // cells without values near them
+    for p in 0..4 {
+        let defined: Vec<f32> = result.iter().map(|c| c[p]).filter(|v| v.is_finite()).collect();
+        let mean = if defined.is_empty() { 1.0 } else { defined.iter().sum::<f32>() / defined.len() as f32 };
+        result.iter_mut().filter(|c| !c[p].is_finite()).for_each(|c| c[p] = mean);
+    }
+    result
+}
+
+pub fn create_sky_flat(
+    files:        &[PathBuf],
+    opts:         &SkyFlatOpts,
+    progress:     &ProgressTs,
+    is_cancelled: &IsCancelledFun,
+) -> anyhow::Result<SkyFlat> {
+    if files.len() < 3 {
+        anyhow::bail!("At least 3 dithered light frames are needed for synthetic flat");
+    }
+    progress.lock().unwrap().stage("Measuring background of light frames...");
+    let mut frames = Vec::new();
+    let mut first: Option<(ImageInfo, f32)> = None;
+    for (index, file_name) in files.iter().enumerate() {
+        if is_cancelled() { anyhow::bail!("Cancelled"); }
+        progress.lock().unwrap().percent(index, files.len(), file_name.to_str().unwrap_or(""));
+        let (mut raw, info) = load_raw_file(file_name, None)?;
+        if let Some((first_info, _)) = &first {
+            if (first_info.width, first_info.height) != (info.width, info.height) {
+                anyhow::bail!("Size of {} differs from first file", file_name.to_str().unwrap_or(""));
+            }
+        } else {
+            first = Some((info, raw.info.black_values[0]));
+        }
+        raw.extract_black();
+        frames.push(frame_cells(&raw, opts)?);
+    }
+    let (info, black) = first.unwrap();
+
+    let (width, height) = (info.width, info.height);
+    let (cells_width, cells_height) = (width.div_ceil(CELL), height.div_ceil(CELL));
+    let cells = combine_frames(&frames, opts.clip_kappa);
+    let cells = smooth_cells(&cells, cells_width, cells_height, opts.smooth);
+
+    // bilinear interpolation between centers of cells
+    let cell_value = |cx: usize, cy: usize, p: usize| cells[cy.min(cells_height - 1) * cells_width + cx.min(cells_width - 1)][p];
+    let mut data = vec![0_u16; width * height];
+    data.par_chunks_mut(width).enumerate().for_each(|(y, row)| {
+        let fy = ((y as f32 + 0.5) / CELL as f32 - 0.5).max(0.0);
+        let (cy, ky) = (fy as usize, fy.fract());
+        for (x, v) in row.iter_mut().enumerate() {
+            let fx = ((x as f32 + 0.5) / CELL as f32 - 0.5).max(0.0);
+            let (cx, kx) = (fx as usize, fx.fract());
+            let p = parity(x, y);
+            let top = cell_value(cx, cy, p) * (1.0 - kx) + cell_value(cx + 1, cy, p) * kx;
+            let bottom = cell_value(cx, cy + 1, p) * (1.0 - kx) + cell_value(cx + 1, cy + 1, p) * kx;
+            let flat = top * (1.0 - ky) + bottom * ky;
+            *v = (flat * TARGET_LEVEL + black).round().clamp(0.0, u16::MAX as f32) as u16;
+        }
+    });
+    let info = ImageInfo {
+        file_name: PathBuf::new(),
+        width, height,
+        cfa_type: info.cfa_type,
+        camera: info.camera,
+        lens: info.lens,
+        ..ImageInfo::default()
+    };
+    Ok(SkyFlat { data, info, black })
+}
@@ -0,0 +1,132 @@
+use std::path::*;
+use crate::{image_raw::*, image_io::*, calc::*};
+
+/* Calculator of sky limited exposure. Read noise is measured by bias frame
+   and sky signal by light frame. Sub is sky limited if shot noise of sky
+   swamps read noise: sky electrons >= swamp * read_noise^2. With swamp = 10
+   read noise adds only ~5% to total noise of sub */
+
+#[derive(Clone, Debug)]
+pub struct SkyLimitOpts {
+    pub gain:     Option<f64>, // electrons per ADU of values in files
+    pub exposure: Option<f64>, // seconds, from light file if not defined
+    pub swamp:    f64,
+}
+
+impl Default for SkyLimitOpts {
+    fn default() -> Self {
+        Self {
+            gain: None,
+            exposure: None,
+            swamp: 10.0,
+        }
+    }
+}
+
+pub struct SkyLimitResult {
+    pub read_noise_adu: f64,
+    pub read_noise_e:   f64,
+    pub sky_adu:        f64, // sky level above bias
+    pub sky_e:          f64,
+    pub exposure:       f64,
+    pub min_exposure:   f64, // minimum sky limited exposure
+    pub noise_increase: f64, // relative increase of noise by read noise
+    pub is_sky_limited: bool,
+    pub reasoning:      Vec<String>,
+}
+
+// Median and noise of pixels of same CFA color (step 2 in both directions).
+// Noise is estimated by differences of neighbours so gradients and
+// fixed pattern of large scale are not counted
+fn level_and_noise(raw: &RawImage) -> anyhow::Result<(f64, f64)> {
+    let width = raw.info.width as usize;
+    let height = raw.info.height as usize;
+    let data = raw.data.as_slice();
+    let (mut values, mut diffs) = (Vec::new(), Vec::new());
+    for y in (0..height).step_by(2) {
+        let row = &data[y * width..(y + 1) * width];
+        for x in (0..width.saturating_sub(2)).step_by(2) {
+            let (v1, v2) = (row[x], row[x + 2]);
+            if !v1.is_finite() || !v2.is_finite() { continue; }
+            values.push(v1);
+            diffs.push(v1 - v2);
+        }
+    }
+    let level = median_f32(&mut values)
+        .ok_or_else(|| anyhow::anyhow!("Image has no valid pixels"))?;
+    let diff_median = median_f32(&mut diffs).unwrap_or(0.0);
+    let mut deviations: Vec<f32> = diffs.iter().map(|d| (d - diff_median).abs()).collect();
+    let mad = median_f32(&mut deviations).unwrap_or(0.0);
+    // MAD -> sigma and difference of two pixels -> one pixel
+    let noise = mad as f64 * 1.4826 / std::f64::consts::SQRT_2;
+    Ok((level as f64, noise))
+}
+
+pub fn calc_sky_limit(
+    light_file: &Path,
+    bias_file:  &Path,
+    opts:       &SkyLimitOpts,
+) -> anyhow::Result<SkyLimitResult> {
+    let gain = opts.gain.ok_or_else(|| anyhow::anyhow!("Gain is not defined (--gain)"))?;
+    if gain <= 0.0 {
+        anyhow::bail!("Gain must be positive");
+    }
+    let (light, light_info) = load_raw_file(light_file, None)?;
+    let (bias, _) = load_raw_file(bias_file, None)?;
+    if (light.info.width, light.info.height) != (bias.info.width, bias.info.height) {
+        anyhow::bail!("Sizes of light and bias files are different");
+    }
+    let exposure = opts.exposure
+        .or(light_info.exp)
+        .filter(|exp| *exp > 0.0)
+        .ok_or_else(|| anyhow::anyhow!("Exposure of light file is unknown (--exposure)"))?;
+
+    let (bias_level, read_noise_adu) = level_and_noise(&bias)?;
+    let (light_level, _) = level_and_noise(&light)?;
+    let sky_adu = light_level - bias_level;
+    if sky_adu <= 0.0 {
+        anyhow::bail!("Light file has no sky signal above bias level");
+    }
+    let read_noise_e = read_noise_adu * gain;
+    let sky_e = sky_adu * gain;
+    let sky_rate = sky_e / exposure;
+    let min_exposure = opts.swamp * read_noise_e * read_noise_e / sky_rate;
+    let noise_increase = (1.0 + read_noise_e * read_noise_e / sky_e).sqrt() - 1.0;
+    let is_sky_limited = exposure >= min_exposure;
+
+    let mut reasoning = vec![
+        format!(
+            "Read noise from bias: {:.2} ADU * {:.3} e-/ADU = {:.2} e-",
+            read_noise_adu, gain, read_noise_e
+        ),
+        format!(
+            "Sky level above bias: {:.1} ADU * {:.3} e-/ADU = {:.1} e- in {:.1} s ({:.2} e-/s)",
+            sky_adu, gain, sky_e, exposure, sky_rate
+        ),
+        format!(
+            "Sky is {:.1} times of read noise squared (needed {:.1})",
+            sky_e / (read_noise_e * read_noise_e), opts.swamp
+        ),
+        format!(
+            "Read noise increases total noise of sub by {:.1}%",
+            100.0 * noise_increase
+        ),
+        format!(
+            "Minimum sky limited exposure: {:.1} * {:.2}^2 / {:.2} = {:.1} s",
+            opts.swamp, read_noise_e, sky_rate, min_exposure
+        ),
+    ];
+    reasoning.push(if is_sky_limited {
+        "Subs are sky limited: longer subs will not improve result for the same total time".to_string()
+    } else {
+        format!("Subs are read noise limited: use subs of {:.0} s or longer", min_exposure.ceil())
+    });
+
+    Ok(SkyLimitResult {
+        read_noise_adu, read_noise_e,
+        sky_adu, sky_e,
+        exposure, min_exposure,
+        noise_increase, is_sky_limited,
+        reasoning,
+    })
+}
@@ -1,6 +1,7 @@
 use std::{path::*, io::*, fs::*};
 use std::sync::*;
 use anyhow::bail;
+use serde::{Serialize, Deserialize};
 use bitstream_io::{BigEndian, BitReader};
 use crate::{
     compression::*,
@@ -14,9 +15,19 @@ use crate::{
     image_norm::*,
     light_file::*,
     log_utils::*,
+    field_rotation::*,
+    noise::*,
+    airmass::*,
+    gpu::*,
+    plugins::*,
+    integration_meta::*,
+    wcs::*,
+    resample::{warp_layer_flux_conserving, ResampleKernel, interpolation_variance_k},
 };
 
 use std::f64::consts::PI;
+use std::collections::BTreeMap;
+use chrono::prelude::*;
 
 ///////////////////////////////////////////////////////////////////////////////
 
@@ -25,6 +36,7 @@ use std::f64::consts::PI;
 pub fn create_master_dark_or_bias_file(
     files_list:  &[PathBuf],
     calc_opts:   &CalcOpts,
+    hdu:         Option<&FitsHduSelector>,
     result_file: &Path,
     progress:    &ProgressTs,
     thread_pool: &rayon::ThreadPool,
@@ -33,20 +45,31 @@ pub fn create_master_dark_or_bias_file(
     create_master_calibr_file(
         files_list,
         calc_opts,
+        hdu,
         result_file,
         |_| true,
         progress,
         thread_pool,
         cancel_flag,
-        false
+        false,
+        FlatNorm::default()
     )
 }
 
+fn flat_mean(data: &[f32]) -> f32 {
+    let (sum, cnt) = data.iter()
+        .filter(|v| v.is_finite())
+        .fold((0_f64, 0_usize), |(sum, cnt), v| (sum + *v as f64, cnt + 1));
+    if cnt == 0 { return 0.0; }
+    (sum / cnt as f64) as f32
+}
+
 fn postprocess_single_flat_image_color(
     raw_image:    &mut RawImage,
     cc:           CfaColor,
     white_level:  f32,
-    check_values: bool
+    check_values: bool,
+    flat_norm:    FlatNorm,
 ) -> bool {
     let center_x = raw_image.info.width / 2;
     let center_y = raw_image.info.height / 2;
@@ -77,16 +100,25 @@ fn postprocess_single_flat_image_color(
         return false;
     }
 
-    data.clear();
-    for (x, y, v) in raw_image.data.iter_crd() {
-        if raw_image.info.cfa.get_pixel_color(x, y) != cc { continue; }
-        data.push(v);
-    }
-
-    if data.is_empty() { return true; }
-
-    let high_index = 99 * data.len() / 100;
-    let norm_value = *data.select_nth_unstable_by(high_index, cmp_f32).1;
+    let norm_value = match flat_norm {
+        FlatNorm::GlobalMean => return true, // all channels are normalized together
+        FlatNorm::CenterMean => flat_mean(&data),
+        FlatNorm::Percentile|FlatNorm::ChannelMean => {
+            data.clear();
+            for (x, y, v) in raw_image.data.iter_crd() {
+                if raw_image.info.cfa.get_pixel_color(x, y) != cc { continue; }
+                data.push(v);
+            }
+            if data.is_empty() { return true; }
+            if flat_norm == FlatNorm::ChannelMean {
+                flat_mean(&data)
+            } else {
+                let high_index = 99 * data.len() / 100;
+                *data.select_nth_unstable_by(high_index, cmp_f32).1
+            }
+        }
+    };
+    if norm_value <= 0.0 { return true; }
 
     for (x, y, v) in raw_image.data.iter_crd_mut() {
         if raw_image.info.cfa.get_pixel_color(x, y) != cc { continue; }
@@ -96,10 +128,11 @@ fn postprocess_single_flat_image_color(
     true
 }
 
-fn postprocess_single_flat_image(
+pub fn postprocess_single_flat_image(
     raw_image:    &mut RawImage,
     bias_image:   Option<&RawImage>,
     check_values: bool,
+    flat_norm:    FlatNorm,
 ) -> bool {
     if let Some(bias_image) = bias_image {
         raw_image.data -= &bias_image.data;
@@ -110,6 +143,7 @@ fn postprocess_single_flat_image(
         CfaColor::Mono,
         raw_image.info.max_values[0],
         check_values,
+        flat_norm,
     );
 
     let r_ok = postprocess_single_flat_image_color(
@@ -117,6 +151,7 @@ fn postprocess_single_flat_image(
         CfaColor::R,
         raw_image.info.max_values[0],
         check_values,
+        flat_norm,
     );
 
     let g_ok = postprocess_single_flat_image_color(
@@ -124,6 +159,7 @@ fn postprocess_single_flat_image(
         CfaColor::G,
         raw_image.info.max_values[1],
         check_values,
+        flat_norm,
     );
 
     let b_ok = postprocess_single_flat_image_color(
@@ -131,8 +167,16 @@ fn postprocess_single_flat_image(
         CfaColor::B,
         raw_image.info.max_values[2],
         check_values,
+        flat_norm,
     );
 
+    if flat_norm == FlatNorm::GlobalMean {
+        let mean = flat_mean(raw_image.data.as_slice());
+        if mean > 0.0 {
+            raw_image.data.iter_mut().for_each(|v| *v /= mean);
+        }
+    }
+
     raw_image.info.max_values.fill(1.0);
     raw_image.info.black_values.fill(0.0);
 
@@ -142,12 +186,14 @@ fn postprocess_single_flat_image(
 pub fn create_master_flat_file(
     files_list:       &[PathBuf],
     calc_opts:        &CalcOpts,
+    hdu:              Option<&FitsHduSelector>,
     master_bias_file: &Option<PathBuf>,
     result_file:      &Path,
     progress:         &ProgressTs,
     thread_pool:      &rayon::ThreadPool,
     cancel_flag:      &IsCancelledFun,
-    force_if_exist: bool,
+    force_if_exist:   bool,
+    flat_norm:        FlatNorm,
 ) -> anyhow::Result<bool> {
     let bias_image = match master_bias_file {
         Some(master_bias_file) =>
@@ -161,30 +207,35 @@ pub fn create_master_flat_file(
     create_master_calibr_file(
         files_list,
         calc_opts,
+        hdu,
         result_file,
         move |img| {
             postprocess_single_flat_image(
                 img,
                 bias_image.as_ref(),
-                check_file_data
+                check_file_data,
+                flat_norm
             )
         },
         progress,
         thread_pool,
         cancel_flag,
-        force_if_exist
+        force_if_exist,
+        flat_norm
     )
 }
 
 fn create_master_calibr_file<PF>(
     files_list:      &[PathBuf],
     calc_opts:       &CalcOpts,
+    hdu:             Option<&FitsHduSelector>,
     result_file:     &Path,
     postprocess_fun: PF,
     progress:        &ProgressTs,
     thread_pool:     &rayon::ThreadPool,
     cancel_flag:     &IsCancelledFun,
-    force_if_exist:  bool) -> anyhow::Result<bool>
+    force_if_exist:  bool,
+    flat_norm:       FlatNorm) -> anyhow::Result<bool>
 where
     PF: Fn (&mut RawImage) -> bool + Send + Sync + 'static
 {
@@ -193,6 +244,7 @@ where
     let this_info = MasterFileInfo {
         files: files_list.to_vec(),
         calc_opts: calc_opts.clone(),
+        flat_norm,
     };
 
     if !force_if_exist && result_file.exists() {
@@ -214,7 +266,7 @@ where
                 || cur_result.lock().unwrap().is_err() {
                     return;
                 }
-                let (mut raw, _) = match load_raw_file(file_path) {
+                let (mut raw, _) = match load_raw_file(file_path, hdu) {
                     Ok(raw) => raw,
                     Err(err) => {
                         *cur_result.lock().unwrap() = Err(anyhow::anyhow!(
@@ -230,7 +282,9 @@ where
                 if !is_ok { return; }
                 let temp_fn = file_path.with_extension("temp_raw");
                 let locker = disk_access_mutex.lock();
-                let save_res = save_calibr_format_file(&raw, &temp_fn);
+                let save_res = write_file_atomically(&temp_fn, |file_name| {
+                    save_calibr_format_file(&raw, file_name)
+                });
                 drop(locker);
                 if let Err(save_res) = save_res {
                     *cur_result.lock().unwrap() = Err(save_res);
@@ -250,6 +304,10 @@ where
         return cur_result.into_inner()?;
     }
 
+    // files are stacked in order of list, not in order of end of
+    // processing by threads, so result is the same for every run
+    files_to_process.lock().unwrap().sort();
+
     struct FileData {
         reader: BitReader<BufReader<File>, BigEndian>,
         decompress: ValuesDecompressor,
@@ -309,11 +367,9 @@ where
 
     progress.lock().unwrap().percent(100, 100, "Done");
 
-    save_master_format_file(
-        &image,
-        result_file,
-        &this_info
-    )?;
+    write_file_atomically(result_file, |file_name| {
+        save_master_format_file(&image, file_name, &this_info)
+    })?;
 
     Ok(true)
 }
@@ -322,21 +378,486 @@ where
 
 /* Stacking light files */
 
+#[derive(Serialize, Deserialize, Clone)]
 pub struct TempFileData {
     orig_file:    PathBuf,
     file_name:    PathBuf,
     range_factor: f32,
     noise:        f32,
+    #[serde(default)]
+    noise_est:    f32, // k-sigma noise, 0 for temp files of previous versions
     info:         ImageInfo,
     img_offset:   ImageOffset,
     group_idx:    usize,
+    #[serde(default)]
+    airmass:      Option<f32>,
+    #[serde(default)]
+    interpolation: ResampleKernel, // used for alignment
+}
+
+/// Splits temporary light files into time windows of `minutes` length
+/// starting from earliest file. Result contains start time of every
+/// non-empty window. Files without time of shot are not included
+pub fn split_temp_files_by_time(
+    files:   &[TempFileData],
+    minutes: u32,
+) -> Vec<(DateTime<Local>, Vec<TempFileData>)> {
+    let window_secs = 60 * minutes.max(1) as i64;
+    let Some(first_time) = files.iter().filter_map(|f| f.info.file_time).min() else {
+        return Vec::new();
+    };
+    let mut windows = BTreeMap::<i64, Vec<TempFileData>>::new();
+    for file in files {
+        let Some(time) = file.info.file_time else {
+            log::warn!(
+                "Light file {} is not included into sub-stacks: time of shot is unknown",
+                path_to_str(&file.orig_file)
+            );
+            continue;
+        };
+        let index = (time - first_time).num_seconds() / window_secs;
+        windows.entry(index).or_default().push(file.clone());
+    }
+    windows.into_iter()
+        .map(|(index, files)| (first_time + chrono::Duration::seconds(index * window_secs), files))
+        .collect()
+}
+
+/// Files for maps of stacking (FITS, one channel for mono images
+/// or three channels for RGB ones)
+#[derive(Default, Clone, Debug)]
+pub struct StackMapsOpts {
+    pub rejection_low:  Option<PathBuf>, // count of values rejected as too low
+    pub rejection_high: Option<PathBuf>, // count of values rejected as too high
+    pub weight:         Option<PathBuf>, // total weight of used values
+    pub variance:       Option<VarianceModel>, // variance plane of result
+}
+
+impl StackMapsOpts {
+    /// Files of maps to be saved
+    pub fn files(&self) -> impl Iterator<Item = &PathBuf> {
+        [&self.rejection_low, &self.rejection_high, &self.weight]
+            .into_iter()
+            .flatten()
+    }
+
+    fn is_any(&self) -> bool {
+        self.rejection_low.is_some()
+        || self.rejection_high.is_some()
+        || self.weight.is_some()
+        || self.variance.is_some()
+    }
+}
+
+/// Noise model of light files for propagation of per-pixel variance into
+/// stacked image. Values of light files are supposed to be in ADU
+#[derive(Clone, Copy, Debug)]
+pub struct VarianceModel {
+    pub gain:       f32, // e-/ADU
+    pub read_noise: f32, // e-
+}
+
+impl VarianceModel {
+    /// "<gain>,<read noise>"
+    pub fn from_str(text: &str) -> anyhow::Result<Self> {
+        let err = || anyhow::anyhow!("Wrong noise model {} (<gain e-/ADU>,<read noise e->)", text);
+        let (gain, read_noise) = text.split_once(',').ok_or_else(err)?;
+        let gain: f32 = gain.trim().parse().map_err(|_| err())?;
+        let read_noise: f32 = read_noise.trim().parse().map_err(|_| err())?;
+        if gain <= 0.0 || read_noise < 0.0 {
+            return Err(err());
+        }
+        Ok(Self { gain, read_noise })
+    }
+
+    /// Variance of value of temporary light file. Calibrated value in ADU
+    /// (shot noise of signal and sky + read noise) is multiplied by `range`
+    /// during normalization and `resample_k` is reduction of variance by
+    /// interpolation during alignment
+    fn frame_variance(&self, value: f64, range: f64, resample_k: f64) -> f64 {
+        let gain = self.gain as f64;
+        let read_var = (self.read_noise as f64 / gain).powi(2);
+        let shot_var = (value / range).max(0.0) / gain;
+        resample_k * range * range * (read_var + shot_var)
+    }
+}
+
+// Mean reduction of variance by interpolation. For shift only
+// it is defined by fractional parts of offsets, for rotated image
+// it is mean for all fractional parts (4/9 for bilinear interpolation)
+fn interpolation_variance_k_for(offset: &ImageOffset, kernel: ResampleKernel) -> f64 {
+    if offset.angle.abs() < 1e-4 {
+        interpolation_variance_k(kernel, Some(offset.offset_x), Some(offset.offset_y))
+    } else {
+        interpolation_variance_k(kernel, None, None)
+    }
+}
+
+#[derive(Default, Clone, Copy)]
+struct PixelStackStat {
+    rejected_low:  f32,
+    rejected_high: f32,
+    weight:        f32,
+    variance:      f32,
+}
+
+struct StackMaps {
+    rejection_low:  Image,
+    rejection_high: Image,
+    weight:         Image,
+    variance:       Image,
+}
+
+impl StackMaps {
+    fn new(is_rgb: bool, width: Crd, height: Crd) -> Self {
+        let create = || if is_rgb {
+            Image::new_color(width, height)
+        } else {
+            Image::new_grey(width, height)
+        };
+        Self {
+            rejection_low: create(),
+            rejection_high: create(),
+            weight: create(),
+            variance: create(),
+        }
+    }
+
+    fn set(&mut self, x: Crd, y: Crd, stats: &[PixelStackStat]) {
+        for (image, get) in [
+            (&mut self.rejection_low, (|s: &PixelStackStat| s.rejected_low) as fn(&PixelStackStat) -> f32),
+            (&mut self.rejection_high, |s| s.rejected_high),
+            (&mut self.weight, |s| s.weight),
+            (&mut self.variance, |s| s.variance),
+        ] {
+            if stats.len() == 1 {
+                image.l.set(x, y, get(&stats[0]));
+            } else {
+                image.r.set(x, y, get(&stats[0]));
+                image.g.set(x, y, get(&stats[1]));
+                image.b.set(x, y, get(&stats[2]));
+            }
+        }
+    }
+
+    fn save(&self, opts: &StackMapsOpts, compression: FitsCompression) -> anyhow::Result<()> {
+        let fits_opts = FitsSaveOpts {
+            bitpix: FitsBitPix::Float32,
+            compression,
+            compat: FitsCompat::Default,
+            quantization: Quantization::Nearest,
+            range: OutputRange::Normalized,
+            pedestal: 0.0,
+        };
+        for (image, file_name) in [
+            (&self.rejection_low, &opts.rejection_low),
+            (&self.rejection_high, &opts.rejection_high),
+            (&self.weight, &opts.weight),
+        ] {
+            let Some(file_name) = file_name else { continue; };
+            log::info!("Saving stacking map into file {}", file_name.to_str().unwrap_or(""));
+            write_file_atomically(file_name, |tmp_file_name| {
+                save_image_to_fits_file(image, &ImageInfo::default(), tmp_file_name, fits_opts)
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// File for variance plane of TIFF or XISF result
+pub fn get_variance_file_name(result_file: &Path) -> PathBuf {
+    let stem = result_file.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+    result_file.with_file_name(format!("{}.variance.fit", stem))
+}
+
+/// Noise of stacked light files and of result image
+#[derive(Default, Clone, Debug)]
+pub struct StackNoise {
+    pub frames_count: usize,
+    pub frame_noise:  f32, // median of k-sigma noise of light files
+    pub result_noise: f32, // MRS noise of result
+}
+
+/// Light file in stack with its weight and transform to reference image
+#[derive(Serialize, Clone, Debug)]
+pub struct StackedFrame {
+    pub file:         PathBuf,
+    pub offset_x:     f64,
+    pub offset_y:     f64,
+    pub angle:        f64, // degrees
+    pub weight:       f64,
+    pub range_factor: f32,
+    pub noise:        f32,
+    pub airmass:      Option<f32>,
+}
+
+/// Part of values rejected by kappa-sigma clipping (in percents)
+#[derive(Serialize, Default, Clone, Debug)]
+pub struct RejectionStat {
+    pub low_percent:  f64,
+    pub high_percent: f64,
+}
+
+#[derive(Default)]
+struct RejectionCounter {
+    values: u64,
+    low:    u64,
+    high:   u64,
+}
+
+impl RejectionCounter {
+    fn add(&mut self, values_count: usize, stat: &PixelStackStat) {
+        self.values += values_count as u64;
+        self.low += stat.rejected_low as u64;
+        self.high += stat.rejected_high as u64;
+    }
+
+    fn get(&self) -> RejectionStat {
+        if self.values == 0 { return RejectionStat::default(); }
+        RejectionStat {
+            low_percent:  100.0 * self.low as f64 / self.values as f64,
+            high_percent: 100.0 * self.high as f64 / self.values as f64,
+        }
+    }
+}
+
+/// Result of merging of temporary light files
+#[derive(Default, Clone, Debug)]
+pub struct StackStat {
+    pub noise:     StackNoise,
+    pub frames:    Vec<StackedFrame>,
+    pub rejection: RejectionStat,
+}
+
+impl StackNoise {
+    /// Achieved improvement of SNR and ideal one (square root of frames count)
+    pub fn snr_improvement(&self) -> Option<(f32, f32)> {
+        if self.frame_noise <= 0.0 || self.result_noise <= 0.0 {
+            return None;
+        }
+        Some((self.frame_noise / self.result_noise, (self.frames_count as f32).sqrt()))
+    }
+}
+
+/// Running weighted mean of temporary light files for live stacking.
+/// Every temporary file is added only once so new frames are integrated
+/// without reading of previous ones
+pub struct LiveStack {
+    sum:       Image,
+    weights:   Image,
+    added:     std::collections::HashSet<PathBuf>,
+    ref_noise: f32,
+    meta:      IntegrationMeta,
+}
+
+impl LiveStack {
+    pub fn new() -> Self {
+        Self {
+            sum:       Image::new(),
+            weights:   Image::new(),
+            added:     std::collections::HashSet::new(),
+            ref_noise: 0.0,
+            meta:      IntegrationMeta::default(),
+        }
+    }
+
+    pub fn frames_count(&self) -> usize {
+        self.added.len()
+    }
+
+    /// Adds temporary files which are not in stack yet. Returns count of added files
+    pub fn add_temp_files(
+        &mut self,
+        temp_files:  &[TempFileData],
+        is_rgb:      bool,
+        width:       Crd,
+        height:      Crd,
+        cancel_flag: &IsCancelledFun,
+    ) -> anyhow::Result<usize> {
+        if self.sum.is_empty() {
+            for image in [&mut self.sum, &mut self.weights] {
+                if is_rgb {
+                    image.make_color(width, height);
+                } else {
+                    image.make_grey(width, height);
+                }
+            }
+        } else if self.sum.width() != width || self.sum.height() != height || self.sum.is_rgb() != is_rgb {
+            bail!("Size or type of reference image is changed");
+        }
+
+        let mut count = 0;
+        for temp_file in temp_files {
+            if cancel_flag() { break; }
+            if self.added.contains(&temp_file.orig_file) { continue; }
+            let noise = if temp_file.noise_est > 0.0 { temp_file.noise_est } else { temp_file.noise };
+            if self.ref_noise == 0.0 {
+                self.ref_noise = noise;
+            }
+            let weight = if noise > 0.0 { (self.ref_noise / noise).powi(2) } else { 1.0 };
+            let mut reader = InternalFormatReader::new(&temp_file.file_name)?;
+            let add = |sum: &mut f32, weights: &mut f32, value: f32| {
+                if value.is_finite() && value != NO_VALUE_F32 {
+                    *sum += weight * value;
+                    *weights += weight;
+                }
+            };
+            if is_rgb {
+                let (sum, weights) = (&mut self.sum, &mut self.weights);
+                for (sr, sg, sb, wr, wg, wb) in itertools::izip!(
+                    sum.r.iter_mut(), sum.g.iter_mut(), sum.b.iter_mut(),
+                    weights.r.iter_mut(), weights.g.iter_mut(), weights.b.iter_mut()
+                ) {
+                    let (r, g, b) = reader.get_rgb()?;
+                    add(sr, wr, r);
+                    add(sg, wg, g);
+                    add(sb, wb, b);
+                }
+            } else {
+                for (sl, wl) in self.sum.l.iter_mut().zip(self.weights.l.iter_mut()) {
+                    add(sl, wl, reader.get_l()?);
+                }
+            }
+            log::info!(
+                "Light file {} is added into live stack with weight {:.3}",
+                extract_file_name(&temp_file.orig_file), weight
+            );
+            self.meta.add_frame(&temp_file.info, &temp_file.orig_file);
+            self.added.insert(temp_file.orig_file.clone());
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Current result of stacking normalized to 0..1 range
+    pub fn result(&self) -> Image {
+        let mut result = Image {
+            r: self.sum.r.clone(),
+            g: self.sum.g.clone(),
+            b: self.sum.b.clone(),
+            l: self.sum.l.clone(),
+        };
+        let layers = if result.is_rgb() {
+            vec![(&mut result.r, &self.weights.r), (&mut result.g, &self.weights.g), (&mut result.b, &self.weights.b)]
+        } else {
+            vec![(&mut result.l, &self.weights.l)]
+        };
+        for (layer, weights) in layers {
+            for (v, w) in layer.iter_mut().zip(weights.iter()) {
+                *v = if *w > 0.0 { *v / *w } else { NO_VALUE_F32 };
+            }
+        }
+        result.normalize_to_1(false);
+        result
+    }
+
+    pub fn save(&self, file_name: &Path, fits_opts: FitsSaveOpts) -> anyhow::Result<Image> {
+        let result = self.result();
+        let mut info = ImageInfo::default();
+        self.meta.apply_to_info(&mut info);
+        log::info!("Saving live stack into file {}", file_name.to_str().unwrap_or(""));
+        write_file_atomically(file_name, |tmp_file_name| {
+            save_image_to_file(&result, &info, tmp_file_name, fits_opts)
+        })?;
+        Ok(result)
+    }
+}
+
+pub struct LightsAlignOpts {
+    pub translation_only: bool, // fast mode for short untracked exposures
+    pub by_wcs:           bool, // frames are reprojected by their WCS instead of stars matching
+    pub skip_bad_lights:  bool, // skip light files that can't be aligned
+    pub min_stars:        usize,
+    pub field_rotation:   Option<FieldRotationParams>,
+    pub interpolation:    ResampleKernel, // for rotation and translation
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ResumeMode {
+    Off,    // temporary files are deleted after stacking
+    Resume, // temporary files of previous run are used if source file and options are same
+    Force,  // temporary files of previous run are ignored and overwritten
+}
+
+/// Result of processing of light file. Skipped and failed files are
+/// not processed again by resumed run
+#[derive(Serialize, Deserialize)]
+enum TempFileResult {
+    Done(TempFileData),
+    Skipped(String),
+    Failed(String),
+}
+
+#[derive(Serialize, Deserialize)]
+struct TempFileState {
+    key:    String,
+    result: TempFileResult,
+}
+
+pub fn fnv1a_hash(data: &[u8]) -> u64 {
+    let mut hash = 0xcbf29ce484222325_u64;
+    for b in data {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+pub fn get_file_state_str(file_name: &Path) -> String {
+    let Ok(metadata) = std::fs::metadata(file_name) else {
+        return format!("{}:none", file_name.to_str().unwrap_or(""));
+    };
+    let modified = metadata.modified()
+        .ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|d| d.as_millis())
+        .unwrap_or_default();
+    format!("{}:{}:{}", file_name.to_str().unwrap_or(""), metadata.len(), modified)
+}
+
+/// As `get_file_state_str` but by content of file
+/// instead of size and modification time
+pub fn get_file_content_state_str(file_name: &Path) -> String {
+    let hash = calc_file_sha256(file_name).unwrap_or_else(|_| "none".to_string());
+    format!("{}:{}", file_name.to_str().unwrap_or(""), hash)
+}
+
+fn calc_temp_file_key(file_name: &Path, options_key: &str) -> String {
+    let key_src = format!("{}|{}", get_file_content_state_str(file_name), options_key);
+    format!("{:016x}", fnv1a_hash(key_src.as_bytes()))
+}
+
+fn load_temp_file_state(state_file_name: &Path, key: &str) -> Option<TempFileResult> {
+    let state_str = std::fs::read_to_string(state_file_name).ok()?;
+    let state: TempFileState = serde_json::from_str(&state_str).ok()?;
+    if state.key != key {
+        return None;
+    }
+    if let TempFileResult::Done(data) = &state.result {
+        if !data.file_name.is_file() { return None; }
+    }
+    Some(state.result)
+}
+
+fn save_temp_file_state(state_file_name: &Path, state: &TempFileState) -> anyhow::Result<()> {
+    let state_str = serde_json::to_string(state)?;
+    write_file_atomically(state_file_name, |file_name| {
+        Ok(std::fs::write(file_name, &state_str)?)
+    })
+}
+
+pub fn delete_temp_light_files(temp_files: &[TempFileData]) {
+    for temp_file in temp_files {
+        let _ = std::fs::remove_file(&temp_file.file_name);
+        let _ = std::fs::remove_file(temp_file.orig_file.with_extension("temp_light_state"));
+    }
 }
 
 #[derive(Copy, Clone)]
 pub enum SaveAlignedImageMode {
     No,
     Tif,
-    Fits
+    Fits(FitsSaveOpts),
 }
 
 struct SaveTempFileData {
@@ -345,12 +866,15 @@ struct SaveTempFileData {
     info:         ImageInfo,
     save_aligned: SaveAlignedImageMode,
     orig_fn:      PathBuf,
+    state:        Option<(PathBuf, TempFileState)>,
 }
 
 fn save_temp_file(mut args: SaveTempFileData) -> anyhow::Result<()> {
     let save_log = TimeLogger::start();
 
-    let compr_coeff = save_image_into_internal_format(&args.image, &args.file_name)?;
+    let compr_coeff = write_file_atomically(&args.file_name, |file_name| {
+        save_image_into_internal_format(&args.image, file_name)
+    })?;
 
     log::info!(
         "compression coeff. for {} is {:.2}",
@@ -358,22 +882,24 @@ fn save_temp_file(mut args: SaveTempFileData) -> anyhow::Result<()> {
         compr_coeff
     );
 
-    if let SaveAlignedImageMode::Fits|SaveAlignedImageMode::Tif = args.save_aligned {
-        let ext = match args.save_aligned {
-            SaveAlignedImageMode::Fits => FIT_EXTS[0],
-            SaveAlignedImageMode::Tif => TIF_EXTS[0],
-            _ => "",
+    // state is saved after temp file to be sure temp file is complete
+    if let Some((state_file_name, state)) = &args.state {
+        save_temp_file_state(state_file_name, state)?;
+    }
+
+    if let SaveAlignedImageMode::Fits(_)|SaveAlignedImageMode::Tif = args.save_aligned {
+        let (ext, fits_opts) = match args.save_aligned {
+            SaveAlignedImageMode::Fits(opts) => (FIT_EXTS[0], opts),
+            _ => (TIF_EXTS[0], FitsSaveOpts::default()),
         };
         let file_name = args.file_name.with_extension(format!("aligned.{}", ext));
         log::info!("Saving aligned image to {:?} file", file_name);
 
         args.image.set_novalue_as_zero();
         args.image.fill_inf_areas();
-        save_image_to_file(
-            &args.image,
-            &args.info,
-            &file_name
-        )?;
+        write_file_atomically(&file_name, |file_name| {
+            save_image_to_file(&args.image, &args.info, file_name, fits_opts)
+        })?;
     }
 
     save_log.log("saving temp file");
@@ -387,9 +913,12 @@ pub fn create_temp_light_files(
     master_flat:        Option<&Path>,
     master_dark:        Option<&Path>,
     master_bias:        Option<&Path>,
+    flat_drift:         Option<&FlatDriftParams>,
+    hot_pixels_by_lights: bool,
     ref_data:           &RefBgData,
     bin:                usize,
     raw_params:         &RawOpenParams,
+    stars_opts:         &StarsFindOpts,
     result_list:        &Mutex<Vec<TempFileData>>,
     files_to_del_later: &Mutex<FilesToDeleteLater>,
     thread_pool:        &rayon::ThreadPool,
@@ -397,13 +926,25 @@ pub fn create_temp_light_files(
     group_idx:          usize,
     save_aligned:       SaveAlignedImageMode,
     align_rgb_each:     bool,
+    align_opts:         &LightsAlignOpts,
+    airmass_opts:       &AirmassOpts,
+    plugins:            &[PluginOpts],
+    resume:             ResumeMode,
+    options_key:        &str,
 ) -> anyhow::Result<()> {
     progress.lock().unwrap().percent(0, 100, "Loading calibration images...");
-    let cal_data = CalibrationData::load(
+    let mut cal_data = CalibrationData::load(
         master_flat,
         master_dark,
         master_bias
     )?;
+    if let Some(flat_drift) = flat_drift {
+        cal_data.load_flat_drift(flat_drift)?;
+    }
+    if hot_pixels_by_lights && cal_data.dark_image.is_none() {
+        progress.lock().unwrap().percent(0, 100, "Detecting hot pixels by light files...");
+        cal_data.find_hot_pixels_in_light_files(&files_list, raw_params.fits_hdu_selector().as_ref(), thread_pool);
+    }
 
     let (save_tx, save_rx) = mpsc::sync_channel::<SaveTempFileData>(5);
     let cur_result = Arc::new(Mutex::new(anyhow::Result::<()>::Ok(())));
@@ -429,7 +970,7 @@ pub fn create_temp_light_files(
                 }
                 progress.lock().unwrap().progress(true, extract_file_name(&org_fn));
             }
-            log::info!("Exiting from save thread...");
+            log::debug!("Exiting from save thread...");
         })
     };
 
@@ -449,14 +990,32 @@ pub fn create_temp_light_files(
                     ref_data,
                     bin,
                     raw_params,
+                    stars_opts,
                     files_to_del_later,
                     result_list,
                     save_tx,
                     save_aligned,
-                    align_rgb_each
+                    align_rgb_each,
+                    align_opts,
+                    airmass_opts,
+                    plugins,
+                    resume,
+                    options_key,
                 );
+                if let Ok(false) = res {
+                    progress.lock().unwrap().progress(true, extract_file_name(file));
+                }
                 if let Err(err) = res {
-                    *cur_result.lock().unwrap() = Err(anyhow::anyhow!(
+                    let mut cur_result = cur_result.lock().unwrap();
+                    // error of other file can stop saving thread
+                    if resume != ResumeMode::Off && cur_result.is_ok() {
+                        let state = TempFileState {
+                            key:    calc_temp_file_key(file, options_key),
+                            result: TempFileResult::Failed(err.to_string()),
+                        };
+                        _ = save_temp_file_state(&file.with_extension("temp_light_state"), &state);
+                    }
+                    *cur_result = Err(anyhow::anyhow!(
                         r#"Error "{}" during processing of file "{}""#,
                         err.to_string(),
                         file.to_str().unwrap_or("")
@@ -484,57 +1043,111 @@ fn create_temp_file_from_light_file(
     ref_data:           &RefBgData,
     bin:                usize,
     raw_params:         &RawOpenParams,
+    stars_opts:         &StarsFindOpts,
     files_to_del_later: &Mutex<FilesToDeleteLater>,
     result_list:        &Mutex<Vec<TempFileData>>,
     save_tx:            mpsc::SyncSender<SaveTempFileData>,
     save_aligned:       SaveAlignedImageMode,
-    align_rgb:          bool
-) -> anyhow::Result<()> {
+    align_rgb:          bool,
+    align_opts:         &LightsAlignOpts,
+    airmass_opts:       &AirmassOpts,
+    plugins:            &[PluginOpts],
+    resume:             ResumeMode,
+    options_key:        &str,
+) -> anyhow::Result<bool> { // true if image is sent to saving queue
     let file_total_log = TimeLogger::start();
 
+    let temp_file_name = file.with_extension("temp_light_data");
+    let state_file_name = file.with_extension("temp_light_state");
+    let state_key = if resume != ResumeMode::Off {
+        calc_temp_file_key(file, options_key)
+    } else {
+        String::new()
+    };
+    let save_skipped_state = |reason: String| -> anyhow::Result<()> {
+        if resume == ResumeMode::Off { return Ok(()); }
+        let state = TempFileState {
+            key:    state_key.clone(),
+            result: TempFileResult::Skipped(reason),
+        };
+        save_temp_file_state(&state_file_name, &state)
+    };
+
+    if resume == ResumeMode::Resume {
+        match load_temp_file_state(&state_file_name, &state_key) {
+            Some(TempFileResult::Done(temp_data)) => {
+                log::info!(
+                    "Temp file from previous run is used for {}",
+                    file.to_str().unwrap_or("")
+                );
+                result_list.lock().unwrap().push(TempFileData { group_idx, ..temp_data });
+                return Ok(false);
+            }
+            Some(TempFileResult::Skipped(reason)) => {
+                log::warn!(
+                    "Light file {} skipped in previous run: {}",
+                    file.to_str().unwrap_or(""),
+                    reason
+                );
+                return Ok(false);
+            }
+            Some(TempFileResult::Failed(err)) => anyhow::bail!(
+                "{} (failed in previous run, use --force to process it again)", err
+            ),
+            None => {}
+        }
+    }
+
+    let mut flags = LoadLightFlags::STARS | LoadLightFlags::NOISE;
+    if align_opts.skip_bad_lights || align_opts.by_wcs {
+        flags |= LoadLightFlags::NO_ERR_IF_NO_STARS;
+    }
+
     log::info!("loading light file {}...", file.to_str().unwrap_or(""));
     let load_log = TimeLogger::start();
     let mut light_file = LightFile::load_and_calc_params(
         file,
         cal_data,
-        LoadLightFlags::STARS | LoadLightFlags::NOISE,
+        flags,
         OpenMode::Processing,
         bin,
-        raw_params
+        raw_params,
+        stars_opts
     )?;
     log::info!("loaded light file {}!", file.to_str().unwrap_or(""));
     load_log.log("loading light file TOTAL");
 
-    log::info!("noise = {:.8}", light_file.noise);
-    log::info!("info = {:?}", light_file.info);
+    log::info!("noise = {:.8}, k-sigma noise = {:.8}", light_file.noise, light_file.noise_est);
+    log::debug!("info = {:?}", light_file.info);
+
+    if !align_opts.by_wcs && light_file.stars.len() < align_opts.min_stars {
+        let reason = format!("{} stars found, {} required", light_file.stars.len(), align_opts.min_stars);
+        log::warn!("Light file {} skipped: {}", file.to_str().unwrap_or(""), reason);
+        save_skipped_state(reason)?;
+        return Ok(false);
+    }
+
+    run_plugins(plugins, PluginStage::Calibrated, &mut light_file.image, &light_file.info, file)?;
+
+    let airmass = airmass_opts.airmass_for(&light_file.info);
+    if let Some(airmass) = airmass {
+        log::info!("airmass = {:.3}", airmass);
+    }
+    let ref_airmass = airmass_opts.airmass_for(&ref_data.image.info);
+    if let (Some(coeffs), Some(airmass), Some(ref_airmass)) = (airmass_opts.extinction, airmass, ref_airmass) {
+        correct_extinction(&mut light_file.image, coeffs, airmass, ref_airmass);
+    }
 
     if align_rgb && light_file.image.is_rgb() {
         align_rgb_layers(&mut light_file.image)?;
     }
 
     let diff_log = TimeLogger::start();
-    let mut img_offset: Option<ImageOffset> = None;
-
-    for (max_stars, find_triangle_max_err, triangulation) in [
-        (50,  5.0, false),
-        (100, 3.0, false),
-        (200, 3.0, false),
-        (50,  3.0, true),
-        (100, 3.0, true),
-    ] {
-        img_offset = calc_image_offset_by_stars(
-            &ref_data.image.stars,
-            &light_file.stars,
-            light_file.image.width() as f64,
-            light_file.image.height() as f64,
-            max_stars,
-            find_triangle_max_err,
-            triangulation,
-        );
-        if img_offset.is_some() {
-            break;
-        }
-    }
+    let img_offset = if align_opts.by_wcs {
+        Some(reproject_light_file_by_wcs(&mut light_file, file, ref_data, bin)?)
+    } else {
+        calc_light_file_offset(&light_file, ref_data, align_opts)
+    };
     diff_log.log("calculating light and ref difference");
 
     if let Some(img_offset) = img_offset {
@@ -545,16 +1158,19 @@ fn create_temp_file_from_light_file(
             180.0 * img_offset.angle / PI
         );
 
-        let rot_log = TimeLogger::start();
-        light_file.image = light_file.image.rotated_and_translated(
-            -img_offset.angle,
-            -img_offset.offset_x,
-            -img_offset.offset_y,
-            NO_VALUE_F32,
-            light_file.image.width(),
-            light_file.image.height()
-        );
-        rot_log.log("rotating image");
+        if !align_opts.by_wcs {
+            let rot_log = TimeLogger::start();
+            light_file.image = light_file.image.rotated_and_translated_by(
+                align_opts.interpolation,
+                -img_offset.angle,
+                -img_offset.offset_x,
+                -img_offset.offset_y,
+                NO_VALUE_F32,
+                light_file.image.width(),
+                light_file.image.height()
+            );
+            rot_log.log("rotating image");
+        }
 
         let norm_log = TimeLogger::start();
         let norm_res = normalize_range_and_bg(ref_data, &mut light_file)?;
@@ -564,32 +1180,281 @@ fn create_temp_file_from_light_file(
         light_file.image.check_contains_inf_or_nan(false, true)?;
         nan_log.log("check_contains_nan");
 
-        let temp_file_name = file.with_extension("temp_light_data");
-        log::info!("Sending image into saving queue...");
+        let temp_data = TempFileData {
+            orig_file:    file.to_path_buf(),
+            file_name:    temp_file_name.clone(),
+            range_factor: norm_res.range_factor,
+            noise:        light_file.noise * norm_res.range_factor,
+            noise_est:    light_file.noise_est * norm_res.range_factor,
+            info:         light_file.info.clone(),
+            img_offset,
+            group_idx,
+            airmass:      airmass.map(|v| v as f32),
+            interpolation: if align_opts.by_wcs {
+                ResampleKernel::FluxConserving
+            } else {
+                align_opts.interpolation
+            },
+        };
+
+        let state = if resume != ResumeMode::Off {
+            let state = TempFileState {
+                key:    state_key.clone(),
+                result: TempFileResult::Done(temp_data.clone()),
+            };
+            Some((state_file_name, state))
+        } else {
+            None
+        };
+
+        log::debug!("Sending image into saving queue...");
         save_tx.send(SaveTempFileData{
             file_name:    temp_file_name.clone(),
             orig_fn:      file.to_path_buf(),
             image:        light_file.image,
-            info:         light_file.info.clone(),
+            info:         light_file.info,
             save_aligned,
+            state,
         })?;
-        log::info!("Sending image into saving queue... OK!");
+        log::debug!("Sending image into saving queue... OK!");
 
-        files_to_del_later.lock().unwrap().add(&temp_file_name);
-        result_list.lock().unwrap().push(TempFileData{
-            orig_file:    file.to_path_buf(),
-            file_name:    temp_file_name,
-            range_factor: norm_res.range_factor,
-            noise:        light_file.noise * norm_res.range_factor,
-            info:         light_file.info,
-            img_offset,
-            group_idx,
-        });
+        if resume == ResumeMode::Off {
+            files_to_del_later.lock().unwrap().add(&temp_file_name);
+        }
+        result_list.lock().unwrap().push(temp_data);
+    } else if align_opts.skip_bad_lights {
+        log::warn!(
+            "Light file {} skipped: can't calculate offset and angle",
+            file.to_str().unwrap_or("")
+        );
+        save_skipped_state("can't calculate offset and angle".to_string())?;
+        return Ok(false);
     } else {
         anyhow::bail!("Can't calculate offset and angle between reference image and light file");
     }
     file_total_log.log("processing file TOTAL");
-    Ok(())
+    Ok(true)
+}
+
+pub fn calc_light_file_offset(
+    light_file: &LightFile,
+    ref_data:   &RefBgData,
+    align_opts: &LightsAlignOpts,
+) -> Option<ImageOffset> {
+    let mut img_offset: Option<ImageOffset> = None;
+
+    let angle_hint = match (
+        &align_opts.field_rotation,
+        &ref_data.image.info.file_time,
+        &light_file.info.file_time
+    ) {
+        (Some(field_rotation), Some(ref_time), Some(time)) => {
+            let angle = field_rotation.predict_angle(ref_time, time);
+            log::info!("predicted field rotation = {:.3}°", 180.0 * angle / PI);
+            Some(angle)
+        },
+        _ => None,
+    };
+
+    if align_opts.translation_only || angle_hint.is_some() {
+        for (max_stars, max_err) in [(20, 2.0), (50, 4.0)] {
+            img_offset = calc_image_offset_by_stars_translation(
+                &ref_data.image.stars,
+                &light_file.stars,
+                light_file.image.width() as f64,
+                light_file.image.height() as f64,
+                max_stars,
+                max_err,
+                angle_hint.unwrap_or(0.0),
+            );
+            if img_offset.is_some() {
+                break;
+            }
+        }
+    }
+
+    // Full search if translation only search is not used or
+    // is failed due to large field rotation
+    if img_offset.is_none() {
+        img_offset = find_image_offset_by_stars(
+            &ref_data.image.stars,
+            &light_file.stars,
+            light_file.image.width() as f64,
+            light_file.image.height() as f64,
+        );
+    }
+    img_offset
+}
+
+/// Transform from pixels of light file to pixels of reference image
+/// by WCS of files. `bin` is applied to both WCS
+pub fn calc_wcs_light_transform(
+    file:     &Path,
+    width:    Crd,
+    height:   Crd,
+    ref_data: &RefBgData,
+    bin:      usize,
+) -> anyhow::Result<[[f64; 3]; 2]> {
+    let wcs = Wcs::load_for_image(file)?.binned(bin);
+    let ref_file = &ref_data.image.info.file_name;
+    let ref_wcs = Wcs::load_for_image(ref_file)?.binned(bin);
+    calc_wcs_transform(&wcs, width as usize, height as usize, &ref_wcs).ok_or_else(|| anyhow::anyhow!(
+        "Light file {} and reference image {} don't overlap by WCS",
+        path_to_str(file), path_to_str(ref_file)
+    ))
+}
+
+/// Offset and rotation of center of light file equivalent to affine `matrix`
+fn offset_by_matrix(width: Crd, height: Crd, matrix: &[[f64; 3]; 2]) -> ImageOffset {
+    let cx = (width as f64 - 1.0) / 2.0;
+    let cy = (height as f64 - 1.0) / 2.0;
+    let [[a, b, c], [d, e, f]] = *matrix;
+    ImageOffset {
+        offset_x: cx - (a * cx + b * cy + c),
+        offset_y: cy - (d * cx + e * cy + f),
+        angle:    b.atan2(a),
+        ratio:    (a * e - b * d).abs().sqrt(),
+    }
+}
+
+/// Reprojects light file into pixels of reference image by WCS of both
+/// files. Light file can have other size, pixel scale or rotation so
+/// pixels are integrated over source pixels instead of interpolation
+fn reproject_light_file_by_wcs(
+    light_file: &mut LightFile,
+    file:       &Path,
+    ref_data:   &RefBgData,
+    bin:        usize,
+) -> anyhow::Result<ImageOffset> {
+    let (width, height) = (light_file.image.width(), light_file.image.height());
+    let matrix = calc_wcs_light_transform(file, width, height, ref_data, bin)?;
+    let [[a, b, c], [d, e, f]] = matrix;
+    let det = a * e - b * d;
+    if det.abs() < 1e-12 {
+        bail!("WCS of light file {} is degenerate", path_to_str(file));
+    }
+    let inv = [
+        [ e / det, -b / det, (b * f - c * e) / det],
+        [-d / det,  a / det, (c * d - a * f) / det],
+    ];
+    let ref_image = &ref_data.image.image;
+    let warp = |layer: &ImageLayerF32| warp_layer_flux_conserving(
+        layer, &inv, ref_image.width(), ref_image.height(), NO_VALUE_F32
+    );
+    let image = &light_file.image;
+    let warped = Image {
+        l: warp(&image.l),
+        r: warp(&image.r),
+        g: warp(&image.g),
+        b: warp(&image.b),
+    };
+    light_file.image = warped;
+    Ok(offset_by_matrix(width, height, &matrix))
+}
+
+/// Alignment of light file relative to reference image
+#[derive(Serialize, Clone)]
+pub struct FrameTransform {
+    pub file:     PathBuf,
+    pub width:    Crd,
+    pub height:   Crd,
+    pub offset_x: f64,
+    pub offset_y: f64,
+    pub angle:    f64, // radians
+    pub matrix:   [[f64; 3]; 2], // affine transform from light file to reference image coordinates
+}
+
+/// Affine matrix from light file to reference image coordinates
+/// by offset and rotation around center of image
+pub fn transform_matrix(width: Crd, height: Crd, offset_x: f64, offset_y: f64, angle: f64) -> [[f64; 3]; 2] {
+    // inverse of transformation used in `rotated_and_translated`
+    let cx = (width as f64 - 1.0) / 2.0;
+    let cy = (height as f64 - 1.0) / 2.0;
+    let (sin_a, cos_a) = angle.sin_cos();
+    [
+        [ cos_a, sin_a, cx - offset_x - cos_a * cx - sin_a * cy],
+        [-sin_a, cos_a, cy - offset_y + sin_a * cx - cos_a * cy],
+    ]
+}
+
+impl FrameTransform {
+    fn new(file: &Path, width: Crd, height: Crd, offset: &ImageOffset) -> Self {
+        let matrix = transform_matrix(width, height, offset.offset_x, offset.offset_y, offset.angle);
+        Self {
+            file: file.to_path_buf(),
+            width,
+            height,
+            offset_x: offset.offset_x,
+            offset_y: offset.offset_y,
+            angle: offset.angle,
+            matrix,
+        }
+    }
+}
+
+/// Calculates alignment of light files without resampling and saving them
+pub fn calc_light_files_transforms(
+    progress:    &ProgressTs,
+    files_list:  &[PathBuf],
+    cal_data:    &CalibrationData,
+    ref_data:    &RefBgData,
+    bin:         usize,
+    raw_params:  &RawOpenParams,
+    stars_opts:  &StarsFindOpts,
+    thread_pool: &rayon::ThreadPool,
+    cancel_flag: &IsCancelledFun,
+    align_opts:  &LightsAlignOpts,
+) -> anyhow::Result<Vec<FrameTransform>> {
+    use rayon::prelude::*;
+    progress.lock().unwrap().set_total(files_list.len());
+    let mut flags = LoadLightFlags::STARS;
+    if align_opts.skip_bad_lights || align_opts.by_wcs {
+        flags |= LoadLightFlags::NO_ERR_IF_NO_STARS;
+    }
+    let results: Vec<anyhow::Result<Option<FrameTransform>>> = thread_pool.install(|| {
+        files_list.par_iter().map(|file| {
+            if cancel_flag() { bail!("Termimated"); }
+            let light_file = LightFile::load_and_calc_params(
+                file, cal_data, flags, OpenMode::Processing, bin, raw_params, stars_opts
+            )?;
+            if align_opts.by_wcs {
+                let (width, height) = (light_file.image.width(), light_file.image.height());
+                let matrix = calc_wcs_light_transform(file, width, height, ref_data, bin)?;
+                let offset = offset_by_matrix(width, height, &matrix);
+                progress.lock().unwrap().progress(true, extract_file_name(file));
+                return Ok(Some(FrameTransform { matrix, ..FrameTransform::new(file, width, height, &offset) }));
+            }
+            let offset = if light_file.stars.len() >= align_opts.min_stars {
+                calc_light_file_offset(&light_file, ref_data, align_opts)
+            } else {
+                None
+            };
+            progress.lock().unwrap().progress(true, extract_file_name(file));
+            match offset {
+                Some(offset) => Ok(Some(FrameTransform::new(
+                    file,
+                    light_file.image.width(),
+                    light_file.image.height(),
+                    &offset
+                ))),
+                None if align_opts.skip_bad_lights => {
+                    log::warn!("Light file {} skipped: can't calculate offset and angle", path_to_str(file));
+                    Ok(None)
+                },
+                None => bail!(
+                    "Can't calculate offset and angle between reference image and light file {}",
+                    path_to_str(file)
+                ),
+            }
+        }).collect()
+    });
+    let mut result = Vec::new();
+    for item in results {
+        if let Some(transform) = item? {
+            result.push(transform);
+        }
+    }
+    Ok(result)
 }
 
 pub fn seconds_to_total_time_str(seconds: f64, short: bool) -> String {
@@ -612,25 +1477,163 @@ pub fn seconds_to_total_time_str(seconds: f64, short: bool) -> String {
     }
 }
 
+// Max rows of band of values integrated by GPU in one pass
+const GPU_BAND_MAX_ROWS: usize = 256;
+
+/// Calls `fun` for index and value of every pixel of temp file.
+/// Values of RGB file are indexed layer by layer
+fn read_temp_file_values(
+    file_name: &Path,
+    is_rgb:    bool,
+    pixels:    usize,
+    mut fun:   impl FnMut(usize, f32),
+) -> anyhow::Result<()> {
+    let mut reader = InternalFormatReader::new(file_name)?;
+    for i in 0..pixels {
+        if is_rgb {
+            let (r, g, b) = reader.get_rgb()?;
+            fun(i, r);
+            fun(pixels + i, g);
+            fun(2 * pixels + i, b);
+        } else {
+            fun(i, reader.get_l()?);
+        }
+    }
+    Ok(())
+}
+
+/// Integration of temp files read one by one, so count of files is limited
+/// neither by open files nor by memory for values of pixel. First pass
+/// accumulates running mean and variance of every pixel. For kappa-sigma
+/// every of `repeats` next passes keeps values inside of kappa-sigma ranges
+/// of all previous passes (as `cappa_sigma_weighted_result` does) and
+/// accumulates statistics of them in the same buffer. Passes are stopped
+/// when no more values are rejected. Result is false if stacking is cancelled
+fn merge_temp_light_files_streaming(
+    progress:        &ProgressTs,
+    temp_file_names: &[TempFileData],
+    weights:         &[f64],
+    calc_opts:       &CalcOpts,
+    result_image:    &mut Image,
+    rejection:       &mut RejectionCounter,
+    cancel_flag:     &IsCancelledFun,
+) -> anyhow::Result<bool> {
+    if calc_opts.mode == CalcMode::Median {
+        bail!("Median can't be calculated in streaming mode. Use kappa-sigma or mean");
+    }
+    let is_rgb = result_image.is_rgb();
+    let pixels = result_image.width() as usize * result_image.height() as usize;
+    let values_count = if is_rgb { 3 * pixels } else { pixels };
+    let repeats = if calc_opts.mode == CalcMode::CappaSigma { calc_opts.repeats as usize } else { 0 };
+    let total = (repeats + 1) * temp_file_names.len();
+    let kappa = calc_opts.kappa;
+
+    let mut stats = vec![RunningStat::default(); values_count];
+    let mut bounds = Vec::new(); // kappa-sigma range of every value
+    let mut overexposed = vec![false; values_count];
+    let mut kept_count = u64::MAX;
+    let (mut rejected_low, mut rejected_high) = (0_u64, 0_u64);
+    for pass in 0..=repeats {
+        if pass == 1 {
+            rejection.values += kept_count;
+            bounds = vec![(f32::MIN, f32::MAX); values_count];
+        }
+        if pass != 0 {
+            for (stat, (low, high)) in stats.iter_mut().zip(&mut bounds) {
+                if let (Some(mean), Some(std_dev)) = (stat.mean(), stat.std_dev()) {
+                    *low = low.max(mean - kappa * std_dev);
+                    *high = high.min(mean + kappa * std_dev);
+                }
+                *stat = RunningStat::default();
+            }
+            (rejected_low, rejected_high) = (0, 0);
+        }
+        for (idx, (temp_file, weight)) in temp_file_names.iter().zip(weights).enumerate() {
+            if cancel_flag() { return Ok(false); }
+            let text = if pass == 0 {
+                "Accumulating mean and variance...".to_string()
+            } else {
+                format!("Averaging values inside kappa-sigma range (pass {} of {})...", pass, repeats)
+            };
+            progress.lock().unwrap().percent(pass * temp_file_names.len() + idx + 1, total, &text);
+            read_temp_file_values(&temp_file.file_name, is_rgb, pixels, |i, v| {
+                if v == NO_VALUE_F32 { return; }
+                if v.is_infinite() {
+                    overexposed[i] = true;
+                    return;
+                }
+                match bounds.get(i) {
+                    Some((low, _)) if v < *low => rejected_low += 1,
+                    Some((_, high)) if v > *high => rejected_high += 1,
+                    _ => stats[i].add(v, *weight as f32),
+                }
+            })?;
+        }
+        // sets of kept values only shrink so they are
+        // the same for all pixels if total count is the same
+        let new_kept_count = stats.iter().map(|stat| stat.count() as u64).sum::<u64>();
+        if new_kept_count == kept_count { break; }
+        kept_count = new_kept_count;
+    }
+    if bounds.is_empty() {
+        rejection.values += kept_count;
+    }
+    rejection.low += rejected_low;
+    rejection.high += rejected_high;
+
+    let layers = if is_rgb {
+        vec![&mut result_image.r, &mut result_image.g, &mut result_image.b]
+    } else {
+        vec![&mut result_image.l]
+    };
+    for (layer_idx, layer) in layers.into_iter().enumerate() {
+        for (j, dst) in layer.iter_mut().enumerate() {
+            let i = layer_idx * pixels + j;
+            // center of kappa-sigma range if all values are rejected
+            let result = stats[i].weighted_mean().or_else(|| {
+                let (low, high) = *bounds.get(i)?;
+                if low == f32::MIN { return None; }
+                Some(0.5 * (low + high))
+            });
+            *dst = match result {
+                Some(result) => result,
+                None if overexposed[i] => f32::INFINITY,
+                None => 0.0,
+            };
+        }
+    }
+    Ok(true)
+}
+
 pub fn merge_temp_light_files(
     progress:        &ProgressTs,
     temp_file_names: &[TempFileData],
     calc_opts:       &CalcOpts,
+    streaming:       bool,
     is_rgb_image:    bool,
     ref_width:       Crd,
     ref_height:      Crd,
     align_rgb:       bool,
     result_file:     &Path,
+    tiff16:          bool,
+    fits_opts:       FitsSaveOpts,
+    maps_opts:       &StackMapsOpts,
+    plugins:         &[PluginOpts],
     cancel_flag:     &IsCancelledFun,
-) -> anyhow::Result<()> {
-    let min_noise = temp_file_names.iter().map(|v| v.noise).min_by(cmp_f32).unwrap();
+) -> anyhow::Result<StackStat> {
+    // k-sigma noise is used for weighting if it is known for all files
+    let use_noise_est = temp_file_names.iter().all(|v| v.noise_est > 0.0);
+    let file_noise = |v: &TempFileData| if use_noise_est { v.noise_est } else { v.noise };
+    let min_noise = temp_file_names.iter().map(file_noise).min_by(cmp_f32).unwrap();
 
     progress.lock().unwrap().percent(0, 100, "Opening temp files...");
     let mut stack_items = Vec::new();
 
     struct StackItem {
-        reader: InternalFormatReader,
-        weight: f64,
+        reader:     InternalFormatReader,
+        weight:     f64,
+        range:      f64,
+        resample_k: f64,
     }
 
     log::info!(
@@ -639,10 +1642,14 @@ pub fn merge_temp_light_files(
     );
     let mut total_time = 0_f64;
     let mut weighted_time = 0_f64;
+    let mut meta = IntegrationMeta::default();
+    let mut frames = Vec::new();
+    let mut weights = Vec::new();
     for temp_file in temp_file_names.iter() {
-        let weight = min_noise.powf(2.0) / temp_file.noise.powf(2.0);
+        let weight = min_noise.powf(2.0) / file_noise(temp_file).powf(2.0);
         total_time += temp_file.info.exp.unwrap_or(0.0) as f64;
         weighted_time += weight as f64 * temp_file.info.exp.unwrap_or(0.0);
+        meta.add_frame(&temp_file.info, &temp_file.orig_file);
 
         log::info!(
             "| {:7.1} | {:7.1} | {:7.2} | {:6.3} | {:6.3} | {:9.7} | {:6} | {:8.1} | {:7.1} | {:7} | {}",
@@ -659,9 +1666,24 @@ pub fn merge_temp_light_files(
             extract_file_name(&temp_file.orig_file)
         );
 
+        frames.push(StackedFrame {
+            file:         temp_file.orig_file.clone(),
+            offset_x:     temp_file.img_offset.offset_x,
+            offset_y:     temp_file.img_offset.offset_y,
+            angle:        180.0 * temp_file.img_offset.angle / PI,
+            weight:       weight as f64,
+            range_factor: temp_file.range_factor,
+            noise:        file_noise(temp_file),
+            airmass:      temp_file.airmass,
+        });
+
+        weights.push(weight as f64);
+        if streaming { continue; } // files are opened one by one
         stack_items.push(StackItem {
-            reader: InternalFormatReader::new(&temp_file.file_name)?,
-            weight: weight as f64,
+            reader:     InternalFormatReader::new(&temp_file.file_name)?,
+            weight:     weight as f64,
+            range:      temp_file.range_factor as f64,
+            resample_k: interpolation_variance_k_for(&temp_file.img_offset, temp_file.interpolation),
         });
     }
 
@@ -670,32 +1692,176 @@ pub fn merge_temp_light_files(
 
     let time_log = TimeLogger::start();
 
-    let calc_for_values = |values: &mut Vec<CalcValue>| -> f32 {
+    let variance_model = maps_opts.variance;
+
+    // `frame_vars` are variances of `values` (empty if variance is not calculated)
+    let calc_for_values = |values: &mut Vec<CalcValue>, frame_vars: &mut Vec<f64>, stat: &mut PixelStackStat| -> f32 {
+        *stat = PixelStackStat::default();
         if values.is_empty() { return 0.0; }
         let contains_inf = values.iter().any(|v| v.value.is_infinite());
         let contains_values = values.iter().any(|v| !v.value.is_infinite());
         if contains_inf && !contains_values {
             return f32::INFINITY;
         } else if contains_inf && contains_values {
+            if !frame_vars.is_empty() {
+                let mut is_inf = values.iter().map(|v| v.value.is_infinite());
+                frame_vars.retain(|_| !is_inf.next().unwrap_or(false));
+            }
             values.retain(|v| !v.value.is_infinite());
         }
-        calc(values, calc_opts)
-            .map(|v| v.result as f32)
-            .unwrap_or(NO_VALUE_F32)
+        // values are reordered by median so its variance
+        // (pi/2 of variance of mean) is calculated before
+        let median_variance = if !frame_vars.is_empty() && calc_opts.mode == CalcMode::Median {
+            let n = frame_vars.len() as f64;
+            Some(0.5 * PI * frame_vars.iter().sum::<f64>() / (n * n))
+        } else {
+            None
+        };
+        let Some(result) = calc(values, calc_opts) else {
+            return NO_VALUE_F32;
+        };
+        for v in values.iter() {
+            if v.used {
+                stat.weight += v.weight as f32;
+            } else if v.value < result.result {
+                stat.rejected_low += 1.0;
+            } else {
+                stat.rejected_high += 1.0;
+            }
+        }
+        if let Some(variance) = median_variance {
+            stat.variance = variance as f32;
+        } else if !frame_vars.is_empty() {
+            // variance of weighted mean of used values
+            let (mut sum_w, mut sum_w2_var) = (0_f64, 0_f64);
+            for (v, var) in values.iter().zip(frame_vars.iter()) {
+                if !v.used { continue; }
+                sum_w += v.weight;
+                sum_w2_var += v.weight * v.weight * var;
+            }
+            if sum_w > 0.0 {
+                stat.variance = (sum_w2_var / (sum_w * sum_w)) as f32;
+            }
+        }
+        result.result as f32
     };
 
     let mut result_image = Image::new();
-
-    if is_rgb_image {
+    if streaming && (maps_opts.is_any() || variance_model.is_some()) {
+        log::warn!("Stack maps and variance are not calculated in streaming mode");
+    }
+    let variance_model = variance_model.filter(|_| !streaming);
+    let mut maps = if maps_opts.is_any() && !streaming {
+        Some(StackMaps::new(is_rgb_image, ref_width, ref_height))
+    } else {
+        None
+    };
+    let mut stats = [PixelStackStat::default(); 3];
+    let mut rejection = RejectionCounter::default();
+
+    // Variance, stack maps and median are calculated on CPU only
+    let gpu_band_rows = gpu_stack_band_pixels(stack_items.len())
+        .filter(|_| !streaming && variance_model.is_none() && maps.is_none() && calc_opts.mode != CalcMode::Median)
+        .map(|pixels| (pixels / ref_width as usize).min(GPU_BAND_MAX_ROWS))
+        .filter(|rows| *rows > 0);
+
+    if streaming {
+        if is_rgb_image {
+            result_image.make_color(ref_width, ref_height);
+        } else {
+            result_image.make_grey(ref_width, ref_height);
+        }
+        let completed = merge_temp_light_files_streaming(
+            progress,
+            temp_file_names,
+            &weights,
+            calc_opts,
+            &mut result_image,
+            &mut rejection,
+            cancel_flag
+        )?;
+        if !completed {
+            return Ok(StackStat::default());
+        }
+    } else if let Some(band_rows) = gpu_band_rows {
+        // Values of band of rows are integrated by GPU.
+        // Band is integrated on CPU if GPU failed
+        if is_rgb_image {
+            result_image.make_color(ref_width, ref_height);
+        } else {
+            result_image.make_grey(ref_width, ref_height);
+        }
+        let width = ref_width as usize;
+        let frames = stack_items.len();
+        let weights: Vec<f32> = stack_items.iter().map(|item| item.weight as f32).collect();
+        let mut bands = vec![Vec::<f32>::new(); if is_rgb_image { 3 } else { 1 }];
+        let mut values = Vec::new();
+        let mut y = 0;
+        while y < ref_height as usize {
+            if cancel_flag() {
+                return Ok(StackStat::default());
+            }
+            progress.lock().unwrap().percent(y + 1, ref_height as usize, "Merging values...");
+            let pixels = band_rows.min(ref_height as usize - y) * width;
+            for band in &mut bands {
+                band.resize(frames * pixels, NO_VALUE_F32);
+            }
+            for (frame, stack_item) in stack_items.iter_mut().enumerate() {
+                for i in frame * pixels..(frame + 1) * pixels {
+                    if is_rgb_image {
+                        (bands[0][i], bands[1][i], bands[2][i]) = stack_item.reader.get_rgb()?;
+                    } else {
+                        bands[0][i] = stack_item.reader.get_l()?;
+                    }
+                }
+            }
+            let layers = if is_rgb_image {
+                vec![&mut result_image.r, &mut result_image.g, &mut result_image.b]
+            } else {
+                vec![&mut result_image.l]
+            };
+            for ((layer, band), stat) in layers.into_iter().zip(&bands).zip(&mut stats) {
+                let dst = &mut layer.as_slice_mut()[y * width..y * width + pixels];
+                let gpu_result = gpu_stack_values(band, &weights, pixels, calc_opts);
+                for (i, dst) in dst.iter_mut().enumerate() {
+                    let count = if let Some(gpu_result) = &gpu_result {
+                        let pixel = &gpu_result[i];
+                        *dst = pixel.result;
+                        *stat = PixelStackStat {
+                            rejected_low:  pixel.rejected_low as f32,
+                            rejected_high: pixel.rejected_high as f32,
+                            weight:        pixel.weight,
+                            variance:      0.0,
+                        };
+                        pixel.count
+                    } else {
+                        values.clear();
+                        for (frame, stack_item) in stack_items.iter().enumerate() {
+                            let v = band[frame * pixels + i];
+                            if v == NO_VALUE_F32 { continue; }
+                            values.push(CalcValue::new_weighted(v as f64, stack_item.weight));
+                        }
+                        *dst = calc_for_values(&mut values, &mut Vec::new(), stat);
+                        values.len()
+                    };
+                    rejection.add(count, stat);
+                }
+            }
+            y += pixels / width;
+        }
+    } else if is_rgb_image {
         result_image.make_color(ref_width, ref_height);
         let mut r_values = Vec::new();
         let mut g_values = Vec::new();
         let mut b_values = Vec::new();
+        let mut r_vars = Vec::new();
+        let mut g_vars = Vec::new();
+        let mut b_vars = Vec::new();
         let mut prev_y = -1;
         for (x, y, r, g, b) in result_image.iter_rgb_crd_mut() {
             if y != prev_y {
                 if cancel_flag() {
-                    return Ok(());
+                    return Ok(StackStat::default());
                 }
                 progress.lock().unwrap().percent(
                     y as usize + 1,
@@ -708,22 +1874,33 @@ pub fn merge_temp_light_files(
             r_values.clear();
             g_values.clear();
             b_values.clear();
+            r_vars.clear();
+            g_vars.clear();
+            b_vars.clear();
             for stack_item in stack_items.iter_mut() {
                 let (fr, fg, fb) = stack_item.reader.get_rgb()?;
-                if fr != NO_VALUE_F32 {
-                    r_values.push(CalcValue::new_weighted(fr as f64, stack_item.weight));
-                }
-                if fg != NO_VALUE_F32 {
-                    g_values.push(CalcValue::new_weighted(fg as f64, stack_item.weight));
-                }
-                if fb != NO_VALUE_F32 {
-                    b_values.push(CalcValue::new_weighted(fb as f64, stack_item.weight));
+                for (v, values, vars) in [
+                    (fr, &mut r_values, &mut r_vars),
+                    (fg, &mut g_values, &mut g_vars),
+                    (fb, &mut b_values, &mut b_vars),
+                ] {
+                    if v == NO_VALUE_F32 { continue; }
+                    values.push(CalcValue::new_weighted(v as f64, stack_item.weight));
+                    if let Some(model) = &variance_model {
+                        vars.push(model.frame_variance(v as f64, stack_item.range, stack_item.resample_k));
+                    }
                 }
             }
 
-            *r = calc_for_values(&mut r_values);
-            *g = calc_for_values(&mut g_values);
-            *b = calc_for_values(&mut b_values);
+            *r = calc_for_values(&mut r_values, &mut r_vars, &mut stats[0]);
+            *g = calc_for_values(&mut g_values, &mut g_vars, &mut stats[1]);
+            *b = calc_for_values(&mut b_values, &mut b_vars, &mut stats[2]);
+            for (values, stat) in [&r_values, &g_values, &b_values].into_iter().zip(&stats) {
+                rejection.add(values.len(), stat);
+            }
+            if let Some(maps) = &mut maps {
+                maps.set(x, y, &stats);
+            }
 
             if r.is_nan() || g.is_nan() || b.is_nan() {
                 log::error!("NAN result in merge_temp_light_files!");
@@ -736,11 +1913,12 @@ pub fn merge_temp_light_files(
     } else {
         result_image.make_grey(ref_width, ref_height);
         let mut l_values = Vec::new();
+        let mut l_vars = Vec::new();
         let mut prev_y = -1;
-        for (_, y, l) in result_image.l.iter_crd_mut() {
+        for (x, y, l) in result_image.l.iter_crd_mut() {
             if y != prev_y {
                 if cancel_flag() {
-                    return Ok(());
+                    return Ok(StackStat::default());
                 }
                 progress.lock().unwrap().percent(
                     y as usize + 1,
@@ -751,14 +1929,22 @@ pub fn merge_temp_light_files(
             }
 
             l_values.clear();
+            l_vars.clear();
             for stack_item in stack_items.iter_mut() {
                 let fl = stack_item.reader.get_l()?;
                 if fl != NO_VALUE_F32 {
                     l_values.push(CalcValue::new_weighted(fl as f64, stack_item.weight));
+                    if let Some(model) = &variance_model {
+                        l_vars.push(model.frame_variance(fl as f64, stack_item.range, stack_item.resample_k));
+                    }
                 }
             }
 
-            *l = calc_for_values(&mut l_values);
+            *l = calc_for_values(&mut l_values, &mut l_vars, &mut stats[0]);
+            rejection.add(l_values.len(), &stats[0]);
+            if let Some(maps) = &mut maps {
+                maps.set(x, y, &stats[..1]);
+            }
         }
     }
 
@@ -766,8 +1952,29 @@ pub fn merge_temp_light_files(
 
     progress.lock().unwrap().percent(100, 100, "Saving result...");
 
+    // noise is measured before normalization to compare with light files
+    let noise_layer = if result_image.is_rgb() { &result_image.g } else { &result_image.l };
+    let mut frames_noise: Vec<f32> = temp_file_names.iter()
+        .map(|v| v.noise_est)
+        .filter(|v| *v > 0.0)
+        .collect();
+    let noise = StackNoise {
+        frames_count: temp_file_names.len(),
+        frame_noise:  median_f32(&mut frames_noise).unwrap_or(0.0),
+        result_noise: estimate_noise_mrs(noise_layer),
+    };
+    log::info!(
+        "Noise of frames (median) = {:.8}, noise of result = {:.8}",
+        noise.frame_noise, noise.result_noise
+    );
+    let rejection = rejection.get();
+    log::info!(
+        "Rejected values: {:.3}% low, {:.3}% high",
+        rejection.low_percent, rejection.high_percent
+    );
+
     result_image.check_contains_inf_or_nan(false, true)?;
-    result_image.normalize_to_1(false);
+    let norm_k = result_image.normalize_to_1(false);
     result_image.fill_inf_areas_with_one();
     result_image.check_contains_inf_or_nan(true, true)?;
 
@@ -775,20 +1982,61 @@ pub fn merge_temp_light_files(
         align_rgb_layers(&mut result_image)?;
     }
 
-    log::info!("Saving image into file {}", result_file.to_str().unwrap_or(""));
     let mut dst_info = ImageInfo::default();
-    dst_info.exp = Some(total_time);
-    save_image_to_file(&result_image, &dst_info, result_file)?;
+    meta.apply_to_info(&mut dst_info);
+    run_plugins(plugins, PluginStage::Result, &mut result_image, &dst_info, result_file)?;
+
+    log::info!("Saving image into file {}", result_file.to_str().unwrap_or(""));
+    if let (Some(maps), Some(_)) = (&mut maps, variance_model) {
+        // variance is scaled as result values
+        let k2 = norm_k * norm_k;
+        for layer in [&mut maps.variance.l, &mut maps.variance.r, &mut maps.variance.g, &mut maps.variance.b] {
+            layer.iter_mut().for_each(|v| *v *= k2);
+        }
+    }
+    let variance = maps.as_ref()
+        .filter(|_| variance_model.is_some())
+        .map(|maps| &maps.variance);
+    let variance_in_result = variance.is_some() && is_fits_ext(extract_extension(result_file));
+    write_file_atomically(result_file, |file_name| {
+        if tiff16 && is_tiff_ext(extract_extension(result_file)) {
+            save_image_to_tiff16_file(&result_image, &dst_info, file_name, fits_opts.quantization)?;
+        } else {
+            save_image_to_file(&result_image, &dst_info, file_name, fits_opts)?;
+            if is_fits_ext(extract_extension(result_file)) {
+                let clip_stats = fits_clip_stats(&result_image, &dst_info, &fits_opts);
+                if !clip_stats.is_empty() {
+                    log::info!("Values clipped at saving: {}", clip_stats);
+                }
+            }
+        }
+        if let (Some(variance), true) = (variance, variance_in_result) {
+            append_image_to_fits_file(variance, "VARIANCE", file_name)?;
+        }
+        Ok(())
+    })?;
+    if let (Some(variance), false) = (variance, variance_in_result) {
+        // TIFF and XISF results don't have extensions
+        let variance_file = get_variance_file_name(result_file);
+        log::info!("Saving variance into file {}", variance_file.to_str().unwrap_or(""));
+        write_file_atomically(&variance_file, |tmp_file_name| {
+            save_image_to_fits_file(variance, &ImageInfo::default(), tmp_file_name, FitsSaveOpts::default())
+        })?;
+    }
+
+    if let Some(maps) = &maps {
+        maps.save(maps_opts, fits_opts.compression)?;
+    }
 
     progress.lock().unwrap().percent(100, 100, "Done!");
 
-    Ok(())
+    Ok(StackStat { noise, frames, rejection })
 }
 
 fn align_rgb_layers(image: &mut Image) -> anyhow::Result<()> {
     let get_stars = |img| {
         let noise = calc_noise(img) as f32;
-        find_stars_on_image(img, Some(noise), false)
+        find_stars_on_image(img, Some(noise), false, &StarsFindOpts::default())
     };
     let g_stars = get_stars(&image.g)?;
     let img_width = image.width();
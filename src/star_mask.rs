@@ -0,0 +1,99 @@
+use std::path::Path;
+use crate::{image::*, image_io::*, stars::*, calc::*};
+
+/* Soft mask of stars (1 - star, 0 - background) made by star detector.
+   Mask is saved as mono FITS file and can be used by gradient removal,
+   deconvolution and in external editors */
+
+#[derive(Clone, Debug)]
+pub struct StarMaskOpts {
+    pub grow:      f32, // pixels added to radius of star
+    pub feather:   f32, // width of soft edge in pixels
+    pub mag_limit: Option<f32>, // only stars not fainter than brightest one by this magnitudes
+}
+
+impl Default for StarMaskOpts {
+    fn default() -> Self {
+        Self {
+            grow: 2.0,
+            feather: 3.0,
+            mag_limit: None,
+        }
+    }
+}
+
+pub struct StarMask {
+    pub mask:  ImageLayerF32,
+    pub stars: usize, // stars in mask
+}
+
+/// Draws star discs with radius `star.radius + grow` and
+/// linear transition to background of `feather` pixels
+pub fn render_soft_stars_mask<'a>(
+    width:   Crd,
+    height:  Crd,
+    stars:   impl Iterator<Item = &'a Star>,
+    grow:    f32,
+    feather: f32,
+) -> ImageLayerF32 {
+    let mut mask = ImageLayerF32::new(width, height);
+    let (width, height) = (width as usize, height as usize);
+    let data = mask.as_slice_mut();
+    for star in stars {
+        let inner = (star.radius + grow) as f64;
+        let outer = inner + feather.max(0.0) as f64;
+        let (x1, x2) = ((star.x - outer).floor().max(0.0) as usize, ((star.x + outer).ceil() as usize).min(width - 1));
+        let (y1, y2) = ((star.y - outer).floor().max(0.0) as usize, ((star.y + outer).ceil() as usize).min(height - 1));
+        for y in y1..=y2 {
+            for x in x1..=x2 {
+                let dist = ((x as f64 - star.x).powi(2) + (y as f64 - star.y).powi(2)).sqrt();
+                let v = if outer > inner {
+                    ((outer - dist) / (outer - inner)).clamp(0.0, 1.0) as f32
+                } else if dist <= inner {
+                    1.0
+                } else {
+                    0.0
+                };
+                let m = &mut data[y * width + x];
+                *m = m.max(v);
+            }
+        }
+    }
+    mask
+}
+
+pub fn create_star_mask(image: &Image, opts: &StarMaskOpts) -> anyhow::Result<StarMask> {
+    let layer = image.create_greyscale_layer();
+    let noise = calc_noise(&layer) as f32;
+    let stars = find_stars_on_image(&layer, Some(noise), true, &StarsFindOpts::default())?;
+    let max_brightness = stars.iter().map(|s| s.brightness).fold(0.0, f64::max);
+    let is_bright_enough = |star: &Star| match opts.mag_limit {
+        Some(mag_limit) if max_brightness > 0.0 && star.brightness > 0.0 =>
+            -2.5 * (star.brightness / max_brightness).log10() <= mag_limit as f64,
+        Some(_) => false,
+        None => true,
+    };
+    let selected: Vec<&Star> = stars.iter().filter(|s| is_bright_enough(s)).collect();
+    let mask = render_soft_stars_mask(
+        layer.width(),
+        layer.height(),
+        selected.iter().copied(),
+        opts.grow,
+        opts.feather
+    );
+    Ok(StarMask { mask, stars: selected.len() })
+}
+
+/// Loads mask created by `create_star_mask` for image of given size
+pub fn load_star_mask(file_name: &Path, width: Crd, height: Crd) -> anyhow::Result<ImageLayerF32> {
+    let ImageData { image: RawOrImage::Image(image), .. } = load_image_from_file(file_name, false)? else {
+        anyhow::bail!("{} is RAW image", file_name.to_str().unwrap_or(""));
+    };
+    if image.width() != width || image.height() != height {
+        anyhow::bail!(
+            "Size of star mask {}x{} is different from size of image {}x{}",
+            image.width(), image.height(), width, height
+        );
+    }
+    Ok(if image.is_rgb() { image.create_greyscale_layer() } else { image.l })
+}
@@ -0,0 +1,201 @@
+use std::path::*;
+use crate::{image::*, image_io::*, image_raw::*, calc::*, light_file::*, stars::*};
+
+/* FWHM and eccentricity of stars across field for optical diagnostics.
+   Shapes of single stars are interpolated into heat maps of image size:
+   FWHM growing to one side means tilt of sensor, growing to all corners
+   means field curvature (wrong back focus of flattener) and elongated
+   stars on one side or in center mean collimation problems */
+
+/// Stars (by median) which define value of every cell of map grid
+const STARS_PER_CELL: usize = 8;
+const MAX_GRID_SIZE: usize = 16;
+
+/// Second moment of gaussian weighted by values above half maximum
+/// relative to its sigma squared
+const HALF_MAX_MOMENT: f64 = 1.0 - std::f64::consts::LN_2;
+
+#[derive(Clone, Debug)]
+pub struct StarShape {
+    pub x:            f64,
+    pub y:            f64,
+    pub fwhm:         f32, // pixels
+    pub eccentricity: f32, // 0 - round star
+}
+
+/// Shape of star by second moments of pixels above half maximum
+fn measure_star_shape(layer: &ImageLayerF32, star: &Star) -> Option<StarShape> {
+    if star.overexposured { return None; }
+    let bg = star.background as f64;
+    let half_max = bg + 0.5 * (star.max_value as f64 - bg);
+    let r = star.width.max(star.height) + 1;
+    let (cx, cy) = (star.x.round() as Crd, star.y.round() as Crd);
+    let mut points = Vec::new();
+    for y in cy - r..=cy + r {
+        for x in cx - r..=cx + r {
+            let Some(v) = layer.get(x, y) else { continue; };
+            if !v.is_finite() || v == NO_VALUE_F32 || (v as f64) < half_max { continue; }
+            points.push((x as f64, y as f64, v as f64 - bg));
+        }
+    }
+    if points.len() < 3 { return None; }
+    let sum: f64 = points.iter().map(|(_, _, w)| w).sum();
+    let mx = points.iter().map(|(x, _, w)| x * w).sum::<f64>() / sum;
+    let my = points.iter().map(|(_, y, w)| y * w).sum::<f64>() / sum;
+    let (mut mxx, mut myy, mut mxy) = (0.0, 0.0, 0.0);
+    for (x, y, w) in &points {
+        let (dx, dy) = (x - mx, y - my);
+        mxx += w * dx * dx;
+        myy += w * dy * dy;
+        mxy += w * dx * dy;
+    }
+    let (mxx, myy, mxy) = (mxx / sum, myy / sum, mxy / sum);
+    let d = ((mxx - myy).powi(2) + 4.0 * mxy * mxy).sqrt();
+    let (l1, l2) = (0.5 * (mxx + myy + d), 0.5 * (mxx + myy - d));
+    if l1 <= 0.0 || l2 <= 0.0 { return None; }
+
+    // FWHM is geometric mean of FWHM of axes
+    let sigma2 = (l1 * l2).sqrt() / HALF_MAX_MOMENT;
+    Some(StarShape {
+        x: star.x,
+        y: star.y,
+        fwhm: (2.3548 * sigma2.sqrt()) as f32,
+        eccentricity: (1.0 - l2 / l1).sqrt() as f32,
+    })
+}
+
+pub fn measure_star_shapes(layer: &ImageLayerF32, stars: &Stars) -> Vec<StarShape> {
+    stars.iter()
+        .filter_map(|star| measure_star_shape(layer, star))
+        .collect()
+}
+
+/// Medians of stars in zones of field (thirds of width and height)
+#[derive(Clone, Debug, Default)]
+pub struct FieldZones {
+    pub center:       Option<f32>,
+    pub top_left:     Option<f32>,
+    pub top_right:    Option<f32>,
+    pub bottom_left:  Option<f32>,
+    pub bottom_right: Option<f32>,
+}
+
+impl FieldZones {
+    fn new(shapes: &[StarShape], width: Crd, height: Crd, value: impl Fn(&StarShape) -> f32) -> Self {
+        let zone_median = |zone_x: i32, zone_y: i32| -> Option<f32> {
+            let mut values: Vec<f32> = shapes.iter()
+                .filter(|s| {
+                    (3.0 * s.x / width as f64).floor() as i32 == zone_x
+                    && (3.0 * s.y / height as f64).floor() as i32 == zone_y
+                })
+                .map(&value)
+                .collect();
+            median_f32(&mut values)
+        };
+        Self {
+            center:       zone_median(1, 1),
+            top_left:     zone_median(0, 0),
+            top_right:    zone_median(2, 0),
+            bottom_left:  zone_median(0, 2),
+            bottom_right: zone_median(2, 2),
+        }
+    }
+
+    pub fn corners(&self) -> Vec<f32> {
+        [self.top_left, self.top_right, self.bottom_left, self.bottom_right]
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+}
+
+pub struct StarShapeMaps {
+    pub stars:              usize, // measured
+    pub grid:               (usize, usize),
+    pub fwhm:               ImageLayerF32,
+    pub eccentricity:       ImageLayerF32,
+    pub fwhm_zones:         FieldZones,
+    pub eccentricity_zones: FieldZones,
+}
+
+/// Values of grid cells: median of stars nearest to center of cell
+/// (as many as cell has but not less than half of STARS_PER_CELL)
+fn grid_values(
+    shapes: &[StarShape],
+    width:  Crd,
+    height: Crd,
+    cols:   usize,
+    rows:   usize,
+    value:  impl Fn(&StarShape) -> f32,
+) -> Vec<f32> {
+    let (cell_w, cell_h) = (width as f64 / cols as f64, height as f64 / rows as f64);
+    let mut result = Vec::with_capacity(cols * rows);
+    let mut by_dist = Vec::with_capacity(shapes.len());
+    for row in 0..rows {
+        for col in 0..cols {
+            let (x, y) = ((col as f64 + 0.5) * cell_w, (row as f64 + 0.5) * cell_h);
+            by_dist.clear();
+            by_dist.extend(shapes.iter().map(|s| ((s.x - x).powi(2) + (s.y - y).powi(2), value(s))));
+            let in_cell = shapes.iter()
+                .filter(|s| (s.x - x).abs() <= 0.5 * cell_w && (s.y - y).abs() <= 0.5 * cell_h)
+                .count();
+            let count = in_cell.max(STARS_PER_CELL / 2).min(by_dist.len());
+            by_dist.select_nth_unstable_by(count - 1, |(d1, _), (d2, _)| d1.total_cmp(d2));
+            let mut values: Vec<f32> = by_dist[..count].iter().map(|(_, v)| *v).collect();
+            result.push(median_f32(&mut values).unwrap_or(0.0));
+        }
+    }
+    result
+}
+
+/// Bilinear interpolation between centers of grid cells
+fn interpolate_grid(values: &[f32], cols: usize, rows: usize, width: Crd, height: Crd) -> ImageLayerF32 {
+    let mut result = ImageLayerF32::new(width, height);
+    let grid_crd = |crd: Crd, size: Crd, cells: usize| -> (usize, usize, f32) {
+        let pos = ((crd as f32 + 0.5) * cells as f32 / size as f32 - 0.5).clamp(0.0, (cells - 1) as f32);
+        let i1 = pos.floor() as usize;
+        let i2 = (i1 + 1).min(cells - 1);
+        (i1, i2, pos - i1 as f32)
+    };
+    for (x, y, v) in result.iter_crd_mut() {
+        let (c1, c2, kx) = grid_crd(x, width, cols);
+        let (r1, r2, ky) = grid_crd(y, height, rows);
+        let top = values[c1 + r1 * cols] * (1.0 - kx) + values[c2 + r1 * cols] * kx;
+        let bottom = values[c1 + r2 * cols] * (1.0 - kx) + values[c2 + r2 * cols] * kx;
+        *v = top * (1.0 - ky) + bottom * ky;
+    }
+    result
+}
+
+pub fn create_star_shape_maps(layer: &ImageLayerF32) -> anyhow::Result<StarShapeMaps> {
+    let noise = calc_noise(layer) as f32;
+    let stars = find_stars_on_image(layer, Some(noise), false, &StarsFindOpts::default())?;
+    let shapes = measure_star_shapes(layer, &stars);
+    if shapes.len() < STARS_PER_CELL {
+        anyhow::bail!("Not enough stars to measure ({} found)", shapes.len());
+    }
+    let (width, height) = (layer.width(), layer.height());
+    let cells = (shapes.len() / STARS_PER_CELL).max(1) as f64;
+    let cols = ((cells * width as f64 / height as f64).sqrt().round() as usize).clamp(1, MAX_GRID_SIZE);
+    let rows = ((cols as f64 * height as f64 / width as f64).round() as usize).clamp(1, MAX_GRID_SIZE);
+    let fwhm_values = grid_values(&shapes, width, height, cols, rows, |s| s.fwhm);
+    let ecc_values = grid_values(&shapes, width, height, cols, rows, |s| s.eccentricity);
+    Ok(StarShapeMaps {
+        stars:              shapes.len(),
+        grid:               (cols, rows),
+        fwhm:               interpolate_grid(&fwhm_values, cols, rows, width, height),
+        eccentricity:       interpolate_grid(&ecc_values, cols, rows, width, height),
+        fwhm_zones:         FieldZones::new(&shapes, width, height, |s| s.fwhm),
+        eccentricity_zones: FieldZones::new(&shapes, width, height, |s| s.eccentricity),
+    })
+}
+
+/// Maps for light frame or result of stacking (RAW is demosaiced)
+pub fn create_star_shape_maps_for_file(file_name: &Path) -> anyhow::Result<(StarShapeMaps, ImageInfo)> {
+    let ImageData { image, info } = load_image_from_file(file_name, false)?;
+    let layer = match image {
+        RawOrImage::Image(image) => image.create_greyscale_layer(),
+        RawOrImage::Raw(raw) => raw.demosaic(DemosaicAlgo::Linear, false)?.create_greyscale_layer(),
+    };
+    Ok((create_star_shape_maps(&layer)?, info))
+}
@@ -1,12 +1,18 @@
 use std::collections::HashSet;
 use itertools::*;
+use serde::{Serialize, Deserialize};
 use rayon::prelude::*;
-use crate::{image::*, calc::*, log_utils::*};
+use crate::{image::*, calc::*, log_utils::*, resample::*};
 use std::f64::consts::PI;
 
 pub const MAX_STAR_DIAMETER: Crd = 25; // in pixels
 pub const STAR_BG_BORDER: f32 = 0.001;
 
+/// Stars of images bigger than this (mosaics, drizzled images)
+/// are searched on binned copy and refined at full resolution
+const PYRAMID_MIN_PIXELS: usize = 40_000_000;
+const PYRAMID_TARGET_PIXELS: usize = 12_000_000;
+
 pub struct StarPoint {
     pub x: Crd,
     pub y: Crd,
@@ -38,10 +44,152 @@ impl Star {
 
 pub type Stars = Vec<Star>;
 
+/// Star detection settings. Defaults are good for usual fields.
+/// For dense fields (globular clusters) increase `deblend_level`
+/// and decrease `bg_filter_size` to prevent merging of near stars
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct StarsFindOpts {
+    /// Detection threshold above local background in noise units
+    pub threshold: f32,
+
+    /// Size of local background filter in pixels
+    pub bg_filter_size: f32,
+
+    /// Level of star border between background (0.0) and star peak (1.0)
+    pub deblend_level: f32,
+
+    /// Maximum stars count to keep (0 - no limit). Brightest stars are kept
+    pub max_stars: usize,
+}
+
+impl Default for StarsFindOpts {
+    fn default() -> Self {
+        Self {
+            threshold: 20.0,
+            bg_filter_size: 42.0,
+            deblend_level: 0.5,
+            max_stars: 0,
+        }
+    }
+}
+
 pub fn find_stars_on_image(
     img:             &ImageLayerF32,
     noise:           Option<f32>,
     return_no_error: bool,
+    opts:            &StarsFindOpts,
+) -> anyhow::Result<Stars> {
+    let pixels = img.width() as usize * img.height() as usize;
+    if pixels > PYRAMID_MIN_PIXELS {
+        let factor = ((pixels as f64 / PYRAMID_TARGET_PIXELS as f64).sqrt().ceil() as usize).max(2);
+        find_stars_by_pyramid(img, factor, noise, return_no_error, opts)
+    } else {
+        find_stars_full_res(img, noise, return_no_error, opts)
+    }
+}
+
+/// Stars are found on binned image. Then points of every star
+/// are found again at full resolution near position of binned star
+fn find_stars_by_pyramid(
+    img:             &ImageLayerF32,
+    factor:          usize,
+    noise:           Option<f32>,
+    return_no_error: bool,
+    opts:            &StarsFindOpts,
+) -> anyhow::Result<Stars> {
+    let tmr = TimeLogger::start();
+    let binned = bin_layer(img, factor, BinMode::Average);
+    tmr.log(&format!("binning {}x{} for stars detection", factor, factor));
+
+    // averaging of NxN pixels decreases noise N times
+    let binned_noise = noise.map(|v| v / factor as f32);
+    let coarse_stars = find_stars_full_res(&binned, binned_noise, return_no_error, opts)?;
+
+    let tmr = TimeLogger::start();
+    let max_img_value = img.iter()
+        .copied()
+        .filter(|v| !v.is_infinite())
+        .max_by(cmp_f32)
+        .unwrap_or(1.0);
+    let deblend_level = opts.deblend_level.clamp(0.05, 0.95);
+    let factor = factor as Crd;
+    let mut taken_points: HashSet<(Crd, Crd)> = HashSet::new();
+    let mut flood_filler = FloodFiller::new();
+    let mut star_calc = StarCalulator::new();
+    let mut stars = Stars::new();
+    for coarse in &coarse_stars {
+        // brightest pixel in area of binned star
+        let cx = ((coarse.x + 0.5) * factor as f64) as Crd;
+        let cy = ((coarse.y + 0.5) * factor as f64) as Crd;
+        let half = (coarse.radius.ceil() as Crd + 1) * factor;
+        let mut peak: Option<(Crd, Crd, f32)> = None;
+        for y in cy - half..=cy + half {
+            for x in cx - half..=cx + half {
+                let Some(mut v) = img.get(x, y) else { continue; };
+                if v.is_infinite() { v = max_img_value; }
+                if peak.map(|(_, _, p)| v > p).unwrap_or(true) {
+                    peak = Some((x, y, v));
+                }
+            }
+        }
+        let Some((px, py, peak_value)) = peak else { continue; };
+        if taken_points.contains(&(px, py)) { continue; }
+
+        use MAX_STAR_DIAMETER as MSD;
+        let star_bg = coarse.background;
+        let border_value = star_bg + (peak_value - star_bg) * deblend_level;
+        let mut star_points = HashSet::new();
+        flood_filler.fill(px, py, |x, y| {
+            if star_points.len() as i64 > MSD*MSD { return false; }
+            if taken_points.contains(&(x, y)) || star_points.contains(&(x, y)) { return false; }
+            match img.get(x, y) {
+                Some(v) if v >= border_value => {},
+                _ => return false,
+            }
+            star_points.insert((x, y));
+            true
+        });
+        if star_points.is_empty() || star_points.len() as i64 > MSD*MSD {
+            continue;
+        }
+        let Ok((center_x, center_y, br, radius, radius_dev)) = star_calc.calc_center_brightness_radius_and_deviation(
+            img, star_bg, &star_points, true, max_img_value
+        ) else {
+            continue;
+        };
+
+        taken_points.extend(star_points.iter().copied());
+        let min_x = star_points.iter().map(|p| p.0).min().unwrap_or(px);
+        let max_x = star_points.iter().map(|p| p.0).max().unwrap_or(px);
+        let min_y = star_points.iter().map(|p| p.1).min().unwrap_or(py);
+        let max_y = star_points.iter().map(|p| p.1).max().unwrap_or(py);
+        stars.push(Star {
+            x:              center_x,
+            y:              center_y,
+            background:     star_bg,
+            max_value:      peak_value,
+            width:          max_x - min_x + 1,
+            height:         max_y - min_y + 1,
+            brightness:     br,
+            radius:         radius as f32,
+            radius_std_dev: radius_dev as f32,
+            overexposured:  false,
+            points:         star_points.iter().map(|pt| StarPoint { x: pt.0, y: pt.1 }).collect(),
+        });
+    }
+    tmr.log("refinement of stars at full resolution");
+    log::info!("{} of {} stars refined at full resolution", stars.len(), coarse_stars.len());
+
+    stars.sort_by(|s1, s2| cmp_f64(&s1.brightness, &s2.brightness).reverse());
+    Ok(stars)
+}
+
+fn find_stars_full_res(
+    img:             &ImageLayerF32,
+    noise:           Option<f32>,
+    return_no_error: bool,
+    opts:            &StarsFindOpts,
 ) -> anyhow::Result<Stars> {
     let max_img_value = img.iter()
         .copied()
@@ -50,7 +198,7 @@ pub fn find_stars_on_image(
         .unwrap_or(1.0);
     let border = match noise {
         Some(noise) if noise != 0.0 =>
-            (noise * 20.0).max(STAR_BG_BORDER * max_img_value),
+            (noise * opts.threshold).max(STAR_BG_BORDER * max_img_value),
         _ => {
             STAR_BG_BORDER * max_img_value
         },
@@ -63,7 +211,7 @@ pub fn find_stars_on_image(
     let tmr = TimeLogger::start();
     let mut filtered: Vec<f32> = Vec::new();
     let mut possible_stars = Vec::new();
-    let mut heavy_filter = IirFilter::new_gauss(42.0);
+    let mut heavy_filter = IirFilter::new_gauss(opts.bg_filter_size);
     let mut heavy_filtered: Vec<f32> = Vec::new();
     heavy_filtered.resize(img.width() as usize, 0.0);
     img.foreach_row_and_col(IterType::Rows, |values, y, _| {
@@ -161,8 +309,10 @@ pub fn find_stars_on_image(
 
         if (possible_max_value - star_bg) < border { continue };
 
-        // Find all points of star. Border is as 1/2 of brightness of star center
-        let border_value = (possible_max_value + star_bg) / 2.0;
+        // Find all points of star. Border is between background and brightness
+        // of star center (1/2 by default)
+        let deblend_level = opts.deblend_level.clamp(0.05, 0.95);
+        let border_value = star_bg + (possible_max_value - star_bg) * deblend_level;
         let mut star_points = HashSet::new();
 
         flood_filler.fill(
@@ -280,9 +430,31 @@ pub fn find_stars_on_image(
 
     stars.sort_by(|s1, s2| cmp_f64(&s1.brightness, &s2.brightness).reverse());
 
+    if opts.max_stars != 0 {
+        stars.truncate(opts.max_stars);
+    }
+
     Ok(stars)
 }
 
+/// Mask of stars pixels. Every star is extended by `grow` pixels
+pub fn create_stars_mask(
+    width:  Crd,
+    height: Crd,
+    stars:  &Stars,
+    grow:   Crd,
+) -> ImageLayer<bool> {
+    let mut mask = ImageLayer::<bool>::new(width, height);
+    for star in stars {
+        for &StarPoint{x, y} in &star.points {
+            for dy in -grow..=grow { for dx in -grow..=grow {
+                mask.set_safe(x + dx, y + dy, true);
+            }}
+        }
+    }
+    mask
+}
+
 pub fn set_stars_overexposured_flag(
     stars: &mut Stars,
     img:   &Image
@@ -392,7 +564,7 @@ impl StarCalulator {
 }
 
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ImageOffset {
     pub offset_x: f64,
     pub offset_y: f64,
@@ -575,6 +747,135 @@ pub fn calc_image_offset_by_stars(
     })
 }
 
+/// Offset of image with `stars` relative to image with `ref_stars` by
+/// triangles of stars with increasing number of stars and triangulation
+pub fn find_image_offset_by_stars(
+    ref_stars:  &Stars,
+    stars:      &Stars,
+    img_width:  f64,
+    img_height: f64,
+) -> Option<ImageOffset> {
+    for (max_stars, find_triangle_max_err, triangulation) in [
+        (50,  5.0, false),
+        (100, 3.0, false),
+        (200, 3.0, false),
+        (50,  3.0, true),
+        (100, 3.0, true),
+    ] {
+        let offset = calc_image_offset_by_stars(
+            ref_stars, stars, img_width, img_height,
+            max_stars, find_triangle_max_err, triangulation
+        );
+        if offset.is_some() {
+            return offset;
+        }
+    }
+    None
+}
+
+pub fn calc_image_offset_by_stars_translation(
+    ref_stars:  &Stars,
+    stars:      &Stars,
+    img_width:  f64,
+    img_height: f64,
+    max_stars:  usize,
+    max_err:    f64,
+    angle_hint: f64, // predicted rotation, for example from field rotation model
+) -> Option<ImageOffset> {
+    log::info!(
+        "-*= Translation only align calculation. max_stars={}, max_err={}, angle_hint={:.3}° =*-",
+        max_stars, max_err, 180.0 * angle_hint / PI
+    );
+
+    let ref_stars = &ref_stars[..usize::min(max_stars, ref_stars.len())];
+    let stars = &stars[..usize::min(max_stars, stars.len())];
+    if ref_stars.len() < 3 || stars.len() < 3 { return None; }
+
+    // Stars coordinates with predicted rotation removed
+
+    let center_x = (img_width - 1.0) / 2.0;
+    let center_y = (img_height - 1.0) / 2.0;
+    let hinted: Vec<_> = stars
+        .iter()
+        .map(|s| rotate_point(s.x, s.y, center_x, center_y, -angle_hint))
+        .collect();
+
+    // Voting for offset between all pairs of brightest stars
+
+    let mut votes = std::collections::HashMap::<(i64, i64), usize>::new();
+    for ref_star in ref_stars { for (x, y) in &hinted {
+        let key = (
+            ((x - ref_star.x) / max_err).round() as i64,
+            ((y - ref_star.y) / max_err).round() as i64,
+        );
+        *votes.entry(key).or_insert(0) += 1;
+    }}
+    let ((vote_x, vote_y), _) = votes.iter().max_by_key(|(_, cnt)| **cnt)?;
+    let approx_dx = *vote_x as f64 * max_err;
+    let approx_dy = *vote_y as f64 * max_err;
+
+    // Stars pairs matched by approximate offset
+
+    let max_dist = 2.0 * max_err;
+    let mut pairs = Vec::new();
+    for ref_star in ref_stars {
+        let x = ref_star.x + approx_dx;
+        let y = ref_star.y + approx_dy;
+        let nearest = hinted
+            .iter()
+            .map(|(hx, hy)| (*hx, *hy, f64::hypot(hx - x, hy - y)))
+            .filter(|(.., dist)| *dist < max_dist)
+            .min_by(|(.., d1), (.., d2)| cmp_f64(d1, d2));
+        if let Some((hx, hy, _)) = nearest {
+            pairs.push((ref_star, hx, hy));
+        }
+    }
+    log::info!("matched stars pairs = {}", pairs.len());
+    if pairs.len() < 3 { return None; }
+
+    // Small rotation between images (accumulated field rotation)
+    // which is not covered by angle_hint
+
+    let cnt = pairs.len() as f64;
+    let ref_cx = pairs.iter().map(|(r, ..)| r.x).sum::<f64>() / cnt;
+    let ref_cy = pairs.iter().map(|(r, ..)| r.y).sum::<f64>() / cnt;
+    let cx = pairs.iter().map(|(_, x, _)| *x).sum::<f64>() / cnt;
+    let cy = pairs.iter().map(|(.., y)| *y).sum::<f64>() / cnt;
+    let mut sum_cross = 0_f64;
+    let mut sum_dot = 0_f64;
+    for (ref_star, x, y) in &pairs {
+        let (rx, ry) = (ref_star.x - ref_cx, ref_star.y - ref_cy);
+        let (sx, sy) = (x - cx, y - cy);
+        sum_cross += rx * sy - ry * sx;
+        sum_dot += rx * sx + ry * sy;
+    }
+    let residual_angle = if pairs.len() >= 5 { f64::atan2(sum_cross, sum_dot) } else { 0.0 };
+
+    let mut x_offsets = Vec::new();
+    let mut y_offsets = Vec::new();
+    for (ref_star, x, y) in &pairs {
+        let (on_ref_x, on_ref_y) = rotate_point(*x, *y, center_x, center_y, -residual_angle);
+        x_offsets.push(CalcValue::new(on_ref_x - ref_star.x));
+        y_offsets.push(CalcValue::new(on_ref_y - ref_star.y));
+    }
+    let offset_x = cappa_sigma_weighted_result(&mut x_offsets, 2.0, 10, true, true)?.result;
+    let offset_y = cappa_sigma_weighted_result(&mut y_offsets, 2.0, 10, true, true)?.result;
+
+    let (_, x_dev) = mean_and_std_dev(&x_offsets)?;
+    let (_, y_dev) = mean_and_std_dev(&y_offsets)?;
+    log::info!("x_dev = {:.2}, y_dev = {:.2}", x_dev, y_dev);
+    if x_dev > max_err || y_dev > max_err {
+        return None;
+    }
+
+    Some(ImageOffset {
+        angle: angle_hint + residual_angle,
+        offset_x,
+        offset_y,
+        ratio: 1.0,
+    })
+}
+
 #[derive(Clone)]
 struct StarsTriangle<'a> {
     stars: [&'a Star; 3],
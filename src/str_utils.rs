@@ -1,12 +1,26 @@
-use std::{fmt::Debug, path::Path};
-use gettextrs::*;
+use std::{fmt::Debug, path::Path, sync::OnceLock};
+
+static TRANSLATOR: OnceLock<fn(&str) -> String> = OnceLock::new();
+
+/// Sets function translating messages of library (gettext of GUI).
+/// Without it messages are not translated
+pub fn set_translator(translator: fn(&str) -> String) {
+    _ = TRANSLATOR.set(translator);
+}
+
+pub fn tr(text: &str) -> String {
+    match TRANSLATOR.get() {
+        Some(translator) => translator(text),
+        None => text.to_string(),
+    }
+}
 
 pub fn debug_to_str<T: Debug>(value: &T) -> String {
     format!("{:?}", value)
 }
 
 pub fn transl_and_replace(text: &str, items: &[(&str, String)]) -> String {
-    let mut result = gettext(text);
+    let mut result = tr(text);
     for (from, to) in items {
         result = result.replace(from, to);
     }
@@ -15,4 +29,22 @@ pub fn transl_and_replace(text: &str, items: &[(&str, String)]) -> String {
 
 pub fn path_to_string(path: &Path) -> String {
     path.to_str().unwrap_or_default().to_string()
-}
\ No newline at end of file
+}
+
+/// Values of CSV line. Values can be quoted ("" is quote inside value)
+pub fn parse_csv_line(line: &str, separator: char) -> Vec<String> {
+    let mut result = Vec::new();
+    let mut value = String::new();
+    let mut in_quotes = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if in_quotes && chars.peek() == Some(&'"') => { value.push('"'); chars.next(); },
+            '"' => in_quotes = !in_quotes,
+            c if c == separator && !in_quotes => result.push(std::mem::take(&mut value)),
+            _ => value.push(c),
+        }
+    }
+    result.push(value);
+    result
+}
@@ -0,0 +1,237 @@
+use crate::{calc::*, project::*, stars::*};
+
+/* Suggestion of stacking parameters by registration info of light files */
+
+pub struct Suggestion {
+    pub param:  &'static str,
+    pub value:  String,
+    pub reason: String,
+}
+
+struct LightsSummary {
+    count:      usize,
+    registered: usize,
+    max_exp:    Option<f32>,
+    has_cfa:    bool,
+    max_width:  usize,
+    stars:      Option<f64>,
+    fwhm:       Option<f64>,
+    noise_dev:  Option<f64>, // relative deviation of noise between files
+}
+
+impl LightsSummary {
+    fn new(project: &Project) -> Self {
+        let files = project.groups().iter()
+            .filter(|g| g.used())
+            .flat_map(|g| g.light_files.list().iter())
+            .filter(|f| f.used());
+
+        let mut count = 0;
+        let mut max_exp: Option<f32> = None;
+        let mut has_cfa = false;
+        let mut max_width = 0;
+        let mut stars = Vec::new();
+        let mut fwhm = Vec::new();
+        let mut noise = Vec::new();
+        for file in files {
+            count += 1;
+            if let Some(exp) = file.exp() {
+                max_exp = Some(max_exp.map(|v| v.max(*exp)).unwrap_or(*exp));
+            }
+            has_cfa |= file.cfa_type().is_some();
+            max_width = max_width.max(file.width().unwrap_or(0));
+            if let Some(reg_info) = file.reg_info() {
+                stars.push(reg_info.stars as f64);
+                fwhm.push(reg_info.fwhm as f64);
+                noise.push(CalcValue::new(reg_info.noise as f64));
+            }
+        }
+        let noise_dev = mean_and_std_dev(&noise)
+            .filter(|(mean, _)| *mean != 0.0)
+            .map(|(mean, dev)| dev / mean);
+
+        Self {
+            count,
+            registered: stars.len(),
+            max_exp,
+            has_cfa,
+            max_width,
+            stars: median_f64(&mut stars),
+            fwhm: median_f64(&mut fwhm),
+            noise_dev,
+        }
+    }
+}
+
+fn calc_opts_str(opts: &CalcOpts) -> String {
+    match opts.mode {
+        CalcMode::CappaSigma => format!("kappa-sigma (kappa={}, repeats={})", opts.kappa, opts.repeats),
+        CalcMode::Median => "median".to_string(),
+        CalcMode::Mean => "mean".to_string(),
+    }
+}
+
+fn suggest_calc_opts(frames: usize) -> (CalcOpts, String) {
+    if frames < 3 {
+        (
+            CalcOpts { mode: CalcMode::Mean, .. CalcOpts::default() },
+            format!("only {} frame(s), outliers rejection is not possible", frames)
+        )
+    } else if frames < 8 {
+        (
+            CalcOpts { mode: CalcMode::Median, .. CalcOpts::default() },
+            format!("{} frames are too few for kappa-sigma clipping", frames)
+        )
+    } else if frames < 20 {
+        (
+            CalcOpts { mode: CalcMode::CappaSigma, kappa: 3.0, repeats: 3 },
+            format!("{} frames, soft clipping to keep signal", frames)
+        )
+    } else {
+        (
+            CalcOpts { mode: CalcMode::CappaSigma, kappa: 2.5, repeats: 5 },
+            format!("{} frames are enough for kappa-sigma clipping", frames)
+        )
+    }
+}
+
+/// Returns suggested configuration together with list of changed
+/// parameters and reasons of changes. Light files have to be registered
+/// to get suggestions about alignment and stars detection
+pub fn suggest_project_config(project: &Project) -> anyhow::Result<(ProjectConfig, Vec<Suggestion>)> {
+    let summary = LightsSummary::new(project);
+    if summary.count == 0 {
+        anyhow::bail!("No light files in project");
+    }
+
+    let mut config = project.config().clone();
+    let mut result = Vec::new();
+
+    // Stacking of light files
+
+    let (light_opts, reason) = suggest_calc_opts(summary.count);
+    if light_opts != config.light_calc_opts {
+        result.push(Suggestion {
+            param: "light_calc_opts",
+            value: calc_opts_str(&light_opts),
+            reason,
+        });
+        config.light_calc_opts = light_opts;
+    }
+
+    // Master files
+
+    let count_files = |file_type| project.groups().iter()
+        .filter(|g| g.used())
+        .map(|g| g.get_file_list_by_type(file_type).get_checked_count())
+        .max()
+        .unwrap_or(0);
+
+    let masters = [
+        (ProjectFileType::Dark, "dark_calc_opts", &mut config.dark_calc_opts),
+        (ProjectFileType::Flat, "flat_calc_opts", &mut config.flat_calc_opts),
+        (ProjectFileType::Bias, "bias_calc_opts", &mut config.bias_calc_opts),
+    ];
+    for (file_type, param, opts) in masters {
+        let count = count_files(file_type);
+        if count == 0 { continue; }
+        let (new_opts, reason) = suggest_calc_opts(count);
+        if new_opts != *opts {
+            result.push(Suggestion { param, value: calc_opts_str(&new_opts), reason });
+            *opts = new_opts;
+        }
+    }
+
+    // Color
+
+    if summary.has_cfa && !config.align_rgb {
+        config.align_rgb = true;
+        result.push(Suggestion {
+            param: "align_rgb",
+            value: "true".to_string(),
+            reason: "color camera: compensates atmospheric dispersion".to_string(),
+        });
+    }
+
+    // Short exposures
+
+    let short_exp = matches!(summary.max_exp, Some(exp) if exp < 1.0);
+    if short_exp && config.align_mode == AlignMode::Triangles {
+        config.align_mode = AlignMode::Translation;
+        result.push(Suggestion {
+            param: "align_mode",
+            value: "translation".to_string(),
+            reason: format!(
+                "max exposure is {:.2}s, no rotation between frames expected",
+                summary.max_exp.unwrap_or_default()
+            ),
+        });
+    }
+
+    if summary.registered != summary.count {
+        log::info!(
+            "Only {} of {} light files are registered. Register files to get more suggestions",
+            summary.registered, summary.count
+        );
+        return Ok((config, result));
+    }
+
+    // Stars
+
+    let stars = summary.stars.unwrap_or(0.0);
+    if stars > 2000.0 {
+        let opts = StarsFindOpts {
+            deblend_level: 0.7,
+            bg_filter_size: 21.0,
+            max_stars: 2000,
+            .. config.stars_opts.clone()
+        };
+        if opts != config.stars_opts {
+            result.push(Suggestion {
+                param: "stars_opts",
+                value: format!(
+                    "deblend_level={}, bg_filter_size={}, max_stars={}",
+                    opts.deblend_level, opts.bg_filter_size, opts.max_stars
+                ),
+                reason: format!("dense star field (median {:.0} stars per frame)", stars),
+            });
+            config.stars_opts = opts;
+        }
+    }
+
+    if stars >= 50.0 && !config.skip_bad_lights {
+        config.skip_bad_lights = true;
+        config.min_stars_in_light = (stars / 5.0) as usize;
+        result.push(Suggestion {
+            param: "skip_bad_lights, min_stars_in_light",
+            value: format!("true, {}", config.min_stars_in_light),
+            reason: "skips frames damaged by clouds or tracking errors".to_string(),
+        });
+    }
+
+    // Undersampled or oversampled images
+
+    let fwhm = summary.fwhm.unwrap_or(0.0);
+    if fwhm > 5.0 && summary.max_width > 4000 && config.image_size == ImageSize::Original {
+        config.image_size = ImageSize::Bin2x2;
+        result.push(Suggestion {
+            param: "image_size",
+            value: "bin2x2".to_string(),
+            reason: format!("oversampled image (median FWHM is {:.1} px)", fwhm),
+        });
+    }
+
+    // Reference image
+
+    let noise_dev = summary.noise_dev.unwrap_or(0.0);
+    if noise_dev > 0.3 && !matches!(config.ref_image_auto_mode, RefImageAutoMode::NinNoise) {
+        config.ref_image_auto_mode = RefImageAutoMode::NinNoise;
+        result.push(Suggestion {
+            param: "ref_image_auto_mode",
+            value: "min noise".to_string(),
+            reason: format!("noise differs by {:.0}% between frames", 100.0 * noise_dev),
+        });
+    }
+
+    Ok((config, result))
+}
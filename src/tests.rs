@@ -38,4 +38,1277 @@ fn area_iter() {
     }
 }
 
-} // mod tests
\ No newline at end of file
+// Malformed files must give errors but not panics
+#[test]
+fn malformed_files() {
+    use rand::prelude::*;
+    use crate::{image_io::*, xisf::*, ser::*};
+
+    let dir = std::env::temp_dir().join(format!("electra_malformed_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let xisf_file = dir.join("src.xisf");
+    save_image_to_xisf_file(&Image::new_color(16, 8), &ImageInfo::default(), &xisf_file).unwrap();
+    let xisf_data = std::fs::read(&xisf_file).unwrap();
+
+    let mut ser_data = b"LUCAM-RECORDER".to_vec();
+    for v in [0_i32, 0, 0, 8, 4, 8, 2] { ser_data.extend(v.to_le_bytes()); }
+    ser_data.extend([0_u8; 3 * 40 + 2 * 8]);
+    ser_data.extend([100_u8; 2 * 8 * 4]);
+
+    let mut rng = StdRng::seed_from_u64(42);
+    for (src_data, ext) in [(&xisf_data, "xisf"), (&ser_data, "ser")] {
+        let file_name = dir.join(format!("test.{}", ext));
+        for _ in 0..500 {
+            let mut data = src_data.clone();
+            if rng.gen_bool(0.3) {
+                data.truncate(rng.gen_range(0..data.len()));
+            }
+            for _ in 0..rng.gen_range(1..8) {
+                if data.is_empty() { break; }
+                let pos = rng.gen_range(0..data.len());
+                data[pos] = rng.gen();
+            }
+            std::fs::write(&file_name, &data).unwrap();
+            if ext == "ser" {
+                if let Ok(mut ser) = SerFile::open(&file_name) {
+                    for i in 0..ser.frames.min(4) { _ = ser.read_frame_u16(i); }
+                }
+            } else {
+                _ = load_image_from_file(&file_name, false);
+            }
+        }
+    }
+    _ = std::fs::remove_dir_all(&dir);
+}
+
+// Truncated headers and huge declared sizes must be rejected by checks
+// of safe_read before allocation and reading of image data
+#[test]
+fn malformed_headers() {
+    use crate::{image_io::*, ser::*};
+
+    let dir = std::env::temp_dir().join(format!("electra_headers_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    fn error_text<T>(result: anyhow::Result<T>) -> String {
+        format!("{:?}", result.err().expect("Error expected"))
+    }
+    let load = |name: &str, data: &[u8]| {
+        let file_name = dir.join(name);
+        std::fs::write(&file_name, data).unwrap();
+        load_image_from_file(&file_name, false)
+    };
+
+    // SER
+    let ser_header = |width: i32, height: i32, frames: i32| {
+        let mut data = b"LUCAM-RECORDER".to_vec();
+        for v in [0_i32, 0, 0, width, height, 16, frames] { data.extend(v.to_le_bytes()); }
+        data.extend([0_u8; 3 * 40 + 2 * 8]);
+        data
+    };
+    let open_ser = |data: &[u8]| {
+        let file_name = dir.join("test.ser");
+        std::fs::write(&file_name, data).unwrap();
+        SerFile::open(&file_name)
+    };
+    assert!(open_ser(&ser_header(8, 8, 1)[..100]).is_err());
+    assert!(error_text(open_ser(&ser_header(100_000, 100_000, 1))).contains("too big"));
+    assert!(error_text(open_ser(&ser_header(1000, 1000, 1_000_000))).contains("out of file"));
+
+    // XISF
+    let xisf = |header_len: u32, header: &str| {
+        let mut data = b"XISF0100".to_vec();
+        data.extend(header_len.to_le_bytes());
+        data.extend(0_u32.to_le_bytes());
+        data.extend(header.as_bytes());
+        data
+    };
+    let image_header = |geometry: &str, location: &str| format!(
+        "<xisf><Image geometry=\"{}\" sampleFormat=\"Float32\" location=\"{}\"/></xisf>",
+        geometry, location
+    );
+    assert!(error_text(load("test.xisf", &xisf(u32::MAX, "<xisf>"))).contains("too big"));
+    assert!(error_text(load("test.xisf", &xisf(1000, "<xisf>"))).contains("truncated"));
+    let header = image_header("100000:100000:3", "attachment:4096:16");
+    assert!(error_text(load("test.xisf", &xisf(header.len() as u32, &header))).contains("too big"));
+    let header = image_header("16:8:1", "attachment:1000000:512");
+    assert!(error_text(load("test.xisf", &xisf(header.len() as u32, &header))).contains("out of file"));
+
+    // FITS
+    let fits_header = |cards: &[&str]| {
+        let mut data = Vec::new();
+        for card in cards {
+            data.extend(format!("{:<80}", card).as_bytes());
+        }
+        data
+    };
+    let simple = "SIMPLE  =                    T";
+    let bitpix = "BITPIX  =                   16";
+    let naxis = "NAXIS   =                    2";
+    assert!(load("test.fit", &fits_header(&[simple, bitpix])).is_err());
+    let mut huge = fits_header(&[
+        simple, bitpix, naxis, "NAXIS1  =               100000", "NAXIS2  =               100000", "END"
+    ]);
+    huge.resize(2880, b' ');
+    assert!(load("test.fit", &huge).is_err());
+
+    // TIFF (little endian, one strip of 16 bit grey image)
+    let tiff = |width: u32, height: u32| {
+        let mut data = b"II*\0".to_vec();
+        data.extend(8_u32.to_le_bytes());
+        let entries: &[(u16, u16, u32)] = &[
+            (256, 4, width), (257, 4, height), (258, 3, 16), (259, 3, 1), (262, 3, 1),
+            (273, 4, 8), (277, 3, 1), (278, 4, height), (279, 4, 0),
+        ];
+        data.extend((entries.len() as u16).to_le_bytes());
+        for &(tag, typ, value) in entries {
+            data.extend(tag.to_le_bytes());
+            data.extend(typ.to_le_bytes());
+            data.extend(1_u32.to_le_bytes());
+            data.extend(value.to_le_bytes());
+        }
+        data.extend(0_u32.to_le_bytes());
+        data
+    };
+    assert!(load("test.tif", &tiff(16, 8)[..20]).is_err());
+    assert!(load("test.tif", &tiff(100_000, 100_000)).is_err());
+
+    _ = std::fs::remove_dir_all(&dir);
+}
+
+fn layer_sum(layer: &ImageLayerF32) -> f64 {
+    layer.iter().map(|v| *v as f64).sum()
+}
+
+fn add_star(layer: &mut ImageLayerF32, cx: f64, cy: f64, sigma: f64, ampl: f32) {
+    for (x, y, v) in layer.iter_crd_mut() {
+        let r2 = (x as f64 - cx).powi(2) + (y as f64 - cy).powi(2);
+        *v += ampl * (-r2 / (2.0 * sigma * sigma)).exp() as f32;
+    }
+}
+
+#[test]
+fn resampling_keeps_flux() {
+    use rand::prelude::*;
+    let mut rng = StdRng::seed_from_u64(1);
+
+    for _ in 0..20 {
+        let (width, height) = (2 * rng.gen_range(1..50), 2 * rng.gen_range(1..50));
+        let mut layer = ImageLayerF32::new(width, height);
+        for v in layer.iter_mut() { *v = rng.gen(); }
+        let reduced = layer.decrease_2x();
+        let mean = layer_sum(&layer) / (width * height) as f64;
+        let reduced_mean = layer_sum(&reduced) / (width * height / 4) as f64;
+        assert!((mean - reduced_mean).abs() < 1e-5);
+    }
+
+    // star far from borders is shifted and rotated
+    for _ in 0..20 {
+        let mut layer = ImageLayerF32::new(64, 64);
+        let sigma = rng.gen_range(1.0..3.0);
+        add_star(&mut layer, rng.gen_range(28.0..36.0), rng.gen_range(28.0..36.0), sigma, 0.5);
+        let moved = layer.rotated_and_translated(
+            rng.gen_range(-0.1..0.1),
+            rng.gen_range(-3.0..3.0),
+            rng.gen_range(-3.0..3.0),
+            0.0, 64, 64
+        );
+        assert!((layer_sum(&moved) / layer_sum(&layer) - 1.0).abs() < 0.01);
+    }
+
+    // flux conserving resampling keeps total flux exactly
+    use crate::resample::*;
+    for _ in 0..20 {
+        let (width, height) = (rng.gen_range(5..60), rng.gen_range(5..60));
+        let mut layer = ImageLayerF32::new(width, height);
+        for v in layer.iter_mut() { *v = rng.gen(); }
+        let scale = rng.gen_range(0.3..2.5);
+        let (res_width, res_height) = ((width as f64 * scale).round() as Crd, (height as f64 * scale).round() as Crd);
+        if res_width < 1 || res_height < 1 { continue; }
+        let resized = resize_layer(&layer, res_width, res_height, ResampleKernel::FluxConserving);
+        assert!((layer_sum(&resized) / layer_sum(&layer) - 1.0).abs() < 1e-4);
+
+        let angle: f64 = rng.gen_range(-0.5..0.5);
+        let (cos_a, sin_a) = (angle.cos(), angle.sin());
+        let inv = [[cos_a, -sin_a, 0.3], [sin_a, cos_a, -0.7]];
+        let mut star = ImageLayerF32::new(64, 64);
+        add_star(&mut star, 32.0, 32.0, 2.0, 0.5);
+        let warped = warp_layer_flux_conserving(&star, &inv, 64, 64, 0.0);
+        assert!((layer_sum(&warped) / layer_sum(&star) - 1.0).abs() < 1e-4);
+    }
+}
+
+#[test]
+fn preview_stretch_is_monotonic() {
+    use rand::prelude::*;
+    use crate::preview::*;
+    let mut rng = StdRng::seed_from_u64(2);
+    let mut image = Image::new_grey(40, 30);
+    for v in image.l.iter_mut() {
+        *v = if rng.gen_bool(0.05) { rng.gen() } else { rng.gen_range(0.05..0.07) };
+    }
+    for stretch in [PreviewStretch::Mtf, PreviewStretch::Asinh] {
+        let opts = PreviewOpts { stretch, ..Default::default() };
+        let (_, _, bytes) = create_stretched_preview(&image, &opts);
+        let mut pairs: Vec<_> = image.l.iter().copied().zip(bytes).collect();
+        pairs.sort_by(|a, b| a.0.total_cmp(&b.0));
+        assert!(pairs.windows(2).all(|w| w[0].1 <= w[1].1));
+    }
+}
+
+#[test]
+fn gradient_removal_golden() {
+    use rand::prelude::*;
+    use crate::{gradient::*, golden::*};
+    let mut rng = StdRng::seed_from_u64(3);
+    let (width, height) = (128, 96);
+    let mut image = Image::new_grey(width, height);
+    for (x, y, v) in image.l.iter_crd_mut() {
+        let (x, y) = (x as f32 / width as f32, y as f32 / height as f32);
+        *v = 0.1 + 0.05 * x + 0.03 * y * y + rng.gen_range(-0.005..0.005);
+    }
+    for _ in 0..10 {
+        let (x, y) = (rng.gen_range(5.0..123.0), rng.gen_range(5.0..91.0));
+        add_star(&mut image.l, x, y, 1.5, rng.gen_range(0.1..0.5));
+    }
+    remove_gradient(&mut image, &GradientOpts::default(), None).unwrap();
+
+    // background must be flat
+    let mut left: Vec<_> = image.l.iter_rect_crd(0, 0, 31, 95).map(|(_, _, v)| v).collect();
+    let mut right: Vec<_> = image.l.iter_rect_crd(96, 0, 127, 95).map(|(_, _, v)| v).collect();
+    let diff = crate::calc::median_f32(&mut left).unwrap() - crate::calc::median_f32(&mut right).unwrap();
+    assert!(diff.abs() < 0.003);
+
+    check_golden_image("gradient_removal", &image, Tolerance { abs: 1e-4, rel: 1e-3 });
+}
+
+#[test]
+fn tiled_gradient_removal() {
+    use rand::prelude::*;
+    use crate::gradient::*;
+    let mut rng = StdRng::seed_from_u64(5);
+    let (width, height) = (512, 384);
+    let mut image = Image::new_grey(width, height);
+    for (x, y, v) in image.l.iter_crd_mut() {
+        let (x, y) = (x as f32 / width as f32, y as f32 / height as f32);
+        *v = 0.1 + 0.08 * x + 0.05 * y * y + rng.gen_range(-0.005..0.005);
+        // empty corner of mosaic
+        if x > 0.8 && y > 0.7 { *v = NO_VALUE_F32; }
+    }
+    for _ in 0..40 {
+        let (x, y) = (rng.gen_range(5.0..400.0), rng.gen_range(5.0..260.0));
+        add_star(&mut image.l, x, y, 1.5, rng.gen_range(0.1..0.5));
+    }
+    let opts = GradientOpts { tile: Some(128), grid: 8, keep_model: false, ..GradientOpts::default() };
+    let result = remove_gradient(&mut image, &opts, None).unwrap();
+    assert!(result.model.is_none());
+    let median = |x1, y1, x2, y2| {
+        let mut values: Vec<_> = image.l.iter_rect_crd(x1, y1, x2, y2).map(|(_, _, v)| v).collect();
+        crate::calc::median_f32(&mut values).unwrap()
+    };
+    let left = median(0, 0, 63, 383);
+    let right = median(320, 0, 383, 255);
+    let bottom = median(0, 320, 255, 383);
+    assert!((left - right).abs() < 0.003);
+    assert!((left - bottom).abs() < 0.003);
+    assert_eq!(image.l.get(500, 380), Some(NO_VALUE_F32));
+}
+
+#[test]
+fn bright_objects_mask() {
+    use rand::prelude::*;
+    use crate::bg_mask::*;
+    let mut rng = StdRng::seed_from_u64(7);
+    let mut layer = ImageLayerF32::new(200, 150);
+    for v in layer.iter_mut() {
+        *v = 0.1 + rng.gen_range(-0.01..0.01);
+    }
+    add_star(&mut layer, 60.0, 70.0, 15.0, 0.05); // nebula
+    let mut mask = ImageMask::new(200, 150);
+    mask_bright_objects(&layer, &BgMaskPreset::Objects.objects_opts().unwrap(), &mut mask);
+    assert_eq!(mask.get(60, 70), Some(true));
+    assert_eq!(mask.get(180, 20), Some(false));
+    let masked = mask.iter().filter(|v| **v).count();
+    assert!(masked < 200 * 150 / 3);
+    assert!(BgMaskPreset::Stars.objects_opts().is_none());
+}
+
+#[test]
+fn binning_and_rescaling() {
+    use crate::resample::*;
+    let mut layer = ImageLayerF32::new(7, 6);
+    for (x, y, v) in layer.iter_crd_mut() {
+        *v = (x + 10 * y) as f32;
+    }
+    let binned = bin_layer(&layer, 3, BinMode::Average);
+    assert_eq!((binned.width(), binned.height()), (2, 2));
+    assert_eq!(binned.get(1, 1), Some(4.0 + 40.0));
+    assert_eq!(bin_layer(&layer, 2, BinMode::Sum).get(0, 0), Some(22.0));
+
+    // constant image stays constant for any kernel
+    let image = Image { l: ImageLayerF32::new_from_vec(40, 30, vec![0.25; 1200]), ..Image::new() };
+    for kernel in [ResampleKernel::Bicubic, ResampleKernel::Lanczos3, ResampleKernel::Lanczos4, ResampleKernel::BSpline] {
+        for scale in [0.3, 1.7] {
+            let result = rescale_image(&image, scale, kernel).unwrap();
+            assert!(result.l.iter().all(|v| (v - 0.25).abs() < 1e-5));
+        }
+    }
+}
+
+#[test]
+fn geometry_keeps_wcs() {
+    use crate::{geometry::*, wcs::*};
+    let mut image = Image::new_grey(6, 4);
+    for (x, y, v) in image.l.iter_crd_mut() {
+        *v = (1 + x + 10 * y) as f32;
+    }
+    let wcs = Wcs { crval: [83.8, -5.4], crpix: [3.5, 2.0], cd: [[-3e-4, 1e-5], [1e-5, 3e-4]] };
+    let ops = [
+        GeometryOp::Rotate(90.0),
+        GeometryOp::FlipH,
+        GeometryOp::Crop { x: 1, y: 1, width: 3, height: 4 },
+    ];
+    let result = apply_geometry(Image { l: image.l.clone(), ..Image::new() }, &ops).unwrap();
+    let new_wcs = transform_wcs(&wcs, &result.transform).unwrap();
+    for (x, y, v) in result.image.l.iter_crd() {
+        let (sx, sy) = transform_apply(&result.transform, x, y);
+        assert_eq!(image.l.get(sx, sy), Some(v));
+        let (ra1, dec1) = wcs.pixel_to_world(sx as f64, sy as f64);
+        let (ra2, dec2) = new_wcs.pixel_to_world(x as f64, y as f64);
+        assert!((ra1 - ra2).abs() < 1e-9 && (dec1 - dec2).abs() < 1e-9);
+    }
+
+    fn transform_apply(t: &PixelTransform, x: Crd, y: Crd) -> (Crd, Crd) {
+        let (x, y) = (x as f64, y as f64);
+        (
+            (t.a[0][0] * x + t.a[0][1] * y + t.t[0]).round() as Crd,
+            (t.a[1][0] * x + t.a[1][1] * y + t.t[1]).round() as Crd,
+        )
+    }
+}
+
+#[test]
+fn common_coverage_of_stacks() {
+    use crate::geometry::*;
+    let mut l_stack = Image::new_grey(10, 8);
+    let mut ha_stack = Image::new_grey(10, 8);
+    l_stack.l.iter_mut().for_each(|v| *v = 0.5);
+    ha_stack.l.iter_mut().for_each(|v| *v = 0.5);
+    for y in 0..8 {
+        l_stack.l.set(0, y, NO_VALUE_F32);
+        l_stack.l.set(1, y, NO_VALUE_F32);
+    }
+    for x in 0..10 {
+        ha_stack.l.set(x, 0, 0.0);
+    }
+    ha_stack.l.set(9, 7, f32::INFINITY); // overexposed star is covered
+    let rect = find_common_coverage_of_images(&[&l_stack, &ha_stack]).unwrap();
+    assert_eq!(rect, (2, 1, 8, 7));
+}
+
+#[test]
+fn siril_script_parsing() {
+    use crate::{siril_script::*, calc::*};
+    let commands = parse_siril_script(
+        "requires 1.2.0\n\
+        # comment\n\
+        cd lights\n\
+        convert light -out=../process\n\
+        calibrate light -dark=dark_stacked -flat=\"pp_flat_stacked\" -cfa -debayer\n\
+        register pp_light\n\
+        stack r_pp_light rej 3 4 -norm=addscale -out=result\n"
+    ).unwrap();
+    let commands: Vec<_> = commands.into_iter().map(|(_, c)| c).collect();
+    assert_eq!(commands[1], SirilCommand::Cd("lights".into()));
+    assert_eq!(commands[3], SirilCommand::Calibrate {
+        seq: "light".into(),
+        bias: None,
+        dark: Some("dark_stacked".into()),
+        flat: Some("pp_flat_stacked".into()),
+        prefix: "pp_".into(),
+    });
+    assert_eq!(commands[5], SirilCommand::Stack {
+        seq: "r_pp_light".into(),
+        calc_opts: CalcOpts { kappa: 4.0, ..CalcOpts::default() },
+        out: Some("result".into()),
+    });
+    assert!(parse_siril_script("pm \"$a$ * 2\"").is_err());
+}
+
+#[test]
+fn overscan_correction() {
+    use crate::image_io::*;
+    let section = FitsSection::from_str("[9:10,1:4]").unwrap();
+    assert_eq!(section, FitsSection { x1: 8, x2: 9, y1: 0, y2: 3 });
+    assert!(FitsSection::from_str("[0:10,1:4]").is_err());
+
+    // 8 columns of data and 2 columns of overscan with bias = 100
+    let (width, height) = (10, 4);
+    let mut data: Vec<f32> = (0..width * height)
+        .map(|i| if i % width >= 8 { 100.0 } else { 100.0 + (i % width) as f32 })
+        .collect();
+    let overscan = FitsOverscan {
+        biassec: Some(section),
+        trimsec: Some(FitsSection::from_str("[1:8,1:4]").unwrap()),
+    };
+    let (new_width, new_height) = correct_overscan(&mut data, width, height, &overscan);
+    assert_eq!((new_width, new_height), (8, 4));
+    assert_eq!(data.len(), 32);
+    assert!(data.chunks(8).all(|row| row.iter().enumerate().all(|(x, v)| *v == x as f32)));
+}
+
+#[test]
+fn fits_integer_bitpix_roundtrip() {
+    use crate::image_io::*;
+    let dir = std::env::temp_dir().join(format!("electra_bitpix_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut image = Image::new_grey(16, 8);
+    for (x, y, v) in image.l.iter_crd_mut() {
+        *v = (x + y * 16) as f32 / 127.0;
+    }
+    for (bitpix, tolerance) in [(FitsBitPix::UInt8, 0.5 / 255.0), (FitsBitPix::Int64, 1e-6)] {
+        let file_name = dir.join(format!("{:?}.fit", bitpix));
+        let opts = FitsSaveOpts { bitpix, ..FitsSaveOpts::default() };
+        save_image_to_fits_file(&image, &ImageInfo::default(), &file_name, opts).unwrap();
+        let ImageData { image: RawOrImage::Image(loaded), .. } =
+            load_image_from_file(&file_name, false).unwrap() else { panic!() };
+        for (v1, v2) in image.l.iter().zip(loaded.l.iter()) {
+            assert!((v1.min(1.0) - v2).abs() <= tolerance, "{:?}: {} != {}", bitpix, v1, v2);
+        }
+    }
+    _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn fits_image_extension_after_empty_primary_hdu() {
+    use crate::image_io::*;
+    let dir = std::env::temp_dir().join(format!("electra_mef_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let file_name = dir.join("mef.fit");
+    fitsio::FitsFile::create(&file_name).open().unwrap(); // primary HDU without data
+    for (ext_name, value) in [("SCI", 0.25), ("ERR", 0.75)] {
+        let mut image = Image::new_grey(8, 4);
+        image.l.iter_mut().for_each(|v| *v = value);
+        append_image_to_fits_file(&image, ext_name, &file_name).unwrap();
+    }
+    let data = load_fits_data(&file_name);
+    assert_eq!(data.len(), 32);
+    assert!(data.iter().all(|v| *v == 0.25));
+    _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn fits_output_range_and_pedestal() {
+    use crate::image_io::*;
+    let dir = std::env::temp_dir().join(format!("electra_out_range_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut image = Image::new_grey(4, 1);
+    image.l.iter_mut().zip([-0.01, 0.0, 0.5, 1.2]).for_each(|(d, v)| *d = v);
+    let info = ImageInfo { data_max: Some(4095.0), ..ImageInfo::default() };
+    let opts = FitsSaveOpts {
+        bitpix: FitsBitPix::Int16,
+        range: OutputRange::Input,
+        pedestal: 100.0,
+        ..FitsSaveOpts::default()
+    };
+    // 1.2 * 4095 + 100 is in range of 16-bit data, -0.01 * 4095 + 100 is not below zero
+    assert!(fits_clip_stats(&image, &info, &opts).is_empty());
+    let file_name = dir.join("input_range.fit");
+    save_image_to_fits_file(&image, &info, &file_name, opts).unwrap();
+    let data: Vec<f32> = load_fits_data(&file_name).iter().map(|v| v * 65535.0).collect();
+    for (v, e) in data.iter().zip([59.0, 100.0, 2148.0, 5014.0]) {
+        assert!((v - e).abs() <= 1.0, "{} != {}", v, e);
+    }
+
+    let opts = FitsSaveOpts { bitpix: FitsBitPix::UInt8, ..FitsSaveOpts::default() };
+    let stats = fits_clip_stats(&image, &info, &opts);
+    assert_eq!((stats.low, stats.high, stats.total), (1, 1, 4));
+
+    let opts = FitsSaveOpts { range: OutputRange::Adu16, pedestal: 10.0, ..FitsSaveOpts::default() };
+    let file_name = dir.join("adu16.fit");
+    save_image_to_fits_file(&image, &info, &file_name, opts).unwrap();
+    let loaded = load_fits_data(&file_name); // float data above 1 is normalized by maximum
+    let expected = (10.0 + 0.5 * 65535.0) / (10.0 + 1.2 * 65535.0);
+    assert!((loaded[2] / loaded[3] - expected).abs() < 1e-5);
+    _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn noise_estimation() {
+    use rand::prelude::*;
+    use crate::noise::*;
+    let mut rng = StdRng::seed_from_u64(7);
+    let sigma = 0.01_f32;
+    let mut layer = ImageLayerF32::new(300, 200);
+    for v in layer.iter_mut() {
+        // sum of uniform values is close to gaussian
+        let sum: f32 = (0..12).map(|_| rng.gen_range(-0.5..0.5)).sum();
+        *v = 0.1 + sigma * sum;
+    }
+    add_star(&mut layer, 100.0, 80.0, 2.0, 0.5);
+    add_star(&mut layer, 220.0, 150.0, 3.0, 0.3);
+    for method in [NoiseMethod::KSigma, NoiseMethod::Mrs] {
+        let noise = estimate_noise(&layer, method);
+        assert!((noise - sigma).abs() < 0.1 * sigma, "{:?}: {}", method, noise);
+    }
+}
+
+fn save_test_fits_frames(dir: &std::path::Path, count: usize, width: usize, height: usize) -> Vec<std::path::PathBuf> {
+    use crate::image_io::*;
+    std::fs::create_dir_all(dir).unwrap();
+    let mut image = Image::new_grey(width as Crd, height as Crd);
+    (0..count).map(|i| {
+        for (x, y, v) in image.l.iter_crd_mut() {
+            *v = ((x * 7 + y * 13 + i as Crd) % 1000) as f32 / 1000.0;
+        }
+        let file_name = dir.join(format!("frame{}.fit", i));
+        let opts = FitsSaveOpts { bitpix: FitsBitPix::Int16, ..FitsSaveOpts::default() };
+        save_image_to_fits_file(&image, &ImageInfo::default(), &file_name, opts).unwrap();
+        file_name
+    }).collect()
+}
+
+fn load_fits_data(file_name: &std::path::Path) -> Vec<f32> {
+    use crate::image_io::*;
+    let ImageData { image: RawOrImage::Image(loaded), .. } =
+        load_image_from_file(file_name, false).unwrap() else { panic!() };
+    loaded.l.iter().copied().collect()
+}
+
+#[test]
+fn fits_parallel_decoding_is_same() {
+    use crate::image_io::*;
+    let dir = std::env::temp_dir().join(format!("electra_par_fits_{}", std::process::id()));
+    let files = save_test_fits_frames(&dir, 1, 300, 200);
+    set_fits_parallel_decoding(false);
+    let cfitsio_data = load_fits_data(&files[0]);
+    set_fits_parallel_decoding(true);
+    let parallel_data = load_fits_data(&files[0]);
+    assert_eq!(cfitsio_data, parallel_data);
+    _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn dithered_quantization_keeps_mean() {
+    use crate::image_io::*;
+    // value between two levels of 8 bit data
+    let value = 100.3_f32 / 255.0;
+    let mean = |quantization: Quantization| {
+        (0..10_000_u64)
+            .map(|i| quantization.quantize(value, 255.0, i))
+            .sum::<f64>() / 10_000.0
+    };
+    assert_eq!(mean(Quantization::Nearest), 100.0);
+    assert_eq!(mean(Quantization::Floor), 100.0);
+    assert!((mean(Quantization::Dither) - 100.3).abs() < 0.05);
+    for i in 0..1000 {
+        let v = Quantization::Dither.quantize(value, 255.0, i);
+        assert!((99.0..=102.0).contains(&v));
+        assert_eq!(v, Quantization::Dither.quantize(value, 255.0, i));
+    }
+    assert_eq!(Quantization::Dither.quantize(1.0, 255.0, 7), 255.0);
+}
+
+#[test]
+fn integer_conversion_near_saturation() {
+    use crate::image_io::*;
+    for quantization in [Quantization::Nearest, Quantization::Floor, Quantization::Dither] {
+        for max in [255.0, 65535.0, u32::MAX as f64] {
+            for (i, value) in [1.0 - f32::EPSILON, 1.0, 1.5, f32::INFINITY, f32::MAX].into_iter().enumerate() {
+                let v = quantization.quantize(value, max, i as u64);
+                assert!(v <= max && v >= max - 2.0, "{:?} {}: {}", quantization, value, v);
+            }
+            for (i, value) in [f32::NAN, f32::NEG_INFINITY, -0.5, NO_VALUE_F32].into_iter().enumerate() {
+                assert_eq!(quantization.quantize(value, max, i as u64), 0.0, "{:?} {}", quantization, value);
+            }
+        }
+    }
+
+    // overexposed and near maximum values must not wrap in integer FITS
+    let dir = std::env::temp_dir().join(format!("electra_saturation_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let values = [0.9999, 1.0, 1.5, f32::INFINITY, NO_VALUE_F32, f32::NAN];
+    let expected = [0.9999, 1.0, 1.0, 1.0, 0.0, 0.0];
+    let mut image = Image::new_grey(values.len() as Crd, 1);
+    image.l.iter_mut().zip(values).for_each(|(d, v)| *d = v);
+    for (bitpix, tolerance) in [(FitsBitPix::UInt8, 0.5 / 255.0), (FitsBitPix::Int16, 0.5 / 65535.0)] {
+        for quantization in [Quantization::Nearest, Quantization::Dither] {
+            let file_name = dir.join(format!("{:?}_{:?}.fit", bitpix, quantization));
+            let opts = FitsSaveOpts { bitpix, quantization, ..FitsSaveOpts::default() };
+            save_image_to_fits_file(&image, &ImageInfo::default(), &file_name, opts).unwrap();
+            for (v, e) in load_fits_data(&file_name).iter().zip(expected) {
+                assert!((v - e).abs() <= 2.0 * tolerance, "{:?} {:?}: {} != {}", bitpix, quantization, v, e);
+            }
+        }
+    }
+    _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn integrator_of_aligned_frames() {
+    use crate::{pipeline::*, image_io::*, stars::*, calc::*};
+    let frame = |value: f32, noise: f32| {
+        let mut image = Image::new_grey(20, 10);
+        image.l.iter_mut().for_each(|v| *v = value);
+        image.l.set(3, 3, NO_VALUE_F32); // out of frame after alignment
+        AlignedFrame {
+            image,
+            info: ImageInfo::default(),
+            offset: ImageOffset { offset_x: 0.0, offset_y: 0.0, angle: 0.0, ratio: 1.0 },
+            noise,
+        }
+    };
+    let mut integrator = Integrator::new(CalcOpts { mode: CalcMode::Mean, ..CalcOpts::default() });
+    assert!(integrator.integrate().is_err());
+    integrator.add(frame(0.2, 0.01)).unwrap();
+    integrator.add(frame(0.5, 0.02)).unwrap();
+    assert!(integrator.add(AlignedFrame { image: Image::new_grey(10, 10), ..frame(0.2, 0.01) }).is_err());
+    let mut result = integrator.integrate().unwrap();
+    // weights are 1 and 1/4 so mean is 0.26 before normalization to 0..1 range
+    let k = 0.26 / result.l.get(0, 0).unwrap();
+    result.l.iter_mut().for_each(|v| *v *= k);
+    assert!((result.l.get(5, 5).unwrap() - 0.26).abs() < 1e-5);
+    assert_eq!(result.l.get(3, 3), Some(0.0));
+}
+
+#[test]
+fn log_options_from_cmd_line() {
+    use crate::log_utils::*;
+    let mut args: Vec<String> = ["app", "-v", "--log-file", "run.log", "--run", "p.es_proj"]
+        .iter().map(|s| s.to_string()).collect();
+    let opts = LogOpts::from_cmd_line(&mut args).unwrap();
+    assert_eq!(opts.verbosity, 1);
+    assert!(!opts.quiet);
+    assert_eq!(opts.log_file, Some(std::path::PathBuf::from("run.log")));
+    assert_eq!(args, ["app", "--run", "p.es_proj"]);
+
+    // options after command are not taken (they can be values of other options)
+    let mut args: Vec<String> = ["app", "--export-obs", "m.csv", "--notes", "-v"]
+        .iter().map(|s| s.to_string()).collect();
+    let opts = LogOpts::from_cmd_line(&mut args).unwrap();
+    assert_eq!(opts.verbosity, 0);
+    assert_eq!(args, ["app", "--export-obs", "m.csv", "--notes", "-v"]);
+
+    let mut args: Vec<String> = ["app", "-q", "-vv"].iter().map(|s| s.to_string()).collect();
+    assert!(LogOpts::from_cmd_line(&mut args).is_err());
+}
+
+#[test]
+fn auto_group_keys_from_str() {
+    use crate::auto_groups::*;
+    let keys = GroupKey::list_from_str("filter, EXPTIME,temp").unwrap();
+    assert_eq!(keys, [GroupKey::Filter, GroupKey::Exposure, GroupKey::Temperature]);
+    assert!(GroupKey::list_from_str("filter,binning").is_err());
+}
+
+#[test]
+fn cal_library_best_match() {
+    use crate::{cal_library::*, image_io::ImageInfo};
+    let entry = |temperature: f32, exposure: f32| CalLibEntry {
+        file: std::path::PathBuf::new(), file_type: CalFileType::Dark, camera: None,
+        width: 100, height: 100, gain: Some(120), exposure: Some(exposure),
+        temperature: Some(temperature), filter: None, date: None, frames: 20,
+    };
+    let mut library = CalLibrary::default();
+    library.entries = vec![entry(-10.0, 300.0), entry(-20.0, 300.0), entry(-10.0, 60.0)];
+    let light = ImageInfo {
+        width: 100, height: 100, iso: Some(120), exp: Some(300.0), temperature: Some(-11.0),
+        ..Default::default()
+    };
+    let tolerances = CalMatchTolerances::default();
+    let found = library.find_best(CalFileType::Dark, &light, &tolerances).unwrap();
+    assert_eq!(found.entry.temperature, Some(-10.0));
+    assert_eq!(found.entry.exposure, Some(300.0));
+    assert!(found.warnings.is_empty());
+
+    let light = ImageInfo { temperature: Some(0.0), ..light };
+    let found = library.find_best(CalFileType::Dark, &light, &tolerances).unwrap();
+    assert_eq!(found.warnings.len(), 1);
+
+    let light = ImageInfo { iso: Some(0), ..light };
+    assert!(library.find_best(CalFileType::Dark, &light, &tolerances).is_none());
+}
+
+#[test]
+fn airmass_at_pole() {
+    use crate::airmass::*;
+    use chrono::prelude::*;
+    // at pole altitude of target equals its declination at any time
+    let site = SiteCoords { lat: 90.0, lon: 0.0 };
+    let time = Utc.with_ymd_and_hms(2024, 1, 15, 22, 0, 0).unwrap();
+    let airmass = |dec| calc_airmass(&site, &EqCoords { ra: 83.8, dec }, &time);
+    assert!((airmass(90.0).unwrap() - 1.0).abs() < 1e-3);
+    assert!((airmass(30.0).unwrap() - 2.0).abs() < 0.01);
+    assert!(airmass(-10.0).is_none());
+}
+
+#[test]
+fn aavso_extended_report() {
+    use crate::obs_report::*;
+    let observations = [Observation { jd: 2460000.51234, mag: 11.2351, err: Some(0.0031), ..Default::default() }];
+    let mut opts = ObsReportOpts {
+        obs_code: Some("XYZ".to_string()),
+        star: Some("SS CYG".to_string()),
+        comp: Some("105".to_string()),
+        ..Default::default()
+    };
+    let mut text = Vec::new();
+    write_obs_report(&observations, &opts, &mut text).unwrap();
+    let text = String::from_utf8(text).unwrap();
+    assert!(text.starts_with("#TYPE=EXTENDED\n#OBSCODE=XYZ\n"));
+    assert!(text.ends_with("\nSS CYG,2460000.51234,11.235,0.003,V,NO,STD,105,na,na,na,na,na,na,na\n"));
+
+    opts.filter = "Q".to_string();
+    assert!(write_obs_report(&observations, &opts, &mut Vec::new()).is_err());
+}
+
+#[test]
+fn presets_from_str() {
+    use crate::{presets::*, project::*};
+    for preset in Preset::ALL {
+        assert_eq!(Preset::from_str(preset.name()).unwrap(), preset);
+    }
+    assert!(Preset::from_str("dslr").is_err());
+    let mut config = ProjectConfig::default();
+    Preset::MonoLrgb.apply_to_project_config(&mut config);
+    assert!(!config.raw_params.apply_wb);
+    assert_eq!(config.light_calc_opts.kappa, 3.0);
+}
+
+#[test]
+fn lrgb_merge_keeps_colors_of_rgb() {
+    use crate::lrgb::*;
+    let make_layer = |star: f32, l_scale: f32, l_offset: f32| {
+        let mut layer = ImageLayerF32::new(32, 32);
+        for y in 0..32 {
+            for x in 0..32 {
+                let mut v = 0.1 + ((x * 7 + y * 3) % 5) as f32 * 0.001;
+                if (14..18).contains(&x) && (14..18).contains(&y) { v += star; }
+                layer.set(x, y, l_scale * v + l_offset);
+            }
+        }
+        layer
+    };
+    let (r, g, b) = (make_layer(0.5, 1.0, 0.0), make_layer(0.3, 1.0, 0.0), make_layer(0.1, 1.0, 0.0));
+    let l = make_layer(0.3, 2.0, 0.05); // same luminance in other units
+    let channels = [(LrgbChannel::L, &l), (LrgbChannel::R, &r), (LrgbChannel::G, &g), (LrgbChannel::B, &b)];
+    let result = merge_lrgb(&channels, &LrgbOpts::default()).unwrap();
+    assert!((result.lum_scale.unwrap() - 0.5).abs() < 0.01);
+    assert!((result.image.r.get(15, 15).unwrap() - r.get(15, 15).unwrap()).abs() < 0.01);
+    assert!((result.image.b.get(15, 15).unwrap() - b.get(15, 15).unwrap()).abs() < 0.01);
+
+    let opts = LrgbOpts { lum_weight: 0.0, ..LrgbOpts::default() };
+    let result = merge_lrgb(&channels, &opts).unwrap();
+    assert!(result.lum_scale.is_none());
+    assert_eq!(result.image.r.get(15, 15), r.get(15, 15));
+
+    let small = ImageLayerF32::new(16, 16);
+    let channels = [(LrgbChannel::R, &r), (LrgbChannel::G, &g), (LrgbChannel::B, &small)];
+    assert!(merge_lrgb(&channels, &LrgbOpts::default()).is_err());
+    assert!(merge_lrgb(&channels[..2], &LrgbOpts::default()).is_err());
+}
+
+#[test]
+fn config_layers_keep_sources() {
+    use crate::{config_layers::*, presets::*, project::*};
+    let default = ProjectConfig::default();
+    let mut layers = ConfigLayers::new(&default).unwrap();
+    let mut preset = default.clone();
+    Preset::MonoLrgb.apply_to_project_config(&mut preset);
+    layers.add_layer(ConfigSource::Preset, &preset).unwrap();
+    let mut project = default.clone();
+    project.min_stars_in_light = 5;
+    layers.add_layer(ConfigSource::Project, &project).unwrap();
+    let before: ProjectConfig = layers.get().unwrap();
+    let mut after = before.clone();
+    after.sub_stacks = Some(30);
+    layers.add_changes(ConfigSource::CommandLine, &before, &after).unwrap();
+
+    let config: ProjectConfig = layers.get().unwrap();
+    assert_eq!(config.min_stars_in_light, 5);
+    assert_eq!(config.light_calc_opts.kappa, 3.0);
+    assert_eq!(config.sub_stacks, Some(30));
+    assert_eq!(layers.source_of("min_stars_in_light"), ConfigSource::Project);
+    assert_eq!(layers.source_of("light_calc_opts.kappa"), ConfigSource::Preset);
+    assert_eq!(layers.source_of("sub_stacks"), ConfigSource::CommandLine);
+    assert_eq!(layers.source_of("fits_compression"), ConfigSource::Default);
+    assert!(layers.entries().iter().any(|e| e.key == "skip_bad_lights" && e.value == "true"));
+}
+
+#[test]
+fn integration_meta_of_stacks() {
+    use chrono::prelude::*;
+    use crate::{image_io::*, integration_meta::*};
+    let start = Local.with_ymd_and_hms(2024, 3, 10, 22, 0, 0).unwrap();
+    let frame = |minutes: i64| ImageInfo {
+        exp: Some(60.0),
+        file_time: Some(start + chrono::Duration::minutes(minutes)),
+        ..ImageInfo::default()
+    };
+    let mut l = IntegrationMeta::default();
+    l.add_frame(&frame(10), std::path::Path::new("/lights/l_002.fit"));
+    l.add_frame(&frame(0), std::path::Path::new("/lights/l_001.fit"));
+    assert_eq!(l.count, 2);
+    assert_eq!(l.exp_time, 120.0);
+    assert_eq!(l.date_obs, Some(start));
+    assert_eq!(l.date_end, Some(start + chrono::Duration::minutes(11)));
+
+    let mut info = ImageInfo::default();
+    l.apply_to_info(&mut info);
+    assert_eq!(info.exp, Some(120.0));
+    let mut merged = IntegrationMeta::default();
+    merged.add_stack(&info);
+    merged.add_stack(&frame(30)); // stack without metadata is one frame
+    assert_eq!(merged.count, 3);
+    assert_eq!(merged.exp_time, 180.0);
+    assert_eq!(merged.date_end, Some(start + chrono::Duration::minutes(31)));
+    assert_eq!(merged.files.len(), 3);
+    assert_eq!(format_fits_time(start).len(), "2024-03-10T22:00:00.000".len());
+}
+
+#[test]
+fn hot_pixels_persistent_in_dithered_lights() {
+    use crate::image_raw::*;
+    use std::collections::HashSet;
+    let hot = BadPixel { x: 10, y: 20 };
+    let sets: Vec<HashSet<BadPixel>> = (0..4)
+        .map(|i| HashSet::from([hot, BadPixel { x: 100 + i, y: 50 }])) // star moved by dithering
+        .collect();
+    let result = select_persistent_pixels(&sets, 0.75);
+    assert_eq!(result.len(), 1);
+    assert!(result.contains(&hot));
+}
+
+#[test]
+#[cfg(unix)]
+fn plugin_returns_image_from_stdout() {
+    use crate::{plugins::*, image_io::ImageInfo};
+    let mut image = Image::new_grey(8, 6);
+    image.l.set(3, 2, 0.5);
+    let plugins = [
+        PluginOpts::from_str("calibrated:cat").unwrap(), // returns input image
+        PluginOpts::from_str("result:false").unwrap(), // exits with error
+    ];
+    let src_file = std::path::Path::new("light.fits");
+    run_plugins(&plugins, PluginStage::Calibrated, &mut image, &ImageInfo::default(), src_file).unwrap();
+    assert_eq!(image.width(), 8);
+    assert_eq!(image.l.get(3, 2), Some(0.5));
+    assert!(run_plugins(&plugins, PluginStage::Result, &mut image, &ImageInfo::default(), src_file).is_err());
+}
+
+// cargo test --release fits_decoding_benchmark -- --ignored
+#[test]
+#[ignore]
+fn fits_decoding_benchmark() {
+    use crate::image_io::*;
+    let dir = std::env::temp_dir().join(format!("electra_fits_bench_{}", std::process::id()));
+    let files = save_test_fits_frames(&dir, 20, 6000, 4000);
+    let mut frames_per_sec = Vec::new();
+    for parallel in [false, true] {
+        set_fits_parallel_decoding(parallel);
+        let timer = std::time::Instant::now();
+        for file in &files {
+            load_fits_data(file);
+        }
+        frames_per_sec.push(files.len() as f64 / timer.elapsed().as_secs_f64());
+    }
+    set_fits_parallel_decoding(true);
+    _ = std::fs::remove_dir_all(&dir);
+    assert!(
+        frames_per_sec[1] > frames_per_sec[0],
+        "parallel decoding is not faster: {:.2} <= {:.2} frames/s", frames_per_sec[1], frames_per_sec[0]
+    );
+}
+
+#[test]
+fn wcs_transform_between_pixel_scales() {
+    use crate::wcs::*;
+    let dst = Wcs { crval: [83.8, -5.4], crpix: [500.0, 400.0], cd: [[-3e-4, 0.0], [0.0, 3e-4]] };
+    // twice coarser pixel scale, rotated by 90° and shifted
+    let src = Wcs { crval: [83.85, -5.38], crpix: [200.0, 150.0], cd: [[0.0, 6e-4], [6e-4, 0.0]] };
+    let matrix = calc_wcs_transform(&src, 400, 300, &dst).unwrap();
+    for (x, y) in [(0.0, 0.0), (399.0, 0.0), (200.0, 150.0), (17.0, 288.0)] {
+        let (ra, dec) = src.pixel_to_world(x, y);
+        let (ex, ey) = dst.world_to_pixel(ra, dec).unwrap();
+        let px = matrix[0][0] * x + matrix[0][1] * y + matrix[0][2];
+        let py = matrix[1][0] * x + matrix[1][1] * y + matrix[1][2];
+        assert!((px - ex).abs() < 0.05 && (py - ey).abs() < 0.05);
+    }
+    let binned = dst.binned(2);
+    let (ra, dec) = dst.pixel_to_world(10.5, 20.5);
+    let (bx, by) = binned.world_to_pixel(ra, dec).unwrap();
+    assert!((bx - 5.0).abs() < 1e-6 && (by - 10.0).abs() < 1e-6);
+}
+
+#[test]
+fn diff_finds_transient() {
+    use rand::prelude::*;
+    use crate::image_diff::*;
+    let mut rng = StdRng::seed_from_u64(7);
+    let (width, height) = (256, 256);
+    let mut reference = Image::new_grey(width, height);
+    let mut new = Image::new_grey(width, height);
+    for (r, n) in reference.l.iter_mut().zip(new.l.iter_mut()) {
+        *r = 0.1 + rng.gen_range(-0.005..0.005);
+        *n = 0.12 + rng.gen_range(-0.005..0.005);
+    }
+    let (shift_x, shift_y) = (3.0, -2.0);
+    for _ in 0..40 {
+        let (x, y) = (rng.gen_range(10.0..240.0), rng.gen_range(10.0..240.0));
+        if (x - 150.0_f64).abs() < 15.0 && (y - 120.0_f64).abs() < 15.0 { continue; }
+        let ampl = rng.gen_range(0.1..0.5);
+        add_star(&mut reference.l, x, y, 1.5, ampl);
+        add_star(&mut new.l, x + shift_x, y + shift_y, 1.5, 0.8 * ampl);
+    }
+    add_star(&mut new.l, 150.0 + shift_x, 120.0 + shift_y, 1.5, 0.3); // supernova
+    let result = diff_images(&reference, &new, &DiffOpts::default()).unwrap();
+    assert!((result.scale - 1.25).abs() < 0.05);
+    let transients: Vec<_> = result.sources.iter().filter(|s| !s.on_star).collect();
+    assert!(!transients.is_empty());
+    let best = transients[0];
+    assert!((best.x - 150.0).abs() < 1.0 && (best.y - 120.0).abs() < 1.0);
+    assert!(best.flux > 0.0);
+}
+
+#[test]
+fn aperture_photometry_of_star() {
+    use crate::photometry::*;
+    let mut layer = ImageLayerF32::new(64, 64);
+    layer.iter_mut().for_each(|v| *v = 0.1);
+    add_star(&mut layer, 30.3, 33.6, 1.5, 0.5);
+    let opts = PhotometryOpts::default();
+    let star = measure_star(&layer, 31.0, 33.0, Some(10.0), &opts).unwrap();
+    assert!((star.x - 30.3).abs() < 0.05 && (star.y - 33.6).abs() < 0.05);
+    assert!((star.bg - 0.1).abs() < 1e-4);
+    let total = 0.5 * 2.0 * std::f64::consts::PI * 1.5 * 1.5;
+    assert!((star.flux / total - 1.0).abs() < 0.01);
+    assert!((star.mag - (INSTR_ZERO_POINT - 2.5 * (star.flux / 10.0).log10())).abs() < 1e-9);
+
+    // twice fainter star is 0.753 magnitudes fainter
+    let mut faint = ImageLayerF32::new(64, 64);
+    faint.iter_mut().for_each(|v| *v = 0.1);
+    add_star(&mut faint, 30.3, 33.6, 1.5, 0.25);
+    let faint_star = measure_star(&faint, 31.0, 33.0, Some(10.0), &opts).unwrap();
+    assert!((faint_star.mag - star.mag - 2.5 * 2.0_f64.log10()).abs() < 0.01);
+    assert!(matches!(StarPos::from_str("21:42:42.8,+43:35:10").unwrap(), StarPos::Sky(_)));
+    assert_eq!(StarPos::from_str("10.5,20").unwrap(), StarPos::Pixel(10.5, 20.0));
+}
+
+#[test]
+fn star_shape_map_shows_tilt() {
+    use rand::prelude::*;
+    use crate::star_shape::*;
+    let mut rng = StdRng::seed_from_u64(3);
+    let (width, height) = (300, 200);
+    let mut layer = ImageLayerF32::new(width, height);
+    layer.iter_mut().for_each(|v| *v = 0.1 + rng.gen_range(-0.002..0.002));
+    for _ in 0..150 {
+        let (x, y) = (rng.gen_range(10.0..290.0), rng.gen_range(10.0..190.0));
+        let fwhm = 2.5 + 3.0 * x / width as f64; // worse to the right
+        add_star(&mut layer, x, y, fwhm / 2.3548, rng.gen_range(0.2..0.6));
+    }
+    let maps = create_star_shape_maps(&layer).unwrap();
+    let left = maps.fwhm.get(20, 100).unwrap();
+    let right = maps.fwhm.get(280, 100).unwrap();
+    assert!((left - 2.8).abs() < 0.5, "left = {}", left);
+    assert!((right - 5.3).abs() < 0.7, "right = {}", right);
+    let ecc = maps.eccentricity_zones.center.unwrap();
+    assert!(ecc < 0.6, "eccentricity = {}", ecc);
+}
+
+#[test]
+fn lanczos_anti_ringing() {
+    use crate::resample::*;
+    // sharp star on zero background
+    let mut layer = ImageLayerF32::new(32, 32);
+    add_star(&mut layer, 16.0, 16.0, 0.6, 1.0);
+    for kernel in [ResampleKernel::Lanczos3, ResampleKernel::Lanczos4] {
+        let moved = layer.rotated_and_translated_by(kernel, 0.3, 0.5, 0.5, 0.0, 32, 32);
+        assert!(moved.iter().all(|v| *v >= 0.0), "dark halo for {:?}", kernel);
+        let resized = resize_layer(&layer, 45, 45, kernel);
+        assert!(resized.iter().all(|v| *v >= 0.0), "dark halo for {:?}", kernel);
+    }
+    // without clamp bicubic rings
+    let moved = layer.rotated_and_translated_by(ResampleKernel::Bicubic, 0.3, 0.5, 0.5, 0.0, 32, 32);
+    assert!(moved.iter().any(|v| *v < 0.0));
+
+    // values at pixel centers are kept
+    let center = interpolate_pixel(&layer, 16.0, 16.0, ResampleKernel::Lanczos3).unwrap();
+    assert!((center - layer.get(16, 16).unwrap()).abs() < 1e-6);
+    assert_eq!(interpolate_pixel(&layer, 40.0, 16.0, ResampleKernel::Lanczos3), None);
+
+    // bilinear interpolation reduces variance by 4/9 for rotated image
+    let k = interpolation_variance_k(ResampleKernel::Bilinear, None, None);
+    assert!((k - 4.0 / 9.0).abs() < 1e-3);
+    assert!(interpolation_variance_k(ResampleKernel::Lanczos3, None, None) > k);
+    assert_eq!(interpolation_variance_k(ResampleKernel::Nearest, Some(0.3), Some(0.2)), 1.0);
+}
+
+#[test]
+fn incompatible_channels() {
+    use crate::{image_io::*, dataset_check::*};
+    let info = |binning: u32, gain: u32| ImageInfo {
+        width: 100,
+        height: 80,
+        binning: Some(binning),
+        iso: Some(gain),
+        ..ImageInfo::default()
+    };
+    let l = vec![info(1, 100), info(1, 100)];
+    let r = vec![info(1, 200)];
+    let g = vec![info(2, 100)];
+    let report = check_channels_compatibility(&[("L", &l), ("R", &r)]);
+    assert!(report.passed());
+    assert_eq!(report.count(CheckLevel::Warning), 1);
+    let report = check_channels_compatibility(&[("L", &l), ("R", &r), ("G", &g)]);
+    assert!(!report.passed());
+    assert_eq!(report.items[1].group.as_deref(), Some("G"));
+    assert_eq!(report.files_checked, 4);
+}
+
+#[test]
+fn end_to_end_stacking_golden() {
+    use std::sync::Arc;
+    use crate::{project::*, image_io::*, config::*, progress::*, stacking_utils::*, lrgb::*, golden::*};
+    let dir = std::env::temp_dir().join(format!("electra_e2e_{}", std::process::id()));
+    let fixtures = create_star_field_fixtures(&dir, 6);
+    let progress = ProgressConsole::new_ts();
+    let cancel_flag: IsCancelledFun = Arc::new(|| false);
+    let mut project = Project::default();
+    project.add_new_group(GroupOptions { name: Some("field".to_string()) });
+    for (file_type, files) in [
+        (ProjectFileType::Light, &fixtures.lights),
+        (ProjectFileType::Dark, &fixtures.darks),
+        (ProjectFileType::Flat, &fixtures.flats),
+    ] {
+        let infos = load_src_file_info_for_files(files, None, &cancel_flag, &progress).unwrap();
+        project.group_by_index_mut(0).file_list_by_type_mut(file_type).add_files_from_src_file_info(infos);
+    }
+    let project_file = dir.join("project.es_proj");
+    project.save(&project_file).unwrap();
+    let reg_info = project.register_light_files(&progress, &cancel_flag, CpuLoad::AllCPUs).unwrap();
+    project.update_light_files_reg_info(reg_info);
+    project.set_ref_image(fixtures.lights[0].clone());
+
+    let stack = || {
+        let result = project.stack_light_files(
+            &progress, &cancel_flag, CpuLoad::AllCPUs, ResumeMode::Off, &StackMapsOpts::default()
+        ).unwrap();
+        let ImageData { image: RawOrImage::Image(image), .. } =
+            load_image_from_file(&result.file_name, false).unwrap() else { panic!() };
+        image
+    };
+    let image = stack();
+    assert_eq!(image.l.as_slice(), stack().l.as_slice(), "Results of stacking differ");
+
+    // vignetting is removed by flats and stars are kept
+    let (w, h) = (image.width(), image.height());
+    let bg_median = |x1: Crd, y1: Crd| {
+        let mut values: Vec<_> = image.l.iter_rect_crd(x1, y1, x1 + w / 6, y1 + h / 6)
+            .map(|(_, _, v)| v)
+            .filter(|v| v.is_finite())
+            .collect();
+        crate::calc::median_f32(&mut values).unwrap()
+    };
+    let center_bg = bg_median(w / 2 - w / 12, h / 2 - h / 12);
+    let corner_bg = bg_median(w / 8, h / 8);
+    assert!((corner_bg / center_bg - 1.0).abs() < 0.05, "{} != {}", corner_bg, center_bg);
+    let max = image.l.as_slice().iter().copied().filter(|v| v.is_finite()).fold(0.0, f32::max);
+    assert!(max > 2.0 * center_bg);
+    check_golden_image("e2e_stack", &image, Tolerance { abs: 1e-4, rel: 1e-3 });
+
+    let grey = image.create_greyscale_layer();
+    let mut g = grey.clone();
+    let mut b = grey.clone();
+    g.iter_mut().for_each(|v| *v *= 0.8);
+    b.iter_mut().for_each(|v| *v *= 0.6);
+    let channels = [(LrgbChannel::L, &grey), (LrgbChannel::R, &grey), (LrgbChannel::G, &g), (LrgbChannel::B, &b)];
+    let merged = merge_lrgb(&channels, &LrgbOpts::default()).unwrap();
+    let mean = |layer: &ImageLayerF32| {
+        let values: Vec<_> = layer.as_slice().iter().copied().filter(|v| v.is_finite()).collect();
+        values.iter().sum::<f32>() / values.len() as f32
+    };
+    assert!(merged.image.is_rgb());
+    let (r, g, b) = (mean(&merged.image.r), mean(&merged.image.g), mean(&merged.image.b));
+    assert!(r > g && g > b, "{} {} {}", r, g, b);
+    check_golden_image("e2e_lrgb", &merged.image, Tolerance { abs: 1e-4, rel: 1e-3 });
+    _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn running_stat() {
+    use crate::calc::*;
+    let values = [0.21_f32, 0.25, 0.19, 0.23, 0.9, 0.22, 0.2, 0.24];
+    let weights = [1.0_f32, 0.5, 0.8, 1.0, 1.0, 0.7, 0.9, 0.6];
+    let mut stat = RunningStat::default();
+    assert!(stat.mean().is_none() && stat.weighted_mean().is_none());
+    for (v, w) in values.iter().zip(&weights) {
+        stat.add(*v, *w);
+    }
+    let calc_values: Vec<_> = values.iter().zip(&weights)
+        .map(|(v, w)| CalcValue::new_weighted(*v as f64, *w as f64))
+        .collect();
+    let (mean, std_dev) = mean_and_std_dev(&calc_values).unwrap();
+    let weighted_mean = mean_weighted(&calc_values).unwrap();
+    assert_eq!(stat.count(), 8);
+    assert!((stat.mean().unwrap() as f64 - mean).abs() < 1e-6);
+    assert!((stat.std_dev().unwrap() as f64 - std_dev).abs() < 1e-6);
+    assert!((stat.weighted_mean().unwrap() as f64 - weighted_mean).abs() < 1e-6);
+}
+
+#[test]
+fn lrgb_clipped_star_core() {
+    use crate::lrgb::*;
+    // orange star clipped in R and G channels
+    let star = |k: f32| {
+        let mut layer = ImageLayerF32::new(64, 64);
+        for (x, y, v) in layer.iter_crd_mut() {
+            let d2 = ((x - 32).pow(2) + (y - 32).pow(2)) as f32;
+            let noise = 0.0005 * ((x * 7 + y * 13) % 5) as f32;
+            *v = (0.1 + noise + 4.0 * k * (-d2 / 18.0).exp()).min(1.0);
+        }
+        layer
+    };
+    let (r, g, b) = (star(1.0), star(0.6), star(0.2));
+    let channels = [(LrgbChannel::R, &r), (LrgbChannel::G, &g), (LrgbChannel::B, &b)];
+    let plain = merge_lrgb(&channels, &LrgbOpts::default()).unwrap();
+    let ratio = |image: &Image| (image.g.get(32, 32).unwrap() - 0.1) / (image.r.get(32, 32).unwrap() - 0.1);
+    assert!(ratio(&plain.image) > 0.95);
+
+    let opts = LrgbOpts { reconstruct_cores: true, ..LrgbOpts::default() };
+    let fixed = merge_lrgb(&channels, &opts).unwrap();
+    assert_eq!(fixed.cores.found, 1);
+    assert_eq!(fixed.cores.reconstructed, 1);
+    assert!((ratio(&fixed.image) - 0.6).abs() < 0.1, "ratio = {}", ratio(&fixed.image));
+
+    let opts = LrgbOpts { desaturate_clipped: true, ..LrgbOpts::default() };
+    let neutral = merge_lrgb(&channels, &opts).unwrap();
+    assert_eq!(neutral.cores.desaturated, 1);
+    assert!((neutral.image.b.get(32, 32).unwrap() - neutral.image.r.get(32, 32).unwrap()).abs() < 0.01);
+
+    // brightest star of image without saturation is not changed
+    let (r, g, b) = (star(0.2), star(0.12), star(0.04));
+    let channels = [(LrgbChannel::R, &r), (LrgbChannel::G, &g), (LrgbChannel::B, &b)];
+    let opts = LrgbOpts { reconstruct_cores: true, desaturate_clipped: true, ..LrgbOpts::default() };
+    let merged = merge_lrgb(&channels, &opts).unwrap();
+    assert_eq!(merged.cores.found, 0);
+    assert_eq!(merged.image.r.as_slice(), r.as_slice());
+    assert_eq!(merged.image.g.as_slice(), g.as_slice());
+    assert_eq!(merged.image.b.as_slice(), b.as_slice());
+}
+
+#[test]
+fn flat_normalization_modes() {
+    use crate::{image_raw::*, image_io::RawImageInfo, stacking_utils::*};
+    // RGGB flat of tinted panel with vignetting (0.5 in corners)
+    let (width, height) = (200, 200);
+    let levels = [0.2_f32, 0.4, 0.3]; // R, G, B
+    let cfa = Cfa::from_cfa_type(Some(CfaType::RGGB));
+    let channel = |x, y| match cfa.get_pixel_color(x, y) {
+        CfaColor::R => 0,
+        CfaColor::G => 1,
+        _           => 2,
+    };
+    let mut flat = RawImage {
+        info: RawImageInfo {
+            width,
+            height,
+            max_values: [1.0; 4],
+            cfa,
+            ..RawImageInfo::default()
+        },
+        data: ImageLayerF32::new(width, height),
+    };
+    let (cx, cy) = (width as f32 / 2.0, height as f32 / 2.0);
+    for (x, y, v) in flat.data.iter_crd_mut() {
+        let r2 = (x as f32 - cx).powi(2) + (y as f32 - cy).powi(2);
+        *v = levels[channel(x, y)] * (1.0 - 0.5 * r2 / (cx * cx + cy * cy));
+    }
+    let mut sums = [0_f64; 3];
+    let mut counts = [0_usize; 3];
+    for (x, y, v) in flat.data.iter_crd() {
+        sums[channel(x, y)] += v as f64;
+        counts[channel(x, y)] += 1;
+    }
+    let channel_means: Vec<f32> = (0..3).map(|c| (sums[c] / counts[c] as f64) as f32).collect();
+    let global_mean = (sums.iter().sum::<f64>() / (width * height) as f64) as f32;
+
+    for (flat_norm, expected, tolerance) in [
+        // bright top of every channel (center of frame)
+        (FlatNorm::Percentile,  levels.to_vec(),      0.02),
+        (FlatNorm::GlobalMean,  vec![global_mean; 3], 1e-4),
+        (FlatNorm::ChannelMean, channel_means.clone(), 1e-4),
+        (FlatNorm::CenterMean,  levels.to_vec(),      0.01),
+    ] {
+        let mut normalized = RawImage { info: flat.info.clone(), data: flat.data.clone() };
+        assert!(postprocess_single_flat_image(&mut normalized, None, false, flat_norm));
+        // R, G and B pixels of RGGB
+        for (c, (x, y)) in [(0, 0), (1, 0), (1, 1)].into_iter().enumerate() {
+            let factor = flat.data.get(x, y).unwrap() / normalized.data.get(x, y).unwrap();
+            assert!(
+                (factor - expected[c]).abs() <= tolerance * expected[c],
+                "{:?}: factor of channel {} is {}, expected {}", flat_norm, c, factor, expected[c]
+            );
+        }
+    }
+}
+
+#[test]
+fn run_config_is_not_saved() {
+    use crate::{project::*, image_io::*};
+    let dir = std::env::temp_dir().join(format!("electra_run_config_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let file_name = dir.join("project.es_proj");
+    let mut project = Project::default();
+    project.make_default();
+    project.save(&file_name).unwrap();
+
+    let mut run_config = project.config().clone();
+    run_config.fits_compression = FitsCompression::Rice;
+    project.set_run_config(run_config);
+    project.save(&file_name).unwrap();
+    assert_eq!(project.config().fits_compression, FitsCompression::Rice);
+    assert_eq!(project.saved_config().fits_compression, FitsCompression::None);
+
+    let mut loaded = Project::default();
+    loaded.load(&file_name).unwrap();
+    assert_eq!(loaded.config().fits_compression, FitsCompression::None);
+    _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn run_config_sources_of_next_run() {
+    use crate::{project::*, presets::*, config_layers::*, bg_mask::*};
+    let dir = std::env::temp_dir().join(format!("electra_run_sources_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let file_name = dir.join("project.es_proj");
+    let mut project = Project::default();
+    project.make_default();
+    project.save(&file_name).unwrap();
+
+    // --preset mono-lrgb --bg-mask objects
+    let layers = |project_config: &ProjectConfig| project_config_layers(
+        Some(Preset::MonoLrgb),
+        project_config,
+        |config| config.bg_mask = BgMaskPreset::Objects
+    ).unwrap();
+    let run = || {
+        let mut project = Project::default();
+        project.load(&file_name).unwrap();
+        let run_layers = layers(project.saved_config());
+        project.set_run_config(run_layers.get().unwrap());
+        project.save(&file_name).unwrap();
+        run_layers.entries().into_iter()
+            .map(|entry| (entry.key, entry.value, entry.source))
+            .collect::<Vec<_>>()
+    };
+    let first = run();
+    let second = run();
+    let source = |key: &str| first.iter().find(|(k, _, _)| k == key).map(|(_, _, s)| *s);
+    assert_eq!(source("bg_mask"), Some(ConfigSource::CommandLine));
+    assert_eq!(source("min_stars_in_light"), Some(ConfigSource::Preset));
+    assert_eq!(first, second);
+    _ = std::fs::remove_dir_all(&dir);
+}
+
+} // mod tests
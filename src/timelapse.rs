@@ -0,0 +1,178 @@
+use std::{path::*, fs::File, io::BufWriter};
+use rayon::prelude::*;
+use crate::{image::*, image_io::*, image_raw::*, preview::*, progress::*, fs_utils::*};
+
+/* Timelapse of sequence of frames. All frames are stretched by the same
+   auto-stretch (calculated for middle frame of sequence) so clouds,
+   satellites and transient events are visible as changes between frames.
+   Result is animated GIF, animated PNG or sequence of PNG files */
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TimelapseFormat {
+    Gif,
+    Apng,
+    PngSequence,
+}
+
+impl TimelapseFormat {
+    /// `.gif` - GIF, `.png` or `.apng` - animated PNG, otherwise
+    /// directory for sequence of PNG files
+    pub fn by_file_name(file_name: &Path) -> Self {
+        match extract_extension(file_name).to_lowercase().as_str() {
+            "gif"         => TimelapseFormat::Gif,
+            "png"|"apng"  => TimelapseFormat::Apng,
+            _             => TimelapseFormat::PngSequence,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TimelapseOpts {
+    pub stretch:   PreviewStretch,
+    pub max_width: Option<usize>, // frames are halved until they fit
+    pub fps:       f32,
+}
+
+impl Default for TimelapseOpts {
+    fn default() -> Self {
+        Self {
+            stretch:   PreviewStretch::Mtf,
+            max_width: Some(1280),
+            fps:       10.0,
+        }
+    }
+}
+
+/// Loads frame (RAW is demosaiced)
+fn load_frame(file_name: &Path) -> anyhow::Result<Image> {
+    let ImageData { image, .. } = load_image_from_file(file_name, false)?;
+    match image {
+        RawOrImage::Image(image) => Ok(image),
+        RawOrImage::Raw(raw) => raw.demosaic(DemosaicAlgo::Linear, false),
+    }
+}
+
+struct FramesSource<'a> {
+    files:        &'a [PathBuf],
+    stretch:      FixedStretch,
+    max_width:    Option<usize>,
+    width:        usize,
+    height:       usize,
+    progress:     &'a ProgressTs,
+    is_cancelled: &'a IsCancelledFun,
+}
+
+impl FramesSource<'_> {
+    /// Calls `fun` for stretched bytes of every frame in order of files.
+    /// Frames are loaded in parallel by portions
+    fn for_each(&self, mut fun: impl FnMut(usize, &[u8]) -> anyhow::Result<()>) -> anyhow::Result<()> {
+        self.progress.lock().unwrap().set_total(self.files.len());
+        let portion = rayon::current_num_threads().max(1);
+        for (portion_idx, files) in self.files.chunks(portion).enumerate() {
+            if (self.is_cancelled)() {
+                anyhow::bail!("Termimated");
+            }
+            let frames: Vec<_> = files.par_iter()
+                .map(|file| self.stretch.apply(&load_frame(file)?, self.max_width))
+                .collect();
+            for (idx, (file, frame)) in files.iter().zip(frames).enumerate() {
+                let (width, height, bytes) = frame.map_err(|err| anyhow::anyhow!(
+                    "Can't load frame {}: {}", path_to_str(file), err
+                ))?;
+                if (width, height) != (self.width, self.height) {
+                    anyhow::bail!(
+                        "Size of frame {} {}x{} differs from size of other frames {}x{}",
+                        path_to_str(file), width, height, self.width, self.height
+                    );
+                }
+                fun(portion_idx * portion + idx, &bytes)?;
+                self.progress.lock().unwrap().progress(true, extract_file_name(file));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Creates timelapse from `files` in their order. Format of result is
+/// defined by `out` (see `TimelapseFormat::by_file_name`)
+pub fn create_timelapse(
+    files:        &[PathBuf],
+    out:          &Path,
+    opts:         &TimelapseOpts,
+    progress:     &ProgressTs,
+    is_cancelled: &IsCancelledFun,
+) -> anyhow::Result<TimelapseFormat> {
+    if files.is_empty() {
+        anyhow::bail!("No frames for timelapse");
+    }
+    if opts.fps.is_nan() || opts.fps <= 0.0 {
+        anyhow::bail!("Frames per second must be positive");
+    }
+    progress.lock().unwrap().stage("Calculating stretch by middle frame...");
+    let middle = &files[files.len() / 2];
+    let middle_image = load_frame(middle)?;
+    let stretch = FixedStretch::new(&middle_image, opts.stretch);
+    let (width, height, _) = stretch.apply(&middle_image, opts.max_width)?;
+    drop(middle_image);
+    let is_rgb = stretch.is_rgb();
+    let source = FramesSource {
+        files,
+        stretch,
+        max_width: opts.max_width,
+        width,
+        height,
+        progress,
+        is_cancelled,
+    };
+
+    progress.lock().unwrap().stage("Creating timelapse...");
+    let format = TimelapseFormat::by_file_name(out);
+    match format {
+        TimelapseFormat::Gif => {
+            if width > u16::MAX as usize || height > u16::MAX as usize {
+                anyhow::bail!("Frames are too big for GIF");
+            }
+            let (width, height) = (width as u16, height as u16);
+            let delay = (100.0 / opts.fps).round().max(1.0) as u16; // in 1/100 s
+            write_file_atomically(out, |tmp_file_name| {
+                let writer = BufWriter::new(File::create(tmp_file_name)?);
+                let mut encoder = gif::Encoder::new(writer, width, height, &[])?;
+                encoder.set_repeat(gif::Repeat::Infinite)?;
+                source.for_each(|_, bytes| {
+                    let rgb: Vec<u8> = if is_rgb {
+                        bytes.to_vec()
+                    } else {
+                        bytes.iter().flat_map(|v| [*v, *v, *v]).collect()
+                    };
+                    let mut frame = gif::Frame::from_rgb_speed(width, height, &rgb, 10);
+                    frame.delay = delay;
+                    encoder.write_frame(&frame)?;
+                    Ok(())
+                })
+            })?;
+        }
+        TimelapseFormat::Apng => {
+            let delay = (1000.0 / opts.fps).round().clamp(1.0, u16::MAX as f32) as u16; // in 1/1000 s
+            write_file_atomically(out, |tmp_file_name| {
+                let writer = BufWriter::new(File::create(tmp_file_name)?);
+                let mut encoder = png::Encoder::new(writer, width as u32, height as u32);
+                encoder.set_color(if is_rgb { png::ColorType::Rgb } else { png::ColorType::Grayscale });
+                encoder.set_depth(png::BitDepth::Eight);
+                encoder.set_animated(files.len() as u32, 0)?;
+                encoder.set_frame_delay(delay, 1000)?;
+                let mut writer = encoder.write_header()?;
+                source.for_each(|_, bytes| Ok(writer.write_image_data(bytes)?))?;
+                writer.finish()?;
+                Ok(())
+            })?;
+        }
+        TimelapseFormat::PngSequence => {
+            std::fs::create_dir_all(out)?;
+            source.for_each(|idx, bytes| {
+                let file_name = out.join(format!("frame_{:05}.png", idx + 1));
+                save_8bit_image(&file_name, width, height, is_rgb, bytes, 90)
+            })?;
+        }
+    }
+    Ok(format)
+}
@@ -0,0 +1,110 @@
+use rayon::prelude::*;
+use crate::{image::*, noise::*};
+
+/* Multiscale processing by "a trous" wavelet transform with B3 spline.
+   Each detail layer is multiplied by its gain (sharpening of planetary
+   and lunar images) and soft thresholded by noise level (noise reduction).
+   Threshold is defined in units of noise sigma for each layer */
+
+#[derive(Clone, Debug)]
+pub struct WaveletOpts {
+    pub gains:   Vec<f32>, // gain of each layer starting from the finest
+    pub denoise: Vec<f32>, // threshold of each layer (noise sigmas)
+}
+
+impl Default for WaveletOpts {
+    fn default() -> Self {
+        Self {
+            gains:   vec![1.5, 1.3, 1.1, 1.0],
+            denoise: vec![3.0, 1.0, 0.0, 0.0],
+        }
+    }
+}
+
+impl WaveletOpts {
+    /// "1.5,1.3,1" -> [1.5, 1.3, 1.0]
+    pub fn parse_list(text: &str) -> anyhow::Result<Vec<f32>> {
+        let values: Vec<f32> = text.split(',')
+            .map(|v| v.trim().parse())
+            .collect::<Result<_, _>>()
+            .map_err(|_| anyhow::anyhow!("Wrong list of values {}", text))?;
+        if values.iter().any(|v| *v < 0.0) {
+            anyhow::bail!("Values in list {} must not be negative", text);
+        }
+        Ok(values)
+    }
+
+    fn layers_count(&self) -> usize {
+        self.gains.len().max(self.denoise.len())
+    }
+}
+
+pub struct WaveletsResult {
+    pub layers: usize,      // really processed layers (small image has less)
+    pub noise:  Vec<f32>,   // noise of each channel
+}
+
+// Noise of layer j for gaussian noise with sigma = 1. Every next
+// layer is about two times smaller than previous
+fn layer_sigma(j: usize) -> f32 {
+    let last = SCALE_SIGMA.len() - 1;
+    if j <= last {
+        SCALE_SIGMA[j]
+    } else {
+        SCALE_SIGMA[last] / (1 << (j - last)) as f32
+    }
+}
+
+fn process_layer(layer: &mut ImageLayerF32, opts: &WaveletOpts) -> (usize, f32) {
+    let (width, height) = (layer.width() as usize, layer.height() as usize);
+    if width < 3 || height < 3 { return (0, 0.0); }
+    let noise = estimate_noise_k_sigma(layer);
+    let (data, mask) = prepare_data(layer);
+
+    let mut result = vec![0_f32; data.len()];
+    let mut current = data;
+    let mut layers = 0;
+    for j in 0..opts.layers_count() {
+        let step = 1 << j;
+        if 4 * step >= width.min(height) { break; }
+        let gain = opts.gains.get(j).copied().unwrap_or(1.0);
+        let threshold = opts.denoise.get(j).copied().unwrap_or(0.0) * noise * layer_sigma(j);
+        let smoothed = b3_smooth(&current, width, height, step);
+        result.par_iter_mut()
+            .zip(current.par_iter().zip(smoothed.par_iter()))
+            .for_each(|(r, (c, s))| {
+                let w = c - s;
+                let w = w.signum() * (w.abs() - threshold).max(0.0);
+                *r += gain * w;
+            });
+        current = smoothed;
+        layers += 1;
+    }
+
+    // residual layer is not changed
+    for ((v, r), (c, m)) in layer.as_slice_mut().iter_mut().zip(&result).zip(current.iter().zip(&mask)) {
+        if *m { *v = r + c; }
+    }
+    (layers, noise)
+}
+
+pub fn process_wavelets(image: &mut Image, opts: &WaveletOpts) -> anyhow::Result<WaveletsResult> {
+    if opts.layers_count() == 0 {
+        anyhow::bail!("Wavelet layers are not defined");
+    }
+    let layers: Vec<&mut ImageLayerF32> = if image.is_rgb() {
+        vec![&mut image.r, &mut image.g, &mut image.b]
+    } else {
+        vec![&mut image.l]
+    };
+    let mut result = WaveletsResult { layers: 0, noise: Vec::new() };
+    for layer in layers {
+        let (layers, noise) = process_layer(layer, opts);
+        result.layers = layers;
+        result.noise.push(noise);
+    }
+    if result.layers == 0 {
+        anyhow::bail!("Image is too small for wavelet processing");
+    }
+    Ok(result)
+}
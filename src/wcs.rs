@@ -0,0 +1,222 @@
+use std::path::*;
+use serde::*;
+use crate::{image_io::*, fs_utils::*};
+
+/* World coordinate system (FITS WCS with gnomonic TAN projection) */
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Wcs {
+    pub crval: [f64; 2], // RA and DEC of reference point in degrees
+    pub crpix: [f64; 2], // reference pixel (FITS convention, starts from 1)
+    pub cd:    [[f64; 2]; 2], // degrees per pixel
+}
+
+impl Wcs {
+    /// Pixel coordinates (starting from 0) to RA and DEC in degrees
+    pub fn pixel_to_world(&self, x: f64, y: f64) -> (f64, f64) {
+        let dx = x + 1.0 - self.crpix[0];
+        let dy = y + 1.0 - self.crpix[1];
+        let xi = (self.cd[0][0] * dx + self.cd[0][1] * dy).to_radians();
+        let eta = (self.cd[1][0] * dx + self.cd[1][1] * dy).to_radians();
+        let ra0 = self.crval[0].to_radians();
+        let dec0 = self.crval[1].to_radians();
+        let denom = dec0.cos() - eta * dec0.sin();
+        let ra = ra0 + xi.atan2(denom);
+        let dec = (dec0.sin() + eta * dec0.cos()).atan2((xi * xi + denom * denom).sqrt());
+        (ra.to_degrees().rem_euclid(360.0), dec.to_degrees())
+    }
+
+    /// RA and DEC in degrees to pixel coordinates (starting from 0)
+    pub fn world_to_pixel(&self, ra: f64, dec: f64) -> Option<(f64, f64)> {
+        let ra0 = self.crval[0].to_radians();
+        let dec0 = self.crval[1].to_radians();
+        let (ra, dec) = (ra.to_radians(), dec.to_radians());
+        let cos_c = dec0.sin() * dec.sin() + dec0.cos() * dec.cos() * (ra - ra0).cos();
+        if cos_c <= 0.0 { return None; } // other hemisphere
+        let xi = (dec.cos() * (ra - ra0).sin() / cos_c).to_degrees();
+        let eta = ((dec0.cos() * dec.sin() - dec0.sin() * dec.cos() * (ra - ra0).cos()) / cos_c).to_degrees();
+        let det = self.cd[0][0] * self.cd[1][1] - self.cd[0][1] * self.cd[1][0];
+        if det == 0.0 { return None; }
+        let dx = ( self.cd[1][1] * xi - self.cd[0][1] * eta) / det;
+        let dy = (-self.cd[1][0] * xi + self.cd[0][0] * eta) / det;
+        Some((dx + self.crpix[0] - 1.0, dy + self.crpix[1] - 1.0))
+    }
+
+    /// Pixel scale in arcseconds
+    pub fn pixel_scale(&self) -> f64 {
+        let det = self.cd[0][0] * self.cd[1][1] - self.cd[0][1] * self.cd[1][0];
+        3600.0 * det.abs().sqrt()
+    }
+
+    /// Rotation of image (angle of north direction relative to Y axis) in degrees
+    pub fn rotation(&self) -> f64 {
+        self.cd[0][1].atan2(self.cd[1][1]).to_degrees()
+    }
+
+    /// WCS of image binned by `bin`
+    pub fn binned(&self, bin: usize) -> Wcs {
+        let bin = bin.max(1) as f64;
+        Wcs {
+            crval: self.crval,
+            crpix: [(self.crpix[0] - 0.5) / bin + 0.5, (self.crpix[1] - 0.5) / bin + 0.5],
+            cd: [
+                [self.cd[0][0] * bin, self.cd[0][1] * bin],
+                [self.cd[1][0] * bin, self.cd[1][1] * bin],
+            ],
+        }
+    }
+
+    pub fn load_from_fits_file(file_name: &Path) -> anyhow::Result<Wcs> {
+        const KEYS: &[&str] = &[
+            "CRVAL1", "CRVAL2", "CRPIX1", "CRPIX2",
+            "CD1_1", "CD1_2", "CD2_1", "CD2_2",
+        ];
+        let values = read_fits_float_keys(file_name, KEYS)?;
+        let get = |name: &str| values.get(name).copied().ok_or_else(||
+            anyhow::anyhow!("WCS key {} is not found in {}", name, file_name.to_str().unwrap_or(""))
+        );
+        Ok(Wcs {
+            crval: [get("CRVAL1")?, get("CRVAL2")?],
+            crpix: [get("CRPIX1")?, get("CRPIX2")?],
+            cd: [[get("CD1_1")?, get("CD1_2")?], [get("CD2_1")?, get("CD2_2")?]],
+        })
+    }
+
+    /// WCS of image from FITS header or from `<image>.wcs.json` for other formats
+    pub fn load_for_image(file_name: &Path) -> anyhow::Result<Wcs> {
+        if is_fits_ext(extract_extension(file_name)) {
+            return Self::load_from_fits_file(file_name);
+        }
+        let wcs_file = wcs_json_file_name(file_name);
+        let text = std::fs::read_to_string(&wcs_file).map_err(|err| anyhow::anyhow!(
+            "Can't read WCS of {} from {} ({}). Plate solve image first",
+            file_name.to_str().unwrap_or(""), wcs_file.to_str().unwrap_or(""), err
+        ))?;
+        Ok(serde_json::from_str(&text)?)
+    }
+
+    /// Writes WCS into FITS header or into `<image>.wcs.json` for other formats
+    pub fn save_for_image(&self, file_name: &Path) -> anyhow::Result<()> {
+        if is_fits_ext(extract_extension(file_name)) {
+            return self.save_into_fits_file(file_name);
+        }
+        let text = serde_json::to_string_pretty(self)?;
+        write_file_atomically(&wcs_json_file_name(file_name), |tmp_file_name| {
+            Ok(std::fs::write(tmp_file_name, &text)?)
+        })
+    }
+
+    pub fn save_into_fits_file(&self, file_name: &Path) -> anyhow::Result<()> {
+        use FitsKeyValue::*;
+        update_fits_header(file_name, &[
+            ("WCSAXES", Float(2.0)),
+            ("CTYPE1",  Str("RA---TAN".to_string())),
+            ("CTYPE2",  Str("DEC--TAN".to_string())),
+            ("CUNIT1",  Str("deg".to_string())),
+            ("CUNIT2",  Str("deg".to_string())),
+            ("EQUINOX", Float(2000.0)),
+            ("CRVAL1",  Float(self.crval[0])),
+            ("CRVAL2",  Float(self.crval[1])),
+            ("CRPIX1",  Float(self.crpix[0])),
+            ("CRPIX2",  Float(self.crpix[1])),
+            ("CD1_1",   Float(self.cd[0][0])),
+            ("CD1_2",   Float(self.cd[0][1])),
+            ("CD2_1",   Float(self.cd[1][0])),
+            ("CD2_2",   Float(self.cd[1][1])),
+        ])
+    }
+}
+
+/// Affine transform from pixels of image with `src` WCS to pixels of
+/// image with `dst` WCS. Transform is fitted by least squares over grid
+/// of points of source image so difference of projections of images is
+/// averaged. None if images are in different hemispheres of sky
+pub fn calc_wcs_transform(src: &Wcs, width: usize, height: usize, dst: &Wcs) -> Option<[[f64; 3]; 2]> {
+    const GRID: usize = 8;
+    let mut sums = [[0_f64; 3]; 3]; // sums of products of (x, y, 1)
+    let mut sums_x = [0_f64; 3];
+    let mut sums_y = [0_f64; 3];
+    for iy in 0..=GRID {
+        for ix in 0..=GRID {
+            let x = (width.max(1) - 1) as f64 * ix as f64 / GRID as f64;
+            let y = (height.max(1) - 1) as f64 * iy as f64 / GRID as f64;
+            let (ra, dec) = src.pixel_to_world(x, y);
+            let (dx, dy) = dst.world_to_pixel(ra, dec)?;
+            let p = [x, y, 1.0];
+            for (i, pi) in p.iter().enumerate() {
+                for (s, pj) in sums[i].iter_mut().zip(p) {
+                    *s += pi * pj;
+                }
+                sums_x[i] += pi * dx;
+                sums_y[i] += pi * dy;
+            }
+        }
+    }
+    let row_x = solve_3x3(&sums, &sums_x)?;
+    let row_y = solve_3x3(&sums, &sums_y)?;
+    Some([row_x, row_y])
+}
+
+fn solve_3x3(m: &[[f64; 3]; 3], v: &[f64; 3]) -> Option<[f64; 3]> {
+    let det = |m: &[[f64; 3]; 3]|
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1]) -
+        m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0]) +
+        m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0]);
+    let d = det(m);
+    if d.abs() < 1e-12 { return None; }
+    let mut result = [0_f64; 3];
+    for (col, r) in result.iter_mut().enumerate() {
+        let mut mc = *m;
+        for (mc_row, value) in mc.iter_mut().zip(v) {
+            mc_row[col] = *value;
+        }
+        *r = det(&mc) / d;
+    }
+    Some(result)
+}
+
+/// File with WCS for images which format has no standard WCS keywords
+pub fn wcs_json_file_name(image_file: &Path) -> PathBuf {
+    image_file.with_extension("wcs.json")
+}
+
+/// "12:30:45.5", "12h30m45.5s" or degrees
+pub fn parse_ra(text: &str) -> anyhow::Result<f64> {
+    if let Ok(degrees) = text.trim().parse::<f64>() {
+        return Ok(degrees);
+    }
+    let hours = parse_sexagesimal(text)
+        .ok_or_else(|| anyhow::anyhow!("Wrong RA {}", text))?;
+    Ok(15.0 * hours)
+}
+
+/// "-05:23:10", "-5d23m10s" or degrees
+pub fn parse_dec(text: &str) -> anyhow::Result<f64> {
+    let result = match text.trim().parse::<f64>() {
+        Ok(degrees) => degrees,
+        Err(_) => parse_sexagesimal(text).ok_or_else(|| anyhow::anyhow!("Wrong DEC {}", text))?,
+    };
+    if !(-90.0..=90.0).contains(&result) {
+        anyhow::bail!("DEC {} is out of range", text);
+    }
+    Ok(result)
+}
+
+pub fn parse_sexagesimal(text: &str) -> Option<f64> {
+    let text = text.trim();
+    let (sign, text) = match text.strip_prefix('-') {
+        Some(rest) => (-1.0, rest),
+        None => (1.0, text.strip_prefix('+').unwrap_or(text)),
+    };
+    let parts: Vec<f64> = text
+        .split(|c: char| c == ':' || c == ' ' || c.is_ascii_alphabetic())
+        .filter(|s| !s.is_empty())
+        .map(|s| s.parse().ok())
+        .collect::<Option<_>>()?;
+    if parts.is_empty() || parts.len() > 3 { return None; }
+    let value = parts.iter()
+        .zip([1.0, 60.0, 3600.0])
+        .map(|(v, div)| v / div)
+        .sum::<f64>();
+    Some(sign * value)
+}
@@ -0,0 +1,437 @@
+use std::{path::*, io::*, fs::File, collections::HashMap};
+use byteorder::{LittleEndian, BigEndian, ReadBytesExt, ByteOrder};
+use regex::Regex;
+use crate::{image::*, image_io::*, image_raw::*, fs_utils::*, cameras_database::*, safe_read::*, airmass::*, integration_meta::*};
+use crate::wcs::{parse_ra, parse_dec};
+
+/* XISF format (PixInsight) */
+
+const XISF_SIGNATURE: &[u8] = b"XISF0100";
+
+#[derive(Clone, Copy, PartialEq)]
+enum SampleFormat { UInt8, UInt16, UInt32, UInt64, Float32, Float64 }
+
+impl SampleFormat {
+    fn from_str(text: &str) -> anyhow::Result<Self> {
+        match text {
+            "UInt8"   => Ok(SampleFormat::UInt8),
+            "UInt16"  => Ok(SampleFormat::UInt16),
+            "UInt32"  => Ok(SampleFormat::UInt32),
+            "UInt64"  => Ok(SampleFormat::UInt64),
+            "Float32" => Ok(SampleFormat::Float32),
+            "Float64" => Ok(SampleFormat::Float64),
+            _ => anyhow::bail!("XISF sample format {} is not supported", text),
+        }
+    }
+
+    fn size(self) -> usize {
+        match self {
+            SampleFormat::UInt8   => 1,
+            SampleFormat::UInt16  => 2,
+            SampleFormat::UInt32  => 4,
+            SampleFormat::UInt64  => 8,
+            SampleFormat::Float32 => 4,
+            SampleFormat::Float64 => 8,
+        }
+    }
+
+    fn max_value(self) -> f32 {
+        match self {
+            SampleFormat::UInt8   => u8::MAX as f32,
+            SampleFormat::UInt16  => u16::MAX as f32,
+            SampleFormat::UInt32  => u32::MAX as f32,
+            SampleFormat::UInt64  => u64::MAX as f32,
+            SampleFormat::Float32 => 1.0,
+            SampleFormat::Float64 => 1.0,
+        }
+    }
+}
+
+struct XisfImageHeader {
+    width:       usize,
+    height:      usize,
+    channels:    usize,
+    format:      SampleFormat,
+    big_endian:  bool,
+    planar:      bool,
+    position:    u64,
+    size:        usize,
+    compression: Option<(String, usize, Option<usize>)>, // codec, uncompressed size, shuffle item size
+    keywords:    HashMap<String, String>,
+}
+
+fn parse_attributes(text: &str) -> HashMap<String, String> {
+    let re = Regex::new(r#"([\w:]+)\s*=\s*"([^"]*)""#).unwrap();
+    re.captures_iter(text)
+        .map(|c| (c[1].to_string(), unescape_xml(&c[2])))
+        .collect()
+}
+
+fn unescape_xml(text: &str) -> String {
+    text.replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn read_header(file: &mut File) -> anyhow::Result<XisfImageHeader> {
+    let mut signature = [0_u8; 8];
+    file.read_exact(&mut signature)?;
+    if signature != XISF_SIGNATURE {
+        anyhow::bail!("File is not XISF file");
+    }
+    let header_len = file.read_u32::<LittleEndian>()? as usize;
+    let _reserved = file.read_u32::<LittleEndian>()?;
+    let header = read_bytes(file, header_len, MAX_HEADER_SIZE, "XISF header")?;
+    let header = String::from_utf8_lossy(&header);
+
+    // Only first image of file is used
+    let image_re = Regex::new(r"(?s)<Image\b([^>]*?)(/>|>(.*?)</Image>)").unwrap();
+    let image = image_re.captures(&header)
+        .ok_or_else(|| anyhow::anyhow!("Image is not found in XISF file"))?;
+    let attrs = parse_attributes(&image[1]);
+    let get_attr = |name: &str| attrs.get(name)
+        .ok_or_else(|| anyhow::anyhow!("Attribute {} is not found in XISF image", name));
+
+    let geometry: Vec<usize> = get_attr("geometry")?
+        .split(':')
+        .map(|v| v.parse())
+        .collect::<std::result::Result<_, _>>()?;
+    let (width, height, channels) = match geometry.as_slice() {
+        &[width, height, channels] => (width, height, channels),
+        _ => anyhow::bail!("Only 2D images are supported in XISF file"),
+    };
+    if channels != 1 && channels != 3 {
+        anyhow::bail!("XISF image with {} channels is not supported", channels);
+    }
+
+    let format = SampleFormat::from_str(get_attr("sampleFormat")?)?;
+    checked_image_size(width, height, channels, format.size())?;
+    let big_endian = attrs.get("byteOrder").map(|v| v == "big").unwrap_or(false);
+    let planar = attrs.get("pixelStorage").map(|v| v != "Normal").unwrap_or(true);
+
+    let location: Vec<&str> = get_attr("location")?.split(':').collect();
+    let (position, size) = match location.as_slice() {
+        &["attachment", position, size] => (position.parse()?, size.parse()?),
+        _ => anyhow::bail!("Only attached data blocks are supported in XISF file"),
+    };
+
+    let compression = if let Some(compr) = attrs.get("compression") {
+        let items: Vec<&str> = compr.split(':').collect();
+        match items.as_slice() {
+            &[codec, size] =>
+                Some((codec.to_string(), size.parse()?, None)),
+            &[codec, size, item_size] =>
+                Some((codec.to_string(), size.parse()?, Some(item_size.parse()?))),
+            _ => anyhow::bail!("Wrong compression attribute {} in XISF file", compr),
+        }
+    } else {
+        None
+    };
+
+    let mut keywords = HashMap::new();
+    if let Some(children) = image.get(3) {
+        let kw_re = Regex::new(r"<FITSKeyword\b([^>]*?)/?>").unwrap();
+        for kw in kw_re.captures_iter(children.as_str()) {
+            let kw_attrs = parse_attributes(&kw[1]);
+            if let (Some(name), Some(value)) = (kw_attrs.get("name"), kw_attrs.get("value")) {
+                let value = value.trim().trim_matches('\'').trim().to_string();
+                keywords.insert(name.trim().to_string(), value);
+            }
+        }
+    }
+
+    Ok(XisfImageHeader {
+        width, height, channels, format, big_endian, planar,
+        position, size, compression, keywords
+    })
+}
+
+fn unshuffle(data: &[u8], item_size: usize) -> Vec<u8> {
+    let items = data.len() / item_size.max(1);
+    let mut result = data.to_vec();
+    for (i, v) in data[..items * item_size].iter().enumerate() {
+        let byte = i / items;
+        let item = i % items;
+        result[item * item_size + byte] = *v;
+    }
+    result
+}
+
+fn read_data_block(file: &mut File, header: &XisfImageHeader) -> anyhow::Result<Vec<u8>> {
+    let expected_size = header.width * header.height * header.channels * header.format.size();
+    let file_len = file.metadata()?.len();
+    check_block_in_file(header.position, header.size as u64, file_len, "XISF data block")?;
+    file.seek(SeekFrom::Start(header.position))?;
+    let data = read_bytes(file, header.size, usize::MAX, "XISF data block")?;
+    let data = match &header.compression {
+        None => data,
+        Some((codec, uncompressed_size, item_size)) => {
+            let codec = codec.trim_end_matches("+sh");
+            if *uncompressed_size != expected_size {
+                anyhow::bail!("Wrong uncompressed size of XISF data block");
+            }
+            let mut data = match codec {
+                "zlib" =>
+                    read_to_end_limited(
+                        flate2::read::ZlibDecoder::new(data.as_slice()),
+                        expected_size,
+                        "XISF data block"
+                    )?,
+                "lz4"|"lz4hc" =>
+                    lz4_flex::block::decompress(&data, expected_size)?,
+                _ =>
+                    anyhow::bail!("XISF compression {} is not supported", codec),
+            };
+            if let Some(item_size) = item_size {
+                data = unshuffle(&data, *item_size);
+            }
+            data
+        }
+    };
+    if data.len() < expected_size {
+        anyhow::bail!("XISF data block is too small");
+    }
+    Ok(data)
+}
+
+fn convert_samples<BO: ByteOrder>(data: &[u8], format: SampleFormat, count: usize) -> Vec<f32> {
+    let size = format.size();
+    data[..count * size].chunks_exact(size)
+        .map(|v| match format {
+            SampleFormat::UInt8   => v[0] as f32,
+            SampleFormat::UInt16  => BO::read_u16(v) as f32,
+            SampleFormat::UInt32  => BO::read_u32(v) as f32,
+            SampleFormat::UInt64  => BO::read_u64(v) as f32,
+            SampleFormat::Float32 => BO::read_f32(v),
+            SampleFormat::Float64 => BO::read_f64(v) as f32,
+        })
+        .collect()
+}
+
+fn info_from_keywords(header: &XisfImageHeader, file_name: &Path) -> ImageInfo {
+    let kw = |name: &str| header.keywords.get(name).map(|v| v.as_str());
+    let kw_f64 = |name: &str| kw(name).and_then(|v| v.parse::<f64>().ok());
+    ImageInfo {
+        file_name: file_name.to_path_buf(),
+        width: header.width,
+        height: header.height,
+        file_time: kw("DATE-LOC")
+            .or_else(|| kw("DATE-OBS"))
+            .and_then(try_to_decode_date_time_str)
+            .or_else(|| get_file_time(file_name).ok()),
+        cfa_type: kw("BAYERPAT").and_then(CfaType::from_string),
+        iso: kw_f64("GAIN").or_else(|| kw_f64("ISOSPEED")).map(|v| v as u32),
+        exp: kw_f64("EXPTIME").or_else(|| kw_f64("EXPOSURE")),
+        fnumber: kw_f64("FOCRATIO").map(|v| v as f32),
+        focal_len: kw_f64("FOCALLEN").map(|v| v as f32),
+        camera: kw("INSTRUME").map(|v| v.to_string()),
+        lens: kw("TELESCOP").map(|v| v.to_string()),
+        temperature: kw_f64("CCD-TEMP").map(|v| v as f32),
+        filter: kw("FILTER").map(|v| v.trim().to_string()).filter(|v| !v.is_empty()),
+        frame_type: kw("IMAGETYP").map(|v| v.to_string()),
+        target: kw_f64("RA").or_else(|| kw("OBJCTRA").and_then(|v| parse_ra(v).ok()))
+            .zip(kw_f64("DEC").or_else(|| kw("OBJCTDEC").and_then(|v| parse_dec(v).ok())))
+            .map(|(ra, dec)| EqCoords { ra, dec }),
+        site: kw("SITELAT").and_then(parse_site_angle)
+            .zip(kw("SITELONG").and_then(parse_site_angle))
+            .map(|(lat, lon)| SiteCoords { lat, lon }),
+        integration: kw_f64("NCOMBINE").filter(|v| *v > 0.0).map(|count| IntegrationMeta {
+            exp_time: kw_f64("EXPTIME").unwrap_or(0.0),
+            count: count as usize,
+            date_obs: kw("DATE-OBS").and_then(try_to_decode_date_time_str),
+            date_end: kw("DATE-END").and_then(try_to_decode_date_time_str),
+            files: Vec::new(),
+        }),
+    }
+}
+
+fn open_xisf_file(file_name: &Path) -> anyhow::Result<(File, XisfImageHeader)> {
+    let mut file = File::open(file_name)?;
+    let header = read_header(&mut file).map_err(|err| anyhow::anyhow!(
+        "{} ({})", err, file_name.to_str().unwrap_or("")
+    ))?;
+    Ok((file, header))
+}
+
+pub fn load_src_file_info_xisf(file_name: &Path) -> anyhow::Result<ImageInfo> {
+    let (_, header) = open_xisf_file(file_name)?;
+    Ok(info_from_keywords(&header, file_name))
+}
+
+pub fn load_image_from_xisf_file(
+    file_name:    &Path,
+    force_as_raw: bool
+) -> anyhow::Result<ImageData> {
+    let (mut file, header) = open_xisf_file(file_name)?;
+    let info = info_from_keywords(&header, file_name);
+    let data = read_data_block(&mut file, &header)?;
+    let count = header.width * header.height * header.channels;
+    let mut values = if header.big_endian {
+        convert_samples::<BigEndian>(&data, header.format, count)
+    } else {
+        convert_samples::<LittleEndian>(&data, header.format, count)
+    };
+    drop(data);
+
+    let width = header.width as Crd;
+    let height = header.height as Crd;
+    let camera_params = find_camera_params(info.camera.as_deref());
+    let max = header.format.max_value();
+
+    if header.channels == 1 && (info.cfa_type.is_some() || camera_params.is_some() || force_as_raw) {
+        let ct = info.cfa_type.or_else(|| camera_params.and_then(|(_, ct, _)| ct));
+        let raw_info = RawImageInfo {
+            width,
+            height,
+            max_values: [max; 4],
+            black_values: [0.0; 4],
+            wb: camera_params.map(|(wb, _, _)| wb).unwrap_or([1.0; 4]),
+            cam_to_rgb: camera_params.and_then(|(_, _, ccm)| ccm.map(|v| v.clone())),
+            cfa: Cfa::from_cfa_type(ct),
+            camera: info.camera.clone(),
+            exposure: info.exp.map(|v| v as f32),
+            iso: info.iso,
+        };
+        let raw = RawImage {
+            info: raw_info,
+            data: ImageLayerF32::new_from_vec(width, height, values),
+        };
+        return Ok(ImageData { image: RawOrImage::Raw(raw), info });
+    }
+
+    if force_as_raw {
+        anyhow::bail!("Image is not RAW camera file!");
+    }
+
+    if max != 1.0 {
+        for v in &mut values { *v /= max; }
+    }
+
+    let mut image = Image::new();
+    if header.channels == 1 {
+        image.l = ImageLayerF32::new_from_vec(width, height, values);
+    } else {
+        let plane_size = header.width * header.height;
+        let get_plane = |c: usize| -> Vec<f32> {
+            if header.planar {
+                values[c * plane_size..(c + 1) * plane_size].to_vec()
+            } else {
+                values.iter().skip(c).step_by(3).copied().collect()
+            }
+        };
+        image.r = ImageLayerF32::new_from_vec(width, height, get_plane(0));
+        image.g = ImageLayerF32::new_from_vec(width, height, get_plane(1));
+        image.b = ImageLayerF32::new_from_vec(width, height, get_plane(2));
+    }
+
+    Ok(ImageData { image: RawOrImage::Image(image), info })
+}
+
+fn create_header(image: &Image, info: &ImageInfo, position: usize, size: usize) -> String {
+    let channels = if image.is_rgb() { 3 } else { 1 };
+    let color_space = if image.is_rgb() { "RGB" } else { "Gray" };
+
+    let mut keywords = Vec::new();
+    if let Some(exp) = info.exp {
+        keywords.push(("EXPTIME", format!("{}", exp), "Exposure time in seconds"));
+    }
+    if let Some(integration) = &info.integration {
+        keywords.push(("NCOMBINE", format!("{}", integration.count), "Number of combined frames"));
+        if let Some(date_obs) = integration.date_obs {
+            keywords.push(("DATE-OBS", format!("'{}'", format_fits_time(date_obs)), "Start of first frame (UTC)"));
+        }
+        if let Some(date_end) = integration.date_end {
+            keywords.push(("DATE-END", format!("'{}'", format_fits_time(date_end)), "End of last frame (UTC)"));
+        }
+    }
+    if let Some(camera) = &info.camera {
+        keywords.push(("INSTRUME", format!("'{}'", camera), "Camera"));
+    }
+    if let Some(lens) = &info.lens {
+        keywords.push(("TELESCOP", format!("'{}'", lens), "Telescope or lens"));
+    }
+    if let Some(focal_len) = info.focal_len {
+        keywords.push(("FOCALLEN", format!("{}", focal_len), "Focal length in mm"));
+    }
+    if let Some(time) = &info.file_time {
+        keywords.push(("DATE-LOC", format!("'{}'", time.format("%Y-%m-%dT%H:%M:%S%.3f")), "Local date and time"));
+    }
+    keywords.push(("ROWORDER", "'TOP-DOWN'".to_string(), "Order of image rows"));
+
+    let mut result = String::new();
+    result.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    result.push_str("<xisf version=\"1.0\" xmlns=\"http://www.pixinsight.com/xisf\">\n");
+    result.push_str(&format!(
+        "<Image geometry=\"{}:{}:{}\" sampleFormat=\"Float32\" bounds=\"0:1\" \
+        colorSpace=\"{}\" location=\"attachment:{}:{}\">\n",
+        image.width(), image.height(), channels, color_space, position, size
+    ));
+    for (name, value, comment) in keywords {
+        result.push_str(&format!(
+            "<FITSKeyword name=\"{}\" value=\"{}\" comment=\"{}\"/>\n",
+            name, escape_xml(&value), escape_xml(comment)
+        ));
+    }
+    if let Some(integration) = &info.integration {
+        let (history, comments) = integration.history();
+        let cards = std::iter::once(("HISTORY", history)).chain(comments.into_iter().map(|c| ("COMMENT", c)));
+        for (name, text) in cards {
+            result.push_str(&format!(
+                "<FITSKeyword name=\"{}\" value=\"\" comment=\"{}\"/>\n",
+                name, escape_xml(&text)
+            ));
+        }
+    }
+    result.push_str("</Image>\n");
+    result.push_str(&format!(
+        "<Metadata><Property id=\"XISF:CreatorApplication\" type=\"String\">{} {}</Property></Metadata>\n",
+        env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")
+    ));
+    result.push_str("</xisf>");
+    result
+}
+
+pub fn save_image_to_xisf_file(
+    image:     &Image,
+    info:      &ImageInfo,
+    file_name: &Path
+) -> anyhow::Result<()> {
+    let layers = if image.is_rgb() {
+        vec![&image.r, &image.g, &image.b]
+    } else {
+        vec![&image.l]
+    };
+    let size = layers.iter().map(|l| l.as_slice().len()).sum::<usize>() * 4;
+
+    // Position of data depends on header size and header contains position
+    let mut position = 0;
+    let header = loop {
+        let header = create_header(image, info, position, size);
+        let new_position = XISF_SIGNATURE.len() + 8 + header.len();
+        if new_position == position { break header; }
+        position = new_position;
+    };
+
+    let mut file = BufWriter::new(File::create(file_name)?);
+    file.write_all(XISF_SIGNATURE)?;
+    file.write_all(&(header.len() as u32).to_le_bytes())?;
+    file.write_all(&0_u32.to_le_bytes())?;
+    file.write_all(header.as_bytes())?;
+    for layer in layers {
+        for v in layer.iter() {
+            let v = if v.is_finite() { *v } else { 0.0 };
+            file.write_all(&v.to_le_bytes())?;
+        }
+    }
+    file.flush()?;
+    Ok(())
+}